@@ -0,0 +1,147 @@
+//! Signalling over a plain TCP connection between the peers, for when they
+//! already have a way to reach each other, e.g. through an existing control
+//! channel, and only need ICE to find a direct path.
+//!
+//! cargo run --example custom_signalling
+
+use futures::{future::LocalBoxFuture, FutureExt};
+use icepipe::{
+    ice::IceServer,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    signalling::Signalling,
+    ConnectOptions,
+};
+use std::{error::Error, io};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Messages prefixed by their length as a big endian `u32`.
+struct TcpSignalling {
+    stream: TcpStream,
+    /// Read but not yet handed out.
+    pending: Vec<u8>,
+}
+impl TcpSignalling {
+    fn new(stream: TcpStream) -> TcpSignalling {
+        TcpSignalling {
+            stream,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Length of the first message in `pending` once it is complete.
+    fn complete(&self) -> Option<usize> {
+        let len = self.pending.first_chunk::<4>()?;
+        let len = u32::from_be_bytes(*len) as usize;
+        (self.pending.len() >= 4 + len).then_some(len)
+    }
+}
+impl Signalling for TcpSignalling {
+    fn send(&mut self, msg: String) -> LocalBoxFuture<'_, io::Result<()>> {
+        async move {
+            let len = u32::try_from(msg.len()).map_err(io::Error::other)?;
+            self.stream.write_all(&len.to_be_bytes()).await?;
+            self.stream.write_all(msg.as_bytes()).await
+        }
+        .boxed_local()
+    }
+}
+impl WaitThen for TcpSignalling {
+    type Value = usize;
+    type Output = Option<String>;
+    type Error = io::Error;
+
+    /// Must be cancel safe, as it is raced against the ICE agent. Bytes are
+    /// kept in `pending` across calls, so a cancelled wait loses nothing.
+    fn wait(&mut self) -> LocalBoxFuture<'_, io::Result<usize>> {
+        async move {
+            loop {
+                if let Some(len) = self.complete() {
+                    return Ok(len);
+                }
+                let mut buf = [0; 4096];
+                let n = self.stream.read(&mut buf).await?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.pending.extend_from_slice(&buf[..n]);
+            }
+        }
+        .boxed_local()
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        len: &'a mut usize,
+    ) -> LocalBoxFuture<'a, io::Result<Option<String>>> {
+        async move {
+            let msg: Vec<u8> = self.pending.drain(..4 + *len).skip(4).collect();
+            let msg = String::from_utf8(msg).map_err(io::Error::other)?;
+            Ok(Some(msg))
+        }
+        .boxed_local()
+    }
+}
+
+fn options() -> ConnectOptions {
+    ConnectOptions {
+        channel: "custom-signalling-example".into(),
+        ice_servers: vec![IceServer::new("stun:127.0.0.1:3478")],
+        ..Default::default()
+    }
+}
+
+async fn run() -> Result<Vec<u8>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let (accepted, connected) = tokio::join!(
+        listener.accept(),
+        TcpStream::connect(listener.local_addr()?)
+    );
+    let (a, b) = (
+        TcpSignalling::new(accepted?.0),
+        TcpSignalling::new(connected?),
+    );
+
+    // Which peer dials is up to the signalling, here the one that accepted
+    let (dialer, listener) = tokio::join!(
+        options().connect_psk_with_signalling(a, true),
+        options().connect_psk_with_signalling(b, false),
+    );
+    let (mut dialer, mut listener) = (dialer?, listener?);
+
+    dialer.send(b"found a path").await?;
+    // Values carrying no data, such as state changes, come out as `None`
+    let received = loop {
+        if listener.rx_closed() {
+            return Err("peer left early".into());
+        }
+        let mut value = listener.wait().await?;
+        if let Some(data) = listener.then(&mut value).await? {
+            break data;
+        }
+    };
+
+    let (a, b) = tokio::join!(dialer.close(), listener.close());
+    a?;
+    b?;
+    Ok(received)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let received = run().await?;
+    println!("listener: {}", String::from_utf8_lossy(&received));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn run() {
+        assert_eq!(super::run().await.unwrap(), b"found a path");
+    }
+}
@@ -0,0 +1,97 @@
+//! Peers authenticated by their Ed25519 keys instead of a pre-shared key,
+//! each knows the public key of the other beforehand.
+//!
+//! cargo run --example key_auth
+
+use icepipe::{
+    agreement::Ed25519PairAndPeer,
+    connect::Connection,
+    crypto_backend::{self, Ed25519KeyPair},
+    ice::IceServer,
+    memory_signalling::MemorySignalling,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    ConnectOptions,
+};
+use std::{error::Error, time::Duration};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn generate() -> Result<Ed25519KeyPair> {
+    let mut seed = [0; 32];
+    crypto_backend::fill_random(&mut seed)?;
+    Ok(Ed25519KeyPair::from_seed(&seed)?)
+}
+
+fn options() -> ConnectOptions {
+    ConnectOptions {
+        // Only used to meet on the signalling server, it is not a secret
+        channel: "key-auth-example".into(),
+        ice_servers: vec![IceServer::new("stun:127.0.0.1:3478")],
+        timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    }
+}
+
+/// Connects a pair of peers where the listener expects `listener_peer` as
+/// the public key of the dialer.
+async fn connect(
+    dialer: Ed25519KeyPair,
+    listener: Ed25519KeyPair,
+    listener_peer: Vec<u8>,
+) -> Result<(Connection, Connection)> {
+    let dialer_peer = listener.public_key().to_vec();
+    let (a, b) = MemorySignalling::pair();
+    let (a, b) = tokio::join!(
+        options().connect_with_signalling(a, true, Ed25519PairAndPeer(dialer, dialer_peer)),
+        options().connect_with_signalling(b, false, Ed25519PairAndPeer(listener, listener_peer)),
+    );
+
+    Ok((a?, b?))
+}
+
+async fn run() -> Result<Vec<u8>> {
+    let (dialer, listener) = (generate()?, generate()?);
+    let dialer_public = dialer.public_key().to_vec();
+    let (mut dialer, mut listener) = connect(dialer, listener, dialer_public).await?;
+
+    dialer.send(b"signed and sealed").await?;
+    // Values carrying no data, such as state changes, come out as `None`
+    let received = loop {
+        if listener.rx_closed() {
+            return Err("peer left early".into());
+        }
+        let mut value = listener.wait().await?;
+        if let Some(data) = listener.then(&mut value).await? {
+            break data;
+        }
+    };
+
+    let (a, b) = tokio::join!(dialer.close(), listener.close());
+    a?;
+    b?;
+    Ok(received)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let received = run().await?;
+    println!("listener: {}", String::from_utf8_lossy(&received));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn run() {
+        assert_eq!(super::run().await.unwrap(), b"signed and sealed");
+    }
+
+    #[tokio::test]
+    async fn unknown_peer() {
+        let impostor = generate().unwrap().public_key().to_vec();
+        let r = connect(generate().unwrap(), generate().unwrap(), impostor).await;
+        assert!(r.is_err());
+    }
+}
@@ -0,0 +1,83 @@
+//! Two libp2p nodes in the same process pinging each other over icepipe.
+//!
+//! `cargo run --example libp2p_ping --features libp2p [psk]`
+//!
+//! The nodes secure the connection with noise, so `plaintext` is set on the
+//! transport to avoid encrypting twice.
+
+use futures::StreamExt;
+use icepipe::{
+    channel::Psk,
+    libp2p::{multiaddr, IcepipeTransport},
+    ConnectOptions,
+};
+use libp2p::{
+    core::upgrade,
+    noise, ping,
+    swarm::{Swarm, SwarmEvent},
+    yamux, SwarmBuilder, Transport,
+};
+use std::time::Duration;
+use tokio::select;
+
+fn node(psk: &Psk) -> Swarm<ping::Behaviour> {
+    SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_other_transport(|key| {
+            let mut transport = IcepipeTransport::new(ConnectOptions {
+                channel: psk.clone().into(),
+                ..Default::default()
+            });
+            transport.plaintext = true;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                transport
+                    .upgrade(upgrade::Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()),
+            )
+        })
+        .unwrap()
+        .with_behaviour(|_| ping::Behaviour::default())
+        .unwrap()
+        .with_swarm_config(|config| config.with_idle_connection_timeout(Duration::MAX))
+        .build()
+}
+
+fn pinged<E: std::fmt::Debug>(name: &str, event: SwarmEvent<E>) -> bool {
+    match event {
+        SwarmEvent::Behaviour(event) => {
+            println!("{name}: {event:?}");
+            true
+        }
+        event => {
+            println!("{name}: {event:?}");
+            false
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let psk = Psk::new(
+        std::env::args()
+            .nth(1)
+            .unwrap_or_else(|| "icepipe-libp2p-ping".to_string()),
+    );
+    let addr = multiaddr(&psk.channel());
+
+    let mut listener = node(&psk);
+    let mut dialer = node(&psk);
+    listener.listen_on(addr.clone()).unwrap();
+    dialer.dial(addr).unwrap();
+
+    let mut pings = 0;
+    while pings < 4 {
+        let pinged = select! {
+            event = listener.select_next_some() => pinged("listener", event),
+            event = dialer.select_next_some() => pinged("dialer", event),
+        };
+        if pinged {
+            pings += 1;
+        }
+    }
+}
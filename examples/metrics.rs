@@ -0,0 +1,52 @@
+//! Runs a loopback transfer forever while exposing the collected metrics on
+//! http://127.0.0.1:9000/metrics
+//!
+//! cargo run --example metrics --features metrics
+
+use icepipe::{
+    ice::IceServer,
+    memory_signalling::MemorySignalling,
+    pipe_stream::{Control, PipeStream, StreamResult, WaitThen},
+    ConnectOptions,
+};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::time::Duration;
+
+fn options() -> ConnectOptions {
+    ConnectOptions {
+        channel: "metrics-example".into(),
+        ice_servers: vec![IceServer::new("stun:127.0.0.1:3478")],
+        ..Default::default()
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> StreamResult<()> {
+    PrometheusBuilder::new()
+        .with_http_listener(([127, 0, 0, 1], 9000))
+        .install()
+        .expect("failed to install Prometheus exporter");
+    println!("Serving metrics on http://127.0.0.1:9000/metrics");
+
+    loop {
+        let (a, b) = MemorySignalling::pair();
+        let (dialer, listener) = tokio::join!(
+            options().connect_psk_with_signalling(a, true),
+            options().connect_psk_with_signalling(b, false),
+        );
+        let (mut dialer, mut listener) = (dialer?, listener?);
+
+        let payload = vec![0x55; 1024];
+        for _ in 0..64 {
+            dialer.send(&payload).await?;
+            let mut value = listener.wait().await?;
+            listener.then(&mut value).await?;
+        }
+
+        let (a, b) = tokio::join!(dialer.close(), listener.close());
+        a?;
+        b?;
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
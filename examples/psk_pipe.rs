@@ -0,0 +1,95 @@
+//! Two tasks of the same process connecting with a pre-shared key and
+//! exchanging messages, signalling through memory instead of a server.
+//!
+//! cargo run --example psk_pipe
+
+use icepipe::{
+    connect::Connection,
+    ice::IceServer,
+    memory_signalling::MemorySignalling,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    ConnectOptions,
+};
+use std::error::Error;
+use tokio::task::LocalSet;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn options() -> ConnectOptions {
+    ConnectOptions {
+        // Also the pre-shared key, both peers must use the same one
+        channel: "psk-pipe-example".into(),
+        ice_servers: vec![IceServer::new("stun:127.0.0.1:3478")],
+        ..Default::default()
+    }
+}
+
+async fn recv(connection: &mut Connection) -> Result<Option<Vec<u8>>> {
+    loop {
+        if connection.rx_closed() {
+            return Ok(None);
+        }
+        let mut value = connection.wait().await?;
+        if let Some(data) = connection.then(&mut value).await? {
+            return Ok(Some(data));
+        }
+    }
+}
+
+async fn dialer(signalling: MemorySignalling) -> Result<()> {
+    let mut connection = options()
+        .connect_psk_with_signalling(signalling, true)
+        .await?;
+
+    for word in ["ping", "pong", "bye"] {
+        connection.send(word.as_bytes()).await?;
+        let echo = recv(&mut connection).await?.ok_or("peer left early")?;
+        println!("dialer: {}", String::from_utf8_lossy(&echo));
+    }
+
+    connection.close().await?;
+    Ok(())
+}
+
+/// Echoes everything back until the dialer closes.
+async fn listener(signalling: MemorySignalling) -> Result<usize> {
+    let mut connection = options()
+        .connect_psk_with_signalling(signalling, false)
+        .await?;
+
+    let mut echoed = 0;
+    while let Some(data) = recv(&mut connection).await? {
+        connection.send(&data).await?;
+        echoed += 1;
+    }
+
+    connection.close().await?;
+    Ok(echoed)
+}
+
+/// Connections are not `Send`, so both peers run on a `LocalSet`.
+async fn run() -> Result<usize> {
+    let (a, b) = MemorySignalling::pair();
+    let tasks = LocalSet::new();
+    let dialer = tasks.spawn_local(async move { dialer(a).await.map_err(|e| e.to_string()) });
+    let listener = tasks.spawn_local(async move { listener(b).await.map_err(|e| e.to_string()) });
+    tasks.await;
+
+    dialer.await??;
+    Ok(listener.await??)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let echoed = run().await?;
+    println!("listener echoed {echoed} messages");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn run() {
+        assert_eq!(super::run().await.unwrap(), 3);
+    }
+}
@@ -0,0 +1,105 @@
+//! Streams composed on top of a connection: text is read one line per
+//! message, sent through a bounded queue so receiving is never held back by
+//! a slow link, and the traffic is reported once done.
+//!
+//! cargo run --example wrapped_stream
+
+use icepipe::{
+    async_pipe_stream::{AsyncPipeStream, Framing},
+    connect::Connection,
+    ice::IceServer,
+    memory_signalling::MemorySignalling,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    queued_stream::{DropPolicy, QueueConfig, QueuedStream},
+    ConnectOptions,
+};
+use std::error::Error;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+const TEXT: &[u8] = b"The first line\nThe second line\nA last line without newline";
+
+fn options() -> ConnectOptions {
+    ConnectOptions {
+        channel: "wrapped-stream-example".into(),
+        ice_servers: vec![IceServer::new("stun:127.0.0.1:3478")],
+        ..Default::default()
+    }
+}
+
+async fn send_lines(connection: Connection) -> Result<()> {
+    let mut input = AsyncPipeStream::new(TEXT, tokio::io::sink())
+        .with_framing(Framing::Lines { max_len: 1024 });
+    let mut output = QueuedStream::new(
+        connection,
+        QueueConfig {
+            max_bytes: 64 * 1024,
+            policy: DropPolicy::Block,
+        },
+    );
+
+    while !input.rx_closed() {
+        let mut value = input.wait().await?;
+        if let Some(line) = input.then(&mut value).await? {
+            output.send(&line).await?;
+        }
+    }
+
+    // Sends what is still queued first
+    output.close().await?;
+    println!("dialer: {:?}", output.stats());
+    Ok(())
+}
+
+async fn receive_lines(connection: &mut Connection) -> Result<Vec<String>> {
+    let mut lines = vec![];
+    while !connection.rx_closed() {
+        let mut value = connection.wait().await?;
+        if let Some(line) = connection.then(&mut value).await? {
+            lines.push(String::from_utf8(line)?);
+        }
+    }
+
+    Ok(lines)
+}
+
+async fn run() -> Result<Vec<String>> {
+    let (a, b) = MemorySignalling::pair();
+    let (dialer, listener) = tokio::join!(
+        options().connect_psk_with_signalling(a, true),
+        options().connect_psk_with_signalling(b, false),
+    );
+    let (dialer, mut listener) = (dialer?, listener?);
+
+    let (sent, received) = tokio::join!(send_lines(dialer), receive_lines(&mut listener));
+    sent?;
+    let lines = received?;
+    println!("listener: {:?}", listener.stats());
+    listener.close().await?;
+
+    Ok(lines)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    for line in run().await? {
+        print!("{line}");
+    }
+    println!();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn run() {
+        assert_eq!(
+            super::run().await.unwrap(),
+            [
+                "The first line\n",
+                "The second line\n",
+                "A last line without newline"
+            ]
+        );
+    }
+}
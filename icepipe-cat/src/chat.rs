@@ -0,0 +1,108 @@
+//! Line based chat, `--chat`, to check a channel works end to end by hand.
+//! Every line typed is sent as a message and every message received is
+//! printed after [`PEER`]. Lines about the connection itself start with
+//! [`STATUS`].
+
+use icepipe::pipe_stream::{CloseReason, PipeStream, StreamError, StreamResult};
+use std::io::Write;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt},
+    select,
+};
+
+const PEER: &str = "peer> ";
+const STATUS: &str = "* ";
+
+/// Until either side closes, the input ending closes from this side.
+pub async fn chat<S, I, O>(
+    peer: &mut S,
+    dialer: bool,
+    input: I,
+    mut output: O,
+) -> StreamResult<Option<CloseReason>>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+    I: AsyncBufRead + Unpin,
+    O: Write,
+{
+    let role = match dialer {
+        true => "dialer",
+        false => "listener",
+    };
+    writeln!(
+        output,
+        "{STATUS}Connected as the {role}, lines typed are sent, end the input to close"
+    )?;
+    output.flush()?;
+
+    let mut lines = input.lines();
+    while !peer.rx_closed() {
+        select! {
+            value = peer.wait() => {
+                let mut value = value.map_err(Into::into)?;
+                if let Some(data) = peer.then(&mut value).await.map_err(Into::into)? {
+                    writeln!(output, "{PEER}{}", String::from_utf8_lossy(&data))?;
+                    output.flush()?;
+                }
+            }
+            line = lines.next_line() => match line? {
+                Some(line) => peer.send(line.as_bytes()).await.map_err(Into::into)?,
+                None => {
+                    writeln!(output, "{STATUS}Closing")?;
+                    output.flush()?;
+                    peer.close().await.map_err(Into::into)?;
+                    return Ok(peer.close_reason());
+                }
+            },
+        }
+    }
+
+    writeln!(output, "{STATUS}Peer closed the connection")?;
+    output.flush()?;
+    peer.close().await.map_err(Into::into)?;
+
+    Ok(peer.close_reason())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use icepipe::{memory_signalling::MemorySignalling, ConnectOptions};
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn lines_both_ways() {
+        let options = ConnectOptions {
+            channel: "chat".into(),
+            ice: vec!["stun:127.0.0.1:3478".to_string()],
+            ..Default::default()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            options.clone().connect_psk_with_signalling(a, true),
+            options.connect_psk_with_signalling(b, false),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        // The listener never types, its input stays open
+        let (_typing, idle) = tokio::io::duplex(64);
+        let (mut a_output, mut b_output) = (Vec::new(), Vec::new());
+        let (a_reason, b_reason) = tokio::join!(
+            chat(&mut a, true, &b"hello\nbye\n"[..], &mut a_output),
+            chat(&mut b, false, BufReader::new(idle), &mut b_output),
+        );
+        a_reason.unwrap();
+        assert!(b_reason.unwrap().is_some_and(|reason| reason.is_clean()));
+
+        let a_output = String::from_utf8(a_output).unwrap();
+        assert!(a_output.starts_with("* Connected as the dialer"));
+        assert!(a_output.ends_with("* Closing\n"));
+        let b_output = String::from_utf8(b_output).unwrap();
+        assert!(b_output.starts_with("* Connected as the listener"));
+        assert!(
+            b_output.ends_with("peer> hello\npeer> bye\n* Peer closed the connection\n"),
+            "{b_output}"
+        );
+    }
+}
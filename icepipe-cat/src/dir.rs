@@ -0,0 +1,592 @@
+//! Directory transfer, `send-dir` streams a tar archive of the directory and
+//! `recv-dir` unpacks it.
+//!
+//! Every message starts with a tag byte: the hello, archive data, per-file
+//! progress events and the SHA-256 of all archive data, which the receiver
+//! acknowledges once it verified it.
+//!
+//! The receiver spools the archive to a temporary file and only unpacks it
+//! once the hash matches, into a staging directory whose entries are then
+//! moved into the destination. A corrupted or interrupted transfer leaves
+//! nothing behind.
+
+use clap::ValueEnum;
+use icepipe::pipe_stream::{PipeStream, StreamError, StreamResult};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+use tokio::{sync::mpsc, task::spawn_blocking};
+
+const HELLO: &[u8] = b"icepipe-dir";
+const TAG_HELLO: u8 = b'h';
+const TAG_DATA: u8 = b'd';
+const TAG_FILE: u8 = b'f';
+const TAG_END: u8 = b'e';
+const ACK_OK: &[u8] = b"ok";
+const ACK_BAD: &[u8] = b"bad";
+pub(crate) const CHUNK: usize = 4000;
+/// Compressed archives at least this share of their size are reported as
+/// not worth compressing.
+const POOR_RATIO: f64 = 0.9;
+
+/// What to do with symbolic links found in the directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Symlinks {
+    /// Sends the link itself.
+    #[default]
+    Preserve,
+    /// Sends the file or directory the link points to.
+    Follow,
+    Skip,
+}
+
+/// What to do with fifos, sockets and devices found in the directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SpecialFiles {
+    /// Aborts the transfer.
+    #[default]
+    Error,
+    Skip,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    /// Matched against both the path relative to the directory and the file
+    /// name.
+    pub exclude: Vec<glob::Pattern>,
+    pub symlinks: Symlinks,
+    pub special_files: SpecialFiles,
+    pub zstd: bool,
+}
+
+/// Archive bytes against what was sent of them, after compression.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SentDir {
+    pub archived: u64,
+    pub sent: u64,
+}
+impl SentDir {
+    /// Sent over archived bytes, below 1 when compression helped.
+    pub fn compression_ratio(&self) -> f64 {
+        match self.archived {
+            0 => 1.0,
+            archived => self.sent as f64 / archived as f64,
+        }
+    }
+}
+
+enum Event {
+    Data(Vec<u8>),
+    File(String, u64),
+}
+
+pub async fn send_dir<S>(peer: &mut S, path: PathBuf, options: SendOptions) -> StreamResult<SentDir>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let compression: &[u8] = match options.zstd {
+        true => b"zstd",
+        false => b"none",
+    };
+    send(peer, TAG_HELLO, &[HELLO, b"\0", compression].concat()).await?;
+
+    let zstd = options.zstd;
+    let (tx, mut rx) = mpsc::channel(16);
+    let archive = spawn_blocking(move || archive(&path, &options, tx));
+
+    let mut hash = Sha256::new();
+    let mut sent = 0;
+    while let Some(event) = rx.recv().await {
+        match event {
+            Event::Data(data) => {
+                hash.update(&data);
+                send(peer, TAG_DATA, &data).await?;
+                sent += data.len() as u64;
+            }
+            Event::File(name, size) => {
+                log::info!("Sending {name} ({size} bytes)");
+                let progress = format!("{size}\0{name}");
+                send(peer, TAG_FILE, progress.as_bytes()).await?;
+            }
+        }
+    }
+    let archived = archive.await.map_err(io::Error::from)??;
+    send(peer, TAG_END, &hash.finalize()).await?;
+    let summary = SentDir { archived, sent };
+    if zstd {
+        report_compression(&summary);
+    }
+
+    match recv(peer).await?.as_slice() {
+        ACK_OK => Ok(summary),
+        ACK_BAD => Err(invalid_data("Peer received a corrupted archive")),
+        _ => Err(invalid_data("Unexpected acknowledgement")),
+    }
+}
+
+pub async fn recv_dir<S>(peer: &mut S, dest: PathBuf) -> StreamResult<()>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let hello = recv(peer).await?;
+    let zstd = match hello.split_first() {
+        Some((&TAG_HELLO, hello)) if hello == [HELLO, b"\0none"].concat() => false,
+        Some((&TAG_HELLO, hello)) if hello == [HELLO, b"\0zstd"].concat() => true,
+        _ => return Err(invalid_data("Peer is not sending a directory")),
+    };
+    if zstd && !cfg!(feature = "zstd") {
+        return Err(invalid_data(
+            "Peer sends zstd compressed data, not supported by this build",
+        ));
+    }
+
+    fs::create_dir_all(&dest)?;
+    let (tx, rx) = mpsc::channel(16);
+    let spool_dir = dest.clone();
+    let spool = spawn_blocking(move || spool(ChannelReader::new(rx), &spool_dir));
+
+    let mut hash = Sha256::new();
+    let expected = loop {
+        let message = recv(peer).await?;
+        match message.split_first() {
+            Some((&TAG_DATA, data)) => {
+                hash.update(data);
+                // Only fails if spooling did, reported once the archive ends
+                let _ = tx.send(data.to_owned()).await;
+            }
+            Some((&TAG_FILE, progress)) => {
+                let progress = String::from_utf8_lossy(progress);
+                if let Some((size, name)) = progress.split_once('\0') {
+                    log::info!("Receiving {name} ({size} bytes)");
+                }
+            }
+            Some((&TAG_END, expected)) => break expected.to_owned(),
+            _ => return Err(invalid_data("Unexpected directory transfer message")),
+        }
+    };
+    drop(tx);
+    let spooled = spool.await.map_err(io::Error::from)?;
+
+    let intact = hash.finalize().as_slice() == expected;
+    let unpacked = match (spooled, intact) {
+        (Ok(archive), true) => spawn_blocking(move || unpack(archive, zstd, &dest))
+            .await
+            .map_err(io::Error::from)?,
+        (spooled, _) => spooled.map(drop),
+    };
+    let ack = match intact && unpacked.is_ok() {
+        true => ACK_OK,
+        false => ACK_BAD,
+    };
+    peer.send(ack).await.map_err(Into::into)?;
+    unpacked?;
+    if !intact {
+        return Err(invalid_data("Archive hash mismatch, nothing was unpacked"));
+    }
+
+    Ok(())
+}
+
+fn report_compression(summary: &SentDir) {
+    let ratio = summary.compression_ratio();
+    log::info!(
+        "Compressed the {} bytes archive to {} bytes, {:.1}% of its size",
+        summary.archived,
+        summary.sent,
+        ratio * 100.0
+    );
+    if ratio >= POOR_RATIO {
+        log::info!("The directory barely compresses, --zstd only costs CPU time for it");
+    }
+}
+
+/// Returns the size of the archive, before compression.
+fn archive(path: &Path, options: &SendOptions, tx: mpsc::Sender<Event>) -> io::Result<u64> {
+    let writer = ChannelWriter {
+        tx: tx.clone(),
+        buf: Vec::with_capacity(CHUNK),
+    };
+    let writer: Box<dyn Write> = match options.zstd {
+        #[cfg(feature = "zstd")]
+        true => Box::new(zstd::Encoder::new(writer, 0)?.auto_finish()),
+        #[cfg(not(feature = "zstd"))]
+        true => return Err(io::Error::other("zstd support was not compiled in")),
+        false => Box::new(writer),
+    };
+
+    let mut builder = tar::Builder::new(Counting {
+        inner: writer,
+        written: 0,
+    });
+    builder.follow_symlinks(options.symlinks == Symlinks::Follow);
+    walk(&mut builder, path, Path::new(""), options, &tx)?;
+    let mut writer = builder.into_inner()?;
+    writer.flush()?;
+
+    Ok(writer.written)
+}
+
+fn walk<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    options: &SendOptions,
+    tx: &mpsc::Sender<Event>,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = name.join(entry.file_name());
+        if options.exclude.iter().any(|pattern| {
+            pattern.matches_path(&name) || pattern.matches(&entry.file_name().to_string_lossy())
+        }) {
+            continue;
+        }
+
+        let mut metadata = fs::symlink_metadata(&path)?;
+        if metadata.is_symlink() {
+            match options.symlinks {
+                Symlinks::Preserve => {}
+                Symlinks::Follow => metadata = fs::metadata(&path)?,
+                Symlinks::Skip => {
+                    log::info!("Skipping symlink {}", name.display());
+                    continue;
+                }
+            }
+        }
+
+        if metadata.is_dir() {
+            builder.append_dir(&name, &path)?;
+            walk(builder, &path, &name, options, tx)?;
+        } else if metadata.is_file() || metadata.is_symlink() {
+            let event = Event::File(name.to_string_lossy().into_owned(), metadata.len());
+            tx.blocking_send(event).map_err(|_| broken_pipe())?;
+            builder.append_path_with_name(&path, &name)?;
+        } else {
+            match options.special_files {
+                SpecialFiles::Error => {
+                    return Err(io::Error::other(format!(
+                        "{} is a special file, pass --special-files skip to leave it out",
+                        name.display()
+                    )))
+                }
+                SpecialFiles::Skip => log::info!("Skipping special file {}", name.display()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the archive to an unnamed temporary file in `dir`, removed once
+/// closed.
+fn spool(mut reader: ChannelReader, dir: &Path) -> io::Result<File> {
+    let mut file = tempfile::tempfile_in(dir)?;
+    io::copy(&mut reader, &mut file)?;
+    file.rewind()?;
+
+    Ok(file)
+}
+
+/// Unpacks into a staging directory in `dest`, moved into place once the
+/// whole archive unpacked.
+fn unpack(archive: File, zstd: bool, dest: &Path) -> io::Result<()> {
+    let reader: Box<dyn Read> = match zstd {
+        #[cfg(feature = "zstd")]
+        true => Box::new(zstd::Decoder::new(archive)?),
+        _ => Box::new(archive),
+    };
+
+    let staging = tempfile::Builder::new()
+        .prefix(".icepipe-recv-dir")
+        .tempdir_in(dest)?;
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.unpack(staging.path())?;
+
+    move_into(staging.path(), dest)
+}
+
+/// Renames the entries of `from` into `to`, merging directories present in
+/// both and replacing anything else.
+fn move_into(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let merge = entry.file_type()?.is_dir()
+            && fs::symlink_metadata(&target).is_ok_and(|target| target.is_dir());
+        match merge {
+            true => move_into(&entry.path(), &target)?,
+            false => fs::rename(entry.path(), &target)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Counts the bytes written through it.
+struct Counting<W> {
+    inner: W,
+    written: u64,
+}
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(data)?;
+        self.written += n as u64;
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ChannelWriter {
+    tx: mpsc::Sender<Event>,
+    buf: Vec<u8>,
+}
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(CHUNK - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == CHUNK {
+            self.flush()?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let data = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK));
+        self.tx
+            .blocking_send(Event::Data(data))
+            .map_err(|_| broken_pipe())
+    }
+}
+impl Drop for ChannelWriter {
+    /// Compression writes its trailer when dropped, after the last flush.
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+impl ChannelReader {
+    fn new(rx: mpsc::Receiver<Vec<u8>>) -> ChannelReader {
+        ChannelReader {
+            rx,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.rx.blocking_recv() {
+                Some(data) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..][..n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+pub(crate) async fn send<S>(peer: &mut S, tag: u8, data: &[u8]) -> StreamResult<()>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let message = [&[tag], data].concat();
+    peer.send(&message).await.map_err(Into::into)
+}
+
+pub(crate) async fn recv<S>(peer: &mut S) -> StreamResult<Vec<u8>>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    loop {
+        if peer.rx_closed() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut value = peer.wait().await.map_err(Into::into)?;
+        if let Some(data) = peer.then(&mut value).await.map_err(Into::into)? {
+            return Ok(data);
+        }
+    }
+}
+
+pub(crate) fn invalid_data(message: &str) -> StreamError {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string()).into()
+}
+
+pub(crate) fn broken_pipe() -> io::Error {
+    io::ErrorKind::BrokenPipe.into()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use icepipe::{connect::Connection, memory_signalling::MemorySignalling, ConnectOptions};
+    use std::{
+        os::unix::fs::{symlink, PermissionsExt},
+        time::UNIX_EPOCH,
+    };
+
+    fn tree(root: &Path) {
+        let nested = root.join("a dir/ünïcödé/deeper");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.join("top.txt"), b"top").unwrap();
+        fs::write(root.join("a dir/with spaces & 'quotes'.txt"), b"spaces").unwrap();
+        fs::write(nested.join("x".repeat(120)), vec![7; 100_000]).unwrap();
+        fs::write(nested.join("excluded.tmp"), b"tmp").unwrap();
+        fs::set_permissions(root.join("top.txt"), fs::Permissions::from_mode(0o751)).unwrap();
+        symlink("../top.txt", root.join("a dir/link")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        check_round_trip(false).await;
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn round_trip_zstd() {
+        check_round_trip(true).await;
+    }
+
+    async fn pair() -> (Connection, Connection) {
+        let options = ConnectOptions {
+            channel: "send-dir".into(),
+            ice: vec!["stun:127.0.0.1:3478".to_string()],
+            ..Default::default()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            options.clone().connect_psk_with_signalling(a, true),
+            options.connect_psk_with_signalling(b, false),
+        );
+        (a.unwrap(), b.unwrap())
+    }
+
+    async fn check_round_trip(zstd: bool) {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        tree(src.path());
+        // Merged with the received directory of the same name
+        fs::create_dir(dst.path().join("a dir")).unwrap();
+        fs::write(dst.path().join("a dir/kept.txt"), b"kept").unwrap();
+        let (mut a, mut b) = pair().await;
+
+        let options = SendOptions {
+            exclude: vec![glob::Pattern::new("*.tmp").unwrap()],
+            zstd,
+            ..Default::default()
+        };
+        let (sent, received) = tokio::join!(
+            send_dir(&mut a, src.path().to_owned(), options),
+            recv_dir(&mut b, dst.path().to_owned()),
+        );
+        let sent = sent.unwrap();
+        received.unwrap();
+        match zstd {
+            // Mostly the 100 kB of a single byte
+            true => assert!(sent.compression_ratio() < 0.1),
+            false => assert_eq!(sent.sent, sent.archived),
+        }
+
+        let dst = dst.path();
+        assert_eq!(fs::read(dst.join("top.txt")).unwrap(), b"top");
+        assert_eq!(
+            fs::metadata(dst.join("top.txt"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o751
+        );
+        assert_eq!(
+            fs::read(dst.join("a dir/with spaces & 'quotes'.txt")).unwrap(),
+            b"spaces"
+        );
+        assert_eq!(fs::read(dst.join("a dir/kept.txt")).unwrap(), b"kept");
+        let nested = dst.join("a dir/ünïcödé/deeper");
+        assert_eq!(
+            fs::read(nested.join("x".repeat(120))).unwrap(),
+            vec![7; 100_000]
+        );
+        assert!(!nested.join("excluded.tmp").exists());
+        assert_eq!(
+            fs::read_link(dst.join("a dir/link")).unwrap(),
+            Path::new("../top.txt")
+        );
+        let mtime = |path: &Path| {
+            let modified = fs::metadata(path).unwrap().modified().unwrap();
+            modified.duration_since(UNIX_EPOCH).unwrap().as_secs()
+        };
+        assert_eq!(
+            mtime(&dst.join("top.txt")),
+            mtime(&src.path().join("top.txt"))
+        );
+        let staged = fs::read_dir(dst).unwrap().filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_string_lossy().starts_with(".icepipe-recv")
+        });
+        assert_eq!(staged.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn corrupted_archive() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        tree(src.path());
+        let (tx, mut rx) = mpsc::channel(16);
+        let path = src.path().to_owned();
+        let options = SendOptions::default();
+        spawn_blocking(move || archive(&path, &options, tx));
+        let (mut a, mut b) = pair().await;
+
+        let tampered = async {
+            send(&mut a, TAG_HELLO, &[HELLO, b"\0none"].concat())
+                .await
+                .unwrap();
+            while let Some(event) = rx.recv().await {
+                if let Event::Data(data) = event {
+                    send(&mut a, TAG_DATA, &data).await.unwrap();
+                }
+            }
+            send(&mut a, TAG_END, &[0; 32]).await.unwrap();
+            recv(&mut a).await.unwrap()
+        };
+        let (ack, received) = tokio::join!(tampered, recv_dir(&mut b, dst.path().to_owned()));
+        assert_eq!(ack, ACK_BAD);
+        assert!(received.is_err());
+        assert_eq!(fs::read_dir(dst.path()).unwrap().count(), 0);
+    }
+}
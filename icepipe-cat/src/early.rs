@@ -0,0 +1,118 @@
+//! Local input read while the connection is still being established, so a
+//! producer piping into icepipe-cat does not block on a full pipe during the
+//! handshake. What was read is sent first once connected.
+
+use std::io;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    select,
+    sync::oneshot,
+    task::JoinHandle,
+};
+
+/// Reads ahead from `input` on a task of its own until
+/// [`EarlyInput::finish`]. Reading pauses once `capacity` bytes are
+/// buffered, nothing is dropped.
+pub struct EarlyInput<R> {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<io::Result<(Vec<u8>, R)>>,
+}
+impl<R> EarlyInput<R>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    pub fn spawn(input: R, capacity: usize) -> EarlyInput<R> {
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(read_ahead(input, capacity, stopped));
+
+        EarlyInput { stop, task }
+    }
+
+    /// What was buffered followed by the rest of the input.
+    pub async fn finish(self) -> io::Result<impl AsyncRead> {
+        let _ = self.stop.send(());
+        let (buffer, input) = self.task.await.map_err(io::Error::other)??;
+        log::debug!("{} bytes of input read while connecting", buffer.len());
+
+        Ok(io::Cursor::new(buffer).chain(input))
+    }
+}
+
+async fn read_ahead<R>(
+    mut input: R,
+    capacity: usize,
+    mut stopped: oneshot::Receiver<()>,
+) -> io::Result<(Vec<u8>, R)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0; 64 * 1024];
+    while buffer.len() < capacity {
+        let len = chunk.len().min(capacity - buffer.len());
+        // Reading is cancel safe, a read interrupted by the stop is resumed
+        // once forwarding
+        let n = select! {
+            biased;
+            _ = &mut stopped => return Ok((buffer, input)),
+            n = input.read(&mut chunk[..len]) => n?,
+        };
+        if n == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+
+    let _ = stopped.await;
+    Ok((buffer, input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn nothing_lost() {
+        let data = (0..1024 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let (mut producer, input) = tokio::io::duplex(4096);
+        let early = EarlyInput::spawn(input, 64 * 1024);
+
+        let written = data.clone();
+        let producer = tokio::spawn(async move {
+            producer.write_all(&written).await.unwrap();
+        });
+        // A slow connect, the producer fills the buffer meanwhile and then
+        // waits for it to drain
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!producer.is_finished());
+
+        let mut received = Vec::new();
+        early
+            .finish()
+            .await
+            .unwrap()
+            .read_to_end(&mut received)
+            .await
+            .unwrap();
+        producer.await.unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn input_ended_while_connecting() {
+        let early = EarlyInput::spawn(&b"early"[..], 1024);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut received = Vec::new();
+        early
+            .finish()
+            .await
+            .unwrap()
+            .read_to_end(&mut received)
+            .await
+            .unwrap();
+        assert_eq!(received, b"early");
+    }
+}
@@ -0,0 +1,710 @@
+//! Multiple file transfer, `send-files` streams files and directories one by
+//! one and `recv-files` recreates them.
+//!
+//! Every message starts with a tag byte: the hello, the number of files and
+//! bytes about to be sent, an entry header with the path, size, mode and
+//! mtime followed by the file data, and the end, which the receiver
+//! acknowledges once everything is written. Paths are relative and use `/`,
+//! the receiver refuses anything that would leave its destination.
+
+use crate::dir::{broken_pipe, invalid_data, recv, send, CHUNK};
+use icepipe::pipe_stream::{PipeStream, StreamError, StreamResult};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task::spawn_blocking};
+
+const HELLO: &[u8] = b"icepipe-files";
+const TAG_HELLO: u8 = b'h';
+const TAG_TOTAL: u8 = b't';
+const TAG_ENTRY: u8 = b'n';
+const TAG_DATA: u8 = b'd';
+const TAG_END: u8 = b'e';
+const KIND_DIR: u8 = b'd';
+const KIND_FILE: u8 = b'f';
+const ACK_OK: &[u8] = b"ok";
+const ACK_BAD: &[u8] = b"bad";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Header {
+    /// Relative to the destination, separated by `/`.
+    path: String,
+    dir: bool,
+    mode: u32,
+    /// Seconds since the Unix epoch.
+    mtime: u64,
+    size: u64,
+}
+impl Header {
+    const LEN: usize = 1 + 4 + 8 + 8;
+
+    fn encode(&self) -> Vec<u8> {
+        let kind = match self.dir {
+            true => KIND_DIR,
+            false => KIND_FILE,
+        };
+        [
+            &[kind][..],
+            &self.mode.to_be_bytes(),
+            &self.mtime.to_be_bytes(),
+            &self.size.to_be_bytes(),
+            self.path.as_bytes(),
+        ]
+        .concat()
+    }
+
+    fn decode(data: &[u8]) -> Option<Header> {
+        if data.len() < Header::LEN {
+            return None;
+        }
+        let (fixed, path) = data.split_at(Header::LEN);
+        let dir = match fixed[0] {
+            KIND_DIR => true,
+            KIND_FILE => false,
+            _ => return None,
+        };
+
+        Some(Header {
+            path: String::from_utf8(path.to_owned()).ok()?,
+            dir,
+            mode: u32::from_be_bytes(fixed[1..5].try_into().ok()?),
+            mtime: u64::from_be_bytes(fixed[5..13].try_into().ok()?),
+            size: u64::from_be_bytes(fixed[13..21].try_into().ok()?),
+        })
+    }
+
+    fn mtime(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.mtime)
+    }
+}
+
+struct Entry {
+    source: PathBuf,
+    header: Header,
+}
+
+/// Bytes the peer received but did not write yet past which the progress
+/// tells the peer is still applying them.
+const REMOTE_LAG_HIGH: usize = 512 * 1024;
+
+/// `remote_lag` tells how far the peer is behind, e.g.
+/// [`Connection::remote_lag_bytes`](icepipe::connect::Connection::remote_lag_bytes).
+pub async fn send_files<S, L>(peer: &mut S, paths: Vec<PathBuf>, remote_lag: L) -> StreamResult<()>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+    L: Fn(&S) -> Option<usize>,
+{
+    let entries = spawn_blocking(move || collect(&paths))
+        .await
+        .map_err(io::Error::from)??;
+    let mut progress = Progress::new(&entries);
+    send(peer, TAG_HELLO, HELLO).await?;
+    let total = [
+        progress.total_files.to_be_bytes(),
+        progress.total_bytes.to_be_bytes(),
+    ];
+    send(peer, TAG_TOTAL, &total.concat()).await?;
+
+    let files = entries
+        .iter()
+        .filter(|entry| !entry.header.dir)
+        .map(|entry| (entry.source.clone(), entry.header.size))
+        .collect();
+    let (tx, mut rx) = mpsc::channel(16);
+    let reader = spawn_blocking(move || read_files(files, tx));
+
+    for Entry { header, .. } in &entries {
+        send(peer, TAG_ENTRY, &header.encode()).await?;
+        progress.entry("Sending", header)?;
+        while progress.remaining > 0 {
+            let Some(data) = rx.recv().await else {
+                reader.await.map_err(io::Error::from)??;
+                return Err(broken_pipe().into());
+            };
+            progress.data(data.len())?;
+            send(peer, TAG_DATA, &data).await?;
+            progress.remote_lag(remote_lag(peer));
+        }
+    }
+    reader.await.map_err(io::Error::from)??;
+    send(peer, TAG_END, &[]).await?;
+    if progress.lagging {
+        log::info!("All sent, remote applying…");
+    }
+
+    match recv(peer).await?.as_slice() {
+        ACK_OK => Ok(()),
+        ACK_BAD => Err(invalid_data("Peer could not write the files")),
+        _ => Err(invalid_data("Unexpected acknowledgement")),
+    }
+}
+
+pub async fn recv_files<S>(peer: &mut S, dest: PathBuf) -> StreamResult<()>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    match recv(peer).await?.split_first() {
+        Some((&TAG_HELLO, HELLO)) => {}
+        _ => return Err(invalid_data("Peer is not sending files")),
+    }
+    let total = recv(peer).await?;
+    let (total_files, total_bytes) = match total.split_first() {
+        Some((&TAG_TOTAL, total)) if total.len() == 16 => {
+            let (files, bytes) = total.split_at(8);
+            (
+                u64::from_be_bytes(files.try_into().unwrap()),
+                u64::from_be_bytes(bytes.try_into().unwrap()),
+            )
+        }
+        _ => return Err(invalid_data("Unexpected file transfer message")),
+    };
+
+    let (tx, rx) = mpsc::channel(16);
+    let writer = spawn_blocking(move || write_files(&dest, rx));
+    let mut progress = Progress {
+        total_files,
+        total_bytes,
+        ..Default::default()
+    };
+    let received = receive_entries(peer, &tx, &mut progress).await;
+    drop(tx);
+    let written = writer.await.map_err(io::Error::from)?;
+
+    // A failed write also fails receiving, report the cause
+    let r = written.map_err(StreamError::from).and(received);
+    let ack = match r.is_ok() {
+        true => ACK_OK,
+        false => ACK_BAD,
+    };
+    let acked = peer.send(ack).await.map_err(Into::into);
+    r?;
+
+    acked
+}
+
+async fn receive_entries<S>(
+    peer: &mut S,
+    tx: &mpsc::Sender<Op>,
+    progress: &mut Progress,
+) -> StreamResult<()>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    loop {
+        let message = recv(peer).await?;
+        let op = match message.split_first() {
+            Some((&TAG_ENTRY, header)) => {
+                let header =
+                    Header::decode(header).ok_or_else(|| invalid_data("Malformed entry header"))?;
+                let path = safe_path(&header.path).ok_or_else(|| {
+                    invalid_data(&format!(
+                        "Refusing to write outside of the destination: {:?}",
+                        header.path
+                    ))
+                })?;
+                progress.entry("Receiving", &header)?;
+                Op::Entry(path, header)
+            }
+            Some((&TAG_DATA, data)) => {
+                progress.data(data.len())?;
+                Op::Data(data.to_owned())
+            }
+            Some((&TAG_END, [])) if progress.remaining == 0 => return Ok(()),
+            _ => return Err(invalid_data("Unexpected file transfer message")),
+        };
+        tx.send(op).await.map_err(|_| broken_pipe())?;
+    }
+}
+
+/// Logs where the transfer is, and checks files carry as much data as their
+/// header announced.
+#[derive(Default)]
+struct Progress {
+    total_files: u64,
+    total_bytes: u64,
+    files: u64,
+    bytes: u64,
+    file: String,
+    /// Bytes of the current file not transferred yet.
+    remaining: u64,
+    /// The peer is more than [`REMOTE_LAG_HIGH`] behind.
+    lagging: bool,
+}
+impl Progress {
+    fn new(entries: &[Entry]) -> Progress {
+        let files = entries.iter().filter(|entry| !entry.header.dir);
+        Progress {
+            total_files: files.clone().count() as u64,
+            total_bytes: files.map(|entry| entry.header.size).sum(),
+            ..Default::default()
+        }
+    }
+
+    fn entry(&mut self, verb: &str, header: &Header) -> StreamResult<()> {
+        if self.remaining != 0 {
+            return Err(invalid_data(&format!("{} ended early", self.file)));
+        }
+        if header.dir {
+            log::debug!("{verb} directory {}", header.path);
+            return Ok(());
+        }
+
+        self.files += 1;
+        self.file = header.path.clone();
+        self.remaining = header.size;
+        log::info!(
+            "{verb} {} ({} bytes), file {} of {}",
+            self.file,
+            header.size,
+            self.files,
+            self.total_files
+        );
+        if self.remaining == 0 {
+            self.done();
+        }
+
+        Ok(())
+    }
+
+    fn data(&mut self, len: usize) -> StreamResult<()> {
+        let len = len as u64;
+        if len > self.remaining {
+            return Err(invalid_data("File data exceeds the announced size"));
+        }
+
+        self.remaining -= len;
+        self.bytes += len;
+        if self.remaining == 0 {
+            self.done();
+        }
+
+        Ok(())
+    }
+
+    /// Tells when the peer falls behind and when it catches up.
+    fn remote_lag(&mut self, lag: Option<usize>) {
+        let lag = lag.unwrap_or_default();
+        match (self.lagging, lag > REMOTE_LAG_HIGH) {
+            (false, true) => log::info!("Remote applying… {lag} bytes behind"),
+            (true, false) => log::debug!("Remote caught up"),
+            _ => return,
+        }
+        self.lagging = !self.lagging;
+    }
+
+    fn done(&self) {
+        let percent = match self.total_bytes {
+            0 => 100,
+            total => self.bytes * 100 / total,
+        };
+        log::info!(
+            "{} done, {} of {} bytes overall ({percent}%)",
+            self.file,
+            self.bytes,
+            self.total_bytes
+        );
+    }
+}
+
+/// Entries for every path, named after its last component, directories
+/// before their contents. Symbolic links inside directories are skipped.
+fn collect(paths: &[PathBuf]) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for path in paths {
+        let name = match path.file_name() {
+            Some(name) => name.to_owned(),
+            None => fs::canonicalize(path)?
+                .file_name()
+                .ok_or_else(|| io::Error::other(format!("{} has no name", path.display())))?
+                .to_owned(),
+        };
+        let name = utf8(name)?;
+        walk(path, name, fs::metadata(path)?, &mut entries)?;
+    }
+
+    Ok(entries)
+}
+
+fn walk(
+    path: &Path,
+    name: String,
+    metadata: fs::Metadata,
+    entries: &mut Vec<Entry>,
+) -> io::Result<()> {
+    let header = Header {
+        path: name,
+        dir: metadata.is_dir(),
+        mode: mode(&metadata),
+        mtime: metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        size: match metadata.is_dir() {
+            true => 0,
+            false => metadata.len(),
+        },
+    };
+
+    if metadata.is_file() {
+        entries.push(Entry {
+            source: path.to_owned(),
+            header,
+        });
+    } else if metadata.is_dir() {
+        let name = header.path.clone();
+        entries.push(Entry {
+            source: path.to_owned(),
+            header,
+        });
+
+        let mut children = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+        children.sort_by_key(|entry| entry.file_name());
+        for child in children {
+            let path = child.path();
+            let name = format!("{name}/{}", utf8(child.file_name())?);
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_symlink() {
+                log::info!("Skipping symlink {name}, send-dir preserves them");
+                continue;
+            }
+            walk(&path, name, metadata, entries)?;
+        }
+    } else {
+        log::info!("Skipping special file {}", header.path);
+    }
+
+    Ok(())
+}
+
+fn utf8(name: std::ffi::OsString) -> io::Result<String> {
+    name.into_string()
+        .map_err(|name| io::Error::other(format!("{} is not valid UTF-8", name.to_string_lossy())))
+}
+
+/// Bits of the mode sent and applied, setuid, setgid and sticky left out.
+#[cfg(unix)]
+const PERMISSIONS: u32 = 0o777;
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & PERMISSIONS
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    match metadata.is_dir() {
+        true => 0o755,
+        false => 0o644,
+    }
+}
+
+/// Sends exactly the announced size of every file, in chunks.
+fn read_files(files: Vec<(PathBuf, u64)>, tx: mpsc::Sender<Vec<u8>>) -> io::Result<()> {
+    for (path, size) in files {
+        let mut file = File::open(&path)?.take(size);
+        let mut sent = 0;
+        loop {
+            let mut chunk = vec![0; CHUNK];
+            let n = file.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            chunk.truncate(n);
+            sent += n as u64;
+            tx.blocking_send(chunk).map_err(|_| broken_pipe())?;
+        }
+        if sent != size {
+            return Err(io::Error::other(format!(
+                "{} shrank while being sent",
+                path.display()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Path made of plain components only, `None` if it is empty, absolute or
+/// has `.` or `..` in it.
+fn safe_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.split('/') {
+        let mut components = Path::new(component).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(component),
+            _ => return None,
+        }
+    }
+
+    Some(path)
+}
+
+enum Op {
+    Entry(PathBuf, Header),
+    Data(Vec<u8>),
+}
+
+fn write_files(dest: &Path, mut rx: mpsc::Receiver<Op>) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    let dest = fs::canonicalize(dest)?;
+    let mut file: Option<(File, Header)> = None;
+    let mut dirs = vec![];
+
+    while let Some(op) = rx.blocking_recv() {
+        match op {
+            Op::Entry(path, header) => {
+                if let Some((file, header)) = file.take() {
+                    finish_file(file, &header)?;
+                }
+                let path = dest.join(path);
+                if header.dir {
+                    create_dir_inside(&dest, &path)?;
+                    dirs.push((path, header));
+                } else {
+                    let parent = path.parent().unwrap_or(&dest);
+                    create_dir_inside(&dest, parent)?;
+                    // Replaces a link instead of writing where it points
+                    if fs::symlink_metadata(&path).is_ok_and(|m| m.is_symlink()) {
+                        fs::remove_file(&path)?;
+                    }
+                    file = Some((File::create(&path)?, header));
+                }
+            }
+            Op::Data(data) => match &mut file {
+                Some((file, _)) => file.write_all(&data)?,
+                None => return Err(io::Error::other("File data outside of a file")),
+            },
+        }
+    }
+    if let Some((file, header)) = file.take() {
+        finish_file(file, &header)?;
+    }
+
+    // Deepest first, writing files into a directory changes its mtime
+    for (path, header) in dirs.iter().rev() {
+        finish_dir(path, header)?;
+    }
+
+    Ok(())
+}
+
+/// Creates `path` and its missing parents in `dest` one at a time, each
+/// existing one checked with [`inside`] before anything is created in it.
+fn create_dir_inside(dest: &Path, path: &Path) -> io::Result<()> {
+    let relative = path
+        .strip_prefix(dest)
+        .map_err(|_| io::Error::other(format!("{} is not in the destination", path.display())))?;
+    let mut current = dest.to_owned();
+    for component in relative.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(_) => inside(dest, &current)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&current)?,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails if a symbolic link already in the destination leads `path` out of
+/// it.
+fn inside(dest: &Path, path: &Path) -> io::Result<()> {
+    match fs::canonicalize(path)?.starts_with(dest) {
+        true => Ok(()),
+        false => Err(io::Error::other(format!(
+            "{} leads outside of the destination",
+            path.display()
+        ))),
+    }
+}
+
+fn finish_file(file: File, header: &Header) -> io::Result<()> {
+    file.set_modified(header.mtime())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(header.mode & PERMISSIONS))?;
+    }
+
+    Ok(())
+}
+
+fn finish_dir(path: &Path, header: &Header) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        File::open(path)?.set_modified(header.mtime())?;
+        fs::set_permissions(path, fs::Permissions::from_mode(header.mode & PERMISSIONS))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, header);
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use icepipe::{connect::Connection, memory_signalling::MemorySignalling, ConnectOptions};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    async fn pair() -> (Connection, Connection) {
+        let options = ConnectOptions {
+            channel: "send-files".into(),
+            ice: vec!["stun:127.0.0.1:3478".to_string()],
+            ..Default::default()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            options.clone().connect_psk_with_signalling(a, true),
+            options.connect_psk_with_signalling(b, false),
+        );
+
+        (a.unwrap(), b.unwrap())
+    }
+
+    fn mtime(path: &Path) -> u64 {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        modified.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let root = src.path().join("tree");
+        fs::create_dir_all(root.join("nested/empty")).unwrap();
+        fs::write(root.join("nested/big"), vec![7; 100_000]).unwrap();
+        fs::write(root.join("zero"), b"").unwrap();
+        fs::set_permissions(root.join("zero"), fs::Permissions::from_mode(0o4751)).unwrap();
+        symlink("zero", root.join("link")).unwrap();
+        File::options()
+            .write(true)
+            .open(root.join("nested/big"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000))
+            .unwrap();
+        let single = src.path().join("single.txt");
+        fs::write(&single, b"single").unwrap();
+
+        let (mut a, mut b) = pair().await;
+        let (sent, received) = tokio::join!(
+            send_files(&mut a, vec![root, single], |_| None),
+            recv_files(&mut b, dst.path().to_owned()),
+        );
+        sent.unwrap();
+        received.unwrap();
+
+        let dst = dst.path();
+        assert_eq!(
+            fs::read(dst.join("tree/nested/big")).unwrap(),
+            vec![7; 100_000]
+        );
+        assert_eq!(mtime(&dst.join("tree/nested/big")), 1_000_000);
+        assert!(dst.join("tree/nested/empty").is_dir());
+        assert_eq!(fs::read(dst.join("tree/zero")).unwrap(), b"");
+        assert_eq!(
+            fs::metadata(dst.join("tree/zero"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777,
+            // Without the setuid bit
+            0o751
+        );
+        assert!(!dst.join("tree/link").exists());
+        assert_eq!(fs::read(dst.join("single.txt")).unwrap(), b"single");
+        assert_eq!(
+            mtime(&dst.join("tree/nested")),
+            mtime(&src.path().join("tree/nested"))
+        );
+    }
+
+    /// Sends a single file, whatever its path, and returns the ack.
+    async fn evil(a: &mut Connection, path: &str) -> Vec<u8> {
+        let header = Header {
+            path: path.to_string(),
+            dir: false,
+            mode: 0o644,
+            mtime: 0,
+            size: 4,
+        };
+        send(a, TAG_HELLO, HELLO).await.unwrap();
+        send(
+            a,
+            TAG_TOTAL,
+            &[[0, 0, 0, 0, 0, 0, 0, 1], 4u64.to_be_bytes()].concat(),
+        )
+        .await
+        .unwrap();
+        send(a, TAG_ENTRY, &header.encode()).await.unwrap();
+        send(a, TAG_DATA, b"evil").await.unwrap();
+        send(a, TAG_END, &[]).await.unwrap();
+        recv(a).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_traversal() {
+        let dst = tempfile::tempdir().unwrap();
+        let dest = dst.path().join("dest");
+        let (mut a, mut b) = pair().await;
+
+        let (ack, received) = tokio::join!(evil(&mut a, "../escaped"), recv_files(&mut b, dest));
+
+        assert_eq!(ack, ACK_BAD);
+        assert!(received.is_err());
+        assert!(!dst.path().join("escaped").exists());
+    }
+
+    #[tokio::test]
+    async fn rejects_links_out() {
+        let dst = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let dest = dst.path().join("dest");
+        fs::create_dir(&dest).unwrap();
+        symlink(outside.path(), dest.join("out")).unwrap();
+        let (mut a, mut b) = pair().await;
+
+        let (_, received) =
+            tokio::join!(evil(&mut a, "out/created/file"), recv_files(&mut b, dest));
+
+        assert!(received.is_err());
+        // Failed before creating anything past the link
+        assert!(!outside.path().join("created").exists());
+    }
+
+    #[test]
+    fn safe_paths() {
+        assert_eq!(safe_path("a/b c/d"), Some(PathBuf::from("a/b c/d")));
+        assert_eq!(safe_path("../x"), None);
+        assert_eq!(safe_path("a/../../x"), None);
+        assert_eq!(safe_path("/etc/passwd"), None);
+        assert_eq!(safe_path("a//b"), None);
+        assert_eq!(safe_path("./a"), None);
+        assert_eq!(safe_path(""), None);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            path: "dïr/file".to_string(),
+            dir: false,
+            mode: 0o640,
+            mtime: 1_700_000_000,
+            size: 1 << 40,
+        };
+
+        assert_eq!(Header::decode(&header.encode()), Some(header));
+        assert_eq!(Header::decode(b"short"), None);
+    }
+}
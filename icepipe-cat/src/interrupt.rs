@@ -0,0 +1,153 @@
+//! Ctrl-C, SIGTERM on unix and console close events on Windows stop
+//! forwarding cleanly, a second one exits at once.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit code once interrupted, as shells report for SIGINT.
+pub const EXIT_CODE: u8 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Resolves on the first interrupt, any later one exits the process without
+/// closing anything.
+pub async fn interrupted() {
+    if signal().await.is_err() {
+        // Without a handler the default one still terminates the process
+        return std::future::pending().await;
+    }
+    INTERRUPTED.store(true, Ordering::Relaxed);
+    log::warn!("Interrupted, closing. Interrupt again to exit at once");
+
+    tokio::spawn(async {
+        if signal().await.is_ok() {
+            log::error!("Interrupted while closing");
+            std::process::exit(EXIT_CODE.into());
+        }
+    });
+}
+
+/// Whether [`interrupted`] resolved.
+pub fn was_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+#[cfg(unix)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(windows)]
+async fn signal() -> std::io::Result<()> {
+    use tokio::signal::windows::{ctrl_break, ctrl_close, ctrl_shutdown};
+
+    let (mut ctrl_break, mut close, mut shutdown) =
+        (ctrl_break()?, ctrl_close()?, ctrl_shutdown()?);
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r,
+        _ = ctrl_break.recv() => Ok(()),
+        _ = close.recv() => Ok(()),
+        _ = shutdown.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+async fn signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use icepipe::{
+        async_pipe_stream::AsyncPipeStream,
+        memory_signalling::MemorySignalling,
+        pipe_stream::{Control, WaitThen},
+        service, ConnectOptions,
+    };
+    use std::{
+        pin::Pin,
+        sync::{atomic::AtomicUsize, Arc},
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+    /// Counts what forwarding read from it.
+    struct Counting<R>(R, Arc<AtomicUsize>);
+    impl<R: AsyncRead + Unpin> AsyncRead for Counting<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let before = buf.filled().len();
+            let r = Pin::new(&mut self.0).poll_read(cx, buf);
+            self.1
+                .fetch_add(buf.filled().len() - before, Ordering::Relaxed);
+            r
+        }
+    }
+
+    #[tokio::test]
+    async fn sigint_mid_transfer() {
+        let options = ConnectOptions {
+            channel: "interrupt".into(),
+            ice: vec!["stun:127.0.0.1:3478".to_string()],
+            ..Default::default()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            options.clone().connect_psk_with_signalling(a, true),
+            options.connect_psk_with_signalling(b, false),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        // Far more than is sent before the signal
+        let read = Arc::new(AtomicUsize::new(0));
+        let input = Counting(tokio::io::repeat(7).take(1 << 30), read.clone());
+        let mut local = AsyncPipeStream::new(input, tokio::io::sink());
+
+        let sigint = async {
+            while read.load(Ordering::Relaxed) < 1 << 20 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let status = std::process::Command::new("kill")
+                .args(["-INT", &std::process::id().to_string()])
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        let receive = async {
+            let mut received = 0;
+            while !b.rx_closed() {
+                let mut value = b.wait().await.unwrap();
+                if let Some(data) = b.then(&mut value).await.unwrap() {
+                    assert!(data.iter().all(|&byte| byte == 7));
+                    received += data.len();
+                }
+            }
+            b.close().await.unwrap();
+            received
+        };
+        let forward = async {
+            let (summary, ()) = tokio::join!(
+                service::forward_until(&mut a, &mut local, interrupted()),
+                sigint
+            );
+            summary
+        };
+        let (summary, received) = tokio::join!(forward, receive);
+
+        summary.unwrap();
+        assert!(was_interrupted());
+        let read = read.load(Ordering::Relaxed);
+        assert!(read < 1 << 30);
+        assert_eq!(received, read);
+    }
+}
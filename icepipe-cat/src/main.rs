@@ -1,31 +1,219 @@
-use clap::Parser;
+mod chat;
+mod dir;
+mod early;
+mod files;
+mod interrupt;
+mod profile;
+
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use early::EarlyInput;
 use icepipe::{
     agreement::Ed25519PairAndPeer,
-    async_pipe_stream::{AsyncPipeStream, DynAsyncRead, DynAsyncWrite},
+    async_pipe_stream::{AsyncPipeStream, DynAsyncRead, DynAsyncWrite, Framing},
+    channel::Psk,
+    channel_hopping::ChannelHopping,
+    connect::{Connection, LatencyProfile},
+    crypto_backend::{self, Ed25519KeyPair, Unspecified},
     curve25519_conversion,
-    pipe_stream::{Control, PipeStream, StreamError, StreamResult, WaitThen},
-    ring::signature::{self, KeyPair},
+    diagnostics::Diagnostics,
+    doctor::Status,
+    events::{ConnectionEvent, Events},
+    framed_signalling::SignallingFraming,
+    ice::{IceServer, PairSelection},
+    manager::{PeerSpec, ReconnectPolicy},
+    ping::{PingConfig, MAX_SERVER_INTERVAL},
+    pipe_stream::{CloseReason, Control, StreamError, StreamResult},
+    sctp::SctpConfig,
+    service::{self, ServiceRegistry},
+    testing::{self, EchoCheck},
+    traffic_limit::{Counted, TrafficLimit},
+    tunnels::Tunnels,
+};
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    pin::pin,
+    process::ExitCode,
+    rc::Rc,
+    time::Duration,
 };
 use tokio::{
     net::{TcpListener, TcpStream},
     select,
+    sync::broadcast,
+    task::LocalSet,
 };
 
-fn main() -> StreamResult<()> {
+fn main() -> StreamResult<ExitCode> {
     env_logger::init();
+    let mut args = Args::parse();
+    if let Some(Command::Echo { channel, .. }) = &mut args.command {
+        args.channel = args.channel.take().or(channel.take());
+    }
+    // Subcommands lift the requirements, only doctor runs without a channel
+    if args.channel.is_none()
+        && args.channels.is_empty()
+        && args.profile.is_none()
+        && !matches!(args.command, None | Some(Command::Doctor { .. }))
+    {
+        Args::command()
+            .error(ErrorKind::MissingRequiredArgument, "<CHANNEL> is required")
+            .exit();
+    }
+    let diag_file = args.diag_file.clone();
+    let full = args.diagnostics_full;
+    let diagnostics = match diag_file.is_some() || args.diagnostics.is_some() {
+        true => Diagnostics::new(),
+        false => Diagnostics::default(),
+    };
 
-    tokio::runtime::Builder::new_multi_thread()
+    let mut runtime = match args.single_thread {
+        true => tokio::runtime::Builder::new_current_thread(),
+        false => tokio::runtime::Builder::new_multi_thread(),
+    };
+    let r = runtime
         .enable_all()
         .build()?
-        .block_on(main2())
+        .block_on(main2(args, diagnostics.clone()));
+
+    // Also written when connecting failed, that is when it is most useful
+    if let Some(path) = diag_file {
+        let file = std::fs::File::create(path)?;
+        match full {
+            true => diagnostics.write_json_full(file)?,
+            false => diagnostics.write_json(file)?,
+        }
+    }
+
+    let reason = r?;
+    if interrupt::was_interrupted() {
+        return Ok(ExitCode::from(interrupt::EXIT_CODE));
+    }
+
+    Ok(exit_code(reason))
+}
+
+/// 0 once either side finished, 3 if the connection broke, 4 if it timed out
+/// and 5 if it reached --max-bytes. Errors exit with 1, closing on Ctrl-C or
+/// SIGTERM exits with 130.
+fn exit_code(reason: Option<CloseReason>) -> ExitCode {
+    let Some(reason) = reason else {
+        return ExitCode::SUCCESS;
+    };
+
+    match reason {
+        CloseReason::CleanFin | CloseReason::LocalClose | CloseReason::RemoteRequested => {
+            log::info!("Connection closed: {reason}");
+            ExitCode::SUCCESS
+        }
+        CloseReason::TransportFailed(_) => {
+            log::error!("Connection closed: {reason}");
+            ExitCode::from(3)
+        }
+        CloseReason::Timeout => {
+            log::error!("Connection closed: {reason}");
+            ExitCode::from(4)
+        }
+        CloseReason::TrafficLimit => {
+            log::error!("Connection closed: {reason}");
+            ExitCode::from(5)
+        }
+    }
+}
+
+/// Default of --close-timeout.
+const CLOSE_TIMEOUT: u64 = 10;
+
+/// Default of --signaling-ping.
+const SIGNALING_PING: u64 = 15;
+
+/// See [`LatencyProfile`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Latency {
+    /// Keeps little data queued so small messages, like keystrokes, arrive at once
+    Interactive,
+    #[default]
+    Balanced,
+    /// Queues more data, for bulk transfers
+    Throughput,
+}
+impl From<Latency> for LatencyProfile {
+    fn from(value: Latency) -> Self {
+        match value {
+            Latency::Interactive => LatencyProfile::Interactive,
+            Latency::Balanced => LatencyProfile::Balanced,
+            Latency::Throughput => LatencyProfile::Throughput,
+        }
+    }
+}
+
+/// See [`PairSelection`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Nomination {
+    /// Waits briefly for the best path, e.g. a direct one over a relay
+    #[default]
+    Regular,
+    /// Takes the first path that works, connecting sooner
+    Aggressive,
+}
+impl From<Nomination> for PairSelection {
+    fn from(value: Nomination) -> Self {
+        match value {
+            Nomination::Regular => PairSelection::Standard,
+            Nomination::Aggressive => PairSelection::FirstResponding,
+        }
+    }
+}
+
+/// See [`SignallingFraming`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum SignalingFraming {
+    /// One message per websocket message, switching to delimited once the peer does
+    #[default]
+    Raw,
+    /// Tags every message with its length, for servers that merge or pad the messages they relay
+    Delimited,
+}
+impl From<SignalingFraming> for SignallingFraming {
+    fn from(value: SignalingFraming) -> Self {
+        match value {
+            SignalingFraming::Raw => SignallingFraming::Raw,
+            SignalingFraming::Delimited => SignallingFraming::Delimited,
+        }
+    }
 }
 
 /// Establishes P2P connection between two peers
 #[derive(Parser)]
+#[clap(disable_version_flag = true, subcommand_negates_reqs = true)]
+#[clap(group(clap::ArgGroup::new("reports").multiple(true).args(["diagnostics", "diag_file"])))]
 struct Args {
     /// Channel to connect to, both side must pass the same value to establish a connection
     /// If private key is provided, channel is assumed to be the peer public key.
-    channel: String,
+    #[clap(required_unless_present_any = ["gen_key", "version", "capabilities", "profile", "channels"])]
+    channel: Option<String>,
+
+    /// Keeps a connection up on each channel given, along with <CHANNEL>, serving the services of
+    /// --serve on all of them. Each one reconnects on its own once its peer leaves or the connection
+    /// fails, their status is logged whenever it changes.
+    #[clap(long = "channel")]
+    channels: Vec<String>,
+
+    /// Takes the channel, keys, servers and timeouts of the named profile, those given on the
+    /// command line take precedence.
+    #[clap(long = "profile")]
+    profile: Option<String>,
+
+    /// Saves the channel, --private-key, --signaling, --ice and the timeouts to the named profile
+    /// and closes the program.
+    #[clap(long = "save-profile")]
+    save_profile: Option<String>,
+
+    /// File of the profiles, readable by its owner only as they hold secrets. Default:
+    /// icepipe/profiles.json in the user configuration directory
+    #[clap(long = "profiles")]
+    profiles: Option<PathBuf>,
 
     /// Private key for DH mode. Channel will be assumed to be peers public key.
     #[clap(long = "private-key")]
@@ -35,18 +223,111 @@ struct Args {
     #[clap(long = "gen-key")]
     gen_key: bool,
 
+    /// Prints the version and closes the program
+    #[clap(short = 'V', long = "version")]
+    version: bool,
+
+    /// Prints what this build supports (ciphers, transports, signalling) and closes the program
+    #[clap(long = "capabilities")]
+    capabilities: bool,
+
     /// Specify a different signalling server URL
     #[clap(long = "signaling")]
     signaling: Option<String>,
 
+    /// Times to reconnect to the signalling server if it drops while exchanging candidates
+    #[clap(long = "signaling-reconnects", default_value_t = 3)]
+    signaling_reconnects: u32,
+
+    /// Seconds between pings to the signalling server, 0 disables them. Fewer pings save traffic on
+    /// metered connections but a dead server is noticed later, after four missed pings or once TCP
+    /// gives up when disabled. At most 50, icepipe-signal drops clients silent for 60s, which it
+    /// does with pings disabled too. Default: 15
+    #[clap(
+        long = "signaling-ping",
+        value_parser = clap::value_parser!(u64).range(..=MAX_SERVER_INTERVAL.as_secs())
+    )]
+    signaling_ping: Option<u64>,
+
+    /// Retries a wss:// signalling server over plain ws:// when TLS is refused, e.g. on networks
+    /// intercepting TLS. Only with a private key, a channel used as PSK could be guessed offline by
+    /// anyone on the path. Peers stay authenticated and encrypted end to end, but the server and
+    /// the network see the channel and may disrupt the connection.
+    #[clap(long = "signaling-tls-fallback")]
+    signaling_tls_fallback: bool,
+
+    /// How signalling messages are delimited, both peers must pass delimited when the server
+    /// merges or pads messages. Versions without this option only understand raw
+    #[clap(long = "signaling-framing", value_enum, default_value_t)]
+    signaling_framing: SignalingFraming,
+
+    /// Derives a new channel every given number of seconds so a leaked channel cannot be squatted
+    /// for long. Both peers must pass the same value.
+    #[clap(long = "channel-window")]
+    channel_window: Option<u64>,
+
+    /// Writes the candidates gathered and exchanged, the selected pair, ICE state transitions and
+    /// timings of the session to the given file as JSON, to be attached to bug reports. Candidate
+    /// addresses are redacted.
+    #[clap(long = "diag-file")]
+    diag_file: Option<PathBuf>,
+
+    /// Writes a report of the connection (versions, signalling host, timings, ICE and SCTP state,
+    /// warnings) to the given file as JSON on exit. Candidate addresses are redacted.
+    #[clap(long = "diagnostics")]
+    diagnostics: Option<PathBuf>,
+
+    /// Keeps candidate addresses in the --diagnostics and --diag-file reports.
+    #[clap(long = "diagnostics-full", requires = "reports")]
+    diagnostics_full: bool,
+
+    /// Closes the session once it sent and received this many bytes, warning at 90% of it.
+    /// Accepts K, M, G and T suffixes, in powers of 1024. Example: 10G
+    #[clap(long = "max-bytes", value_parser = parse_bytes)]
+    max_bytes: Option<u64>,
+
+    /// Applies --max-bytes to what is sent and to what is received on their own, instead of both
+    /// combined.
+    #[clap(long = "max-bytes-each")]
+    max_bytes_each: bool,
+
+    /// Trades throughput for latency, interactive suits ssh and other terminal sessions
+    #[clap(long = "latency", value_enum, default_value_t)]
+    latency: Latency,
+
+    /// Path the dialer picks among those that work, only applies when this side ends up dialing
+    #[clap(long = "nomination", value_enum, default_value_t)]
+    nomination: Nomination,
+
+    /// Seconds to wait for a clean close before dropping the connection, 0 waits indefinitely.
+    /// Default: 10
+    #[clap(long = "close-timeout")]
+    close_timeout: Option<u64>,
+
     /// Url of STUN or TURN server. Example: turn:my.stun.com:19302&username&password
     #[clap(long = "ice")]
-    ice: Vec<String>,
+    ice: Vec<IceServer>,
+
+    /// File with the ICE servers of a WebRTC configuration, as given by TURN providers. Example:
+    /// [{"urls": "turn:my.turn.com:3478", "username": "user", "credential": "password"}]
+    #[clap(long = "ice-json")]
+    ice_json: Option<PathBuf>,
+
+    /// Uses IPv6 link-local addresses (fe80::) too, for peers on the same link without any other
+    /// address.
+    #[clap(long = "keep-link-local")]
+    keep_link_local: bool,
 
     /// Specify input file path to be forward to the peer. Default: read from standard input
     #[clap(short = 'i', long = "input")]
     input: Option<String>,
 
+    /// Bytes of standard input read ahead while connecting, so a producer piping into icepipe-cat
+    /// does not stall during the handshake. Reading pauses once full. Accepts the suffixes of
+    /// --max-bytes, 0 reads only once connected.
+    #[clap(long = "early-buffer", value_parser = parse_bytes, default_value = "4M")]
+    early_buffer: u64,
+
     /// Specify input file as a listening port that will accept one connection.
     #[clap(short = 'L', long = "tcp-input")]
     tcp_input: Option<String>,
@@ -58,31 +339,245 @@ struct Args {
     /// Forwards both input and output to a new TCP connection established with the specified address.
     #[clap(short = 'W', long = "tcp-forward")]
     tcp_forward: Option<String>,
+
+    /// Leaves Nagle's algorithm on for --tcp-input and --tcp-forward, batching small writes at the
+    /// cost of latency.
+    #[clap(long = "no-tcp-nodelay")]
+    no_tcp_nodelay: bool,
+
+    /// Enables TCP keepalive on --tcp-input and --tcp-forward, probing after the given seconds idle.
+    #[clap(long = "tcp-keepalive")]
+    tcp_keepalive: Option<u64>,
+
+    /// Seconds to wait for the --tcp-forward target or a --serve endpoint to accept the connection.
+    /// The peer is told the target is unreachable past it.
+    #[clap(long = "connect-timeout", default_value_t = 10)]
+    connect_timeout: u64,
+
+    /// Sends one message per input line instead of arbitrary chunks, for line oriented text
+    /// protocols.
+    #[clap(long = "lines")]
+    lines: bool,
+
+    /// Longest line sent as a single message with --lines, longer ones are split.
+    #[clap(long = "max-line-length", default_value_t = 4096)]
+    max_line_length: usize,
+
+    /// Sends each line typed as a message and prints those received, a quick check that the
+    /// channel works.
+    #[clap(
+        long = "chat",
+        conflicts_with_all = ["input", "tcp_input", "output", "tcp_forward", "serve"]
+    )]
+    chat: bool,
+
+    /// Sends patterned data to a peer running echo and verifies what comes back, printing PASS with
+    /// the round trip time and throughput, or FAIL.
+    #[clap(long = "test-echo")]
+    test_echo: bool,
+
+    /// Bytes sent by --test-echo, accepts the suffixes of --max-bytes.
+    #[clap(long = "test-echo-bytes", value_parser = parse_bytes, default_value = "16M")]
+    test_echo_bytes: u64,
+
+    /// Serves the connection to the service requested by the peer. Example: --serve ssh=127.0.0.1:22
+    /// Endpoints are host:port, unix:<path> or exec:<command>.
+    #[clap(
+        long = "serve",
+        conflicts_with_all = ["input", "tcp_input", "output", "tcp_forward", "service"]
+    )]
+    serve: Vec<String>,
+
+    /// Requests the named service from a peer started with --serve.
+    #[clap(long = "service")]
+    service: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Runs everything on a single thread, which is usually enough for a single connection and
+    /// may reduce latency and memory usage.
+    #[clap(long = "single-thread")]
+    single_thread: bool,
 }
 
-async fn main2() -> StreamResult<()> {
-    let args = Args::parse();
+#[derive(Subcommand)]
+enum Command {
+    /// Sends a directory to the peer running recv-dir, as a tar archive verified at the end
+    SendDir {
+        path: PathBuf,
+
+        /// Glob of files to leave out, matched against the relative path and the file name
+        #[clap(long = "exclude")]
+        exclude: Vec<String>,
+
+        /// How to send symbolic links
+        #[clap(long = "symlinks", value_enum, default_value_t)]
+        symlinks: dir::Symlinks,
+
+        /// How to handle fifos, sockets and devices
+        #[clap(long = "special-files", value_enum, default_value_t)]
+        special_files: dir::SpecialFiles,
 
+        /// Compresses the archive with zstd, requires the zstd feature
+        #[clap(long = "zstd")]
+        zstd: bool,
+    },
+    /// Receives a directory sent with send-dir into dest. Default: current directory
+    RecvDir { dest: Option<PathBuf> },
+    /// Sends files and directories to the peer running recv-files, with their path, mode and mtime
+    SendFiles {
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Receives files sent with send-files into dest. Default: current directory
+    RecvFiles { dest: Option<PathBuf> },
+    /// Sends back everything received, for a peer to check the connection with --test-echo
+    Echo {
+        /// Channel to connect to, the same as passing it before echo
+        channel: Option<String>,
+
+        /// Only counts what is received instead of sending it back
+        #[clap(long = "discard")]
+        discard: bool,
+    },
+    /// Checks the signalling server, the ICE servers, UDP egress and the clock, without a peer.
+    /// Exits with 1 if any check fails.
+    Doctor {
+        /// Prints the report as JSON
+        #[clap(long = "json")]
+        json: bool,
+    },
+}
+
+/// Why the peer connection ended, if forwarding over it.
+async fn main2(mut args: Args, diagnostics: Diagnostics) -> StreamResult<Option<CloseReason>> {
     if args.gen_key {
         return gen_key()
             .map_err(icepipe::agreement::AgreementError::from)
-            .map_err(|e| StreamError::Other(Box::new(e)));
+            .map_err(|e| StreamError::Other(Box::new(e)))
+            .map(|_| None);
+    }
+
+    if args.version || args.capabilities {
+        println!("icepipe-cat {}", env!("CARGO_PKG_VERSION"));
+        if args.capabilities {
+            println!("{}", icepipe::capabilities());
+        }
+        return Ok(None);
     }
 
+    // Only standard input forwarded as is, the other modes read once connected
+    let early_input = (args.command.is_none()
+        && !args.chat
+        && !args.test_echo
+        && args.serve.is_empty()
+        && args.input.is_none()
+        && args.tcp_input.is_none()
+        && args.tcp_forward.is_none()
+        && args.early_buffer > 0)
+        .then(|| EarlyInput::spawn(tokio::io::stdin(), args.early_buffer as usize));
+
+    let profiles_path = args.profiles.take().or_else(profile::default_path);
+    let profile = match &args.profile {
+        Some(name) => profile::load(profile::required(profiles_path.as_deref())?)?
+            .get(name)
+            .map_err(|e| StreamError::Other(Box::new(e)))?
+            .clone(),
+        None => Default::default(),
+    };
+    let close_timeout = args
+        .close_timeout
+        .or(profile.close_timeout)
+        .unwrap_or(CLOSE_TIMEOUT);
+    let mut ice_servers = profile.ice_servers.clone();
+    ice_servers.append(&mut args.ice);
+
+    let events = Events::new();
+    tokio::spawn(log_events(events.subscribe()));
+    // The peer public key with --private-key, the PSK otherwise
+    let channel = Psk::new(
+        args.channel
+            .take()
+            .unwrap_or_else(|| profile.channel.expose().to_owned()),
+    );
+    if !channel.expose().is_empty() && !args.channels.is_empty() {
+        args.channels.insert(0, channel.expose().to_owned());
+    }
     let options = icepipe::ConnectOptions {
-        channel: args.channel,
+        channel: channel.clone().into(),
         signaling: args
             .signaling
+            .take()
+            .or(profile.signaling.clone())
             .map(|url| url.parse().map_err(|e| StreamError::Other(Box::new(e))))
             .transpose()?,
-        ice: args.ice,
+        ice_servers,
+        encryption: profile.encryption,
+        keep_link_local: args.keep_link_local,
+        pair_selection: args.nomination.into(),
+        signaling_reconnects: args.signaling_reconnects,
+        signalling_ping: match args
+            .signaling_ping
+            .or(profile.signaling_ping)
+            .unwrap_or(SIGNALING_PING)
+        {
+            0 => PingConfig::DISABLED,
+            secs => PingConfig::every(Duration::from_secs(secs)),
+        },
+        signalling_send_timeout: profile.signaling_send_timeout.map(Duration::from_secs),
+        signalling_tls_fallback: args.signaling_tls_fallback,
+        signalling_framing: args.signaling_framing.into(),
+        channel_hopping: args
+            .channel_window
+            .map(|secs| ChannelHopping::new(Duration::from_secs(secs))),
+        diagnostics,
+        events,
+        sctp: SctpConfig {
+            close_timeout: (close_timeout > 0).then(|| Duration::from_secs(close_timeout)),
+            ..Default::default()
+        },
+        traffic_limit: args.max_bytes.map(|hard| TrafficLimit {
+            counted: match args.max_bytes_each {
+                true => Counted::Each,
+                false => Counted::Both,
+            },
+            ..TrafficLimit::new(hard / 10 * 9, hard)
+        }),
+        ..Default::default()
+    }
+    .with_latency_profile(args.latency.into());
+    let options = match &args.ice_json {
+        Some(path) => options.ice_servers_from_json(&std::fs::read_to_string(path)?)?,
+        None => options,
     };
 
-    let mut peer_stream = match args.private_key {
+    let private_key = args
+        .private_key
+        .take()
+        .or_else(|| Some(profile.private_key.as_ref()?.expose().to_owned()));
+    if let Some(name) = &args.save_profile {
+        let path = profile::required(profiles_path.as_deref())?;
+        profile::save(path, name, &options, private_key)?;
+        eprintln!("Saved profile {name} to {}", path.display());
+        return Ok(None);
+    }
+
+    if let Some(Command::Doctor { json }) = args.command {
+        return doctor(&options, json).await.map(|_| None);
+    }
+
+    if !args.channels.is_empty() {
+        return LocalSet::new()
+            .run_until(tunnels(args, options, private_key))
+            .await
+            .map(|_| None);
+    }
+
+    let mut peer_stream = match private_key {
         Some(private_key) => {
-            let (key_pair, peer, x_key_pair, x_peer) = get_keys(private_key, options.channel)
-                .map_err(icepipe::agreement::AgreementError::from)
-                .map_err(|e| StreamError::Other(Box::new(e)))?;
+            let (key_pair, peer, x_key_pair, x_peer) =
+                get_keys(private_key, channel.expose().to_owned())?;
 
             let channel = x_key_pair
                 .diffie_hellman(&x_peer)
@@ -91,7 +586,10 @@ async fn main2() -> StreamResult<()> {
                 .map(|b| format!("{b:02x}"))
                 .collect::<String>();
 
-            let options = icepipe::ConnectOptions { channel, ..options };
+            let options = icepipe::ConnectOptions {
+                channel: Psk::new(channel).into(),
+                ..options
+            };
 
             let auth = Ed25519PairAndPeer(key_pair, peer);
 
@@ -99,6 +597,164 @@ async fn main2() -> StreamResult<()> {
         }
         None => options.connect_psk().await?,
     };
+    log::info!("timings: {}", peer_stream.timings());
+
+    let report = args.diagnostics.take();
+    let full = args.diagnostics_full;
+    let r = session(args, &mut peer_stream, early_input).await;
+    if let Some(path) = report {
+        let report = match full {
+            true => peer_stream.diagnostics_full(),
+            false => peer_stream.diagnostics(),
+        };
+        report.write_json(std::fs::File::create(path)?)?;
+    }
+
+    r
+}
+
+/// Fails if any of the checks does.
+async fn doctor(options: &icepipe::ConnectOptions, json: bool) -> StreamResult<()> {
+    let report = icepipe::doctor::run(options).await;
+    match json {
+        true => {
+            report.write_json(io::stdout())?;
+            println!();
+        }
+        false => print!("{report}"),
+    }
+
+    match report.status() {
+        Status::Fail => Err(StreamError::Other("Some checks failed".into())),
+        Status::Pass | Status::Warn => Ok(()),
+    }
+}
+
+/// Until the connection is dropped. ICE progress replaces itself on a single
+/// line when stderr is a terminal, cleared before anything else is logged.
+async fn log_events(mut events: broadcast::Receiver<ConnectionEvent>) {
+    let tty = io::stderr().is_terminal();
+    let mut progress_shown = false;
+    loop {
+        let event = events.recv().await;
+        if std::mem::take(&mut progress_shown) {
+            eprint!("\r\x1b[K");
+        }
+        match event {
+            Ok(event @ ConnectionEvent::IceProgress { .. }) if tty => {
+                eprint!("{event}");
+                let _ = io::stderr().flush();
+                progress_shown = true;
+            }
+            Ok(event @ ConnectionEvent::IceProgress { .. }) => log::debug!("{event}"),
+            Ok(
+                event @ (ConnectionEvent::PairSelected { .. }
+                | ConnectionEvent::ShutdownRequested { .. }
+                | ConnectionEvent::Recovered { .. }),
+            ) => log::info!("{event}"),
+            Ok(event) => log::warn!("{event}"),
+            Err(broadcast::error::RecvError::Lagged(n)) => log::warn!("{n} events skipped"),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn session(
+    args: Args,
+    peer_stream: &mut Connection,
+    early_input: Option<EarlyInput<tokio::io::Stdin>>,
+) -> StreamResult<Option<CloseReason>> {
+    match args.command {
+        Some(Command::SendDir {
+            path,
+            exclude,
+            symlinks,
+            special_files,
+            zstd,
+        }) => {
+            let exclude = exclude
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<Result<_, _>>()
+                .map_err(|e| StreamError::Other(Box::new(e)))?;
+            let options = dir::SendOptions {
+                exclude,
+                symlinks,
+                special_files,
+                zstd,
+            };
+            dir::send_dir(peer_stream, path, options).await?;
+            peer_stream.close().await?;
+            return Ok(None);
+        }
+        Some(Command::RecvDir { dest }) => {
+            dir::recv_dir(peer_stream, dest.unwrap_or_else(|| ".".into())).await?;
+            peer_stream.close().await?;
+            return Ok(None);
+        }
+        Some(Command::SendFiles { paths }) => {
+            files::send_files(peer_stream, paths, Connection::remote_lag_bytes).await?;
+            peer_stream.close().await?;
+            return Ok(None);
+        }
+        Some(Command::RecvFiles { dest }) => {
+            files::recv_files(peer_stream, dest.unwrap_or_else(|| ".".into())).await?;
+            peer_stream.close().await?;
+            return Ok(None);
+        }
+        Some(Command::Echo { discard, .. }) => {
+            let stats = match discard {
+                true => testing::discard(peer_stream).await?,
+                false => testing::echo(peer_stream).await?,
+            };
+            eprintln!(
+                "Peer closed after {} messages, {} bytes in {:.1?}",
+                stats.messages, stats.bytes, stats.elapsed
+            );
+            return Ok(None);
+        }
+        Some(Command::Doctor { .. }) => unreachable!("Runs without connecting"),
+        None => {}
+    }
+
+    if args.test_echo {
+        let check = EchoCheck {
+            bytes: args.test_echo_bytes,
+            ..Default::default()
+        };
+        let report = testing::echo_check(peer_stream, check).await;
+        let closed = peer_stream.close().await;
+        let report = report.inspect_err(|e| println!("FAIL: {e}"))?;
+        closed?;
+        println!(
+            "PASS: round trip {:.1?}, {} bytes echoed in {:.1?}, {:.2} MiB/s",
+            report.rtt.unwrap_or_default(),
+            report.bytes,
+            report.elapsed,
+            report.throughput() / (1 << 20) as f64
+        );
+        return Ok(None);
+    }
+
+    if args.chat {
+        if let Some(name) = args.service {
+            service::request(peer_stream, &name, None).await?;
+        }
+        let dialer = peer_stream.is_dialer();
+        let input = tokio::io::BufReader::new(tokio::io::stdin());
+        return chat::chat(peer_stream, dialer, input, io::stdout()).await;
+    }
+
+    if !args.serve.is_empty() {
+        registry(&args)?.serve(peer_stream).await?;
+        log::info!("ready to close");
+
+        return Ok(None);
+    }
+
+    if let Some(name) = args.service {
+        service::request(peer_stream, &name, None).await?;
+    }
 
     let input: DynAsyncRead;
     let output: DynAsyncWrite;
@@ -118,6 +774,7 @@ async fn main2() -> StreamResult<()> {
 
         let tcp_listen = TcpListener::bind(tcp_input).await?;
         let (tcp_stream, _) = tcp_listen.accept().await?;
+        tune_tcp(&tcp_stream, !args.no_tcp_nodelay, args.tcp_keepalive)?;
         let (read, write) = tcp_stream.into_split();
         input = Box::pin(read);
         output = Box::pin(write);
@@ -132,14 +789,20 @@ async fn main2() -> StreamResult<()> {
         );
 
         log::info!("Connecting to {tcp_forward}");
-        let tcp_stream = TcpStream::connect(tcp_forward).await?;
+        let timeout = Duration::from_secs(args.connect_timeout);
+        let tcp_stream = match service::connect_tcp(&tcp_forward, timeout).await {
+            Ok(tcp_stream) => tcp_stream,
+            Err(e) => return Err(service::refuse(peer_stream, e).await.into()),
+        };
+        tune_tcp(&tcp_stream, !args.no_tcp_nodelay, args.tcp_keepalive)?;
         let (read, write) = tcp_stream.into_split();
         input = Box::pin(read);
         output = Box::pin(write);
     } else {
-        input = match args.input {
-            Some(path) => Box::pin(tokio::fs::File::open(path).await?),
-            None => Box::pin(tokio::io::stdin()),
+        input = match (args.input, early_input) {
+            (Some(path), _) => Box::pin(tokio::fs::File::open(path).await?),
+            (None, Some(early_input)) => Box::pin(early_input.finish().await?),
+            (None, None) => Box::pin(tokio::io::stdin()),
         };
         output = match args.output {
             Some(path) => Box::pin(tokio::fs::File::create(path).await?),
@@ -147,40 +810,152 @@ async fn main2() -> StreamResult<()> {
         };
     }
 
-    let mut local_stream = AsyncPipeStream::new_dyn(input, output);
+    let framing = match args.lines {
+        true => Framing::Lines {
+            max_len: args.max_line_length,
+        },
+        false => Framing::Chunks,
+    };
+    let mut local_stream = AsyncPipeStream::new_dyn(input, output).with_framing(framing);
+
+    let summary =
+        service::forward_until(peer_stream, &mut local_stream, interrupt::interrupted()).await?;
 
-    while !peer_stream.rx_closed() && !local_stream.rx_closed() {
+    // Closed by now, all that was sent is acknowledged
+    let stats = peer_stream.stats();
+    let transferred = format!(
+        "sent {} bytes, received {} bytes",
+        stats.bytes_sent, stats.bytes_received
+    );
+    match interrupt::was_interrupted() {
+        true => eprintln!("Interrupted, {transferred}"),
+        false => log::info!("ready to close, {transferred}"),
+    }
+
+    Ok(summary.a)
+}
+
+/// Services of --serve.
+fn registry(args: &Args) -> StreamResult<ServiceRegistry> {
+    let mut registry = ServiceRegistry::new();
+    registry.open_timeout = Some(Duration::from_secs(args.connect_timeout));
+    for serve in &args.serve {
+        let (name, endpoint) = serve
+            .split_once('=')
+            .ok_or_else(|| StreamError::Other(format!("Expected name=endpoint: {serve}").into()))?;
+        registry.add(name, endpoint.parse::<service::Endpoint>()?);
+    }
+
+    Ok(registry)
+}
+
+/// A tunnel per --channel serving --serve, until interrupted. Tunnels are
+/// named by their position, channels are secrets.
+async fn tunnels(
+    args: Args,
+    options: icepipe::ConnectOptions,
+    private_key: Option<String>,
+) -> StreamResult<()> {
+    if args.serve.is_empty() {
+        return Err(StreamError::Other("--channel requires --serve".into()));
+    }
+    let registry = Rc::new(registry(&args)?);
+    let seed = private_key
+        .map(|private_key| {
+            <[u8; 32]>::try_from(hex_bytes(&private_key, "Private key")?)
+                .map_err(|_| StreamError::Other("Private key must be 32 bytes".into()))
+        })
+        .transpose()?;
+
+    let mut tunnels = Tunnels::new(options, ReconnectPolicy::default());
+    for (i, channel) in args.channels.iter().enumerate() {
+        let spec = match seed {
+            Some(seed) => PeerSpec::Key {
+                seed,
+                peer: hex_bytes(channel, "Peer key")?,
+            },
+            None => PeerSpec::Psk {
+                psk: Psk::new(channel.as_str()),
+            },
+        };
+        let registry = registry.clone();
+        let session = move |mut connection: Connection| {
+            let registry = registry.clone();
+            async move {
+                let served = registry.serve(&mut connection).await;
+                connection.close().await?;
+                Ok(served?)
+            }
+        };
+        tunnels
+            .add(format!("channel {}", i + 1), spec, session)
+            .map_err(|e| StreamError::Other(Box::new(e)))?;
+    }
+
+    let mut changed = tunnels.watch();
+    let mut interrupted = pin!(interrupt::interrupted());
+    loop {
         select! {
-            value = peer_stream.wait() => {
-                let recv = peer_stream.then(&mut value?).await?;
-                if let Some(data) = recv {
-                    local_stream.send(&data).await?;
-                }
+            _ = changed.changed() => {
+                let status = tunnels.status();
+                log::info!(
+                    "{} of {} tunnels connected\n{}",
+                    status.connected(),
+                    status.0.len(),
+                    status.to_string().trim_end()
+                );
             }
-            value = local_stream.wait() => {
-                let recv = local_stream.then(&mut value?).await?;
-                if let Some(data) = recv {
-                    peer_stream.send(&data).await?;
-                }
-            },
+            _ = &mut interrupted => break,
         }
     }
-    peer_stream.close().await?;
-    local_stream.close().await?;
+    tunnels.close().await;
 
-    log::info!("ready to close");
+    Ok(())
+}
+
+/// `keepalive` is the idle time in seconds before probing.
+fn tune_tcp(stream: &TcpStream, nodelay: bool, keepalive: Option<u64>) -> io::Result<()> {
+    stream.set_nodelay(nodelay)?;
+    if let Some(idle) = keepalive {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle));
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
 
     Ok(())
 }
 
-fn gen_key() -> Result<(), icepipe::ring::error::Unspecified> {
-    let seed: [u8; 32] =
-        icepipe::ring::rand::generate(&icepipe::ring::rand::SystemRandom::new())?.expose();
-    let key = signature::Ed25519KeyPair::from_seed_unchecked(&seed)?;
+/// Byte count with an optional K, M, G or T suffix, optionally followed by
+/// `B` or `iB`.
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("iB")
+        .or_else(|| s.strip_suffix('B'))
+        .unwrap_or(s);
+    let (number, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let number: u64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("Expected a byte count like 500M or 10G, got {s:?}"))?;
+
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("{s} bytes is too large"))
+}
+
+fn gen_key() -> Result<(), Unspecified> {
+    let mut seed = [0; 32];
+    crypto_backend::fill_random(&mut seed)?;
+    let key = Ed25519KeyPair::from_seed(&seed)?;
     let private_key = seed.iter().map(|b| format!("{b:02x}")).collect::<String>();
     let public_key = key
         .public_key()
-        .as_ref()
         .iter()
         .map(|b| format!("{b:02x}"))
         .collect::<String>();
@@ -190,33 +965,124 @@ fn gen_key() -> Result<(), icepipe::ring::error::Unspecified> {
     Ok(())
 }
 
+/// Fails on an odd number of digits or anything but hex digits. `what` names
+/// the key in the error, which leaves out the key itself.
+fn hex_bytes(hex: &str, what: &str) -> StreamResult<Vec<u8>> {
+    let digit = |c: &u8| char::from(*c).to_digit(16);
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((digit(high)? << 4 | digit(low)?) as u8),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| StreamError::Other(format!("{what} must be given in hex").into()))
+}
+
 fn get_keys(
     private_key: String,
     peer: String,
 ) -> Result<
     (
-        signature::Ed25519KeyPair,
+        Ed25519KeyPair,
         Vec<u8>,
         icepipe::x25519_dalek::StaticSecret,
         icepipe::x25519_dalek::PublicKey,
     ),
-    icepipe::ring::error::Unspecified,
+    StreamError,
 > {
-    let seed = (0..private_key.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&private_key[i..][..2], 16).unwrap_or_default())
-        .collect::<Vec<_>>();
+    let seed = hex_bytes(&private_key, "Private key")?;
+    let peer = hex_bytes(&peer, "Peer key")?;
 
-    let peer = (0..peer.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&peer[i..][..2], 16).unwrap_or_default())
-        .collect::<Vec<_>>();
-
-    let private_key = icepipe::ring::signature::Ed25519KeyPair::from_seed_unchecked(&seed)?;
+    let bad_key =
+        |e: Unspecified| StreamError::Other(Box::new(icepipe::agreement::AgreementError::from(e)));
+    let private_key = Ed25519KeyPair::from_seed(&seed).map_err(bad_key)?;
 
     let x25519 = curve25519_conversion::ed25519_seed_to_x25519(&seed);
     let x25519_peer = curve25519_conversion::ed25519_public_key_to_x25519(&peer)
-        .ok_or(icepipe::ring::error::Unspecified)?;
+        .ok_or(Unspecified)
+        .map_err(bad_key)?;
 
     Ok((private_key, peer, x25519, x25519_peer))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_keys() {
+        assert_eq!(hex_bytes("00ff1A", "Key").unwrap(), [0, 255, 26]);
+        assert!(hex_bytes("", "Key").unwrap().is_empty());
+        assert!(hex_bytes("abc", "Key").is_err());
+        assert!(hex_bytes("zz", "Key").is_err());
+        assert!(hex_bytes("+1", "Key").is_err());
+        // Would split a character
+        assert!(hex_bytes("aé", "Key").is_err());
+    }
+
+    #[test]
+    fn serve_conflicts() {
+        let parse = |extra: &[&str]| {
+            let args = ["icepipe-cat", "channel", "--serve", "ssh=127.0.0.1:22"];
+            Args::try_parse_from(args.iter().chain(extra)).map(|_| ())
+        };
+        parse(&[]).unwrap();
+        for extra in [
+            &["-i", "file"][..],
+            &["-o", "file"],
+            &["-L", "127.0.0.1:2222"],
+            &["-W", "127.0.0.1:22"],
+            &["--service", "ssh"],
+        ] {
+            let e = parse(extra).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ArgumentConflict, "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn chat_conflicts() {
+        let parse = |extra: &[&str]| {
+            let args = ["icepipe-cat", "channel", "--chat"];
+            Args::try_parse_from(args.iter().chain(extra)).map(|_| ())
+        };
+        parse(&["--service", "ssh"]).unwrap();
+        for extra in [
+            &["-i", "file"][..],
+            &["-o", "file"],
+            &["-L", "127.0.0.1:2222"],
+            &["-W", "127.0.0.1:22"],
+            &["--serve", "ssh=127.0.0.1:22"],
+        ] {
+            let e = parse(extra).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ArgumentConflict, "{extra:?}");
+        }
+    }
+
+    #[test]
+    fn byte_counts() {
+        assert_eq!(parse_bytes("1234"), Ok(1234));
+        assert_eq!(parse_bytes("10G"), Ok(10 << 30));
+        assert_eq!(parse_bytes("500m"), Ok(500 << 20));
+        assert_eq!(parse_bytes("2KiB"), Ok(2048));
+        assert_eq!(parse_bytes("1TB"), Ok(1 << 40));
+        assert!(parse_bytes("1.5G").is_err());
+        assert!(parse_bytes("G").is_err());
+        assert!(parse_bytes("99999999999T").is_err());
+    }
+
+    #[tokio::test]
+    async fn tcp_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tune_tcp(&stream, true, Some(30)).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+
+        tune_tcp(&stream, false, None).unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+}
@@ -0,0 +1,52 @@
+//! --profile and --save-profile, see [`icepipe::profile`].
+
+use icepipe::{
+    pipe_stream::{StreamError, StreamResult},
+    profile::{ConnectionProfile, Profiles, Secret},
+    ConnectOptions,
+};
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// `icepipe/profiles.json` in the configuration directory of the user, if
+/// there is one.
+pub fn default_path() -> Option<PathBuf> {
+    let config = match cfg!(windows) {
+        true => env::var_os("APPDATA").map(PathBuf::from),
+        false => env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".config"))),
+    };
+
+    Some(config?.join("icepipe").join("profiles.json"))
+}
+
+/// Fails without a path, when there is no [`default_path`].
+pub fn required(path: Option<&Path>) -> StreamResult<&Path> {
+    path.ok_or_else(|| StreamError::Other("No configuration directory, pass --profiles".into()))
+}
+
+pub fn load(path: &Path) -> StreamResult<Profiles> {
+    Profiles::load(path).map_err(|e| StreamError::Other(Box::new(e)))
+}
+
+/// Adds the profile `name` to those in `path`, replacing one of the same
+/// name.
+pub fn save(
+    path: &Path,
+    name: &str,
+    options: &ConnectOptions,
+    private_key: Option<String>,
+) -> StreamResult<()> {
+    let mut profile =
+        ConnectionProfile::from_options(options).map_err(|e| StreamError::Other(Box::new(e)))?;
+    profile.private_key = private_key.map(Secret::new);
+
+    let mut profiles = load(path)?;
+    profiles.insert(name, profile);
+    profiles
+        .save(path)
+        .map_err(|e| StreamError::Other(Box::new(e)))
+}
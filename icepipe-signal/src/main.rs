@@ -0,0 +1,24 @@
+mod server;
+
+use clap::Parser;
+use std::{io, net::SocketAddr};
+use tokio::net::TcpListener;
+
+/// Signalling server pairing icepipe peers that join the same channel
+#[derive(Parser)]
+struct Args {
+    /// Address to listen on, clients connect to ws://<address>/. Put it
+    /// behind a TLS terminating proxy for wss://
+    #[clap(long = "bind", default_value = "0.0.0.0:8080")]
+    bind: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    env_logger::init();
+    let args = Args::parse();
+
+    let listener = TcpListener::bind(args.bind).await?;
+    log::info!("Listening on {}", listener.local_addr()?);
+    server::serve(listener).await
+}
@@ -0,0 +1,352 @@
+//! The protocol [`Websocket`](icepipe::ws::Websocket) expects. The path of
+//! the request is the channel, the first client of a channel waits for a
+//! second one. Once paired the first is told `DIALER` and the second
+//! `LISTENER`, then text messages are relayed between them until either
+//! leaves, which closes the other as well. A third client of the channel
+//! waits for a new peer.
+//!
+//! Pings are answered by the websocket, a client silent for longer than
+//! [`IDLE_TIMEOUT`] is dropped. Clients ping every 15s. So is one that does
+//! not complete the handshake within [`HANDSHAKE_TIMEOUT`] or sends a
+//! message over [`MAX_MESSAGE_SIZE`].
+
+use futures::{SinkExt, StreamExt};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::oneshot,
+    time::{sleep, sleep_until, timeout, Instant},
+};
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        protocol::WebSocketConfig,
+        Message,
+    },
+    WebSocketStream,
+};
+
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Limit of both messages and frames. Descriptions, the largest messages
+/// peers send, take a few KiB.
+pub const MAX_MESSAGE_SIZE: usize = 64 << 10;
+/// Pause after failing to accept a connection, e.g. out of file descriptors,
+/// before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+type Ws = WebSocketStream<TcpStream>;
+
+struct Waiting {
+    id: u64,
+    peer: oneshot::Sender<Ws>,
+}
+
+/// Clients waiting for a peer, by channel.
+#[derive(Clone, Default)]
+struct Channels {
+    waiting: Arc<Mutex<HashMap<String, Waiting>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+enum Joined {
+    /// Handed to the waiting client, whose task relays.
+    Paired(oneshot::Sender<Ws>),
+    Waiting(u64, oneshot::Receiver<Ws>),
+}
+
+impl Channels {
+    fn join(&self, channel: &str) -> Joined {
+        let mut waiting = self.waiting.lock().unwrap();
+        if let Some(first) = waiting.remove(channel) {
+            return Joined::Paired(first.peer);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (peer, rx) = oneshot::channel();
+        waiting.insert(channel.to_owned(), Waiting { id, peer });
+        Joined::Waiting(id, rx)
+    }
+
+    fn leave(&self, channel: &str, id: u64) {
+        let mut waiting = self.waiting.lock().unwrap();
+        if waiting.get(channel).is_some_and(|w| w.id == id) {
+            waiting.remove(channel);
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)] // The handshake callback signature is given by tungstenite
+pub async fn serve(listener: TcpListener) -> io::Result<()> {
+    let channels = Channels::default();
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    loop {
+        let (tcp, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("Accepting a client failed: {e}");
+                sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let channels = channels.clone();
+        tokio::spawn(async move {
+            let mut channel = String::new();
+            let handshake = accept_hdr_async_with_config(
+                tcp,
+                |request: &Request, response: Response| {
+                    channel = request.uri().path().to_owned();
+                    Ok(response)
+                },
+                Some(config),
+            );
+            match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(ws)) => {
+                    log::debug!("{addr} joined {channel}");
+                    join(ws, channel, channels).await;
+                }
+                Ok(Err(e)) => log::debug!("Handshake with {addr} failed: {e}"),
+                Err(_) => log::debug!("Handshake with {addr} timed out"),
+            }
+        });
+    }
+}
+
+async fn join(mut ws: Ws, channel: String, channels: Channels) {
+    loop {
+        match channels.join(&channel) {
+            Joined::Paired(first) => match first.send(ws) {
+                Ok(()) => return,
+                // The first client left meanwhile
+                Err(back) => ws = back,
+            },
+            Joined::Waiting(id, mut peer) => {
+                let waited = wait(&mut ws, &mut peer).await;
+                channels.leave(&channel, id);
+                match waited {
+                    Some(listener) => return relay(ws, listener, &channel).await,
+                    None => match peer.try_recv() {
+                        // Paired right as this client left
+                        Ok(listener) => ws = listener,
+                        Err(_) => return,
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// The peer, `None` if the client left first.
+async fn wait(ws: &mut Ws, peer: &mut oneshot::Receiver<Ws>) -> Option<Ws> {
+    let mut deadline = Instant::now() + IDLE_TIMEOUT;
+    loop {
+        select! {
+            listener = &mut *peer => return listener.ok(),
+            msg = ws.next() => match msg {
+                Some(Ok(_)) => deadline = Instant::now() + IDLE_TIMEOUT,
+                _ => return None,
+            },
+            _ = sleep_until(deadline) => return None,
+        }
+    }
+}
+
+async fn relay(mut dialer: Ws, mut listener: Ws, channel: &str) {
+    if dialer.send(Message::Text("DIALER".into())).await.is_err()
+        || listener
+            .send(Message::Text("LISTENER".into()))
+            .await
+            .is_err()
+    {
+        return;
+    }
+    log::debug!("Paired {channel}");
+
+    let (mut dialer_tx, mut dialer_rx) = dialer.split();
+    let (mut listener_tx, mut listener_rx) = listener.split();
+    let mut dialer_deadline = Instant::now() + IDLE_TIMEOUT;
+    let mut listener_deadline = dialer_deadline;
+    loop {
+        let r = select! {
+            msg = dialer_rx.next() => match msg {
+                Some(Ok(msg)) => {
+                    dialer_deadline = Instant::now() + IDLE_TIMEOUT;
+                    match msg {
+                        msg @ Message::Text(_) => listener_tx.send(msg).await,
+                        _ => Ok(()),
+                    }
+                }
+                _ => break,
+            },
+            msg = listener_rx.next() => match msg {
+                Some(Ok(msg)) => {
+                    listener_deadline = Instant::now() + IDLE_TIMEOUT;
+                    match msg {
+                        msg @ Message::Text(_) => dialer_tx.send(msg).await,
+                        _ => Ok(()),
+                    }
+                }
+                _ => break,
+            },
+            _ = sleep_until(dialer_deadline.min(listener_deadline)) => break,
+        };
+        if r.is_err() {
+            break;
+        }
+    }
+    log::debug!("Closed {channel}");
+
+    let _ = dialer_tx.close().await;
+    let _ = listener_tx.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use icepipe::{
+        pipe_stream::{Control, PipeStream, WaitThen},
+        signalling::Signalling,
+        ws::Websocket,
+        ConnectOptions,
+    };
+    use tokio::{io::AsyncReadExt, time::sleep};
+
+    async fn server() -> url::Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener));
+        url.parse().unwrap()
+    }
+
+    async fn recv(ws: &mut Websocket) -> String {
+        loop {
+            let mut value = ws.wait().await.unwrap();
+            if let Some(msg) = ws.then(&mut value).await.unwrap() {
+                return msg;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn assigns_roles_and_relays() {
+        let url = server().await.join("channel").unwrap();
+        let (dialer, listener) = tokio::join!(Websocket::new(url.clone()), async {
+            sleep(Duration::from_millis(100)).await;
+            Websocket::new(url.clone()).await
+        });
+        let ((mut dialer, dialer_role), (mut listener, listener_role)) =
+            (dialer.unwrap(), listener.unwrap());
+        assert!(dialer_role);
+        assert!(!listener_role);
+
+        dialer.send("to listener".to_string()).await.unwrap();
+        assert_eq!(recv(&mut listener).await, "to listener");
+        listener.send("to dialer".to_string()).await.unwrap();
+        assert_eq!(recv(&mut dialer).await, "to dialer");
+
+        // Leaving closes the peer as well
+        drop(dialer);
+        let closed = timeout(Duration::from_secs(5), async {
+            while let Ok(mut value) = listener.wait().await {
+                if listener.then(&mut value).await.is_err() {
+                    break;
+                }
+            }
+        });
+        closed.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_client_leaves() {
+        let url = server().await.join("channel").unwrap();
+        let left = tokio_tungstenite::connect_async(url.clone())
+            .await
+            .unwrap()
+            .0;
+        drop(left);
+        sleep(Duration::from_millis(100)).await;
+
+        let (a, b) = timeout(Duration::from_secs(5), async {
+            tokio::join!(Websocket::new(url.clone()), Websocket::new(url.clone()))
+        })
+        .await
+        .unwrap();
+        assert_ne!(a.unwrap().1, b.unwrap().1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_silent_handshakes() {
+        let url = server().await;
+        let addr = url.socket_addrs(|| None).unwrap()[0];
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        let start = Instant::now();
+        assert_eq!(silent.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(start.elapsed() >= HANDSHAKE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn drops_oversized_messages() {
+        let url = server().await.join("channel").unwrap();
+        let (a, b) = tokio::join!(
+            tokio_tungstenite::connect_async(url.clone()),
+            tokio_tungstenite::connect_async(url.clone()),
+        );
+        let (mut a, mut b) = (a.unwrap().0, b.unwrap().0);
+        for ws in [&mut a, &mut b] {
+            assert!(matches!(ws.next().await, Some(Ok(Message::Text(_)))));
+        }
+
+        a.send(Message::Text("x".repeat(MAX_MESSAGE_SIZE + 1)))
+            .await
+            .unwrap();
+        let closed = timeout(Duration::from_secs(5), async {
+            while let Some(Ok(msg)) = b.next().await {
+                assert!(!matches!(msg, Message::Text(_)));
+            }
+        });
+        closed.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connects_peers() {
+        let options = ConnectOptions {
+            channel: "icepipe-signal".into(),
+            signaling: Some(server().await),
+            ice: vec!["stun:127.0.0.1:3478".to_string()],
+            ..Default::default()
+        };
+        let (a, b) = timeout(Duration::from_secs(30), async {
+            tokio::join!(options.clone().connect_psk(), options.connect_psk())
+        })
+        .await
+        .unwrap();
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        a.send(b"self hosted").await.unwrap();
+        let received = loop {
+            let mut value = b.wait().await.unwrap();
+            if let Some(data) = b.then(&mut value).await.unwrap() {
+                break data;
+            }
+        };
+        assert_eq!(received, b"self hosted");
+
+        let (a, b) = tokio::join!(a.close(), b.close());
+        a.unwrap();
+        b.unwrap();
+    }
+}
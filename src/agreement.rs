@@ -1,17 +1,17 @@
 use crate::{
+    channel::{Channel, Psk},
+    crypto_backend::{self, Ed25519KeyPair, Unspecified, X25519EphemeralKey},
     error::TimeoutError,
-    signalling::{SignalingError, Signalling},
+    logging,
+    signalling::{Rejection, SignalingError, Signalling},
+    trace,
 };
 use base64::{
     prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD},
     Engine,
 };
-use ring::{
-    agreement, hmac, pbkdf2,
-    rand::SystemRandom,
-    signature::{self, VerificationAlgorithm},
-};
-use std::{io, num::NonZeroU32};
+use std::{collections::HashSet, fmt, io, num::NonZeroU32, time::Duration};
+use tokio::time::timeout;
 
 pub struct Agreement<T, A>
 where
@@ -32,39 +32,82 @@ where
         Self { signalling, auth }
     }
 
-    pub async fn agree(mut self) -> AgreementResult<(Vec<u8>, T)> {
-        let rng = SystemRandom::new();
-        let my_private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &rng)?;
-        let my_public_key = my_private_key.compute_public_key()?;
+    /// Agrees on a key with the peer, handing it `local_payload` along with
+    /// the public key, signed by the same [`Authentication`]. Either side
+    /// may go without a payload, the peer's is `None` then.
+    pub async fn agree(mut self, local_payload: Option<Vec<u8>>) -> AgreementResult<Agreed<T>> {
+        if let Some(payload) = &local_payload {
+            check_payload(payload)?;
+        }
+        let my_private_key = X25519EphemeralKey::generate()?;
+        let my_public_key = BASE64_STANDARD.encode(my_private_key.public_key());
 
-        self.signalling
-            .send(BASE64_STANDARD.encode(&my_public_key))
-            .await
-            .map_err(Into::into)?;
+        let message = match &local_payload {
+            None => my_public_key,
+            Some(payload) => format!(
+                "{PAYLOAD}\0{my_public_key}\0{}",
+                BASE64_STANDARD.encode(payload)
+            ),
+        };
+        self.signalling.send(message).await.map_err(Into::into)?;
 
+        let signature = self.auth.sign(&signed(
+            my_private_key.public_key(),
+            local_payload.as_deref(),
+        ));
         self.signalling
-            .send(BASE64_STANDARD.encode(self.auth.sign(my_public_key.as_ref())))
+            .send(BASE64_STANDARD.encode(signature))
             .await
             .map_err(Into::into)?;
 
-        let peer_public_key = self.signalling_recv().await?;
-        let peer_public_key = BASE64_STANDARD.decode(&peer_public_key)?;
+        trace::debug!(target: logging::AGREEMENT, "TX public key");
+
+        let message = self.signalling_recv().await?;
+        let (peer_public_key, peer_payload) = match message.split('\0').collect::<Vec<_>>()[..] {
+            [PAYLOAD, peer_public_key, peer_payload] => {
+                (peer_public_key, Some(BASE64_STANDARD.decode(peer_payload)?))
+            }
+            _ => (message.as_str(), None),
+        };
+        let peer_public_key = BASE64_STANDARD.decode(peer_public_key)?;
+        if let Some(payload) = &peer_payload {
+            check_payload(payload)?;
+        }
         let peer_public_key_signature = self.signalling_recv().await?;
         let peer_public_key_signature = BASE64_STANDARD.decode(peer_public_key_signature)?;
-        self.auth
-            .check_peer(&peer_public_key, &peer_public_key_signature)
-            .map_err(|e| AgreementError::BadAuth(Box::new(e)))?;
-        let peer_public_key =
-            agreement::UnparsedPublicKey::new(&agreement::X25519, peer_public_key);
+        let checked = self.auth.check_peer(
+            &signed(&peer_public_key, peer_payload.as_deref()),
+            &peer_public_key_signature,
+        );
+        let peer_identity = match checked {
+            Ok(identity) => identity,
+            Err(e) => {
+                // The peer may have authenticated this side and moved on, it
+                // learns it is the one rejected. Leaving at once would fail the
+                // message it sends next instead, its next one is awaited.
+                trace::debug!(
+                    target: logging::AGREEMENT,
+                    "Peer failed authentication, rejecting it"
+                );
+                if let Ok(basekey) = my_private_key.agree(&peer_public_key) {
+                    let notice = Rejection::notice(&basekey);
+                    if self.signalling.send(notice).await.is_ok() {
+                        let _ = timeout(REJECTED_LINGER, self.signalling_recv()).await;
+                    }
+                }
+                return Err(AgreementError::BadAuth(Box::new(e)));
+            }
+        };
+        trace::debug!(target: logging::AGREEMENT, "Peer authenticated");
 
-        let key_material = agreement::agree_ephemeral(
-            my_private_key,
-            &peer_public_key,
-            ring::error::Unspecified,
-            |key_material| Ok(key_material.to_owned()),
-        )?;
+        let basekey = my_private_key.agree(&peer_public_key)?;
 
-        Ok((key_material, self.signalling))
+        Ok(Agreed {
+            basekey,
+            signalling: self.signalling,
+            peer_payload,
+            peer_public_key: peer_identity,
+        })
     }
 
     async fn signalling_recv(&mut self) -> AgreementResult<String> {
@@ -79,6 +122,46 @@ where
     }
 }
 
+/// How long a rejected peer is given to read the notice before this side
+/// leaves, when the peer does not send anything.
+const REJECTED_LINGER: Duration = Duration::from_secs(1);
+
+/// Prefixes the public key when a payload goes along with it.
+const PAYLOAD: &str = "icepipe-payload";
+
+/// Largest payload handed over by [`Agreement::agree`], in bytes.
+pub const MAX_PAYLOAD: usize = 4096;
+
+/// What [`Agreement::agree`] settled on.
+pub struct Agreed<T> {
+    /// Key material shared with the peer.
+    pub basekey: Vec<u8>,
+    /// Returned for the exchanges following the agreement.
+    pub signalling: T,
+    /// Authenticated along with the peer's public key.
+    pub peer_payload: Option<Vec<u8>>,
+    /// Key the peer signed with, as returned by
+    /// [`Authentication::check_peer`].
+    pub peer_public_key: Option<Vec<u8>>,
+}
+
+/// What is signed for `public_key`. A payload is tagged after the key, which
+/// has a fixed length, so one cannot be stripped nor a signature without it
+/// passed for one with an empty payload.
+fn signed(public_key: &[u8], payload: Option<&[u8]>) -> Vec<u8> {
+    match payload {
+        None => public_key.to_vec(),
+        Some(payload) => [public_key, PAYLOAD.as_bytes(), payload].concat(),
+    }
+}
+
+fn check_payload(payload: &[u8]) -> AgreementResult<()> {
+    match payload.len() {
+        len if len > MAX_PAYLOAD => Err(AgreementError::PayloadTooLarge(len)),
+        _ => Ok(()),
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum AgreementError {
     #[error(transparent)]
@@ -90,9 +173,13 @@ pub enum AgreementError {
     #[error(transparent)]
     Base64Error(#[from] base64::DecodeError),
     #[error("Crypto error")]
-    CryptoError(ring::error::Unspecified),
-    #[error("Mismatch authentication tag on key agreement based on PSK, {0}")]
+    CryptoError(Unspecified),
+    /// The peer did not sign with the expected key or PSK. The peer is
+    /// told, failing with [`PeerRejected`](crate::signalling::PeerRejected).
+    #[error("Mismatch authentication tag on key agreement, the peer is not the one expected or uses another PSK: {0}")]
     BadAuth(Box<AgreementError>),
+    #[error("Agreement payload of {0} bytes, at most {MAX_PAYLOAD} are allowed")]
+    PayloadTooLarge(usize),
 }
 impl From<SignalingError> for AgreementError {
     fn from(value: SignalingError) -> Self {
@@ -103,8 +190,8 @@ impl From<SignalingError> for AgreementError {
         }
     }
 }
-impl From<ring::error::Unspecified> for AgreementError {
-    fn from(value: ring::error::Unspecified) -> Self {
+impl From<Unspecified> for AgreementError {
+    fn from(value: Unspecified) -> Self {
         Self::CryptoError(value)
     }
 }
@@ -112,16 +199,77 @@ pub type AgreementResult<T> = Result<T, AgreementError>;
 
 pub trait Authentication {
     fn sign(&self, data: &[u8]) -> Vec<u8>;
-    fn check_peer(&self, data: &[u8], signature: &[u8]) -> AgreementResult<()>;
+    /// Checks the peer signed `data`, returning the key it signed with if
+    /// this authentication tells keys apart, e.g. out of an allowlist.
+    fn check_peer(&self, data: &[u8], signature: &[u8]) -> AgreementResult<Option<Vec<u8>>>;
+    /// Key the peer is expected to sign with, if it has one of its own.
+    fn peer_public_key(&self) -> Option<Vec<u8>> {
+        None
+    }
+    /// How peers are authenticated, checked against
+    /// [`SecurityFloor::min_auth`](crate::negotiation::SecurityFloor::min_auth).
+    fn kind(&self) -> AuthKind {
+        AuthKind::Custom
+    }
+}
+impl<A: Authentication + ?Sized> Authentication for &A {
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        (**self).sign(data)
+    }
+
+    fn check_peer(&self, data: &[u8], signature: &[u8]) -> AgreementResult<Option<Vec<u8>>> {
+        (**self).check_peer(data, signature)
+    }
+
+    fn peer_public_key(&self) -> Option<Vec<u8>> {
+        (**self).peer_public_key()
+    }
+
+    fn kind(&self) -> AuthKind {
+        (**self).kind()
+    }
+}
+
+/// Weakest first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AuthKind {
+    /// An [`Authentication`] of the application, which icepipe cannot vouch
+    /// for.
+    #[default]
+    Custom,
+    /// A secret shared by both peers, see [`PskAuthentication`].
+    Psk,
+    /// Ed25519 keys, see [`Ed25519PairAndPeer`].
+    Key,
+}
+impl AuthKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AuthKind::Custom => "custom",
+            AuthKind::Psk => "psk",
+            AuthKind::Key => "key",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<AuthKind> {
+        [AuthKind::Custom, AuthKind::Psk, AuthKind::Key]
+            .into_iter()
+            .find(|kind| kind.name() == name)
+    }
+}
+impl fmt::Display for AuthKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
+#[derive(Clone)]
 pub struct PskAuthentication {
-    psk: String,
+    key: Vec<u8>,
 }
 impl PskAuthentication {
     pub fn derive(basekey: &str, salt: &str, out: &mut [u8]) {
-        pbkdf2::derive(
-            pbkdf2::PBKDF2_HMAC_SHA512,
+        crypto_backend::pbkdf2_hmac_sha512(
             NonZeroU32::new(4096).unwrap(),
             salt.as_bytes(),
             basekey.as_bytes(),
@@ -139,34 +287,281 @@ impl PskAuthentication {
         BASE64_URL_SAFE_NO_PAD.encode(Self::derive_len(basekey, salt, 32))
     }
 
+    /// Derives the key authenticating the agreement right away, clone it
+    /// rather than deriving again.
     pub fn new(psk: String) -> PskAuthentication {
-        PskAuthentication { psk }
+        PskAuthentication {
+            key: Self::derive_len(&psk, "keymaterial_check", 32),
+        }
     }
 
-    fn key(&self) -> hmac::Key {
-        hmac::Key::new(
-            hmac::HMAC_SHA512,
-            &Self::derive_len(&self.psk, "keymaterial_check", 32),
-        )
+    /// [`PskAuthentication::new`] off the runtime, see [`blocking`].
+    pub async fn new_blocking(psk: Psk) -> PskAuthentication {
+        blocking(move || Self::new(psk.expose().to_owned())).await
     }
 }
 impl Authentication for PskAuthentication {
     fn sign(&self, data: &[u8]) -> Vec<u8> {
-        hmac::sign(&self.key(), data).as_ref().to_owned()
+        crypto_backend::hmac_sha512_sign(&self.key, data)
+    }
+
+    fn check_peer(&self, data: &[u8], signature: &[u8]) -> AgreementResult<Option<Vec<u8>>> {
+        crypto_backend::hmac_sha512_verify(&self.key, data, signature)?;
+        Ok(None)
     }
 
-    fn check_peer(&self, data: &[u8], signature: &[u8]) -> AgreementResult<()> {
-        Ok(hmac::verify(&self.key(), data, signature)?)
+    fn kind(&self) -> AuthKind {
+        AuthKind::Psk
     }
 }
 
-pub struct Ed25519PairAndPeer(pub signature::Ed25519KeyPair, pub Vec<u8>);
+/// What is derived from a PSK, deriving is slow on purpose so reconnecting
+/// peers keep it, see
+/// [`ConnectOptions::connect_psk_material`](crate::connect::ConnectOptions::connect_psk_material).
+/// The ephemeral keys are still fresh on every agreement.
+#[derive(Clone)]
+pub struct PskMaterial {
+    /// Joined on the signalling server.
+    pub channel: Channel,
+    pub auth: PskAuthentication,
+}
+impl PskMaterial {
+    pub fn derive(psk: &Psk) -> PskMaterial {
+        PskMaterial {
+            channel: psk.channel(),
+            auth: PskAuthentication::new(psk.expose().to_owned()),
+        }
+    }
+
+    /// [`PskMaterial::derive`] off the runtime, see [`blocking`].
+    pub async fn derive_blocking(psk: Psk) -> PskMaterial {
+        blocking(move || Self::derive(&psk)).await
+    }
+}
+
+/// Runs a key derivation on the blocking thread pool of the runtime. They
+/// are slow on purpose, run on the runtime one stalls every other task of
+/// its thread for as long.
+pub async fn blocking<T, F>(derive: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match tokio::task::spawn_blocking(derive).await {
+        Ok(derived) => derived,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+pub struct Ed25519PairAndPeer(pub Ed25519KeyPair, pub Vec<u8>);
 impl Authentication for Ed25519PairAndPeer {
     fn sign(&self, data: &[u8]) -> Vec<u8> {
-        self.0.sign(data).as_ref().to_owned()
+        self.0.sign(data)
+    }
+
+    fn check_peer(&self, data: &[u8], signature: &[u8]) -> AgreementResult<Option<Vec<u8>>> {
+        crypto_backend::ed25519_verify(&self.1, data, signature)?;
+        Ok(Some(self.1.clone()))
+    }
+
+    fn peer_public_key(&self) -> Option<Vec<u8>> {
+        Some(self.1.clone())
+    }
+
+    fn kind(&self) -> AuthKind {
+        AuthKind::Key
     }
+}
 
-    fn check_peer(&self, data: &[u8], signature: &[u8]) -> AgreementResult<()> {
-        Ok(signature::ED25519.verify(self.1.as_slice().into(), data.into(), signature.into())?)
+/// Accepts any peer signing with one of the `allowed` keys, e.g. the clients
+/// of a server. The key that matched is returned by the check, see
+/// [`Agreed::peer_public_key`], so one instance serves any number of
+/// concurrent agreements.
+pub struct Ed25519PairAndAllowlist {
+    key_pair: Ed25519KeyPair,
+    allowed: HashSet<Vec<u8>>,
+}
+impl Ed25519PairAndAllowlist {
+    pub fn new(key_pair: Ed25519KeyPair, allowed: HashSet<Vec<u8>>) -> Ed25519PairAndAllowlist {
+        Ed25519PairAndAllowlist { key_pair, allowed }
+    }
+}
+impl Authentication for Ed25519PairAndAllowlist {
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.key_pair.sign(data)
+    }
+
+    fn check_peer(&self, data: &[u8], signature: &[u8]) -> AgreementResult<Option<Vec<u8>>> {
+        let matched = self
+            .allowed
+            .iter()
+            .find(|key| crypto_backend::ed25519_verify(key, data, signature).is_ok())
+            .ok_or(Unspecified)?;
+        Ok(Some(matched.clone()))
+    }
+
+    fn kind(&self) -> AuthKind {
+        AuthKind::Key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory_signalling::MemorySignalling, pipe_stream::WaitThen, signalling::PeerRejected,
+    };
+    use futures::future::LocalBoxFuture;
+
+    fn psk() -> PskAuthentication {
+        PskAuthentication::new("payload".to_string())
+    }
+
+    #[tokio::test]
+    async fn payloads() {
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            Agreement::new(a, psk()).agree(Some(b"dialer".to_vec())),
+            Agreement::new(b, psk()).agree(Some(Vec::new())),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.basekey, b.basekey);
+        assert_eq!(a.peer_payload.as_deref(), Some(&b""[..]));
+        assert_eq!(b.peer_payload.as_deref(), Some(&b"dialer"[..]));
+
+        // Either side may go without
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            Agreement::new(a, psk()).agree(Some(b"dialer".to_vec())),
+            Agreement::new(b, psk()).agree(None),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.basekey, b.basekey);
+        assert_eq!(a.peer_payload, None);
+        assert_eq!(b.peer_payload.as_deref(), Some(&b"dialer"[..]));
+    }
+
+    #[tokio::test]
+    async fn tampered_payload() {
+        let replaced = |message: String| match message.split('\0').collect::<Vec<_>>()[..] {
+            [PAYLOAD, key, _] => format!("{PAYLOAD}\0{key}\0{}", BASE64_STANDARD.encode("evil")),
+            _ => message,
+        };
+        let stripped = |message: String| match message.split('\0').collect::<Vec<_>>()[..] {
+            [PAYLOAD, key, _] => key.to_string(),
+            _ => message,
+        };
+
+        for tamper in [replaced, stripped] {
+            let (a, b) = MemorySignalling::pair();
+            let a = Tamper { inner: a, tamper };
+            let (_, b) = tokio::join!(
+                Agreement::new(a, psk()).agree(Some(b"dialer".to_vec())),
+                Agreement::new(b, psk()).agree(None),
+            );
+            assert!(matches!(b, Err(AgreementError::BadAuth(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn reused_material() {
+        let material = PskMaterial::derive(&Psk::new("payload"));
+        assert_eq!(
+            material.channel.as_str(),
+            PskAuthentication::derive_text("payload", "channel")
+        );
+
+        let mut basekeys = Vec::new();
+        for _ in 0..2 {
+            let (a, b) = MemorySignalling::pair();
+            let (a, b) = tokio::join!(
+                Agreement::new(a, material.auth.clone()).agree(None),
+                Agreement::new(b, psk()).agree(None),
+            );
+            let (a, b) = (a.unwrap(), b.unwrap());
+            assert_eq!(a.basekey, b.basekey);
+            basekeys.push(a.basekey);
+        }
+        // Fresh ephemeral keys on every agreement
+        assert_ne!(basekeys[0], basekeys[1]);
+    }
+
+    #[tokio::test]
+    async fn payload_too_large() {
+        let (a, _b) = MemorySignalling::pair();
+        let r = Agreement::new(a, psk())
+            .agree(Some(vec![0; MAX_PAYLOAD + 1]))
+            .await;
+        assert!(matches!(r, Err(AgreementError::PayloadTooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn shared_allowlist() {
+        let key_pair = |seed| Ed25519KeyPair::from_seed(&[seed; 32]).unwrap();
+        let public_key = |seed| key_pair(seed).public_key().to_vec();
+        let server = Ed25519PairAndAllowlist::new(
+            key_pair(0),
+            HashSet::from([public_key(1), public_key(2)]),
+        );
+
+        // Both agreements run at once on the same allowlist, each learns the
+        // key of its own peer
+        let (a1, b1) = MemorySignalling::pair();
+        let (a2, b2) = MemorySignalling::pair();
+        let (a1, a2, b1, b2) = tokio::join!(
+            Agreement::new(a1, &server).agree(None),
+            Agreement::new(a2, &server).agree(None),
+            Agreement::new(b1, Ed25519PairAndPeer(key_pair(1), public_key(0))).agree(None),
+            Agreement::new(b2, Ed25519PairAndPeer(key_pair(2), public_key(0))).agree(None),
+        );
+        assert_eq!(a1.unwrap().peer_public_key, Some(public_key(1)));
+        assert_eq!(a2.unwrap().peer_public_key, Some(public_key(2)));
+        assert_eq!(b1.unwrap().peer_public_key, Some(public_key(0)));
+        assert_eq!(b2.unwrap().peer_public_key, Some(public_key(0)));
+    }
+
+    #[test]
+    fn forged_rejection() {
+        let rejected = |check: &Rejection, notice: &str| match check.check(notice) {
+            Err(SignalingError::ProtocolError(e)) => e.is::<PeerRejected>(),
+            _ => false,
+        };
+        let check = Rejection::new(b"basekey");
+        assert!(rejected(&check, &Rejection::notice(b"basekey")));
+        assert!(!rejected(&check, &Rejection::notice(b"another basekey")));
+        // As sent before notices were authenticated, or by the server
+        assert!(!rejected(&check, "icepipe-rejected"));
+        assert!(!rejected(&check, "icepipe-rejected\0garbage"));
+        assert!(!rejected(
+            &Rejection::default(),
+            &Rejection::notice(b"basekey")
+        ));
+    }
+
+    /// Rewrites what is sent through it.
+    struct Tamper {
+        inner: MemorySignalling,
+        tamper: fn(String) -> String,
+    }
+    impl WaitThen for Tamper {
+        type Value = String;
+        type Output = Option<String>;
+        type Error = SignalingError;
+
+        fn wait(&mut self) -> LocalBoxFuture<'_, Result<String, SignalingError>> {
+            self.inner.wait()
+        }
+
+        fn then<'a>(
+            &'a mut self,
+            value: &'a mut String,
+        ) -> LocalBoxFuture<'a, Result<Option<String>, SignalingError>> {
+            self.inner.then(value)
+        }
+    }
+    impl Signalling for Tamper {
+        fn send(&mut self, msg: String) -> LocalBoxFuture<'_, Result<(), SignalingError>> {
+            self.inner.send((self.tamper)(msg))
+        }
     }
 }
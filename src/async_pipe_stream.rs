@@ -1,4 +1,4 @@
-use crate::pipe_stream::{Control, PipeStream, WaitThen};
+use crate::pipe_stream::{CloseReason, Control, PipeStream, WaitThen};
 use futures::{
     future::{ready, LocalBoxFuture},
     FutureExt,
@@ -9,11 +9,35 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 pub type DynAsyncRead = Pin<Box<dyn AsyncRead>>;
 pub type DynAsyncWrite = Pin<Box<dyn AsyncWrite>>;
 
+const CHUNK_LEN: usize = 4096;
+
+/// How the input is divided into messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Whatever a single read returns, up to 4096 bytes.
+    #[default]
+    Chunks,
+    /// One message per line, newline included. Lines longer than `max_len`
+    /// are sent in pieces of `max_len` bytes rather than buffered whole.
+    Lines { max_len: usize },
+    /// Each message preceded by its length as a big endian `u32`, on both
+    /// input and output, so messages keep their boundaries over a byte
+    /// stream. Longer messages than `max_len` fail with
+    /// [`InvalidData`](io::ErrorKind::InvalidData).
+    LengthPrefixed { max_len: usize },
+}
+
+const PREFIX_LEN: usize = 4;
+
 pub struct AsyncPipeStream {
     input: Pin<Box<dyn AsyncRead>>,
     output: Pin<Box<dyn AsyncWrite>>,
     rx_shut: bool,
     buf: Vec<u8>,
+    framing: Framing,
+    /// Read but not yet emitted, only used with [`Framing::Lines`] and
+    /// [`Framing::LengthPrefixed`].
+    pending: Vec<u8>,
 }
 impl AsyncPipeStream {
     pub fn new<I, O>(input: I, output: O) -> AsyncPipeStream
@@ -33,6 +57,60 @@ impl AsyncPipeStream {
             output,
             rx_shut: false,
             buf: Vec::new(),
+            framing: Framing::Chunks,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn with_framing(mut self, framing: Framing) -> AsyncPipeStream {
+        self.framing = framing;
+        self
+    }
+
+    /// Length of the next line in `pending`, 0 once the input ended.
+    async fn wait_line(&mut self, max_len: usize) -> io::Result<usize> {
+        let max_len = max_len.max(1);
+        loop {
+            if let Some(pos) = self.pending.iter().position(|&b| b == b'\n') {
+                return Ok((pos + 1).min(max_len));
+            }
+            if self.pending.len() >= max_len {
+                return Ok(max_len);
+            }
+
+            let n = self.input.read(&mut self.buf).await?;
+            if n == 0 {
+                return Ok(self.pending.len());
+            }
+            self.pending.extend_from_slice(&self.buf[..n]);
+        }
+    }
+
+    /// Length of the next message in `pending` along with its prefix, 0 once
+    /// the input ended.
+    async fn wait_prefixed(&mut self, max_len: usize) -> io::Result<usize> {
+        loop {
+            if let Some(prefix) = self.pending.first_chunk::<PREFIX_LEN>() {
+                let len = u32::from_be_bytes(*prefix) as usize;
+                if len > max_len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Message of {len} bytes, up to {max_len} expected"),
+                    ));
+                }
+                if self.pending.len() >= PREFIX_LEN + len {
+                    return Ok(PREFIX_LEN + len);
+                }
+            }
+
+            let n = self.input.read(&mut self.buf).await?;
+            if n == 0 {
+                return match self.pending.is_empty() {
+                    true => Ok(0),
+                    false => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+            self.pending.extend_from_slice(&self.buf[..n]);
         }
     }
 
@@ -43,6 +121,18 @@ impl AsyncPipeStream {
 impl PipeStream for AsyncPipeStream {
     fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, io::Result<()>> {
         async move {
+            if let Framing::LengthPrefixed { max_len } = self.framing {
+                let len = u32::try_from(data.len())
+                    .ok()
+                    .filter(|len| *len as usize <= max_len)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Message of {} bytes, up to {max_len} allowed", data.len()),
+                        )
+                    })?;
+                self.output.write_all(&len.to_be_bytes()).await?;
+            }
             self.output.write_all(data).await?;
             self.output.flush().await?;
             Ok(())
@@ -55,11 +145,16 @@ impl WaitThen for AsyncPipeStream {
     type Output = Option<Vec<u8>>;
     type Error = io::Error;
 
+    /// Cancel safe, a partial line or message stays in `pending` and a
+    /// cancelled read reads nothing.
     fn wait(&mut self) -> LocalBoxFuture<'_, io::Result<Self::Value>> {
-        self.buf.resize(4096, 0);
+        self.buf.resize(CHUNK_LEN, 0);
         async move {
-            let n = self.input.read(&mut self.buf).await?;
-            Ok(n)
+            match self.framing {
+                Framing::Chunks => Ok(self.input.read(&mut self.buf).await?),
+                Framing::Lines { max_len } => self.wait_line(max_len).await,
+                Framing::LengthPrefixed { max_len } => self.wait_prefixed(max_len).await,
+            }
         }
         .boxed_local()
     }
@@ -73,7 +168,13 @@ impl WaitThen for AsyncPipeStream {
             return Box::pin(ready(Ok(None)));
         }
 
-        let r = self.buf[0..*value].to_owned();
+        let r = match self.framing {
+            Framing::Chunks => self.buf[0..*value].to_owned(),
+            Framing::Lines { .. } => self.pending.drain(..*value).collect(),
+            Framing::LengthPrefixed { .. } => {
+                self.pending.drain(..*value).skip(PREFIX_LEN).collect()
+            }
+        };
 
         Box::pin(ready(Ok(Some(r))))
     }
@@ -90,4 +191,80 @@ impl Control for AsyncPipeStream {
     fn rx_closed(&self) -> bool {
         self.rx_shut
     }
+
+    /// The input reaching its end counts as the peer finishing.
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.rx_shut.then_some(CloseReason::CleanFin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn messages(input: &[u8], framing: Framing) -> Vec<Vec<u8>> {
+        let (mut tx, rx) = tokio::io::duplex(64);
+        let input = input.to_owned();
+        let write = async move {
+            // Split across writes so lines span several reads
+            for piece in input.chunks(3) {
+                tx.write_all(piece).await.unwrap();
+            }
+        };
+
+        let read = async {
+            let mut stream = AsyncPipeStream::new(rx, tokio::io::sink()).with_framing(framing);
+            let mut messages = vec![];
+            while !stream.rx_closed() {
+                let mut value = stream.wait().await.unwrap();
+                if let Some(data) = stream.then(&mut value).await.unwrap() {
+                    messages.push(data);
+                }
+            }
+            messages
+        };
+
+        tokio::join!(write, read).1
+    }
+
+    #[tokio::test]
+    async fn line_framing() {
+        let messages = messages(
+            b"first\nsecond line\n\nunterminated",
+            Framing::Lines { max_len: 64 },
+        )
+        .await;
+        assert_eq!(
+            messages,
+            [
+                b"first\n".as_slice(),
+                b"second line\n",
+                b"\n",
+                b"unterminated"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_framing() {
+        let framing = Framing::LengthPrefixed { max_len: 16 };
+        let (tx, mut rx) = tokio::io::duplex(1024);
+        let mut writer = AsyncPipeStream::new(tokio::io::empty(), tx).with_framing(framing);
+        for message in [b"first".as_slice(), b"", b"with\nnewline"] {
+            writer.send(message).await.unwrap();
+        }
+        assert!(writer.send(&[0; 17]).await.is_err());
+        drop(writer);
+        let mut encoded = vec![];
+        rx.read_to_end(&mut encoded).await.unwrap();
+
+        let messages = messages(&encoded, framing).await;
+        assert_eq!(messages, [b"first".as_slice(), b"", b"with\nnewline"]);
+    }
+
+    #[tokio::test]
+    async fn line_framing_splits_long_lines() {
+        let messages = messages(b"0123456789\nab\n", Framing::Lines { max_len: 4 }).await;
+        assert_eq!(messages, [b"0123".as_slice(), b"4567", b"89\n", b"ab\n"]);
+    }
 }
@@ -0,0 +1,205 @@
+//! Streams with their types erased, so that one can be stored in a struct
+//! field without naming it, or streams of different types side by side, see
+//! [`BoxedPipeStream`].
+
+use crate::pipe_stream::{
+    stream_result, CloseReason, ConnectionInfo, Control, PipeStream, ShutdownRequest, StreamError,
+    StreamResult, WaitThen,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use std::{any::Any, sync::Arc, time::Duration};
+
+/// What a [`DynPipeStream`] waits for, the value of the erased stream.
+pub type DynValue = Box<dyn Any>;
+
+/// [`PipeStream`] usable as a trait object, any stream turns into one with
+/// [`boxed`].
+pub trait DynPipeStream: PipeStream<Value = DynValue, Error = StreamError> {}
+impl<S> DynPipeStream for S where S: PipeStream<Value = DynValue, Error = StreamError> {}
+
+/// Any stream, e.g. a [`Connection`](crate::connect::Connection) or an
+/// [`AsyncPipeStream`](crate::async_pipe_stream::AsyncPipeStream) in tests,
+/// behind a single type. Methods of the stream outside of [`PipeStream`] and
+/// [`Control`] are no longer reachable, keep what they return before boxing.
+pub type BoxedPipeStream = Box<dyn DynPipeStream>;
+
+pub fn boxed<S>(stream: S) -> BoxedPipeStream
+where
+    S: PipeStream + 'static,
+    S::Value: 'static,
+    S::Error: Into<StreamError>,
+{
+    Box::new(Erased(stream))
+}
+
+struct Erased<S>(S);
+impl<S> PipeStream for Erased<S>
+where
+    S: PipeStream,
+    S::Value: 'static,
+    S::Error: Into<StreamError>,
+{
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, StreamResult<()>> {
+        stream_result(self.0.send(data))
+    }
+}
+impl<S> WaitThen for Erased<S>
+where
+    S: PipeStream,
+    S::Value: 'static,
+    S::Error: Into<StreamError>,
+{
+    type Value = DynValue;
+    type Output = Option<Vec<u8>>;
+    type Error = StreamError;
+
+    fn wait(&mut self) -> LocalBoxFuture<'_, StreamResult<Self::Value>> {
+        self.0
+            .wait()
+            .map(|r| match r {
+                Ok(value) => Ok(Box::new(value) as DynValue),
+                Err(e) => Err(e.into()),
+            })
+            .boxed_local()
+    }
+
+    /// Panics if `value` was not waited for on this stream.
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, StreamResult<Self::Output>> {
+        let value = value
+            .downcast_mut::<S::Value>()
+            .expect("Value waited for on another stream");
+        stream_result(self.0.then(value))
+    }
+}
+impl<S> Control for Erased<S>
+where
+    S: PipeStream,
+    S::Value: 'static,
+    S::Error: Into<StreamError>,
+{
+    fn close(&mut self) -> LocalBoxFuture<'_, StreamResult<()>> {
+        stream_result(self.0.close())
+    }
+
+    fn rx_closed(&self) -> bool {
+        self.0.rx_closed()
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.0.close_reason()
+    }
+
+    fn notify<'a>(&'a mut self, notice: &'a str) -> LocalBoxFuture<'a, StreamResult<()>> {
+        stream_result(self.0.notify(notice))
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        self.0.connection_info()
+    }
+
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        self.0.writable()
+    }
+
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, StreamResult<()>> {
+        stream_result(self.0.request_shutdown(deadline))
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        self.0.shutdown_request()
+    }
+}
+
+impl PipeStream for BoxedPipeStream {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, StreamResult<()>> {
+        (**self).send(data)
+    }
+}
+impl WaitThen for BoxedPipeStream {
+    type Value = DynValue;
+    type Output = Option<Vec<u8>>;
+    type Error = StreamError;
+
+    fn wait(&mut self) -> LocalBoxFuture<'_, StreamResult<Self::Value>> {
+        (**self).wait()
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, StreamResult<Self::Output>> {
+        (**self).then(value)
+    }
+}
+impl Control for BoxedPipeStream {
+    fn close(&mut self) -> LocalBoxFuture<'_, StreamResult<()>> {
+        (**self).close()
+    }
+
+    fn rx_closed(&self) -> bool {
+        (**self).rx_closed()
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        (**self).close_reason()
+    }
+
+    fn notify<'a>(&'a mut self, notice: &'a str) -> LocalBoxFuture<'a, StreamResult<()>> {
+        (**self).notify(notice)
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        (**self).connection_info()
+    }
+
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        (**self).writable()
+    }
+
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, StreamResult<()>> {
+        (**self).request_shutdown(deadline)
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        (**self).shutdown_request()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_pipe_stream::AsyncPipeStream;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    /// What an application would keep, without naming the stream.
+    struct Peer {
+        stream: BoxedPipeStream,
+    }
+
+    #[tokio::test]
+    async fn erased() {
+        let (mut input, rx) = duplex(64);
+        let (tx, mut output) = duplex(64);
+        let mut peer = Peer {
+            stream: boxed(AsyncPipeStream::new(rx, tx)),
+        };
+
+        input.write_all(b"hello").await.unwrap();
+        let mut value = peer.stream.wait().await.unwrap();
+        let data = peer.stream.then(&mut value).await.unwrap();
+        assert_eq!(data.as_deref(), Some(&b"hello"[..]));
+
+        peer.stream.send(b"world").await.unwrap();
+        let mut received = [0; 5];
+        output.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"world");
+
+        drop(input);
+        let mut value = peer.stream.wait().await.unwrap();
+        assert_eq!(peer.stream.then(&mut value).await.unwrap(), None);
+        assert!(peer.stream.rx_closed());
+    }
+}
@@ -0,0 +1,118 @@
+//! What this build of icepipe supports, depending on the enabled features.
+
+#[cfg(feature = "full")]
+use crate::connect::SIGNALING_SCHEMES;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: &'static str,
+    /// Implementation behind [`crypto_backend`](crate::crypto_backend),
+    /// `none` without either backend.
+    pub crypto_backend: &'static str,
+    pub ciphers: Vec<&'static str>,
+    pub compression: Vec<&'static str>,
+    pub transports: Vec<&'static str>,
+    /// URL schemes accepted for the signalling server.
+    pub signalling: Vec<&'static str>,
+    /// See [`SignallingFormat`](crate::signalling::SignallingFormat).
+    pub signalling_formats: Vec<&'static str>,
+    /// Optional instrumentation compiled in.
+    pub instrumentation: Vec<&'static str>,
+}
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "crypto backend: {}", self.crypto_backend)?;
+        writeln!(f, "ciphers: {}", list(&self.ciphers))?;
+        writeln!(f, "compression: {}", list(&self.compression))?;
+        writeln!(f, "transports: {}", list(&self.transports))?;
+        writeln!(f, "signalling: {}", list(&self.signalling))?;
+        writeln!(f, "signalling formats: {}", list(&self.signalling_formats))?;
+        write!(f, "instrumentation: {}", list(&self.instrumentation))
+    }
+}
+
+fn list(values: &[&str]) -> String {
+    match values {
+        [] => "none".to_string(),
+        values => values.join(", "),
+    }
+}
+
+pub fn capabilities() -> Capabilities {
+    let crypto_backend = if cfg!(feature = "ring") {
+        "ring"
+    } else if cfg!(feature = "rustcrypto") {
+        "rustcrypto"
+    } else {
+        "none"
+    };
+
+    let mut ciphers = vec![];
+    if cfg!(feature = "crypto") {
+        ciphers.push("chacha20-poly1305");
+    }
+    if cfg!(feature = "dtls") {
+        ciphers.push("dtls");
+    }
+
+    let mut transports = vec![];
+    let mut signalling_formats = vec![];
+    if cfg!(feature = "ice-transport") {
+        transports.push("ice-sctp");
+        signalling_formats.extend(["native", "sdp"]);
+    }
+    if cfg!(feature = "libp2p") {
+        transports.push("libp2p");
+    }
+
+    let mut instrumentation = vec![];
+    if cfg!(feature = "tracing") {
+        instrumentation.push("tracing");
+    }
+    if cfg!(feature = "metrics") {
+        instrumentation.push("metrics");
+    }
+
+    #[cfg(feature = "full")]
+    let signalling = SIGNALING_SCHEMES.to_vec();
+    #[cfg(not(feature = "full"))]
+    let signalling = vec![];
+
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        crypto_backend,
+        ciphers,
+        compression: vec![],
+        transports,
+        signalling,
+        signalling_formats,
+        instrumentation,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respects_features() {
+        let capabilities = capabilities();
+        assert_eq!(
+            capabilities.ciphers.contains(&"chacha20-poly1305"),
+            cfg!(feature = "crypto")
+        );
+        assert_eq!(
+            capabilities.ciphers.contains(&"dtls"),
+            cfg!(feature = "dtls")
+        );
+        if cfg!(feature = "full") {
+            assert_eq!(capabilities.signalling, ["ws", "wss"]);
+        }
+        assert_eq!(
+            capabilities.transports.contains(&"libp2p"),
+            cfg!(feature = "libp2p")
+        );
+    }
+}
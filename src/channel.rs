@@ -0,0 +1,165 @@
+//! The pre-shared key of a connection and the channel derived from it,
+//! kept apart so the key is never logged, see [`ChannelSource`].
+//!
+//! Peers meet on the signalling server on a [`Channel`] derived from the
+//! [`Psk`], a slow derivation so that the channel, which the server and
+//! anyone watching it learn, tells little about the key.
+
+use crate::agreement::{blocking, PskAuthentication};
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// Secret both peers connect with, also authenticating them with
+/// [`ConnectOptions::connect_psk`](crate::connect::ConnectOptions::connect_psk).
+/// Printed redacted and zeroed once dropped.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Psk(Zeroizing<String>);
+impl Psk {
+    pub fn new(psk: impl Into<String>) -> Psk {
+        Psk(Zeroizing::new(psk.into()))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Derives the channel right away, see [`Psk::channel_blocking`].
+    pub fn channel(&self) -> Channel {
+        Channel(PskAuthentication::derive_text(self.expose(), "channel"))
+    }
+
+    /// [`Psk::channel`] off the runtime, see
+    /// [`blocking`](crate::agreement::blocking).
+    pub async fn channel_blocking(&self) -> Channel {
+        let psk = self.clone();
+        blocking(move || psk.channel()).await
+    }
+}
+impl fmt::Debug for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Psk(..)")
+    }
+}
+impl fmt::Display for Psk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+impl From<String> for Psk {
+    fn from(psk: String) -> Self {
+        Psk::new(psk)
+    }
+}
+impl From<&str> for Psk {
+    fn from(psk: &str) -> Self {
+        Psk::new(psk)
+    }
+}
+
+/// Joined on the signalling server, derived from a [`Psk`] and safe to log.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Channel(String);
+impl Channel {
+    /// One derived beforehand, e.g. by the peer handing it out.
+    pub fn new(channel: impl Into<String>) -> Channel {
+        Channel(channel.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl From<Channel> for String {
+    fn from(channel: Channel) -> Self {
+        channel.0
+    }
+}
+
+/// Where the channel of a connection comes from. Strings convert to
+/// [`ChannelSource::Psk`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelSource {
+    /// Derives the channel, the key may also authenticate the peer.
+    Psk(Psk),
+    /// Joins the channel as is, for peers authenticated by other means.
+    /// Connecting with the PSK, or hopping channels, fails with
+    /// [`ConnectError::NoPsk`](crate::connect::ConnectError::NoPsk).
+    Derived(Channel),
+}
+impl ChannelSource {
+    pub fn psk(&self) -> Option<&Psk> {
+        match self {
+            ChannelSource::Psk(psk) => Some(psk),
+            ChannelSource::Derived(_) => None,
+        }
+    }
+
+    /// Derives it off the runtime if needed.
+    pub async fn channel(&self) -> Channel {
+        match self {
+            ChannelSource::Psk(psk) => psk.channel_blocking().await,
+            ChannelSource::Derived(channel) => channel.clone(),
+        }
+    }
+}
+impl Default for ChannelSource {
+    fn default() -> Self {
+        ChannelSource::Psk(Psk::default())
+    }
+}
+impl From<Psk> for ChannelSource {
+    fn from(psk: Psk) -> Self {
+        ChannelSource::Psk(psk)
+    }
+}
+impl From<Channel> for ChannelSource {
+    fn from(channel: Channel) -> Self {
+        ChannelSource::Derived(channel)
+    }
+}
+impl From<String> for ChannelSource {
+    fn from(psk: String) -> Self {
+        ChannelSource::Psk(psk.into())
+    }
+}
+impl From<&str> for ChannelSource {
+    fn from(psk: &str) -> Self {
+        ChannelSource::Psk(psk.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn redacted() {
+        let source = ChannelSource::from("hunter2");
+        let psk = source.psk().unwrap();
+        assert_eq!(psk.expose(), "hunter2");
+        for printed in [
+            format!("{source:?}"),
+            format!("{source:#?}"),
+            format!("{psk}"),
+            format!("{psk:?}"),
+        ] {
+            assert!(!printed.contains("hunter2"), "{printed}");
+        }
+
+        let channel = source.channel().await;
+        assert_eq!(channel, psk.channel());
+        assert_eq!(
+            channel.as_str(),
+            PskAuthentication::derive_text("hunter2", "channel")
+        );
+        let derived = ChannelSource::from(channel.clone());
+        assert_eq!(derived.psk(), None);
+        assert_eq!(derived.channel().await, channel);
+        assert!(format!("{derived:?}").contains(channel.as_str()));
+    }
+}
@@ -0,0 +1,64 @@
+//! Time based channels, so a channel name that leaks only lets others camp
+//! on the signalling path for a single window, see
+//! [`ConnectOptions::channel_hopping`](crate::connect::ConnectOptions::channel_hopping).
+
+use crate::agreement::PskAuthentication;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Derives a new channel from the PSK every `window`. Both peers must use
+/// the same window.
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelHopping {
+    pub window: Duration,
+    /// Source of the current time, only meant to be replaced by tests.
+    pub clock: fn() -> SystemTime,
+}
+impl ChannelHopping {
+    pub fn new(window: Duration) -> ChannelHopping {
+        ChannelHopping {
+            window,
+            clock: SystemTime::now,
+        }
+    }
+
+    /// Channel of the current window and of the previous one, which is also
+    /// joined so a peer that started just before the window changed, or with
+    /// a clock slightly behind, is still met.
+    pub fn channels(&self, psk: &str) -> (String, String) {
+        self.channels_at(psk, (self.clock)())
+    }
+
+    fn channels_at(&self, psk: &str, now: SystemTime) -> (String, String) {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let index = (now.as_secs() / self.window.as_secs().max(1)).max(1);
+
+        (channel(psk, index), channel(psk, index - 1))
+    }
+}
+
+fn channel(psk: &str, index: u64) -> String {
+    PskAuthentication::derive_text(psk, &format!("channel-{index}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels(psk: &str, secs: u64) -> (String, String) {
+        ChannelHopping::new(Duration::from_secs(600))
+            .channels_at(psk, UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn hops_every_window() {
+        let (first, _) = channels("psk", 6000);
+        let (same, _) = channels("psk", 6599);
+        let (next, previous) = channels("psk", 6600);
+
+        assert_eq!(first, same);
+        assert_ne!(first, next);
+        assert_eq!(first, previous);
+        assert_ne!(first, PskAuthentication::derive_text("psk", "channel"));
+        assert_ne!(first, channels("other psk", 6000).0);
+    }
+}
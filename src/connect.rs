@@ -1,67 +1,1573 @@
+#[cfg(feature = "dtls")]
+use crate::dtls::{DtlsError, DtlsIdentity};
 use crate::{
-    agreement::{Agreement, AgreementError, Authentication, PskAuthentication},
-    constants,
-    crypto_stream::{Chacha20Error, Chacha20Stream},
+    agreement::{
+        blocking, Agreed, Agreement, AgreementError, AuthKind, Authentication, PskAuthentication,
+        PskMaterial,
+    },
+    boxed_stream::{boxed, BoxedPipeStream},
+    channel::{Channel, ChannelSource, Psk},
+    channel_hopping::ChannelHopping,
+    connect_limiter::{ConnectLimiter, ConnectPermit, QueueTimeout},
+    constants, crypto_backend,
+    crypto_stream::{
+        Chacha20Error, Chacha20ReadHalf, Chacha20Stream, Chacha20WriteHalf, DesyncPolicy,
+    },
+    deadline::Deadline,
+    diagnostics::{ConnectTimings, Diagnostics, DiagnosticsReport},
     error::TimeoutError,
-    ice::{IceAgent, IceError},
-    pipe_stream::StreamError,
-    sctp::{Sctp, SctpError},
-    signalling::SignalingError,
-    ws::Websocket,
+    events::{ConnectionEvent, Events},
+    framed_signalling::{FramedSignalling, SignallingFraming},
+    ice::{
+        AgentConfigHook, CandidateCache, CandidatePairEntry, GatherPolicy, IceAgent, IceConfig,
+        IceError, IceServer, PairSelection, Reconnect, SignallingPolicy,
+    },
+    logging, metrics,
+    mtu_probe::PathMtu,
+    negotiation::{DowngradeError, SecurityFloor, SecurityParams},
+    ping::PingConfig,
+    pipe_stream::{
+        stream_result, CloseReason, ConnectionInfo, Control, PipeReadHalf, PipeStream,
+        PipeWriteHalf, ShutdownRequest, Split, StreamError, StreamResult, TransportKind, WaitThen,
+    },
+    policy::{admit, present, AdmissionError, ConnectPolicy, Hello, PeerIdentity},
+    rate_limit::RateLimit,
+    replay_state::{ReplayState, ReplayStateError, ReplayStore},
+    resolver::Resolver,
+    sctp::{
+        EstablishError, PauseHandle, Sctp, SctpConfig, SctpError, SctpReadHalf, SctpStats,
+        SctpValue, SctpWriteHalf,
+    },
+    signalling::{
+        role_name, CandidateEncoding, PeerRejected, Rejection, SignalingError, Signalling,
+        SignallingFormat,
+    },
+    stream_signalling::StreamSignalling,
+    strictness::{Strictness, Violation},
+    trace::{self, Instrument},
+    traffic_limit::TrafficLimit,
+    ws::{Websocket, WebsocketConfig, SEND_TIMEOUT},
 };
-use std::{io, str::FromStr};
+use futures::{future::LocalBoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    future::{self, Future},
+    io,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    select,
+    sync::{broadcast, watch},
+    time::timeout,
+};
+use webrtc_ice::{state::ConnectionState, url::Url};
+use webrtc_util::Conn;
 
-pub type Connection = Chacha20Stream<ConnectionSctp>;
 type ConnectionSctp = Sctp;
 
+/// Layer securing the data of a [`Connection`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encryption {
+    /// ChaCha20-Poly1305 with keys derived from the key agreement.
+    #[default]
+    Chacha20,
+    /// DTLS with per session certificates authenticated by the key
+    /// agreement, see [`crate::dtls`].
+    #[cfg(feature = "dtls")]
+    Dtls,
+}
+impl Encryption {
+    fn transport(self) -> TransportKind {
+        match self {
+            Encryption::Chacha20 => TransportKind::Chacha20,
+            #[cfg(feature = "dtls")]
+            Encryption::Dtls => TransportKind::Dtls,
+        }
+    }
+}
+
+/// Tunes the connection for latency or throughput, see
+/// [`ConnectOptions::with_latency_profile`]. Sends are never coalesced and
+/// [`AsyncPipeStream`](crate::async_pipe_stream::AsyncPipeStream) flushes
+/// every message whatever the profile, they differ in how much the SCTP
+/// association may buffer before `send` blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LatencyProfile {
+    /// For keystrokes and other small messages that should arrive at once.
+    /// Back pressure engages past 64 KiB buffered, checked every 5ms, so a
+    /// bulk transfer sharing the connection does not queue up in front of
+    /// them. Forwarding holds back past 16 KiB.
+    Interactive,
+    /// The defaults of [`SctpConfig`], 4 MiB buffered checked every 100ms,
+    /// forwarding holds back past 1 MiB.
+    #[default]
+    Balanced,
+    /// For bulk transfers, 16 MiB buffered checked every 100ms, forwarding
+    /// holds back past 8 MiB.
+    Throughput,
+}
+impl LatencyProfile {
+    pub fn sctp_config(self) -> SctpConfig {
+        let default = SctpConfig::default();
+        match self {
+            LatencyProfile::Interactive => SctpConfig {
+                max_buffered_amount: 64 * 1024,
+                backpressure_poll: Duration::from_millis(5),
+                writable_threshold: 16 * 1024,
+                ..default
+            },
+            LatencyProfile::Balanced => default,
+            LatencyProfile::Throughput => SctpConfig {
+                max_buffered_amount: 16 * 1024 * 1024,
+                writable_threshold: 8 * 1024 * 1024,
+                ..default
+            },
+        }
+    }
+}
+
+/// Signalling entry points per role, for servers bridging peers that join
+/// through different relays, see [`ConnectOptions::role_signaling`]. The
+/// channel is derived the same whatever the entry point, it is the path
+/// joined to either URL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoleSignaling {
+    pub dialer: url::Url,
+    pub listener: url::Url,
+    /// Which entry point this peer joins through. It keeps that role
+    /// whatever the server assigns, the peer must be configured with the
+    /// other one.
+    pub as_dialer: bool,
+}
+impl RoleSignaling {
+    fn url(&self) -> &url::Url {
+        match self.as_dialer {
+            true => &self.dialer,
+            false => &self.listener,
+        }
+    }
+}
+
+/// How long a peer met on the previous channel waits for one on the current
+/// channel, see [`join_hopping`].
+const CURRENT_CHANNEL_GRACE: Duration = Duration::from_secs(2);
+
+/// Schemes of the signalling backends, only the websocket one exists so far.
+pub(crate) const SIGNALING_SCHEMES: &[&str] = &["ws", "wss"];
+
+#[derive(Clone, Default)]
 pub struct ConnectOptions {
-    pub channel: String,
+    /// The pre-shared key of the peers, or the channel derived from it.
+    pub channel: ChannelSource,
     pub signaling: Option<url::Url>,
+    /// Replaces [`signaling`](ConnectOptions::signaling) with an entry
+    /// point per role.
+    pub role_signaling: Option<RoleSignaling>,
+    /// STUN and TURN servers, the default ones if neither these nor
+    /// [`ice`](ConnectOptions::ice) are given.
+    pub ice_servers: Vec<IceServer>,
+    /// Servers in the `url&username&credential` format of the command line,
+    /// used along with [`ice_servers`](ConnectOptions::ice_servers).
     pub ice: Vec<String>,
+    /// Limit on inbound signalling messages, excess candidates are dropped.
+    pub signaling_rate_limit: RateLimit,
+    pub sctp: SctpConfig,
+    /// Candidates from a previous connection to the same peer, see
+    /// [`Connection::candidate_cache`].
+    pub candidate_cache: Option<CandidateCache>,
+    /// Format of the ICE parameters exchanged after the key agreement.
+    pub signalling_format: SignallingFormat,
+    /// [`SignallingFraming::Delimited`] for signalling servers that
+    /// concatenate or pad the messages they relay, both peers must set it.
+    /// Applies to the channel of
+    /// [`connect_with_signalling`](ConnectOptions::connect_with_signalling)
+    /// as well.
+    pub signalling_framing: SignallingFraming,
+    /// Times the signalling server connection is re-established if it drops
+    /// while candidates are still being exchanged.
+    pub signaling_reconnects: u32,
+    /// Both peers must select the same one.
+    pub encryption: Encryption,
+    /// Derives a new channel every window instead of a fixed one, both peers
+    /// must use the same setting.
+    pub channel_hopping: Option<ChannelHopping>,
+    /// Bounds every await of each connect, counted from its start, and,
+    /// unless replaced with [`Connection::set_deadline`], of the connection
+    /// afterwards. `None` never times out.
+    pub timeout: Option<Duration>,
+    /// Fails with [`IceError::RoleConflict`] when both peers are assigned
+    /// the same role instead of re-assigning them.
+    pub strict_roles: bool,
+    /// Records candidates, state transitions and timings of the connection,
+    /// also when connecting fails. Disabled by default, the session of
+    /// [`Connection::diagnostics`] then only holds the timings.
+    pub diagnostics: Diagnostics,
+    /// Where non-fatal events are emitted, subscribe before connecting to
+    /// see those of connecting as well. Connections emit to a bus of their
+    /// own otherwise, see [`Connection::events`].
+    pub events: Events,
+    /// Caps the plaintext the connection transfers, only enforced with
+    /// [`Encryption::Chacha20`]. Going past the hard limit fails with
+    /// [`StreamError::TrafficLimit`] and closes the connection.
+    pub traffic_limit: Option<TrafficLimit>,
+    /// Handling of messages lost or repeated below the encryption layer,
+    /// only applies to [`Encryption::Chacha20`].
+    pub desync_policy: DesyncPolicy,
+    /// Used when this peer ends up the dialer, which nominates the pair.
+    pub pair_selection: PairSelection,
+    /// Decides on the peer right after the key agreement, which must then
+    /// present a [`hello`](ConnectOptions::hello). Whatever the roles, only
+    /// one of the peers has a policy.
+    pub policy: Option<Arc<dyn ConnectPolicy>>,
+    /// Presented to a peer with a [`policy`](ConnectOptions::policy) over
+    /// the signalling channel, sealed with ChaCha20 under a key derived from
+    /// the agreed basekey, see [`crate::policy`]. Only a peer holding the
+    /// channel secret or key reads it, not the signalling server.
+    pub hello: Option<Hello>,
+    /// What becomes of the signalling channel once connected, e.g.
+    /// [`SignallingPolicy::KeepExchanging`] to exchange the candidates
+    /// gathered late, see [`IceAgent::settle`]. Only for the signalling
+    /// server, the channel of
+    /// [`connect_with_signalling`](ConnectOptions::connect_with_signalling)
+    /// is left to its caller. Policies other than the default keep servicing
+    /// the channel on a local task, connecting must then happen within a
+    /// [`LocalSet`](tokio::task::LocalSet).
+    pub signalling_policy: SignallingPolicy,
+    /// E.g. [`GatherPolicy::RelayOnlyNoBind`] to never open a listening
+    /// socket, connecting through the TURN servers of
+    /// [`ice_servers`](ConnectOptions::ice_servers) only.
+    pub gather_policy: GatherPolicy,
+    /// Gathers and accepts IPv6 link-local candidates, for peers on the same
+    /// link without any other address. Dropped otherwise, see
+    /// [`ice::link_local`](crate::ice::link_local).
+    pub keep_link_local: bool,
+    /// Only applies to [`SignallingFormat::Native`].
+    pub candidate_encoding: CandidateEncoding,
+    /// See [`IceConfig::candidate_queue`].
+    pub candidate_queue: Option<usize>,
+    /// Escape hatch for agent settings without an option of their own, see
+    /// [`AgentConfigHook`].
+    pub agent_config: Option<AgentConfigHook>,
+    /// [`Strictness::Strict`] fails with [`ConnectError::Violation`] on
+    /// what is otherwise logged and ignored, for listeners exposed to anyone.
+    /// Violations once connected fail the connection instead.
+    pub strictness: Strictness,
+    /// Both peers announce the authentication and encryption they use once
+    /// the key agreement is done, and fail with [`ConnectError::Downgrade`]
+    /// when either falls below this floor. The announcements are
+    /// authenticated by the agreed key. Both peers must set it.
+    pub security_floor: Option<SecurityFloor>,
+    /// Keeps the signalling server connection alive, also once connected
+    /// with [`signalling_policy`](ConnectOptions::signalling_policy).
+    /// See [`PingConfig`] for the trade-off of pinging less.
+    pub signalling_ping: PingConfig,
+    /// How long the signalling server may take to accept a message,
+    /// [`SEND_TIMEOUT`](crate::ws::SEND_TIMEOUT) if unset. Failing past it
+    /// reports an overloaded server rather than leaving candidates to pile
+    /// up in [`candidate_queue`](ConnectOptions::candidate_queue).
+    pub signalling_send_timeout: Option<Duration>,
+    /// Retries a `wss` signalling server over plain `ws` when TLS is
+    /// refused, see [`WebsocketConfig::tls_fallback`]. Falling back warns.
+    /// Refused with anything short of key authentication, e.g. a PSK, whose
+    /// exchange anyone on the path could then try to guess offline, and with
+    /// a [`hello`](ConnectOptions::hello).
+    pub signalling_tls_fallback: bool,
+    /// Resolves the signalling server and the STUN and TURN servers,
+    /// [`SystemResolver`](crate::resolver::SystemResolver) if unset.
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// Shared by the connects that should not run all at once, e.g. those a
+    /// listener starts for each incoming peer. Waiting for a slot counts
+    /// against the [`timeout`](ConnectOptions::timeout). Connects with
+    /// [`connect_over`](ConnectOptions::connect_over) are not limited.
+    pub connect_limiter: Option<ConnectLimiter>,
+    /// Where the ChaCha20 layer records its sequence numbers, see
+    /// [`crate::replay_state`].
+    pub state_dir: Option<PathBuf>,
+    /// Handed to the peer during the key agreement, authenticated along with
+    /// the public key, see [`Connection::peer_agreement_payload`]. At most
+    /// [`MAX_PAYLOAD`](crate::agreement::MAX_PAYLOAD) bytes.
+    pub agreement_payload: Option<Vec<u8>>,
 }
 impl ConnectOptions {
+    /// Sets every knob covered by `profile`, individual ones can still be
+    /// overridden afterwards.
+    pub fn with_latency_profile(mut self, profile: LatencyProfile) -> ConnectOptions {
+        let sctp = profile.sctp_config();
+        self.sctp.max_buffered_amount = sctp.max_buffered_amount;
+        self.sctp.backpressure_poll = sctp.backpressure_poll;
+        self.sctp.writable_threshold = sctp.writable_threshold;
+        self
+    }
+
+    /// Adds the servers of a WebRTC `iceServers` configuration to
+    /// [`ice_servers`](ConnectOptions::ice_servers), see
+    /// [`IceServer::from_json`].
+    pub fn ice_servers_from_json(mut self, json: &str) -> ConnectResult<ConnectOptions> {
+        let servers = IceServer::from_json(json).map_err(ConnectError::BadIceJson)?;
+        for server in &servers {
+            server.parse_urls().map_err(ConnectError::BadIceUrl)?;
+        }
+        self.ice_servers.extend(servers);
+
+        Ok(self)
+    }
+
+    /// Authenticates the peer with the [`Psk`] of
+    /// [`channel`](ConnectOptions::channel).
     pub async fn connect_psk(self) -> Result<Connection, ConnectError> {
-        let psk = self.channel.to_owned();
-        self.connect(PskAuthentication::new(psk)).await
+        let psk = self.psk("connect_psk")?.clone();
+        self.connect_derived(PskAuthentication::new_blocking(psk), None)
+            .await
+    }
+
+    pub async fn connect_psk_with_signalling<S>(
+        self,
+        signalling: S,
+        dialer: bool,
+    ) -> Result<Connection, ConnectError>
+    where
+        S: Signalling,
+        S::Error: Into<SignalingError>,
+    {
+        let psk = self.psk("connect_psk_with_signalling")?.clone();
+        self.signalled(signalling, dialer, PskAuthentication::new_blocking(psk))
+            .await
+    }
+
+    /// Like [`ConnectOptions::connect_psk`] without deriving from the PSK
+    /// again, for peers reconnecting with the same one. The channel of
+    /// `material` is not used with
+    /// [`channel_hopping`](ConnectOptions::channel_hopping).
+    pub async fn connect_psk_material(
+        self,
+        material: PskMaterial,
+    ) -> Result<Connection, ConnectError> {
+        self.connect_derived(future::ready(material.auth), Some(material.channel))
+            .await
     }
 
     pub async fn connect<A: Authentication>(self, auth: A) -> Result<Connection, ConnectError> {
-        let signaling = self.signaling.map(ConnectResult::Ok).unwrap_or_else(|| {
-            let default_signaling = constants::signalling_server()
-                .ok_or_else(|| ConnectError::NoDefaultValue(Constants::Signaling))?;
+        self.connect_derived(future::ready(auth), None).await
+    }
 
-            default_signaling
-                .parse()
-                .map_err(ConnectError::BadSignalingUrl)
-        })?;
+    /// `auth` is derived once a handshake slot is acquired, `channel` is the
+    /// one joined if already derived.
+    async fn connect_derived<A: Authentication>(
+        mut self,
+        auth: impl Future<Output = A>,
+        channel: Option<Channel>,
+    ) -> Result<Connection, ConnectError> {
+        let started = Instant::now();
+        self.diagnostics = self.diagnostics.or_timings();
+        self.events = self.events.or_new();
+        let diagnostics = self.diagnostics.clone();
+        let deadline = Deadline::from_timeout(self.timeout);
+        deadline
+            .run(async move {
+                let _slot = self.handshake_slot().await?;
+                let auth = auth.await;
+                let (signalling, dialer, span, reconnect, channel) =
+                    self.open_signalling(channel, auth.kind()).await?;
 
-        let ice_urls = self
-            .ice
-            .into_option()
-            .or_else(|| constants::ice_urls().into_option())
-            .ok_or(ConnectError::NoDefaultValue(Constants::Ice))?;
-        let ice_urls = ice_urls
-            .into_iter()
-            .map(|s| {
-                ParseUrl::from_str(&s)
-                    .map(|u| u.0)
-                    .map_err(ConnectError::BadIceUrl)
+                let (connection, agent) = self
+                    .establish(signalling, dialer, auth, Some(reconnect), channel, deadline)
+                    .instrument(span.clone())
+                    .await?;
+                agent.settle().instrument(span).await?;
+
+                Ok(connection)
             })
-            .collect::<ConnectResult<_>>()?;
+            .await
+            .inspect(|_| connected(&diagnostics, started))
+    }
 
-        let base_password = self.channel;
-        let channel = PskAuthentication::derive_text(&base_password, "channel");
+    /// Like [`ConnectOptions::connect`] but without the ChaCha20 layer, the
+    /// peer is still authenticated by the key agreement. Only meant for
+    /// applications that secure the stream on their own. The DTLS layer is
+    /// kept if selected.
+    pub async fn connect_unencrypted<A: Authentication>(
+        mut self,
+        auth: A,
+    ) -> Result<Sctp, ConnectError> {
+        let started = Instant::now();
+        self.diagnostics = self.diagnostics.or_timings();
+        self.events = self.events.or_new();
+        let diagnostics = self.diagnostics.clone();
+        let deadline = Deadline::from_timeout(self.timeout);
+        deadline
+            .run(async move {
+                let _slot = self.handshake_slot().await?;
+                let (signalling, dialer, span, reconnect, channel) =
+                    self.open_signalling(None, auth.kind()).await?;
+
+                // The DTLS layer is kept
+                let transport = match self.encryption.transport() {
+                    TransportKind::Chacha20 => TransportKind::Sctp,
+                    transport => transport,
+                };
+                let (_, dialer, mut stream, agent) = self
+                    .establish_sctp(
+                        signalling,
+                        dialer,
+                        auth,
+                        Some(reconnect),
+                        channel,
+                        transport,
+                    )
+                    .instrument(span.clone())
+                    .await?;
+                stream.set_deadline(deadline);
+                agent.settle().instrument(span).await?;
+                metrics::connect_success(role_name(dialer));
+
+                Ok(stream)
+            })
+            .await
+            .inspect(|_| connected(&diagnostics, started))
+    }
+
+    fn psk(&self, needed_by: &'static str) -> ConnectResult<&Psk> {
+        self.channel.psk().ok_or(ConnectError::NoPsk(needed_by))
+    }
+
+    /// A slot of the [`connect_limiter`](ConnectOptions::connect_limiter),
+    /// if any, held until connected.
+    async fn handshake_slot(&self) -> ConnectResult<Option<ConnectPermit>> {
+        let Some(limiter) = &self.connect_limiter else {
+            return Ok(None);
+        };
+        let slot = limiter
+            .acquire()
+            .await
+            .inspect_err(|_| metrics::connect_failure("queue"))?;
+
+        Ok(Some(slot))
+    }
+
+    /// Falls back to plain `ws` only with `auth` of keys and no hello, see
+    /// [`signalling_tls_fallback`](ConnectOptions::signalling_tls_fallback).
+    fn websocket_config(&self, auth: AuthKind) -> WebsocketConfig {
+        let tls_fallback =
+            self.signalling_tls_fallback && auth >= AuthKind::Key && self.hello.is_none();
+        if self.signalling_tls_fallback && !tls_fallback {
+            trace::warn!(
+                target: logging::SIGNALLING,
+                "Not falling back to plain ws with {} authentication or a hello",
+                auth.name()
+            );
+        }
+        WebsocketConfig {
+            strictness: self.strictness,
+            ping: self.signalling_ping,
+            send_timeout: Some(self.signalling_send_timeout.unwrap_or(SEND_TIMEOUT)),
+            resolver: self.resolver.clone(),
+            tls_fallback,
+            ..Default::default()
+        }
+    }
+
+    /// Signalling server connected to, the default one if none is given.
+    pub(crate) fn signalling_url(&self) -> ConnectResult<url::Url> {
+        let signaling = match (&self.role_signaling, &self.signaling) {
+            (Some(role_signaling), _) => role_signaling.url().clone(),
+            (None, Some(signaling)) => signaling.clone(),
+            (None, None) => {
+                let default_signaling = constants::signalling_server()
+                    .ok_or(ConnectError::NoDefaultValue(Constants::Signaling))?;
+
+                default_signaling
+                    .parse()
+                    .map_err(ConnectError::BadSignalingUrl)?
+            }
+        };
+        if !SIGNALING_SCHEMES.contains(&signaling.scheme()) {
+            return Err(ConnectError::UnsupportedSignalingScheme(
+                signaling.scheme().to_owned(),
+            ));
+        }
+
+        Ok(signaling)
+    }
+
+    /// Joins `channel` if already derived, the one of
+    /// [`channel`](ConnectOptions::channel) otherwise.
+    async fn open_signalling(
+        &self,
+        channel: Option<Channel>,
+        auth: AuthKind,
+    ) -> ConnectResult<(
+        FramedSignalling<Websocket>,
+        bool,
+        trace::Span,
+        Reconnect<FramedSignalling<Websocket>>,
+        String,
+    )> {
+        let signaling = self.signalling_url()?;
+        self.diagnostics.signalling_host(signaling.host_str());
+        let config = self.websocket_config(auth);
+
+        metrics::connect_attempt();
+        let span = trace::info_span!(
+            "connection",
+            channel = tracing::field::Empty,
+            role = tracing::field::Empty,
+        );
+        let (channel, signalling, dialer) = match &self.channel_hopping {
+            None => {
+                let channel = match channel {
+                    Some(channel) => channel,
+                    None => self.channel.channel().await,
+                };
+                join(&signaling, channel.into(), config.clone())
+                    .instrument(trace::info_span!(parent: &span, "signalling"))
+                    .await
+            }
+            Some(hopping) => {
+                let hopping = *hopping;
+                let psk = self.psk("channel hopping")?.clone();
+                let channels = blocking(move || hopping.channels(psk.expose())).await;
+                join_hopping(&signaling, channels, config.clone())
+                    .instrument(trace::info_span!(parent: &span, "signalling"))
+                    .await
+            }
+        }
+        .inspect_err(|_| metrics::connect_failure("signalling"))?;
+        if signalling.fell_back {
+            self.diagnostics
+                .warning("TLS to the signalling server failed, fell back to plain ws".into());
+        }
+        self.diagnostics.timing("websocket", signalling.opened_in);
+        self.diagnostics
+            .timing("role assignment", signalling.role_in);
+        span.record("channel", channel.as_str());
+        let dialer = match &self.role_signaling {
+            Some(role_signaling) if role_signaling.as_dialer != dialer => {
+                trace::info!(
+                    target: logging::SIGNALLING,
+                    "Assigned the {} role, keeping the {} one of the entry point",
+                    role_name(dialer),
+                    role_name(role_signaling.as_dialer)
+                );
+                role_signaling.as_dialer
+            }
+            _ => dialer,
+        };
         let url = signaling.join(&channel).unwrap();
 
-        let (signalling, dialer) = Websocket::new(url).await.map_err(SignalingError::from)?;
-        let agreement = Agreement::new(signalling, auth);
-        let (basekey, signalling) = agreement.agree().await?;
+        // The role assigned by the server on reconnection is irrelevant, the
+        // original one is kept
+        let reconnect: Reconnect<Websocket> = Box::new(move || {
+            let (url, config) = (url.clone(), config.clone());
+            async move { Ok(Websocket::connect(url, config).await?.0) }.boxed_local()
+        });
+        let signalling = FramedSignalling::new(signalling, self.signalling_framing);
+        let reconnect = signalling.reconnect(reconnect);
+
+        Ok((signalling, dialer, span, reconnect, channel))
+    }
+
+    /// Connects using an already established signalling channel instead of
+    /// the websocket signalling server.
+    pub async fn connect_with_signalling<S, A>(
+        self,
+        signalling: S,
+        dialer: bool,
+        auth: A,
+    ) -> Result<Connection, ConnectError>
+    where
+        S: Signalling,
+        S::Error: Into<SignalingError>,
+        A: Authentication,
+    {
+        self.signalled(signalling, dialer, future::ready(auth))
+            .await
+    }
+
+    /// See [`ConnectOptions::connect_derived`].
+    async fn signalled<S, A>(
+        mut self,
+        signalling: S,
+        dialer: bool,
+        auth: impl Future<Output = A>,
+    ) -> Result<Connection, ConnectError>
+    where
+        S: Signalling,
+        S::Error: Into<SignalingError>,
+        A: Authentication,
+    {
+        let started = Instant::now();
+        self.diagnostics = self.diagnostics.or_timings();
+        self.events = self.events.or_new();
+        metrics::connect_attempt();
+        let diagnostics = self.diagnostics.clone();
+
+        let deadline = Deadline::from_timeout(self.timeout);
+        deadline
+            .run(async move {
+                let _slot = self.handshake_slot().await?;
+                let auth = auth.await;
+                let channel = String::from(self.channel.channel().await);
+                let span = trace::info_span!(
+                    "connection",
+                    channel = %channel,
+                    role = tracing::field::Empty,
+                );
+                let signalling = FramedSignalling::new(signalling, self.signalling_framing);
+                let (connection, _) = self
+                    .establish(signalling, dialer, auth, None, channel, deadline)
+                    .instrument(span)
+                    .await?;
+                Ok(connection)
+            })
+            .await
+            .inspect(|_| connected(&diagnostics, started))
+    }
+
+    /// Skips signalling and ICE, running SCTP and ChaCha20 straight over
+    /// `conn`, see [`Sctp::over`]. Both peers pass the same `basekey` and
+    /// opposite roles, each session is keyed by `basekey` mixed with a fresh
+    /// nonce from either peer. Only the SCTP settings, deadline, traffic
+    /// limit, strictness and diagnostics of these options apply, the
+    /// encryption is always ChaCha20.
+    pub async fn connect_over(
+        mut self,
+        conn: Arc<dyn Conn + Send + Sync>,
+        dialer: bool,
+        basekey: &[u8],
+    ) -> Result<Connection, ConnectError> {
+        let started = Instant::now();
+        self.diagnostics = self.diagnostics.or_timings();
+        self.events = self.events.or_new();
+        metrics::connect_attempt();
+        let diagnostics = self.diagnostics.clone();
+        let deadline = Deadline::from_timeout(self.timeout);
+        deadline
+            .run(async move {
+                let diagnostics = self.diagnostics.clone();
+                diagnostics.role(role_name(dialer));
+                let mut stream = diagnostics
+                    .phase("sctp", Sctp::over(conn, dialer, self.sctp))
+                    .await
+                    .inspect_err(|_| metrics::connect_failure("sctp"))?;
+                stream.set_deadline(deadline);
+                stream.set_diagnostics(diagnostics.clone());
+                stream.set_events(self.events.clone());
+                stream.set_strictness(self.strictness);
+                stream.set_info(ConnectionInfo {
+                    dialer,
+                    channel_id: String::new(),
+                    transport: TransportKind::Chacha20,
+                    peer_public_key: None,
+                    peer_payload: None,
+                });
+
+                let session_key = session_key(basekey, dialer, &mut stream)
+                    .await
+                    .inspect_err(|_| metrics::connect_failure("crypto"))?;
+                let replay = self
+                    .state_dir
+                    .as_ref()
+                    .map(|dir| ReplayStore::new(dir).fresh(&session_key, dialer));
+                let connection =
+                    secure(&session_key, dialer, stream, replay, (true, true), &self).await?;
+                connection.underlying().start_ready_barrier()?;
+                metrics::connect_success(role_name(dialer));
+
+                Ok(Connection::Chacha20(connection))
+            })
+            .await
+            .inspect(|_| connected(&diagnostics, started))
+    }
+
+    async fn establish<S, A>(
+        self,
+        signalling: S,
+        dialer: bool,
+        auth: A,
+        reconnect: Option<Reconnect<S>>,
+        channel: String,
+        deadline: Deadline,
+    ) -> ConnectResult<(Connection, IceAgent<S>)>
+    where
+        S: Signalling,
+        S::Error: Into<SignalingError>,
+        A: Authentication,
+    {
+        let encryption = self.encryption;
+        let transport = encryption.transport();
+        // What the ChaCha20 layer is set up with
+        let crypto = ConnectOptions {
+            traffic_limit: self.traffic_limit,
+            desync_policy: self.desync_policy,
+            strictness: self.strictness,
+            diagnostics: self.diagnostics.clone(),
+            events: self.events.clone(),
+            state_dir: self.state_dir.clone(),
+            ..Default::default()
+        };
+        let (basekey, dialer, mut stream, agent) = self
+            .establish_sctp(signalling, dialer, auth, reconnect, channel, transport)
+            .await?;
+        stream.set_deadline(deadline);
+        let connection = match encryption {
+            Encryption::Chacha20 => {
+                let replay = crypto
+                    .state_dir
+                    .as_ref()
+                    .map(|dir| ReplayStore::new(dir).fresh(&basekey, dialer));
+                let peer = (agent.peer_confirms_keys(), agent.peer_numbers_messages());
+                Connection::Chacha20(secure(&basekey, dialer, stream, replay, peer, &crypto).await?)
+            }
+            #[cfg(feature = "dtls")]
+            Encryption::Dtls => Connection::Dtls(stream),
+        };
+        connection.sctp().start_ready_barrier()?;
+        metrics::connect_success(role_name(dialer));
+
+        Ok((connection, agent))
+    }
+
+    async fn establish_sctp<S, A>(
+        self,
+        signalling: S,
+        dialer: bool,
+        auth: A,
+        reconnect: Option<Reconnect<S>>,
+        channel: String,
+        transport: TransportKind,
+    ) -> ConnectResult<(Vec<u8>, bool, ConnectionSctp, IceAgent<S>)>
+    where
+        S: Signalling,
+        S::Error: Into<SignalingError>,
+        A: Authentication,
+    {
+        trace::Span::current().record("role", role_name(dialer));
+
+        let ice_urls = ice_urls(self.ice_servers, self.ice)
+            .inspect_err(|_| metrics::connect_failure("config"))?;
+        self.gather_policy
+            .check(&ice_urls)
+            .inspect_err(|_| metrics::connect_failure("config"))?;
+
+        let agreement = Agreement::new(signalling, &auth);
+        let diagnostics = self.diagnostics.clone();
+        let Agreed {
+            basekey,
+            mut signalling,
+            peer_payload,
+            peer_public_key,
+        } = diagnostics
+            .phase(
+                "agreement",
+                agreement
+                    .agree(self.agreement_payload)
+                    .instrument(trace::info_span!("agreement")),
+            )
+            .await
+            .inspect_err(|_| metrics::connect_failure("agreement"))?;
+        let peer = PeerIdentity {
+            channel,
+            public_key: peer_public_key,
+        };
+
+        if self.policy.is_some() || self.hello.is_some() {
+            let admission = async {
+                match (&self.policy, &self.hello) {
+                    (Some(policy), _) => {
+                        admit(&mut signalling, &basekey, policy.as_ref(), &peer).await
+                    }
+                    (None, Some(hello)) => present(&mut signalling, &basekey, hello).await,
+                    (None, None) => Ok(()),
+                }
+            };
+            diagnostics
+                .phase(
+                    "admission",
+                    admission.instrument(trace::info_span!("admission")),
+                )
+                .await
+                .inspect_err(|_| metrics::connect_failure("admission"))?;
+        }
+        let PeerIdentity {
+            channel,
+            public_key: peer_public_key,
+        } = peer;
+
+        if let Some(floor) = &self.security_floor {
+            let local = SecurityParams {
+                auth: auth.kind(),
+                transport,
+            };
+            diagnostics
+                .phase(
+                    "security announcement",
+                    floor
+                        .negotiate(&mut signalling, &basekey, local)
+                        .instrument(trace::info_span!("negotiation")),
+                )
+                .await
+                .inspect_err(|_| metrics::connect_failure("downgrade"))?;
+        }
+
+        #[cfg(feature = "dtls")]
+        let dtls = match self.encryption {
+            Encryption::Chacha20 => None,
+            Encryption::Dtls => {
+                let identity = DtlsIdentity::generate()?;
+                let peer_fingerprint = diagnostics
+                    .phase(
+                        "dtls fingerprint exchange",
+                        identity
+                            .exchange(&mut signalling, &basekey)
+                            .instrument(trace::info_span!("dtls")),
+                    )
+                    .await
+                    .inspect_err(|_| metrics::connect_failure("dtls"))?;
+                Some((identity, peer_fingerprint))
+            }
+        };
+
+        let ice_span = trace::info_span!("ice");
+        #[cfg(feature = "dtls")]
+        let fingerprint = dtls
+            .as_ref()
+            .map(|(identity, _)| identity.fingerprint().to_owned());
+        #[cfg(not(feature = "dtls"))]
+        let fingerprint = None;
+        let ice_config = IceConfig {
+            urls: ice_urls,
+            signalling_rate_limit: self.signaling_rate_limit,
+            candidate_cache: self.candidate_cache,
+            format: self.signalling_format,
+            fingerprint,
+            strict_roles: self.strict_roles,
+            strictness: self.strictness,
+            diagnostics: diagnostics.clone(),
+            events: self.events.clone(),
+            pair_selection: self.pair_selection,
+            gather_policy: self.gather_policy,
+            keep_link_local: self.keep_link_local,
+            candidate_encoding: self.candidate_encoding,
+            candidate_queue: self.candidate_queue,
+            agent_config: self.agent_config,
+            resolver: self.resolver,
+            signalling_policy: self.signalling_policy,
+            rejection: Rejection::new(&basekey),
+        };
+        let mut agent = diagnostics
+            .phase(
+                "ice handshake",
+                IceAgent::new(signalling, dialer, ice_config).instrument(ice_span.clone()),
+            )
+            .await
+            .inspect_err(|_| metrics::connect_failure("ice"))?;
+        if let Some(reconnect) = reconnect {
+            agent.set_signalling_reconnect(reconnect, self.signaling_reconnects);
+        }
+        let net_conn = diagnostics
+            .phase("ice", agent.connect().instrument(ice_span))
+            .await
+            .inspect_err(|_| metrics::connect_failure("ice"))?;
+        // Both peers may have been assigned the same role
+        let dialer = agent.dialer();
+        trace::Span::current().record("role", role_name(dialer));
+        diagnostics.role(role_name(dialer));
+        #[cfg(feature = "dtls")]
+        let net_conn = match dtls {
+            None => net_conn,
+            Some((identity, peer_fingerprint)) => diagnostics
+                .phase(
+                    "dtls handshake",
+                    identity
+                        .handshake(net_conn, peer_fingerprint, dialer)
+                        .instrument(trace::info_span!("dtls")),
+                )
+                .await
+                .inspect_err(|_| metrics::connect_failure("dtls"))?,
+        };
+        let mut stream = diagnostics
+            .phase(
+                "sctp",
+                Sctp::new(
+                    net_conn,
+                    agent.agent(),
+                    dialer,
+                    agent.connection(),
+                    self.sctp,
+                )
+                .instrument(trace::info_span!("sctp")),
+            )
+            .await
+            .inspect_err(|_| metrics::connect_failure("sctp"))?;
+        stream.set_candidate_cache(Some(agent.candidate_cache()));
+        stream.set_diagnostics(diagnostics);
+        stream.set_events(self.events);
+        stream.set_strictness(self.strictness);
+        stream.set_info(ConnectionInfo {
+            dialer,
+            channel_id: channel,
+            transport,
+            peer_public_key,
+            peer_payload,
+        });
+
+        Ok((basekey, dialer, stream, agent))
+    }
+}
+
+/// Records how long connecting took in total and reports every step, see
+/// [`ConnectTimings`].
+fn connected(diagnostics: &Diagnostics, started: Instant) {
+    diagnostics.timing("total", started.elapsed());
+    for (step, duration) in diagnostics.timings().steps() {
+        metrics::connect_step(step, duration);
+    }
+}
+
+/// Runs the key agreement over `stream`, a channel the peers already share,
+/// and secures it with ChaCha20, no signalling server nor ICE involved.
+/// `stream` must keep message boundaries, see [`StreamSignalling`]. Both
+/// peers pass opposite roles.
+pub async fn secure_existing<S, A>(
+    stream: S,
+    auth: A,
+    dialer: bool,
+) -> ConnectResult<Chacha20Stream<S>>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+    A: Authentication,
+{
+    let agreement = Agreement::new(StreamSignalling::new(stream), auth);
+    let Agreed {
+        basekey,
+        signalling,
+        ..
+    } = agreement
+        .agree(None)
+        .instrument(trace::info_span!("agreement"))
+        .await?;
+
+    secure(
+        &basekey,
+        dialer,
+        signalling.into_inner(),
+        None,
+        (true, true),
+        &ConnectOptions::default(),
+    )
+    .await
+}
+
+/// Wraps `stream` in the ChaCha20 layer, once both peers confirmed the key
+/// if `confirm`, set up with the traffic limit, desync policy, strictness,
+/// diagnostics and events of `options`. Only confirm with peers known to,
+/// see [`IceAgent::peer_confirms_keys`], and only number messages for peers
+/// known to, see [`IceAgent::peer_numbers_messages`].
+async fn secure<S>(
+    basekey: &[u8],
+    dialer: bool,
+    stream: S,
+    replay: Option<Result<ReplayState, ReplayStateError>>,
+    (confirm, numbered): (bool, bool),
+    options: &ConnectOptions,
+) -> ConnectResult<Chacha20Stream<S>>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let mut connection = Chacha20Stream::new(basekey, dialer, stream)
+        .inspect_err(|_| metrics::connect_failure("crypto"))?;
+    if let Some(replay) = replay {
+        let replay = replay
+            .map_err(Chacha20Error::from)
+            .inspect_err(|_| metrics::connect_failure("crypto"))?;
+        connection.set_replay_state(replay);
+    }
+    if !numbered {
+        trace::debug!(target: logging::CRYPTO, "Messages not numbered, unknown to the peer");
+        connection.set_unnumbered();
+    }
+    connection.set_desync_policy(options.desync_policy);
+    connection.set_diagnostics(options.diagnostics.clone());
+    connection.set_events(options.events.clone());
+    match confirm {
+        true => options
+            .diagnostics
+            .phase("key confirmation", connection.confirm_key())
+            .await
+            .inspect_err(|_| metrics::connect_failure("crypto"))?,
+        false => trace::debug!(target: logging::CRYPTO, "Key not confirmed, unknown to the peer"),
+    }
+    connection.set_traffic_limit(options.traffic_limit);
+    connection.set_strictness(options.strictness);
+
+    Ok(connection)
+}
+
+/// Bytes each peer contributes to a [`ConnectOptions::connect_over`] session.
+const SESSION_NONCE_LEN: usize = 32;
+
+/// Key of a [`ConnectOptions::connect_over`] session, `basekey` mixed with a
+/// fresh nonce from each peer so that no two sessions share a key, and so a
+/// nonce sequence, even with `basekey` used over and over.
+async fn session_key(basekey: &[u8], dialer: bool, stream: &mut Sctp) -> ConnectResult<Vec<u8>> {
+    let mut nonce = [0; SESSION_NONCE_LEN];
+    crypto_backend::fill_random(&mut nonce).map_err(Chacha20Error::CryptoError)?;
+    stream.send(&nonce).await?;
+    let peer_nonce = loop {
+        let mut value = stream.wait().await?;
+        if let Some(msg) = stream.then(&mut value).await? {
+            break msg;
+        }
+        if stream.rx_closed() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    };
+    if peer_nonce.len() != SESSION_NONCE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Peer sent a session nonce of {} bytes", peer_nonce.len()),
+        )
+        .into());
+    }
+
+    let (dialer_nonce, listener_nonce) = match dialer {
+        true => (&nonce[..], &peer_nonce[..]),
+        false => (&peer_nonce[..], &nonce[..]),
+    };
+    let mut key = vec![0; 32];
+    crypto_backend::hkdf_sha512(
+        &[dialer_nonce, listener_nonce].concat(),
+        basekey,
+        b"icepipe connect_over session",
+        &mut key,
+    );
+
+    Ok(key)
+}
+
+/// Both forms of servers together, or else the default ones.
+pub(crate) fn ice_urls(servers: Vec<IceServer>, ice: Vec<String>) -> ConnectResult<Vec<Url>> {
+    let parse = |ice: Vec<String>| {
+        ice.iter()
+            .map(|s| IceServer::from_str(s))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let servers = [servers, parse(ice).map_err(ConnectError::BadIceUrl)?].concat();
+    let servers = match servers.into_option() {
+        Some(servers) => servers,
+        None => parse(constants::ice_urls())
+            .map_err(ConnectError::BadIceUrl)?
+            .into_option()
+            .ok_or(ConnectError::NoDefaultValue(Constants::Ice))?,
+    };
+
+    let urls = servers.iter().map(IceServer::parse_urls);
+    let urls = urls.collect::<Result<Vec<_>, _>>();
+    Ok(urls.map_err(ConnectError::BadIceUrl)?.concat())
+}
+
+async fn join(
+    signaling: &url::Url,
+    channel: String,
+    config: WebsocketConfig,
+) -> ConnectResult<(String, Websocket, bool)> {
+    let (signalling, dialer) = Websocket::connect(signaling.join(&channel).unwrap(), config)
+        .await
+        .map_err(SignalingError::from)?;
+
+    Ok((channel, signalling, dialer))
+}
+
+/// Waits for the peer on both the current and the previous channel. The
+/// current one is preferred, a peer met on the previous channel may also be
+/// arriving on the current one when both peers are in the same window.
+async fn join_hopping(
+    signaling: &url::Url,
+    (current, previous): (String, String),
+    config: WebsocketConfig,
+) -> ConnectResult<(String, Websocket, bool)> {
+    let mut current = join(signaling, current, config.clone()).boxed_local();
+    let mut previous = join(signaling, previous, config).boxed_local();
+
+    select! {
+        joined = &mut current => joined,
+        joined = &mut previous => {
+            let previous = joined?;
+            match timeout(CURRENT_CHANNEL_GRACE, current).await {
+                Ok(Ok(current)) => Ok(current),
+                _ => Ok(previous),
+            }
+        }
+    }
+}
+
+/// A [`Connection`] with its type erased, see [`Connection::boxed`].
+pub type BoxedConnection = BoxedPipeStream;
+
+/// Connection established by [`ConnectOptions::connect`], secured as
+/// selected by [`ConnectOptions::encryption`].
+///
+/// Applications keep it as a `Connection` field, or as a [`BoxedConnection`]
+/// when the same field may also hold another stream, see
+/// [`Connection::boxed`].
+// ChaCha20 is both the default and the large variant, boxing it would only
+// add an indirection
+#[allow(clippy::large_enum_variant)]
+pub enum Connection {
+    Chacha20(Chacha20Stream<ConnectionSctp>),
+    /// SCTP over DTLS.
+    #[cfg(feature = "dtls")]
+    Dtls(ConnectionSctp),
+}
+impl Connection {
+    fn sctp(&self) -> &ConnectionSctp {
+        match self {
+            Connection::Chacha20(stream) => stream.underlying(),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream,
+        }
+    }
+
+    /// Candidates exchanged for this connection, pass them in
+    /// [`ConnectOptions::candidate_cache`] so the next connection checks
+    /// them before gathering again, see [`CandidateCache`].
+    pub fn candidate_cache(&self) -> Option<&CandidateCache> {
+        self.sctp().candidate_cache()
+    }
+
+    /// Every subsequent await, including close, fails with [`TimeoutError`]
+    /// once `deadline` passes.
+    pub fn set_deadline(&mut self, deadline: Deadline) {
+        match self {
+            Connection::Chacha20(stream) => stream.underlying_mut().set_deadline(deadline),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream.set_deadline(deadline),
+        }
+    }
+
+    /// Stops receiving until [`Connection::resume`], making the peer's sends
+    /// block once the buffers fill up, see [`PauseHandle`].
+    pub fn pause(&self) {
+        self.pause_handle().pause();
+    }
+
+    pub fn resume(&self) {
+        self.pause_handle().resume();
+    }
+
+    /// Handle to pause and resume from elsewhere, e.g. while this connection
+    /// is being waited on.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.sctp().pause_handle()
+    }
+
+    /// ICE connection state, which keeps changing after the connection is
+    /// returned.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.sctp().connection_state()
+    }
+
+    /// Resolves once the connection is ready to carry data, see
+    /// [`Sctp::wait_established`]. Does not borrow the connection, so it can
+    /// be awaited while the connection is used elsewhere.
+    pub fn wait_established(&self) -> LocalBoxFuture<'static, Result<(), EstablishError>> {
+        self.sctp().wait_established()
+    }
+
+    /// Shared with the halves and any wrapper, see
+    /// [`Control::connection_info`].
+    pub fn info(&self) -> &Arc<ConnectionInfo> {
+        self.sctp().info()
+    }
+
+    /// Who the peer authenticated as, e.g. which key of an
+    /// [`Ed25519PairAndAllowlist`](crate::agreement::Ed25519PairAndAllowlist)
+    /// it signed with.
+    pub fn peer_identity(&self) -> PeerIdentity {
+        let info = self.info();
+        PeerIdentity {
+            channel: info.channel_id.clone(),
+            public_key: info.peer_public_key.clone(),
+        }
+    }
+
+    /// What the peer handed over in
+    /// [`ConnectOptions::agreement_payload`], authenticated like its key.
+    pub fn peer_agreement_payload(&self) -> Option<&[u8]> {
+        self.info().peer_payload.as_deref()
+    }
 
-        let mut agent = IceAgent::new(signalling, dialer, ice_urls).await?;
-        let net_conn = agent.connect().await?;
-        let stream = Sctp::new(net_conn, dialer, agent.connection()).await?;
+    /// Whether this side is the dialer, the peer is the listener and the
+    /// other way around. Final once connected, both peers may have been
+    /// assigned the same role by the signalling server at first.
+    pub fn is_dialer(&self) -> bool {
+        self.info().dialer
+    }
+
+    /// See [`ConnectionInfo::channel_id`].
+    pub fn channel_id(&self) -> &str {
+        &self.info().channel_id
+    }
 
-        Ok(Chacha20Stream::new(&basekey, dialer, stream)?)
+    pub fn transport_kind(&self) -> TransportKind {
+        self.info().transport
+    }
+
+    /// Traffic of the SCTP association so far, encryption overhead
+    /// included.
+    pub fn stats(&self) -> SctpStats {
+        self.sctp().stats()
+    }
+
+    /// Bytes the peer received but its application did not read yet, see
+    /// [`SctpStats::remote_lag`]. High while the peer is slow to apply what
+    /// it receives, e.g. writing to a slow disk.
+    pub fn remote_lag_bytes(&self) -> Option<usize> {
+        self.stats().remote_lag
+    }
+
+    /// Tells the peer this application is ready to receive, see
+    /// [`SctpConfig::ready_barrier`].
+    pub fn ready(&self) -> StreamResult<()> {
+        Ok(self.sctp().ready()?)
+    }
+
+    /// See [`Sctp::probe_mtu`].
+    pub async fn probe_mtu(&self) -> PathMtu {
+        self.sctp().probe_mtu().await
+    }
+
+    /// Every candidate pair ICE checked, the one carrying the connection is
+    /// marked as selected.
+    pub async fn candidate_pairs(&self) -> Vec<CandidatePairEntry> {
+        self.sctp().candidate_pairs().await
+    }
+
+    /// Report to attach to bug reports, candidate addresses are redacted.
+    pub fn diagnostics(&self) -> DiagnosticsReport {
+        self.sctp().diagnostics(self.cipher(), false)
+    }
+
+    /// How long each step of establishing this connection took, also part
+    /// of [`Connection::diagnostics`].
+    pub fn timings(&self) -> ConnectTimings {
+        self.sctp().timings()
+    }
+
+    /// Like [`Connection::diagnostics`] but with full candidates, which
+    /// reveal the addresses of both peers.
+    pub fn diagnostics_full(&self) -> DiagnosticsReport {
+        self.sctp().diagnostics(self.cipher(), true)
+    }
+
+    /// Non-fatal events from now on, see [`ConnectOptions::events`] to
+    /// receive those of connecting as well.
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.sctp().events().subscribe()
+    }
+
+    /// Erases the type of this connection, so it can be stored along with
+    /// other streams. Keep what [`Connection::diagnostics`],
+    /// [`Connection::events`] and the like return beforehand, only
+    /// [`PipeStream`] and [`Control`] are left afterwards.
+    pub fn boxed(self) -> BoxedConnection {
+        boxed(self)
+    }
+
+    fn cipher(&self) -> &'static str {
+        match self {
+            Connection::Chacha20(_) => "chacha20-poly1305",
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(_) => "dtls",
+        }
+    }
+}
+impl PipeStream for Connection {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, StreamResult<()>> {
+        match self {
+            Connection::Chacha20(stream) => stream_result(stream.send(data)),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream_result(stream.send(data)),
+        }
+    }
+}
+impl WaitThen for Connection {
+    type Value = SctpValue;
+    type Output = Option<Vec<u8>>;
+    type Error = StreamError;
+
+    fn wait(&mut self) -> LocalBoxFuture<'_, StreamResult<Self::Value>> {
+        match self {
+            Connection::Chacha20(stream) => stream_result(stream.wait()),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream_result(stream.wait()),
+        }
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, StreamResult<Self::Output>> {
+        match self {
+            Connection::Chacha20(stream) => stream_result(stream.then(value)),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream_result(stream.then(value)),
+        }
+    }
+}
+impl Control for Connection {
+    /// Bounded by [`SctpConfig::close_timeout`].
+    fn close(&mut self) -> LocalBoxFuture<'_, StreamResult<()>> {
+        match self {
+            // Also bounds sending the FIN, which may wait on the SCTP buffer
+            Connection::Chacha20(stream) => {
+                let watchdog = stream.underlying().watchdog();
+                watchdog.guard(stream_result(stream.close())).boxed_local()
+            }
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream_result(stream.close()),
+        }
+    }
+
+    fn rx_closed(&self) -> bool {
+        match self {
+            Connection::Chacha20(stream) => stream.rx_closed(),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream.rx_closed(),
+        }
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        match self {
+            Connection::Chacha20(stream) => stream.close_reason(),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream.close_reason(),
+        }
+    }
+
+    fn notify<'a>(&'a mut self, notice: &'a str) -> LocalBoxFuture<'a, StreamResult<()>> {
+        match self {
+            Connection::Chacha20(stream) => stream_result(stream.notify(notice)),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream_result(stream.notify(notice)),
+        }
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        Some(self.info().clone())
+    }
+
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        match self {
+            Connection::Chacha20(stream) => stream.writable(),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream.writable(),
+        }
+    }
+
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, StreamResult<()>> {
+        match self {
+            Connection::Chacha20(stream) => stream_result(stream.request_shutdown(deadline)),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream_result(stream.request_shutdown(deadline)),
+        }
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        match self {
+            Connection::Chacha20(stream) => stream.shutdown_request(),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => stream.shutdown_request(),
+        }
+    }
+}
+impl Split for Connection {
+    type ReadHalf = ConnectionReadHalf;
+    type WriteHalf = ConnectionWriteHalf;
+
+    fn split(self) -> (ConnectionReadHalf, ConnectionWriteHalf) {
+        match self {
+            Connection::Chacha20(stream) => {
+                let (rx, tx) = stream.split();
+                (
+                    ConnectionReadHalf::Chacha20(rx),
+                    ConnectionWriteHalf::Chacha20(tx),
+                )
+            }
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(stream) => {
+                let (rx, tx) = stream.split();
+                (ConnectionReadHalf::Dtls(rx), ConnectionWriteHalf::Dtls(tx))
+            }
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum ConnectionReadHalf {
+    Chacha20(Chacha20ReadHalf<SctpReadHalf>),
+    #[cfg(feature = "dtls")]
+    Dtls(SctpReadHalf),
+}
+impl ConnectionReadHalf {
+    pub fn set_deadline(&mut self, deadline: Deadline) {
+        match self {
+            ConnectionReadHalf::Chacha20(rx) => rx.underlying_mut().set_deadline(deadline),
+            #[cfg(feature = "dtls")]
+            ConnectionReadHalf::Dtls(rx) => rx.set_deadline(deadline),
+        }
+    }
+
+    /// See [`Connection::pause_handle`].
+    pub fn pause_handle(&self) -> PauseHandle {
+        match self {
+            ConnectionReadHalf::Chacha20(rx) => rx.underlying().pause_handle(),
+            #[cfg(feature = "dtls")]
+            ConnectionReadHalf::Dtls(rx) => rx.pause_handle(),
+        }
+    }
+}
+impl WaitThen for ConnectionReadHalf {
+    type Value = SctpValue;
+    type Output = Option<Vec<u8>>;
+    type Error = StreamError;
+
+    fn wait(&mut self) -> LocalBoxFuture<'_, StreamResult<Self::Value>> {
+        match self {
+            ConnectionReadHalf::Chacha20(rx) => stream_result(rx.wait()),
+            #[cfg(feature = "dtls")]
+            ConnectionReadHalf::Dtls(rx) => stream_result(rx.wait()),
+        }
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, StreamResult<Self::Output>> {
+        match self {
+            ConnectionReadHalf::Chacha20(rx) => stream_result(rx.then(value)),
+            #[cfg(feature = "dtls")]
+            ConnectionReadHalf::Dtls(rx) => stream_result(rx.then(value)),
+        }
+    }
+}
+impl PipeReadHalf for ConnectionReadHalf {
+    fn rx_closed(&self) -> bool {
+        match self {
+            ConnectionReadHalf::Chacha20(rx) => rx.rx_closed(),
+            #[cfg(feature = "dtls")]
+            ConnectionReadHalf::Dtls(rx) => rx.rx_closed(),
+        }
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        match self {
+            ConnectionReadHalf::Chacha20(rx) => rx.close_reason(),
+            #[cfg(feature = "dtls")]
+            ConnectionReadHalf::Dtls(rx) => rx.close_reason(),
+        }
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        match self {
+            ConnectionReadHalf::Chacha20(rx) => rx.connection_info(),
+            #[cfg(feature = "dtls")]
+            ConnectionReadHalf::Dtls(rx) => rx.connection_info(),
+        }
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        match self {
+            ConnectionReadHalf::Chacha20(rx) => rx.shutdown_request(),
+            #[cfg(feature = "dtls")]
+            ConnectionReadHalf::Dtls(rx) => rx.shutdown_request(),
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum ConnectionWriteHalf {
+    Chacha20(Chacha20WriteHalf<SctpWriteHalf>),
+    #[cfg(feature = "dtls")]
+    Dtls(SctpWriteHalf),
+}
+impl ConnectionWriteHalf {
+    pub fn set_deadline(&mut self, deadline: Deadline) {
+        match self {
+            ConnectionWriteHalf::Chacha20(tx) => tx.underlying_mut().set_deadline(deadline),
+            #[cfg(feature = "dtls")]
+            ConnectionWriteHalf::Dtls(tx) => tx.set_deadline(deadline),
+        }
+    }
+
+    /// See [`Connection::ready`].
+    pub fn ready(&self) -> StreamResult<()> {
+        let tx = match self {
+            ConnectionWriteHalf::Chacha20(tx) => tx.underlying(),
+            #[cfg(feature = "dtls")]
+            ConnectionWriteHalf::Dtls(tx) => tx,
+        };
+        Ok(tx.ready()?)
+    }
+}
+impl PipeWriteHalf for ConnectionWriteHalf {
+    type Error = StreamError;
+
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, StreamResult<()>> {
+        match self {
+            ConnectionWriteHalf::Chacha20(tx) => stream_result(tx.send(data)),
+            #[cfg(feature = "dtls")]
+            ConnectionWriteHalf::Dtls(tx) => stream_result(tx.send(data)),
+        }
+    }
+
+    fn close(&mut self) -> LocalBoxFuture<'_, StreamResult<()>> {
+        match self {
+            ConnectionWriteHalf::Chacha20(tx) => {
+                let watchdog = tx.underlying().watchdog();
+                watchdog.guard(stream_result(tx.close())).boxed_local()
+            }
+            #[cfg(feature = "dtls")]
+            ConnectionWriteHalf::Dtls(tx) => stream_result(tx.close()),
+        }
+    }
+
+    fn notify<'a>(&'a mut self, notice: &'a str) -> LocalBoxFuture<'a, StreamResult<()>> {
+        match self {
+            ConnectionWriteHalf::Chacha20(tx) => stream_result(tx.notify(notice)),
+            #[cfg(feature = "dtls")]
+            ConnectionWriteHalf::Dtls(tx) => stream_result(tx.notify(notice)),
+        }
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        match self {
+            ConnectionWriteHalf::Chacha20(tx) => tx.connection_info(),
+            #[cfg(feature = "dtls")]
+            ConnectionWriteHalf::Dtls(tx) => tx.connection_info(),
+        }
+    }
+
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        match self {
+            ConnectionWriteHalf::Chacha20(tx) => tx.writable(),
+            #[cfg(feature = "dtls")]
+            ConnectionWriteHalf::Dtls(tx) => tx.writable(),
+        }
+    }
+
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, StreamResult<()>> {
+        match self {
+            ConnectionWriteHalf::Chacha20(tx) => stream_result(tx.request_shutdown(deadline)),
+            #[cfg(feature = "dtls")]
+            ConnectionWriteHalf::Dtls(tx) => stream_result(tx.request_shutdown(deadline)),
+        }
     }
 }
 
@@ -76,9 +1582,10 @@ pub async fn connect(
         .map_err(ConnectError::BadSignalingUrl)?;
 
     ConnectOptions {
-        channel: channel.to_owned(),
+        channel: channel.into(),
         signaling,
         ice: ice.to_owned(),
+        ..Default::default()
     }
     .connect_psk()
     .await
@@ -100,19 +1607,50 @@ pub enum ConnectError {
     SctpError(SctpError),
     #[error(transparent)]
     Chacha20Error(Chacha20Error),
+    #[cfg(feature = "dtls")]
+    #[error(transparent)]
+    DtlsError(DtlsError),
     #[error("No default value available for signaling, must provide one")]
     NoDefaultValue(Constants),
     #[error(transparent)]
     BadSignalingUrl(url::ParseError),
+    #[error("Unsupported signaling scheme {0:?}, expected one of {SIGNALING_SCHEMES:?}")]
+    UnsupportedSignalingScheme(String),
     #[error(transparent)]
     BadIceUrl(webrtc_ice::Error),
+    #[error("Bad ICE servers JSON: {0}")]
+    BadIceJson(serde_json::Error),
+    #[error("Both peers were assigned the {0} role, is the signalling server misconfigured?")]
+    RoleConflict(&'static str),
+    #[error(transparent)]
+    AdmissionError(AdmissionError),
+    #[error("GatherPolicy::RelayOnlyNoBind needs a turn: server over UDP with credentials in ice_servers")]
+    NoRelay,
+    #[error(transparent)]
+    Violation(Violation),
+    #[error(transparent)]
+    Downgrade(DowngradeError),
+    #[error(transparent)]
+    QueueTimeout(#[from] QueueTimeout),
+    #[error("The channel was given already derived, {0} needs the PSK")]
+    NoPsk(&'static str),
+    /// This peer authenticated the other but was not authenticated by it,
+    /// as opposed to [`AgreementError::BadAuth`].
+    #[error(transparent)]
+    PeerRejected(PeerRejected),
 }
 impl From<SignalingError> for ConnectError {
     fn from(value: SignalingError) -> Self {
         match value {
             SignalingError::Io(e) => e.into(),
             SignalingError::Timeout(e) => e.into(),
-            e @ SignalingError::ProtocolError(_) => Self::SignalingError(e),
+            e @ SignalingError::ProtocolError(_) => match Violation::downcast(e) {
+                Ok(violation) => Self::Violation(violation),
+                Err(SignalingError::ProtocolError(e)) if e.is::<PeerRejected>() => {
+                    Self::PeerRejected(PeerRejected)
+                }
+                Err(e) => Self::SignalingError(e),
+            },
         }
     }
 }
@@ -125,12 +1663,38 @@ impl From<AgreementError> for ConnectError {
             e @ AgreementError::Base64Error(_) => Self::AgreementError(e),
             e @ AgreementError::CryptoError(_) => Self::AgreementError(e),
             e @ AgreementError::BadAuth(..) => Self::AgreementError(e),
+            e @ AgreementError::PayloadTooLarge(_) => Self::AgreementError(e),
+        }
+    }
+}
+impl From<DowngradeError> for ConnectError {
+    fn from(value: DowngradeError) -> Self {
+        match value {
+            DowngradeError::Io(e) => e.into(),
+            DowngradeError::Timeout(e) => e.into(),
+            DowngradeError::SignalingError(e) => e.into(),
+            e @ (DowngradeError::Auth { .. }
+            | DowngradeError::Unencrypted { .. }
+            | DowngradeError::Transport { .. }
+            | DowngradeError::NotAnnounced
+            | DowngradeError::Tampered) => Self::Downgrade(e),
         }
     }
 }
 impl From<IceError> for ConnectError {
     fn from(value: IceError) -> Self {
-        Self::StreamError(value.into())
+        match value {
+            IceError::RoleConflict(role) => Self::RoleConflict(role),
+            IceError::NoRelay => Self::NoRelay,
+            IceError::Violation(violation) => Self::Violation(violation),
+            IceError::SignalingError(e) => match e {
+                SignalingError::ProtocolError(e) if e.is::<PeerRejected>() => {
+                    Self::PeerRejected(PeerRejected)
+                }
+                e => Self::StreamError(IceError::SignalingError(e).into()),
+            },
+            e => Self::StreamError(e.into()),
+        }
     }
 }
 impl From<StreamError> for ConnectError {
@@ -139,7 +1703,9 @@ impl From<StreamError> for ConnectError {
             StreamError::Io(e) => e.into(),
             StreamError::Timeout(e) => e.into(),
             StreamError::SignalingError(e) => e.into(),
-            e @ StreamError::Other(_) => Self::StreamError(e),
+            e @ (StreamError::Other(_)
+            | StreamError::TrafficLimit(_)
+            | StreamError::ClosedDirty(_)) => Self::StreamError(e),
         }
     }
 }
@@ -152,6 +1718,8 @@ impl From<SctpError> for ConnectError {
             e @ SctpError::StreamError(_) => Self::SctpError(e),
             e @ SctpError::AssociationClosedWithoutStream => Self::SctpError(e),
             e @ SctpError::WebrtcSctpError(_) => Self::SctpError(e),
+            e @ SctpError::ClosedDirty(_) => Self::SctpError(e),
+            SctpError::Violation(violation) => Self::Violation(violation),
         }
     }
 }
@@ -163,6 +1731,41 @@ impl From<Chacha20Error> for ConnectError {
             Chacha20Error::SignalingError(e) => e.into(),
             Chacha20Error::StreamError(e) => e.into(),
             e @ Chacha20Error::CryptoError(_) => Self::Chacha20Error(e),
+            e @ Chacha20Error::KeyConfirmationFailed => Self::Chacha20Error(e),
+            e @ Chacha20Error::Truncated => Self::Chacha20Error(e),
+            e @ Chacha20Error::NonceDesync { .. } => Self::Chacha20Error(e),
+            e @ Chacha20Error::BadFinish { .. } => Self::Chacha20Error(e),
+            e @ Chacha20Error::Incomplete { .. } => Self::Chacha20Error(e),
+            e @ Chacha20Error::TrafficLimit(_) => Self::Chacha20Error(e),
+            e @ Chacha20Error::ReplayState(_) => Self::Chacha20Error(e),
+            Chacha20Error::Violation(violation) => Self::Violation(violation),
+        }
+    }
+}
+#[cfg(feature = "dtls")]
+impl From<DtlsError> for ConnectError {
+    fn from(value: DtlsError) -> Self {
+        match value {
+            DtlsError::Io(e) => e.into(),
+            DtlsError::Timeout(e) => e.into(),
+            DtlsError::SignalingError(e) => e.into(),
+            e @ DtlsError::WebrtcDtlsError(_) => Self::DtlsError(e),
+            e @ DtlsError::NotNegotiated => Self::DtlsError(e),
+            e @ DtlsError::BadFingerprint => Self::DtlsError(e),
+        }
+    }
+}
+impl From<AdmissionError> for ConnectError {
+    fn from(value: AdmissionError) -> Self {
+        match value {
+            AdmissionError::Io(e) => e.into(),
+            AdmissionError::Timeout(e) => e.into(),
+            AdmissionError::SignalingError(e) => e.into(),
+            e @ (AdmissionError::BadMessage(_)
+            | AdmissionError::UnexpectedHello
+            | AdmissionError::Tampered
+            | AdmissionError::Rejected(_)
+            | AdmissionError::Throttled(_)) => Self::AdmissionError(e),
         }
     }
 }
@@ -178,9 +1781,21 @@ impl From<ConnectError> for StreamError {
             ConnectError::StreamError(e) => e,
             ConnectError::SctpError(e) => e.into(),
             ConnectError::Chacha20Error(e) => e.into(),
+            #[cfg(feature = "dtls")]
+            e @ ConnectError::DtlsError(_) => StreamError::Other(Box::new(e)),
             e @ ConnectError::NoDefaultValue(_) => StreamError::Other(Box::new(e)),
             e @ ConnectError::BadSignalingUrl(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::UnsupportedSignalingScheme(_) => StreamError::Other(Box::new(e)),
             e @ ConnectError::BadIceUrl(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::BadIceJson(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::RoleConflict(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::AdmissionError(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::NoRelay => StreamError::Other(Box::new(e)),
+            e @ ConnectError::Violation(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::Downgrade(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::QueueTimeout(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::NoPsk(_) => StreamError::Other(Box::new(e)),
+            e @ ConnectError::PeerRejected(_) => StreamError::Other(Box::new(e)),
         }
     }
 }
@@ -212,19 +1827,1407 @@ impl<T> Optional for Vec<T> {
     }
 }
 
-struct ParseUrl(webrtc_ice::url::Url);
-impl FromStr for ParseUrl {
-    type Err = webrtc_ice::Error;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agreement::{AgreementResult, AuthKind, Ed25519PairAndAllowlist, Ed25519PairAndPeer},
+        async_pipe_stream::AsyncPipeStream,
+        crypto_backend::Ed25519KeyPair,
+        memory_signalling::MemorySignalling,
+        pipe_stream::{Control, PipeReadHalf, PipeStream, PipeWriteHalf, Split, WaitThen},
+        policy::{AllowlistPolicy, Decision, RateLimitPolicy},
+        queued_stream::QueuedStream,
+        sctp::MAX_MESSAGE_SIZE,
+        service,
+    };
+    use std::{
+        cell::Cell,
+        collections::HashSet,
+        net::SocketAddr,
+        sync::atomic::{AtomicBool, AtomicU64, Ordering},
+        time::Instant,
+    };
+    #[cfg(feature = "tracing")]
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UdpSocket,
+        task::LocalSet,
+    };
+    #[cfg(feature = "tracing")]
+    use tracing_subscriber::fmt::{format::FmtSpan, MakeWriter};
+
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+    #[cfg(feature = "tracing")]
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    #[cfg(feature = "tracing")]
+    impl<'a> MakeWriter<'a> for Capture {
+        type Writer = Capture;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn loopback_options() -> ConnectOptions {
+        ConnectOptions {
+            channel: "loopback".into(),
+            ice: vec!["stun:127.0.0.1:3478".to_string()],
+            ..Default::default()
+        }
+    }
+
+    async fn loopback(
+        dialer_options: ConnectOptions,
+        listener_options: ConnectOptions,
+    ) -> (Connection, Connection) {
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            dialer_options.connect_psk_with_signalling(a, true),
+            listener_options.connect_psk_with_signalling(b, false),
+        );
+
+        (a.unwrap(), b.unwrap())
+    }
+
+    #[tokio::test]
+    async fn allowlisted_keys() {
+        let key_pair = |seed| Ed25519KeyPair::from_seed(&[seed; 32]).unwrap();
+        let public_key = |seed| key_pair(seed).public_key().to_vec();
+        let server = || {
+            Ed25519PairAndAllowlist::new(key_pair(0), HashSet::from([public_key(1), public_key(2)]))
+        };
+
+        for client in [1, 2] {
+            let (a, b) = MemorySignalling::pair();
+            let (a, b) = tokio::join!(
+                loopback_options().connect_with_signalling(a, true, server()),
+                loopback_options().connect_with_signalling(
+                    b,
+                    false,
+                    Ed25519PairAndPeer(key_pair(client), public_key(0))
+                ),
+            );
+            let (a, b) = (a.unwrap(), b.unwrap());
+            assert_eq!(a.peer_identity().public_key, Some(public_key(client)));
+            assert_eq!(b.peer_identity().public_key, Some(public_key(0)));
+            assert_eq!(a.peer_identity().channel, b.peer_identity().channel);
+            close(a, b).await;
+        }
+
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            loopback_options().connect_with_signalling(a, true, server()),
+            loopback_options().connect_with_signalling(
+                b,
+                false,
+                Ed25519PairAndPeer(key_pair(3), public_key(0))
+            ),
+        );
+        assert!(matches!(
+            a,
+            Err(ConnectError::AgreementError(AgreementError::BadAuth(_)))
+        ));
+        // Authenticated the server, told it is not on the allowlist
+        assert!(
+            matches!(b, Err(ConnectError::PeerRejected(_))),
+            "{:?}",
+            b.err()
+        );
+
+        // Expecting another key of the server is a misconfiguration instead
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            loopback_options().connect_with_signalling(a, true, server()),
+            loopback_options().connect_with_signalling(
+                b,
+                false,
+                Ed25519PairAndPeer(key_pair(1), public_key(3))
+            ),
+        );
+        assert!(matches!(a, Err(ConnectError::PeerRejected(_))));
+        assert!(matches!(
+            b,
+            Err(ConnectError::AgreementError(AgreementError::BadAuth(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn security_floor() {
+        let strict = || ConnectOptions {
+            security_floor: Some(SecurityFloor::strict()),
+            ..loopback_options()
+        };
+        let (a, b) = loopback(strict(), strict()).await;
+        close(a, b).await;
+
+        /// A PSK icepipe does not know about.
+        struct Custom(PskAuthentication);
+        impl Authentication for Custom {
+            fn sign(&self, data: &[u8]) -> Vec<u8> {
+                self.0.sign(data)
+            }
+
+            fn check_peer(
+                &self,
+                data: &[u8],
+                signature: &[u8],
+            ) -> AgreementResult<Option<Vec<u8>>> {
+                self.0.check_peer(data, signature)
+            }
+        }
+        let lenient = ConnectOptions {
+            security_floor: Some(SecurityFloor::default()),
+            ..loopback_options()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let r = tokio::select! {
+            r = strict().connect_psk_with_signalling(a, true) => r.map(drop),
+            r = lenient.connect_with_signalling(
+                b,
+                false,
+                Custom(PskAuthentication::new("loopback".to_string())),
+            ) => panic!("downgraded listener connected: {:?}", r.map(drop)),
+        };
+        assert!(matches!(
+            r,
+            Err(ConnectError::Downgrade(DowngradeError::Auth {
+                peer: true,
+                auth: AuthKind::Custom,
+                ..
+            }))
+        ));
+
+        // The floor stripped on the way, or unset by the peer, which fails
+        // on the announcement as well
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            strict().connect_psk_with_signalling(a, true),
+            loopback_options().connect_psk_with_signalling(b, false),
+        );
+        assert!(matches!(
+            a,
+            Err(ConnectError::Downgrade(DowngradeError::NotAnnounced))
+        ));
+        assert!(b.is_err());
+    }
+
+    async fn close(mut a: Connection, mut b: Connection) {
+        let (a, b) = tokio::join!(a.close(), b.close());
+        a.unwrap();
+        b.unwrap();
+    }
+
+    #[tokio::test]
+    async fn candidate_pairs() {
+        let dialer_options = ConnectOptions {
+            pair_selection: PairSelection::FirstResponding,
+            ..loopback_options()
+        };
+        let (a, b) = loopback(dialer_options, loopback_options()).await;
+
+        for connection in [&a, &b] {
+            let pairs = connection.candidate_pairs().await;
+            let selected: Vec<_> = pairs.iter().filter(|pair| pair.selected).collect();
+            assert_eq!(selected.len(), 1, "{pairs:?}");
+            assert_eq!(selected[0].state, "succeeded");
+            assert!(selected[0].local.starts_with("host "), "{pairs:?}");
+        }
+        close(a, b).await;
+    }
+
+    /// Relays like a server concatenating the messages pending and padding
+    /// them with whitespace.
+    struct Mangling(MemorySignalling);
+    impl WaitThen for Mangling {
+        type Value = String;
+        type Output = Option<String>;
+        type Error = SignalingError;
+
+        fn wait(&mut self) -> LocalBoxFuture<'_, Result<String, SignalingError>> {
+            async move {
+                let mut msg = format!(" \n{}", self.0.wait().await?);
+                while let Some(next) = self.0.wait().now_or_never() {
+                    msg += &next?;
+                }
+                msg += "\t\n";
+                Ok(msg)
+            }
+            .boxed_local()
+        }
+
+        fn then<'a>(
+            &'a mut self,
+            value: &'a mut String,
+        ) -> LocalBoxFuture<'a, Result<Option<String>, SignalingError>> {
+            self.0.then(value)
+        }
+    }
+    impl Signalling for Mangling {
+        fn send(&mut self, msg: String) -> LocalBoxFuture<'_, Result<(), SignalingError>> {
+            self.0.send(msg)
+        }
+    }
+
+    #[tokio::test]
+    async fn delimited_framing() {
+        let delimited = || ConnectOptions {
+            signalling_framing: SignallingFraming::Delimited,
+            ..loopback_options()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            delimited().connect_psk_with_signalling(Mangling(a), true),
+            delimited().connect_psk_with_signalling(Mangling(b), false),
+        );
+        close(a.unwrap(), b.unwrap()).await;
+
+        // A raw peer splits the frames it gets and frames in turn
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            delimited().connect_psk_with_signalling(a, true),
+            loopback_options().connect_psk_with_signalling(Mangling(b), false),
+        );
+        close(a.unwrap(), b.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn candidate_cache() {
+        let (a, b) = loopback(loopback_options(), loopback_options()).await;
+        let a_cache = a.candidate_cache().unwrap();
+        let b_cache = b.candidate_cache().unwrap();
+        assert!(!a_cache.local.is_empty());
+        assert_eq!(a_cache.local, b_cache.remote);
+        assert_eq!(a_cache.remote, b_cache.local);
+        close(a, b).await;
+
+        // Unreachable and malformed entries must not prevent connecting
+        let stale = CandidateCache {
+            local: vec!["not a candidate".to_string()],
+            remote: vec!["1 1 udp 2130706431 192.0.2.1 9 typ host".to_string()],
+        };
+        let (a, b) = loopback(
+            ConnectOptions {
+                candidate_cache: Some(stale.clone()),
+                ..loopback_options()
+            },
+            ConnectOptions {
+                candidate_cache: Some(stale),
+                ..loopback_options()
+            },
+        )
+        .await;
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn json_candidates() {
+        let json = ConnectOptions {
+            candidate_encoding: CandidateEncoding::Json,
+            ..loopback_options()
+        };
+        // Either side understands both
+        let (mut a, mut b) = loopback(json, loopback_options()).await;
+        a.send(b"data").await.unwrap();
+        assert_eq!(recv(&mut b).await, b"data");
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn sdp_signalling_format() {
+        let options = || ConnectOptions {
+            signalling_format: SignallingFormat::Sdp,
+            ..loopback_options()
+        };
+        let (mut a, mut b) = loopback(options(), options()).await;
+
+        a.send(b"over sdp").await.unwrap();
+        let received = loop {
+            let mut value = b.wait().await.unwrap();
+            if let Some(data) = b.then(&mut value).await.unwrap() {
+                break data;
+            }
+        };
+        assert_eq!(received, b"over sdp");
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn diagnostics() {
+        let diagnostics = Diagnostics::new();
+        let (a, b) = loopback(
+            ConnectOptions {
+                diagnostics: diagnostics.clone(),
+                ..loopback_options()
+            },
+            loopback_options(),
+        )
+        .await;
+        close(a, b).await;
+
+        let session = diagnostics.session().unwrap();
+        assert_eq!(session.role, Some("dialer"));
+        assert!(!session.local_candidates.is_empty());
+        assert!(session.local_candidates.iter().any(|c| c.kind == "host"));
+        assert!(!session.remote_candidates.is_empty());
+        assert!(session.selected_pair.is_some());
+        assert!(session.states.iter().any(|s| s.state == "Connected"));
+        let phases: Vec<_> = session.phases.iter().map(|p| p.name).collect();
+        assert_eq!(
+            phases,
+            [
+                "agreement",
+                "ice handshake",
+                "ice",
+                "sctp",
+                "key confirmation"
+            ]
+        );
+        assert!(session.phases.iter().all(|p| p.error.is_none()));
+
+        let mut json = vec![];
+        diagnostics.write_json(&mut json).unwrap();
+        assert!(String::from_utf8(json)
+            .unwrap()
+            .contains("\"selected_pair\""));
+        assert!(Diagnostics::default().session().is_none());
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let events = Events::new();
+        let mut rx = events.subscribe();
+        let (a, b) = loopback(
+            ConnectOptions {
+                events: events.clone(),
+                ..loopback_options()
+            },
+            loopback_options(),
+        )
+        .await;
+
+        // Notified by the agent on a task of its own
+        let selected = timeout(Duration::from_secs(5), async {
+            loop {
+                if let ConnectionEvent::PairSelected { local, .. } = rx.recv().await.unwrap() {
+                    break local;
+                }
+            }
+        });
+        assert!(selected.await.unwrap().contains(" typ host"));
+
+        let mut later = a.events();
+        events.emit(ConnectionEvent::NonceDesync {
+            expected: 1,
+            received: 0,
+        });
+        assert!(matches!(
+            later.try_recv(),
+            Ok(ConnectionEvent::NonceDesync { .. })
+        ));
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn diagnostics_report() {
+        let recorded = ConnectOptions {
+            diagnostics: Diagnostics::new(),
+            ..loopback_options()
+        };
+        let (mut a, mut b) = loopback(loopback_options(), recorded).await;
+        a.send(b"report").await.unwrap();
+        loop {
+            let mut value = b.wait().await.unwrap();
+            if b.then(&mut value).await.unwrap().is_some() {
+                break;
+            }
+        }
+
+        let report = b.diagnostics();
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(report.cipher, "chacha20-poly1305");
+        assert_eq!(report.session.role, Some("listener"));
+        assert_eq!(report.session.signalling_format, Some("native"));
+        assert_eq!(report.session.peer_role_negotiation, Some(true));
+        assert!(report.session.warnings.is_empty());
+        assert!(report.sctp.stats.bytes_received > 0);
+        let mut json = vec![];
+        report.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("<redacted>"));
+        assert!(!json.contains("127.0.0.1"));
+
+        let full = b.diagnostics_full();
+        assert!(!full.session.local_candidates.is_empty());
+        assert!(full
+            .session
+            .local_candidates
+            .iter()
+            .all(|c| !c.candidate.contains("<redacted>")));
+
+        // Only the timings unless recording was asked for
+        let session = a.diagnostics().session;
+        assert!(session.role.is_none() && session.local_candidates.is_empty());
+        assert!(session.timings.total.is_some());
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn unsupported_signaling_scheme() {
+        let r = connect("channel", Some("http://localhost/"), &[]).await;
+        assert!(matches!(
+            r,
+            Err(ConnectError::UnsupportedSignalingScheme(scheme)) if scheme == "http"
+        ));
+    }
+
+    #[tokio::test]
+    async fn same_role_reassigned() {
+        let options = || ConnectOptions {
+            diagnostics: Diagnostics::new(),
+            ..loopback_options()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            options().connect_psk_with_signalling(a, true),
+            options().connect_psk_with_signalling(b, true),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        a.send(b"same role").await.unwrap();
+        let received = loop {
+            let mut value = b.wait().await.unwrap();
+            if let Some(data) = b.then(&mut value).await.unwrap() {
+                break data;
+            }
+        };
+        assert_eq!(received, b"same role");
+        let warnings = [a.diagnostics(), b.diagnostics()].map(|r| r.session.warnings.len());
+        assert_eq!(warnings.iter().sum::<usize>(), 1);
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn sdp_double_dialer() {
+        let options = |strict_roles| ConnectOptions {
+            signalling_format: SignallingFormat::Sdp,
+            strict_roles,
+            diagnostics: Diagnostics::new(),
+            ..loopback_options()
+        };
+
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            options(false).connect_psk_with_signalling(a, true),
+            options(false).connect_psk_with_signalling(b, true),
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        let roles = [&a, &b].map(|c| c.diagnostics().session.role.unwrap());
+        assert!(roles.contains(&"dialer") && roles.contains(&"listener"));
+        close(a, b).await;
+
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            options(true).connect_psk_with_signalling(a, true),
+            options(true).connect_psk_with_signalling(b, true),
+        );
+        for r in [a, b] {
+            assert!(matches!(r, Err(ConnectError::RoleConflict("dialer"))));
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_roles() {
+        let options = || ConnectOptions {
+            strict_roles: true,
+            ..loopback_options()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            options().connect_psk_with_signalling(a, false),
+            options().connect_psk_with_signalling(b, false),
+        );
+
+        for r in [a, b] {
+            assert!(matches!(r, Err(ConnectError::RoleConflict("listener"))));
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_deadline() {
+        // The peer never shows up
+        let (a, _b) = MemorySignalling::pair();
+        let options = ConnectOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..loopback_options()
+        };
+
+        let r = options.clone().connect_psk_with_signalling(a, true).await;
+        assert!(matches!(r, Err(ConnectError::Timeout(_))));
+
+        // Reused, counted again from the start of the connect
+        let (a, _b) = MemorySignalling::pair();
+        let started = Instant::now();
+        let r = options.connect_psk_with_signalling(a, true).await;
+        assert!(matches!(r, Err(ConnectError::Timeout(_))));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    async fn drain(stream: &mut Connection) {
+        while !stream.rx_closed() {
+            let mut value = stream.wait().await.unwrap();
+            stream.then(&mut value).await.unwrap();
+        }
+    }
+
+    async fn recv(stream: &mut Connection) -> Vec<u8> {
+        loop {
+            let mut value = stream.wait().await.unwrap();
+            if let Some(data) = stream.then(&mut value).await.unwrap() {
+                return data;
+            }
+        }
+    }
+
+    /// Every message until the peer closes.
+    async fn recv_half(rx: &mut ConnectionReadHalf) -> Vec<Vec<u8>> {
+        let mut received = vec![];
+        while !rx.rx_closed() {
+            let mut value = rx.wait().await.unwrap();
+            received.extend(rx.then(&mut value).await.unwrap());
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn split_halves() {
+        const MESSAGES: u8 = 32;
+        let (a, b) = loopback(loopback_options(), loopback_options()).await;
+
+        LocalSet::new()
+            .run_until(async {
+                // Each half on a task of its own, both directions at once
+                let (a, b) = (a.split(), b.split());
+                let tasks = [a, b].map(|(mut rx, mut tx)| {
+                    let rx = tokio::task::spawn_local(async move {
+                        let received = recv_half(&mut rx).await;
+                        (rx, received)
+                    });
+                    let tx = tokio::task::spawn_local(async move {
+                        for i in 0..MESSAGES {
+                            tx.send(&[i; 1000]).await.unwrap();
+                        }
+                        tx.close().await.unwrap();
+                    });
+                    (rx, tx)
+                });
+
+                for (rx, tx) in tasks {
+                    tx.await.unwrap();
+                    let (rx, received) = rx.await.unwrap();
+                    let expected: Vec<_> = (0..MESSAGES).map(|i| vec![i; 1000]).collect();
+                    assert_eq!(received, expected);
+                    assert!(rx.rx_closed());
+                }
+            })
+            .await;
+    }
+
+    #[test]
+    fn latency_profiles() {
+        let options = ConnectOptions {
+            sctp: SctpConfig {
+                close_linger: Duration::ZERO,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let interactive = options.with_latency_profile(LatencyProfile::Interactive);
+        assert_eq!(interactive.sctp.close_linger, Duration::ZERO);
+
+        let buffered = |profile: LatencyProfile| profile.sctp_config().max_buffered_amount;
+        assert!(interactive.sctp.max_buffered_amount < buffered(LatencyProfile::Balanced));
+        assert!(buffered(LatencyProfile::Balanced) < buffered(LatencyProfile::Throughput));
+    }
+
+    #[tokio::test]
+    async fn interactive_round_trip() {
+        let options = || {
+            let mut options = loopback_options();
+            options.sctp.close_linger = Duration::from_millis(250);
+            options.with_latency_profile(LatencyProfile::Interactive)
+        };
+        let sctp = options().sctp;
+        assert_eq!(sctp.max_buffered_amount, 64 * 1024);
+        assert_eq!(sctp.backpressure_poll, Duration::from_millis(5));
+        assert_eq!(sctp.writable_threshold, 16 * 1024);
+        // Only the buffering is replaced
+        assert_eq!(sctp.close_linger, Duration::from_millis(250));
+
+        let (mut a, mut b) = loopback(options(), options()).await;
+        for i in 0..50u8 {
+            a.send(&[i]).await.unwrap();
+            let echo = recv(&mut b).await;
+            b.send(&echo).await.unwrap();
+            assert_eq!(recv(&mut a).await, [i]);
+        }
+
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn close_reason_clean() {
+        let (mut a, mut b) = loopback(loopback_options(), loopback_options()).await;
+        assert!(b.close_reason().is_none());
+
+        a.close().await.unwrap();
+        drain(&mut b).await;
+        assert!(matches!(b.close_reason(), Some(CloseReason::CleanFin)));
+        assert!(matches!(a.close_reason(), Some(CloseReason::LocalClose)));
+        // Only the stream ended, ICE is still up
+        assert!(!b.sctp().association_closed());
+        b.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_reason_transport_failed() {
+        let (a, mut b) = loopback(loopback_options(), loopback_options()).await;
+
+        // Gone without closing, the ICE connection times out
+        drop(a);
+        drain(&mut b).await;
+        assert!(b.sctp().association_closed());
+        assert!(matches!(
+            b.close_reason(),
+            Some(CloseReason::TransportFailed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn connection_info() {
+        let (a, mut b) = loopback(loopback_options(), loopback_options()).await;
+
+        assert!(a.is_dialer());
+        assert!(!b.is_dialer());
+        assert_eq!(
+            a.channel_id(),
+            PskAuthentication::derive_text("loopback", "channel")
+        );
+        assert_eq!(a.channel_id(), b.channel_id());
+        assert_eq!(a.transport_kind(), TransportKind::Chacha20);
+
+        // Halves and wrappers forward the same record
+        let info = a.info().clone();
+        let (rx, tx) = a.split();
+        assert!(Arc::ptr_eq(&rx.connection_info().unwrap(), &info));
+        assert!(Arc::ptr_eq(&tx.connection_info().unwrap(), &info));
+        let mut a = QueuedStream::from_halves(rx, tx, Default::default());
+        assert!(Arc::ptr_eq(&a.connection_info().unwrap(), &info));
+
+        let (a, b) = tokio::join!(a.close(), b.close());
+        a.unwrap();
+        b.unwrap();
+    }
+
+    #[tokio::test]
+    async fn agreement_payload() {
+        let with_payload = |payload: &[u8]| ConnectOptions {
+            agreement_payload: Some(payload.to_vec()),
+            ..loopback_options()
+        };
+
+        let (a, b) = loopback(with_payload(b"dialer"), with_payload(b"listener")).await;
+        assert_eq!(a.peer_agreement_payload(), Some(&b"listener"[..]));
+        assert_eq!(b.peer_agreement_payload(), Some(&b"dialer"[..]));
+        close(a, b).await;
+
+        // Optional on either side
+        let (a, b) = loopback(with_payload(b"display name"), loopback_options()).await;
+        assert_eq!(a.peer_agreement_payload(), None);
+        assert_eq!(b.peer_agreement_payload(), Some(&b"display name"[..]));
+        close(a, b).await;
+    }
+
+    async fn udp_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        (a, b)
+    }
+
+    /// Drops every packet once severed, like a path that died.
+    struct Severable {
+        socket: UdpSocket,
+        severed: Arc<AtomicBool>,
+    }
+    #[async_trait::async_trait]
+    impl Conn for Severable {
+        async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+            Ok(self.socket.connect(addr).await?)
+        }
+
+        async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+            Ok(self.recv_from(buf).await?.0)
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+            loop {
+                let r = self.socket.recv_from(buf).await?;
+                if !self.severed.load(Ordering::Relaxed) {
+                    return Ok(r);
+                }
+            }
+        }
+
+        async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+            match self.severed.load(Ordering::Relaxed) {
+                true => Ok(buf.len()),
+                false => Ok(self.socket.send(buf).await?),
+            }
+        }
+
+        async fn send_to(&self, buf: &[u8], _: SocketAddr) -> webrtc_util::Result<usize> {
+            self.send(buf).await
+        }
+
+        fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+            Ok(self.socket.local_addr()?)
+        }
+
+        fn remote_addr(&self) -> Option<SocketAddr> {
+            self.socket.peer_addr().ok()
+        }
+
+        async fn close(&self) -> webrtc_util::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn largest_message() {
+        let (a, b) = udp_pair().await;
+        let (a, b) = tokio::join!(
+            Sctp::over(Arc::new(a), true, SctpConfig::default()),
+            Sctp::over(Arc::new(b), false, SctpConfig::default()),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        let largest: Vec<u8> = (0..MAX_MESSAGE_SIZE).map(|i| i as u8).collect();
+        a.send(&largest).await.unwrap();
+        a.send(b"next").await.unwrap();
+        assert!(a.send(&[0; MAX_MESSAGE_SIZE + 1]).await.is_err());
+        for expected in [largest.as_slice(), b"next"] {
+            let data = loop {
+                let mut value = b.wait().await.unwrap();
+                if let Some(data) = b.then(&mut value).await.unwrap() {
+                    break data;
+                }
+            };
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_over_udp() {
+        let (a, b) = udp_pair().await;
+
+        let basekey = b"shared out of band";
+        let (a, b) = tokio::join!(
+            ConnectOptions::default().connect_over(Arc::new(a), true, basekey),
+            ConnectOptions::default().connect_over(Arc::new(b), false, basekey),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert!(a.is_dialer());
+        assert_eq!(a.transport_kind(), TransportKind::Chacha20);
+
+        a.send(b"over udp").await.unwrap();
+        assert_eq!(recv(&mut b).await, b"over udp");
+        b.send(b"and back").await.unwrap();
+        assert_eq!(recv(&mut a).await, b"and back");
+
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn connect_over_session_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = || ConnectOptions {
+            state_dir: Some(dir.path().to_owned()),
+            ..Default::default()
+        };
+        let basekey = b"shared out of band";
+        for _ in 0..2 {
+            let (a, b) = udp_pair().await;
+            let (a, b) = tokio::join!(
+                options().connect_over(Arc::new(a), true, basekey),
+                options().connect_over(Arc::new(b), false, basekey),
+            );
+            let (mut a, mut b) = (a.unwrap(), b.unwrap());
+            a.send(b"data").await.unwrap();
+            assert_eq!(recv(&mut b).await, b"data");
+            close(a, b).await;
+        }
+
+        // State is recorded per session key, one file for each role of each
+        // session, a reused key would have been refused
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 4);
+    }
+
+    #[tokio::test]
+    async fn close_watchdog() {
+        let (a, b) = udp_pair().await;
+        let severed = Arc::new(AtomicBool::new(false));
+        let a = Severable {
+            socket: a,
+            severed: severed.clone(),
+        };
+        let options = ConnectOptions {
+            sctp: SctpConfig {
+                close_timeout: Some(Duration::from_secs(1)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let basekey = b"shared out of band";
+        let (a, b) = tokio::join!(
+            options.connect_over(Arc::new(a), true, basekey),
+            ConnectOptions::default().connect_over(Arc::new(b), false, basekey),
+        );
+        let (mut a, _b) = (a.unwrap(), b.unwrap());
+
+        severed.store(true, Ordering::Relaxed);
+        // Never acknowledged, so closing would wait on it
+        for _ in 0..10 {
+            a.send(&[0; 4000]).await.unwrap();
+        }
+        let started = Instant::now();
+        let r = a.close().await;
+        let elapsed = started.elapsed();
+
+        assert!(matches!(r, Err(StreamError::ClosedDirty(_))), "{r:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+        assert!(matches!(a.close_reason(), Some(CloseReason::LocalClose)));
+    }
+
+    /// Holds every packet sent for `delay_ms`, like a queue on the path.
+    struct Delayed {
+        socket: Arc<UdpSocket>,
+        delay_ms: Arc<AtomicU64>,
+    }
+    #[async_trait::async_trait]
+    impl Conn for Delayed {
+        async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+            Ok(self.socket.connect(addr).await?)
+        }
+
+        async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+            Ok(self.socket.recv(buf).await?)
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+            Ok(self.socket.recv_from(buf).await?)
+        }
+
+        async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+            let socket = self.socket.clone();
+            let delay = Duration::from_millis(self.delay_ms.load(Ordering::Relaxed));
+            let packet = buf.to_owned();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = socket.send(&packet).await;
+            });
+            Ok(buf.len())
+        }
+
+        async fn send_to(&self, buf: &[u8], _: SocketAddr) -> webrtc_util::Result<usize> {
+            self.send(buf).await
+        }
+
+        fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+            Ok(self.socket.local_addr()?)
+        }
+
+        fn remote_addr(&self) -> Option<SocketAddr> {
+            self.socket.peer_addr().ok()
+        }
+
+        async fn close(&self) -> webrtc_util::Result<()> {
+            Ok(())
+        }
+    }
 
-    fn from_str(url: &str) -> Result<Self, Self::Err> {
-        let mut fields = url.split('&');
-        let url = fields.next().unwrap_or_default();
-        let username = fields.next();
-        let password = fields.next();
-        let mut url = webrtc_ice::url::Url::parse_url(url)?;
-        url.username = username.unwrap_or_default().to_owned();
-        url.password = password.unwrap_or_default().to_owned();
+    #[tokio::test]
+    async fn one_way_delay() {
+        let (a, b) = udp_pair().await;
+        let (outbound, inbound) = (Arc::new(AtomicU64::new(10)), Arc::new(AtomicU64::new(10)));
+        let a = Delayed {
+            socket: Arc::new(a),
+            delay_ms: outbound.clone(),
+        };
+        let b = Delayed {
+            socket: Arc::new(b),
+            delay_ms: inbound.clone(),
+        };
+        let options = ConnectOptions {
+            sctp: SctpConfig {
+                delay_probe_interval: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
 
-        Ok(ParseUrl(url))
+        let basekey = b"shared out of band";
+        let (a, b) = tokio::join!(
+            options.connect_over(Arc::new(a), true, basekey),
+            ConnectOptions::default().connect_over(Arc::new(b), false, basekey),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        // Probes are sent and answered while both ends wait
+        let second = Duration::from_secs(1);
+        let r = timeout(second, async { tokio::join!(drain(&mut a), drain(&mut b)) }).await;
+        r.unwrap_err();
+        outbound.store(70, Ordering::Relaxed);
+        let r = timeout(second, async { tokio::join!(drain(&mut a), drain(&mut b)) }).await;
+        r.unwrap_err();
+
+        let delay = a.stats().one_way_delay.unwrap();
+        assert!(delay.samples >= 10, "{delay:?}");
+        let (outbound, inbound) = (delay.outbound, delay.inbound);
+        assert!(
+            (60.0..90.0).contains(&outbound.owd_estimate_ms),
+            "{delay:?}"
+        );
+        assert!((50.0..80.0).contains(&outbound.queuing_ms), "{delay:?}");
+        assert!(
+            outbound.low_ms <= 70.0 && 70.0 <= outbound.high_ms,
+            "{delay:?}"
+        );
+        assert!(inbound.owd_estimate_ms < 25.0, "{delay:?}");
+        assert!(inbound.queuing_ms < 15.0, "{delay:?}");
+        assert_eq!(b.stats().one_way_delay, None);
+    }
+
+    #[tokio::test]
+    async fn probe_mtu() {
+        let (mut a, mut b) = loopback(loopback_options(), loopback_options()).await;
+        assert_eq!(a.stats().path_mtu, None);
+
+        // Probes are answered below the stream, without waiting on it
+        let mtu = a.probe_mtu().await;
+        assert_eq!(mtu.max_payload, Some(8192));
+        assert_eq!(a.stats().path_mtu, Some(mtu));
+        assert_eq!(b.stats().path_mtu, None);
+
+        a.send(b"after probing").await.unwrap();
+        assert_eq!(recv(&mut b).await, b"after probing");
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn cancelled_waits() {
+        let (mut a, mut b) = loopback(loopback_options(), loopback_options()).await;
+        let sent: Vec<Vec<u8>> = (0..200u32).map(|i| i.to_be_bytes().to_vec()).collect();
+
+        let send = async {
+            for data in &sent {
+                a.send(data).await.unwrap();
+            }
+        };
+        let receive = async {
+            let mut received = vec![];
+            for polls in 0.. {
+                if received.len() == sent.len() {
+                    break;
+                }
+                let value = match polls % 3 {
+                    0 => b.wait().now_or_never(),
+                    1 => timeout(Duration::from_micros(100), b.wait()).await.ok(),
+                    _ => Some(b.wait().await),
+                };
+                if let Some(value) = value {
+                    if let Some(data) = b.then(&mut value.unwrap()).await.unwrap() {
+                        received.push(data);
+                    }
+                }
+            }
+            received
+        };
+        let ((), received) = tokio::join!(send, receive);
+        assert_eq!(received, sent);
+
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn admission() {
+        let hello = Hello {
+            token: Some("invite".to_string()),
+        };
+        let dialer_options = || ConnectOptions {
+            hello: Some(hello.clone()),
+            ..loopback_options()
+        };
+        let listener_options = |policy: Arc<dyn ConnectPolicy>| ConnectOptions {
+            policy: Some(policy),
+            ..loopback_options()
+        };
+
+        let rate_limit = Arc::new(RateLimitPolicy::new(RateLimit {
+            burst: 1,
+            per_second: 1,
+        }));
+        let (a, b) = loopback(dialer_options(), listener_options(rate_limit.clone())).await;
+        close(a, b).await;
+
+        let peer = PeerIdentity {
+            channel: "loopback".into(),
+            public_key: None,
+        };
+        while rate_limit.authorize(&peer, &hello).await == Decision::Accept {}
+        let (a, b) = MemorySignalling::pair();
+        let (dialer, listener) = tokio::join!(
+            dialer_options().connect_psk_with_signalling(a, true),
+            listener_options(rate_limit).connect_psk_with_signalling(b, false),
+        );
+        assert!(matches!(
+            dialer,
+            Err(ConnectError::AdmissionError(AdmissionError::Throttled(_)))
+        ));
+        assert!(listener.is_err());
+
+        // Peers using a pre-shared key have no key of their own
+        let allowlist = Arc::new(AllowlistPolicy {
+            keys: [vec![0; 32]].into(),
+        });
+        let (a, b) = MemorySignalling::pair();
+        let (dialer, listener) = tokio::join!(
+            dialer_options().connect_psk_with_signalling(a, true),
+            listener_options(allowlist).connect_psk_with_signalling(b, false),
+        );
+        assert!(matches!(
+            dialer,
+            Err(ConnectError::AdmissionError(AdmissionError::Rejected(_)))
+        ));
+        assert!(listener.is_err());
+    }
+
+    #[tokio::test]
+    async fn ready_barrier() {
+        let options = || ConnectOptions {
+            sctp: SctpConfig {
+                ready_barrier: true,
+                ..Default::default()
+            },
+            ..loopback_options()
+        };
+        let (mut a, mut b) = loopback(options(), options()).await;
+
+        a.send(b"early").await.unwrap();
+        assert_eq!(a.stats().buffered_amount, 0);
+        let r = timeout(Duration::from_millis(300), async {
+            tokio::join!(drain(&mut a), recv(&mut b))
+        });
+        r.await.unwrap_err();
+
+        b.ready().unwrap();
+        // Released once the dialer reads the ready
+        let received = select! {
+            data = recv(&mut b) => data,
+            _ = drain(&mut a) => unreachable!(),
+        };
+        assert_eq!(received, b"early");
+        close(a, b).await;
+
+        // A peer without the barrier is ready as soon as it connects
+        let (mut a, mut b) = loopback(options(), loopback_options()).await;
+        a.send(b"right away").await.unwrap();
+        let received = select! {
+            data = recv(&mut b) => data,
+            _ = drain(&mut a) => unreachable!(),
+        };
+        assert_eq!(received, b"right away");
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_request() {
+        let (mut a, mut b) = loopback(loopback_options(), loopback_options()).await;
+        let mut events = b.events();
+
+        // Queued on the asked side before it sees the request
+        for i in 0..10u8 {
+            b.send(&[i; 1000]).await.unwrap();
+        }
+        a.request_shutdown(Duration::from_secs(5)).await.unwrap();
+        assert!(!a.shutdown_request().unwrap().remote);
+
+        let (local, _app) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(local);
+        let mut local = AsyncPipeStream::new(read, write);
+        let mut received = vec![];
+        let (summary, ()) = tokio::join!(service::forward(&mut b, &mut local), async {
+            while !a.rx_closed() {
+                let mut value = a.wait().await.unwrap();
+                received.extend(a.then(&mut value).await.unwrap());
+            }
+        });
+        assert!(matches!(
+            summary.unwrap().a,
+            Some(CloseReason::RemoteRequested)
+        ));
+        assert!(matches!(
+            b.close_reason(),
+            Some(CloseReason::RemoteRequested)
+        ));
+        assert!(b.shutdown_request().unwrap().remote);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            ConnectionEvent::ShutdownRequested { deadline } if deadline == Duration::from_secs(5)
+        ));
+
+        // Everything queued arrives before the FIN
+        assert_eq!(
+            received,
+            (0..10u8).map(|i| vec![i; 1000]).collect::<Vec<_>>()
+        );
+        assert!(matches!(a.close_reason(), Some(CloseReason::CleanFin)));
+        a.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_request_ignored() {
+        let (mut a, mut b) = loopback(loopback_options(), loopback_options()).await;
+
+        let deadline = Duration::from_millis(300);
+        a.request_shutdown(deadline).await.unwrap();
+        let started = Instant::now();
+        let (local, _app) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(local);
+        let mut local = AsyncPipeStream::new(read, write);
+        let (summary, ()) = tokio::join!(service::forward(&mut a, &mut local), drain(&mut b));
+        summary.unwrap();
+        assert!(started.elapsed() >= deadline);
+        assert!(matches!(a.close_reason(), Some(CloseReason::LocalClose)));
+        assert!(matches!(b.close_reason(), Some(CloseReason::CleanFin)));
+        b.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_request_half_closes() {
+        let (mut a, mut b) = loopback(loopback_options(), loopback_options()).await;
+        b.send(b"in flight").await.unwrap();
+        let deadline = Duration::from_secs(30);
+        a.request_shutdown(deadline).await.unwrap();
+        let started = Instant::now();
+
+        // Written on the asking side after the request, never read
+        let (local_a, mut app_a) = tokio::io::duplex(1024);
+        app_a.write_all(b"late").await.unwrap();
+        let (read, write) = tokio::io::split(local_a);
+        let mut local_a = AsyncPipeStream::new(read, write);
+        let (local_b, mut app_b) = tokio::io::duplex(1024);
+        let (read, write) = tokio::io::split(local_b);
+        let mut local_b = AsyncPipeStream::new(read, write);
+        let (summary_a, summary_b) = tokio::join!(
+            service::forward(&mut a, &mut local_a),
+            service::forward(&mut b, &mut local_b),
+        );
+        assert!(matches!(summary_a.unwrap().a, Some(CloseReason::CleanFin)));
+        assert!(matches!(
+            summary_b.unwrap().a,
+            Some(CloseReason::RemoteRequested)
+        ));
+        // Without waiting for the deadline
+        assert!(started.elapsed() < deadline);
+
+        let mut received = vec![];
+        app_a.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"in flight");
+        received.clear();
+        app_b.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_shutdown_request() {
+        let (mut a, mut b) = loopback(loopback_options(), loopback_options()).await;
+        let sctp = match &mut a {
+            Connection::Chacha20(stream) => stream.underlying_mut(),
+            #[cfg(feature = "dtls")]
+            Connection::Dtls(_) => unreachable!(),
+        };
+        // What anyone on the path can inject under ChaCha20
+        sctp.request_shutdown(Duration::from_secs(5)).await.unwrap();
+        a.send(b"after").await.unwrap();
+
+        let received = select! {
+            data = recv(&mut b) => data,
+            _ = drain(&mut a) => unreachable!(),
+        };
+        assert_eq!(received, b"after");
+        assert_eq!(b.shutdown_request(), None);
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn wait_established() {
+        let (a, b) = loopback(loopback_options(), loopback_options()).await;
+        let established = b.wait_established();
+
+        timeout(Duration::from_secs(1), established)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            *b.connection_state().borrow(),
+            ConnectionState::Connected | ConnectionState::Completed
+        ));
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn pause_blocks_peer() {
+        let (mut a, mut b) = loopback(loopback_options(), loopback_options()).await;
+        let pause = b.pause_handle();
+        let received = Cell::new(0);
+        let expected = Cell::new(None);
+        b.pause();
+
+        let send = async {
+            let message = vec![0; 4000];
+            let mut sent = 0;
+            let flood = timeout(Duration::from_secs(3), async {
+                loop {
+                    a.send(&message).await.unwrap();
+                    sent += message.len();
+                }
+            });
+            assert!(flood.await.is_err(), "send never blocked");
+            assert_eq!(received.get(), 0);
+            assert!(sent < 32 * 1024 * 1024, "{sent} bytes sent while paused");
+
+            expected.set(Some(sent));
+            pause.resume();
+        };
+        let recv = async {
+            while expected
+                .get()
+                .is_none_or(|expected| received.get() < expected)
+            {
+                let mut value = b.wait().await.unwrap();
+                if let Some(data) = b.then(&mut value).await.unwrap() {
+                    received.set(received.get() + data.len());
+                }
+            }
+        };
+        tokio::join!(send, recv);
+
+        assert_eq!(Some(received.get()), expected.get());
+        close(a, b).await;
+    }
+
+    #[tokio::test]
+    async fn traffic_limit() {
+        let limited = ConnectOptions {
+            traffic_limit: Some(TrafficLimit::new(1000, 2000)),
+            ..loopback_options()
+        };
+        let recorded = ConnectOptions {
+            diagnostics: Diagnostics::new(),
+            ..loopback_options()
+        };
+        let (mut a, mut b) = loopback(limited, recorded).await;
+
+        for _ in 0..3 {
+            a.send(&[1; 600]).await.unwrap();
+        }
+        let r = a.send(&[1; 600]).await;
+        assert!(matches!(r, Err(StreamError::TrafficLimit(_))), "{r:?}");
+        assert!(matches!(a.close_reason(), Some(CloseReason::TrafficLimit)));
+        assert!(a.rx_closed());
+
+        let mut received = 0;
+        while !b.rx_closed() {
+            let mut value = b.wait().await.unwrap();
+            if let Some(data) = b.then(&mut value).await.unwrap() {
+                received += data.len();
+            }
+        }
+        assert_eq!(received, 1800);
+        assert!(matches!(b.close_reason(), Some(CloseReason::CleanFin)));
+        let warnings = b.diagnostics().session.warnings;
+        assert!(
+            warnings
+                .iter()
+                .any(|w| w.message.contains("soft traffic limit")),
+            "{warnings:?}"
+        );
+        assert!(
+            warnings
+                .iter()
+                .any(|w| w.message.contains("Traffic limit of 2000")),
+            "{warnings:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_deadline() {
+        let (mut a, b) = loopback(loopback_options(), loopback_options()).await;
+        a.set_deadline(Deadline::after(Duration::from_millis(100)));
+
+        let r = a.wait().await;
+        assert!(matches!(r, Err(StreamError::Timeout(_))));
+        let r = a.close().await;
+        assert!(matches!(r, Err(StreamError::Timeout(_))));
+
+        a.set_deadline(Deadline::NEVER);
+        close(a, b).await;
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn loopback_connect_spans() {
+        let capture = Capture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(capture.clone())
+            .with_ansi(false)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_max_level(tracing::Level::DEBUG)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (a, b) = loopback(loopback_options(), loopback_options()).await;
+        close(a, b).await;
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        for role in ["dialer", "listener"] {
+            let connection = format!("role=\"{role}\"}}");
+            for phase in ["agreement", "ice", "sctp"] {
+                let child = format!("{connection}:{phase}: ");
+                assert!(
+                    output.lines().any(|line| line.contains(&child)),
+                    "missing {child} in {output}"
+                );
+            }
+        }
+        assert!(output.contains("TX candidate"));
+        assert!(output.contains("Stream closed"));
     }
 }
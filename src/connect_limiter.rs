@@ -0,0 +1,165 @@
+//! Bounds how many connections are established at once, so a burst of
+//! incoming peers waits its turn instead of running dozens of key
+//! derivations and agreements side by side, see [`ConnectLimiter`].
+
+use crate::metrics;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Handshake slots shared by every connect holding a clone of it, e.g. the
+/// links of a [`ConnectionManager`](crate::manager::ConnectionManager)
+/// through its template. A slot is held from the key derivation until the
+/// connection is established or fails, not for the life of the connection.
+#[derive(Clone, Debug)]
+pub struct ConnectLimiter {
+    slots: Arc<Semaphore>,
+    max: usize,
+    queue_timeout: Option<Duration>,
+    queued: Arc<AtomicUsize>,
+}
+impl ConnectLimiter {
+    /// At most `max` handshakes at once, at least one. Those past it wait
+    /// in line without a limit, see [`ConnectLimiter::with_queue_timeout`].
+    pub fn new(max: usize) -> ConnectLimiter {
+        let max = max.max(1);
+        ConnectLimiter {
+            slots: Arc::new(Semaphore::new(max)),
+            max,
+            queue_timeout: None,
+            queued: Default::default(),
+        }
+    }
+
+    /// Handshakes waiting longer than `timeout` for a slot fail with
+    /// [`QueueTimeout`]. The wait also counts against the
+    /// [`deadline`](crate::connect::ConnectOptions::deadline).
+    pub fn with_queue_timeout(mut self, timeout: Duration) -> ConnectLimiter {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Handshakes holding a slot.
+    pub fn active(&self) -> usize {
+        self.max - self.slots.available_permits()
+    }
+
+    /// Handshakes waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Waits for a slot, released once the permit is dropped.
+    pub async fn acquire(&self) -> Result<ConnectPermit, QueueTimeout> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(ConnectPermit { _permit: permit });
+        }
+
+        let started = Instant::now();
+        let _queued = Queued::new(&self.queued);
+        let acquire = self.slots.clone().acquire_owned();
+        let permit = match self.queue_timeout {
+            None => acquire.await,
+            Some(timeout) => {
+                tokio::time::timeout(timeout, acquire)
+                    .await
+                    .map_err(|_| QueueTimeout {
+                        waited: timeout,
+                        slots: self.max,
+                    })?
+            }
+        };
+        metrics::connect_queue_wait(started.elapsed());
+
+        Ok(ConnectPermit {
+            _permit: permit.expect("The semaphore of a limiter is never closed"),
+        })
+    }
+}
+
+/// A handshake slot of a [`ConnectLimiter`].
+#[derive(Debug)]
+pub struct ConnectPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Keeps the queue depth up to date, also when the wait is cancelled.
+struct Queued<'a>(&'a AtomicUsize);
+impl Queued<'_> {
+    fn new(queued: &AtomicUsize) -> Queued<'_> {
+        let depth = queued.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics::connect_queue_depth(depth);
+        Queued(queued)
+    }
+}
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let depth = self.0.fetch_sub(1, Ordering::Relaxed) - 1;
+        metrics::connect_queue_depth(depth);
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Waited {waited:?} for one of the {slots} handshake slots")]
+pub struct QueueTimeout {
+    pub waited: Duration,
+    pub slots: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queue() {
+        let limiter = ConnectLimiter::new(2).with_queue_timeout(Duration::from_millis(50));
+        let a = limiter.acquire().await.unwrap();
+        let _b = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.active(), 2);
+
+        let e = limiter.acquire().await.unwrap_err();
+        assert_eq!(e.slots, 2);
+        assert_eq!(limiter.queued(), 0);
+
+        let waiting = limiter.clone();
+        let c = tokio::spawn(async move { waiting.acquire().await.map(|_| ()) });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(limiter.queued(), 1);
+        drop(a);
+        c.await.unwrap().unwrap();
+        assert_eq!(limiter.queued(), 0);
+        assert_eq!(limiter.active(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_come_first_served() {
+        let limiter = ConnectLimiter::new(2);
+        let served = std::cell::RefCell::new(Vec::new());
+        let start = tokio::time::Instant::now();
+        let handshakes = (0..6).map(|i| {
+            let (limiter, served) = (&limiter, &served);
+            async move {
+                let _permit = limiter.acquire().await.unwrap();
+                served.borrow_mut().push((i, start.elapsed()));
+                assert!(limiter.active() <= 2);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+        futures::future::join_all(handshakes).await;
+
+        // In order of arrival, two at a time
+        let round = |i: u64| Duration::from_millis(100 * (i / 2));
+        let expected = (0..6).map(|i| (i, round(i))).collect::<Vec<_>>();
+        assert_eq!(served.into_inner(), expected);
+        assert_eq!((limiter.active(), limiter.queued()), (0, 0));
+    }
+}
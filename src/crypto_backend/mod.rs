@@ -0,0 +1,30 @@
+//! Cryptographic primitives used by icepipe.
+//!
+//! The default implementation is built on top of `ring`. Enabling the
+//! `rustcrypto` feature (with default features disabled) swaps it for a pure
+//! Rust implementation based on the RustCrypto crates, which is easier to
+//! cross compile. Both backends produce bit-identical outputs, so peers using
+//! different backends can talk to each other.
+
+#[cfg(feature = "ring")]
+pub mod ring;
+#[cfg(feature = "rustcrypto")]
+pub mod rustcrypto;
+
+#[cfg(feature = "ring")]
+pub use self::ring::*;
+#[cfg(all(feature = "rustcrypto", not(feature = "ring")))]
+pub use self::rustcrypto::*;
+
+#[cfg(not(any(feature = "ring", feature = "rustcrypto")))]
+compile_error!("icepipe requires either the `ring` or the `rustcrypto` feature");
+
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Crypto error")]
+pub struct Unspecified;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,141 @@
+use super::{Unspecified, NONCE_LEN};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    agreement, digest, hkdf, hmac, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+    signature::{self, KeyPair, VerificationAlgorithm},
+};
+use std::num::NonZeroU32;
+
+impl From<ring::error::Unspecified> for Unspecified {
+    fn from(_: ring::error::Unspecified) -> Self {
+        Unspecified
+    }
+}
+
+pub fn fill_random(out: &mut [u8]) -> Result<(), Unspecified> {
+    Ok(SystemRandom::new().fill(out)?)
+}
+
+pub fn sha512(data: &[u8]) -> [u8; 64] {
+    digest::digest(&digest::SHA512, data)
+        .as_ref()
+        .try_into()
+        .unwrap()
+}
+
+pub fn pbkdf2_hmac_sha512(iterations: NonZeroU32, salt: &[u8], secret: &[u8], out: &mut [u8]) {
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA512, iterations, salt, secret, out);
+}
+
+pub fn hkdf_sha512(salt: &[u8], secret: &[u8], info: &[u8], out: &mut [u8]) {
+    struct Len(usize);
+    impl hkdf::KeyType for Len {
+        fn len(&self) -> usize {
+            self.0
+        }
+    }
+
+    let info = [info];
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA512, salt).extract(secret);
+    let okm = prk.expand(&info, Len(out.len())).unwrap();
+    okm.fill(out).unwrap();
+}
+
+pub fn hmac_sha512_sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA512, key);
+    hmac::sign(&key, data).as_ref().to_owned()
+}
+
+pub fn hmac_sha512_verify(key: &[u8], data: &[u8], tag: &[u8]) -> Result<(), Unspecified> {
+    let key = hmac::Key::new(hmac::HMAC_SHA512, key);
+    Ok(hmac::verify(&key, data, tag)?)
+}
+
+pub struct Ed25519KeyPair(signature::Ed25519KeyPair);
+impl Ed25519KeyPair {
+    pub fn from_seed(seed: &[u8]) -> Result<Self, Unspecified> {
+        let key_pair =
+            signature::Ed25519KeyPair::from_seed_unchecked(seed).map_err(|_| Unspecified)?;
+
+        Ok(Ed25519KeyPair(key_pair))
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.0.public_key().as_ref()
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.0.sign(data).as_ref().to_owned()
+    }
+}
+
+pub fn ed25519_verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<(), Unspecified> {
+    Ok(signature::ED25519.verify(public_key.into(), data.into(), signature.into())?)
+}
+
+pub struct X25519EphemeralKey {
+    private_key: agreement::EphemeralPrivateKey,
+    public_key: Vec<u8>,
+}
+impl X25519EphemeralKey {
+    pub fn generate() -> Result<Self, Unspecified> {
+        let private_key =
+            agreement::EphemeralPrivateKey::generate(&agreement::X25519, &SystemRandom::new())?;
+        let public_key = private_key.compute_public_key()?.as_ref().to_owned();
+
+        Ok(X25519EphemeralKey {
+            private_key,
+            public_key,
+        })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn agree(self, peer_public_key: &[u8]) -> Result<Vec<u8>, Unspecified> {
+        let peer_public_key =
+            agreement::UnparsedPublicKey::new(&agreement::X25519, peer_public_key);
+
+        Ok(agreement::agree_ephemeral(
+            self.private_key,
+            &peer_public_key,
+            ring::error::Unspecified,
+            |key_material| Ok(key_material.to_owned()),
+        )?)
+    }
+}
+
+pub struct Chacha20Poly1305Key(LessSafeKey);
+impl Chacha20Poly1305Key {
+    pub const KEY_LEN: usize = 32;
+
+    pub fn new(key: &[u8]) -> Result<Self, Unspecified> {
+        Ok(Chacha20Poly1305Key(LessSafeKey::new(UnboundKey::new(
+            &CHACHA20_POLY1305,
+            key,
+        )?)))
+    }
+
+    pub fn seal_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        data: &mut Vec<u8>,
+    ) -> Result<(), Unspecified> {
+        let nonce = Nonce::assume_unique_for_key(nonce);
+        Ok(self.0.seal_in_place_append_tag(nonce, Aad::empty(), data)?)
+    }
+
+    pub fn open_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        data: &mut Vec<u8>,
+    ) -> Result<(), Unspecified> {
+        let nonce = Nonce::assume_unique_for_key(nonce);
+        let len = self.0.open_in_place(nonce, Aad::empty(), data)?.len();
+        data.truncate(len);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use super::{Unspecified, NONCE_LEN};
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use ed25519_dalek::{Signer, Verifier};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha512};
+use std::num::NonZeroU32;
+
+pub fn fill_random(out: &mut [u8]) -> Result<(), Unspecified> {
+    getrandom::getrandom(out).map_err(|_| Unspecified)
+}
+
+pub fn sha512(data: &[u8]) -> [u8; 64] {
+    Sha512::digest(data).into()
+}
+
+pub fn pbkdf2_hmac_sha512(iterations: NonZeroU32, salt: &[u8], secret: &[u8], out: &mut [u8]) {
+    pbkdf2::pbkdf2_hmac::<Sha512>(secret, salt, iterations.get(), out);
+}
+
+pub fn hkdf_sha512(salt: &[u8], secret: &[u8], info: &[u8], out: &mut [u8]) {
+    hkdf::Hkdf::<Sha512>::new(Some(salt), secret)
+        .expand(info, out)
+        .unwrap();
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> Hmac<Sha512> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac
+}
+
+pub fn hmac_sha512_sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac_sha512(key, data).finalize().into_bytes().to_vec()
+}
+
+pub fn hmac_sha512_verify(key: &[u8], data: &[u8], tag: &[u8]) -> Result<(), Unspecified> {
+    hmac_sha512(key, data)
+        .verify_slice(tag)
+        .map_err(|_| Unspecified)
+}
+
+pub struct Ed25519KeyPair(ed25519_dalek::SigningKey, ed25519_dalek::VerifyingKey);
+impl Ed25519KeyPair {
+    pub fn from_seed(seed: &[u8]) -> Result<Self, Unspecified> {
+        let seed: &ed25519_dalek::SecretKey = seed.try_into().map_err(|_| Unspecified)?;
+        let secret = ed25519_dalek::SigningKey::from_bytes(seed);
+        let public = secret.verifying_key();
+
+        Ok(Ed25519KeyPair(secret, public))
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.1.as_bytes()
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        self.0.sign(data).to_bytes().to_vec()
+    }
+}
+
+pub fn ed25519_verify(public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<(), Unspecified> {
+    let public_key: &[u8; 32] = public_key.try_into().map_err(|_| Unspecified)?;
+    let public_key =
+        ed25519_dalek::VerifyingKey::from_bytes(public_key).map_err(|_| Unspecified)?;
+    let signature = ed25519_dalek::Signature::try_from(signature).map_err(|_| Unspecified)?;
+
+    public_key.verify(data, &signature).map_err(|_| Unspecified)
+}
+
+pub struct X25519EphemeralKey {
+    private_key: x25519_dalek::StaticSecret,
+    public_key: x25519_dalek::PublicKey,
+}
+impl X25519EphemeralKey {
+    pub fn generate() -> Result<Self, Unspecified> {
+        let mut bytes = [0; 32];
+        fill_random(&mut bytes)?;
+        let private_key = x25519_dalek::StaticSecret::from(bytes);
+        let public_key = x25519_dalek::PublicKey::from(&private_key);
+
+        Ok(X25519EphemeralKey {
+            private_key,
+            public_key,
+        })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.public_key.as_bytes()
+    }
+
+    pub fn agree(self, peer_public_key: &[u8]) -> Result<Vec<u8>, Unspecified> {
+        let peer_public_key: [u8; 32] = peer_public_key.try_into().map_err(|_| Unspecified)?;
+        let shared = self
+            .private_key
+            .diffie_hellman(&x25519_dalek::PublicKey::from(peer_public_key));
+
+        // ring refuses low order points, which result in an all zero secret
+        match shared.as_bytes().iter().all(|b| *b == 0) {
+            true => Err(Unspecified),
+            false => Ok(shared.as_bytes().to_vec()),
+        }
+    }
+}
+
+pub struct Chacha20Poly1305Key(ChaCha20Poly1305);
+impl Chacha20Poly1305Key {
+    pub const KEY_LEN: usize = 32;
+
+    pub fn new(key: &[u8]) -> Result<Self, Unspecified> {
+        if key.len() != Self::KEY_LEN {
+            return Err(Unspecified);
+        }
+
+        Ok(Chacha20Poly1305Key(ChaCha20Poly1305::new(Key::from_slice(
+            key,
+        ))))
+    }
+
+    pub fn seal_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        data: &mut Vec<u8>,
+    ) -> Result<(), Unspecified> {
+        self.0
+            .encrypt_in_place(Nonce::from_slice(&nonce), &[], data)
+            .map_err(|_| Unspecified)
+    }
+
+    pub fn open_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        data: &mut Vec<u8>,
+    ) -> Result<(), Unspecified> {
+        self.0
+            .decrypt_in_place(Nonce::from_slice(&nonce), &[], data)
+            .map_err(|_| Unspecified)
+    }
+}
@@ -0,0 +1,134 @@
+//! Golden vectors shared by every backend, the expected values were produced
+//! by the `ring` backend which defines the wire format.
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+macro_rules! golden_tests {
+    ($backend:ident) => {
+        mod $backend {
+            use super::hex;
+            use crate::crypto_backend::$backend::*;
+            use std::num::NonZeroU32;
+
+            #[test]
+            fn pbkdf2_golden() {
+                let mut out = [0; 32];
+                pbkdf2_hmac_sha512(
+                    NonZeroU32::new(4096).unwrap(),
+                    b"channel",
+                    b"secret",
+                    &mut out,
+                );
+                assert_eq!(
+                    hex(&out),
+                    "b1f99cfbc74304d8e921cfe10d262ea30ca6e9da8fc00d915984b768348bf70f"
+                );
+            }
+
+            #[test]
+            fn hkdf_golden() {
+                let mut key = [0; 32];
+                hkdf_sha512(b"key", b"basekey", b"dialer", &mut key);
+                assert_eq!(
+                    hex(&key),
+                    "111613231e372bef4e9df18618f18aab749a2c32dcb26683f93dbfb4d3c4825c"
+                );
+
+                let mut seq = [0; 16];
+                hkdf_sha512(b"seq", b"basekey", b"listener", &mut seq);
+                assert_eq!(hex(&seq), "30756e27bf8cda4f9a802d07cf0ba5e1");
+            }
+
+            #[test]
+            fn hmac_golden() {
+                let tag = hmac_sha512_sign(b"key", b"data");
+                assert_eq!(
+                    hex(&tag),
+                    "3c5953a18f7303ec653ba170ae334fafa08e3846f2efe317b87efce82376253c\
+                     b52a8c31ddcde5a3a2eee183c2b34cb91f85e64ddbc325f7692b199473579c58"
+                );
+                hmac_sha512_verify(b"key", b"data", &tag).unwrap();
+                hmac_sha512_verify(b"key", b"other", &tag).unwrap_err();
+            }
+
+            #[test]
+            fn sha512_golden() {
+                assert_eq!(
+                    hex(&sha512(b"data")),
+                    "77c7ce9a5d86bb386d443bb96390faa120633158699c8844c30b13ab0bf92760\
+                     b7e4416aea397db91b4ac0e5dd56b8ef7e4b066162ab1fdc088319ce6defc876"
+                );
+            }
+
+            #[test]
+            fn ed25519_golden() {
+                let key_pair = Ed25519KeyPair::from_seed(&[7; 32]).unwrap();
+                assert_eq!(
+                    hex(key_pair.public_key()),
+                    "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c"
+                );
+
+                let signature = key_pair.sign(b"data");
+                assert_eq!(
+                    hex(&signature),
+                    "dc54ddc01725d8347dcbd7b2083265ce00923346a8692ec722092848896442b7\
+                     5381f4cae37ae7c5771127b5dda0343d07a53add59bc65febd5eb125619b3701"
+                );
+                ed25519_verify(key_pair.public_key(), b"data", &signature).unwrap();
+                ed25519_verify(key_pair.public_key(), b"other", &signature).unwrap_err();
+            }
+
+            #[test]
+            fn chacha20_poly1305_golden() {
+                let key = Chacha20Poly1305Key::new(&[9; 32]).unwrap();
+                let mut data = b"plaintext".to_vec();
+                key.seal_in_place([1; 12], &mut data).unwrap();
+                assert_eq!(
+                    hex(&data),
+                    "7e0bfd41415832fcc29dadf1ad43c9f7ed02d5933a5e092cfd"
+                );
+
+                let mut tampered = data.clone();
+                tampered[0] ^= 1;
+                key.open_in_place([1; 12], &mut tampered).unwrap_err();
+
+                key.open_in_place([1; 12], &mut data).unwrap();
+                assert_eq!(data, b"plaintext");
+            }
+
+            #[test]
+            fn x25519_agreement() {
+                let a = X25519EphemeralKey::generate().unwrap();
+                let b = X25519EphemeralKey::generate().unwrap();
+                let a_public = a.public_key().to_owned();
+                let b_public = b.public_key().to_owned();
+
+                assert_eq!(a.agree(&b_public).unwrap(), b.agree(&a_public).unwrap());
+            }
+
+            #[test]
+            fn x25519_rejects_low_order_point() {
+                let a = X25519EphemeralKey::generate().unwrap();
+                a.agree(&[0; 32]).unwrap_err();
+            }
+        }
+    };
+}
+
+#[cfg(feature = "ring")]
+golden_tests!(ring);
+#[cfg(feature = "rustcrypto")]
+golden_tests!(rustcrypto);
+
+#[cfg(all(feature = "ring", feature = "rustcrypto"))]
+#[test]
+fn x25519_across_backends() {
+    let a = super::ring::X25519EphemeralKey::generate().unwrap();
+    let b = super::rustcrypto::X25519EphemeralKey::generate().unwrap();
+    let a_public = a.public_key().to_owned();
+    let b_public = b.public_key().to_owned();
+
+    assert_eq!(a.agree(&b_public).unwrap(), b.agree(&a_public).unwrap());
+}
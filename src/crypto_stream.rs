@@ -1,119 +1,394 @@
 use crate::{
-    error::TimeoutError,
-    pipe_stream::{Control, PipeStream, StreamError, WaitThen},
-    signalling::SignalingError,
+    crypto_backend::{self, Chacha20Poly1305Key, Unspecified, NONCE_LEN},
+    diagnostics::Diagnostics,
+    error::{TimeoutError, TrafficLimitExceeded},
+    events::{ConnectionEvent, Events},
+    logging, metrics,
+    pipe_stream::{
+        CloseReason, ConnectionInfo, Control, PipeReadHalf, PipeStream, PipeWriteHalf,
+        ShutdownRequest, Split, StreamError, WaitThen,
+    },
+    replay_state::{ReplayState, ReplayStateError},
+    signalling::{role_name, SignalingError},
+    strictness::{Strictness, Violation},
+    trace,
+    traffic_limit::{Direction, TrafficLimit, TrafficMeter},
 };
 use futures::{
     future::{ready, LocalBoxFuture},
     FutureExt,
 };
-use ring::{
-    aead::{
-        Aad, BoundKey, Nonce, NonceSequence, OpeningKey, SealingKey, UnboundKey, CHACHA20_POLY1305,
-    },
-    error::Unspecified,
-    hkdf::{self, KeyType},
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use std::io;
+
+/// Sent by both sides right after the keys are derived, see
+/// [`Chacha20Stream::confirm_key`].
+const KEY_CONFIRMATION: &[u8] = b"icepipe key confirmation";
+
+/// Bytes of the big endian sequence number in front of every sealed message,
+/// unless [`Chacha20Stream::set_unnumbered`]. It picks the nonce, so a
+/// tampered one fails to open.
+const SEQ_LEN: usize = 8;
+
+/// Set in the sequence number in front of a sealed control frame. Those are
+/// sealed under nonces of their own, so the mark cannot be moved to data.
+const CONTROL: u64 = 1 << 63;
+
+/// Control frame ending the stream, see [`Chacha20Stream::finish`].
+const FINISH: u8 = 1;
+/// Control frame asking the peer to close, followed by the deadline in
+/// milliseconds, see [`ShutdownRequest`].
+const SHUTDOWN: u8 = 2;
 
 pub struct Sequential(u128);
-impl NonceSequence for Sequential {
-    fn advance(&mut self) -> Result<ring::aead::Nonce, ring::error::Unspecified> {
-        let seq = self.0.to_be_bytes();
-        let mut nonce = [0u8; 12];
-        nonce[..].copy_from_slice(&seq[4..16]);
+impl Sequential {
+    pub fn advance(&mut self) -> [u8; NONCE_LEN] {
+        let nonce = self.at(0);
 
         self.0 += 1;
 
-        Ok(Nonce::assume_unique_for_key(nonce))
+        nonce
+    }
+
+    /// Nonce `n` messages past the current one, without advancing.
+    pub fn at(&self, n: u64) -> [u8; NONCE_LEN] {
+        let seq = self.0.wrapping_add(n.into()).to_be_bytes();
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..].copy_from_slice(&seq[4..16]);
+
+        nonce
     }
+
+    /// Nonce of a control frame `n` messages past the current one, never
+    /// that of a message.
+    pub fn control_at(&self, n: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = self.at(n);
+        nonce[0] ^= 0x80;
+
+        nonce
+    }
+}
+
+pub struct SequentialKey {
+    key: Chacha20Poly1305Key,
+    seq: Sequential,
+    /// Sequence number of the next message.
+    next: u64,
+    /// Messages received in sequence, short of `next` once some were
+    /// skipped under [`DesyncPolicy::Skip`].
+    delivered: u64,
+    /// The peer resumed the session, its first message may skip ahead.
+    resumed: bool,
+    replay: Option<ReplayState>,
+    /// Nothing is sealed past the finish, see [`Chacha20Stream::finish`].
+    finished: bool,
+    /// Whether messages carry their sequence number, see
+    /// [`Chacha20Stream::set_unnumbered`].
+    numbered: bool,
 }
+impl SequentialKey {
+    /// Whether `seq` is the expected message, skipping ahead once past a
+    /// resumption. Replays of the earlier run do not count as that once.
+    fn expects(&mut self, seq: u64) -> bool {
+        if self.resumed && seq >= self.next {
+            self.resumed = false;
+            self.next = seq;
+            self.delivered = seq;
+        }
+        seq == self.next
+    }
+
+    /// Records the message as received.
+    fn advance(&mut self, seq: u64) {
+        self.next = seq + 1;
+        self.delivered += 1;
+        if let Some(replay) = &self.replay {
+            replay.received(self.next);
+        }
+    }
+
+    /// Reserves the next message in the replay state, before sealing it.
+    async fn reserve(&self) -> Chacha20Result<()> {
+        match &self.replay {
+            Some(replay) => Ok(replay.reserve(self.next).await?),
+            None => Ok(()),
+        }
+    }
+
+    /// Records what was received, right away once the stream ended and
+    /// every so often otherwise.
+    async fn checkpoint(&self, ended: bool) -> Chacha20Result<()> {
+        match (&self.replay, ended) {
+            (Some(replay), true) => Ok(replay.sync().await?),
+            (Some(replay), false) => Ok(replay.checkpoint().await?),
+            (None, _) => Ok(()),
+        }
+    }
 
-impl hkdf::KeyType for Sequential {
-    fn len(&self) -> usize {
-        16
+    async fn sync(&self) -> Chacha20Result<()> {
+        match &self.replay {
+            Some(replay) => Ok(replay.sync().await?),
+            None => Ok(()),
+        }
     }
 }
 
+/// How the peer ended the stream with [`Chacha20Stream::finish`], all of it
+/// authenticated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finish {
+    /// Sequence number of the finish, the one following the last message.
+    pub seq: u64,
+    pub reason: String,
+}
+
+/// What the receiving side does with a message whose sequence number is not
+/// the next one, meaning messages were dropped or repeated below the
+/// encryption layer, e.g. by a [`DropPolicy`](crate::queued_stream::DropPolicy)
+/// under it. Either way the message is reported as a nonce desync in the log,
+/// the [`Diagnostics`] and the metrics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DesyncPolicy {
+    /// Fails with [`Chacha20Error::NonceDesync`] and ends the stream, as
+    /// data was lost. Meant for reliable transports.
+    #[default]
+    Fail,
+    /// Resynchronizes past the gap and goes on, repeated messages are
+    /// discarded. Meant for transports allowed to lose messages.
+    Skip,
+}
+
 pub struct Chacha20Stream<S>
 where
     S: PipeStream,
     S::Error: Into<StreamError>,
 {
-    sealing_key: SealingKey<Sequential>,
-    opening_key: OpeningKey<Sequential>,
+    sealing_key: SequentialKey,
+    opening_key: SequentialKey,
     underlying: S,
+    role: &'static str,
+    fin: Option<CloseReason>,
+    peer_finish: Option<Finish>,
+    traffic: Option<TrafficMeter>,
+    desync: Desync,
+    shutdown: Shutdown,
 }
 impl<S> Chacha20Stream<S>
 where
     S: PipeStream,
     S::Error: Into<StreamError>,
 {
-    pub fn derive<L: KeyType>(
-        basekey: &[u8],
-        dialer: bool,
-        salt: &str,
-        key_type: L,
-        out: &mut [u8],
-    ) {
+    pub fn derive(basekey: &[u8], dialer: bool, salt: &str, out: &mut [u8]) {
         let info = match dialer {
             true => "dialer",
             false => "listener",
         };
-        let algorithm = hkdf::HKDF_SHA512;
-        let info = [info.as_bytes()];
-        let salt = hkdf::Salt::new(algorithm, salt.as_bytes());
-        let prk = salt.extract(basekey);
-        let okm = prk.expand(&info, key_type).unwrap();
-        okm.fill(out).unwrap();
+        crypto_backend::hkdf_sha512(salt.as_bytes(), basekey, info.as_bytes(), out);
     }
 
-    fn get_key(basekey: &[u8], dialer: bool) -> Chacha20Result<UnboundKey> {
-        let mut key_bytes = [0; 32];
-        Self::derive(basekey, dialer, "key", &CHACHA20_POLY1305, &mut key_bytes);
+    fn get_key(basekey: &[u8], dialer: bool) -> Chacha20Result<Chacha20Poly1305Key> {
+        let mut key_bytes = [0; Chacha20Poly1305Key::KEY_LEN];
+        Self::derive(basekey, dialer, "key", &mut key_bytes);
 
-        UnboundKey::new(&CHACHA20_POLY1305, &key_bytes).map_err(Chacha20Error::CryptoError)
+        Chacha20Poly1305Key::new(&key_bytes).map_err(Chacha20Error::CryptoError)
     }
 
     fn get_seq(basekey: &[u8], dialer: bool) -> Sequential {
         let mut u128_be = [0; 16];
-        Self::derive(basekey, dialer, "seq", Sequential(0), &mut u128_be);
+        Self::derive(basekey, dialer, "seq", &mut u128_be);
 
         Sequential(u128::from_be_bytes(u128_be))
     }
 
+    fn get_sequential_key(basekey: &[u8], dialer: bool) -> Chacha20Result<SequentialKey> {
+        Ok(SequentialKey {
+            key: Self::get_key(basekey, dialer)?,
+            seq: Self::get_seq(basekey, dialer),
+            next: 0,
+            delivered: 0,
+            resumed: false,
+            replay: None,
+            finished: false,
+            numbered: true,
+        })
+    }
+
+    pub fn underlying(&self) -> &S {
+        &self.underlying
+    }
+
+    pub fn underlying_mut(&mut self) -> &mut S {
+        &mut self.underlying
+    }
+
     pub fn new(basekey: &[u8], dialer: bool, underlying: S) -> Chacha20Result<Self> {
-        let sealing = Self::get_key(basekey, dialer)?;
-        let sealing_seq = Self::get_seq(basekey, dialer);
-        let opening = Self::get_key(basekey, !dialer)?;
-        let opening_seq = Self::get_seq(basekey, !dialer);
-        let sealing_key = BoundKey::new(sealing, sealing_seq);
-        let opening_key = BoundKey::new(opening, opening_seq);
+        let sealing_key = Self::get_sequential_key(basekey, dialer)?;
+        let opening_key = Self::get_sequential_key(basekey, !dialer)?;
 
         Ok(Chacha20Stream {
             sealing_key,
             opening_key,
             underlying,
+            role: role_name(dialer),
+            fin: None,
+            peer_finish: None,
+            traffic: None,
+            desync: Desync::default(),
+            shutdown: Shutdown::default(),
         })
     }
+
+    /// Records sequence numbers in `replay` and continues from those of a
+    /// resumed session, see [`crate::replay_state`]. Must be set before
+    /// anything is sent or received.
+    pub fn set_replay_state(&mut self, replay: ReplayState) {
+        let (sent, received, resumed) = replay.start();
+        self.sealing_key.next = sent;
+        self.opening_key.next = received;
+        self.opening_key.delivered = received;
+        self.opening_key.resumed = resumed;
+        self.sealing_key.replay = Some(replay.clone());
+        self.opening_key.replay = Some(replay);
+    }
+
+    /// Frames messages the way peers predating sequence numbers do, without
+    /// them, see
+    /// [`IceAgent::peer_numbers_messages`](crate::ice::IceAgent::peer_numbers_messages).
+    /// A lost message then fails every one after it to open, and
+    /// [`Chacha20Stream::finish`] and shutdown requests are refused, as
+    /// such peers cannot read control frames. Must be set before anything
+    /// is sent or received.
+    pub fn set_unnumbered(&mut self) {
+        self.sealing_key.numbered = false;
+        self.opening_key.numbered = false;
+    }
+
+    /// Applies to both halves once split.
+    pub fn set_desync_policy(&mut self, policy: DesyncPolicy) {
+        self.desync.policy = policy;
+    }
+
+    /// Where nonce desyncs are recorded.
+    pub fn set_diagnostics(&mut self, diagnostics: Diagnostics) {
+        self.desync.diagnostics = diagnostics;
+    }
+
+    /// Where nonce desyncs are emitted.
+    pub fn set_events(&mut self, events: Events) {
+        self.desync.events = events;
+    }
+
+    /// Under [`Strictness::Strict`] nonce desyncs fail whatever the
+    /// [`DesyncPolicy`].
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.desync.strictness = strictness;
+    }
+
+    /// Counts plaintext against `limit` from now on, shared by both halves
+    /// once split.
+    pub fn set_traffic_limit(&mut self, limit: Option<TrafficLimit>) {
+        self.traffic = limit.map(TrafficMeter::new);
+    }
+
+    /// Plaintext bytes counted against the traffic limit, if any.
+    pub fn traffic(&self) -> Option<u64> {
+        self.traffic.as_ref().map(TrafficMeter::bytes)
+    }
+
+    async fn flush_notice(&mut self) -> Chacha20Result<()> {
+        if let Some(notice) = self.traffic.as_ref().and_then(TrafficMeter::take_notice) {
+            self.underlying.notify(&notice).await.map_err(Into::into)?;
+        }
+
+        Ok(())
+    }
+
+    /// Tells the peer why and closes.
+    async fn exceeded(&mut self, e: TrafficLimitExceeded) -> Chacha20Error {
+        let _ = self.underlying.notify(&exceeded_notice(e)).await;
+        let _ = self.close().await;
+
+        e.into()
+    }
+
+    /// Ends the stream with an authenticated frame carrying its final
+    /// sequence number and `reason`, which the peer checks and reads back
+    /// with [`Chacha20Stream::peer_finish`]. Unlike [`Control::close`] the
+    /// underlying stream is left open, closing it afterwards sends no FIN.
+    /// Peers predating it fail to open the frame, only finish towards peers
+    /// known to read it. [`Connection`](crate::connect::Connection) does not
+    /// use it, its close sends the plain authenticated FIN, this is for
+    /// callers driving the stream themselves.
+    pub async fn finish(&mut self, reason: &str) -> Chacha20Result<()> {
+        self.sealing_key.reserve().await?;
+        let frame = seal_finish(&mut self.sealing_key, reason)?;
+        self.underlying.send(&frame).await.map_err(Into::into)?;
+        self.sealing_key.sync().await
+    }
+
+    /// How the peer finished the stream, `None` if it did not or closed it
+    /// with a plain FIN.
+    pub fn peer_finish(&self) -> Option<&Finish> {
+        self.peer_finish.as_ref()
+    }
+
+    /// Exchanges an encrypted token with the peer, so keys that diverged,
+    /// e.g. from different versions deriving them differently, fail here
+    /// instead of on the first message. Only once both peers announced it,
+    /// peers predating it take the token for data and never send theirs.
+    pub async fn confirm_key(&mut self) -> Chacha20Result<()> {
+        self.send(KEY_CONFIRMATION).await?;
+
+        let token = loop {
+            if self.underlying.rx_closed() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let mut value = self.underlying.wait().await.map_err(Into::into)?;
+            let token: Option<Vec<u8>> =
+                self.underlying.then(&mut value).await.map_err(Into::into)?;
+            if let Some(token) = token {
+                break token;
+            }
+        };
+        match open(&self.opening_key, token, self.role) {
+            Ok((seq, token, false))
+                if self.opening_key.expects(seq) && token == KEY_CONFIRMATION =>
+            {
+                self.opening_key.advance(seq);
+                trace::debug!(target: logging::CRYPTO, "Key confirmed");
+                Ok(())
+            }
+            Ok(_) | Err(Chacha20Error::CryptoError(_)) => Err(Chacha20Error::KeyConfirmationFailed),
+            Err(e) => Err(e),
+        }
+    }
 }
 impl<S> PipeStream for Chacha20Stream<S>
 where
     S: PipeStream,
     S::Error: Into<StreamError>,
 {
+    /// Empty messages are not sent, as they would read as the FIN.
     fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, Chacha20Result<()>> {
-        let mut data = data.to_owned();
-
-        if let Err(e) = self
-            .sealing_key
-            .seal_in_place_append_tag(Aad::empty(), &mut data)
-            .map_err(Chacha20Error::CryptoError)
-        {
-            return Box::pin(ready(Err(e)));
+        if data.is_empty() {
+            return ready(Ok(())).boxed_local();
         }
 
-        async move { Ok(self.underlying.send(&data).await.map_err(Into::into)?) }.boxed_local()
+        async move {
+            if let Err(e) = count(&self.traffic, Direction::Sent, data.len()) {
+                return Err(self.exceeded(e).await);
+            }
+            self.sealing_key.reserve().await?;
+            // Sealed and handed over in the same poll, so a dropped send
+            // leaves no gap in the nonces, see [`WaitThen`]
+            let data = seal(&mut self.sealing_key, data)?;
+            self.underlying.send(&data).await.map_err(Into::into)?;
+            self.flush_notice().await
+        }
+        .boxed_local()
     }
 }
 impl<S> WaitThen for Chacha20Stream<S>
@@ -134,19 +409,25 @@ where
         value: &'a mut Self::Value,
     ) -> LocalBoxFuture<'a, Chacha20Result<Self::Output>> {
         Box::pin(async move {
-            let mut data: Option<Vec<u8>> =
-                self.underlying.then(value).await.map_err(Into::into)?;
-            let r = match data.as_mut() {
-                Some(data) => Some(
-                    self.opening_key
-                        .open_in_place(Aad::empty(), data)
-                        .map_err(Chacha20Error::CryptoError)?
-                        .to_owned(),
-                ),
-                None => None,
-            };
+            let data: Option<Vec<u8>> = self.underlying.then(value).await.map_err(Into::into)?;
+            let reason = self.underlying.close_reason();
+            let data = receive(
+                &mut self.opening_key,
+                (&mut self.fin, &mut self.peer_finish),
+                reason,
+                data,
+                self.role,
+                &self.desync,
+                &self.shutdown,
+            )?;
+            self.opening_key.checkpoint(self.fin.is_some()).await?;
+            let len = data.as_ref().map_or(0, Vec::len);
+            if let Err(e) = count(&self.traffic, Direction::Received, len) {
+                return Err(self.exceeded(e).await);
+            }
+            self.flush_notice().await?;
 
-            Ok(r)
+            Ok(data)
         })
     }
 }
@@ -155,12 +436,599 @@ where
     S: PipeStream,
     S::Error: Into<StreamError>,
 {
+    /// Sends the FIN before closing the underlying stream.
     fn close(&mut self) -> LocalBoxFuture<'_, Chacha20Result<()>> {
-        async move { Ok(self.underlying.close().await.map_err(Into::into)?) }.boxed_local()
+        async move {
+            if !self.sealing_key.finished {
+                self.sealing_key.reserve().await?;
+                let fin = seal(&mut self.sealing_key, &[])?;
+                trace::debug!(target: logging::CRYPTO, "TX FIN");
+                self.underlying.send(&fin).await.map_err(Into::into)?;
+                self.sealing_key.sync().await?;
+            }
+            Ok(self.underlying.close().await.map_err(Into::into)?)
+        }
+        .boxed_local()
+    }
+
+    fn rx_closed(&self) -> bool {
+        self.fin.is_some() || exceeded(&self.traffic) || self.underlying.rx_closed()
     }
 
+    fn close_reason(&self) -> Option<CloseReason> {
+        close_reason(
+            &self.fin,
+            &self.traffic,
+            &self.shutdown,
+            self.underlying.close_reason(),
+        )
+    }
+
+    fn notify<'a>(&'a mut self, notice: &'a str) -> LocalBoxFuture<'a, Chacha20Result<()>> {
+        async move { Ok(self.underlying.notify(notice).await.map_err(Into::into)?) }.boxed_local()
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        self.underlying.connection_info()
+    }
+
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        self.underlying.writable()
+    }
+
+    /// Sent as an authenticated control frame, in order with the data.
+    /// Peers predating it fail to open the frame.
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, Chacha20Result<()>> {
+        async move {
+            self.sealing_key.reserve().await?;
+            let frame = seal_shutdown(&mut self.sealing_key, deadline)?;
+            self.underlying.send(&frame).await.map_err(Into::into)?;
+            self.shutdown.requested(ShutdownRequest {
+                deadline,
+                at: Instant::now(),
+                remote: false,
+            });
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        self.shutdown.get()
+    }
+}
+impl<S> Split for Chacha20Stream<S>
+where
+    S: Split,
+    S::Error: Into<StreamError>,
+    <S::ReadHalf as WaitThen>::Error: Into<StreamError>,
+    <S::WriteHalf as PipeWriteHalf>::Error: Into<StreamError>,
+{
+    type ReadHalf = Chacha20ReadHalf<S::ReadHalf>;
+    type WriteHalf = Chacha20WriteHalf<S::WriteHalf>;
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf) {
+        let (rx, tx) = self.underlying.split();
+
+        (
+            Chacha20ReadHalf {
+                opening_key: self.opening_key,
+                underlying: rx,
+                role: self.role,
+                fin: self.fin,
+                peer_finish: self.peer_finish,
+                traffic: self.traffic.clone(),
+                desync: self.desync,
+                shutdown: self.shutdown.clone(),
+            },
+            Chacha20WriteHalf {
+                sealing_key: self.sealing_key,
+                underlying: tx,
+                traffic: self.traffic,
+                shutdown: self.shutdown,
+            },
+        )
+    }
+}
+
+pub struct Chacha20ReadHalf<R>
+where
+    R: PipeReadHalf,
+    R::Error: Into<StreamError>,
+{
+    opening_key: SequentialKey,
+    underlying: R,
+    role: &'static str,
+    fin: Option<CloseReason>,
+    peer_finish: Option<Finish>,
+    traffic: Option<TrafficMeter>,
+    desync: Desync,
+    shutdown: Shutdown,
+}
+impl<R> Chacha20ReadHalf<R>
+where
+    R: PipeReadHalf,
+    R::Error: Into<StreamError>,
+{
+    pub fn underlying(&self) -> &R {
+        &self.underlying
+    }
+
+    pub fn underlying_mut(&mut self) -> &mut R {
+        &mut self.underlying
+    }
+
+    /// See [`Chacha20Stream::peer_finish`].
+    pub fn peer_finish(&self) -> Option<&Finish> {
+        self.peer_finish.as_ref()
+    }
+}
+impl<R> WaitThen for Chacha20ReadHalf<R>
+where
+    R: PipeReadHalf,
+    R::Error: Into<StreamError>,
+{
+    type Value = R::Value;
+    type Output = Option<Vec<u8>>;
+    type Error = Chacha20Error;
+
+    fn wait(&mut self) -> LocalBoxFuture<'_, Chacha20Result<Self::Value>> {
+        async move { Ok(self.underlying.wait().await.map_err(Into::into)?) }.boxed_local()
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, Chacha20Result<Self::Output>> {
+        // Without the write half the limit can only fail, the write half
+        // notifies the peer and fails on its next send
+        Box::pin(async move {
+            let data: Option<Vec<u8>> = self.underlying.then(value).await.map_err(Into::into)?;
+            let reason = self.underlying.close_reason();
+            let data = receive(
+                &mut self.opening_key,
+                (&mut self.fin, &mut self.peer_finish),
+                reason,
+                data,
+                self.role,
+                &self.desync,
+                &self.shutdown,
+            )?;
+            self.opening_key.checkpoint(self.fin.is_some()).await?;
+            let len = data.as_ref().map_or(0, Vec::len);
+            count(&self.traffic, Direction::Received, len)?;
+
+            Ok(data)
+        })
+    }
+}
+impl<R> PipeReadHalf for Chacha20ReadHalf<R>
+where
+    R: PipeReadHalf,
+    R::Error: Into<StreamError>,
+{
     fn rx_closed(&self) -> bool {
-        self.underlying.rx_closed()
+        self.fin.is_some() || exceeded(&self.traffic) || self.underlying.rx_closed()
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        close_reason(
+            &self.fin,
+            &self.traffic,
+            &self.shutdown,
+            self.underlying.close_reason(),
+        )
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        self.underlying.connection_info()
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        self.shutdown.get()
+    }
+}
+
+pub struct Chacha20WriteHalf<W>
+where
+    W: PipeWriteHalf,
+    W::Error: Into<StreamError>,
+{
+    sealing_key: SequentialKey,
+    underlying: W,
+    traffic: Option<TrafficMeter>,
+    shutdown: Shutdown,
+}
+impl<W> Chacha20WriteHalf<W>
+where
+    W: PipeWriteHalf,
+    W::Error: Into<StreamError>,
+{
+    pub fn underlying(&self) -> &W {
+        &self.underlying
+    }
+
+    pub fn underlying_mut(&mut self) -> &mut W {
+        &mut self.underlying
+    }
+
+    /// See [`Chacha20Stream::finish`].
+    pub async fn finish(&mut self, reason: &str) -> Chacha20Result<()> {
+        self.sealing_key.reserve().await?;
+        let frame = seal_finish(&mut self.sealing_key, reason)?;
+        self.underlying.send(&frame).await.map_err(Into::into)?;
+        self.sealing_key.sync().await
+    }
+
+    async fn flush_notice(&mut self) -> Chacha20Result<()> {
+        if let Some(notice) = self.traffic.as_ref().and_then(TrafficMeter::take_notice) {
+            self.underlying.notify(&notice).await.map_err(Into::into)?;
+        }
+
+        Ok(())
+    }
+
+    async fn exceeded(&mut self, e: TrafficLimitExceeded) -> Chacha20Error {
+        let _ = self.underlying.notify(&exceeded_notice(e)).await;
+        let _ = self.close().await;
+
+        e.into()
+    }
+}
+impl<W> PipeWriteHalf for Chacha20WriteHalf<W>
+where
+    W: PipeWriteHalf,
+    W::Error: Into<StreamError>,
+{
+    type Error = Chacha20Error;
+
+    /// Empty messages are not sent, as they would read as the FIN.
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, Chacha20Result<()>> {
+        if data.is_empty() {
+            return ready(Ok(())).boxed_local();
+        }
+
+        async move {
+            if let Err(e) = count(&self.traffic, Direction::Sent, data.len()) {
+                return Err(self.exceeded(e).await);
+            }
+            self.sealing_key.reserve().await?;
+            // Sealed and handed over in the same poll, so a dropped send
+            // leaves no gap in the nonces, see [`WaitThen`]
+            let data = seal(&mut self.sealing_key, data)?;
+            self.underlying.send(&data).await.map_err(Into::into)?;
+            self.flush_notice().await
+        }
+        .boxed_local()
+    }
+
+    fn close(&mut self) -> LocalBoxFuture<'_, Chacha20Result<()>> {
+        async move {
+            if !self.sealing_key.finished {
+                self.sealing_key.reserve().await?;
+                let fin = seal(&mut self.sealing_key, &[])?;
+                trace::debug!(target: logging::CRYPTO, "TX FIN");
+                self.underlying.send(&fin).await.map_err(Into::into)?;
+                self.sealing_key.sync().await?;
+            }
+            Ok(self.underlying.close().await.map_err(Into::into)?)
+        }
+        .boxed_local()
+    }
+
+    fn notify<'a>(&'a mut self, notice: &'a str) -> LocalBoxFuture<'a, Chacha20Result<()>> {
+        async move { Ok(self.underlying.notify(notice).await.map_err(Into::into)?) }.boxed_local()
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        self.underlying.connection_info()
+    }
+
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        self.underlying.writable()
+    }
+
+    /// Sent as an authenticated control frame, in order with the data.
+    /// Peers predating it fail to open the frame.
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, Chacha20Result<()>> {
+        async move {
+            self.sealing_key.reserve().await?;
+            let frame = seal_shutdown(&mut self.sealing_key, deadline)?;
+            self.underlying.send(&frame).await.map_err(Into::into)?;
+            self.shutdown.requested(ShutdownRequest {
+                deadline,
+                at: Instant::now(),
+                remote: false,
+            });
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+fn count(
+    traffic: &Option<TrafficMeter>,
+    direction: Direction,
+    len: usize,
+) -> Result<(), TrafficLimitExceeded> {
+    match traffic {
+        Some(traffic) => traffic.count(direction, len),
+        None => Ok(()),
+    }
+}
+
+fn exceeded(traffic: &Option<TrafficMeter>) -> bool {
+    traffic.as_ref().is_some_and(TrafficMeter::exceeded)
+}
+
+fn exceeded_notice(e: TrafficLimitExceeded) -> String {
+    format!("Peer closed the connection at its {e}")
+}
+
+fn seal(key: &mut SequentialKey, data: &[u8]) -> Chacha20Result<Vec<u8>> {
+    seal_frame(key, data, false)
+}
+
+fn seal_frame(key: &mut SequentialKey, data: &[u8], control: bool) -> Chacha20Result<Vec<u8>> {
+    if key.finished {
+        return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Stream already finished").into());
+    }
+    // Reserved ahead by the async callers, see [`SequentialKey::reserve`]
+    if key
+        .replay
+        .as_ref()
+        .is_some_and(|replay| !replay.reserved(key.next))
+    {
+        return Err(io::Error::other("Sequence number not reserved in the replay state").into());
+    }
+    if control && !key.numbered {
+        let e = io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Peer predates control frames, it would fail to open them",
+        );
+        return Err(e.into());
+    }
+    let (header, nonce) = match control {
+        false => (key.next, key.seq.at(key.next)),
+        true => (key.next | CONTROL, key.seq.control_at(key.next)),
+    };
+    let mut sealed = data.to_owned();
+    key.key
+        .seal_in_place(nonce, &mut sealed)
+        .map_err(Chacha20Error::CryptoError)?;
+
+    let mut data = match key.numbered {
+        true => header.to_be_bytes().to_vec(),
+        false => Vec::new(),
+    };
+    data.append(&mut sealed);
+    key.next += 1;
+
+    Ok(data)
+}
+
+/// The finish frame, after which nothing is sealed.
+fn seal_finish(key: &mut SequentialKey, reason: &str) -> Chacha20Result<Vec<u8>> {
+    let mut frame = vec![FINISH];
+    frame.extend_from_slice(&key.next.to_be_bytes());
+    frame.extend_from_slice(reason.as_bytes());
+    let frame = seal_frame(key, &frame, true)?;
+    trace::debug!(target: logging::CRYPTO, "TX finish at {}: {}", key.next - 1, reason);
+    key.finished = true;
+
+    Ok(frame)
+}
+
+fn seal_shutdown(key: &mut SequentialKey, deadline: Duration) -> Chacha20Result<Vec<u8>> {
+    let millis = u64::try_from(deadline.as_millis()).unwrap_or(u64::MAX);
+    let mut frame = vec![SHUTDOWN];
+    frame.extend_from_slice(&millis.to_be_bytes());
+    trace::debug!(target: logging::CRYPTO, "TX shutdown request within {:?}", deadline);
+
+    seal_frame(key, &frame, true)
+}
+
+/// Opens a message under the sequence number it carries, the expected one
+/// if unnumbered, leaving the check against the expected one to the caller.
+/// Also tells whether it is a control frame.
+fn open(
+    key: &SequentialKey,
+    mut data: Vec<u8>,
+    role: &'static str,
+) -> Chacha20Result<(u64, Vec<u8>, bool)> {
+    let failed = |e| {
+        metrics::aead_failure(role);
+        Chacha20Error::CryptoError(e)
+    };
+    let (seq, control) = match key.numbered {
+        true => {
+            let Some(header) = data.first_chunk::<SEQ_LEN>() else {
+                return Err(failed(Unspecified));
+            };
+            let header = u64::from_be_bytes(*header);
+            data.drain(..SEQ_LEN);
+            (header & !CONTROL, header & CONTROL != 0)
+        }
+        false => (key.next, false),
+    };
+    let nonce = match control {
+        false => key.seq.at(seq),
+        true => key.seq.control_at(seq),
+    };
+    key.key.open_in_place(nonce, &mut data).map_err(failed)?;
+
+    Ok((seq, data, control))
+}
+
+/// Handling of nonce desyncs, shared by both halves once split.
+#[derive(Clone, Default)]
+struct Desync {
+    policy: DesyncPolicy,
+    strictness: Strictness,
+    diagnostics: Diagnostics,
+    events: Events,
+}
+
+/// The first shutdown requested, by either side, shared by both halves once
+/// split.
+#[derive(Clone, Default)]
+struct Shutdown(Arc<Mutex<Option<ShutdownRequest>>>);
+impl Shutdown {
+    /// Keeps the first request.
+    fn requested(&self, request: ShutdownRequest) -> bool {
+        let mut shutdown = self.0.lock().unwrap();
+        if shutdown.is_some() {
+            return false;
+        }
+        *shutdown = Some(request);
+        true
+    }
+
+    fn get(&self) -> Option<ShutdownRequest> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Opens a received message, an empty one is the FIN, recorded along with
+/// how the underlying stream stood when it arrived, as is a finish. Messages
+/// out of sequence are handled according to `policy`.
+fn receive(
+    key: &mut SequentialKey,
+    (fin, finish): (&mut Option<CloseReason>, &mut Option<Finish>),
+    underlying: Option<CloseReason>,
+    data: Option<Vec<u8>>,
+    role: &'static str,
+    desync: &Desync,
+    shutdown: &Shutdown,
+) -> Chacha20Result<Option<Vec<u8>>> {
+    let Some(data) = data else {
+        return Ok(None);
+    };
+    let (seq, data, control) = open(key, data, role)?;
+    if !key.expects(seq) {
+        let error = || Chacha20Error::NonceDesync {
+            expected: key.next,
+            received: seq,
+        };
+        trace::warn!(target: logging::CRYPTO, "{}", error());
+        desync.diagnostics.warning(error().to_string());
+        desync.events.emit(ConnectionEvent::NonceDesync {
+            expected: key.next,
+            received: seq,
+        });
+        metrics::nonce_desync(role);
+
+        if let Err(violation) = desync.strictness.check(|| Violation::NonceDesync {
+            expected: key.next,
+            received: seq,
+        }) {
+            fin.get_or_insert(CloseReason::transport_failed(violation.clone()));
+            return Err(violation.into());
+        }
+        if desync.policy == DesyncPolicy::Fail {
+            fin.get_or_insert(CloseReason::transport_failed(error()));
+            return Err(error());
+        }
+        if seq < key.next {
+            return Ok(None);
+        }
+    }
+    let delivered = key.delivered;
+    key.advance(seq);
+
+    if control {
+        let seq = (seq, delivered);
+        received_control((fin, finish), seq, &data, (shutdown, &desync.events))?;
+        return Ok(None);
+    }
+    if data.is_empty() {
+        trace::debug!(target: logging::CRYPTO, "RX FIN");
+        fin.get_or_insert(underlying.unwrap_or(CloseReason::CleanFin));
+        return Ok(None);
+    }
+
+    Ok(Some(data))
+}
+
+/// Checks a finish against the sequence number it came with and the
+/// messages `delivered` before it, and records a shutdown request. Control
+/// frames unknown to this version are skipped.
+fn received_control(
+    (fin, finish): (&mut Option<CloseReason>, &mut Option<Finish>),
+    (seq, delivered): (u64, u64),
+    data: &[u8],
+    (shutdown, events): (&Shutdown, &Events),
+) -> Chacha20Result<()> {
+    if let Some((&SHUTDOWN, frame)) = data.split_first() {
+        let Some(millis) = frame.first_chunk::<8>() else {
+            trace::debug!(target: logging::CRYPTO, "RX short shutdown request {}", seq);
+            return Ok(());
+        };
+        let deadline = Duration::from_millis(u64::from_be_bytes(*millis));
+        let request = ShutdownRequest {
+            deadline,
+            at: Instant::now(),
+            remote: true,
+        };
+        if shutdown.requested(request) {
+            trace::info!(target: logging::CRYPTO, "Peer asked to close within {deadline:?}");
+            events.emit(ConnectionEvent::ShutdownRequested { deadline });
+        }
+        return Ok(());
+    }
+    let Some((&FINISH, frame)) = data.split_first() else {
+        trace::debug!(target: logging::CRYPTO, "RX unknown control frame {}", seq);
+        return Ok(());
+    };
+    let claimed = frame
+        .first_chunk::<SEQ_LEN>()
+        .map(|s| u64::from_be_bytes(*s));
+    if claimed != Some(seq) {
+        let error = || Chacha20Error::BadFinish { seq, claimed };
+        trace::warn!(target: logging::CRYPTO, "{}", error());
+        fin.get_or_insert(CloseReason::transport_failed(error()));
+        return Err(error());
+    }
+
+    let reason = String::from_utf8_lossy(&frame[SEQ_LEN..]).into_owned();
+    trace::debug!(target: logging::CRYPTO, "RX finish at {}: {}", seq, reason);
+    *finish = Some(Finish { seq, reason });
+    if delivered < seq {
+        // Authentic, but messages were skipped on the way
+        let error = Chacha20Error::Incomplete { seq, delivered };
+        trace::warn!(target: logging::CRYPTO, "{}", error);
+        fin.get_or_insert(CloseReason::transport_failed(error));
+        return Ok(());
+    }
+    fin.get_or_insert(CloseReason::CleanFin);
+
+    Ok(())
+}
+
+/// Only the authenticated FIN is a clean end, the underlying stream ending
+/// without it may be an attacker cutting the stream short, or a peer too old
+/// to send it. Closing locally once the peer asked is closing as it asked.
+fn close_reason(
+    fin: &Option<CloseReason>,
+    traffic: &Option<TrafficMeter>,
+    shutdown: &Shutdown,
+    underlying: Option<CloseReason>,
+) -> Option<CloseReason> {
+    if exceeded(traffic) {
+        return Some(CloseReason::TrafficLimit);
+    }
+    if let Some(fin) = fin {
+        return Some(fin.clone());
+    }
+
+    match underlying {
+        Some(CloseReason::CleanFin) => {
+            Some(CloseReason::transport_failed(Chacha20Error::Truncated))
+        }
+        Some(CloseReason::LocalClose) if shutdown.get().is_some_and(|request| request.remote) => {
+            Some(CloseReason::RemoteRequested)
+        }
+        reason => reason,
     }
 }
 
@@ -171,11 +1039,35 @@ pub enum Chacha20Error {
     #[error(transparent)]
     Timeout(#[from] TimeoutError),
     #[error(transparent)]
+    TrafficLimit(#[from] TrafficLimitExceeded),
+    #[error(transparent)]
     SignalingError(SignalingError),
     #[error(transparent)]
     StreamError(StreamError),
     #[error("Crypto error")]
     CryptoError(Unspecified),
+    #[error("Peer derived different keys, is it running a compatible version?")]
+    KeyConfirmationFailed,
+    #[error("Stream ended without the peer finishing, data may be truncated")]
+    Truncated,
+    #[error("Expected message {expected} from the peer, received {received}")]
+    NonceDesync { expected: u64, received: u64 },
+    #[error("Peer finished at message {seq} but claimed {claimed:?}")]
+    BadFinish { seq: u64, claimed: Option<u64> },
+    #[error("Peer finished after {seq} messages, only {delivered} arrived")]
+    Incomplete { seq: u64, delivered: u64 },
+    #[error(transparent)]
+    Violation(#[from] Violation),
+    #[error(transparent)]
+    ReplayState(ReplayStateError),
+}
+impl From<ReplayStateError> for Chacha20Error {
+    fn from(value: ReplayStateError) -> Self {
+        match value {
+            ReplayStateError::Io(e) => e.into(),
+            e => Self::ReplayState(e),
+        }
+    }
 }
 impl From<SignalingError> for Chacha20Error {
     fn from(value: SignalingError) -> Self {
@@ -192,7 +1084,8 @@ impl From<StreamError> for Chacha20Error {
             StreamError::Io(e) => e.into(),
             StreamError::Timeout(e) => e.into(),
             StreamError::SignalingError(e) => e.into(),
-            e @ StreamError::Other(_) => Self::StreamError(e),
+            StreamError::TrafficLimit(e) => e.into(),
+            e @ (StreamError::Other(_) | StreamError::ClosedDirty(_)) => Self::StreamError(e),
         }
     }
 }
@@ -203,9 +1096,366 @@ impl From<Chacha20Error> for StreamError {
         match value {
             Chacha20Error::Io(e) => e.into(),
             Chacha20Error::Timeout(e) => e.into(),
+            Chacha20Error::TrafficLimit(e) => e.into(),
             Chacha20Error::SignalingError(e) => e.into(),
             Chacha20Error::StreamError(e) => e,
             e @ Chacha20Error::CryptoError(_) => Self::Other(Box::new(e)),
+            e @ (Chacha20Error::KeyConfirmationFailed
+            | Chacha20Error::Truncated
+            | Chacha20Error::NonceDesync { .. }
+            | Chacha20Error::BadFinish { .. }
+            | Chacha20Error::Incomplete { .. }
+            | Chacha20Error::Violation(_)
+            | Chacha20Error::ReplayState(_)) => Self::Other(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        async_pipe_stream::AsyncPipeStream,
+        replay_state::{ReplayStore, SYNC_EVERY},
+    };
+    use tokio::io::{duplex, split};
+
+    fn pair(
+        dialer_key: &[u8],
+        listener_key: &[u8],
+    ) -> (
+        Chacha20Stream<AsyncPipeStream>,
+        Chacha20Stream<AsyncPipeStream>,
+    ) {
+        let (a, b) = duplex(4096);
+        let (a_rx, a_tx) = split(a);
+        let (b_rx, b_tx) = split(b);
+
+        (
+            Chacha20Stream::new(dialer_key, true, AsyncPipeStream::new(a_rx, a_tx)).unwrap(),
+            Chacha20Stream::new(listener_key, false, AsyncPipeStream::new(b_rx, b_tx)).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn key_confirmation() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        let (a, b) = tokio::join!(a.confirm_key(), b.confirm_key());
+        a.unwrap();
+        b.unwrap();
+    }
+
+    #[tokio::test]
+    async fn key_confirmation_mismatch() {
+        let (mut a, mut b) = pair(b"basekey", b"other basekey");
+        let (a, b) = tokio::join!(a.confirm_key(), b.confirm_key());
+        assert!(matches!(a, Err(Chacha20Error::KeyConfirmationFailed)));
+        assert!(matches!(b, Err(Chacha20Error::KeyConfirmationFailed)));
+    }
+
+    async fn drain(stream: &mut Chacha20Stream<AsyncPipeStream>) -> Vec<Vec<u8>> {
+        let mut received = vec![];
+        while !stream.rx_closed() {
+            let mut value = stream.wait().await.unwrap();
+            if let Some(data) = stream.then(&mut value).await.unwrap() {
+                received.push(data);
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn authenticated_fin() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        // Reads are not framed, so each message is read before the next is sent
+        a.send(b"data").await.unwrap();
+        let mut value = b.wait().await.unwrap();
+        assert_eq!(b.then(&mut value).await.unwrap().unwrap(), b"data");
+        a.send(b"").await.unwrap();
+        a.close().await.unwrap();
+
+        assert!(drain(&mut b).await.is_empty());
+        assert!(matches!(b.close_reason(), Some(CloseReason::CleanFin)));
+        assert_eq!(b.peer_finish(), None);
+    }
+
+    #[tokio::test]
+    async fn finish() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        a.send(b"data").await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap().unwrap(), b"data");
+        a.finish("done").await.unwrap();
+        assert!(a.send(b"late").await.is_err());
+        // Only the transport is left to close
+        a.close().await.unwrap();
+
+        assert!(drain(&mut b).await.is_empty());
+        assert!(matches!(b.close_reason(), Some(CloseReason::CleanFin)));
+        assert_eq!(
+            b.peer_finish(),
+            Some(&Finish {
+                seq: 1,
+                reason: "done".to_string()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn finish_after_gap() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        b.set_desync_policy(DesyncPolicy::Skip);
+
+        // Lost below the encryption layer
+        a.send(b"lost").await.unwrap();
+        let underlying = b.underlying_mut();
+        let mut value = underlying.wait().await.unwrap();
+        underlying.then(&mut value).await.unwrap();
+        a.send(b"data").await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap().unwrap(), b"data");
+        a.finish("done").await.unwrap();
+        a.close().await.unwrap();
+
+        assert!(drain(&mut b).await.is_empty());
+        let Some(CloseReason::TransportFailed(e)) = b.close_reason() else {
+            panic!("{:?}", b.close_reason());
+        };
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(Chacha20Error::Incomplete {
+                seq: 2,
+                delivered: 1
+            })
+        ));
+        assert_eq!(b.peer_finish().unwrap().seq, 2);
+    }
+
+    #[tokio::test]
+    async fn forged_finish() {
+        // Marked as a control frame, but sealed as data
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        let mut sealed = seal(&mut a.sealing_key, &[FINISH, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        sealed[0] |= 0x80;
+        a.underlying_mut().send(&sealed).await.unwrap();
+        assert!(matches!(
+            recv(&mut b).await,
+            Err(Chacha20Error::CryptoError(_))
+        ));
+
+        // Claiming fewer messages than were sent, which only a forged frame
+        // can, sealed with the internals of the stream
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        exchange(&mut a, &mut b, b"data").await;
+        let sealed = seal_frame(&mut a.sealing_key, &[FINISH, 0, 0, 0, 0, 0, 0, 0, 0], true);
+        a.underlying_mut().send(&sealed.unwrap()).await.unwrap();
+        assert!(matches!(
+            recv(&mut b).await,
+            Err(Chacha20Error::BadFinish {
+                seq: 1,
+                claimed: Some(0)
+            })
+        ));
+        assert!(b.rx_closed());
+        assert_eq!(b.peer_finish(), None);
+    }
+
+    #[tokio::test]
+    async fn unnumbered() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        a.set_unnumbered();
+        b.set_unnumbered();
+
+        // Framed as peers predating sequence numbers do
+        let sealed = seal(&mut a.sealing_key, b"data").unwrap();
+        assert_eq!(sealed.len(), b"data".len() + crypto_backend::TAG_LEN);
+        let mut opened = sealed.clone();
+        let nonce = a.sealing_key.seq.at(0);
+        a.sealing_key.key.open_in_place(nonce, &mut opened).unwrap();
+        assert_eq!(opened, b"data");
+        a.underlying_mut().send(&sealed).await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap().unwrap(), b"data");
+
+        exchange(&mut a, &mut b, b"more").await;
+        assert!(a.finish("done").await.is_err());
+        assert!(a.request_shutdown(Duration::ZERO).await.is_err());
+        a.close().await.unwrap();
+        assert!(drain(&mut b).await.is_empty());
+        assert!(matches!(b.close_reason(), Some(CloseReason::CleanFin)));
+    }
+
+    async fn recv(stream: &mut Chacha20Stream<AsyncPipeStream>) -> Chacha20Result<Option<Vec<u8>>> {
+        let mut value = stream.wait().await?;
+        stream.then(&mut value).await
+    }
+
+    #[tokio::test]
+    async fn nonce_desync_fails() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        let diagnostics = Diagnostics::new();
+        b.set_diagnostics(diagnostics.clone());
+
+        // Lost below the encryption layer
+        seal(&mut a.sealing_key, b"lost").unwrap();
+        a.send(b"data").await.unwrap();
+
+        assert!(matches!(
+            recv(&mut b).await,
+            Err(Chacha20Error::NonceDesync {
+                expected: 0,
+                received: 1
+            })
+        ));
+        assert!(b.rx_closed());
+        assert!(matches!(
+            b.close_reason(),
+            Some(CloseReason::TransportFailed(_))
+        ));
+        assert_eq!(diagnostics.session().unwrap().warnings.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn nonce_desync_skips() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        b.set_desync_policy(DesyncPolicy::Skip);
+        let events = Events::new();
+        let mut rx = events.subscribe();
+        b.set_events(events);
+
+        seal(&mut a.sealing_key, b"lost").unwrap();
+        a.send(b"data").await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap().unwrap(), b"data");
+
+        // Repeated below the encryption layer
+        let repeated = seal(&mut a.sealing_key, b"again").unwrap();
+        a.underlying_mut().send(&repeated).await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap().unwrap(), b"again");
+        a.underlying_mut().send(&repeated).await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap(), None);
+
+        a.send(b"more").await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap().unwrap(), b"more");
+        assert!(!b.rx_closed());
+
+        for (expected, received) in [(0, 1), (3, 2)] {
+            assert_eq!(
+                rx.try_recv(),
+                Ok(ConnectionEvent::NonceDesync { expected, received })
+            );
         }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn strict_nonce_desync() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        b.set_desync_policy(DesyncPolicy::Skip);
+        b.set_strictness(Strictness::Strict);
+
+        seal(&mut a.sealing_key, b"lost").unwrap();
+        a.send(b"data").await.unwrap();
+        assert!(matches!(
+            recv(&mut b).await,
+            Err(Chacha20Error::Violation(Violation::NonceDesync {
+                expected: 0,
+                received: 1
+            }))
+        ));
+        assert!(b.rx_closed());
+    }
+
+    #[tokio::test]
+    async fn tampered_sequence() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        b.set_desync_policy(DesyncPolicy::Skip);
+
+        let mut sealed = seal(&mut a.sealing_key, b"data").unwrap();
+        sealed[SEQ_LEN - 1] ^= 1;
+        a.underlying_mut().send(&sealed).await.unwrap();
+        assert!(matches!(
+            recv(&mut b).await,
+            Err(Chacha20Error::CryptoError(_))
+        ));
+    }
+
+    /// Sends `data` and has it received, returning what went over the wire.
+    async fn exchange(
+        a: &mut Chacha20Stream<AsyncPipeStream>,
+        b: &mut Chacha20Stream<AsyncPipeStream>,
+        data: &[u8],
+    ) -> Vec<u8> {
+        a.sealing_key.reserve().await.unwrap();
+        let sealed = seal(&mut a.sealing_key, data).unwrap();
+        a.underlying_mut().send(&sealed).await.unwrap();
+        assert_eq!(recv(b).await.unwrap().unwrap(), data);
+        sealed
+    }
+
+    #[tokio::test]
+    async fn restart_with_replay_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ReplayStore::new(dir.path());
+
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        a.set_replay_state(store.fresh(b"basekey", true).unwrap());
+        b.set_replay_state(store.fresh(b"basekey", false).unwrap());
+        exchange(&mut a, &mut b, b"first").await;
+        let old = exchange(&mut a, &mut b, b"second").await;
+        // Restarted mid-session, without closing
+        drop((a, b));
+        assert!(matches!(
+            store.fresh(b"basekey", true),
+            Err(ReplayStateError::Reused)
+        ));
+
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        a.set_replay_state(store.resume(b"basekey", true).unwrap());
+        b.set_replay_state(store.resume(b"basekey", false).unwrap());
+        assert_eq!(a.sealing_key.next, SYNC_EVERY);
+        assert_eq!(b.opening_key.next, 2);
+        exchange(&mut a, &mut b, b"after restart").await;
+
+        // Replayed from the earlier run
+        a.underlying_mut().send(&old).await.unwrap();
+        assert!(matches!(
+            recv(&mut b).await,
+            Err(Chacha20Error::NonceDesync {
+                expected: 257,
+                received: 1
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn restart_without_replay_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ReplayStore::new(dir.path());
+
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        exchange(&mut a, &mut b, b"first").await;
+        let old = exchange(&mut a, &mut b, b"second").await;
+        drop((a, b));
+
+        // Nothing was recorded, the counters restart and the replay goes
+        // through
+        assert!(matches!(
+            store.resume(b"basekey", false),
+            Err(ReplayStateError::Missing)
+        ));
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        exchange(&mut a, &mut b, b"first").await;
+        a.underlying_mut().send(&old).await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap().unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let (mut a, mut b) = pair(b"basekey", b"basekey");
+        a.send(b"data").await.unwrap();
+        a.underlying_mut().close().await.unwrap();
+
+        assert_eq!(drain(&mut b).await, [b"data"]);
+        assert!(matches!(
+            b.close_reason(),
+            Some(CloseReason::TransportFailed(_))
+        ));
     }
 }
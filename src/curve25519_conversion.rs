@@ -1,3 +1,4 @@
+use crate::crypto_backend;
 use curve25519_dalek::edwards::CompressedEdwardsY;
 
 ///! Remarks:
@@ -12,6 +13,7 @@ use curve25519_dalek::edwards::CompressedEdwardsY;
 
 pub fn ed25519_public_key_to_x25519(public_key: &[u8]) -> Option<x25519_dalek::PublicKey> {
     let public_point = CompressedEdwardsY::from_slice(public_key)
+        .ok()?
         .decompress()?
         .to_montgomery();
 
@@ -21,8 +23,8 @@ pub fn ed25519_public_key_to_x25519(public_key: &[u8]) -> Option<x25519_dalek::P
 }
 
 pub fn ed25519_seed_to_x25519(seed: &[u8]) -> x25519_dalek::StaticSecret {
-    let sha512 = ring::digest::digest(&ring::digest::SHA512, seed);
-    let mut key: [u8; 32] = sha512.as_ref()[0..32].try_into().unwrap();
+    let sha512 = crypto_backend::sha512(seed);
+    let mut key: [u8; 32] = sha512[0..32].try_into().unwrap();
     key[0] &= 248;
     key[31] &= 127;
     key[31] |= 64;
@@ -33,17 +35,16 @@ pub fn ed25519_seed_to_x25519(seed: &[u8]) -> x25519_dalek::StaticSecret {
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use ring::signature::{self, KeyPair};
+    use crate::crypto_backend::Ed25519KeyPair;
 
     #[test]
     fn generate_keys_and_convert() {
-        let seed: [u8; 32] = ring::rand::generate(&ring::rand::SystemRandom::new())
-            .unwrap()
-            .expose();
+        let mut seed = [0; 32];
+        crypto_backend::fill_random(&mut seed).unwrap();
 
-        let ed_keypair = signature::Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        let ed_keypair = Ed25519KeyPair::from_seed(&seed).unwrap();
         let ed_public_key = ed_keypair.public_key();
-        let ed_public_key_as_x = ed25519_public_key_to_x25519(ed_public_key.as_ref()).unwrap();
+        let ed_public_key_as_x = ed25519_public_key_to_x25519(ed_public_key).unwrap();
 
         let x_keypair = ed25519_seed_to_x25519(&seed);
         let x_public_key = x25519_dalek::PublicKey::from(&x_keypair);
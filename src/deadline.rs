@@ -0,0 +1,91 @@
+use crate::error::TimeoutError;
+use std::{
+    future::{pending, Future},
+    time::{Duration, Instant},
+};
+use tokio::{select, time::sleep_until};
+
+/// Point in time shared by every await of an operation, once it passes they
+/// all fail with [`TimeoutError`]. The default never expires.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+impl Deadline {
+    pub const NEVER: Deadline = Deadline(None);
+
+    pub fn at(instant: Instant) -> Deadline {
+        Deadline(Some(instant))
+    }
+
+    pub fn after(duration: Duration) -> Deadline {
+        Deadline(Instant::now().checked_add(duration))
+    }
+
+    /// Deadline for a budget of `timeout` starting now, if any.
+    pub fn from_timeout(timeout: Option<Duration>) -> Deadline {
+        timeout.map(Deadline::after).unwrap_or_default()
+    }
+
+    pub fn instant(&self) -> Option<Instant> {
+        self.0
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.0.is_some_and(|instant| instant <= Instant::now())
+    }
+
+    /// The earliest of both deadlines.
+    pub fn min(self, other: Deadline) -> Deadline {
+        match (self.0, other.0) {
+            (Some(a), Some(b)) => Deadline(Some(a.min(b))),
+            (a, b) => Deadline(a.or(b)),
+        }
+    }
+
+    /// Completes once the deadline passes, never if there is none.
+    pub async fn expired(&self) -> TimeoutError {
+        match self.0 {
+            Some(instant) => sleep_until(instant.into()).await,
+            None => pending().await,
+        }
+
+        TimeoutError
+    }
+
+    /// Runs `future` unless the deadline passes first.
+    pub async fn run<F, T, E>(&self, future: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: From<TimeoutError>,
+    {
+        select! {
+            r = future => r,
+            e = self.expired() => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn earliest() {
+        let soon = Deadline::after(Duration::from_secs(1));
+        let later = Deadline::after(Duration::from_secs(60));
+
+        assert_eq!(soon.min(later), soon);
+        assert_eq!(later.min(soon), soon);
+        assert_eq!(Deadline::NEVER.min(later), later);
+        assert_eq!(Deadline::NEVER.min(Deadline::NEVER), Deadline::NEVER);
+    }
+
+    #[tokio::test]
+    async fn cancels_pending_await() {
+        let deadline = Deadline::after(Duration::from_millis(10));
+        let r: Result<(), TimeoutError> = deadline.run(pending()).await;
+
+        assert!(r.is_err());
+        assert!(deadline.is_expired());
+        assert!(!Deadline::NEVER.is_expired());
+    }
+}
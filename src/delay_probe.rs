@@ -0,0 +1,332 @@
+//! One way delay estimation from timestamped probes, see
+//! [`SctpConfig::delay_probe_interval`](crate::sctp::SctpConfig::delay_probe_interval).
+//!
+//! The peer echoes each probe along with when it received and answered it,
+//! giving the four timestamps of an NTP exchange. The clock offset is taken
+//! from the sample with the lowest round trip time within [`BASE_WINDOW`],
+//! where the least queuing happened, and is only known within half of that
+//! round trip time, which bounds every estimate. Queuing delays are relative
+//! to the lowest one way delay seen in the window, so the offset cancels out
+//! of them.
+
+use crate::rate_limit::{RateLimit, RateLimiter};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+
+/// Floor of the probe interval.
+pub const MIN_PROBE_INTERVAL: Duration = Duration::from_millis(100);
+/// How long samples are kept to find the base delays. Short enough that the
+/// drift between the clocks stays negligible within it.
+pub const BASE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Probes answered per second, whatever rate the peer sends them at.
+const REPLY_LIMIT: RateLimit = RateLimit {
+    burst: 4,
+    per_second: 10,
+};
+/// Largest clock offset plus delay accepted in either direction, keeping
+/// the arithmetic on the timestamps of the peer far from overflowing.
+const MAX_SKEW_US: u64 = 24 * 3600 * 1_000_000;
+/// Probes whose answer is still accepted.
+const OUTSTANDING: usize = 16;
+const REQUEST: u8 = 0;
+const REPLY: u8 = 1;
+const LEN: usize = 1 + 3 * 8;
+
+/// Delay in one direction, in milliseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct DelayEstimate {
+    pub owd_estimate_ms: f64,
+    /// The actual delay is within `low_ms..=high_ms` as long as the clocks
+    /// did not drift since the base sample.
+    pub low_ms: f64,
+    pub high_ms: f64,
+    /// Delay over the lowest seen within [`BASE_WINDOW`].
+    pub queuing_ms: f64,
+}
+
+/// Estimates from the latest answered probe.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct OneWayDelay {
+    /// Towards the peer.
+    pub outbound: DelayEstimate,
+    /// From the peer.
+    pub inbound: DelayEstimate,
+    pub rtt_ms: f64,
+    /// How far ahead the clock of the peer is.
+    pub clock_offset_ms: f64,
+    pub samples: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    at: Instant,
+    /// Peer receive time minus local send time, offset included.
+    outbound_us: i64,
+    /// Local receive time minus peer send time, offset included.
+    inbound_us: i64,
+}
+impl Sample {
+    fn rtt_us(&self) -> i64 {
+        self.outbound_us + self.inbound_us
+    }
+
+    /// Exact if both directions took as long.
+    fn offset_us(&self) -> i64 {
+        (self.outbound_us - self.inbound_us) / 2
+    }
+}
+
+pub(crate) struct DelayProbe {
+    outstanding: VecDeque<i64>,
+    samples: VecDeque<Sample>,
+    count: u64,
+    replies: RateLimiter,
+}
+impl DelayProbe {
+    pub(crate) fn new() -> DelayProbe {
+        DelayProbe {
+            outstanding: VecDeque::new(),
+            samples: VecDeque::new(),
+            count: 0,
+            replies: RateLimiter::new(REPLY_LIMIT),
+        }
+    }
+
+    pub(crate) fn request(&mut self, now_us: i64) -> Vec<u8> {
+        if self.outstanding.len() == OUTSTANDING {
+            self.outstanding.pop_front();
+        }
+        self.outstanding.push_back(now_us);
+        encode(REQUEST, [now_us, 0, 0])
+    }
+
+    /// Records a reply, returns what to answer a request with. Requests past
+    /// the rate limit, malformed probes, replies to unknown requests and
+    /// replies whose timestamps cannot be right are ignored.
+    pub(crate) fn received(&mut self, data: &[u8], now_us: i64) -> Option<Vec<u8>> {
+        let (kind, [sent, peer_received, peer_sent]) = decode(data)?;
+        match kind {
+            REQUEST => self
+                .replies
+                .try_acquire()
+                .then(|| encode(REPLY, [sent, now_us, now_us])),
+            REPLY => {
+                let i = self.outstanding.iter().position(|t| *t == sent)?;
+                let sample = Sample {
+                    at: Instant::now(),
+                    outbound_us: peer_received.checked_sub(sent)?,
+                    inbound_us: now_us.checked_sub(peer_sent)?,
+                };
+                // The peer cannot answer before receiving, and the round
+                // trip is the local time elapsed minus how long it held on
+                let plausible = sample.outbound_us.unsigned_abs() <= MAX_SKEW_US
+                    && sample.inbound_us.unsigned_abs() <= MAX_SKEW_US
+                    && peer_received <= peer_sent
+                    && (0..=now_us.saturating_sub(sent)).contains(&sample.rtt_us());
+                if !plausible {
+                    return None;
+                }
+                self.outstanding.drain(..=i);
+                self.record(sample);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn record(&mut self, sample: Sample) {
+        while let Some(oldest) = self.samples.front() {
+            if sample.at.saturating_duration_since(oldest.at) <= BASE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.count += 1;
+    }
+
+    pub(crate) fn estimate(&self) -> Option<OneWayDelay> {
+        let latest = self.samples.back()?;
+        let base = self.samples.iter().min_by_key(|s| s.rtt_us())?;
+        let min_outbound = self.samples.iter().map(|s| s.outbound_us).min()?;
+        let min_inbound = self.samples.iter().map(|s| s.inbound_us).min()?;
+
+        let offset = base.offset_us();
+        let uncertainty = base.rtt_us() as f64 / 2.0;
+        let rtt = latest.rtt_us() as f64;
+        let direction = |raw: i64, sign: i64, min: i64| {
+            let estimate = (raw - sign * offset) as f64;
+            DelayEstimate {
+                owd_estimate_ms: ms(estimate),
+                low_ms: ms((estimate - uncertainty).max(0.0)),
+                high_ms: ms((estimate + uncertainty).min(rtt)),
+                queuing_ms: ms((raw - min) as f64),
+            }
+        };
+
+        Some(OneWayDelay {
+            outbound: direction(latest.outbound_us, 1, min_outbound),
+            inbound: direction(latest.inbound_us, -1, min_inbound),
+            rtt_ms: ms(rtt),
+            clock_offset_ms: ms(offset as f64),
+            samples: self.count,
+        })
+    }
+}
+
+pub(crate) fn now_us() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as i64)
+        .unwrap_or_default()
+}
+
+fn ms(us: f64) -> f64 {
+    us / 1000.0
+}
+
+fn encode(kind: u8, timestamps: [i64; 3]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(LEN);
+    data.push(kind);
+    for t in timestamps {
+        data.extend_from_slice(&t.to_be_bytes());
+    }
+    data
+}
+
+fn decode(data: &[u8]) -> Option<(u8, [i64; 3])> {
+    let (&kind, rest) = data.split_first()?;
+    if rest.len() != 3 * 8 {
+        return None;
+    }
+    let mut timestamps = [0; 3];
+    for (t, bytes) in timestamps.iter_mut().zip(rest.chunks_exact(8)) {
+        *t = i64::from_be_bytes(bytes.try_into().ok()?);
+    }
+    Some((kind, timestamps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    /// Local clock in microseconds, following the paused tokio clock.
+    fn clock_us(start: Instant) -> i64 {
+        1_000_000 + start.elapsed().as_micros() as i64
+    }
+
+    /// Runs probes through a path taking `outbound` and `inbound` ms, with
+    /// the clock of the peer `offset` ms ahead.
+    async fn exchange(
+        local: &mut DelayProbe,
+        peer: &mut DelayProbe,
+        start: Instant,
+        outbound: u64,
+        inbound: u64,
+        offset: i64,
+    ) {
+        let request = local.request(clock_us(start));
+        advance(Duration::from_millis(outbound)).await;
+        let reply = peer.received(&request, clock_us(start) + offset * 1000);
+        advance(Duration::from_millis(inbound)).await;
+        assert_eq!(local.received(&reply.unwrap(), clock_us(start)), None);
+        advance(Duration::from_millis(100)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recovers_asymmetric_queuing() {
+        let (mut local, mut peer) = (DelayProbe::new(), DelayProbe::new());
+        let start = Instant::now();
+        assert_eq!(local.estimate(), None);
+
+        exchange(&mut local, &mut peer, start, 10, 10, 5000).await;
+        exchange(&mut local, &mut peer, start, 70, 12, 5000).await;
+
+        let estimate = local.estimate().unwrap();
+        assert_eq!(estimate.samples, 2);
+        assert_eq!(estimate.rtt_ms, 82.0);
+        assert_eq!(estimate.clock_offset_ms, 5000.0);
+        assert_eq!(
+            estimate.outbound,
+            DelayEstimate {
+                owd_estimate_ms: 70.0,
+                low_ms: 60.0,
+                high_ms: 80.0,
+                queuing_ms: 60.0,
+            }
+        );
+        assert_eq!(estimate.inbound.owd_estimate_ms, 12.0);
+        assert_eq!(estimate.inbound.low_ms, 2.0);
+        assert_eq!(estimate.inbound.queuing_ms, 2.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn base_expires() {
+        let (mut local, mut peer) = (DelayProbe::new(), DelayProbe::new());
+        let start = Instant::now();
+
+        exchange(&mut local, &mut peer, start, 10, 10, 0).await;
+        advance(BASE_WINDOW).await;
+        exchange(&mut local, &mut peer, start, 40, 40, 0).await;
+
+        // Only the latest sample is left to compare against
+        let estimate = local.estimate().unwrap();
+        assert_eq!(estimate.samples, 2);
+        assert_eq!(estimate.outbound.owd_estimate_ms, 40.0);
+        assert_eq!(estimate.outbound.queuing_ms, 0.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_unknown_replies() {
+        let (mut local, mut peer) = (DelayProbe::new(), DelayProbe::new());
+        let request = local.request(1);
+        let forged = peer.received(&encode(REQUEST, [2, 0, 0]), 3);
+
+        assert_eq!(local.received(&forged.unwrap(), 4), None);
+        assert_eq!(local.received(&request[1..], 4), None);
+        assert_eq!(local.estimate(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ignores_implausible_replies() {
+        let mut local = DelayProbe::new();
+        let sent = 1_000_000;
+        local.request(sent);
+        let now = sent + 20_000;
+        for [peer_received, peer_sent] in [
+            // Would overflow
+            [i64::MIN, i64::MIN],
+            [i64::MAX, i64::MIN],
+            // Answered before receiving
+            [sent + 10_000, sent],
+            // Held on longer than the time elapsed
+            [sent, sent + 30_000],
+            // Clocks years apart
+            [sent << 20, sent << 20],
+        ] {
+            let reply = encode(REPLY, [sent, peer_received, peer_sent]);
+            assert_eq!(local.received(&reply, now), None);
+            assert_eq!(local.estimate(), None);
+        }
+
+        // The probe is still answerable
+        let reply = encode(REPLY, [sent, sent + 10_000, sent + 10_000]);
+        local.received(&reply, now);
+        assert_eq!(local.estimate().unwrap().rtt_ms, 20.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limits_replies() {
+        let mut peer = DelayProbe::new();
+        let replies = (0..20)
+            .filter_map(|t| peer.received(&encode(REQUEST, [t, 0, 0]), t))
+            .count();
+        assert_eq!(replies, REPLY_LIMIT.burst as usize);
+    }
+}
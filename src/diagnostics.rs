@@ -0,0 +1,537 @@
+//! Record of how a connection was established, meant to be attached to
+//! support tickets, see
+//! [`ConnectOptions::diagnostics`](crate::connect::ConnectOptions::diagnostics)
+//! and [`Connection::diagnostics`](crate::connect::Connection::diagnostics).
+
+#[cfg(feature = "ice-transport")]
+use crate::sctp::SctpStats;
+use serde::{Serialize, Serializer};
+#[cfg(feature = "full")]
+use std::future::Future;
+#[cfg(any(feature = "crypto", feature = "ice-transport"))]
+use std::time::Instant;
+use std::{
+    fmt, io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+#[cfg(feature = "ice-transport")]
+use webrtc_ice::{candidate::Candidate, state::ConnectionState};
+
+/// Entries kept in each list of a [`Session`], later ones are only counted
+/// in [`Session::dropped`].
+pub const MAX_ENTRIES: usize = 256;
+
+/// Handle to a session record, clones record into the same one. The default
+/// handle is disabled and records nothing.
+#[derive(Clone, Default)]
+pub struct Diagnostics(Option<Arc<Mutex<Recorder>>>);
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics::recording(false)
+    }
+
+    fn recording(timings_only: bool) -> Diagnostics {
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        Diagnostics(Some(Arc::new(Mutex::new(Recorder {
+            #[cfg(any(feature = "crypto", feature = "ice-transport"))]
+            started: Instant::now(),
+            session: Session {
+                started_at,
+                ..Default::default()
+            },
+            timings_only,
+        }))))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|recorder| !recorder.lock().unwrap().timings_only)
+    }
+
+    #[cfg(feature = "full")]
+    /// This handle if enabled, otherwise one recording only the timings,
+    /// which are cheap and behind the metrics and
+    /// [`Connection::timings`](crate::connect::Connection::timings).
+    pub(crate) fn or_timings(self) -> Diagnostics {
+        match self.0 {
+            Some(_) => self,
+            None => Diagnostics::recording(true),
+        }
+    }
+
+    /// What was recorded so far, `None` if disabled. Only the timings if
+    /// recording was not asked for, see [`Diagnostics::or_timings`].
+    pub fn session(&self) -> Option<Session> {
+        self.0
+            .as_ref()
+            .map(|recorder| recorder.lock().unwrap().session.clone())
+    }
+
+    /// Writes [`Diagnostics::session`] as JSON, candidate addresses
+    /// redacted.
+    pub fn write_json<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        let mut session = self.session();
+        session.iter_mut().for_each(Session::redact);
+        serde_json::to_writer_pretty(writer, &session)?;
+
+        Ok(())
+    }
+
+    /// Like [`Diagnostics::write_json`] but with full candidates, which
+    /// reveal the addresses of both peers.
+    pub fn write_json_full<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(writer, &self.session())?;
+
+        Ok(())
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn role(&self, role: &'static str) {
+        self.record(|session, _| session.role = Some(role));
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn signalling_host(&self, host: Option<&str>) {
+        let host = host.map(ToOwned::to_owned);
+        self.record(|session, _| session.signalling_host = host);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn signalling_format(&self, format: &'static str) {
+        self.record(|session, _| session.signalling_format = Some(format));
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn peer_role_negotiation(&self, supported: bool) {
+        self.record(|session, _| session.peer_role_negotiation = Some(supported));
+    }
+
+    #[cfg(any(feature = "crypto", feature = "ice-transport"))]
+    /// Something that went wrong without failing the connection.
+    pub(crate) fn warning(&self, message: String) {
+        self.record(|session, at_ms| {
+            let warning = Warning { at_ms, message };
+            capped(&mut session.warnings, &mut session.dropped, warning)
+        });
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn local_candidate(&self, candidate: &dyn Candidate) {
+        let entry = CandidateEntry::new(candidate);
+        self.record(|session, at_ms| {
+            capped(
+                &mut session.local_candidates,
+                &mut session.dropped,
+                entry(at_ms),
+            )
+        });
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn remote_candidate(&self, candidate: &dyn Candidate) {
+        let entry = CandidateEntry::new(candidate);
+        self.record(|session, at_ms| {
+            capped(
+                &mut session.remote_candidates,
+                &mut session.dropped,
+                entry(at_ms),
+            )
+        });
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn selected_pair(&self, local: &dyn Candidate, remote: &dyn Candidate) {
+        let (local, remote) = (local.marshal(), remote.marshal());
+        self.record(|session, at_ms| {
+            session.selected_pair = Some(SelectedPair {
+                at_ms,
+                local,
+                remote,
+            })
+        });
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn report(&self, cipher: &'static str, sctp: SctpReport) -> DiagnosticsReport {
+        DiagnosticsReport {
+            version: env!("CARGO_PKG_VERSION"),
+            crypto_backend: crate::capabilities().crypto_backend,
+            cipher,
+            sctp,
+            session: self.session().unwrap_or_default(),
+        }
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn state(&self, state: ConnectionState) {
+        self.record(|session, at_ms| {
+            let entry = StateEntry {
+                at_ms,
+                state: state.to_string(),
+            };
+            capped(&mut session.states, &mut session.dropped, entry)
+        });
+    }
+
+    #[cfg(feature = "ice-transport")]
+    /// How long `phase` took, see [`ConnectTimings`]. Only the first time
+    /// is kept.
+    pub(crate) fn timing(&self, phase: &str, duration: Duration) {
+        if let Some(recorder) = &self.0 {
+            recorder
+                .lock()
+                .unwrap()
+                .session
+                .timings
+                .set(phase, duration);
+        }
+    }
+
+    /// What was recorded in [`Session::timings`] so far.
+    pub fn timings(&self) -> ConnectTimings {
+        self.0
+            .as_ref()
+            .map(|recorder| recorder.lock().unwrap().session.timings)
+            .unwrap_or_default()
+    }
+
+    #[cfg(feature = "full")]
+    /// Runs `future` recording how long it took and how it failed.
+    pub(crate) async fn phase<F, T, E>(&self, name: &'static str, future: F) -> Result<T, E>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: fmt::Display,
+    {
+        let started = Instant::now();
+        let r = future.await;
+        let duration = started.elapsed();
+        let duration_ms = duration.as_millis() as u64;
+        let error = r.as_ref().err().map(ToString::to_string);
+        if error.is_none() {
+            self.timing(name, duration);
+        }
+        self.record(|session, at_ms| {
+            session.phases.push(Phase {
+                name,
+                at_ms: at_ms.saturating_sub(duration_ms),
+                duration_ms,
+                error,
+            })
+        });
+
+        r
+    }
+
+    #[cfg(any(feature = "crypto", feature = "ice-transport"))]
+    fn record<F: FnOnce(&mut Session, u64)>(&self, f: F) {
+        if let Some(recorder) = &self.0 {
+            let mut recorder = recorder.lock().unwrap();
+            if recorder.timings_only {
+                return;
+            }
+            let at_ms = recorder.started.elapsed().as_millis() as u64;
+            f(&mut recorder.session, at_ms);
+        }
+    }
+}
+impl fmt::Debug for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Diagnostics")
+            .field(&self.is_enabled())
+            .finish()
+    }
+}
+
+struct Recorder {
+    #[cfg(any(feature = "crypto", feature = "ice-transport"))]
+    started: Instant,
+    session: Session,
+    /// Only [`Session::timings`] is recorded, see [`Diagnostics::or_timings`].
+    timings_only: bool,
+}
+
+/// Times are milliseconds since recording started.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Session {
+    /// Milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// Only the host, the path names the channel.
+    pub signalling_host: Option<String>,
+    /// See [`SignallingFormat`](crate::signalling::SignallingFormat).
+    pub signalling_format: Option<&'static str>,
+    /// Whether the peer announced its role in the ICE handshake, older
+    /// versions do not.
+    pub peer_role_negotiation: Option<bool>,
+    pub role: Option<&'static str>,
+    pub local_candidates: Vec<CandidateEntry>,
+    /// Received from the peer, cached ones are not included.
+    pub remote_candidates: Vec<CandidateEntry>,
+    pub selected_pair: Option<SelectedPair>,
+    /// ICE connection state transitions.
+    pub states: Vec<StateEntry>,
+    /// Steps of establishing the connection, in order.
+    pub phases: Vec<Phase>,
+    pub timings: ConnectTimings,
+    pub warnings: Vec<Warning>,
+    /// Entries left out of the lists above past [`MAX_ENTRIES`], e.g. from
+    /// a peer flooding candidates.
+    pub dropped: u64,
+}
+impl Session {
+    /// Replaces the addresses in every candidate.
+    pub fn redact(&mut self) {
+        let candidates = self
+            .local_candidates
+            .iter_mut()
+            .chain(&mut self.remote_candidates)
+            .map(|entry| &mut entry.candidate);
+        let pair = self
+            .selected_pair
+            .iter_mut()
+            .flat_map(|pair| [&mut pair.local, &mut pair.remote]);
+        for candidate in candidates.chain(pair) {
+            *candidate = redact_candidate(candidate);
+        }
+    }
+}
+
+/// Everything known about a connection, see
+/// [`Connection::diagnostics`](crate::connect::Connection::diagnostics).
+/// Never holds key material.
+#[cfg(feature = "ice-transport")]
+#[derive(Clone, Debug, Serialize)]
+pub struct DiagnosticsReport {
+    pub version: &'static str,
+    pub crypto_backend: &'static str,
+    pub cipher: &'static str,
+    pub sctp: SctpReport,
+    #[serde(flatten)]
+    pub session: Session,
+}
+#[cfg(feature = "ice-transport")]
+impl DiagnosticsReport {
+    pub fn write_json<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "ice-transport")]
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct SctpReport {
+    pub close_linger_ms: u64,
+    #[serde(flatten)]
+    pub stats: SctpStats,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CandidateEntry {
+    pub at_ms: u64,
+    /// `host`, `srflx`, `prflx` or `relay`.
+    pub kind: String,
+    pub candidate: String,
+}
+#[cfg(feature = "ice-transport")]
+impl CandidateEntry {
+    fn new(candidate: &dyn Candidate) -> impl FnOnce(u64) -> CandidateEntry {
+        let kind = candidate.candidate_type().to_string();
+        let candidate = candidate.marshal();
+
+        move |at_ms| CandidateEntry {
+            at_ms,
+            kind,
+            candidate,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SelectedPair {
+    pub at_ms: u64,
+    pub local: String,
+    pub remote: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StateEntry {
+    pub at_ms: u64,
+    pub state: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Warning {
+    pub at_ms: u64,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Phase {
+    pub name: &'static str,
+    pub at_ms: u64,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+/// How long each step of establishing a connection took, for monitoring
+/// setup times. Steps that did not run, e.g. the websocket when connecting
+/// over other signalling, are `None`. Serialized in milliseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectTimings {
+    /// Opening the websocket to the signalling server.
+    #[serde(serialize_with = "millis")]
+    pub websocket: Option<Duration>,
+    /// From the websocket being open until the server assigned the role,
+    /// i.e. until the peer joined.
+    #[serde(serialize_with = "millis")]
+    pub role: Option<Duration>,
+    #[serde(serialize_with = "millis")]
+    pub agreement: Option<Duration>,
+    /// Gathering local candidates, which overlaps the other ICE steps.
+    #[serde(serialize_with = "millis")]
+    pub gathering: Option<Duration>,
+    /// From starting connectivity checks until a candidate pair succeeded.
+    #[serde(serialize_with = "millis")]
+    pub ice: Option<Duration>,
+    #[serde(serialize_with = "millis")]
+    pub sctp: Option<Duration>,
+    /// From calling connect until the connection was returned.
+    #[serde(serialize_with = "millis")]
+    pub total: Option<Duration>,
+}
+impl ConnectTimings {
+    /// Short name and duration of each step that ran, in order.
+    pub fn steps(&self) -> impl Iterator<Item = (&'static str, Duration)> {
+        [
+            ("ws", self.websocket),
+            ("role", self.role),
+            ("agree", self.agreement),
+            ("gather", self.gathering),
+            ("ice", self.ice),
+            ("sctp", self.sctp),
+            ("total", self.total),
+        ]
+        .into_iter()
+        .filter_map(|(name, duration)| Some((name, duration?)))
+    }
+
+    #[cfg(feature = "ice-transport")]
+    /// Keyed by the name of the [`Phase`] measuring it.
+    fn set(&mut self, phase: &str, duration: Duration) {
+        let timing = match phase {
+            "websocket" => &mut self.websocket,
+            "role assignment" => &mut self.role,
+            "agreement" => &mut self.agreement,
+            "gathering" => &mut self.gathering,
+            "ice" => &mut self.ice,
+            "sctp" => &mut self.sctp,
+            "total" => &mut self.total,
+            _ => return,
+        };
+        timing.get_or_insert(duration);
+    }
+}
+/// `ws=120ms role=5ms agree=310ms gather=850ms ice=1.9s sctp=60ms total=3.2s`
+impl fmt::Display for ConnectTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, duration)) in self.steps().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            match duration < Duration::from_secs(1) {
+                true => write!(f, "{name}={}ms", duration.as_millis())?,
+                false => write!(f, "{name}={:.1}s", duration.as_secs_f64())?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn millis<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    duration
+        .map(|duration| duration.as_millis() as u64)
+        .serialize(serializer)
+}
+
+#[cfg(any(feature = "crypto", feature = "ice-transport"))]
+fn capped<T>(list: &mut Vec<T>, dropped: &mut u64, entry: T) {
+    match list.len() < MAX_ENTRIES {
+        true => list.push(entry),
+        false => *dropped += 1,
+    }
+}
+
+/// Candidates are `foundation component protocol priority address port typ
+/// type`, optionally followed by `raddr address rport port` and extensions.
+fn redact_candidate(candidate: &str) -> String {
+    let mut fields: Vec<&str> = candidate.split(' ').collect();
+    if let Some(address) = fields.get_mut(4) {
+        *address = REDACTED;
+    }
+    if let Some(raddr) = fields.iter().position(|field| *field == "raddr") {
+        if let Some(address) = fields.get_mut(raddr + 1) {
+            *address = REDACTED;
+        }
+    }
+
+    fields.join(" ")
+}
+
+const REDACTED: &str = "<redacted>";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timings_line() {
+        let timings = ConnectTimings {
+            websocket: Some(Duration::from_millis(120)),
+            agreement: Some(Duration::from_millis(310)),
+            ice: Some(Duration::from_millis(1940)),
+            total: Some(Duration::from_millis(3210)),
+            ..Default::default()
+        };
+        assert_eq!(
+            timings.to_string(),
+            "ws=120ms agree=310ms ice=1.9s total=3.2s"
+        );
+
+        let json = serde_json::to_value(timings).unwrap();
+        assert_eq!(json["websocket"], 120);
+        assert!(json["role"].is_null());
+    }
+
+    #[test]
+    fn capped_lists() {
+        let diagnostics = Diagnostics::new();
+        for i in 0..MAX_ENTRIES + 3 {
+            diagnostics.warning(i.to_string());
+        }
+        let session = diagnostics.session().unwrap();
+        assert_eq!(session.warnings.len(), MAX_ENTRIES);
+        assert_eq!(session.warnings.last().unwrap().message, "255");
+        assert_eq!(session.dropped, 3);
+    }
+
+    #[test]
+    fn redacts_addresses() {
+        assert_eq!(
+            redact_candidate("1 1 udp 2130706431 192.168.1.5 50000 typ host"),
+            "1 1 udp 2130706431 <redacted> 50000 typ host"
+        );
+        assert_eq!(
+            redact_candidate(
+                "2 1 udp 1694498815 203.0.113.7 6000 typ srflx raddr 10.0.0.2 rport 50000"
+            ),
+            "2 1 udp 1694498815 <redacted> 6000 typ srflx raddr <redacted> rport 50000"
+        );
+    }
+}
@@ -0,0 +1,576 @@
+//! Checks of what a connection needs from the environment, for failures that
+//! are not icepipe's: name resolution of the signalling server, reaching it
+//! over TCP and TLS, STUN and TURN servers answering over UDP and the clock,
+//! see [`run`].
+//!
+//! STUN answers carry no time, the clock is compared with the `Date` header
+//! the signalling server answers the upgrade with.
+
+use crate::{
+    connect::{ice_urls, ConnectOptions},
+    crypto_backend,
+    ice::resolve_urls,
+    resolver::resolve,
+    ws::parse_role,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::Serialize;
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::watch,
+};
+use tokio_tungstenite::{
+    client_async_tls,
+    tungstenite::{self, Message},
+};
+use turn::client::{Client, ClientConfig};
+use webrtc_ice::url::{ProtoType, SchemeType, Url};
+use webrtc_util::Conn;
+
+/// Longest any single check waits.
+pub const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Clock skew warned about, channel hopping joins the wrong channels past
+/// its window.
+pub const CLOCK_SKEW_WARN: Duration = Duration::from_secs(30);
+/// Clock skew failed on.
+pub const CLOCK_SKEW_FAIL: Duration = Duration::from_secs(300);
+
+/// Outcome of a [`Check`], ordered from best to worst.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    /// Works but may cause trouble, or could not be checked.
+    Warn,
+    Fail,
+}
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    /// What was found, or why it failed.
+    pub detail: String,
+    /// What to try, for warnings and failures.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+impl Check {
+    fn pass(name: impl Into<String>, detail: impl Into<String>) -> Check {
+        Check {
+            name: name.into(),
+            status: Status::Pass,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn warn(name: impl Into<String>, detail: impl Into<String>, fix: impl Into<String>) -> Check {
+        Check {
+            status: Status::Warn,
+            fix: Some(fix.into()),
+            ..Check::pass(name, detail)
+        }
+    }
+
+    fn fail(name: impl Into<String>, detail: impl Into<String>, fix: impl Into<String>) -> Check {
+        Check {
+            status: Status::Fail,
+            ..Check::warn(name, detail, fix)
+        }
+    }
+}
+
+/// Checks in the order they ran, see [`run`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+impl Report {
+    /// The worst of the checks.
+    pub fn status(&self) -> Status {
+        self.checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(Status::Pass)
+    }
+
+    pub fn write_json<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+
+        Ok(())
+    }
+
+    fn push(&mut self, check: Check) {
+        self.checks.push(check);
+    }
+}
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "[{}] {}: {}", check.status, check.name, check.detail)?;
+            if let Some(fix) = &check.fix {
+                writeln!(f, "       {fix}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the signalling server and the ICE servers `options` connect with,
+/// the default ones where none are given. No peer is involved, the
+/// signalling server is joined twice on a random channel, pairing with
+/// itself, and left at once.
+pub async fn run(options: &ConnectOptions) -> Report {
+    let mut report = Report::default();
+    signalling(options, &mut report).await;
+    ice(options, &mut report).await;
+
+    report
+}
+
+async fn signalling(options: &ConnectOptions, report: &mut Report) {
+    let url = match options.signalling_url() {
+        Ok(url) => url,
+        Err(e) => {
+            return report.push(Check::fail(
+                "signalling url",
+                e.to_string(),
+                "Use a ws:// or wss:// signalling server",
+            ))
+        }
+    };
+    let host = url.host_str().unwrap_or_default().to_owned();
+    let port = url.port_or_known_default().unwrap_or_default();
+
+    let addrs = match timed(resolve(options.resolver.as_ref(), &host)).await {
+        Ok((addrs, _)) => addrs
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            return report.push(Check::fail(
+                "dns",
+                format!("resolving {host}: {e}"),
+                "Check the DNS resolver, or give the signalling server by address",
+            ))
+        }
+    };
+    report.push(Check::pass(
+        "dns",
+        format!("{host} resolved to {}", list(&addrs)),
+    ));
+
+    let mut connected = Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+    for addr in &addrs {
+        connected = timed(TcpStream::connect(addr))
+            .await
+            .map(|(_, at)| (addr, at));
+        if connected.is_ok() {
+            break;
+        }
+    }
+    match connected {
+        Ok((addr, at)) => report.push(Check::pass(
+            "tcp",
+            format!("connected to {addr} in {} ms", at.as_millis()),
+        )),
+        Err(e) => {
+            return report.push(Check::fail(
+                "tcp",
+                format!("connecting to {host} port {port}: {e}"),
+                format!("A firewall or proxy may block outbound TCP to port {port}"),
+            ))
+        }
+    }
+
+    // Joined twice on a channel nobody else uses, the server pairs the two
+    let mut channel = [0; 16];
+    let _ = crypto_backend::fill_random(&mut channel);
+    let channel = channel
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    let joined = url.join(&channel).map_err(io::Error::other);
+    let join = || async {
+        let joined = joined
+            .as_ref()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        timed(async {
+            let tcp = TcpStream::connect(&addrs[..]).await?;
+            client_async_tls(joined.clone(), tcp).await
+        })
+        .await
+    };
+    let handshakes = match join().await {
+        Ok(first) => join().await.map(|second| (first, second)),
+        Err(e) => Err(e),
+    };
+    let (((mut first, response), at), ((mut second, _), _)) = match handshakes {
+        Ok(handshakes) => handshakes,
+        Err(e) => {
+            let fix = match url.scheme() {
+                "wss" => "Check the server certificate is valid for the host and trusted by the system, a proxy intercepting TLS is not",
+                _ => "Check the URL is that of an icepipe signalling server",
+            };
+            return report.push(Check::fail(
+                "signalling",
+                format!("{} handshake with {host}: {e}", url.scheme()),
+                fix,
+            ));
+        }
+    };
+    let roles =
+        timed(async { io::Result::Ok((role(&mut first).await?, role(&mut second).await?)) }).await;
+    let _ = first.close(None).await;
+    let _ = second.close(None).await;
+    match roles {
+        Ok(((first, second), _)) if first != second => report.push(Check::pass(
+            "signalling",
+            format!(
+                "{} handshake with {host} in {} ms, paired with itself",
+                url.scheme(),
+                at.as_millis()
+            ),
+        )),
+        Ok(_) => report.push(Check::fail(
+            "signalling",
+            format!("{host} assigned the same role to both sides"),
+            "Check the URL is that of an icepipe signalling server",
+        )),
+        Err(e) => report.push(Check::fail(
+            "signalling",
+            format!("no role assigned by {host}: {e}"),
+            "Check the URL is that of an icepipe signalling server",
+        )),
+    }
+
+    let date = response
+        .headers()
+        .get("date")
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| httpdate::parse_http_date(date).ok());
+    report.push(clock(date, SystemTime::now()));
+}
+
+/// Whether the server made `ws` the dialer.
+async fn role<S>(ws: &mut S) -> io::Result<bool>
+where
+    S: futures::Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    match ws.next().await {
+        Some(Ok(Message::Text(msg))) => parse_role(&msg).map_err(io::Error::other),
+        Some(Ok(msg)) => Err(io::Error::other(format!("unexpected {msg:?}"))),
+        Some(Err(e)) => Err(io::Error::other(e)),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Compares the local clock with `server`, at a second of precision.
+fn clock(server: Option<SystemTime>, local: SystemTime) -> Check {
+    let Some(server) = server else {
+        return Check::warn(
+            "clock",
+            "the signalling server sent no date to compare with",
+            "Make sure the clock is synchronized, e.g. with NTP",
+        );
+    };
+
+    let (skew, direction) = match local.duration_since(server) {
+        Ok(skew) => (skew, "ahead of"),
+        Err(e) => (e.duration(), "behind"),
+    };
+    let detail = format!("{} s {direction} the signalling server", skew.as_secs());
+    let fix = "Synchronize the clock, e.g. with NTP, channel hopping relies on it";
+    if skew >= CLOCK_SKEW_FAIL {
+        Check::fail("clock", detail, fix)
+    } else if skew >= CLOCK_SKEW_WARN {
+        Check::warn("clock", detail, fix)
+    } else {
+        Check::pass("clock", detail)
+    }
+}
+
+async fn ice(options: &ConnectOptions, report: &mut Report) {
+    let urls = match ice_urls(options.ice_servers.clone(), options.ice.clone()) {
+        Ok(urls) => match &options.resolver {
+            Some(resolver) => resolve_urls(urls, resolver).await,
+            None => urls,
+        },
+        Err(e) => {
+            return report.push(Check::fail(
+                "ice servers",
+                e.to_string(),
+                "Servers are given as stun:host:port or turn:host:port with credentials",
+            ))
+        }
+    };
+
+    let (mut checked, mut answered, mut ports) = (0, 0, Vec::new());
+    for url in &urls {
+        let name = format!("{} {}:{}", url.scheme, url.host, url.port);
+        let secure = matches!(url.scheme, SchemeType::Stuns | SchemeType::Turns);
+        if secure || url.proto != ProtoType::Udp {
+            report.push(Check::warn(
+                name,
+                "not checked, only plain UDP servers are",
+                "Add a server over plain UDP to check the network with",
+            ));
+            continue;
+        }
+
+        let server = match Server::bind(url).await {
+            Ok(server) => server,
+            Err(e) => {
+                report.push(Check::fail(
+                    name,
+                    e.to_string(),
+                    "Check the server name resolves, see the dns check",
+                ));
+                continue;
+            }
+        };
+        checked += 1;
+        ports.push(server.local_port);
+        match timed(server.client.send_binding_request()).await {
+            Ok((mapped, at)) => {
+                answered += 1;
+                report.push(Check::pass(
+                    name.clone(),
+                    format!("mapped to {mapped} in {} ms", at.as_millis()),
+                ));
+            }
+            Err(e) => {
+                report.push(Check::fail(
+                    name,
+                    format!("no binding: {e}"),
+                    format!(
+                        "The server may be down, or UDP to port {} is blocked",
+                        url.port
+                    ),
+                ));
+                server.close().await;
+                continue;
+            }
+        }
+
+        if url.scheme == SchemeType::Turn && !url.username.is_empty() {
+            report.push(match timed(server.client.allocate()).await {
+                Ok((relay, at)) => {
+                    let detail = match relay.local_addr() {
+                        Ok(addr) => format!("relayed at {addr} in {} ms", at.as_millis()),
+                        Err(_) => format!("relayed in {} ms", at.as_millis()),
+                    };
+                    let _ = relay.close().await;
+                    Check::pass(format!("{name} allocation"), detail)
+                }
+                Err(e) => Check::fail(
+                    format!("{name} allocation"),
+                    e.to_string(),
+                    "Check the username and credential of the TURN server",
+                ),
+            });
+        }
+        server.close().await;
+    }
+
+    report.push(match (checked, answered) {
+        (0, _) => Check::warn(
+            "udp egress",
+            "no STUN or TURN server over UDP could be checked",
+            "Add a STUN server over plain UDP",
+        ),
+        (_, 0) => Check::fail(
+            "udp egress",
+            format!(
+                "no answer from {checked} servers from ports {}",
+                list(&ports)
+            ),
+            "Outbound UDP seems blocked, allow it to high ports or connect from another network",
+        ),
+        (_, answered) => Check::pass(
+            "udp egress",
+            format!(
+                "{answered} of {checked} servers answered from ports {}",
+                list(&ports)
+            ),
+        ),
+    });
+}
+
+/// A STUN and TURN client on a socket of its own.
+struct Server {
+    client: Client,
+    conn: Arc<ClosableConn>,
+    local_port: u16,
+}
+impl Server {
+    async fn bind(url: &Url) -> Result<Server, turn::Error> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let local_port = socket.local_addr()?.port();
+        let conn = Arc::new(ClosableConn::new(socket));
+        let addr = format!("{}:{}", url.host, url.port);
+        let turn = url.scheme == SchemeType::Turn;
+        let client = Client::new(ClientConfig {
+            stun_serv_addr: addr.clone(),
+            turn_serv_addr: if turn { addr } else { String::new() },
+            username: url.username.clone(),
+            password: url.password.clone(),
+            realm: String::new(),
+            software: String::new(),
+            rto_in_ms: 0,
+            conn: conn.clone(),
+            vnet: None,
+        })
+        .await?;
+        client.listen().await?;
+
+        Ok(Server {
+            client,
+            conn,
+            local_port,
+        })
+    }
+
+    async fn close(self) {
+        let _ = self.client.close().await;
+        let _ = self.conn.close().await;
+    }
+}
+
+/// The client reads its socket on a task of its own until reading fails,
+/// which a plain socket never does once closed.
+struct ClosableConn {
+    socket: UdpSocket,
+    closed: watch::Sender<bool>,
+}
+impl ClosableConn {
+    fn new(socket: UdpSocket) -> ClosableConn {
+        ClosableConn {
+            socket,
+            closed: watch::channel(false).0,
+        }
+    }
+}
+#[async_trait]
+impl Conn for ClosableConn {
+    async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+        Ok(self.socket.connect(addr).await?)
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        Ok(self.recv_from(buf).await?.0)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        let mut closed = self.closed.subscribe();
+        tokio::select! {
+            received = self.socket.recv_from(buf) => Ok(received?),
+            _ = closed.wait_for(|closed| *closed) => {
+                Err(io::Error::from(io::ErrorKind::ConnectionAborted).into())
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        Ok(self.socket.send(buf).await?)
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        Ok(self.socket.send_to(buf, target).await?)
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.socket.peer_addr().ok()
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        self.closed.send_replace(true);
+        Ok(())
+    }
+}
+
+/// `future` bounded by [`CHECK_TIMEOUT`], along with how long it took.
+async fn timed<T, E, F>(future: F) -> io::Result<(T, Duration)>
+where
+    F: Future<Output = Result<T, E>>,
+    E: fmt::Display,
+{
+    let started = Instant::now();
+    match tokio::time::timeout(CHECK_TIMEOUT, future).await {
+        Ok(Ok(value)) => Ok((value, started.elapsed())),
+        Ok(Err(e)) => Err(io::Error::other(e.to_string())),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no answer in {} s", CHECK_TIMEOUT.as_secs()),
+        )),
+    }
+}
+
+fn list<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_skew() {
+        let now = SystemTime::now();
+        let check = clock(Some(now - Duration::from_secs(2)), now);
+        assert_eq!(check.status, Status::Pass);
+        assert_eq!(check.detail, "2 s ahead of the signalling server");
+
+        let check = clock(Some(now + Duration::from_secs(60)), now);
+        assert_eq!(check.status, Status::Warn);
+        assert_eq!(check.detail, "60 s behind the signalling server");
+
+        assert_eq!(clock(Some(now + CLOCK_SKEW_FAIL), now).status, Status::Fail);
+        assert_eq!(clock(None, now).status, Status::Warn);
+    }
+
+    #[test]
+    fn worst_status() {
+        let mut report = Report::default();
+        assert_eq!(report.status(), Status::Pass);
+        report.push(Check::warn("clock", "skewed", "sync it"));
+        report.push(Check::pass("dns", "resolved"));
+        assert_eq!(report.status(), Status::Warn);
+        report.push(Check::fail("tcp", "refused", "open it"));
+        assert_eq!(report.status(), Status::Fail);
+
+        assert_eq!(
+            report.to_string(),
+            "[warn] clock: skewed\n       sync it\n[pass] dns: resolved\n[fail] tcp: refused\n       open it\n"
+        );
+    }
+}
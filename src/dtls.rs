@@ -0,0 +1,211 @@
+//! DTLS layer, the standard alternative to the ChaCha20 one selected with
+//! [`Encryption::Dtls`](crate::connect::Encryption::Dtls). SCTP runs on top
+//! of it as in WebRTC.
+//!
+//! Every session generates its own self-signed certificate. Instead of
+//! deriving traffic keys, the agreed basekey authenticates the certificate
+//! fingerprints exchanged through signalling, and the DTLS handshake only
+//! accepts the announced peer certificate.
+
+use crate::{
+    crypto_backend,
+    error::TimeoutError,
+    signalling::{Rejection, SignalingError, Signalling},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use std::{io, sync::Arc};
+use webrtc_dtls::{
+    config::{ClientAuthType, Config, ExtendedMasterSecretType},
+    conn::DTLSConn,
+    crypto::Certificate,
+};
+use webrtc_util::Conn;
+
+const HELLO: &str = "icepipe-dtls";
+
+/// Certificate of this side for a single session.
+pub struct DtlsIdentity {
+    certificate: Certificate,
+    fingerprint: String,
+}
+impl DtlsIdentity {
+    pub fn generate() -> DtlsResult<DtlsIdentity> {
+        let certificate = Certificate::generate_self_signed(vec!["icepipe".to_owned()])?;
+        let fingerprint = fingerprint(&certificate.certificate[0].0);
+
+        Ok(DtlsIdentity {
+            certificate,
+            fingerprint,
+        })
+    }
+
+    /// `sha-256 AB:CD:...`, as announced in SDP.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Sends the fingerprint to the peer and returns the one of the peer once
+    /// its HMAC, keyed by the agreed `basekey`, checks. Roles are only
+    /// settled later, so both directions share the key, reflecting a
+    /// fingerprint back is useless without its private key.
+    pub async fn exchange<S>(&self, signalling: &mut S, basekey: &[u8]) -> DtlsResult<String>
+    where
+        S: Signalling,
+        S::Error: Into<SignalingError>,
+    {
+        let mac = crypto_backend::hmac_sha512_sign(&mac_key(basekey), self.fingerprint.as_bytes());
+        signalling
+            .send(format!(
+                "{HELLO}\0{}\0{}",
+                self.fingerprint,
+                BASE64_STANDARD.encode(mac)
+            ))
+            .await
+            .map_err(Into::into)?;
+
+        let message = recv(signalling, basekey).await?;
+        let (fingerprint, mac) = match message.split('\0').collect::<Vec<_>>()[..] {
+            [HELLO, fingerprint, mac] => (fingerprint, mac),
+            _ => return Err(DtlsError::NotNegotiated),
+        };
+        let mac = BASE64_STANDARD
+            .decode(mac)
+            .map_err(|_| DtlsError::BadFingerprint)?;
+        crypto_backend::hmac_sha512_verify(&mac_key(basekey), fingerprint.as_bytes(), &mac)
+            .map_err(|_| DtlsError::BadFingerprint)?;
+
+        Ok(fingerprint.to_owned())
+    }
+
+    /// Runs the handshake over the ICE connection, the dialer being the
+    /// client. The peer must present the certificate of `peer_fingerprint`.
+    pub async fn handshake(
+        self,
+        conn: Arc<dyn Conn + Send + Sync>,
+        peer_fingerprint: String,
+        dialer: bool,
+    ) -> DtlsResult<Arc<dyn Conn + Send + Sync>> {
+        let config = Config {
+            certificates: vec![self.certificate],
+            // Self-signed, the fingerprint check below replaces the chain
+            insecure_skip_verify: true,
+            client_auth: ClientAuthType::RequireAnyClientCert,
+            extended_master_secret: ExtendedMasterSecretType::Require,
+            verify_peer_certificate: Some(Arc::new(move |certificates: &[Vec<u8>], _: &[_]| {
+                match certificates.first() {
+                    Some(der) if fingerprint(der) == peer_fingerprint => Ok(()),
+                    _ => Err(webrtc_dtls::Error::ErrVerifyDataMismatch),
+                }
+            })),
+            ..Default::default()
+        };
+
+        Ok(Arc::new(DTLSConn::new(conn, config, dialer, None).await?))
+    }
+}
+
+/// SDP style fingerprint of a DER encoded certificate.
+pub fn fingerprint(der: &[u8]) -> String {
+    let hex = Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":");
+
+    format!("sha-256 {hex}")
+}
+
+fn mac_key(basekey: &[u8]) -> [u8; 64] {
+    let mut key = [0; 64];
+    crypto_backend::hkdf_sha512(b"dtls", basekey, b"fingerprint", &mut key);
+
+    key
+}
+
+async fn recv<S>(signalling: &mut S, basekey: &[u8]) -> DtlsResult<String>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    let rejection = Rejection::new(basekey);
+    loop {
+        let mut value = signalling.wait().await.map_err(Into::into)?;
+        if let Some(message) = signalling.then(&mut value).await.map_err(Into::into)? {
+            rejection.check(&message)?;
+            return Ok(message);
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DtlsError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Timeout(#[from] TimeoutError),
+    #[error(transparent)]
+    SignalingError(SignalingError),
+    #[error(transparent)]
+    WebrtcDtlsError(webrtc_dtls::Error),
+    #[error("Peer did not negotiate DTLS, both sides must use the same encryption")]
+    NotNegotiated,
+    #[error("Peer DTLS fingerprint failed authentication")]
+    BadFingerprint,
+}
+impl From<SignalingError> for DtlsError {
+    fn from(value: SignalingError) -> Self {
+        match value {
+            SignalingError::Io(e) => e.into(),
+            SignalingError::Timeout(e) => e.into(),
+            e @ SignalingError::ProtocolError(_) => Self::SignalingError(e),
+        }
+    }
+}
+impl From<webrtc_dtls::Error> for DtlsError {
+    fn from(value: webrtc_dtls::Error) -> Self {
+        match value {
+            webrtc_dtls::Error::Io(e) => e.0.into(),
+            e => Self::WebrtcDtlsError(e),
+        }
+    }
+}
+pub type DtlsResult<T> = Result<T, DtlsError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_signalling::MemorySignalling;
+
+    #[test]
+    fn fingerprint_format() {
+        let fingerprint = fingerprint(b"certificate");
+        let (hash, hex) = fingerprint.split_once(' ').unwrap();
+        assert_eq!(hash, "sha-256");
+        assert_eq!(hex.split(':').count(), 32);
+        assert!(hex.split(':').all(|b| b.len() == 2));
+    }
+
+    #[tokio::test]
+    async fn exchange_authenticates_fingerprint() {
+        let (mut a, mut b) = MemorySignalling::pair();
+        let (a_identity, b_identity) = (
+            DtlsIdentity::generate().unwrap(),
+            DtlsIdentity::generate().unwrap(),
+        );
+
+        let (a_peer, b_peer) = tokio::join!(
+            a_identity.exchange(&mut a, b"basekey"),
+            b_identity.exchange(&mut b, b"basekey"),
+        );
+        assert_eq!(a_peer.unwrap(), b_identity.fingerprint());
+        assert_eq!(b_peer.unwrap(), a_identity.fingerprint());
+
+        let (a_peer, b_peer) = tokio::join!(
+            a_identity.exchange(&mut a, b"basekey"),
+            b_identity.exchange(&mut b, b"other basekey"),
+        );
+        assert!(matches!(a_peer, Err(DtlsError::BadFingerprint)));
+        assert!(matches!(b_peer, Err(DtlsError::BadFingerprint)));
+    }
+}
@@ -6,3 +6,20 @@ impl From<TimeoutError> for std::io::Error {
         std::io::Error::new(std::io::ErrorKind::TimedOut, value)
     }
 }
+
+/// The session transferred all a
+/// [`TrafficLimit`](crate::traffic_limit::TrafficLimit) allows.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[error("Traffic limit of {hard} bytes exceeded")]
+pub struct TrafficLimitExceeded {
+    pub hard: u64,
+}
+
+/// A close outlived its
+/// [`SctpConfig::close_timeout`](crate::sctp::SctpConfig::close_timeout), the
+/// association was torn down without waiting for the peer.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+#[error("Close did not finish within {after:?}, dropped the association")]
+pub struct ClosedDirty {
+    pub after: std::time::Duration,
+}
@@ -0,0 +1,250 @@
+//! Non-fatal events of a connection, otherwise only visible in the log, see
+//! [`ConnectOptions::events`](crate::connect::ConnectOptions::events) and
+//! [`Connection::events`](crate::connect::Connection::events).
+//!
+//! Events are broadcast, emitting never blocks. A receiver lagging more than
+//! [`CAPACITY`] events behind skips the oldest ones, see
+//! [`broadcast::error::RecvError::Lagged`].
+
+use std::{fmt, time::Duration};
+use tokio::sync::broadcast;
+
+/// Events kept for the slowest receiver.
+pub const CAPACITY: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// A remote candidate was not handed to ICE.
+    CandidateDiscarded { candidate: String, reason: String },
+    /// ICE selected the pair carrying the connection, candidates as sent
+    /// over the signalling channel. Displayed as the candidate types only,
+    /// keeping the addresses out of logs.
+    PairSelected { local: String, remote: String },
+    /// The signalling channel dropped while exchanging candidates and is
+    /// being re-established.
+    SignallingReconnecting { error: String, remaining: u32 },
+    /// A message arrived out of sequence below the encryption layer. It was
+    /// dropped if `received` is below `expected`, the ones in between were
+    /// lost otherwise, see
+    /// [`DesyncPolicy`](crate::crypto_stream::DesyncPolicy).
+    NonceDesync { expected: u64, received: u64 },
+    /// ICE is still connecting, emitted when something changed, at most
+    /// every [`PROGRESS_INTERVAL`](crate::ice::PROGRESS_INTERVAL).
+    IceProgress {
+        /// Gathered so far.
+        local: CandidateCounts,
+        /// Received from the peer, dropped ones included.
+        remote: usize,
+        /// Pairs waiting for or being checked.
+        checking: usize,
+        /// `waiting`, `in-progress`, `succeeded` or `failed`, of the most
+        /// advanced pair. `None` before any pair is formed.
+        best_pair: Option<String>,
+    },
+    /// The peer asked this side to close within `deadline`, see
+    /// [`ShutdownRequest`](crate::pipe_stream::ShutdownRequest).
+    ShutdownRequested { deadline: Duration },
+    /// [`probe_mtu`](crate::sctp::Sctp::probe_mtu) found the path carries
+    /// UDP payloads of `max_payload` bytes at most, below
+    /// [`PACKET_SIZE`](crate::sctp::PACKET_SIZE). Fragmented packets are
+    /// dropped by some NATs, stalling the connection.
+    PathFragments { max_payload: usize },
+    /// ICE disconnected, the stream ends unless it recovers within `grace`,
+    /// see [`SctpConfig::disconnect_grace`](crate::sctp::SctpConfig::disconnect_grace).
+    Degraded { grace: Duration },
+    /// ICE reconnected `after` being [`Degraded`](ConnectionEvent::Degraded).
+    Recovered { after: Duration },
+}
+
+/// Candidates by type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CandidateCounts {
+    pub host: usize,
+    pub srflx: usize,
+    pub prflx: usize,
+    pub relay: usize,
+}
+impl CandidateCounts {
+    pub fn total(&self) -> usize {
+        self.host + self.srflx + self.prflx + self.relay
+    }
+}
+
+impl fmt::Display for ConnectionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionEvent::CandidateDiscarded { candidate, reason } => {
+                write!(f, "Remote candidate {candidate} discarded: {reason}")
+            }
+            ConnectionEvent::PairSelected { local, remote } => write!(
+                f,
+                "Selected pair {} <-> {}",
+                candidate_type(local),
+                candidate_type(remote)
+            ),
+            ConnectionEvent::SignallingReconnecting { error, remaining } => write!(
+                f,
+                "Signalling lost, reconnecting ({remaining} attempts left): {error}"
+            ),
+            ConnectionEvent::NonceDesync { expected, received } => write!(
+                f,
+                "Nonce desync, expected {expected} but received {received}"
+            ),
+            ConnectionEvent::IceProgress {
+                local,
+                remote,
+                checking,
+                best_pair,
+            } => {
+                let kinds: Vec<_> = [
+                    (local.host, "host"),
+                    (local.srflx, "srflx"),
+                    (local.prflx, "prflx"),
+                    (local.relay, "relay"),
+                ]
+                .into_iter()
+                .filter(|(n, _)| *n > 0)
+                .map(|(n, kind)| format!("{n} {kind}"))
+                .collect();
+                write!(f, "gathering {} local", local.total())?;
+                if !kinds.is_empty() {
+                    write!(f, " ({})", kinds.join(", "))?;
+                }
+                write!(
+                    f,
+                    " / {remote} remote candidates, checking {checking} pairs"
+                )?;
+                match best_pair {
+                    Some(state) => write!(f, ", best {state}…"),
+                    None => write!(f, "…"),
+                }
+            }
+            ConnectionEvent::ShutdownRequested { deadline } => {
+                write!(f, "Peer asked to close within {deadline:?}")
+            }
+            ConnectionEvent::PathFragments { max_payload } => write!(
+                f,
+                "Path carries UDP payloads of {max_payload} bytes at most, larger SCTP packets are fragmented"
+            ),
+            ConnectionEvent::Degraded { grace } => {
+                write!(f, "Connection lost, waiting {grace:?} for it to recover")
+            }
+            ConnectionEvent::Recovered { after } => {
+                write!(f, "Connection recovered after {after:?}")
+            }
+        }
+    }
+}
+
+/// The `typ` attribute of a candidate, without its address.
+fn candidate_type(candidate: &str) -> &str {
+    let mut fields = candidate.split_whitespace();
+    fields
+        .by_ref()
+        .find(|field| *field == "typ")
+        .and_then(|_| fields.next())
+        .unwrap_or("unknown")
+}
+
+/// Handle to an event bus, clones emit to the same receivers. The default
+/// handle is disabled and emits nothing.
+#[derive(Clone, Debug, Default)]
+pub struct Events(Option<broadcast::Sender<ConnectionEvent>>);
+impl Events {
+    pub fn new() -> Events {
+        Events(Some(broadcast::channel(CAPACITY).0))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    #[cfg(feature = "full")]
+    /// This handle if enabled, a new one otherwise.
+    pub(crate) fn or_new(self) -> Events {
+        match self.is_enabled() {
+            true => self,
+            false => Events::new(),
+        }
+    }
+
+    /// Receives the events emitted from now on, a disabled handle never
+    /// emits any.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        match &self.0 {
+            Some(tx) => tx.subscribe(),
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    #[cfg(any(feature = "crypto", feature = "ice-transport"))]
+    pub(crate) fn emit(&self, event: ConnectionEvent) {
+        if let Some(tx) = &self.0 {
+            // Fails only without receivers
+            let _ = tx.send(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::error::RecvError;
+
+    fn event(received: u64) -> ConnectionEvent {
+        ConnectionEvent::NonceDesync {
+            expected: 0,
+            received,
+        }
+    }
+
+    #[test]
+    fn lagging_receiver_skips() {
+        let events = Events::new();
+        let mut rx = events.subscribe();
+        for i in 0..CAPACITY as u64 + 2 {
+            events.emit(event(i));
+        }
+
+        assert_eq!(
+            rx.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(2))
+        );
+        assert_eq!(rx.try_recv(), Ok(event(2)));
+    }
+
+    #[test]
+    fn progress_line() {
+        let progress = ConnectionEvent::IceProgress {
+            local: CandidateCounts {
+                host: 2,
+                srflx: 1,
+                ..Default::default()
+            },
+            remote: 2,
+            checking: 4,
+            best_pair: None,
+        };
+        assert_eq!(
+            progress.to_string(),
+            "gathering 3 local (2 host, 1 srflx) / 2 remote candidates, checking 4 pairs…"
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_emits_nothing() {
+        let events = Events::default();
+        let mut rx = events.subscribe();
+        events.emit(event(1));
+        assert_eq!(rx.recv().await, Err(RecvError::Closed));
+    }
+
+    #[test]
+    fn pair_selected_hides_addresses() {
+        let event = ConnectionEvent::PairSelected {
+            local: "1 1 udp 2130706431 192.168.1.2 5000 typ host".into(),
+            remote: "2 1 udp 1694498815 203.0.113.7 6000 typ srflx raddr 0.0.0.0 rport 0".into(),
+        };
+        assert_eq!(event.to_string(), "Selected pair host <-> srflx");
+    }
+}
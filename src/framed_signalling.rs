@@ -0,0 +1,263 @@
+//! Self-delimiting signalling messages, for servers that do not keep one
+//! message per frame, e.g. concatenating messages sent in quick succession
+//! or trimming whitespace. See [`SignallingFraming`].
+//!
+//! A framed message is `#<length>:<message>;`, the length in bytes. Frames
+//! received back to back are split, whitespace around them is skipped, and
+//! the `;` closing each one keeps a message ending with whitespace, like an
+//! SDP, intact on servers trimming what they relay. No message sent raw
+//! starts with `#`, frames and raw messages are told apart by it.
+
+#[cfg(feature = "ice-transport")]
+use crate::ice::Reconnect;
+use crate::{
+    logging,
+    pipe_stream::WaitThen,
+    signalling::{SignalingError, Signalling},
+    trace,
+};
+use futures::{
+    future::{Either, LocalBoxFuture},
+    FutureExt,
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+const TAG: char = '#';
+const END: char = ';';
+
+/// How signalling messages are delimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignallingFraming {
+    /// One message per frame of the signalling server. Switches to framing
+    /// once the peer frames, so a peer set to
+    /// [`Delimited`](SignallingFraming::Delimited) is understood.
+    #[default]
+    Raw,
+    /// Every message framed from the first one, needed on both sides when
+    /// the server mangles messages. Versions without framing do not
+    /// understand them.
+    Delimited,
+}
+impl SignallingFraming {
+    pub fn name(self) -> &'static str {
+        match self {
+            SignallingFraming::Raw => "raw",
+            SignallingFraming::Delimited => "delimited",
+        }
+    }
+}
+
+/// Frames what is sent through `signalling` once framing is on, and splits
+/// what is received when the peer frames.
+pub struct FramedSignalling<S> {
+    signalling: S,
+    /// Shared with the signalling reconnected to, see
+    /// [`FramedSignalling::reconnect`].
+    framed: Arc<AtomicBool>,
+    /// Split off a frame holding several messages.
+    queued: VecDeque<String>,
+}
+impl<S> FramedSignalling<S> {
+    pub fn new(signalling: S, framing: SignallingFraming) -> FramedSignalling<S> {
+        FramedSignalling {
+            signalling,
+            framed: Arc::new(AtomicBool::new(framing == SignallingFraming::Delimited)),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Whether messages are framed, either set so or switched to by the
+    /// peer.
+    pub fn framed(&self) -> bool {
+        self.framed.load(Ordering::Relaxed)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.signalling
+    }
+}
+#[cfg(feature = "ice-transport")]
+impl<S: 'static> FramedSignalling<S> {
+    /// Wraps the signalling reconnected to by `reconnect` alike, keeping
+    /// the framing negotiated so far.
+    pub fn reconnect(&self, mut reconnect: Reconnect<S>) -> Reconnect<FramedSignalling<S>> {
+        let framed = self.framed.clone();
+        Box::new(move || {
+            let (reconnected, framed) = (reconnect(), framed.clone());
+            async move {
+                Ok(FramedSignalling {
+                    signalling: reconnected.await?,
+                    framed,
+                    queued: VecDeque::new(),
+                })
+            }
+            .boxed_local()
+        })
+    }
+}
+impl<S> Signalling for FramedSignalling<S>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    fn send(&mut self, msg: String) -> LocalBoxFuture<'_, Result<(), SignalingError>> {
+        let msg = match self.framed() {
+            true => frame(&msg),
+            false => msg,
+        };
+        async move { self.signalling.send(msg).await.map_err(Into::into) }.boxed_local()
+    }
+}
+impl<S> WaitThen for FramedSignalling<S>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    type Value = Either<String, S::Value>;
+    type Output = Option<String>;
+    type Error = SignalingError;
+
+    /// Cancel safe as long as the signalling is, queued messages are handed
+    /// out in the first poll.
+    fn wait(&mut self) -> LocalBoxFuture<'_, Result<Self::Value, SignalingError>> {
+        async move {
+            if let Some(msg) = self.queued.pop_front() {
+                return Ok(Either::Left(msg));
+            }
+            let value = self.signalling.wait().await.map_err(Into::into)?;
+            Ok(Either::Right(value))
+        }
+        .boxed_local()
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, Result<Self::Output, SignalingError>> {
+        async move {
+            let received = match value {
+                Either::Left(msg) => return Ok(Some(std::mem::take(msg))),
+                Either::Right(value) => self.signalling.then(value).await.map_err(Into::into)?,
+            };
+            let Some(received) = received else {
+                return Ok(None);
+            };
+            let Some(messages) = split(&received)? else {
+                return Ok(Some(received));
+            };
+            if !self.framed.swap(true, Ordering::Relaxed) {
+                trace::debug!(target: logging::SIGNALLING, "Peer frames its messages, framing ours");
+            }
+            if messages.len() > 1 {
+                trace::debug!(
+                    target: logging::SIGNALLING,
+                    "Split {} messages out of a single frame",
+                    messages.len()
+                );
+            }
+            self.queued.extend(messages);
+
+            Ok(self.queued.pop_front())
+        }
+        .boxed_local()
+    }
+}
+
+/// `message` framed.
+pub fn frame(message: &str) -> String {
+    format!("{TAG}{}:{message}{END}", message.len())
+}
+
+/// The messages of a frame as received. `None` if it is not framed, an
+/// error if it starts like a frame but does not parse as one.
+fn split(received: &str) -> Result<Option<Vec<String>>, SignalingError> {
+    let mut rest = received.trim_start();
+    if !is_frame(rest) {
+        return Ok(None);
+    }
+
+    let mut messages = Vec::new();
+    while !rest.is_empty() {
+        let bad = || SignalingError::ProtocolError(format!("Bad signalling frame {rest:?}").into());
+        let (len, tail) = rest
+            .strip_prefix(TAG)
+            .and_then(|tail| tail.split_once(':'))
+            .ok_or_else(bad)?;
+        let len: usize = len.parse().map_err(|_| bad())?;
+        let message = tail.get(..len).ok_or_else(bad)?;
+        rest = tail[len..].strip_prefix(END).ok_or_else(bad)?.trim_start();
+        messages.push(message.to_owned());
+    }
+
+    Ok(Some(messages))
+}
+
+/// Starts with the tag and a length.
+fn is_frame(received: &str) -> bool {
+    let Some(tail) = received.strip_prefix(TAG) else {
+        return false;
+    };
+    let digits = tail.bytes().take_while(u8::is_ascii_digit).count();
+    digits > 0 && tail[digits..].starts_with(':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_signalling::MemorySignalling;
+
+    async fn recv<S>(signalling: &mut S) -> String
+    where
+        S: Signalling,
+        S::Error: Into<SignalingError>,
+    {
+        loop {
+            let mut value = signalling.wait().await.map_err(Into::into).unwrap();
+            let then = signalling.then(&mut value).await.map_err(Into::into);
+            if let Some(msg) = then.unwrap() {
+                return msg;
+            }
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let sdp = "v=0\r\no=- 1 1 IN IP4 0.0.0.0\r\n";
+        let mangled = format!("  {}\n{}{} ", frame("key"), frame(sdp), frame(""));
+        assert_eq!(
+            split(&mangled).unwrap().unwrap(),
+            ["key", sdp, ""].map(String::from)
+        );
+        assert_eq!(split("Icepipe/dialer/00").unwrap(), None);
+        assert_eq!(split("#hashtag").unwrap(), None);
+        assert!(split("#3:key").is_err());
+        assert!(split("#9:key;").is_err());
+        assert!(split("#3:key;#1").is_err());
+    }
+
+    #[tokio::test]
+    async fn raw_side_switches() {
+        let (mut a, b) = MemorySignalling::pair();
+        let mut b = FramedSignalling::new(b, SignallingFraming::Raw);
+        a.send("raw".into()).await.unwrap();
+        assert_eq!(recv(&mut b).await, "raw");
+        b.send("raw".into()).await.unwrap();
+        assert_eq!(recv(&mut a).await, "raw");
+        assert!(!b.framed());
+
+        a.send(format!("{}{}", frame("one"), frame("two")))
+            .await
+            .unwrap();
+        assert_eq!(recv(&mut b).await, "one");
+        assert!(b.framed());
+        b.send("three".into()).await.unwrap();
+        assert_eq!(recv(&mut a).await, frame("three"));
+        assert_eq!(recv(&mut b).await, "two");
+    }
+}
@@ -1,27 +1,459 @@
 use crate::{
+    diagnostics::Diagnostics,
     error::TimeoutError,
+    events::{CandidateCounts, ConnectionEvent, Events},
+    logging,
     pipe_stream::{Control, StreamError, WaitThen},
-    signalling::{SignalingError, Signalling},
+    rate_limit::{RateLimit, RateLimiter},
+    resolver::{resolve, Resolver},
+    sdp::IceCandidateInit,
+    sdp::{sdp_type, SdpError, SdpType, SessionDescription},
+    signalling::{
+        role_name, CandidateEncoding, Rejection, SignalingError, Signalling, SignallingFormat,
+    },
+    strictness::{Strictness, Violation, MAX_CANDIDATES},
+    trace::{self, Instrument},
 };
 use futures::{
     future::{Either, LocalBoxFuture},
     pin_mut, FutureExt,
 };
-use std::{io, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fmt, io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
 use tokio::{
     select,
-    sync::{mpsc, watch},
+    sync::{mpsc, oneshot, watch},
+    time::{interval, sleep_until, Instant, MissedTickBehavior},
 };
+pub use webrtc_ice::agent::agent_config::AgentConfig;
 use webrtc_ice::{
-    agent::{agent_config::AgentConfig, Agent},
-    candidate::{candidate_base::unmarshal_candidate, Candidate},
+    agent::{agent_stats::CandidateStats, Agent},
+    candidate::{
+        candidate_base::unmarshal_candidate, Candidate, CandidatePairState, CandidateType,
+    },
+    mdns::MulticastDnsMode,
+    rand::generate_crypto_random_string,
     state::ConnectionState,
-    url::Url,
+    url::{ProtoType, SchemeType, Url},
 };
 use webrtc_util::Conn;
 
+/// A STUN or TURN server, like the `RTCIceServer` of WebRTC and serialized
+/// as one, see [`IceServer::from_json`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "IceServerJson")]
+pub struct IceServer {
+    /// `stun:` or `turn:` URLs of the same server, sharing the credentials.
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+impl IceServer {
+    /// Without credentials, as for STUN.
+    pub fn new(url: impl Into<String>) -> IceServer {
+        IceServer {
+            urls: vec![url.into()],
+            ..Default::default()
+        }
+    }
+
+    pub fn with_credentials(
+        mut self,
+        username: impl Into<String>,
+        credential: impl Into<String>,
+    ) -> IceServer {
+        self.username = Some(username.into());
+        self.credential = Some(credential.into());
+        self
+    }
+
+    pub fn parse_urls(&self) -> Result<Vec<Url>, webrtc_ice::Error> {
+        self.urls
+            .iter()
+            .map(|url| {
+                let mut url = Url::parse_url(url)?;
+                url.username = self.username.clone().unwrap_or_default();
+                url.password = self.credential.clone().unwrap_or_default();
+                Ok(url)
+            })
+            .collect()
+    }
+
+    /// Parses the `iceServers` of a WebRTC `RTCConfiguration`, as handed out
+    /// by TURN credential APIs: an array of `{"urls", "username",
+    /// "credential"}`, or an object holding it under `iceServers`. `urls`
+    /// may be a single string, other fields are ignored. URLs are not
+    /// checked, see [`IceServer::parse_urls`].
+    pub fn from_json(json: &str) -> serde_json::Result<Vec<IceServer>> {
+        match serde_json::from_str(json)? {
+            IceServersJson::List(servers) => Ok(servers),
+            IceServersJson::Configuration { ice_servers } => Ok(ice_servers),
+        }
+    }
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IceServersJson {
+    List(Vec<IceServer>),
+    Configuration {
+        #[serde(rename = "iceServers")]
+        ice_servers: Vec<IceServer>,
+    },
+}
+#[derive(Deserialize)]
+struct IceServerJson {
+    /// `url` in older configurations.
+    #[serde(alias = "url")]
+    urls: UrlsJson,
+    username: Option<String>,
+    credential: Option<String>,
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UrlsJson {
+    One(String),
+    Many(Vec<String>),
+}
+impl From<IceServerJson> for IceServer {
+    fn from(server: IceServerJson) -> Self {
+        IceServer {
+            urls: match server.urls {
+                UrlsJson::One(url) => vec![url],
+                UrlsJson::Many(urls) => urls,
+            },
+            username: server.username,
+            credential: server.credential,
+        }
+    }
+}
+
+/// `url&username&credential`, the format of the command line, with both
+/// credentials optional.
+impl FromStr for IceServer {
+    type Err = webrtc_ice::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split('&');
+        let url = fields.next().unwrap_or_default();
+        Url::parse_url(url)?;
+
+        Ok(IceServer {
+            urls: vec![url.to_owned()],
+            username: fields.next().map(str::to_owned),
+            credential: fields.next().map(str::to_owned),
+        })
+    }
+}
+
+/// Interval at which [`ConnectionEvent::IceProgress`] is emitted while
+/// connecting, if anything changed.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default capacity of the queue of gathered candidates waiting to be sent,
+/// see [`IceConfig::candidate_queue`].
+pub const CANDIDATE_QUEUE: usize = 32;
+
 const PROTOCOL_START: &str = "Icepipe";
 const PROTOCOL_CLOSE: &str = "Close";
+/// Appended to the nonce of the role announcement by peers that understand
+/// the end of candidates marker, older ones take it as part of the nonce.
+const FEATURE_END_OF_CANDIDATES: &str = "end-of-candidates";
+/// Announced by peers confirming the derived key over the secured stream, see
+/// [`IceAgent::peer_confirms_keys`].
+const FEATURE_KEY_CONFIRMATION: &str = "key-confirmation";
+/// Announced by peers numbering their encrypted messages, see
+/// [`IceAgent::peer_numbers_messages`].
+const FEATURE_SEQUENCE_NUMBERS: &str = "sequence-numbers";
+/// Completes the role announcement into an active TCP candidate, which peers
+/// predating it parse and add to their agent, which only probes passive ones.
+const ANNOUNCEMENT_CANDIDATE: &str = "1 tcp 0 0.0.0.0 9 typ host tcptype active";
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+/// How long a listener waits for the offer with [`SignallingFormat::Sdp`]
+/// before making one itself, see [`CandidateExchange::dialer`]. The dialer
+/// offers once gathering completes, which takes a few seconds at most.
+pub const OFFER_WAIT: Duration = Duration::from_secs(15);
+
+/// Candidates remembered from a previous session between the same hosts.
+///
+/// Cached remote candidates are added to the agent as soon as the exchange
+/// starts and cached local candidates are announced to the peer right away,
+/// so connectivity checks can begin before the candidates they stand for
+/// are gathered again. Gathering is neither skipped nor shortened, webrtc-ice
+/// cannot stop it once started: fresh candidates are gathered and exchanged
+/// as without a cache, and stale entries simply fail their checks. It only
+/// helps where candidates outlive a session, e.g. with fixed ports, letting
+/// a cached pair succeed before slow STUN or TURN servers answer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CandidateCache {
+    pub local: Vec<String>,
+    pub remote: Vec<String>,
+}
+
+/// How the dialer, which nominates the pair for both peers, picks it among
+/// those that succeeded, trading how soon the connection is up for how good
+/// the path is.
+///
+/// The agent only does regular nomination, a pair is nominated with a check
+/// of its own once it succeeded. True aggressive nomination, flagging every
+/// check, is not supported, [`PairSelection::FirstResponding`] gets the
+/// same early pick.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PairSelection {
+    /// Regular nomination: nominates the highest priority pair, waiting up
+    /// to 2s for pairs of a higher priority type to succeed before settling
+    /// for a relayed one. A direct path wins over a relay that answered
+    /// first, at the cost of that wait when only the relay works.
+    #[default]
+    Standard,
+    /// Aggressive: nominates as soon as a pair succeeds, so the first to
+    /// answer, usually the one with the lowest round trip time, wins over a
+    /// higher priority pair still being checked. Connects sooner, but may
+    /// settle for a relay, or a path through a VPN, where a direct one would
+    /// have succeeded moments later.
+    FirstResponding,
+}
+
+/// Which local candidates are gathered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GatherPolicy {
+    /// Host, server reflexive and relay candidates.
+    #[default]
+    Full,
+    /// Only relay candidates, for sandboxes where listening sockets are not
+    /// allowed. The only sockets are the unconnected ones allocating on each
+    /// TURN server, and mDNS is disabled. Needs a `turn:` URL over UDP with
+    /// credentials, the agent does not support TURN over TCP or TLS.
+    RelayOnlyNoBind,
+}
+impl GatherPolicy {
+    /// Fails with [`IceError::NoRelay`] when `urls` cannot satisfy the
+    /// policy.
+    pub fn check(self, urls: &[Url]) -> IceResult<()> {
+        let relay = |url: &Url| {
+            url.scheme == SchemeType::Turn
+                && url.proto == ProtoType::Udp
+                && !url.username.is_empty()
+                && !url.password.is_empty()
+        };
+        match self {
+            GatherPolicy::RelayOnlyNoBind if !urls.iter().any(relay) => Err(IceError::NoRelay),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A pair of candidates checked by ICE, see [`candidate_pairs`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CandidatePairEntry {
+    /// `<type> <address>:<port>`.
+    pub local: String,
+    pub remote: String,
+    /// `waiting`, `in-progress`, `failed` or `succeeded`.
+    pub state: String,
+    pub nominated: bool,
+    /// Carrying the connection.
+    pub selected: bool,
+}
+
+/// Every pair the agent checked, succeeded or not. Round trip times are not
+/// included, the agent does not measure them per pair.
+pub async fn candidate_pairs(agent: &Agent) -> Vec<CandidatePairEntry> {
+    let local = agent.get_local_candidates_stats().await;
+    let remote = agent.get_remote_candidates_stats().await;
+    let selected = agent
+        .get_selected_candidate_pair()
+        .map(|pair| (pair.local.id(), pair.remote.id()));
+    let describe = |candidates: &[CandidateStats], id: &str| {
+        candidates
+            .iter()
+            .find(|candidate| candidate.id == id)
+            .map(|c| format!("{} {}:{}", c.candidate_type, c.ip, c.port))
+            .unwrap_or_else(|| id.to_owned())
+    };
+
+    agent
+        .get_candidate_pairs_stats()
+        .await
+        .into_iter()
+        .map(|pair| CandidatePairEntry {
+            local: describe(&local, &pair.local_candidate_id),
+            remote: describe(&remote, &pair.remote_candidate_id),
+            state: pair.state.to_string(),
+            nominated: pair.nominated,
+            selected: selected.as_ref().is_some_and(|(local, remote)| {
+                *local == pair.local_candidate_id && *remote == pair.remote_candidate_id
+            }),
+        })
+        .collect()
+}
+
+/// Address the socket of the selected pair is bound to, which for a server
+/// reflexive candidate is not the one it announces. `None` for relayed pairs,
+/// whose connection goes through the TURN client.
+pub(crate) fn selected_socket(agent: &Agent) -> Option<SocketAddr> {
+    let pair = agent.get_selected_candidate_pair()?;
+    if pair.local.candidate_type() == CandidateType::Relay {
+        return None;
+    }
+    pair.local.get_conn()?.local_addr().ok()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IceConfig {
+    pub urls: Vec<Url>,
+    pub signalling_rate_limit: RateLimit,
+    pub candidate_cache: Option<CandidateCache>,
+    pub format: SignallingFormat,
+    /// `<hash function> <fingerprint>` of the DTLS certificate announced in
+    /// SDP descriptions.
+    pub fingerprint: Option<String>,
+    /// Fails with [`IceError::RoleConflict`] when both peers claim the same
+    /// role instead of re-assigning them.
+    pub strict_roles: bool,
+    pub strictness: Strictness,
+    pub diagnostics: Diagnostics,
+    pub events: Events,
+    /// Only applies to the dialer.
+    pub pair_selection: PairSelection,
+    pub gather_policy: GatherPolicy,
+    /// Gathers and accepts IPv6 link-local candidates, see [`link_local`].
+    pub keep_link_local: bool,
+    pub candidate_encoding: CandidateEncoding,
+    /// Gathered candidates waiting to be sent, [`CANDIDATE_QUEUE`] if unset.
+    /// Those gathered while it is full are dropped, see [`IceStats`].
+    pub candidate_queue: Option<usize>,
+    /// Applied to the agent settings once icepipe is done with them.
+    pub agent_config: Option<AgentConfigHook>,
+    /// Resolves the hosts of `urls` in place of the agent, which only knows
+    /// of the system resolver, see [`resolve_urls`].
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// What becomes of the exchange once connected, see
+    /// [`IceAgent::settle`].
+    pub signalling_policy: SignallingPolicy,
+    /// Tells the notice of a peer that rejected this one in the key
+    /// agreement, fails the exchange with
+    /// [`PeerRejected`](crate::signalling::PeerRejected).
+    pub rejection: Rejection,
+}
+
+/// What [`IceAgent::settle`] does with the signalling channel once ICE
+/// connected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignallingPolicy {
+    /// Tells the peer the exchange is over, see [`CandidateExchange::close`].
+    #[default]
+    Close,
+    /// Exchanges the candidates gathered late on a task of its own, see
+    /// [`IceAgent::keep_exchanging`]. The agent checks the pairs they form
+    /// but keeps the selected one while it works, webrtc-ice does not
+    /// renominate, so e.g. a relayed connection does not move to a direct
+    /// path found later.
+    KeepExchanging,
+    /// Announces the end of candidates and keeps the signalling channel
+    /// serviced until the ICE connection closes, see
+    /// [`CandidateExchange::park`].
+    Park,
+}
+
+/// Replaces the host of each of `urls` with an address `resolver` gives,
+/// IPv4 preferred as relays are only allocated over it. Both STUN and TURN
+/// go over UDP, where nothing else depends on the host name. Hosts that fail
+/// to resolve are left for the agent.
+pub async fn resolve_urls(urls: Vec<Url>, resolver: &Arc<dyn Resolver>) -> Vec<Url> {
+    let mut resolved = Vec::with_capacity(urls.len());
+    for mut url in urls {
+        match resolve(Some(resolver), &url.host).await {
+            Ok(addrs) => {
+                let ip = addrs.iter().find(|ip| ip.is_ipv4()).unwrap_or(&addrs[0]);
+                trace::debug!(target: logging::ICE, "Resolved {} to {}", url.host, ip);
+                // Bracketed as parsed from the URL
+                url.host = match ip {
+                    IpAddr::V4(ip) => ip.to_string(),
+                    IpAddr::V6(ip) => format!("[{ip}]"),
+                };
+            }
+            Err(e) => {
+                trace::warn!(target: logging::ICE, "Could not resolve {}: {}", url.host, e);
+            }
+        }
+        resolved.push(url);
+    }
+
+    resolved
+}
+
+/// Changes any setting of the [`AgentConfig`] icepipe built, for those it
+/// does not expose, e.g. timeouts. Settings derived from other options can
+/// be overridden too, except the credentials and network types, which
+/// icepipe sets again afterwards as the exchange depends on them.
+#[derive(Clone)]
+pub struct AgentConfigHook(pub Arc<dyn Fn(&mut AgentConfig) + Send + Sync>);
+impl AgentConfigHook {
+    pub fn new<F>(hook: F) -> AgentConfigHook
+    where
+        F: Fn(&mut AgentConfig) + Send + Sync + 'static,
+    {
+        AgentConfigHook(Arc::new(hook))
+    }
+}
+impl fmt::Debug for AgentConfigHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AgentConfigHook")
+    }
+}
+
+/// Local candidates not sent to the peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IceStats {
+    /// Gathered again, e.g. once more from a cached candidate.
+    pub duplicate_candidates: u64,
+    /// Gathered while the queue of candidates waiting to be sent was full.
+    pub dropped_candidates: u64,
+}
+
+/// Hands gathered candidates to [`CandidateExchange`] without ever blocking
+/// the agent, candidates that do not fit in the queue are dropped and
+/// counted. The end of gathering is always queued.
+#[derive(Clone)]
+pub struct CandidateSender {
+    tx: mpsc::Sender<Option<String>>,
+    dropped: Arc<AtomicU64>,
+}
+impl CandidateSender {
+    pub async fn send(&self, candidate: Option<String>) {
+        let Some(candidate) = candidate else {
+            let _ = self.tx.send(None).await;
+            return;
+        };
+        match self.tx.try_send(Some(candidate)) {
+            Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => {}
+            Err(mpsc::error::TrySendError::Full(candidate)) => {
+                trace::warn!(target: logging::ICE,
+                    "TX candidate {} dropped, candidate queue full",
+                    logged(candidate.as_deref().unwrap_or_default())
+                );
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Opens a new signalling channel to the same peer, see
+/// [`IceAgent::set_signalling_reconnect`].
+pub type Reconnect<S> = Box<dyn FnMut() -> LocalBoxFuture<'static, Result<S, SignalingError>>>;
 
 type CandidateExchangeValue<S> = Either<String, <S as WaitThen>::Value>;
 pub struct CandidateExchange<S>
@@ -29,8 +461,54 @@ where
     S: Signalling,
     S::Error: Into<SignalingError>,
 {
-    candidate_rx: mpsc::Receiver<String>,
+    candidate_rx: mpsc::Receiver<Option<String>>,
+    dropped_candidates: Arc<AtomicU64>,
+    duplicate_candidates: u64,
     signalling: S,
+    dialer: bool,
+    format: SignallingFormat,
+    fingerprint: Option<String>,
+    diagnostics: Diagnostics,
+    events: Events,
+    /// Whether the handshake of the peer carried its role, `None` with SDP.
+    peer_role_negotiation: Option<bool>,
+    /// First message of a peer predating role negotiation, received in place
+    /// of the announcement and handled with the next value.
+    held: Option<String>,
+    strict_roles: bool,
+    strictness: Strictness,
+    keep_link_local: bool,
+    encoding: CandidateEncoding,
+    rx_limiter: RateLimiter,
+    /// Remote candidates dropped by `rx_limiter` since it last let one
+    /// through, only the first of them is warned about.
+    rx_throttled: usize,
+    /// Remote candidates received, duplicates included but for those
+    /// already added, see [`CandidateExchange::known`].
+    rx_candidates: usize,
+    exchanged: CandidateCache,
+    /// [`OFFER_WAIT`] but in tests.
+    offer_wait: Duration,
+    rejection: Rejection,
+    reconnect: Option<Reconnect<S>>,
+    reconnects: u32,
+    lost: Option<SignalingError>,
+    /// Kept across cancelled waits, see [`CandidateExchange::resume`].
+    reconnect_at: Option<Instant>,
+    reconnecting: Option<LocalBoxFuture<'static, Result<S, SignalingError>>>,
+    /// Every message sent goes through here, see
+    /// [`CandidateExchange::send_queued`].
+    outgoing: VecDeque<String>,
+    /// Whether the peer announced [`FEATURE_END_OF_CANDIDATES`].
+    peer_end_of_candidates: bool,
+    /// Whether the peer announced [`FEATURE_KEY_CONFIRMATION`].
+    peer_key_confirmation: bool,
+    /// Whether the peer announced [`FEATURE_SEQUENCE_NUMBERS`].
+    peer_sequence_numbers: bool,
+    /// Candidates gathered from now on are not sent, see
+    /// [`CandidateExchange::finish_candidates`].
+    tx_finished: bool,
+    rx_finished: bool,
     tx_shut: bool,
     rx_shut: bool,
 }
@@ -39,47 +517,462 @@ where
     S: Signalling,
     S::Error: Into<SignalingError>,
 {
-    pub async fn new(mut signalling: S) -> IceResult<(Self, mpsc::Sender<String>)> {
-        signalling
-            .send(PROTOCOL_START.into())
-            .await
-            .map_err(Into::into)?;
-        let recv = loop {
-            let mut value = signalling.wait().await.map_err(Into::into)?;
-            if let Some(recv) = signalling.then(&mut value).await.map_err(Into::into)? {
-                break recv;
-            }
+    /// The sender takes gathered candidates, `None` once gathering completes,
+    /// and queues up to `candidate_queue` of them.
+    ///
+    /// The handshake carries the claimed role, see [`CandidateExchange::dialer`].
+    ///
+    /// Under [`Strictness::Strict`] a peer predating role negotiation, one
+    /// exceeding `rx_limit` and one sending unparsable or more than
+    /// [`MAX_CANDIDATES`] candidates fail the exchange.
+    pub async fn new(
+        signalling: S,
+        dialer: bool,
+        rx_limit: RateLimit,
+        format: SignallingFormat,
+        strict_roles: bool,
+        strictness: Strictness,
+        candidate_queue: usize,
+    ) -> IceResult<(Self, CandidateSender)> {
+        let (mut exchange, candidate_tx) = Self::unshaken(
+            signalling,
+            dialer,
+            rx_limit,
+            format,
+            strict_roles,
+            strictness,
+            candidate_queue,
+        );
+        exchange.shake().await?;
+
+        Ok((exchange, candidate_tx))
+    }
+
+    /// [`CandidateExchange::new`] short of the handshake, see
+    /// [`CandidateExchange::shake`].
+    fn unshaken(
+        signalling: S,
+        dialer: bool,
+        rx_limit: RateLimit,
+        format: SignallingFormat,
+        strict_roles: bool,
+        strictness: Strictness,
+        candidate_queue: usize,
+    ) -> (Self, CandidateSender) {
+        let (candidate_tx, candidate_rx) = mpsc::channel(candidate_queue);
+        let dropped_candidates = Arc::new(AtomicU64::new(0));
+        let candidate_tx = CandidateSender {
+            tx: candidate_tx,
+            dropped: dropped_candidates.clone(),
+        };
+        let exchange = CandidateExchange {
+            candidate_rx,
+            dropped_candidates,
+            duplicate_candidates: 0,
+            signalling,
+            dialer,
+            format,
+            fingerprint: None,
+            diagnostics: Default::default(),
+            events: Default::default(),
+            peer_role_negotiation: None,
+            held: None,
+            strict_roles,
+            strictness,
+            keep_link_local: false,
+            encoding: CandidateEncoding::Raw,
+            rx_limiter: RateLimiter::new(rx_limit),
+            rx_throttled: 0,
+            rx_candidates: 0,
+            exchanged: Default::default(),
+            offer_wait: OFFER_WAIT,
+            rejection: Rejection::default(),
+            reconnect: None,
+            reconnects: 0,
+            lost: None,
+            reconnect_at: None,
+            reconnecting: None,
+            outgoing: VecDeque::new(),
+            peer_end_of_candidates: false,
+            peer_key_confirmation: false,
+            peer_sequence_numbers: false,
+            tx_finished: false,
+            rx_finished: false,
+            tx_shut: false,
+            rx_shut: false,
         };
 
+        (exchange, candidate_tx)
+    }
+
+    /// Runs the handshake, which [`SignallingFormat::Sdp`] goes without.
+    async fn shake(&mut self) -> IceResult<()> {
+        match self.format {
+            SignallingFormat::Native => self.handshake().await,
+            SignallingFormat::Sdp => Ok(()),
+        }
+    }
+
+    /// Role after the handshake. When both peers claimed the same one, e.g.
+    /// because the signalling server assigned it twice, the peer with the
+    /// greater nonce becomes the dialer.
+    ///
+    /// There is no handshake with [`SignallingFormat::Sdp`], two dialers are
+    /// told apart by the ufrag of their offers once descriptions are
+    /// exchanged instead. A listener receiving no offer within [`OFFER_WAIT`]
+    /// offers itself, so two listeners end up as two dialers.
+    pub fn dialer(&self) -> bool {
+        self.dialer
+    }
+
+    pub fn stats(&self) -> IceStats {
+        IceStats {
+            duplicate_candidates: self.duplicate_candidates,
+            dropped_candidates: self.dropped_candidates.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether `candidate` was already announced, counting it as a duplicate.
+    fn duplicate(&mut self, candidate: &str) -> bool {
+        if !self.exchanged.local.iter().any(|c| c == candidate) {
+            return false;
+        }
+        trace::debug!(target: logging::ICE, "TX candidate {} already sent", candidate);
+        self.duplicate_candidates += 1;
+        true
+    }
+
+    /// Whether the peer ended its candidates, see
+    /// [`CandidateExchange::finish_candidates`].
+    pub fn peer_finished(&self) -> bool {
+        self.rx_finished
+    }
+
+    /// Sends the bare [`PROTOCOL_START`] every peer expects, followed by the
+    /// role announcement, see [`role_announcement`]. Peers predating it send
+    /// a candidate or [`PROTOCOL_CLOSE`] instead, which is held for later.
+    async fn handshake(&mut self) -> IceResult<()> {
+        let nonce = generate_crypto_random_string(32, b"0123456789abcdef");
+        // Roles are settled over the whole token, features included
+        let nonce = format!(
+            "{nonce};{FEATURE_END_OF_CANDIDATES},{FEATURE_KEY_CONFIRMATION},{FEATURE_SEQUENCE_NUMBERS}"
+        );
+        self.send(PROTOCOL_START.to_owned()).await?;
+        self.send(role_announcement(self.dialer, &nonce)).await?;
+
+        let recv = self.recv().await?;
         if recv != PROTOCOL_START {
             return Err(IceError::BadHandshake(recv));
         }
+        let recv = self.recv().await?;
+        let Some(announced) = announced_role(&recv) else {
+            // Peer predating role negotiation
+            self.peer_role_negotiation = Some(false);
+            self.held = Some(recv);
+            self.strictness.check(|| Violation::LegacyHandshake)?;
+            return Ok(());
+        };
+        self.peer_role_negotiation = Some(true);
+        let (peer_dialer, peer_nonce) = match announced.split('/').collect::<Vec<_>>()[..] {
+            [PROTOCOL_START, "dialer", nonce] => (true, nonce),
+            [PROTOCOL_START, "listener", nonce] => (false, nonce),
+            _ => return Err(IceError::BadHandshake(recv.clone())),
+        };
+        let features = peer_nonce
+            .split_once(';')
+            .map_or("", |(_, features)| features);
+        let features = features.split(',').collect::<Vec<_>>();
+        self.peer_end_of_candidates = features.contains(&FEATURE_END_OF_CANDIDATES);
+        self.peer_key_confirmation = features.contains(&FEATURE_KEY_CONFIRMATION);
+        self.peer_sequence_numbers = features.contains(&FEATURE_SEQUENCE_NUMBERS);
+        if peer_dialer != self.dialer {
+            return Ok(());
+        }
+        if self.strict_roles || peer_nonce == nonce {
+            return Err(IceError::RoleConflict(role_name(self.dialer)));
+        }
 
-        let (candidate_tx, candidate_rx) = mpsc::channel(1);
+        self.dialer = nonce.as_str() > peer_nonce;
+        trace::warn!(target: logging::ICE,
+            "Both peers claimed the {} role, continuing as {}",
+            role_name(peer_dialer),
+            role_name(self.dialer)
+        );
 
-        let r = (
-            CandidateExchange {
-                candidate_rx,
-                signalling,
-                tx_shut: false,
-                rx_shut: false,
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: String) -> IceResult<()> {
+        Ok(self.signalling.send(msg).await.map_err(Into::into)?)
+    }
+
+    async fn recv(&mut self) -> IceResult<String> {
+        loop {
+            let mut value = self.signalling.wait().await.map_err(Into::into)?;
+            if let Some(recv) = self.signalling.then(&mut value).await.map_err(Into::into)? {
+                self.rejection.check(&recv)?;
+                break Ok(recv);
+            }
+        }
+    }
+
+    /// Sends the gathered candidates in an offer, or an answer for the
+    /// listener, and returns the description of the peer.
+    async fn offer_answer(&mut self, agent: &Agent) -> IceResult<SessionDescription> {
+        while let Some(candidate) = self
+            .candidate_rx
+            .recv()
+            .await
+            .ok_or(IceError::AgentClosed)?
+        {
+            if !self.duplicate(&candidate) {
+                self.exchanged.local.push(candidate);
+            }
+        }
+
+        let (ufrag, pwd) = agent.get_local_user_credentials().await;
+        let local = SessionDescription {
+            ufrag,
+            pwd,
+            fingerprint: self.fingerprint.clone(),
+            candidates: self.exchanged.local.clone(),
+        };
+        let remote = match self.dialer {
+            true => self.offer(&local, true).await?,
+            false => match self.wait_offer().await? {
+                Some(sdp) => {
+                    let remote = SessionDescription::parse(&sdp)?;
+                    self.send(local.to_sdp(SdpType::Answer)).await?;
+                    remote
+                }
+                None if self.strict_roles => return Err(IceError::RoleConflict(role_name(false))),
+                None => {
+                    trace::warn!(target: logging::ICE,
+                        "No offer within {:?}, offering in place of the dialer",
+                        self.offer_wait
+                    );
+                    self.dialer = true;
+                    self.offer(&local, false).await?
+                }
             },
-            candidate_tx,
-        );
+        };
+        trace::info!(target: logging::ICE, "RX description with {} candidates", remote.candidates.len());
+        self.rx_candidates += remote.candidates.len();
+        if remote.candidates.len() > MAX_CANDIDATES {
+            self.strictness.check(|| Violation::TooManyCandidates)?;
+        }
 
-        Ok(r)
+        for candidate in &remote.candidates {
+            if self.dropped_link_local(candidate) {
+                continue;
+            }
+            match unmarshal_remote(candidate) {
+                Ok(c) => {
+                    let c: Arc<dyn Candidate + Send + Sync> = Arc::new(c);
+                    agent.add_remote_candidate(&c)?;
+                    self.diagnostics.remote_candidate(c.as_ref());
+                    self.exchanged.remote.push(c.marshal());
+                }
+                Err(e) => {
+                    self.strictness
+                        .check(|| Violation::UnparsableCandidate(candidate.clone()))?;
+                    trace::warn!(target: logging::ICE, "RX candidate {} ignored: {}", logged(candidate), e);
+                    self.diagnostics
+                        .warning(format!("Remote candidate ignored: {e}"));
+                    self.discarded(candidate, e.to_string());
+                }
+            }
+        }
+
+        Ok(remote)
     }
 
-    pub async fn close(&mut self) -> IceResult<()> {
-        if !self.tx_shut {
-            log::info!("TX shutdown");
-            self.signalling
-                .send(PROTOCOL_CLOSE.to_string())
-                .await
-                .map_err(Into::into)?;
-            self.tx_shut = true;
+    /// Sends the offer and returns the answer, or settles a glare if the peer
+    /// offered too. `claimed` is the role assigned to this peer, the listener
+    /// one when it offers after waiting for the offer in vain.
+    async fn offer(
+        &mut self,
+        local: &SessionDescription,
+        claimed: bool,
+    ) -> IceResult<SessionDescription> {
+        self.send(local.to_sdp(SdpType::Offer)).await?;
+        let sdp = self.recv().await?;
+        let remote = SessionDescription::parse(&sdp)?;
+        match sdp_type(&sdp) {
+            Some(SdpType::Offer) => self.offer_glare(local, remote, claimed).await,
+            _ => Ok(remote),
+        }
+    }
+
+    /// Receives the offer of the dialer, `None` if none came within
+    /// `offer_wait`.
+    async fn wait_offer(&mut self) -> IceResult<Option<String>> {
+        let deadline = Instant::now() + self.offer_wait;
+        loop {
+            let mut value = select! {
+                value = self.signalling.wait() => value.map_err(Into::into)?,
+                _ = sleep_until(deadline) => return Ok(None),
+            };
+            if let Some(recv) = self.signalling.then(&mut value).await.map_err(Into::into)? {
+                self.rejection.check(&recv)?;
+                return Ok(Some(recv));
+            }
+        }
+    }
+
+    /// Both peers sent an offer, the one with the greater ufrag stays the
+    /// dialer and the other answers it.
+    async fn offer_glare(
+        &mut self,
+        local: &SessionDescription,
+        remote: SessionDescription,
+        claimed: bool,
+    ) -> IceResult<SessionDescription> {
+        if self.strict_roles || local.ufrag == remote.ufrag {
+            return Err(IceError::RoleConflict(role_name(claimed)));
+        }
+
+        let claimed = role_name(claimed);
+        trace::warn!(target: logging::ICE, "Both peers sent an offer, the {} role was assigned twice", claimed);
+        if local.ufrag > remote.ufrag {
+            // The peer answers the offer it received
+            return Ok(SessionDescription::parse(&self.recv().await?)?);
+        }
+
+        self.dialer = false;
+        self.diagnostics.warning(format!(
+            "Both peers were assigned the {claimed} role, continuing as listener"
+        ));
+        self.send(local.to_sdp(SdpType::Answer)).await?;
+
+        Ok(remote)
+    }
+
+    async fn seed(&mut self, agent: &Agent, cache: &CandidateCache) -> IceResult<()> {
+        // Descriptions carry complete candidates lists, nothing to announce early
+        let announce = match self.format {
+            SignallingFormat::Native => &cache.local[..],
+            SignallingFormat::Sdp => &[],
+        };
+        for candidate in announce {
+            if !self.keep_link_local && link_local(candidate) {
+                continue;
+            }
+            if let Err(e) = unmarshal_candidate(candidate) {
+                trace::warn!(target: logging::ICE, "Cached local candidate {} ignored: {}", logged(candidate), e);
+                self.diagnostics
+                    .warning(format!("Cached local candidate ignored: {e}"));
+                continue;
+            }
+
+            if self.duplicate(candidate) {
+                continue;
+            }
+
+            trace::debug!(target: logging::ICE, "TX cached candidate {}", candidate);
+            self.exchanged.local.push(candidate.clone());
+            self.outgoing
+                .push_back(self.encoding.encode(candidate.clone()));
+            self.send_queued().await?;
+        }
+
+        for candidate in &cache.remote {
+            if self.dropped_link_local(candidate) {
+                continue;
+            }
+            match unmarshal_remote(candidate) {
+                Ok(c) => {
+                    trace::debug!(target: logging::ICE, "Cached remote candidate {}", candidate);
+                    let c: Arc<dyn Candidate + Send + Sync> = Arc::new(c);
+                    agent.add_remote_candidate(&c)?;
+                }
+                Err(e) => {
+                    trace::warn!(target: logging::ICE, "Cached remote candidate {} ignored: {}", logged(candidate), e);
+                    self.diagnostics
+                        .warning(format!("Cached remote candidate ignored: {e}"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tells the peer no more candidates follow, with the JSON marker of
+    /// WebRTC whatever the encoding, and stops sending those gathered from
+    /// now on. Only the peers announcing it in their handshake get the
+    /// marker, others would take it for a bad candidate. Nothing is
+    /// exchanged after the answer with [`SignallingFormat::Sdp`].
+    pub async fn finish_candidates(&mut self) -> IceResult<()> {
+        if self.format == SignallingFormat::Sdp || self.tx_finished || self.tx_shut {
+            return Ok(());
+        }
+        self.tx_finished = true;
+        if !self.peer_end_of_candidates {
+            trace::debug!(target: logging::ICE, "TX end of candidates skipped, unknown to the peer");
+            return Ok(());
+        }
+
+        trace::info!(target: logging::ICE, "TX end of candidates");
+        self.outgoing.push_back(IceCandidateInit::end().to_json());
+        if let Err(e) = self.send_queued().await {
+            self.signalling_lost(e)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stops exchanging candidates, ending them with
+    /// [`CandidateExchange::finish_candidates`], and services the signalling
+    /// channel on a task of its own so that it stays open, e.g. through its
+    /// pings. Candidates the peer sends meanwhile are discarded. The
+    /// exchange is back with [`ParkedExchange::resume`]. The task is spawned
+    /// with [`tokio::task::spawn_local`], so this must be called from within
+    /// a [`LocalSet`](tokio::task::LocalSet).
+    pub async fn park(mut self) -> IceResult<ParkedExchange<S>>
+    where
+        S: 'static,
+    {
+        self.finish_candidates().await?;
+        let (unpark, mut unparked) = oneshot::channel();
+        let (parked, exchange) = oneshot::channel();
+        let span = trace::Span::current();
+        tokio::task::spawn_local(async move {
+            let r = async move {
+                trace::debug!(target: logging::ICE, "Candidate exchange parked");
+                if self.service(&mut unparked).await? == Unpark::Close {
+                    self.close().await?;
+                }
+                Ok(self)
+            }
+            .instrument(span)
+            .await;
+            if let Err(e) = &r {
+                trace::debug!(target: logging::ICE, "Parked candidate exchange ended: {e}");
+            }
+            let _ = parked.send(r);
+        });
+
+        Ok(ParkedExchange {
+            unpark: Some(unpark),
+            exchange,
+        })
+    }
+
+    /// Receives until the peer closes, then waits to be unparked.
+    async fn service(&mut self, unparked: &mut oneshot::Receiver<Unpark>) -> IceResult<Unpark> {
+        while !self.rx_shut {
+            select! {
+                unpark = &mut *unparked => return Ok(unpark.unwrap_or(Unpark::Close)),
+                value = self.wait() => self.then(None, &mut value?).await?,
+            }
         }
 
+        Ok(unparked.await.unwrap_or(Unpark::Close))
+    }
+
+    /// Tells the peer the exchange is over and waits for it to do the same.
+    pub async fn close(&mut self) -> IceResult<()> {
+        self.shutdown().await?;
         while !self.rx_shut {
             let mut value = self.wait().await?;
             self.then(None, &mut value).await?;
@@ -88,14 +981,138 @@ where
         Ok(())
     }
 
+    /// The sending half of [`CandidateExchange::close`].
+    async fn shutdown(&mut self) -> IceResult<()> {
+        if self.format == SignallingFormat::Sdp {
+            // Nothing is exchanged after the answer
+            self.tx_shut = true;
+            self.rx_shut = true;
+        }
+
+        if !self.tx_shut {
+            trace::info!(target: logging::ICE, "TX shutdown");
+            self.tx_shut = true;
+            self.outgoing.push_back(PROTOCOL_CLOSE.to_string());
+            self.send_queued().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Signalling failures are reported by [`CandidateExchange::wait`] so
+    /// reconnecting is raced against the ICE connection.
+    fn signalling_lost(&mut self, error: SignalingError) -> IceResult<()> {
+        match self.reconnect.is_some() && self.reconnects > 0 {
+            true => {
+                self.lost = Some(error);
+                Ok(())
+            }
+            false => Err(error.into()),
+        }
+    }
+
+    /// Replaces the lost signalling channel and announces every local
+    /// candidate again, the peer skips the ones it already knows. Progress
+    /// is kept in `self`, a cancelled wait picks up where it stopped without
+    /// spending another attempt.
+    async fn resume(&mut self) -> IceResult<()> {
+        loop {
+            if let Some(at) = self.reconnect_at {
+                sleep_until(at).await;
+                self.reconnect_at = None;
+                if let Some(reconnect) = &mut self.reconnect {
+                    self.reconnecting = Some(reconnect());
+                }
+            }
+            if let Some(reconnecting) = &mut self.reconnecting {
+                let r = reconnecting.await;
+                self.reconnecting = None;
+                match r {
+                    Ok(signalling) => {
+                        self.signalling = signalling;
+                        let encoding = self.encoding;
+                        self.outgoing = self
+                            .exchanged
+                            .local
+                            .iter()
+                            .map(|candidate| encoding.encode(candidate.clone()))
+                            .collect();
+                        if self.tx_finished && self.peer_end_of_candidates {
+                            self.outgoing.push_back(IceCandidateInit::end().to_json());
+                        }
+                        if self.tx_shut {
+                            self.outgoing.push_back(PROTOCOL_CLOSE.to_string());
+                        }
+                    }
+                    Err(e) => self.lost = Some(e),
+                }
+            }
+
+            if let Some(error) = self.lost.take() {
+                if self.reconnect.is_none() || self.reconnects == 0 {
+                    return Err(error.into());
+                }
+                self.reconnects -= 1;
+                trace::warn!(target: logging::ICE, "Signalling lost, reconnecting: {}", error);
+                self.diagnostics
+                    .warning(format!("Signalling lost, reconnecting: {error}"));
+                self.events.emit(ConnectionEvent::SignallingReconnecting {
+                    error: error.to_string(),
+                    remaining: self.reconnects,
+                });
+                self.reconnect_at = Some(Instant::now() + RECONNECT_BACKOFF);
+                continue;
+            }
+
+            match self.send_queued().await {
+                Ok(()) => return Ok(()),
+                Err(e) => self.lost = Some(e),
+            }
+        }
+    }
+
+    /// Sends the queued messages in order. Each leaves the queue once its
+    /// send resolved, one failed or cancelled is tried again by the next
+    /// call, so none is lost or overtaken. It may then reach the peer twice,
+    /// which skips what it already knows.
+    async fn send_queued(&mut self) -> Result<(), SignalingError> {
+        while let Some(msg) = self.outgoing.front() {
+            self.signalling
+                .send(msg.clone())
+                .await
+                .map_err(Into::into)?;
+            self.outgoing.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn resuming(&self) -> bool {
+        self.lost.is_some()
+            || self.reconnect_at.is_some()
+            || self.reconnecting.is_some()
+            || !self.outgoing.is_empty()
+    }
+
+    /// Cancel safe, a candidate is only taken from the channel once
+    /// returned, as long as the signalling channel is cancel safe too.
     pub async fn wait(&mut self) -> IceResult<CandidateExchangeValue<S>> {
-        select! {
-            candidate = self.candidate_rx.recv() => {
-                let candidate = candidate.expect("Candidate channel closed on handler side");
-                Ok(Either::Left(candidate))
+        loop {
+            if self.resuming() {
+                self.resume().await?;
             }
-            candidate = self.signalling.wait() => {
-                Ok(Either::Right(candidate.map_err(Into::into)?))
+
+            select! {
+                candidate = self.candidate_rx.recv() => {
+                    match candidate.ok_or(IceError::AgentClosed)? {
+                        Some(candidate) => return Ok(Either::Left(candidate)),
+                        None => trace::info!(target: logging::ICE, "Gathering complete"),
+                    }
+                }
+                candidate = self.signalling.wait() => match candidate {
+                    Ok(candidate) => return Ok(Either::Right(candidate)),
+                    Err(e) => self.signalling_lost(e.into())?,
+                }
             }
         }
     }
@@ -105,40 +1122,193 @@ where
         agent: Option<&Agent>,
         value: &mut CandidateExchangeValue<S>,
     ) -> IceResult<()> {
+        self.received_held(agent)?;
         let value = std::mem::replace(value, Either::Left(Default::default()));
         match value {
+            Either::Left(candidate) if self.tx_finished => {
+                trace::debug!(target: logging::ICE, "TX candidate {} dropped, candidates finished", candidate);
+            }
+            Either::Left(candidate) if self.duplicate(&candidate) => {}
             Either::Left(candidate) => {
-                log::info!("TX candidate {}", candidate);
-                self.signalling.send(candidate).await.map_err(Into::into)?;
+                trace::debug!(target: logging::ICE, "TX candidate {}", candidate);
+                self.exchanged.local.push(candidate.clone());
+                self.outgoing.push_back(self.encoding.encode(candidate));
+                if let Err(e) = self.send_queued().await {
+                    self.signalling_lost(e)?;
+                }
             }
-            Either::Right(mut value) => match self
-                .signalling
-                .then(&mut value)
-                .await
-                .map_err(Into::into)?
-                .as_deref()
-            {
-                None => {}
-                Some(PROTOCOL_CLOSE) => {
-                    log::info!("RX shutdown");
-                    self.rx_shut = true;
+            Either::Right(mut value) => match self.signalling.then(&mut value).await {
+                Err(e) => self.signalling_lost(e.into())?,
+                Ok(msg) => self.received(agent, msg.as_deref())?,
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Handles the message held by [`CandidateExchange::handshake`], if any.
+    fn received_held(&mut self, agent: Option<&Agent>) -> IceResult<()> {
+        match self.held.take() {
+            Some(msg) => self.received(agent, Some(&msg)),
+            None => Ok(()),
+        }
+    }
+
+    fn received(&mut self, agent: Option<&Agent>, msg: Option<&str>) -> IceResult<()> {
+        // Either encoding is accepted, whatever the one sent
+        let json = msg
+            .filter(|msg| msg.starts_with('{'))
+            .and_then(IceCandidateInit::candidate_from_json);
+        let msg = match &json {
+            Some(candidate) if candidate.is_empty() => {
+                trace::debug!(target: logging::ICE, "RX end of candidates");
+                self.rx_finished = true;
+                return Ok(());
+            }
+            Some(candidate) => Some(candidate.as_str()),
+            None => msg,
+        };
+        if let Some(candidate) = msg.filter(|msg| self.known(msg)) {
+            trace::debug!(target: logging::ICE, "RX candidate {} already known", candidate);
+            return Ok(());
+        }
+        if msg.is_some_and(|msg| msg != PROTOCOL_CLOSE) {
+            self.rx_candidates += 1;
+            if self.rx_candidates > MAX_CANDIDATES {
+                self.strictness.check(|| Violation::TooManyCandidates)?;
+            }
+        }
+        match msg {
+            None => {}
+            Some(PROTOCOL_CLOSE) => {
+                trace::info!(target: logging::ICE, "RX shutdown");
+                self.rx_shut = true;
+            }
+            Some(candidate) if self.rate_limited(candidate)? => {}
+            Some(candidate) if self.dropped_link_local(candidate) => {}
+            Some(candidate) => match agent {
+                Some(agent) => {
+                    let candidate: Arc<dyn Candidate + Send + Sync> =
+                        Arc::new(unmarshal_remote(candidate)?);
+                    let marshal = candidate.marshal();
+                    trace::debug!(target: logging::ICE, "RX candidate {}", marshal);
+                    agent.add_remote_candidate(&candidate)?;
+                    self.diagnostics.remote_candidate(candidate.as_ref());
+                    self.exchanged.remote.push(marshal);
+                }
+                None => {
+                    trace::debug!(target: logging::ICE, "RX candidate {} discarded", candidate);
+                    self.discarded(candidate, "no ICE agent".to_string());
                 }
-                Some(candidate) => match agent {
-                    Some(agent) => {
-                        log::info!("RX candidate {}", candidate);
-                        let candidate: Arc<dyn Candidate + Send + Sync> =
-                            Arc::new(unmarshal_candidate(candidate)?);
-                        agent.add_remote_candidate(&candidate)?;
-                    }
-                    None => {
-                        log::info!("RX candidate {} discarded", candidate);
-                    }
-                },
             },
         }
 
         Ok(())
     }
+
+    /// Whether `candidate` was already added to the agent, as the peer
+    /// announces all of its candidates again after reconnecting signalling.
+    /// Those count against neither the rate limit nor [`MAX_CANDIDATES`].
+    fn known(&self, candidate: &str) -> bool {
+        !self.exchanged.remote.is_empty()
+            && unmarshal_remote(candidate)
+                .is_ok_and(|candidate| self.exchanged.remote.contains(&candidate.marshal()))
+    }
+
+    fn dropped_link_local(&self, candidate: &str) -> bool {
+        if self.keep_link_local || !link_local(candidate) {
+            return false;
+        }
+        trace::debug!(target: logging::ICE, "RX candidate {} dropped, link-local", candidate);
+        self.discarded(candidate, "link-local address".to_string());
+        true
+    }
+
+    /// Whether `candidate` exceeds the rate limit of the peer and was
+    /// dropped. Only the first candidate dropped is warned about until the
+    /// limit lets one through again, the others are counted.
+    fn rate_limited(&mut self, candidate: &str) -> IceResult<bool> {
+        if self.rx_limiter.try_acquire() {
+            if self.rx_throttled > 1 {
+                trace::warn!(target: logging::ICE,
+                    "{} more RX candidates dropped, signalling rate limit exceeded",
+                    self.rx_throttled - 1
+                );
+            }
+            self.rx_throttled = 0;
+            return Ok(false);
+        }
+
+        self.strictness.check(|| Violation::CandidateRateLimit)?;
+        match self.rx_throttled {
+            0 => {
+                trace::warn!(target: logging::ICE,
+                    "RX candidate {} dropped, signalling rate limit exceeded",
+                    logged(candidate)
+                );
+                self.diagnostics.warning(
+                    "Remote candidates dropped, signalling rate limit exceeded".to_string(),
+                );
+            }
+            _ => trace::debug!(target: logging::ICE,
+                "RX candidate {} dropped, signalling rate limit exceeded",
+                logged(candidate)
+            ),
+        }
+        self.rx_throttled += 1;
+        self.discarded(candidate, "signalling rate limit exceeded".to_string());
+        Ok(true)
+    }
+
+    fn discarded(&self, candidate: &str, reason: String) {
+        self.events.emit(ConnectionEvent::CandidateDiscarded {
+            candidate: candidate.to_string(),
+            reason,
+        });
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Unpark {
+    Resume,
+    Close,
+}
+
+/// A [`CandidateExchange::park`]ed exchange, closed once dropped.
+pub struct ParkedExchange<S>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    unpark: Option<oneshot::Sender<Unpark>>,
+    exchange: oneshot::Receiver<IceResult<CandidateExchange<S>>>,
+}
+impl<S> ParkedExchange<S>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    /// Takes the exchange back, failing if the signalling channel failed
+    /// while parked.
+    pub async fn resume(mut self) -> IceResult<CandidateExchange<S>> {
+        self.unpark(Unpark::Resume).await
+    }
+
+    /// [`CandidateExchange::close`] on the servicing task.
+    pub async fn close(mut self) -> IceResult<()> {
+        self.unpark(Unpark::Close).await?;
+        Ok(())
+    }
+
+    async fn unpark(&mut self, unpark: Unpark) -> IceResult<CandidateExchange<S>> {
+        if let Some(tx) = self.unpark.take() {
+            let _ = tx.send(unpark);
+        }
+        match (&mut self.exchange).await {
+            Ok(r) => r,
+            Err(_) => Err(io::Error::other("Parked candidate exchange lost").into()),
+        }
+    }
 }
 
 pub struct IceAgent<S>
@@ -146,54 +1316,170 @@ where
     S: Signalling,
     S::Error: Into<SignalingError>,
 {
-    agent: Agent,
+    agent: Arc<Agent>,
     exchange: CandidateExchange<S>,
     dialer: bool,
+    format: SignallingFormat,
     connection: watch::Receiver<ConnectionState>,
+    policy: SignallingPolicy,
 }
 impl<S> IceAgent<S>
 where
     S: Signalling,
     S::Error: Into<SignalingError>,
 {
-    pub async fn new(signalling: S, dialer: bool, urls: Vec<Url>) -> IceResult<Self> {
+    pub async fn new(signalling: S, dialer: bool, config: IceConfig) -> IceResult<Self> {
+        // Before the handshake, so that the peer is not left waiting
+        config.gather_policy.check(&config.urls)?;
+
+        let (mut exchange, candidates_tx) = CandidateExchange::unshaken(
+            signalling,
+            dialer,
+            config.signalling_rate_limit,
+            config.format,
+            config.strict_roles,
+            config.strictness,
+            config.candidate_queue.unwrap_or(CANDIDATE_QUEUE),
+        );
+        // A rejecting peer sends its notice in place of the handshake
+        exchange.rejection = config.rejection;
+        exchange.shake().await?;
+        let diagnostics = &config.diagnostics;
+        diagnostics.signalling_format(config.format.name());
+        if let Some(supported) = exchange.peer_role_negotiation {
+            diagnostics.peer_role_negotiation(supported);
+        }
+        if exchange.dialer() != dialer {
+            diagnostics.warning(format!(
+                "Both peers were assigned the {} role, continuing as {}",
+                role_name(dialer),
+                role_name(exchange.dialer())
+            ));
+        }
+        let dialer = exchange.dialer();
+
+        // Empty credentials are generated randomly by the agent
+        let local = match config.format {
+            SignallingFormat::Native => get_local(dialer),
+            SignallingFormat::Sdp => "",
+        };
+        let network_types = vec![
+            webrtc_ice::network_type::NetworkType::Udp4,
+            webrtc_ice::network_type::NetworkType::Udp6,
+        ];
+        let urls = match &config.resolver {
+            Some(resolver) => resolve_urls(config.urls, resolver).await,
+            None => config.urls,
+        };
         let cfg = AgentConfig {
-            local_pwd: get_local(dialer).to_string(),
-            local_ufrag: get_local(dialer).to_string(),
-            network_types: vec![
-                webrtc_ice::network_type::NetworkType::Udp4,
-                webrtc_ice::network_type::NetworkType::Udp6,
-            ],
+            local_pwd: local.to_string(),
+            local_ufrag: local.to_string(),
+            network_types: network_types.clone(),
             urls,
             disconnected_timeout: None,
             ..AgentConfig::default()
         };
+        let cfg = match config.pair_selection {
+            PairSelection::Standard => cfg,
+            PairSelection::FirstResponding => AgentConfig {
+                host_acceptance_min_wait: Some(Duration::ZERO),
+                srflx_acceptance_min_wait: Some(Duration::ZERO),
+                prflx_acceptance_min_wait: Some(Duration::ZERO),
+                relay_acceptance_min_wait: Some(Duration::ZERO),
+                ..cfg
+            },
+        };
+        let cfg = match config.keep_link_local {
+            true => cfg,
+            false => AgentConfig {
+                ip_filter: Arc::new(Some(Box::new(|ip| !is_link_local(ip)))),
+                ..cfg
+            },
+        };
+        let cfg = match config.gather_policy {
+            GatherPolicy::Full => cfg,
+            GatherPolicy::RelayOnlyNoBind => AgentConfig {
+                candidate_types: vec![CandidateType::Relay],
+                multicast_dns_mode: MulticastDnsMode::Disabled,
+                ..cfg
+            },
+        };
+        let cfg = match &config.agent_config {
+            None => cfg,
+            Some(hook) => {
+                let mut cfg = cfg;
+                (hook.0)(&mut cfg);
+                AgentConfig {
+                    local_pwd: local.to_string(),
+                    local_ufrag: local.to_string(),
+                    network_types,
+                    ..cfg
+                }
+            }
+        };
 
-        let agent = Agent::new(cfg).await?;
-        let (exchange, candidates_tx) = CandidateExchange::new(signalling).await?;
+        let agent = Arc::new(Agent::new(cfg).await?);
+        let diagnostics = config.diagnostics.clone();
+        let gathering = Instant::now();
         agent.on_candidate(Box::new(move |c| {
+            match &c {
+                Some(c) => diagnostics.local_candidate(c.as_ref()),
+                None => diagnostics.timing("gathering", gathering.elapsed()),
+            }
             let send = candidates_tx.clone();
             Box::pin(async move {
-                if let Some(c) = c {
-                    send.send(c.marshal()).await.unwrap();
-                }
+                send.send(c.map(|c| c.marshal())).await;
             })
         }));
 
         let (connection_send, connection) = watch::channel(Default::default());
+        let span = trace::Span::current();
+        let diagnostics = config.diagnostics.clone();
         agent.on_connection_state_change(Box::new(move |state| {
+            span.in_scope(|| trace::info!(target: logging::ICE, "ICE state {}", state));
+            diagnostics.state(state);
             let _ = connection_send.send(state);
 
             std::future::ready(()).boxed()
         }));
 
+        let diagnostics = config.diagnostics.clone();
+        let events = config.events.clone();
+        agent.on_selected_candidate_pair_change(Box::new(move |local, remote| {
+            trace::debug!(
+                target: logging::ICE,
+                "Selected pair {} <-> {}",
+                local.marshal(),
+                remote.marshal()
+            );
+            diagnostics.selected_pair(local.as_ref(), remote.as_ref());
+            events.emit(ConnectionEvent::PairSelected {
+                local: local.marshal(),
+                remote: remote.marshal(),
+            });
+
+            std::future::ready(()).boxed()
+        }));
+
+        exchange.fingerprint = config.fingerprint;
+        exchange.diagnostics = config.diagnostics;
+        exchange.events = config.events;
+        exchange.keep_link_local = config.keep_link_local;
+        exchange.encoding = config.candidate_encoding;
+        exchange.received_held(Some(&agent))?;
+        if let Some(cache) = &config.candidate_cache {
+            exchange.seed(&agent, cache).await?;
+        }
+
         agent.gather_candidates()?;
 
         Ok(IceAgent {
             agent,
             exchange,
             dialer,
+            format: config.format,
             connection,
+            policy: config.signalling_policy,
         })
     }
 
@@ -213,35 +1499,38 @@ where
         async fn do_connect(
             agent: &Agent,
             dialer: bool,
+            remote_ufrag: String,
+            remote_pwd: String,
         ) -> Result<Arc<dyn Conn + Send + Sync>, webrtc_ice::Error> {
             let cancel = mpsc::channel(1);
             let r: Arc<dyn Conn + Send + Sync> = match dialer {
-                true => {
-                    agent
-                        .dial(
-                            cancel.1,
-                            get_remote(dialer).to_string(),
-                            get_remote(dialer).to_string(),
-                        )
-                        .await?
-                }
-                false => {
-                    agent
-                        .accept(
-                            cancel.1,
-                            get_remote(dialer).to_string(),
-                            get_remote(dialer).to_string(),
-                        )
-                        .await?
-                }
+                true => agent.dial(cancel.1, remote_ufrag, remote_pwd).await?,
+                false => agent.accept(cancel.1, remote_ufrag, remote_pwd).await?,
             };
             Ok(r)
         }
 
-        let conn_ing = do_connect(&self.agent, self.dialer);
+        let (remote_ufrag, remote_pwd) = match self.format {
+            SignallingFormat::Native => (
+                get_remote(self.dialer).to_string(),
+                get_remote(self.dialer).to_string(),
+            ),
+            SignallingFormat::Sdp => {
+                let remote = self.exchange.offer_answer(&self.agent).await?;
+                self.dialer = self.exchange.dialer();
+                (remote.ufrag, remote.pwd)
+            }
+        };
+        let trickle = self.format == SignallingFormat::Native;
+
+        let conn_ing = do_connect(&self.agent, self.dialer, remote_ufrag, remote_pwd);
         let connection_error = Self::fetch_connection_error(self.connection());
         pin_mut!(conn_ing);
         pin_mut!(connection_error);
+        let report_progress = self.exchange.events.is_enabled();
+        let mut progress = interval(PROGRESS_INTERVAL);
+        progress.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_progress = None;
         let net_conn = loop {
             let conn_ing = &mut conn_ing;
             let connection_error = &mut connection_error;
@@ -249,17 +1538,182 @@ where
                 conn = conn_ing => {
                     break conn?;
                 },
-                value = Self::wait2(&mut self.exchange) => {
+                value = Self::wait2(&mut self.exchange), if trickle => {
                     Self::then2(&self.agent, &mut self.exchange, &mut value?).await?;
                 }
                 r = connection_error => {
                     r?;
                 }
+                _ = progress.tick(), if report_progress => {
+                    let event = self.progress().await;
+                    if last_progress.as_ref() != Some(&event) {
+                        self.exchange.events.emit(event.clone());
+                        last_progress = Some(event);
+                    }
+                }
             }
         };
-        log::info!("ICE connected");
+        trace::info!(target: logging::ICE, "ICE connected");
+
+        Ok(net_conn)
+    }
+
+    /// See [`ConnectionEvent::IceProgress`].
+    async fn progress(&self) -> ConnectionEvent {
+        let mut local = CandidateCounts::default();
+        for candidate in self.agent.get_local_candidates_stats().await {
+            match candidate.candidate_type {
+                CandidateType::Host => local.host += 1,
+                CandidateType::ServerReflexive => local.srflx += 1,
+                CandidateType::PeerReflexive => local.prflx += 1,
+                CandidateType::Relay => local.relay += 1,
+                CandidateType::Unspecified => (),
+            }
+        }
+
+        let pairs = self.agent.get_candidate_pairs_stats().await;
+        let checking = pairs
+            .iter()
+            .filter(|pair| {
+                matches!(
+                    pair.state,
+                    CandidatePairState::Waiting | CandidatePairState::InProgress
+                )
+            })
+            .count();
+        let best_pair = [
+            CandidatePairState::Succeeded,
+            CandidatePairState::InProgress,
+            CandidatePairState::Waiting,
+            CandidatePairState::Failed,
+        ]
+        .into_iter()
+        .find(|state| pairs.iter().any(|pair| pair.state == *state))
+        .map(|state| state.to_string());
+
+        ConnectionEvent::IceProgress {
+            local,
+            remote: self.exchange.rx_candidates,
+            checking,
+            best_pair,
+        }
+    }
+
+    /// Keeps exchanging candidates once connected, so that those gathered
+    /// late, e.g. from a slow STUN or TURN server, are still checked. Runs
+    /// until the peer stops, signalling fails or the connection closes.
+    /// The selected pair is kept, the agent does not renominate. Nothing is
+    /// exchanged after the answer with [`SignallingFormat::Sdp`].
+    pub async fn keep_exchanging(mut self) -> IceResult<()> {
+        if self.format == SignallingFormat::Sdp {
+            return Ok(());
+        }
+        // Not worth restoring once connected
+        self.exchange.reconnect = None;
+
+        let closed = Self::fetch_connection_error(self.connection());
+        pin_mut!(closed);
+        while !self.exchange.rx_shut {
+            select! {
+                value = Self::wait2(&mut self.exchange) => {
+                    Self::then2(&self.agent, &mut self.exchange, &mut value?).await?;
+                }
+                _ = &mut closed => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hands the exchange over once connected, as the
+    /// [`IceConfig::signalling_policy`] says. Neither the exchange nor the
+    /// policy holds the connection up, the peer may follow another policy,
+    /// even one predating them all that drops the signalling channel.
+    /// Except with [`SignallingPolicy::Close`], the exchange goes on in a
+    /// task spawned with [`tokio::task::spawn_local`], so this must be
+    /// called from within a [`LocalSet`](tokio::task::LocalSet).
+    pub async fn settle(mut self) -> IceResult<()>
+    where
+        S: 'static,
+    {
+        match self.policy {
+            SignallingPolicy::Close => {
+                // The peer may be gone already, not worth waiting for
+                if let Err(e) = self.exchange.shutdown().await {
+                    trace::debug!(target: logging::ICE, "TX shutdown failed: {e}");
+                }
+            }
+            SignallingPolicy::KeepExchanging => {
+                let span = trace::info_span!("ice");
+                tokio::task::spawn_local(async move {
+                    if let Err(e) = self.keep_exchanging().instrument(span).await {
+                        trace::debug!(target: logging::ICE, "Candidate exchange ended: {e}");
+                    }
+                });
+            }
+            SignallingPolicy::Park => {
+                self.exchange.reconnect = None;
+                let closed = Self::fetch_connection_error(self.connection());
+                let parked = self.exchange.park().await?;
+                tokio::task::spawn_local(async move {
+                    let _ = closed.await;
+                    if let Err(e) = parked.close().await {
+                        trace::debug!(target: logging::ICE, "Parked candidate exchange ended: {e}");
+                    }
+                });
+            }
+        }
+
+        Ok(())
+    }
 
-        Ok(net_conn)
+    /// Lets [`IceAgent::connect`] replace the signalling channel up to
+    /// `attempts` times if it fails before ICE connects, the agreement and
+    /// credentials are already settled so only candidates are exchanged
+    /// again. Ignored for [`SignallingFormat::Sdp`].
+    pub fn set_signalling_reconnect(&mut self, reconnect: Reconnect<S>, attempts: u32) {
+        if self.format == SignallingFormat::Native {
+            self.exchange.reconnect = Some(reconnect);
+            self.exchange.reconnects = attempts;
+        }
+    }
+
+    /// Candidates exchanged so far, to speed up the next connection between
+    /// the same hosts.
+    pub fn candidate_cache(&self) -> CandidateCache {
+        self.exchange.exchanged.clone()
+    }
+
+    pub fn stats(&self) -> IceStats {
+        self.exchange.stats()
+    }
+
+    /// Role negotiated with the peer, see [`CandidateExchange::dialer`]. Only
+    /// final after [`IceAgent::connect`] with [`SignallingFormat::Sdp`].
+    pub fn dialer(&self) -> bool {
+        self.dialer
+    }
+
+    /// Whether the peer announced it confirms the derived key, see
+    /// [`Chacha20Stream::confirm_key`](crate::crypto_stream::Chacha20Stream::confirm_key).
+    /// Peers predating it, or exchanging [`SignallingFormat::Sdp`], would
+    /// take the token for data.
+    pub fn peer_confirms_keys(&self) -> bool {
+        self.exchange.peer_key_confirmation
+    }
+
+    /// Whether the peer announced it numbers its encrypted messages, see
+    /// [`Chacha20Stream::set_unnumbered`](crate::crypto_stream::Chacha20Stream::set_unnumbered).
+    /// Peers predating it, or exchanging [`SignallingFormat::Sdp`], fail to
+    /// open numbered messages.
+    pub fn peer_numbers_messages(&self) -> bool {
+        self.exchange.peer_sequence_numbers
+    }
+
+    /// The underlying agent, which keeps running after this is dropped until
+    /// [`Agent::close`] is called.
+    pub fn agent(&self) -> Arc<Agent> {
+        self.agent.clone()
     }
 
     pub fn connection(&self) -> watch::Receiver<ConnectionState> {
@@ -318,6 +1772,138 @@ where
     }
 }
 
+/// Claims the role of `dialer` with `nonce`, settling a conflict with a peer
+/// claiming the same. Dressed as a candidate, see [`ANNOUNCEMENT_CANDIDATE`],
+/// since peers predating it fail on anything else after the handshake.
+fn role_announcement(dialer: bool, nonce: &str) -> String {
+    format!(
+        "{PROTOCOL_START}/{}/{nonce} {ANNOUNCEMENT_CANDIDATE}",
+        role_name(dialer)
+    )
+}
+
+/// The `Icepipe/<role>/<nonce>` claim of a role announcement, `None` if
+/// `msg` is anything else.
+fn announced_role(msg: &str) -> Option<&str> {
+    let (announced, candidate) = msg.split_once(' ')?;
+    let is_announcement = announced.starts_with(PROTOCOL_START)
+        && announced[PROTOCOL_START.len()..].starts_with('/')
+        && candidate == ANNOUNCEMENT_CANDIDATE;
+    is_announcement.then_some(announced)
+}
+
+/// Whether `candidate` has an IPv6 link-local address, `fe80::/10`, with or
+/// without a `%scope` suffix. Those only reach peers on the same link, so
+/// they are dropped unless [`IceConfig::keep_link_local`].
+///
+/// The scope of a remote candidate is not kept: it names an interface of
+/// the peer, meaningless on this host, and is dropped before the candidate
+/// reaches the agent. The interface to reach it through is the one of the
+/// local candidate of the pair, but webrtc-ice builds socket addresses
+/// without a scope id, so kept candidates only work where the kernel picks
+/// the right interface on its own, e.g. on a host with a single link.
+pub fn link_local(candidate: &str) -> bool {
+    let Some(address) = candidate.split_whitespace().nth(4) else {
+        return false;
+    };
+    let address = address
+        .split_once('%')
+        .map_or(address, |(address, _)| address);
+    address
+        .parse::<Ipv6Addr>()
+        .is_ok_and(|ip| is_link_local(IpAddr::V6(ip)))
+}
+
+/// Parses a candidate received from the peer, its addresses stripped of
+/// their `%scope`, see [`link_local`]. webrtc-ice fails on scoped ones.
+fn unmarshal_remote(candidate: &str) -> Result<impl Candidate, webrtc_ice::Error> {
+    if !candidate.contains('%') {
+        return unmarshal_candidate(candidate);
+    }
+    let unscoped = candidate
+        .split_whitespace()
+        .map(|field| match field.split_once('%') {
+            Some((address, _)) if address.parse::<Ipv6Addr>().is_ok() => address,
+            _ => field,
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    unmarshal_candidate(&unscoped)
+}
+
+/// `candidate` with its addresses masked, e.g. `192.168.x.x` or
+/// `2001:db8:x:x:x:x:x:x`, for logs that end up pasted in public. Fields are
+/// `foundation component transport priority address port typ type` followed
+/// by attribute pairs, of which `raddr` is masked as well. mDNS names are
+/// kept, they hide the address already. Every field of a candidate that
+/// does not follow that format is masked if it is an address.
+pub fn redact_candidate(candidate: &str) -> String {
+    let mut fields = candidate
+        .split_whitespace()
+        .map(Cow::Borrowed)
+        .collect::<Vec<_>>();
+    match fields.get(6).map(Cow::as_ref) {
+        Some("typ") => {
+            fields[4] = mask_address(&fields[4]).into();
+            let attributes = fields.get_mut(8..).unwrap_or_default();
+            for pair in attributes.chunks_mut(2) {
+                if let [key, value] = pair {
+                    if key == "raddr" {
+                        *value = mask_address(value).into();
+                    }
+                }
+            }
+        }
+        _ => {
+            for field in &mut fields {
+                *field = mask_address(field).into();
+            }
+        }
+    }
+
+    fields.join(" ")
+}
+
+/// Keeps the first half of IPv4 addresses and the first two groups of IPv6
+/// ones, the scope is dropped. Anything else is returned as is.
+fn mask_address(address: &str) -> String {
+    let ip = address
+        .split_once('%')
+        .map_or(address, |(address, _)| address);
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            let [a, b, _, _] = ip.octets();
+            format!("{a}.{b}.x.x")
+        }
+        Ok(IpAddr::V6(ip)) => {
+            let [a, b, ..] = ip.segments();
+            format!("{a:x}:{b:x}:x:x:x:x:x:x")
+        }
+        Err(_) => address.to_string(),
+    }
+}
+
+/// How a candidate appears in warnings, masked with [`redact_candidate`]
+/// unless `ICEPIPE_LOG_FULL_CANDIDATES=1`. Debug logs carry candidates in
+/// full.
+fn logged(candidate: &str) -> Cow<'_, str> {
+    static FULL: OnceLock<bool> = OnceLock::new();
+    let full = FULL.get_or_init(|| {
+        std::env::var("ICEPIPE_LOG_FULL_CANDIDATES").is_ok_and(|value| value == "1")
+    });
+    match full {
+        true => Cow::Borrowed(candidate),
+        false => Cow::Owned(redact_candidate(candidate)),
+    }
+}
+
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+        IpAddr::V4(_) => false,
+    }
+}
+
 fn get_local(dialer: bool) -> &'static str {
     if dialer {
         "locallocallocallocal"
@@ -340,15 +1926,30 @@ pub enum IceError {
     SignalingError(SignalingError),
     #[error("Bad handshake, expected {expected:?} but got {0:?}", expected=PROTOCOL_START)]
     BadHandshake(String),
+    #[error("Both peers claimed the {0} role")]
+    RoleConflict(&'static str),
+    #[error("GatherPolicy::RelayOnlyNoBind needs a turn: server over UDP with credentials")]
+    NoRelay,
+    /// The agent, and with it the candidate handler, was dropped while
+    /// candidates were awaited.
+    #[error("ICE agent closed while gathering")]
+    AgentClosed,
+    #[error(transparent)]
+    Violation(#[from] Violation),
     #[error(transparent)]
     IceError(webrtc_ice::Error),
+    #[error(transparent)]
+    SdpError(#[from] SdpError),
 }
 impl From<SignalingError> for IceError {
     fn from(value: SignalingError) -> Self {
         match value {
             SignalingError::Io(e) => e.into(),
             SignalingError::Timeout(e) => e.into(),
-            e => Self::SignalingError(e),
+            e => match Violation::downcast(e) {
+                Ok(violation) => violation.into(),
+                Err(e) => Self::SignalingError(e),
+            },
         }
     }
 }
@@ -372,3 +1973,932 @@ impl From<IceError> for StreamError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_signalling::MemorySignalling;
+    use std::{cell::Cell, rc::Rc};
+    use tokio::task::LocalSet;
+    use webrtc_ice::tcp_type::TcpType;
+
+    async fn exchange(
+        signalling: MemorySignalling,
+        dialer: bool,
+        strict_roles: bool,
+    ) -> IceResult<bool> {
+        let (exchange, _) = CandidateExchange::new(
+            signalling,
+            dialer,
+            Default::default(),
+            SignallingFormat::Native,
+            strict_roles,
+            Strictness::Lenient,
+            CANDIDATE_QUEUE,
+        )
+        .await?;
+
+        Ok(exchange.dialer())
+    }
+
+    #[test]
+    fn ice_servers() {
+        let server: IceServer = "turn:relay.example:3478&user&secret".parse().unwrap();
+        assert_eq!(
+            server,
+            IceServer::new("turn:relay.example:3478").with_credentials("user", "secret")
+        );
+        let urls = server.parse_urls().unwrap();
+        assert_eq!(urls[0].host, "relay.example");
+        assert_eq!(urls[0].username, "user");
+        assert_eq!(urls[0].password, "secret");
+
+        let stun: IceServer = "stun:stun.example:19302".parse().unwrap();
+        assert_eq!(stun, IceServer::new("stun:stun.example:19302"));
+        assert!("relay.example:3478".parse::<IceServer>().is_err());
+    }
+
+    #[test]
+    fn ice_servers_json() {
+        let json = r#"[
+            {"urls": "stun:stun.example:19302"},
+            {
+                "urls": ["turn:relay.example:3478?transport=udp", "turns:relay.example:443"],
+                "username": "user",
+                "credential": "secret",
+                "credentialType": "password"
+            }
+        ]"#;
+        let relay = IceServer {
+            urls: vec![
+                "turn:relay.example:3478?transport=udp".to_string(),
+                "turns:relay.example:443".to_string(),
+            ],
+            ..IceServer::default()
+        }
+        .with_credentials("user", "secret");
+        let servers = IceServer::from_json(json).unwrap();
+        assert_eq!(
+            servers,
+            [IceServer::new("stun:stun.example:19302"), relay.clone()]
+        );
+        assert_eq!(servers[1].parse_urls().unwrap().len(), 2);
+
+        let configuration = r#"{"iceServers": [{"url": "stun:stun.example:19302"}]}"#;
+        assert_eq!(
+            IceServer::from_json(configuration).unwrap(),
+            [IceServer::new("stun:stun.example:19302")]
+        );
+        assert!(IceServer::from_json(r#"[{"username": "user"}]"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn role_negotiation() {
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(exchange(a, true, false), exchange(b, false, false));
+        assert_eq!((a.unwrap(), b.unwrap()), (true, false));
+
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(exchange(a, true, false), exchange(b, true, false));
+        assert_ne!(a.unwrap(), b.unwrap());
+
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(exchange(a, true, true), exchange(b, true, false));
+        assert!(matches!(a, Err(IceError::RoleConflict("dialer"))));
+        assert!(b.is_ok());
+    }
+
+    #[tokio::test]
+    async fn resume_survives_cancelled_waits() {
+        let new = |signalling, dialer| {
+            CandidateExchange::new(
+                signalling,
+                dialer,
+                Default::default(),
+                SignallingFormat::Native,
+                false,
+                Strictness::Lenient,
+                CANDIDATE_QUEUE,
+            )
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(new(a, true), new(b, false));
+        let (mut a, _candidates) = a.unwrap();
+        // Loses the signalling channel of `a`
+        drop(b);
+
+        let (replacement, mut peer) = MemorySignalling::pair();
+        let mut replacement = Some(replacement);
+        a.reconnect = Some(Box::new(move || {
+            let r = replacement
+                .take()
+                .ok_or_else(|| io::Error::other("Reconnected twice").into());
+            std::future::ready(r).boxed_local()
+        }));
+        a.reconnects = 1;
+        a.exchanged.local.push("candidate".to_string());
+
+        // Dropped many times over while backing off and reconnecting
+        let resent = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(r) = tokio::time::timeout(Duration::from_millis(7), a.wait()).await {
+                    r.unwrap();
+                }
+                if let Some(msg) = peer.wait().now_or_never() {
+                    break msg.unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(resent, "candidate");
+        assert_eq!(a.reconnects, 0);
+        assert!(peer.wait().now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn plain_handshake() {
+        let (a, mut b) = MemorySignalling::pair();
+        b.send(PROTOCOL_START.into()).await.unwrap();
+        b.send(CANDIDATE.into()).await.unwrap();
+
+        // Peers predating role negotiation keep the assigned roles
+        assert!(exchange(a, true, true).await.unwrap());
+    }
+
+    #[test]
+    fn announcement_is_ignored_by_older_peers() {
+        let announcement = role_announcement(true, "0123456789abcdef;end-of-candidates");
+        assert_eq!(
+            announced_role(&announcement),
+            Some("Icepipe/dialer/0123456789abcdef;end-of-candidates")
+        );
+        assert_eq!(announced_role(CANDIDATE), None);
+        assert_eq!(announced_role(PROTOCOL_CLOSE), None);
+
+        // Their agent skips active TCP candidates
+        let candidate = unmarshal_candidate(&announcement).unwrap();
+        assert_eq!(candidate.tcp_type(), TcpType::Active);
+    }
+
+    #[tokio::test]
+    async fn strict_plain_handshake() {
+        let (a, mut b) = MemorySignalling::pair();
+        b.send(PROTOCOL_START.into()).await.unwrap();
+        b.send(CANDIDATE.into()).await.unwrap();
+
+        let r = CandidateExchange::new(
+            a,
+            true,
+            Default::default(),
+            SignallingFormat::Native,
+            false,
+            Strictness::Strict,
+            CANDIDATE_QUEUE,
+        )
+        .await;
+        assert!(matches!(
+            r,
+            Err(IceError::Violation(Violation::LegacyHandshake))
+        ));
+    }
+
+    /// Without a handshake, candidates are received without an agent.
+    async fn sdp_exchange(
+        rx_limit: RateLimit,
+        strictness: Strictness,
+    ) -> CandidateExchange<MemorySignalling> {
+        let (signalling, _) = MemorySignalling::pair();
+        let (exchange, _) = CandidateExchange::new(
+            signalling,
+            true,
+            rx_limit,
+            SignallingFormat::Sdp,
+            false,
+            strictness,
+            CANDIDATE_QUEUE,
+        )
+        .await
+        .unwrap();
+        exchange
+    }
+
+    #[test]
+    fn link_local_candidates() {
+        let candidate = |address| format!("1 1 udp 2130706431 {address} 5000 typ host");
+        assert!(link_local(&candidate("fe80::1")));
+        assert!(link_local(&candidate("fe80::1%eth0")));
+        assert!(link_local(&candidate("febf::1")));
+        assert!(link_local(&format!("candidate:{}", candidate("fe80::1"))));
+        assert!(!link_local(&candidate("fec0::1")));
+        assert!(!link_local(&candidate("2001:db8::1")));
+        assert!(!link_local(&candidate("169.254.0.1")));
+        assert!(!link_local(&candidate("5e9f2a40.local")));
+        assert!(!link_local("garbage"));
+    }
+
+    #[test]
+    fn redacted_candidates() {
+        assert_eq!(
+            redact_candidate("1 1 udp 2130706431 192.168.1.5 50000 typ host"),
+            "1 1 udp 2130706431 192.168.x.x 50000 typ host"
+        );
+        assert_eq!(
+            redact_candidate(
+                "candidate:2 1 udp 1694498815 203.0.113.7 6000 typ srflx raddr 10.0.0.2 rport 50000"
+            ),
+            "candidate:2 1 udp 1694498815 203.0.x.x 6000 typ srflx raddr 10.0.x.x rport 50000"
+        );
+        assert_eq!(
+            redact_candidate(
+                "3 1 udp 16777215 2001:db8:1:2::7 3478 typ relay raddr 2001:db8:3:4::1%2 rport 5000"
+            ),
+            "3 1 udp 16777215 2001:db8:x:x:x:x:x:x 3478 typ relay \
+             raddr 2001:db8:x:x:x:x:x:x rport 5000"
+        );
+        assert_eq!(
+            redact_candidate("4 1 udp 2130706431 fe80::1%eth0 5000 typ host generation 0"),
+            "4 1 udp 2130706431 fe80:0:x:x:x:x:x:x 5000 typ host generation 0"
+        );
+        // Hide the address already
+        let mdns = "5 1 udp 2130706431 5e9f2a40-7c1b-4c8e-9d2f-1b3e4a5c6d7e.local 5000 typ host";
+        assert_eq!(redact_candidate(mdns), mdns);
+        assert_eq!(
+            redact_candidate("6 1 udp 1 10.0.0.1 5000 typ"),
+            "6 1 udp 1 10.0.x.x 5000 typ"
+        );
+        // Not a candidate, addresses are still masked
+        assert_eq!(
+            redact_candidate("garbage 192.168.1.5 typo"),
+            "garbage 192.168.x.x typo"
+        );
+    }
+
+    #[tokio::test]
+    async fn agent_config_hook() {
+        let called = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let hooked = called.clone();
+        let config = IceConfig {
+            agent_config: Some(AgentConfigHook::new(move |cfg| {
+                hooked.store(true, Ordering::Relaxed);
+                cfg.check_interval = Duration::from_millis(50);
+                // Set again by icepipe
+                cfg.local_ufrag = "overridden".to_string();
+            })),
+            ..Default::default()
+        };
+        let (a, b) = MemorySignalling::pair();
+        let (a, b) = tokio::join!(
+            IceAgent::new(a, true, config),
+            IceAgent::new(b, false, Default::default()),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        let (conn_a, conn_b) = tokio::join!(a.connect(), b.connect());
+        conn_a.unwrap();
+        conn_b.unwrap();
+
+        assert!(called.load(Ordering::Relaxed));
+        let (ufrag, _) = a.agent().get_local_user_credentials().await;
+        assert_eq!(ufrag, get_local(true));
+    }
+
+    #[tokio::test]
+    async fn sdp_double_listener() {
+        let listeners = |strict_roles| async move {
+            let config = || IceConfig {
+                format: SignallingFormat::Sdp,
+                strict_roles,
+                ..Default::default()
+            };
+            let (a, b) = MemorySignalling::pair();
+            let (a, b) = tokio::join!(
+                IceAgent::new(a, false, config()),
+                IceAgent::new(b, false, config()),
+            );
+            let (mut a, mut b) = (a.unwrap(), b.unwrap());
+            a.exchange.offer_wait = Duration::from_millis(100);
+            b.exchange.offer_wait = Duration::from_millis(100);
+            let (conn_a, conn_b) = tokio::join!(a.connect(), b.connect());
+            (a.dialer(), b.dialer(), conn_a.map(drop), conn_b.map(drop))
+        };
+
+        let (a, b, conn_a, conn_b) = listeners(false).await;
+        conn_a.unwrap();
+        conn_b.unwrap();
+        assert_ne!(a, b);
+
+        let (_, _, conn_a, conn_b) = listeners(true).await;
+        for r in [conn_a, conn_b] {
+            assert!(matches!(r, Err(IceError::RoleConflict("listener"))));
+        }
+    }
+
+    #[tokio::test]
+    async fn progress_events() {
+        let (a, b) = MemorySignalling::pair();
+        let events = Events::new();
+        let mut rx = events.subscribe();
+        let config = IceConfig {
+            events,
+            ..Default::default()
+        };
+        let (a, b) = tokio::join!(
+            IceAgent::new(a, true, config),
+            IceAgent::new(b, false, Default::default()),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        let (conn_a, conn_b) = tokio::join!(a.connect(), b.connect());
+        conn_a.unwrap();
+        conn_b.unwrap();
+
+        let mut progress = vec![];
+        while let Ok(event) = rx.try_recv() {
+            if let ConnectionEvent::IceProgress { .. } = event {
+                progress.push(event);
+            }
+        }
+        assert!(!progress.is_empty());
+        // Only emitted on change
+        assert!(progress.windows(2).all(|w| w[0] != w[1]));
+        assert!(progress[0].to_string().starts_with("gathering "));
+
+        a.agent().close().await.unwrap();
+        b.agent().close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn candidate_bursts() {
+        let candidate = |port| format!("1 1 udp 2130706431 10.0.0.1 {port} typ host");
+        let (a, b) = MemorySignalling::pair();
+        let new = |signalling, dialer| {
+            CandidateExchange::new(
+                signalling,
+                dialer,
+                Default::default(),
+                SignallingFormat::Native,
+                false,
+                Strictness::Lenient,
+                4,
+            )
+        };
+        let (a, b) = tokio::join!(new(a, true), new(b, false));
+        let (mut a, candidates) = a.unwrap();
+        let (b, _) = b.unwrap();
+
+        // Duplicates, then more than fit in the queue
+        for port in [1, 1, 2, 1, 3, 4, 5, 6] {
+            candidates.send(Some(candidate(port))).await;
+        }
+        while let Some(Ok(mut value)) = a.wait().now_or_never() {
+            a.then(None, &mut value).await.unwrap();
+        }
+        assert_eq!(
+            a.stats(),
+            IceStats {
+                duplicate_candidates: 2,
+                dropped_candidates: 4,
+            }
+        );
+
+        // Drained, gathering goes on and the end of it is never dropped
+        for port in [4, 2, 5] {
+            candidates.send(Some(candidate(port))).await;
+        }
+        candidates.send(None).await;
+        while let Some(Ok(mut value)) = a.wait().now_or_never() {
+            a.then(None, &mut value).await.unwrap();
+        }
+        assert_eq!(a.stats().duplicate_candidates, 3);
+
+        let mut b = b.signalling;
+        let mut wire = vec![];
+        while let Some(msg) = b.wait().now_or_never() {
+            wire.push(msg.unwrap());
+        }
+        assert_eq!(wire, [1, 2, 4, 5].map(candidate));
+    }
+
+    /// Answers pings from `then` like [`crate::ws::Websocket`] and only
+    /// sends after yielding, as a signalling with a busy sink would.
+    struct Pinging {
+        inner: MemorySignalling,
+        outgoing: VecDeque<String>,
+    }
+    impl Pinging {
+        async fn send_queued(&mut self) -> Result<(), SignalingError> {
+            tokio::task::yield_now().await;
+            while let Some(msg) = self.outgoing.pop_front() {
+                self.inner.send(msg).await?;
+            }
+            Ok(())
+        }
+    }
+    impl Signalling for Pinging {
+        fn send(&mut self, msg: String) -> LocalBoxFuture<'_, Result<(), SignalingError>> {
+            async move {
+                self.outgoing.push_back(msg);
+                self.send_queued().await
+            }
+            .boxed_local()
+        }
+    }
+    impl WaitThen for Pinging {
+        type Value = String;
+        type Output = Option<String>;
+        type Error = SignalingError;
+
+        fn wait(&mut self) -> LocalBoxFuture<'_, Result<Self::Value, SignalingError>> {
+            async move {
+                if !self.outgoing.is_empty() {
+                    self.send_queued().await?;
+                }
+                self.inner.wait().await
+            }
+            .boxed_local()
+        }
+
+        fn then<'a>(
+            &'a mut self,
+            value: &'a mut Self::Value,
+        ) -> LocalBoxFuture<'a, Result<Self::Output, SignalingError>> {
+            async move {
+                if value == "ping" {
+                    self.outgoing.push_back("pong".to_string());
+                    self.send_queued().await?;
+                    return Ok(None);
+                }
+                self.inner.then(value).await
+            }
+            .boxed_local()
+        }
+    }
+
+    /// Fails sending while `refuse` is set.
+    struct Refusing {
+        inner: MemorySignalling,
+        refuse: Rc<Cell<bool>>,
+    }
+    impl Signalling for Refusing {
+        fn send(&mut self, msg: String) -> LocalBoxFuture<'_, Result<(), SignalingError>> {
+            match self.refuse.get() {
+                true => std::future::ready(Err(io::Error::other("Refused").into())).boxed_local(),
+                false => self.inner.send(msg),
+            }
+        }
+    }
+    impl WaitThen for Refusing {
+        type Value = String;
+        type Output = Option<String>;
+        type Error = SignalingError;
+
+        fn wait(&mut self) -> LocalBoxFuture<'_, Result<Self::Value, SignalingError>> {
+            self.inner.wait()
+        }
+
+        fn then<'a>(
+            &'a mut self,
+            value: &'a mut Self::Value,
+        ) -> LocalBoxFuture<'a, Result<Self::Output, SignalingError>> {
+            self.inner.then(value)
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_send_stays_queued() {
+        let (a, peer) = MemorySignalling::pair();
+        let refuse = Rc::new(Cell::new(false));
+        let a = Refusing {
+            inner: a,
+            refuse: refuse.clone(),
+        };
+        let (mut a, _candidates, mut peer) = scripted(a, peer, PEER_HANDSHAKE).await;
+
+        refuse.set(true);
+        a.outgoing.push_back(CANDIDATE.to_string());
+        a.send_queued().await.unwrap_err();
+        assert_eq!(a.outgoing, [CANDIDATE]);
+
+        refuse.set(false);
+        a.send_queued().await.unwrap();
+        assert!(a.outgoing.is_empty());
+        assert_eq!(wire(&mut peer), [CANDIDATE]);
+    }
+
+    #[tokio::test]
+    async fn ordered_with_pings() {
+        let candidate = |port| format!("1 1 udp 2130706431 10.0.0.1 {port} typ host");
+        let (a, b) = MemorySignalling::pair();
+        let a = Pinging {
+            inner: a,
+            outgoing: VecDeque::new(),
+        };
+        let (a, b) = tokio::join!(
+            CandidateExchange::new(
+                a,
+                true,
+                Default::default(),
+                SignallingFormat::Native,
+                false,
+                Strictness::Lenient,
+                CANDIDATE_QUEUE,
+            ),
+            CandidateExchange::new(
+                b,
+                false,
+                Default::default(),
+                SignallingFormat::Native,
+                false,
+                Strictness::Lenient,
+                CANDIDATE_QUEUE,
+            ),
+        );
+        let (mut a, candidates) = a.unwrap();
+        let mut b = b.unwrap().0.signalling;
+
+        for burst in 0..20 {
+            for port in 0..5 {
+                b.send("ping".to_string()).await.unwrap();
+                candidates.send(Some(candidate(burst * 5 + port))).await;
+            }
+            loop {
+                // Waits dropped halfway, cut short by the yielding sends
+                let value = match a.wait().now_or_never() {
+                    Some(value) => value,
+                    None => match tokio::time::timeout(Duration::from_millis(10), a.wait()).await {
+                        Ok(value) => value,
+                        Err(_) => break,
+                    },
+                };
+                a.then(None, &mut value.unwrap()).await.unwrap();
+            }
+        }
+        // A close dropped after its first poll is sent again, the peer
+        // ignores the repeat
+        assert!(a.close().now_or_never().is_none());
+        b.send(PROTOCOL_CLOSE.to_string()).await.unwrap();
+        a.close().await.unwrap();
+
+        let mut wire = vec![];
+        while let Some(msg) = tokio::task::unconstrained(b.wait()).now_or_never() {
+            wire.push(msg.unwrap());
+        }
+        let pongs = wire.iter().filter(|msg| *msg == "pong").count();
+        wire.retain(|msg| msg != "pong");
+        let mut expected = (0..100).map(candidate).collect::<Vec<_>>();
+        expected.extend([PROTOCOL_CLOSE.to_string(), PROTOCOL_CLOSE.to_string()]);
+        assert_eq!(wire, expected);
+        assert_eq!(pongs, 100);
+    }
+
+    #[tokio::test]
+    async fn drops_link_local() {
+        let candidate = "1 1 udp 2130706431 fe80::1%eth0 5000 typ host";
+        let mut exchange = sdp_exchange(Default::default(), Strictness::Lenient).await;
+        exchange.events = Events::new();
+        let mut events = exchange.events.subscribe();
+
+        exchange.received(None, Some(candidate)).unwrap();
+        exchange.keep_link_local = true;
+        exchange.received(None, Some(candidate)).unwrap();
+
+        for reason in ["link-local address", "no ICE agent"] {
+            assert_eq!(
+                events.try_recv(),
+                Ok(ConnectionEvent::CandidateDiscarded {
+                    candidate: candidate.to_string(),
+                    reason: reason.to_string(),
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn agent_closed() {
+        for format in [SignallingFormat::Native, SignallingFormat::Sdp] {
+            let (signalling, _peer) = MemorySignalling::pair();
+            let (mut exchange, candidates) = CandidateExchange::unshaken(
+                signalling,
+                true,
+                Default::default(),
+                format,
+                false,
+                Strictness::Lenient,
+                CANDIDATE_QUEUE,
+            );
+            drop(candidates);
+
+            let r = match format {
+                SignallingFormat::Native => exchange.wait().await.map(|_| ()),
+                SignallingFormat::Sdp => {
+                    let agent = Agent::new(Default::default()).await.unwrap();
+                    exchange.offer_answer(&agent).await.map(|_| ())
+                }
+            };
+            assert!(matches!(r, Err(IceError::AgentClosed)), "{format:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_link_local_unscoped() {
+        let mut exchange = sdp_exchange(Default::default(), Strictness::Strict).await;
+        exchange.keep_link_local = true;
+        let agent = Agent::new(Default::default()).await.unwrap();
+
+        exchange
+            .received(
+                Some(&agent),
+                Some("1 1 udp 2130706431 fe80::1%eth0 5000 typ host"),
+            )
+            .unwrap();
+        exchange
+            .received(
+                Some(&agent),
+                Some("2 1 udp 1694498815 2001:db8::1 6000 typ srflx raddr fe80::2%3 rport 7000"),
+            )
+            .unwrap();
+        let remote = &exchange.exchanged.remote;
+        assert_eq!(remote.len(), 2);
+        assert!(remote[0].contains(" fe80::1 5000 "), "{remote:?}");
+        assert!(
+            remote[1].contains(" raddr fe80::2 rport 7000"),
+            "{remote:?}"
+        );
+        agent.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn strict_rate_limit() {
+        let rx_limit = RateLimit {
+            burst: 1,
+            per_second: 1,
+        };
+        let mut lenient = sdp_exchange(rx_limit, Strictness::Lenient).await;
+        let mut strict = sdp_exchange(rx_limit, Strictness::Strict).await;
+        for exchange in [&mut lenient, &mut strict] {
+            exchange.received(None, Some("candidate")).unwrap();
+        }
+
+        lenient.received(None, Some("candidate")).unwrap();
+        assert!(matches!(
+            strict.received(None, Some("candidate")),
+            Err(IceError::Violation(Violation::CandidateRateLimit))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_warns_once() {
+        let rx_limit = RateLimit {
+            burst: 1,
+            per_second: 1,
+        };
+        let mut exchange = sdp_exchange(rx_limit, Strictness::Lenient).await;
+        exchange.diagnostics = Diagnostics::new();
+        let warnings = |exchange: &CandidateExchange<_>| {
+            exchange.diagnostics.session().unwrap().warnings.len()
+        };
+
+        for _ in 0..5 {
+            exchange.received(None, Some("candidate")).unwrap();
+        }
+        assert_eq!(exchange.rx_throttled, 4);
+        assert_eq!(warnings(&exchange), 1);
+
+        // The limit lets one through again, the next flood is warned about
+        exchange.rx_limiter = RateLimiter::new(rx_limit);
+        exchange.received(None, Some("candidate")).unwrap();
+        assert_eq!(exchange.rx_throttled, 0);
+        exchange.received(None, Some("candidate")).unwrap();
+        assert_eq!(exchange.rx_throttled, 1);
+        assert_eq!(warnings(&exchange), 2);
+    }
+
+    #[tokio::test]
+    async fn resent_candidates_exempt() {
+        let rx_limit = RateLimit {
+            burst: 1,
+            per_second: 1,
+        };
+        let mut exchange = sdp_exchange(rx_limit, Strictness::Strict).await;
+        let known = unmarshal_remote(CANDIDATE).unwrap().marshal();
+        exchange.exchanged.remote.push(known);
+
+        // As resent after reconnecting signalling
+        for _ in 0..=MAX_CANDIDATES {
+            exchange.received(None, Some(CANDIDATE)).unwrap();
+        }
+        assert_eq!(exchange.rx_candidates, 0);
+        exchange.received(None, Some("candidate")).unwrap();
+        assert_eq!(exchange.rx_throttled, 0);
+    }
+
+    #[tokio::test]
+    async fn strict_candidate_count() {
+        let mut lenient = sdp_exchange(Default::default(), Strictness::Lenient).await;
+        let mut strict = sdp_exchange(Default::default(), Strictness::Strict).await;
+        for exchange in [&mut lenient, &mut strict] {
+            for _ in 0..MAX_CANDIDATES {
+                exchange.received(None, Some("candidate")).unwrap();
+            }
+            exchange.received(None, Some(PROTOCOL_CLOSE)).unwrap();
+        }
+
+        lenient.received(None, Some("candidate")).unwrap();
+        assert!(matches!(
+            strict.received(None, Some("candidate")),
+            Err(IceError::Violation(Violation::TooManyCandidates))
+        ));
+    }
+
+    /// An exchange handshaken with a peer left to the test, which sent
+    /// `handshake`.
+    async fn scripted<S: Signalling>(
+        signalling: S,
+        mut peer: MemorySignalling,
+        handshake: &[&str],
+    ) -> (CandidateExchange<S>, CandidateSender, MemorySignalling)
+    where
+        S::Error: Into<SignalingError>,
+    {
+        for msg in handshake {
+            peer.send(msg.to_string()).await.unwrap();
+        }
+        let (exchange, candidates) = CandidateExchange::new(
+            signalling,
+            true,
+            Default::default(),
+            SignallingFormat::Native,
+            false,
+            Strictness::Lenient,
+            CANDIDATE_QUEUE,
+        )
+        .await
+        .unwrap();
+        assert_eq!(peer.wait().await.unwrap(), PROTOCOL_START);
+        let sent = peer.wait().await.unwrap();
+        let announced = announced_role(&sent).unwrap();
+        assert!(
+            announced.ends_with(";end-of-candidates,key-confirmation,sequence-numbers"),
+            "{sent}"
+        );
+
+        (exchange, candidates, peer)
+    }
+
+    const PEER_HANDSHAKE: &[&str] = &[
+        PROTOCOL_START,
+        "Icepipe/listener/0123456789abcdef;end-of-candidates 1 tcp 0 0.0.0.0 9 typ host tcptype active",
+    ];
+    const CANDIDATE: &str = "1 1 udp 2130706431 10.0.0.1 5000 typ host";
+
+    /// Every message sent to `peer` so far, until the exchange is dropped.
+    fn wire(peer: &mut MemorySignalling) -> Vec<String> {
+        let mut wire = vec![];
+        while let Some(Ok(msg)) = tokio::task::unconstrained(peer.wait()).now_or_never() {
+            wire.push(msg);
+        }
+        wire
+    }
+
+    async fn pump<S: Signalling>(exchange: &mut CandidateExchange<S>)
+    where
+        S::Error: Into<SignalingError>,
+    {
+        let mut value = exchange.wait().await.unwrap();
+        exchange.then(None, &mut value).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn finish_candidates() {
+        let (a, peer) = MemorySignalling::pair();
+        let (mut a, candidates, mut peer) = scripted(a, peer, PEER_HANDSHAKE).await;
+        candidates.send(Some(CANDIDATE.to_string())).await;
+        pump(&mut a).await;
+        a.finish_candidates().await.unwrap();
+        a.finish_candidates().await.unwrap();
+        candidates
+            .send(Some("2 1 udp 1 10.0.0.2 5000 typ host".to_string()))
+            .await;
+        pump(&mut a).await;
+
+        let end = IceCandidateInit::end().to_json();
+        assert_eq!(wire(&mut peer), [CANDIDATE.to_string(), end.clone()]);
+        assert_eq!(
+            IceCandidateInit::candidate_from_json(&end).as_deref(),
+            Some("")
+        );
+
+        assert!(!a.peer_finished());
+        peer.send(end).await.unwrap();
+        pump(&mut a).await;
+        assert!(a.peer_finished());
+        assert_eq!(a.rx_candidates, 0);
+    }
+
+    #[tokio::test]
+    async fn finish_candidates_legacy_peer() {
+        let announcement =
+            "Icepipe/listener/0123456789abcdef 1 tcp 0 0.0.0.0 9 typ host tcptype active";
+        for handshake in [[PROTOCOL_START, CANDIDATE], [PROTOCOL_START, announcement]] {
+            let (a, peer) = MemorySignalling::pair();
+            let (mut a, candidates, mut peer) = scripted(a, peer, &handshake).await;
+            a.finish_candidates().await.unwrap();
+            candidates.send(Some(CANDIDATE.to_string())).await;
+            pump(&mut a).await;
+
+            // Still closes the way such peers expect
+            peer.send(PROTOCOL_CLOSE.to_string()).await.unwrap();
+            a.close().await.unwrap();
+            assert_eq!(wire(&mut peer), [PROTOCOL_CLOSE]);
+        }
+    }
+
+    #[tokio::test]
+    async fn key_confirmation_feature() {
+        let confirming = "Icepipe/listener/0123456789abcdef;end-of-candidates,key-confirmation 1 tcp 0 0.0.0.0 9 typ host tcptype active";
+        for (handshake, confirms) in [
+            ([PROTOCOL_START, confirming], true),
+            ([PROTOCOL_START, PEER_HANDSHAKE[1]], false),
+            ([PROTOCOL_START, CANDIDATE], false),
+        ] {
+            let (a, peer) = MemorySignalling::pair();
+            let (a, _candidates, _peer) = scripted(a, peer, &handshake).await;
+            assert_eq!(a.peer_key_confirmation, confirms, "{handshake:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn sequence_numbers_feature() {
+        let numbering = "Icepipe/listener/0123456789abcdef;end-of-candidates,key-confirmation,sequence-numbers 1 tcp 0 0.0.0.0 9 typ host tcptype active";
+        let confirming = "Icepipe/listener/0123456789abcdef;end-of-candidates,key-confirmation 1 tcp 0 0.0.0.0 9 typ host tcptype active";
+        for (handshake, numbers) in [
+            ([PROTOCOL_START, numbering], true),
+            ([PROTOCOL_START, confirming], false),
+            ([PROTOCOL_START, CANDIDATE], false),
+        ] {
+            let (a, peer) = MemorySignalling::pair();
+            let (a, _candidates, _peer) = scripted(a, peer, &handshake).await;
+            assert_eq!(a.peer_sequence_numbers, numbers, "{handshake:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn park_and_resume() {
+        LocalSet::new()
+            .run_until(async {
+                let (a, peer) = MemorySignalling::pair();
+                let a = Pinging {
+                    inner: a,
+                    outgoing: VecDeque::new(),
+                };
+                let (a, candidates, mut peer) = scripted(a, peer, PEER_HANDSHAKE).await;
+                let parked = a.park().await.unwrap();
+                assert_eq!(wire(&mut peer), [IceCandidateInit::end().to_json()]);
+
+                // Serviced meanwhile, candidates of either side go nowhere
+                peer.send("ping".to_string()).await.unwrap();
+                assert_eq!(peer.wait().await.unwrap(), "pong");
+                peer.send(CANDIDATE.to_string()).await.unwrap();
+                candidates.send(Some(CANDIDATE.to_string())).await;
+                peer.send("ping".to_string()).await.unwrap();
+                assert_eq!(peer.wait().await.unwrap(), "pong");
+
+                let mut a = parked.resume().await.unwrap();
+                assert_eq!(a.rx_candidates, 1);
+                assert!(a.exchanged.remote.is_empty());
+                peer.send(PROTOCOL_CLOSE.to_string()).await.unwrap();
+                a.close().await.unwrap();
+                assert_eq!(wire(&mut peer), [PROTOCOL_CLOSE]);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn parked_close() {
+        LocalSet::new()
+            .run_until(async {
+                let (a, peer) = MemorySignalling::pair();
+                let (a, _candidates, mut peer) = scripted(a, peer, PEER_HANDSHAKE).await;
+                let parked = a.park().await.unwrap();
+                peer.send(PROTOCOL_CLOSE.to_string()).await.unwrap();
+                parked.close().await.unwrap();
+                assert_eq!(
+                    wire(&mut peer),
+                    [
+                        IceCandidateInit::end().to_json(),
+                        PROTOCOL_CLOSE.to_string()
+                    ]
+                );
+
+                // Dropping it closes too
+                let (a, peer) = MemorySignalling::pair();
+                let (a, _candidates, mut peer) = scripted(a, peer, PEER_HANDSHAKE).await;
+                drop(a.park().await.unwrap());
+                assert_eq!(
+                    peer.wait().await.unwrap(),
+                    IceCandidateInit::end().to_json()
+                );
+                assert_eq!(peer.wait().await.unwrap(), PROTOCOL_CLOSE);
+            })
+            .await;
+    }
+}
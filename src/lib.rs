@@ -1,17 +1,95 @@
+//! Peer to peer connections over ICE and SCTP, encrypted with ChaCha20-Poly1305.
+//!
+//! Connections are driven by `LocalBoxFuture`s and are not `Send`, they are meant to be awaited
+//! on the task that owns them. A current thread runtime, or a `LocalSet` within a multi thread
+//! one, is enough and often cheaper for point to point use.
+//!
+//! The layers can be built on their own, with default features disabled:
+//! `crypto` for [`agreement`] and [`crypto_stream`], which need the `ring` or
+//! the `rustcrypto` backend, `ice-transport` for [`ice`] and [`sctp`] and
+//! `ws-signalling` for [`ws`]. `full`, enabled by default, adds [`connect`]
+//! and everything built on top of it.
+
+#[cfg(feature = "crypto")]
 pub mod agreement;
 pub mod async_pipe_stream;
+pub mod boxed_stream;
+pub mod capabilities;
+#[cfg(feature = "crypto")]
+pub mod channel;
+#[cfg(feature = "full")]
+pub mod channel_hopping;
+#[cfg(feature = "full")]
 pub mod connect;
+#[cfg(feature = "full")]
+pub mod connect_limiter;
 pub mod constants;
+#[cfg(feature = "crypto")]
+pub mod crypto_backend;
+#[cfg(feature = "crypto")]
 pub mod crypto_stream;
+#[cfg(feature = "crypto")]
 pub mod curve25519_conversion;
+pub mod deadline;
+#[cfg(feature = "ice-transport")]
+pub mod delay_probe;
+pub mod diagnostics;
+#[cfg(feature = "full")]
+pub mod doctor;
+#[cfg(feature = "dtls")]
+pub mod dtls;
 pub mod error;
+pub mod events;
+pub mod framed_signalling;
+#[cfg(feature = "ice-transport")]
 pub mod ice;
+#[cfg(feature = "libp2p")]
+pub mod libp2p;
+pub mod logging;
+#[cfg(feature = "full")]
+pub mod manager;
+pub mod memory_signalling;
+pub mod metrics;
+#[cfg(feature = "ice-transport")]
+pub mod mtu_probe;
+#[cfg(feature = "crypto")]
+pub mod negotiation;
 pub mod ping;
 pub mod pipe_stream;
+#[cfg(feature = "full")]
+pub mod policy;
+#[cfg(feature = "full")]
+pub mod profile;
+pub mod queued_stream;
+pub mod rate_limit;
+#[cfg(feature = "crypto")]
+pub mod replay_state;
+pub mod resolver;
+#[cfg(feature = "ice-transport")]
 pub mod sctp;
+pub mod sdp;
+pub mod service;
 pub mod signalling;
+pub mod stream_signalling;
+pub mod strictness;
+#[cfg(feature = "full")]
+pub mod testing;
+mod trace;
+pub mod traffic_limit;
+#[cfg(feature = "full")]
+pub mod tunnels;
+#[cfg(feature = "ws-signalling")]
 pub mod ws;
 
-pub use connect::{connect, ConnectOptions};
-pub use ring;
+pub use capabilities::capabilities;
+#[cfg(feature = "full")]
+pub use connect::{connect, secure_existing, ConnectOptions};
+#[cfg(feature = "crypto")]
 pub use x25519_dalek;
+
+/// Re-export of the `ring` crate, only available with the `ring` backend.
+#[cfg(all(feature = "crypto", feature = "ring"))]
+#[deprecated(note = "use the backend agnostic primitives from `icepipe::crypto_backend` instead")]
+pub mod ring {
+    pub use ::ring::*;
+}
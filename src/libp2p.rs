@@ -0,0 +1,378 @@
+//! libp2p [`Transport`] connecting peers through icepipe. Both dialing and
+//! listening on the [`multiaddr`] of the channel derived from the PSK of the
+//! transport meet the peer on that channel on the signalling server.
+//!
+//! Connections are not `Send` while libp2p requires it, so those of a
+//! transport are driven on a thread of its own, running them on the runtime
+//! the transport is used from, and handed to libp2p as in-memory pipes.
+
+use crate::{
+    agreement::PskAuthentication,
+    channel::{Channel, ChannelSource},
+    connect::{ConnectError, ConnectOptions},
+    logging,
+    pipe_stream::{PipeStream, StreamError, StreamResult},
+    trace,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{ready, BoxFuture, LocalBoxFuture, Ready},
+    FutureExt, StreamExt,
+};
+use libp2p_core::{
+    multiaddr::{Multiaddr, Protocol},
+    transport::{DialOpts, ListenerId, TransportError, TransportEvent},
+    Transport,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    runtime::Handle,
+    select,
+    task::{JoinSet, LocalSet},
+    time::sleep,
+};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+pub type IcepipeStream = Compat<DuplexStream>;
+
+type Event = TransportEvent<Ready<Result<IcepipeStream, ConnectError>>, ConnectError>;
+
+const PIPE_BUFFER: usize = 64 * 1024;
+/// Prefixes the channel in the [`multiaddr`].
+const ADDRESS_PREFIX: &str = "icepipe:";
+
+/// Address of the peers on `channel`, `/unix/icepipe:<channel>`. Multiaddr
+/// has no room for protocols of its own, that of Unix sockets holds it, no
+/// other transport dials such a path. Only the derived channel shows, never
+/// the PSK, see [`Psk::channel`](crate::channel::Psk::channel).
+pub fn multiaddr(channel: &Channel) -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Unix(format!("{ADDRESS_PREFIX}{channel}").into()))
+}
+
+fn channel(addr: &Multiaddr) -> Option<Channel> {
+    let mut protocols = addr.iter();
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Unix(path)), None) => {
+            Some(Channel::new(path.strip_prefix(ADDRESS_PREFIX)?))
+        }
+        _ => None,
+    }
+}
+
+type Task = Box<dyn FnOnce() -> LocalBoxFuture<'static, ()> + Send>;
+
+/// Thread driving the connections of a transport, on the runtime it was
+/// started from. Runs until the transport is dropped and every connection
+/// ended.
+struct Driver(mpsc::UnboundedSender<Task>);
+impl Driver {
+    fn start() -> io::Result<Driver> {
+        let handle = Handle::try_current().map_err(io::Error::other)?;
+        let (tx, mut tasks) = mpsc::unbounded::<Task>();
+        std::thread::Builder::new()
+            .name("icepipe-libp2p".to_string())
+            .spawn(move || {
+                let local = LocalSet::new();
+                local.spawn_local(async move {
+                    while let Some(task) = tasks.next().await {
+                        tokio::task::spawn_local(task());
+                    }
+                });
+                handle.block_on(local);
+            })?;
+
+        Ok(Driver(tx))
+    }
+
+    fn spawn<F, T>(&self, task: F) -> io::Result<()>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        self.0
+            .unbounded_send(Box::new(move || task().boxed_local()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+pub struct IcepipeTransport {
+    /// Template for every connection, whose PSK authenticates the peer.
+    pub options: ConnectOptions,
+    /// Skips the ChaCha20 layer so data is not encrypted twice when libp2p
+    /// secures the connection itself, e.g. with noise. Only use it with such
+    /// an upgrade, the peer is authenticated by the channel but the data is
+    /// sent in the clear otherwise.
+    pub plaintext: bool,
+    /// Derived from the PSK, the only one dialed and listened on.
+    channel: Channel,
+    /// Dropping the sender stops the listener.
+    listeners: HashMap<ListenerId, oneshot::Sender<()>>,
+    events_tx: mpsc::UnboundedSender<Event>,
+    events: mpsc::UnboundedReceiver<Event>,
+    /// Started on first use.
+    driver: Option<Driver>,
+}
+impl IcepipeTransport {
+    /// Derives the channel from the PSK of `options` right away, see
+    /// [`Psk::channel`](crate::channel::Psk::channel).
+    pub fn new(options: ConnectOptions) -> IcepipeTransport {
+        let (events_tx, events) = mpsc::unbounded();
+        let channel = match &options.channel {
+            ChannelSource::Psk(psk) => psk.channel(),
+            ChannelSource::Derived(channel) => channel.clone(),
+        };
+
+        IcepipeTransport {
+            options,
+            plaintext: false,
+            channel,
+            listeners: HashMap::new(),
+            events_tx,
+            events,
+            driver: None,
+        }
+    }
+
+    /// Where this transport dials and listens.
+    pub fn multiaddr(&self) -> Multiaddr {
+        multiaddr(&self.channel)
+    }
+
+    /// Whether `addr` is the [`IcepipeTransport::multiaddr`].
+    fn supports(&self, addr: &Multiaddr) -> bool {
+        channel(addr).is_some_and(|channel| channel == self.channel)
+    }
+
+    /// Runs `task` with the connections of this transport, must be called
+    /// from within a tokio runtime the first time.
+    fn spawn<F, T>(&mut self, task: F) -> io::Result<()>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Future<Output = ()> + 'static,
+    {
+        let driver = match &mut self.driver {
+            Some(driver) => driver,
+            driver @ None => driver.insert(Driver::start()?),
+        };
+        driver.spawn(task)
+    }
+}
+impl Transport for IcepipeTransport {
+    type Output = IcepipeStream;
+    type Error = ConnectError;
+    type ListenerUpgrade = Ready<Result<Self::Output, Self::Error>>;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    /// Accepts connections from peers on the channel one after the other.
+    fn listen_on(
+        &mut self,
+        listener_id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        if !self.supports(&addr) {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        let options = self.options.clone();
+        let plaintext = self.plaintext;
+        let events = self.events_tx.clone();
+        let (stop_tx, mut stop) = oneshot::channel::<()>();
+
+        self.spawn(move || async move {
+            let _ = events.unbounded_send(TransportEvent::NewAddress {
+                listener_id,
+                listen_addr: addr.clone(),
+            });
+
+            let mut connections = JoinSet::new();
+            'accept: loop {
+                let (tx, mut rx) = oneshot::channel();
+                let pending = connections.spawn_local(connect(options.clone(), plaintext, tx));
+                let stream = loop {
+                    select! {
+                        stream = &mut rx => break stream,
+                        Some(_) = connections.join_next() => {}
+                        _ = &mut stop => {
+                            pending.abort();
+                            break 'accept;
+                        }
+                    }
+                };
+
+                match stream {
+                    Ok(Ok(stream)) => {
+                        let _ = events.unbounded_send(TransportEvent::Incoming {
+                            listener_id,
+                            upgrade: ready(Ok(stream)),
+                            local_addr: addr.clone(),
+                            send_back_addr: addr.clone(),
+                        });
+                    }
+                    Ok(Err(error)) => {
+                        let _ = events
+                            .unbounded_send(TransportEvent::ListenerError { listener_id, error });
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                    Err(_) => {}
+                }
+            }
+
+            let _ = events.unbounded_send(TransportEvent::ListenerClosed {
+                listener_id,
+                reason: Ok(()),
+            });
+            while connections.join_next().await.is_some() {}
+        })
+        .map_err(|e| TransportError::Other(e.into()))?;
+        self.listeners.insert(listener_id, stop_tx);
+
+        Ok(())
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.listeners.remove(&id).is_some()
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        _opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        if !self.supports(&addr) {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        let options = self.options.clone();
+        let plaintext = self.plaintext;
+        let (tx, rx) = oneshot::channel();
+
+        self.spawn(move || connect(options, plaintext, tx))
+            .map_err(|e| TransportError::Other(e.into()))?;
+
+        Ok(async move {
+            rx.await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::ConnectionAborted).into()))
+        }
+        .boxed())
+    }
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Event> {
+        match self.events.poll_next_unpin(cx) {
+            Poll::Ready(Some(event)) => Poll::Ready(event),
+            // The transport keeps a sender, the stream never ends
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+type StreamSender = oneshot::Sender<Result<IcepipeStream, ConnectError>>;
+
+/// Connects and forwards between the connection and the pipe sent on `tx`
+/// until either side closes.
+async fn connect(options: ConnectOptions, plaintext: bool, tx: StreamSender) {
+    let Some(psk) = options.channel.psk().cloned() else {
+        let _ = tx.send(Err(ConnectError::NoPsk("libp2p")));
+        return;
+    };
+    let auth = PskAuthentication::new_blocking(psk).await;
+    match plaintext {
+        true => forward(options.connect_unencrypted(auth).await, tx).await,
+        false => forward(options.connect(auth).await, tx).await,
+    }
+}
+
+async fn forward<S>(connection: Result<S, ConnectError>, tx: StreamSender)
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            let _ = tx.send(Err(e));
+            return;
+        }
+    };
+
+    let (pipe, stream) = tokio::io::duplex(PIPE_BUFFER);
+    if tx.send(Ok(stream.compat())).is_err() {
+        return;
+    }
+
+    if let Err(e) = pump(connection, pipe).await {
+        trace::warn!(target: logging::LIBP2P, "libp2p connection failed: {}", e);
+    }
+}
+
+async fn pump<S>(mut connection: S, mut pipe: DuplexStream) -> StreamResult<()>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let mut buf = vec![0; 4096];
+    while !connection.rx_closed() {
+        select! {
+            value = connection.wait() => {
+                let mut value = value.map_err(Into::into)?;
+                if let Some(data) = connection.then(&mut value).await.map_err(Into::into)? {
+                    pipe.write_all(&data).await?;
+                }
+            }
+            n = pipe.read(&mut buf) => match n? {
+                0 => break,
+                n => connection.send(&buf[..n]).await.map_err(Into::into)?,
+            },
+        }
+    }
+
+    pipe.shutdown().await?;
+    connection.close().await.map_err(Into::into)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::Psk;
+
+    #[test]
+    fn channel_from_multiaddr() {
+        let abc = Channel::new("abc");
+        assert_eq!(multiaddr(&abc).to_string(), "/unix/icepipe:abc");
+        assert_eq!(channel(&multiaddr(&abc)), Some(abc.clone()));
+        assert_eq!(channel(&"/unix/icepipe:abc".parse().unwrap()), Some(abc));
+        assert_eq!(channel(&"/unix/abc".parse().unwrap()), None);
+        assert_eq!(channel(&"/dns/abc/webrtc-direct".parse().unwrap()), None);
+        assert_eq!(channel(&"/memory/1".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn only_the_derived_channel() {
+        let mut transport = IcepipeTransport::new(ConnectOptions {
+            channel: "secret".into(),
+            ..Default::default()
+        });
+        let addr = transport.multiaddr();
+        assert_eq!(addr, multiaddr(&Psk::new("secret").channel()));
+        assert!(!addr.to_string().contains("secret"));
+
+        let other = multiaddr(&Psk::new("other").channel());
+        assert!(matches!(
+            transport.dial(
+                other,
+                DialOpts {
+                    role: libp2p_core::Endpoint::Dialer,
+                    port_use: Default::default(),
+                }
+            ),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+    }
+}
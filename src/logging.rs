@@ -0,0 +1,116 @@
+//! Targets the crate logs under and a hook receiving every message.
+//!
+//! Messages go through `tracing` with the `tracing` feature and through `log`
+//! otherwise, always under one of the targets below, so a single subsystem
+//! can be enabled with e.g. `RUST_LOG=icepipe::ice=debug`. The targets are
+//! stable across releases, unlike module paths.
+//!
+//! Connection milestones are logged at info, per candidate and per handshake
+//! step details at debug, and per message details at trace. Candidates in
+//! warnings have their addresses masked, see
+//! [`redact_candidate`](crate::ice::redact_candidate).
+
+use std::{fmt, sync::RwLock};
+
+/// Candidate gathering and exchange, ICE state changes.
+pub const ICE: &str = "icepipe::ice";
+/// Joining the signalling server.
+pub const SIGNALLING: &str = "icepipe::signalling";
+/// Key agreement over the signalling channel.
+pub const AGREEMENT: &str = "icepipe::agreement";
+/// Association and stream setup, back pressure, peer notices.
+pub const SCTP: &str = "icepipe::sctp";
+/// Key confirmation and end of stream of the encryption layer.
+pub const CRYPTO: &str = "icepipe::crypto";
+/// See [`TrafficLimit`](crate::traffic_limit::TrafficLimit).
+pub const TRAFFIC_LIMIT: &str = "icepipe::traffic_limit";
+/// Services requested by peers, see [`crate::service`].
+pub const SERVICE: &str = "icepipe::service";
+/// Links kept up by [`crate::manager`].
+pub const MANAGER: &str = "icepipe::manager";
+/// Tunnels kept up by [`crate::tunnels`].
+pub const TUNNELS: &str = "icepipe::tunnels";
+/// See [`crate::libp2p`].
+pub const LIBP2P: &str = "icepipe::libp2p";
+/// Loading and saving of [`crate::profile`]s.
+pub const PROFILE: &str = "icepipe::profile";
+/// Echo and discard peers of [`crate::testing`].
+pub const TESTING: &str = "icepipe::testing";
+/// Protocol violations rejected under
+/// [`Strictness::Strict`](crate::strictness::Strictness::Strict).
+pub const AUDIT: &str = "icepipe::audit";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// A message as handed to the hook.
+#[derive(Clone, Copy, Debug)]
+pub struct Record<'a> {
+    pub level: Level,
+    /// One of the targets of this module.
+    pub target: &'static str,
+    pub message: fmt::Arguments<'a>,
+}
+
+type Hook = Box<dyn Fn(&Record<'_>) + Send + Sync>;
+
+static HOOK: RwLock<Option<Hook>> = RwLock::new(None);
+
+/// Calls `hook` with every message of the crate, on top of `log` or
+/// `tracing`, whatever their filters. Replaces any previous hook.
+pub fn set_hook<F>(hook: F)
+where
+    F: Fn(&Record<'_>) + Send + Sync + 'static,
+{
+    *HOOK.write().unwrap() = Some(Box::new(hook));
+}
+
+pub fn clear_hook() {
+    *HOOK.write().unwrap() = None;
+}
+
+pub(crate) fn dispatch(level: Level, target: &'static str, message: fmt::Arguments<'_>) {
+    if let Some(hook) = HOOK.read().unwrap().as_ref() {
+        hook(&Record {
+            level,
+            target,
+            message,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn hook() {
+        let records = Arc::new(Mutex::new(vec![]));
+        let hooked = records.clone();
+        set_hook(move |record| {
+            let message = record.message.to_string();
+            // Other tests may log concurrently
+            if record.target == LIBP2P && message.contains("hooked") {
+                hooked.lock().unwrap().push((record.level, message));
+            }
+        });
+
+        let peer = "peer";
+        trace::debug!(target: LIBP2P, "Now hooked, {peer}");
+        clear_hook();
+        trace::debug!(target: LIBP2P, "Unhooked");
+
+        assert_eq!(
+            *records.lock().unwrap(),
+            [(Level::Debug, "Now hooked, peer".to_string())]
+        );
+    }
+}
@@ -0,0 +1,545 @@
+//! Supervises links to many peers: connects them lazily, reconnects them
+//! according to a [`ReconnectPolicy`] and routes messages by [`PeerId`].
+//!
+//! Connections are not `Send`, links are driven by tasks spawned with
+//! [`tokio::task::spawn_local`] so the manager must be used from within a
+//! [`tokio::task::LocalSet`].
+
+use crate::{
+    agreement::{AgreementError, Ed25519PairAndPeer, PskMaterial},
+    channel::Psk,
+    connect::{ConnectError, ConnectOptions, Connection},
+    crypto_backend::{Ed25519KeyPair, Unspecified},
+    curve25519_conversion,
+    ice::CandidateCache,
+    logging,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    trace,
+};
+use futures::future::LocalBoxFuture;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    select,
+    sync::{mpsc, watch, Notify},
+    time::sleep,
+};
+
+/// Messages queued per link before [`LinkHandle::send`] waits.
+const SEND_QUEUE_LEN: usize = 64;
+
+/// Peer identifier, the derived channel for PSK peers and the hex encoded
+/// public key for key based peers.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PeerId(pub String);
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone)]
+pub enum PeerSpec {
+    /// Peers sharing a pre-shared key, see [`ConnectOptions::connect_psk`].
+    Psk { psk: Psk },
+    /// Peers authenticated by Ed25519 keys. The channel is derived from both
+    /// keys the same way `icepipe-cat --private-key` does.
+    Key { seed: [u8; 32], peer: Vec<u8> },
+}
+impl PeerSpec {
+    pub fn id(&self) -> PeerId {
+        match self {
+            PeerSpec::Psk { psk } => PeerId(psk.channel().into()),
+            PeerSpec::Key { peer, .. } => PeerId(hex(peer)),
+        }
+    }
+
+    /// `material` is derived by the first attempt of a PSK peer, for those
+    /// that follow.
+    pub(crate) async fn connect(
+        &self,
+        template: ConnectOptions,
+        material: &mut Option<PskMaterial>,
+    ) -> Result<Connection, ConnectError> {
+        match self {
+            PeerSpec::Psk { psk } => {
+                let material = match material {
+                    Some(material) => material.clone(),
+                    None => material
+                        .insert(PskMaterial::derive_blocking(psk.clone()).await)
+                        .clone(),
+                };
+                ConnectOptions {
+                    channel: psk.clone().into(),
+                    ..template
+                }
+                .connect_psk_material(material)
+                .await
+            }
+            PeerSpec::Key { seed, peer } => {
+                let key_pair = Ed25519KeyPair::from_seed(seed).map_err(AgreementError::from)?;
+                let x25519_peer = curve25519_conversion::ed25519_public_key_to_x25519(peer)
+                    .ok_or(AgreementError::CryptoError(Unspecified))?;
+                let channel = curve25519_conversion::ed25519_seed_to_x25519(seed)
+                    .diffie_hellman(&x25519_peer);
+
+                ConnectOptions {
+                    channel: Psk::new(hex(channel.as_bytes())).into(),
+                    ..template
+                }
+                .connect(Ed25519PairAndPeer(key_pair, peer.clone()))
+                .await
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Consecutive failed attempts before the link gives up, `None` retries
+    /// forever.
+    pub max_attempts: Option<u32>,
+    /// Wait after the first failure, doubled on each consecutive failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+impl ReconnectPolicy {
+    pub(crate) fn backoff(&self, failures: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    /// Not started yet, see [`LinkHandle::start`].
+    Idle,
+    Connecting,
+    Connected,
+    /// Waiting for the backoff to elapse before reconnecting.
+    Backoff,
+    /// Gave up after [`ReconnectPolicy::max_attempts`].
+    Failed,
+    Closed,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkHealth {
+    /// Times the link was established.
+    pub connects: u32,
+    /// Failed attempts since the link was last established.
+    pub failures: u32,
+    pub last_error: Option<String>,
+    pub connected_since: Option<Instant>,
+}
+
+/// Handle to a supervised link, cheap to clone.
+#[derive(Clone)]
+pub struct LinkHandle {
+    peer: PeerId,
+    queue: mpsc::Sender<Vec<u8>>,
+    state: watch::Receiver<LinkState>,
+    health: watch::Receiver<LinkHealth>,
+    close: Rc<Notify>,
+    supervisor: Rc<RefCell<Option<LocalBoxFuture<'static, ()>>>>,
+}
+impl LinkHandle {
+    pub fn peer(&self) -> &PeerId {
+        &self.peer
+    }
+
+    /// Starts connecting if the link is still idle.
+    pub fn start(&self) {
+        if let Some(supervisor) = self.supervisor.borrow_mut().take() {
+            tokio::task::spawn_local(supervisor);
+        }
+    }
+
+    /// Queues a message to the peer, starting the link if needed. Messages
+    /// queued while disconnected are sent once the link is re-established,
+    /// a message in flight when the connection drops is lost.
+    pub async fn send(&self, data: Vec<u8>) -> Result<(), ManagerError> {
+        self.start();
+        self.queue
+            .send(data)
+            .await
+            .map_err(|_| ManagerError::LinkClosed(self.peer.clone()))
+    }
+
+    /// Closes the connection and stops reconnecting.
+    pub fn close(&self) {
+        self.supervisor.borrow_mut().take();
+        self.close.notify_one();
+    }
+
+    pub fn state(&self) -> watch::Receiver<LinkState> {
+        self.state.clone()
+    }
+
+    pub fn health(&self) -> watch::Receiver<LinkHealth> {
+        self.health.clone()
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self.state.borrow() == LinkState::Connected
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(*self.state.borrow(), LinkState::Failed | LinkState::Closed)
+            || (self.supervisor.borrow().is_none() && self.queue.is_closed())
+    }
+}
+
+pub struct ConnectionManager {
+    template: ConnectOptions,
+    policy: ReconnectPolicy,
+    links: BTreeMap<PeerId, LinkHandle>,
+    incoming_tx: mpsc::UnboundedSender<(PeerId, Vec<u8>)>,
+    incoming_rx: mpsc::UnboundedReceiver<(PeerId, Vec<u8>)>,
+}
+impl ConnectionManager {
+    /// Every link connects with a copy of `template`, only the channel and
+    /// the candidate cache are replaced. Links share the
+    /// [`connect_limiter`](ConnectOptions::connect_limiter) of `template`,
+    /// bounding how many of them connect at once.
+    pub fn new(template: ConnectOptions) -> ConnectionManager {
+        Self::with_policy(template, Default::default())
+    }
+
+    pub fn with_policy(template: ConnectOptions, policy: ReconnectPolicy) -> ConnectionManager {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+
+        ConnectionManager {
+            template,
+            policy,
+            links: Default::default(),
+            incoming_tx,
+            incoming_rx,
+        }
+    }
+
+    /// Returns the link to the peer, creating an idle one if there is none
+    /// or the previous one was closed or gave up.
+    pub fn ensure(&mut self, spec: PeerSpec) -> LinkHandle {
+        let peer = spec.id();
+        if let Some(link) = self.links.get(&peer).filter(|link| !link.is_finished()) {
+            return link.clone();
+        }
+
+        let (queue, queue_rx) = mpsc::channel(SEND_QUEUE_LEN);
+        let (state_tx, state) = watch::channel(LinkState::Idle);
+        let (health_tx, health) = watch::channel(LinkHealth::default());
+        let close = Rc::new(Notify::new());
+        let supervisor = Link {
+            name: peer.to_string(),
+            spec,
+            template: self.template.clone(),
+            policy: self.policy,
+            state: state_tx,
+            health: health_tx,
+            close: close.clone(),
+            changed: None,
+            log,
+        };
+        let forwarder = Forwarder {
+            peer: peer.clone(),
+            queue: queue_rx,
+            incoming: self.incoming_tx.clone(),
+        };
+
+        let link = LinkHandle {
+            peer: peer.clone(),
+            queue,
+            state,
+            health,
+            close,
+            supervisor: Rc::new(RefCell::new(Some(Box::pin(supervisor.run(forwarder))))),
+        };
+        self.links.insert(peer, link.clone());
+
+        link
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&LinkHandle> {
+        self.links.get(peer)
+    }
+
+    /// Routes a message to the link of `peer`, see [`LinkHandle::send`].
+    pub async fn send(&self, peer: &PeerId, data: Vec<u8>) -> Result<(), ManagerError> {
+        self.links
+            .get(peer)
+            .ok_or_else(|| ManagerError::UnknownPeer(peer.clone()))?
+            .send(data)
+            .await
+    }
+
+    /// Sends a copy of the message to every connected link, returns the
+    /// peers it was queued to.
+    pub async fn broadcast(&self, data: &[u8]) -> Vec<PeerId> {
+        let mut sent = Vec::new();
+        for link in self.active() {
+            if link.send(data.to_owned()).await.is_ok() {
+                sent.push(link.peer.clone());
+            }
+        }
+
+        sent
+    }
+
+    /// Next message received on any link.
+    pub async fn recv(&mut self) -> (PeerId, Vec<u8>) {
+        self.incoming_rx
+            .recv()
+            .await
+            .expect("Manager holds a sender of its own")
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = &LinkHandle> {
+        self.links.values()
+    }
+
+    /// Links currently connected.
+    pub fn active(&self) -> impl Iterator<Item = &LinkHandle> {
+        self.links().filter(|link| link.is_active())
+    }
+
+    /// Closes every link.
+    pub fn close(&mut self) {
+        for link in self.links.values() {
+            link.close();
+        }
+    }
+}
+
+/// How a session over a connection of a [`Link`] ended.
+pub(crate) enum SessionEnd {
+    /// Closed on this side, the link stops.
+    Closed,
+    /// Counted as a failed attempt, the link connects again after the
+    /// backoff.
+    Lost(String),
+}
+
+/// What runs over each connection of a [`Link`].
+pub(crate) trait LinkSession {
+    /// Runs until the connection ends or `close` is notified.
+    fn run<'a>(
+        &'a mut self,
+        connection: Connection,
+        close: &'a Notify,
+    ) -> LocalBoxFuture<'a, SessionEnd>;
+}
+
+/// Changes of a [`Link`], each owner logs them under its own target.
+pub(crate) enum LinkEvent<'a> {
+    Connected,
+    Lost(&'a str),
+    Closed,
+}
+
+/// Connects to a peer again whenever the session over the connection ends,
+/// according to a [`ReconnectPolicy`]. Drives the links of
+/// [`ConnectionManager`] and the tunnels of
+/// [`Tunnels`](crate::tunnels::Tunnels).
+pub(crate) struct Link {
+    pub(crate) name: String,
+    pub(crate) spec: PeerSpec,
+    pub(crate) template: ConnectOptions,
+    pub(crate) policy: ReconnectPolicy,
+    pub(crate) state: watch::Sender<LinkState>,
+    pub(crate) health: watch::Sender<LinkHealth>,
+    pub(crate) close: Rc<Notify>,
+    /// Notified on every change of state.
+    pub(crate) changed: Option<watch::Sender<()>>,
+    pub(crate) log: fn(&str, LinkEvent<'_>),
+}
+impl Link {
+    fn set_state(&self, state: LinkState) {
+        self.state.send_replace(state);
+        if let Some(changed) = &self.changed {
+            changed.send_replace(());
+        }
+    }
+
+    pub(crate) async fn run(self, mut session: impl LinkSession) {
+        let mut candidate_cache: Option<CandidateCache> = None;
+        let mut material = None;
+
+        loop {
+            self.set_state(LinkState::Connecting);
+            let options = ConnectOptions {
+                candidate_cache: candidate_cache.clone(),
+                ..self.template.clone()
+            };
+            let connection = select! {
+                connection = self.spec.connect(options, &mut material) => connection,
+                _ = self.close.notified() => break,
+            };
+
+            let error = match connection {
+                Ok(connection) => {
+                    candidate_cache = connection.candidate_cache().cloned();
+                    self.health.send_modify(|health| {
+                        health.connects += 1;
+                        health.failures = 0;
+                        health.connected_since = Some(Instant::now());
+                    });
+                    self.set_state(LinkState::Connected);
+                    (self.log)(&self.name, LinkEvent::Connected);
+
+                    let end = session.run(connection, &self.close).await;
+                    self.health
+                        .send_modify(|health| health.connected_since = None);
+                    match end {
+                        SessionEnd::Closed => break,
+                        SessionEnd::Lost(error) => error,
+                    }
+                }
+                Err(e) => e.to_string(),
+            };
+
+            (self.log)(&self.name, LinkEvent::Lost(&error));
+            let failures = self.health.borrow().failures + 1;
+            self.health.send_modify(|health| {
+                health.failures = failures;
+                health.last_error = Some(error);
+            });
+            if self
+                .policy
+                .max_attempts
+                .is_some_and(|max_attempts| failures >= max_attempts)
+            {
+                self.set_state(LinkState::Failed);
+                return;
+            }
+
+            self.set_state(LinkState::Backoff);
+            select! {
+                _ = sleep(self.policy.backoff(failures)) => {},
+                _ = self.close.notified() => break,
+            }
+        }
+
+        (self.log)(&self.name, LinkEvent::Closed);
+        self.set_state(LinkState::Closed);
+    }
+}
+
+/// Forwards the messages of a link of the manager.
+struct Forwarder {
+    peer: PeerId,
+    queue: mpsc::Receiver<Vec<u8>>,
+    incoming: mpsc::UnboundedSender<(PeerId, Vec<u8>)>,
+}
+impl Forwarder {
+    async fn forward(
+        &mut self,
+        connection: &mut Connection,
+        close: &Notify,
+    ) -> Result<Forward, ConnectError> {
+        while !connection.rx_closed() {
+            select! {
+                value = connection.wait() => {
+                    if let Some(data) = connection.then(&mut value?).await? {
+                        let _ = self.incoming.send((self.peer.clone(), data));
+                    }
+                }
+                data = self.queue.recv() => match data {
+                    Some(data) => connection.send(&data).await?,
+                    None => return Ok(Forward::Closed),
+                },
+                _ = close.notified() => return Ok(Forward::Closed),
+            }
+        }
+
+        Ok(Forward::Disconnected)
+    }
+}
+impl LinkSession for Forwarder {
+    fn run<'a>(
+        &'a mut self,
+        mut connection: Connection,
+        close: &'a Notify,
+    ) -> LocalBoxFuture<'a, SessionEnd> {
+        Box::pin(async move {
+            match self.forward(&mut connection, close).await {
+                Ok(Forward::Closed) => {
+                    let _ = connection.close().await;
+                    SessionEnd::Closed
+                }
+                Ok(Forward::Disconnected) => {
+                    let _ = connection.close().await;
+                    SessionEnd::Lost("Peer closed the connection".to_string())
+                }
+                Err(e) => SessionEnd::Lost(e.to_string()),
+            }
+        })
+    }
+}
+
+fn log(peer: &str, event: LinkEvent<'_>) {
+    match event {
+        LinkEvent::Connected => {
+            trace::info!(target: logging::MANAGER, "Link to {} connected", peer)
+        }
+        LinkEvent::Lost(error) => {
+            trace::warn!(target: logging::MANAGER, "Link to {} lost: {}", peer, error)
+        }
+        LinkEvent::Closed => trace::info!(target: logging::MANAGER, "Link to {} closed", peer),
+    }
+}
+
+enum Forward {
+    Closed,
+    Disconnected,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ManagerError {
+    #[error("No link to peer {0}")]
+    UnknownPeer(PeerId),
+    #[error("Link to peer {0} is closed")]
+    LinkClosed(PeerId),
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let policy = ReconnectPolicy {
+            max_attempts: None,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_secs(1));
+        assert_eq!(policy.backoff(100), Duration::from_secs(1));
+    }
+}
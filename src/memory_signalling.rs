@@ -0,0 +1,63 @@
+use crate::{
+    pipe_stream::WaitThen,
+    signalling::{SignalingError, Signalling},
+};
+use futures::{
+    future::{ready, LocalBoxFuture},
+    FutureExt,
+};
+use std::io;
+use tokio::sync::mpsc;
+
+/// In-process signalling, useful to connect two peers living in the same
+/// process without a signalling server.
+pub struct MemorySignalling {
+    tx: mpsc::UnboundedSender<String>,
+    rx: mpsc::UnboundedReceiver<String>,
+}
+impl MemorySignalling {
+    pub fn pair() -> (MemorySignalling, MemorySignalling) {
+        let (a_tx, b_rx) = mpsc::unbounded_channel();
+        let (b_tx, a_rx) = mpsc::unbounded_channel();
+
+        (
+            MemorySignalling { tx: a_tx, rx: a_rx },
+            MemorySignalling { tx: b_tx, rx: b_rx },
+        )
+    }
+}
+impl Signalling for MemorySignalling {
+    fn send(&mut self, msg: String) -> LocalBoxFuture<'_, Result<(), SignalingError>> {
+        let r = self
+            .tx
+            .send(msg)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into());
+
+        ready(r).boxed_local()
+    }
+}
+impl WaitThen for MemorySignalling {
+    type Value = String;
+    type Output = Option<String>;
+    type Error = SignalingError;
+
+    fn wait(&mut self) -> LocalBoxFuture<'_, Result<Self::Value, SignalingError>> {
+        async move {
+            let msg = self
+                .rx
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionReset))?;
+
+            Ok(msg)
+        }
+        .boxed_local()
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, Result<Self::Output, SignalingError>> {
+        ready(Ok(Some(std::mem::take(value)))).boxed_local()
+    }
+}
@@ -0,0 +1,226 @@
+//! Metrics reported through the [`metrics`](https://docs.rs/metrics) facade
+//! when the `metrics` feature is enabled, the application chooses the
+//! exporter. Without the feature every recording function is a no-op.
+//!
+//! Labels are limited to `role` (`dialer` or `listener`), `transport`, for
+//! failures, `phase` and, for timings, `step`, so that the cardinality stays
+//! bounded no matter how many peers are connected. Signalling metrics have no
+//! labels.
+
+/// Gauge of connections currently open.
+pub const CONNECTIONS_ACTIVE: &str = "icepipe_connections_active";
+/// Counter of calls to connect.
+pub const CONNECT_ATTEMPTS: &str = "icepipe_connect_attempts_total";
+/// Counter of connections successfully established.
+pub const CONNECT_SUCCESSES: &str = "icepipe_connect_successes_total";
+/// Counter of failed connects, labeled by the `phase` that failed.
+pub const CONNECT_FAILURES: &str = "icepipe_connect_failures_total";
+/// Counter of bytes written to the transport, including encryption overhead.
+pub const BYTES_SENT: &str = "icepipe_bytes_sent_total";
+/// Counter of bytes read from the transport, including encryption overhead.
+pub const BYTES_RECEIVED: &str = "icepipe_bytes_received_total";
+/// Counter of ICE disconnections the agent checked pairs again to recover
+/// from, see [`SctpConfig::disconnect_grace`](crate::sctp::SctpConfig::disconnect_grace).
+pub const ICE_RESTARTS: &str = "icepipe_ice_restarts_total";
+/// Counter of pings sent to the signalling server, see
+/// [`PingConfig`](crate::ping::PingConfig).
+pub const SIGNALLING_PINGS: &str = "icepipe_signalling_pings_total";
+/// Counter of signalling server connections given up on after
+/// [`MISSED_PINGS`](crate::ping::MISSED_PINGS) unanswered pings.
+pub const SIGNALLING_PING_TIMEOUTS: &str = "icepipe_signalling_ping_timeouts_total";
+/// Counter of messages that failed authenticated decryption.
+pub const AEAD_FAILURES: &str = "icepipe_aead_failures_total";
+/// Counter of messages received out of sequence, see
+/// [`DesyncPolicy`](crate::crypto_stream::DesyncPolicy).
+pub const NONCE_DESYNCS: &str = "icepipe_nonce_desyncs_total";
+/// Gauge of bytes queued in the transport waiting to be sent.
+pub const BUFFERED_AMOUNT: &str = "icepipe_buffered_amount_bytes";
+/// Gauge of bytes the peer still accepts, see
+/// [`SctpConfig::receive_window`](crate::sctp::SctpConfig::receive_window).
+pub const SEND_WINDOW: &str = "icepipe_send_window_bytes";
+/// Gauge of bytes the peer may still send before the application reads
+/// more.
+pub const RECEIVE_WINDOW: &str = "icepipe_receive_window_bytes";
+/// Gauge of bytes the peer received but its application did not read yet,
+/// as of its last report, see
+/// [`SctpStats::remote_lag`](crate::sctp::SctpStats::remote_lag).
+pub const REMOTE_LAG: &str = "icepipe_remote_lag_bytes";
+/// Histogram of seconds each step of establishing a connection took,
+/// labeled by `step`, see
+/// [`ConnectTimings`](crate::diagnostics::ConnectTimings).
+pub const CONNECT_STEP_SECONDS: &str = "icepipe_connect_step_seconds";
+/// Gauge of connects waiting for a handshake slot, see
+/// [`ConnectLimiter`](crate::connect_limiter::ConnectLimiter).
+pub const CONNECT_QUEUE_DEPTH: &str = "icepipe_connect_queue_depth";
+/// Histogram of seconds connects waited for a handshake slot, only those
+/// that had to wait are recorded.
+pub const CONNECT_QUEUE_WAIT_SECONDS: &str = "icepipe_connect_queue_wait_seconds";
+
+#[cfg(feature = "ice-transport")]
+pub(crate) const TRANSPORT_SCTP: &str = "sctp";
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use super::*;
+    #[cfg(feature = "full")]
+    use std::time::Duration;
+
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_attempt() {
+        ::metrics::counter!(CONNECT_ATTEMPTS).increment(1);
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_success(role: &'static str) {
+        ::metrics::counter!(CONNECT_SUCCESSES, "role" => role).increment(1);
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_failure(phase: &'static str) {
+        ::metrics::counter!(CONNECT_FAILURES, "phase" => phase).increment(1);
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_step(step: &'static str, duration: Duration) {
+        ::metrics::histogram!(CONNECT_STEP_SECONDS, "step" => step).record(duration);
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_queue_depth(depth: usize) {
+        ::metrics::gauge!(CONNECT_QUEUE_DEPTH).set(depth as f64);
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_queue_wait(duration: Duration) {
+        ::metrics::histogram!(CONNECT_QUEUE_WAIT_SECONDS).record(duration);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn bytes_sent(role: &'static str, transport: &'static str, n: usize) {
+        ::metrics::counter!(BYTES_SENT, "role" => role, "transport" => transport)
+            .increment(n as u64);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn bytes_received(role: &'static str, transport: &'static str, n: usize) {
+        ::metrics::counter!(BYTES_RECEIVED, "role" => role, "transport" => transport)
+            .increment(n as u64);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn ice_restart(role: &'static str) {
+        ::metrics::counter!(ICE_RESTARTS, "role" => role).increment(1);
+    }
+
+    pub(crate) fn signalling_ping() {
+        ::metrics::counter!(SIGNALLING_PINGS).increment(1);
+    }
+
+    pub(crate) fn signalling_ping_timeout() {
+        ::metrics::counter!(SIGNALLING_PING_TIMEOUTS).increment(1);
+    }
+
+    #[cfg(feature = "crypto")]
+    pub(crate) fn aead_failure(role: &'static str) {
+        ::metrics::counter!(AEAD_FAILURES, "role" => role).increment(1);
+    }
+
+    #[cfg(feature = "crypto")]
+    pub(crate) fn nonce_desync(role: &'static str) {
+        ::metrics::counter!(NONCE_DESYNCS, "role" => role).increment(1);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn buffered_amount(role: &'static str, transport: &'static str, n: usize) {
+        ::metrics::gauge!(BUFFERED_AMOUNT, "role" => role, "transport" => transport).set(n as f64);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn send_window(role: &'static str, n: usize) {
+        ::metrics::gauge!(SEND_WINDOW, "role" => role).set(n as f64);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn receive_window(role: &'static str, n: usize) {
+        ::metrics::gauge!(RECEIVE_WINDOW, "role" => role).set(n as f64);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn remote_lag(role: &'static str, n: usize) {
+        ::metrics::gauge!(REMOTE_LAG, "role" => role).set(n as f64);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn connection_opened(role: &'static str, transport: &'static str) {
+        ::metrics::gauge!(CONNECTIONS_ACTIVE, "role" => role, "transport" => transport)
+            .increment(1.0);
+    }
+
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn connection_closed(role: &'static str, transport: &'static str) {
+        ::metrics::gauge!(CONNECTIONS_ACTIVE, "role" => role, "transport" => transport)
+            .decrement(1.0);
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_attempt() {}
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_success(_role: &'static str) {}
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_failure(_phase: &'static str) {}
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_step(_step: &'static str, _duration: std::time::Duration) {}
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_queue_depth(_depth: usize) {}
+    #[cfg(feature = "full")]
+    pub(crate) fn connect_queue_wait(_duration: std::time::Duration) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn bytes_sent(_role: &'static str, _transport: &'static str, _n: usize) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn bytes_received(_role: &'static str, _transport: &'static str, _n: usize) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn ice_restart(_role: &'static str) {}
+    pub(crate) fn signalling_ping() {}
+    pub(crate) fn signalling_ping_timeout() {}
+    #[cfg(feature = "crypto")]
+    pub(crate) fn aead_failure(_role: &'static str) {}
+    #[cfg(feature = "crypto")]
+    pub(crate) fn nonce_desync(_role: &'static str) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn buffered_amount(_role: &'static str, _transport: &'static str, _n: usize) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn send_window(_role: &'static str, _n: usize) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn receive_window(_role: &'static str, _n: usize) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn remote_lag(_role: &'static str, _n: usize) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn connection_opened(_role: &'static str, _transport: &'static str) {}
+    #[cfg(feature = "ice-transport")]
+    pub(crate) fn connection_closed(_role: &'static str, _transport: &'static str) {}
+}
+
+pub(crate) use imp::*;
+
+/// Keeps the active connections gauge up to date for as long as it lives.
+#[cfg(feature = "ice-transport")]
+pub(crate) struct ActiveConnection {
+    role: &'static str,
+    transport: &'static str,
+}
+#[cfg(feature = "ice-transport")]
+impl ActiveConnection {
+    pub(crate) fn new(role: &'static str, transport: &'static str) -> ActiveConnection {
+        connection_opened(role, transport);
+        ActiveConnection { role, transport }
+    }
+}
+#[cfg(feature = "ice-transport")]
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        connection_closed(self.role, self.transport);
+    }
+}
@@ -0,0 +1,547 @@
+//! Largest datagram the UDP path carries, see
+//! [`Sctp::probe_mtu`](crate::sctp::Sctp::probe_mtu).
+//!
+//! Probes of increasing size are sent straight over the connection under the
+//! association, next to the SCTP packets, and the peer acknowledges each one
+//! it receives. Once one is lost the gap to the largest that arrived is
+//! bisected down to [`PRECISION`]. Probes fail the SCTP checksum, so peers
+//! predating them drop them and every probe times out.
+//!
+//! While probing the socket under the connection sends with the Don't
+//! Fragment bit and never fragments locally, see [`DontFragment`], so a probe
+//! too large for any hop is lost rather than fragmented. webrtc-ice does not
+//! hand out its sockets, the one bound to the address of the selected pair is
+//! looked up among those of the process. Only Linux is supported, and relayed
+//! pairs, whose socket talks to the TURN server, are not probed.
+
+use crate::{
+    logging,
+    rate_limit::{RateLimit, RateLimiter},
+    trace,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::oneshot;
+use webrtc_util::Conn;
+
+/// How long an attempt waits for the acknowledgement.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Attempts before a size counts as lost.
+pub const ATTEMPTS: usize = 2;
+/// Sizes probed before bisecting, UDP payloads. The largest is what ICE
+/// reads at once, bigger datagrams would be truncated by the peer.
+pub const LADDER: [usize; 8] = [508, 1024, 1232, 1280, 1400, 1472, 4096, 8192];
+/// Bisecting stops once the largest arrived and the smallest lost sizes are
+/// this close.
+pub const PRECISION: usize = 8;
+
+/// Acknowledgements sent per second, whatever rate the peer probes at. The
+/// burst fits a whole probing, which waits for each answer.
+const REPLY_LIMIT: RateLimit = RateLimit {
+    burst: 32,
+    per_second: 10,
+};
+/// Where SCTP has its ports, which are 5000 for both peers.
+const MAGIC: [u8; 4] = *b"MTU?";
+const REQUEST: u8 = 0;
+const REPLY: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4;
+
+/// Result of the latest [`Sctp::probe_mtu`](crate::sctp::Sctp::probe_mtu).
+/// Add 28 bytes of IPv4 and UDP headers, or 48 over IPv6, for the MTU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PathMtu {
+    /// Largest UDP payload that reached the peer, `None` if no probe was
+    /// acknowledged, e.g. because the peer predates them, or the path could
+    /// not be probed.
+    pub max_payload: Option<usize>,
+    /// Smallest UDP payload that was lost, `None` if the largest of
+    /// [`LADDER`] arrived.
+    pub lost_payload: Option<usize>,
+}
+
+impl PathMtu {
+    /// Whether UDP payloads of `len` bytes reach the peer, `None` if the
+    /// probe cannot tell, e.g. for being in the last bisected gap or past
+    /// the [`LADDER`].
+    pub fn carries(&self, len: usize) -> Option<bool> {
+        match (self.max_payload, self.lost_payload) {
+            (Some(max), _) if len <= max => Some(true),
+            (Some(_), Some(lost)) if len >= lost => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Probes {
+    pending: Mutex<HashMap<u32, oneshot::Sender<()>>>,
+    next_id: AtomicU32,
+    latest: Mutex<Option<PathMtu>>,
+}
+
+/// Answers probes from the peer and hands acknowledgements to the
+/// [`MtuProbe`], everything else goes through untouched.
+struct ProbedConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    probes: Arc<Probes>,
+    replies: Mutex<RateLimiter>,
+}
+impl ProbedConn {
+    /// Whether `data` was a probe or an acknowledgement.
+    async fn intercept(&self, data: &[u8]) -> bool {
+        let Some((kind, id)) = decode(data) else {
+            return false;
+        };
+        match kind {
+            REQUEST if self.replies.lock().unwrap().try_acquire() => {
+                // A lost reply is a lost probe to the peer
+                let _ = self.conn.send(&encode(REPLY, id, HEADER_LEN)).await;
+            }
+            REPLY => {
+                if let Some(tx) = self.probes.pending.lock().unwrap().remove(&id) {
+                    let _ = tx.send(());
+                }
+            }
+            _ => (),
+        }
+
+        true
+    }
+}
+#[async_trait::async_trait]
+impl Conn for ProbedConn {
+    async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+        loop {
+            let n = self.conn.recv(buf).await?;
+            if !self.intercept(&buf[..n]).await {
+                return Ok(n);
+            }
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+        loop {
+            let (n, addr) = self.conn.recv_from(buf).await?;
+            if !self.intercept(&buf[..n]).await {
+                return Ok((n, addr));
+            }
+        }
+    }
+
+    async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc_util::Result<usize> {
+        self.conn.send_to(buf, target).await
+    }
+
+    fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr()
+    }
+
+    async fn close(&self) -> webrtc_util::Result<()> {
+        self.conn.close().await
+    }
+}
+
+/// Sends probes over the connection returned along with it by
+/// [`MtuProbe::wrap`].
+pub(crate) struct MtuProbe {
+    conn: Arc<dyn Conn + Send + Sync>,
+    probes: Arc<Probes>,
+}
+impl MtuProbe {
+    /// `conn` with probes answered, for the association to run over.
+    pub(crate) fn wrap(
+        conn: Arc<dyn Conn + Send + Sync>,
+    ) -> (Arc<dyn Conn + Send + Sync>, MtuProbe) {
+        let probes = Arc::new(Probes::default());
+        let probed = ProbedConn {
+            conn: conn.clone(),
+            probes: probes.clone(),
+            replies: Mutex::new(RateLimiter::new(REPLY_LIMIT)),
+        };
+
+        (Arc::new(probed), MtuProbe { conn, probes })
+    }
+
+    /// Probes over the UDP socket bound to `socket`, nothing is probed if
+    /// it cannot be kept from fragmenting.
+    pub(crate) async fn probe(&self, socket: Option<SocketAddr>, timeout: Duration) -> PathMtu {
+        let mut mtu = PathMtu::default();
+        let Some(_df) = socket.and_then(DontFragment::set) else {
+            trace::debug!(
+                target: logging::SCTP,
+                "No socket to keep from fragmenting at {socket:?}, not probing the path MTU"
+            );
+            return mtu;
+        };
+        for size in LADDER {
+            if !self.attempt(size, timeout).await {
+                mtu.lost_payload = Some(size);
+                break;
+            }
+            mtu.max_payload = Some(size);
+        }
+
+        if let (Some(mut low), Some(mut high)) = (mtu.max_payload, mtu.lost_payload) {
+            while high - low > PRECISION {
+                let size = (low + high) / 2;
+                match self.attempt(size, timeout).await {
+                    true => low = size,
+                    false => high = size,
+                }
+            }
+            mtu = PathMtu {
+                max_payload: Some(low),
+                lost_payload: Some(high),
+            };
+        }
+
+        *self.probes.latest.lock().unwrap() = Some(mtu);
+        mtu
+    }
+
+    /// Where the connection is bound, the socket itself unless it wraps one.
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        self.conn.local_addr().ok()
+    }
+
+    pub(crate) fn latest(&self) -> Option<PathMtu> {
+        *self.probes.latest.lock().unwrap()
+    }
+
+    async fn attempt(&self, size: usize, timeout: Duration) -> bool {
+        for _ in 0..ATTEMPTS {
+            let id = self.probes.next_id.fetch_add(1, Ordering::Relaxed);
+            let (tx, rx) = oneshot::channel();
+            self.probes.pending.lock().unwrap().insert(id, tx);
+
+            // Failing to send, e.g. for being too large, counts as lost
+            let acknowledged = self.conn.send(&encode(REQUEST, id, size)).await.is_ok()
+                && matches!(tokio::time::timeout(timeout, rx).await, Ok(Ok(())));
+            self.probes.pending.lock().unwrap().remove(&id);
+            if acknowledged {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// Keeps the UDP socket it was set on from fragmenting until dropped, sends
+/// larger than the MTU known for the route fail instead.
+struct DontFragment {
+    #[cfg(target_os = "linux")]
+    fd: std::os::fd::RawFd,
+    #[cfg(target_os = "linux")]
+    ipv6: bool,
+    /// Policy restored once dropped.
+    #[cfg(target_os = "linux")]
+    previous: libc::c_int,
+}
+impl DontFragment {
+    /// Sets it on the UDP socket of this process bound to `local`, if any.
+    #[cfg(target_os = "linux")]
+    fn set(local: SocketAddr) -> Option<DontFragment> {
+        let (fd, ipv6) = std::fs::read_dir("/proc/self/fd")
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .find_map(|fd| Some((fd, udp_bound_to(fd, local)?)))?;
+        let (level, name, probe) = Self::option(ipv6);
+        let previous = getsockopt(fd, level, name)?;
+        // SAFETY: `fd` is an open socket and `probe` an int, as the option expects
+        let set = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                (&probe as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        (set == 0).then_some(DontFragment { fd, ipv6, previous })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set(_: SocketAddr) -> Option<DontFragment> {
+        None
+    }
+
+    /// Level, name and value of the option keeping the socket from
+    /// fragmenting.
+    #[cfg(target_os = "linux")]
+    fn option(ipv6: bool) -> (libc::c_int, libc::c_int, libc::c_int) {
+        match ipv6 {
+            false => (
+                libc::IPPROTO_IP,
+                libc::IP_MTU_DISCOVER,
+                libc::IP_PMTUDISC_PROBE,
+            ),
+            true => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_MTU_DISCOVER,
+                libc::IPV6_PMTUDISC_PROBE,
+            ),
+        }
+    }
+}
+#[cfg(target_os = "linux")]
+impl Drop for DontFragment {
+    fn drop(&mut self) {
+        let (level, name, _) = Self::option(self.ipv6);
+        // SAFETY: as in `set`, the socket outlives the probing
+        unsafe {
+            libc::setsockopt(
+                self.fd,
+                level,
+                name,
+                (&self.previous as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn getsockopt(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` describe an int, fails on anything not a socket
+    let r = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    (r == 0).then_some(value)
+}
+
+/// Whether `fd` is a UDP socket bound to `local`, over IPv6 if `Some(true)`.
+#[cfg(target_os = "linux")]
+fn udp_bound_to(fd: libc::c_int, local: SocketAddr) -> Option<bool> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    if getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE)? != libc::SOCK_DGRAM {
+        return None;
+    }
+    // SAFETY: all zeros is a valid sockaddr_storage, large enough for any address
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: `storage` and `len` describe a buffer for any address
+    let r = unsafe {
+        libc::getsockname(
+            fd,
+            (&mut storage as *mut libc::sockaddr_storage).cast(),
+            &mut len,
+        )
+    };
+    if r != 0 {
+        return None;
+    }
+    let (bound, ipv6) = match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // SAFETY: the family tells it holds a sockaddr_in
+            let addr = unsafe {
+                &*(&storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>()
+            };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            (
+                SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)),
+                false,
+            )
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the family tells it holds a sockaddr_in6
+            let addr = unsafe {
+                &*(&storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>()
+            };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            (
+                SocketAddr::new(ip.into(), u16::from_be(addr.sin6_port)),
+                true,
+            )
+        }
+        _ => return None,
+    };
+
+    (bound == local).then_some(ipv6)
+}
+
+/// Padded with zeros up to `len`.
+fn encode(kind: u8, id: u32, len: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(len.max(HEADER_LEN));
+    data.extend_from_slice(&MAGIC);
+    data.push(kind);
+    data.extend_from_slice(&id.to_be_bytes());
+    data.resize(len.max(HEADER_LEN), 0);
+    data
+}
+
+fn decode(data: &[u8]) -> Option<(u8, u32)> {
+    let rest = data.strip_prefix(&MAGIC)?;
+    let (&kind, rest) = rest.split_first()?;
+    let id = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+    Some((kind, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    /// Drops datagrams longer than `max`, like a tunnel dropping fragments.
+    struct Clamped {
+        socket: UdpSocket,
+        max: usize,
+    }
+    #[async_trait::async_trait]
+    impl Conn for Clamped {
+        async fn connect(&self, addr: SocketAddr) -> webrtc_util::Result<()> {
+            Ok(self.socket.connect(addr).await?)
+        }
+
+        async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+            Ok(self.socket.recv(buf).await?)
+        }
+
+        async fn recv_from(&self, buf: &mut [u8]) -> webrtc_util::Result<(usize, SocketAddr)> {
+            Ok(self.socket.recv_from(buf).await?)
+        }
+
+        async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+            match buf.len() > self.max {
+                true => Ok(buf.len()),
+                false => Ok(self.socket.send(buf).await?),
+            }
+        }
+
+        async fn send_to(&self, buf: &[u8], _: SocketAddr) -> webrtc_util::Result<usize> {
+            self.send(buf).await
+        }
+
+        fn local_addr(&self) -> webrtc_util::Result<SocketAddr> {
+            Ok(self.socket.local_addr()?)
+        }
+
+        fn remote_addr(&self) -> Option<SocketAddr> {
+            self.socket.peer_addr().ok()
+        }
+
+        async fn close(&self) -> webrtc_util::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Probes from `a` towards `b`, which answers when `answering`.
+    async fn probe(max: usize, answering: bool) -> PathMtu {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        let local = a.local_addr().unwrap();
+
+        let a = Arc::new(Clamped { socket: a, max });
+        let (a, probe) = MtuProbe::wrap(a);
+        let b: Arc<dyn Conn + Send + Sync> = match answering {
+            true => MtuProbe::wrap(Arc::new(b)).0,
+            false => Arc::new(b),
+        };
+        for conn in [a, b] {
+            tokio::spawn(async move {
+                let mut buf = vec![0; 8192];
+                while conn.recv(&mut buf).await.is_ok() {}
+            });
+        }
+
+        let mtu = probe.probe(Some(local), Duration::from_millis(50)).await;
+        assert_eq!(probe.latest(), Some(mtu));
+        mtu
+    }
+
+    #[tokio::test]
+    async fn finds_largest_payload() {
+        let mtu = probe(1300, true).await;
+        let (low, high) = (mtu.max_payload.unwrap(), mtu.lost_payload.unwrap());
+        assert!(low <= 1300 && high > 1300, "{mtu:?}");
+        assert!(high - low <= PRECISION);
+
+        let mtu = probe(usize::MAX, true).await;
+        assert_eq!(mtu.max_payload, Some(8192));
+        assert_eq!(mtu.lost_payload, None);
+    }
+
+    #[tokio::test]
+    async fn times_out_without_answers() {
+        let mtu = probe(usize::MAX, false).await;
+        assert_eq!(mtu.max_payload, None);
+        assert_eq!(mtu.lost_payload, Some(LADDER[0]));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn dont_fragment() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&socket);
+        let policy = || getsockopt(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER).unwrap();
+        let default = policy();
+
+        let df = DontFragment::set(socket.local_addr().unwrap()).unwrap();
+        assert_eq!(policy(), libc::IP_PMTUDISC_PROBE);
+        drop(df);
+        assert_eq!(policy(), default);
+
+        let unbound = "127.0.0.1:9".parse().unwrap();
+        assert!(DontFragment::set(unbound).is_none());
+
+        // Nothing to keep from fragmenting, nothing probed
+        let (_, probe) = MtuProbe::wrap(Arc::new(socket));
+        let mtu = probe.probe(Some(unbound), PROBE_TIMEOUT).await;
+        assert_eq!(mtu, PathMtu::default());
+    }
+
+    #[test]
+    fn carries() {
+        let mtu = PathMtu {
+            max_payload: Some(1200),
+            lost_payload: Some(1208),
+        };
+        assert_eq!(mtu.carries(1200), Some(true));
+        assert_eq!(mtu.carries(1204), None);
+        assert_eq!(mtu.carries(1208), Some(false));
+
+        let unanswered = PathMtu {
+            max_payload: None,
+            lost_payload: Some(LADDER[0]),
+        };
+        assert_eq!(unanswered.carries(1), None);
+        let unbounded = PathMtu {
+            max_payload: Some(8192),
+            lost_payload: None,
+        };
+        assert_eq!(unbounded.carries(8192), Some(true));
+        assert_eq!(unbounded.carries(9000), None);
+    }
+}
@@ -0,0 +1,307 @@
+//! Security parameters each peer settled on, announced once the key
+//! agreement is done and checked against a [`SecurityFloor`], see
+//! [`ConnectOptions::security_floor`](crate::connect::ConnectOptions::security_floor).
+//!
+//! Announcements carry an HMAC keyed by the agreed basekey, which a man in
+//! the middle does not know, so it can neither alter nor strip them without
+//! the peer noticing. Each carries a random nonce as well, an announcement
+//! reflected back to its sender is refused.
+
+use crate::{
+    agreement::AuthKind,
+    crypto_backend,
+    error::TimeoutError,
+    pipe_stream::TransportKind,
+    signalling::{Rejection, SignalingError, Signalling},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use std::io;
+
+const HELLO: &str = "icepipe-security";
+
+/// What a peer connects with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SecurityParams {
+    pub auth: AuthKind,
+    pub transport: TransportKind,
+}
+
+/// Weakest parameters accepted, from this side and from the peer. The
+/// default accepts anything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SecurityFloor {
+    pub min_auth: AuthKind,
+    /// The only transport accepted, any if `None`.
+    pub transport: Option<TransportKind>,
+    /// Refuses [`TransportKind::Sctp`].
+    pub require_encryption: bool,
+}
+impl SecurityFloor {
+    /// Authenticated by a PSK or keys, and encrypted.
+    pub fn strict() -> SecurityFloor {
+        SecurityFloor {
+            min_auth: AuthKind::Psk,
+            transport: None,
+            require_encryption: true,
+        }
+    }
+
+    /// Fails with what is below the floor, `peer` telling whose `params`
+    /// these are.
+    pub fn check(&self, params: SecurityParams, peer: bool) -> Result<(), DowngradeError> {
+        if params.auth < self.min_auth {
+            return Err(DowngradeError::Auth {
+                peer,
+                auth: params.auth,
+                min: self.min_auth,
+            });
+        }
+        if self.require_encryption && params.transport == TransportKind::Sctp {
+            return Err(DowngradeError::Unencrypted { peer });
+        }
+        match self.transport {
+            Some(required) if required != params.transport => Err(DowngradeError::Transport {
+                peer,
+                transport: params.transport,
+                required,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Announces `local` and returns the parameters of the peer once
+    /// authenticated, failing if either is below the floor. `local` is
+    /// announced regardless, for the peer to tell why it is refused. Both
+    /// peers must set a floor, a peer without one announces nothing.
+    pub async fn negotiate<S>(
+        &self,
+        signalling: &mut S,
+        basekey: &[u8],
+        local: SecurityParams,
+    ) -> Result<SecurityParams, DowngradeError>
+    where
+        S: Signalling,
+        S::Error: Into<SignalingError>,
+    {
+        let mut nonce = [0; 16];
+        crypto_backend::fill_random(&mut nonce)
+            .map_err(|_| io::Error::other("failed to generate nonce"))?;
+        let nonce = BASE64_STANDARD.encode(nonce);
+        let announcement = format!("{nonce}\0{}\0{}", local.auth, local.transport);
+        let mac = crypto_backend::hmac_sha512_sign(&mac_key(basekey), announcement.as_bytes());
+        signalling
+            .send(format!(
+                "{HELLO}\0{announcement}\0{}",
+                BASE64_STANDARD.encode(mac)
+            ))
+            .await
+            .map_err(Into::into)?;
+        self.check(local, false)?;
+
+        let message = recv(signalling, basekey).await?;
+        let (peer_nonce, auth, transport, mac) = match message.split('\0').collect::<Vec<_>>()[..] {
+            [HELLO, peer_nonce, auth, transport, mac] => (peer_nonce, auth, transport, mac),
+            _ => return Err(DowngradeError::NotAnnounced),
+        };
+        let mac = BASE64_STANDARD
+            .decode(mac)
+            .map_err(|_| DowngradeError::Tampered)?;
+        let announcement = format!("{peer_nonce}\0{auth}\0{transport}");
+        crypto_backend::hmac_sha512_verify(&mac_key(basekey), announcement.as_bytes(), &mac)
+            .map_err(|_| DowngradeError::Tampered)?;
+        if peer_nonce == nonce {
+            return Err(DowngradeError::Tampered);
+        }
+
+        let peer = SecurityParams {
+            auth: AuthKind::from_name(auth).ok_or(DowngradeError::Tampered)?,
+            transport: TransportKind::from_name(transport).ok_or(DowngradeError::Tampered)?,
+        };
+        self.check(peer, true)?;
+
+        Ok(peer)
+    }
+}
+
+fn mac_key(basekey: &[u8]) -> [u8; 64] {
+    let mut key = [0; 64];
+    crypto_backend::hkdf_sha512(b"security", basekey, b"announcement", &mut key);
+
+    key
+}
+
+async fn recv<S>(signalling: &mut S, basekey: &[u8]) -> Result<String, DowngradeError>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    let rejection = Rejection::new(basekey);
+    loop {
+        let mut value = signalling.wait().await.map_err(Into::into)?;
+        if let Some(message) = signalling.then(&mut value).await.map_err(Into::into)? {
+            rejection.check(&message)?;
+            return Ok(message);
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DowngradeError {
+    #[error("Downgrade detected: {} authenticates with {auth}, at least {min} is required", side(*peer))]
+    Auth {
+        peer: bool,
+        auth: AuthKind,
+        min: AuthKind,
+    },
+    #[error("Downgrade detected: {} has encryption off", side(*peer))]
+    Unencrypted { peer: bool },
+    #[error("Downgrade detected: {} encrypts with {transport}, {required} is required", side(*peer))]
+    Transport {
+        peer: bool,
+        transport: TransportKind,
+        required: TransportKind,
+    },
+    #[error("Downgrade detected: the peer did not announce its security parameters")]
+    NotAnnounced,
+    #[error("Downgrade detected: the security announcement of the peer was tampered with")]
+    Tampered,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Timeout(#[from] TimeoutError),
+    #[error(transparent)]
+    SignalingError(SignalingError),
+}
+impl From<SignalingError> for DowngradeError {
+    fn from(value: SignalingError) -> Self {
+        match value {
+            SignalingError::Timeout(e) => e.into(),
+            SignalingError::Io(e) => e.into(),
+            e => Self::SignalingError(e),
+        }
+    }
+}
+
+fn side(peer: bool) -> &'static str {
+    match peer {
+        true => "the peer",
+        false => "this side",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_signalling::MemorySignalling;
+
+    const PSK_CHACHA: SecurityParams = SecurityParams {
+        auth: AuthKind::Psk,
+        transport: TransportKind::Chacha20,
+    };
+
+    #[test]
+    fn floor() {
+        let strict = SecurityFloor::strict();
+        strict.check(PSK_CHACHA, true).unwrap();
+        let custom = SecurityParams {
+            auth: AuthKind::Custom,
+            ..PSK_CHACHA
+        };
+        assert!(matches!(
+            strict.check(custom, true),
+            Err(DowngradeError::Auth { peer: true, .. })
+        ));
+        let unencrypted = SecurityParams {
+            transport: TransportKind::Sctp,
+            ..PSK_CHACHA
+        };
+        assert!(matches!(
+            strict.check(unencrypted, false),
+            Err(DowngradeError::Unencrypted { peer: false })
+        ));
+
+        let dtls_only = SecurityFloor {
+            transport: Some(TransportKind::Dtls),
+            ..Default::default()
+        };
+        assert!(matches!(
+            dtls_only.check(PSK_CHACHA, true),
+            Err(DowngradeError::Transport { .. })
+        ));
+        SecurityFloor::default().check(unencrypted, true).unwrap();
+    }
+
+    #[tokio::test]
+    async fn negotiates() {
+        let (mut a, mut b) = MemorySignalling::pair();
+        let floor = SecurityFloor::strict();
+        let unencrypted = SecurityParams {
+            transport: TransportKind::Sctp,
+            ..PSK_CHACHA
+        };
+        let lenient = SecurityFloor::default();
+        let (a, b) = tokio::join!(
+            floor.negotiate(&mut a, b"basekey", PSK_CHACHA),
+            lenient.negotiate(&mut b, b"basekey", unencrypted),
+        );
+        assert!(matches!(a, Err(DowngradeError::Unencrypted { peer: true })));
+        assert_eq!(b.unwrap(), PSK_CHACHA);
+    }
+
+    #[tokio::test]
+    async fn detects_tampering() {
+        let (mut a, mut b) = MemorySignalling::pair();
+        let floor = SecurityFloor::strict();
+        let (a, b) = tokio::join!(
+            floor.negotiate(&mut a, b"basekey", PSK_CHACHA),
+            floor.negotiate(&mut b, b"other key", PSK_CHACHA),
+        );
+        assert!(matches!(a, Err(DowngradeError::Tampered)));
+        assert!(matches!(b, Err(DowngradeError::Tampered)));
+
+        // Reflected to its sender
+        let mut reflected = Reflector(None);
+        let r = floor
+            .negotiate(&mut reflected, b"basekey", PSK_CHACHA)
+            .await;
+        assert!(matches!(r, Err(DowngradeError::Tampered)));
+    }
+
+    #[tokio::test]
+    async fn stripped_announcement() {
+        let (mut a, mut b) = MemorySignalling::pair();
+        let floor = SecurityFloor::strict();
+        let (a, _) = tokio::join!(floor.negotiate(&mut a, b"basekey", PSK_CHACHA), async {
+            b.send("Icepipe/dialer/0".to_string()).await.unwrap();
+        });
+        assert!(matches!(a, Err(DowngradeError::NotAnnounced)));
+    }
+
+    /// Hands back what is sent through it.
+    struct Reflector(Option<String>);
+    impl crate::pipe_stream::WaitThen for Reflector {
+        type Value = ();
+        type Output = Option<String>;
+        type Error = SignalingError;
+
+        fn wait(&mut self) -> futures::future::LocalBoxFuture<'_, Result<(), SignalingError>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn then<'a>(
+            &'a mut self,
+            _: &'a mut (),
+        ) -> futures::future::LocalBoxFuture<'a, Result<Option<String>, SignalingError>> {
+            Box::pin(async { Ok(self.0.take()) })
+        }
+    }
+    impl Signalling for Reflector {
+        fn send(
+            &mut self,
+            msg: String,
+        ) -> futures::future::LocalBoxFuture<'_, Result<(), SignalingError>> {
+            self.0 = Some(msg);
+            Box::pin(async { Ok(()) })
+        }
+    }
+}
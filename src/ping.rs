@@ -1,34 +1,85 @@
-use crate::error::TimeoutError;
+use crate::{error::TimeoutError, metrics};
+use futures::future::pending;
 use std::time::{Duration, Instant};
 use tokio::{select, time::sleep_until};
 
+/// Unanswered intervals after which the server is taken for gone.
+pub const MISSED_PINGS: u32 = 4;
+
+/// Longest interval icepipe-signal keeps a client across, it drops those
+/// silent for 60s.
+pub const MAX_SERVER_INTERVAL: Duration = Duration::from_secs(50);
+
+/// How often the signalling server connection is pinged, see
+/// [`ConnectOptions::signalling_ping`](crate::ConnectOptions::signalling_ping).
+///
+/// Each ping costs a few bytes and wakes the radio of a mobile connection.
+/// A longer interval or none saves both, at the cost of noticing a dead
+/// server later: after [`MISSED_PINGS`] intervals, or once a send fails or
+/// the OS gives up on the TCP connection when pings are disabled. Servers
+/// dropping idle clients, as icepipe-signal does past
+/// [`MAX_SERVER_INTERVAL`], also drop those pinging less than they expect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PingConfig {
+    /// `None` never pings nor times out.
+    pub interval: Option<Duration>,
+}
+impl PingConfig {
+    pub const DISABLED: PingConfig = PingConfig { interval: None };
+
+    pub fn every(interval: Duration) -> PingConfig {
+        PingConfig {
+            interval: Some(interval),
+        }
+    }
+}
+impl Default for PingConfig {
+    fn default() -> Self {
+        PingConfig::every(Duration::from_secs(15))
+    }
+}
+
 pub struct Ping {
+    config: PingConfig,
     last_ping: Instant,
     last_pong: Instant,
 }
 impl Ping {
     pub fn new() -> Ping {
+        Ping::with_config(PingConfig::default())
+    }
+
+    pub fn with_config(config: PingConfig) -> Ping {
         Ping {
+            config,
             last_ping: Instant::now(),
             last_pong: Instant::now(),
         }
     }
 
     pub async fn wait(&self) -> Result<MustPing, TimeoutError> {
-        let next_ping = self.last_ping + Duration::from_secs(15);
-        let pong_timeout = self.last_pong + Duration::from_secs(60);
+        let Some(interval) = self.config.interval else {
+            return pending().await;
+        };
+        // Too far to be represented, never
+        let next_ping = self.last_ping.checked_add(interval);
+        let pong_timeout = interval
+            .checked_mul(MISSED_PINGS)
+            .and_then(|timeout| self.last_pong.checked_add(timeout));
 
         select! {
-            _ = sleep_until(next_ping.into()) => {
+            _ = sleep_until_some(next_ping) => {
                 Ok(MustPing)
             }
-            _ = sleep_until(pong_timeout.into()) => {
+            _ = sleep_until_some(pong_timeout) => {
+                metrics::signalling_ping_timeout();
                 Err(TimeoutError)
             }
         }
     }
 
     pub fn sent_ping(&mut self) {
+        metrics::signalling_ping();
         self.last_ping = Instant::now();
     }
 
@@ -45,3 +96,24 @@ impl Default for Ping {
 }
 
 pub struct MustPing;
+
+async fn sleep_until_some(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline.into()).await,
+        None => pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn huge_interval() {
+        let ping = Ping::with_config(PingConfig::every(Duration::MAX));
+        assert!(ping.wait().now_or_never().is_none());
+        let ping = Ping::with_config(PingConfig::every(Duration::MAX / 2));
+        assert!(ping.wait().now_or_never().is_none());
+    }
+}
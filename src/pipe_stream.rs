@@ -1,7 +1,33 @@
-use crate::{error::TimeoutError, signalling::SignalingError};
-use futures::future::LocalBoxFuture;
-use std::io;
+use crate::{
+    error::{ClosedDirty, TimeoutError, TrafficLimitExceeded},
+    signalling::SignalingError,
+};
+use futures::{
+    future::{ready, LocalBoxFuture},
+    FutureExt,
+};
+use std::{
+    fmt, io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+/// Receiving split in two, so that waiting can be raced against other
+/// sources in a `select!` while handling what arrived cannot.
+///
+/// Every implementation keeps `wait` cancel safe: a future dropped before
+/// resolving has consumed nothing, the next `wait` yields what it would
+/// have. Progress made on the way, e.g. a partial message or a pending
+/// reconnection, is kept in `self`, and whatever runs before the first
+/// await, e.g. preparing a buffer, discards nothing. `then` is not cancel
+/// safe and each value must go through it before waiting again, a dropped
+/// value is lost.
+///
+/// Sends, on [`PipeStream`] and [`Signalling`](crate::signalling::Signalling)
+/// alike, leave in the order they were first polled. What `then` sends on
+/// its own, e.g. a pong, queues behind the sends before it. A send dropped
+/// after its first poll still goes out, ahead of any later one, while one
+/// dropped before it was polled sends nothing.
 pub trait WaitThen {
     type Value;
     type Output;
@@ -17,6 +43,46 @@ pub trait WaitThen {
 pub trait Control: WaitThen {
     fn close(&mut self) -> LocalBoxFuture<'_, Result<(), Self::Error>>;
     fn rx_closed(&self) -> bool;
+
+    /// Why the stream ended, `None` while it is open or if this stream
+    /// cannot tell.
+    fn close_reason(&self) -> Option<CloseReason> {
+        None
+    }
+
+    /// Sends `notice` to the peer aside from the data, for it to log. Streams
+    /// without such a channel drop it.
+    fn notify<'a>(&'a mut self, _notice: &'a str) -> LocalBoxFuture<'a, Result<(), Self::Error>> {
+        ready(Ok(())).boxed_local()
+    }
+
+    /// The connection this stream runs over, `None` if it is not a
+    /// connection or this stream cannot tell. Wrappers forward it.
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        None
+    }
+
+    /// Resolves once the stream is ready to take more data without piling
+    /// it up, immediately for streams without such a notion. Does not borrow
+    /// the stream, so it can be awaited while the stream is waited on.
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        ready(()).boxed_local()
+    }
+
+    /// Asks the peer to wind down and close within `deadline`, see
+    /// [`ShutdownRequest`]. Streams without such a channel drop it.
+    fn request_shutdown(
+        &mut self,
+        _deadline: Duration,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        ready(Ok(())).boxed_local()
+    }
+
+    /// The shutdown requested by either side, `None` if none was or this
+    /// stream cannot tell. Wrappers forward it.
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        None
+    }
 }
 
 pub trait PipeStream: WaitThen<Output = Option<Vec<u8>>> + Control
@@ -26,6 +92,189 @@ where
     fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, Result<(), Self::Error>>;
 }
 
+pub trait PipeReadHalf: WaitThen<Output = Option<Vec<u8>>> {
+    fn rx_closed(&self) -> bool;
+
+    /// See [`Control::close_reason`].
+    fn close_reason(&self) -> Option<CloseReason> {
+        None
+    }
+
+    /// See [`Control::connection_info`].
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        None
+    }
+
+    /// See [`Control::shutdown_request`].
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        None
+    }
+}
+
+/// Why a stream ended, the first cause wins.
+#[derive(Clone, Debug)]
+pub enum CloseReason {
+    /// The peer finished sending.
+    CleanFin,
+    /// The connection broke, or ended without the peer finishing.
+    TransportFailed(Arc<dyn std::error::Error + Send + Sync>),
+    /// A [`Deadline`](crate::deadline::Deadline) passed.
+    Timeout,
+    /// This side closed first.
+    LocalClose,
+    /// The session reached its
+    /// [`TrafficLimit`](crate::traffic_limit::TrafficLimit).
+    TrafficLimit,
+    /// This side closed as the peer asked with
+    /// [`Control::request_shutdown`].
+    RemoteRequested,
+}
+impl CloseReason {
+    pub fn transport_failed<E>(error: E) -> CloseReason
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        CloseReason::TransportFailed(Arc::from(error.into()))
+    }
+
+    /// Whether the stream ended as intended by either side.
+    pub fn is_clean(&self) -> bool {
+        matches!(
+            self,
+            CloseReason::CleanFin | CloseReason::LocalClose | CloseReason::RemoteRequested
+        )
+    }
+}
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::CleanFin => write!(f, "peer finished"),
+            CloseReason::TransportFailed(e) => write!(f, "transport failed: {e}"),
+            CloseReason::Timeout => write!(f, "timed out"),
+            CloseReason::LocalClose => write!(f, "closed locally"),
+            CloseReason::TrafficLimit => write!(f, "traffic limit reached"),
+            CloseReason::RemoteRequested => write!(f, "closed as the peer asked"),
+        }
+    }
+}
+
+/// A peer asking the other to finish what is in flight and close, sent in
+/// order with the data, so everything sent before it arrives first. The
+/// asking side stops reading what it forwards and the asked side does too,
+/// flushes and closes with [`CloseReason::RemoteRequested`], see
+/// [`forward`](crate::service::forward). Nothing makes it comply, the asking
+/// side closes on its own once the deadline passes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ShutdownRequest {
+    /// Time granted to close.
+    pub deadline: Duration,
+    /// When it was sent or received, the deadline counts from then.
+    pub at: Instant,
+    /// Whether the peer asked, this side did otherwise.
+    pub remote: bool,
+}
+impl ShutdownRequest {
+    pub fn expires(&self) -> Instant {
+        self.at + self.deadline
+    }
+}
+
+/// Facts about a connection fixed once it is established, shared by all of
+/// its layers and halves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Whether this side is the dialer, the peer is the other role.
+    pub dialer: bool,
+    /// Channel joined on the signalling server, derived from the one
+    /// configured. Safe to log, it does not reveal the configured channel.
+    /// Empty when not connected through [`crate::connect`].
+    pub channel_id: String,
+    pub transport: TransportKind,
+    /// Ed25519 key the peer authenticated with, `None` for a pre-shared key.
+    pub peer_public_key: Option<Vec<u8>>,
+    /// Handed over by the peer during the key agreement, see
+    /// [`ConnectOptions::agreement_payload`](crate::connect::ConnectOptions::agreement_payload).
+    pub peer_payload: Option<Vec<u8>>,
+}
+
+/// Layers carrying a connection, on top of ICE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportKind {
+    /// ChaCha20-Poly1305 over SCTP.
+    Chacha20,
+    /// SCTP over DTLS.
+    Dtls,
+    /// SCTP alone, see
+    /// [`ConnectOptions::connect_unencrypted`](crate::connect::ConnectOptions::connect_unencrypted).
+    Sctp,
+}
+impl TransportKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            TransportKind::Chacha20 => "chacha20-poly1305",
+            TransportKind::Dtls => "dtls",
+            TransportKind::Sctp => "none",
+        }
+    }
+
+    #[cfg(feature = "crypto")]
+    pub(crate) fn from_name(name: &str) -> Option<TransportKind> {
+        [
+            TransportKind::Chacha20,
+            TransportKind::Dtls,
+            TransportKind::Sctp,
+        ]
+        .into_iter()
+        .find(|transport| transport.name() == name)
+    }
+}
+impl std::fmt::Display for TransportKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub trait PipeWriteHalf {
+    type Error: std::error::Error;
+
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, Result<(), Self::Error>>;
+    fn close(&mut self) -> LocalBoxFuture<'_, Result<(), Self::Error>>;
+
+    /// See [`Control::notify`].
+    fn notify<'a>(&'a mut self, _notice: &'a str) -> LocalBoxFuture<'a, Result<(), Self::Error>> {
+        ready(Ok(())).boxed_local()
+    }
+
+    /// See [`Control::connection_info`].
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        None
+    }
+
+    /// See [`Control::writable`].
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        ready(()).boxed_local()
+    }
+
+    /// See [`Control::request_shutdown`].
+    fn request_shutdown(
+        &mut self,
+        _deadline: Duration,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        ready(Ok(())).boxed_local()
+    }
+}
+
+/// Streams that can be divided into independently owned read and write halves.
+pub trait Split: PipeStream
+where
+    Self::Error: Into<StreamError>,
+{
+    type ReadHalf: PipeReadHalf;
+    type WriteHalf: PipeWriteHalf;
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf);
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum StreamError {
     #[error(transparent)]
@@ -33,6 +282,10 @@ pub enum StreamError {
     #[error(transparent)]
     Timeout(#[from] TimeoutError),
     #[error(transparent)]
+    TrafficLimit(#[from] TrafficLimitExceeded),
+    #[error(transparent)]
+    ClosedDirty(#[from] ClosedDirty),
+    #[error(transparent)]
     SignalingError(SignalingError),
     #[error(transparent)]
     Other(Box<dyn std::error::Error + Send + Sync>),
@@ -46,4 +299,24 @@ impl From<SignalingError> for StreamError {
         }
     }
 }
+impl From<StreamError> for SignalingError {
+    fn from(value: StreamError) -> Self {
+        match value {
+            StreamError::Io(e) => e.into(),
+            StreamError::Timeout(e) => e.into(),
+            StreamError::SignalingError(e) => e,
+            e => Self::ProtocolError(Box::new(e)),
+        }
+    }
+}
 pub type StreamResult<T> = Result<T, StreamError>;
+
+pub(crate) fn stream_result<'a, T, E>(
+    future: LocalBoxFuture<'a, Result<T, E>>,
+) -> LocalBoxFuture<'a, StreamResult<T>>
+where
+    T: 'a,
+    E: Into<StreamError> + 'a,
+{
+    future.map(|r| r.map_err(Into::into)).boxed_local()
+}
@@ -0,0 +1,399 @@
+//! Admission of peers right after the key agreement, before any ICE work, see
+//! [`ConnectOptions::policy`](crate::connect::ConnectOptions::policy).
+//!
+//! The side with a policy waits for the [`Hello`] of the peer, which waits
+//! for the [`Decision`] in turn, both over the signalling channel. Each is
+//! sealed under its own key derived from the agreed basekey, so the
+//! signalling server neither sees the invite tokens nor forges a decision,
+//! and a message reflected back to its sender fails to open.
+
+use crate::{
+    crypto_backend::{self, Chacha20Poly1305Key, NONCE_LEN},
+    error::TimeoutError,
+    logging,
+    rate_limit::{RateLimit, RateLimiter},
+    signalling::{Rejection, SignalingError, Signalling},
+    trace,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::{
+    future::{ready, LocalBoxFuture},
+    FutureExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, io, sync::Mutex, time::Duration};
+
+/// Who is asking to connect, as far as the agreement tells.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub channel: String,
+    /// Ed25519 key the peer authenticated with, `None` for a pre-shared key.
+    pub public_key: Option<Vec<u8>>,
+}
+
+/// Presented by the peer without a policy, see
+/// [`ConnectOptions::hello`](crate::connect::ConnectOptions::hello).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    /// E.g. a one-time invite.
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Decision {
+    Accept,
+    /// The reason is reported to the peer.
+    Reject(String),
+    /// Tells the peer to try again after a while.
+    Throttle(Duration),
+}
+
+pub trait ConnectPolicy: Send + Sync {
+    fn authorize<'a>(
+        &'a self,
+        peer: &'a PeerIdentity,
+        hello: &'a Hello,
+    ) -> LocalBoxFuture<'a, Decision>;
+}
+
+/// Accepts connections at the pace of a token bucket, throttling the rest.
+pub struct RateLimitPolicy {
+    limit: RateLimit,
+    limiter: Mutex<RateLimiter>,
+}
+impl RateLimitPolicy {
+    pub fn new(limit: RateLimit) -> RateLimitPolicy {
+        RateLimitPolicy {
+            limit,
+            limiter: Mutex::new(RateLimiter::new(limit)),
+        }
+    }
+}
+impl ConnectPolicy for RateLimitPolicy {
+    fn authorize<'a>(&'a self, _: &'a PeerIdentity, _: &'a Hello) -> LocalBoxFuture<'a, Decision> {
+        let decision = match self.limiter.lock().unwrap().try_acquire() {
+            true => Decision::Accept,
+            false => Decision::Throttle(Duration::from_secs_f64(
+                1.0 / self.limit.per_second.max(1) as f64,
+            )),
+        };
+        ready(decision).boxed_local()
+    }
+}
+
+/// Only accepts peers authenticated with one of `keys`, so never peers
+/// using a pre-shared key.
+pub struct AllowlistPolicy {
+    pub keys: HashSet<Vec<u8>>,
+}
+impl ConnectPolicy for AllowlistPolicy {
+    fn authorize<'a>(
+        &'a self,
+        peer: &'a PeerIdentity,
+        _: &'a Hello,
+    ) -> LocalBoxFuture<'a, Decision> {
+        let decision = match &peer.public_key {
+            Some(key) if self.keys.contains(key) => Decision::Accept,
+            _ => Decision::Reject("Not on the allowlist".to_string()),
+        };
+        ready(decision).boxed_local()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Admission {
+    Hello(Hello),
+    Decision(Decision),
+}
+impl Admission {
+    /// Info of the key the message is sealed under, so that one kind of
+    /// message never opens as the other.
+    fn info(&self) -> &'static [u8] {
+        match self {
+            Admission::Hello(_) => b"hello",
+            Admission::Decision(_) => b"decision",
+        }
+    }
+}
+
+/// Decides on the peer with `policy`, the peer is told either way.
+pub(crate) async fn admit<S>(
+    signalling: &mut S,
+    basekey: &[u8],
+    policy: &dyn ConnectPolicy,
+    peer: &PeerIdentity,
+) -> AdmissionResult<()>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    let decision = match recv(signalling, basekey).await? {
+        Admission::Hello(hello) => policy.authorize(peer, &hello).await,
+        Admission::Decision(_) => Decision::Reject("Expected a hello".to_string()),
+    };
+    trace::info!(target: logging::AGREEMENT, "Admission of {}: {decision:?}", peer.channel);
+    send(signalling, basekey, Admission::Decision(decision.clone())).await?;
+
+    decided(decision)
+}
+
+/// Presents `hello` to the peer and waits for its decision.
+pub(crate) async fn present<S>(
+    signalling: &mut S,
+    basekey: &[u8],
+    hello: &Hello,
+) -> AdmissionResult<()>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    send(signalling, basekey, Admission::Hello(hello.clone())).await?;
+    match recv(signalling, basekey).await? {
+        Admission::Decision(decision) => decided(decision),
+        Admission::Hello(_) => Err(AdmissionError::UnexpectedHello),
+    }
+}
+
+fn decided(decision: Decision) -> AdmissionResult<()> {
+    match decision {
+        Decision::Accept => Ok(()),
+        Decision::Reject(reason) => Err(AdmissionError::Rejected(reason)),
+        Decision::Throttle(retry_after) => Err(AdmissionError::Throttled(retry_after)),
+    }
+}
+
+fn key(basekey: &[u8], info: &[u8]) -> AdmissionResult<Chacha20Poly1305Key> {
+    let mut key = [0; Chacha20Poly1305Key::KEY_LEN];
+    crypto_backend::hkdf_sha512(b"admission", basekey, info, &mut key);
+
+    Chacha20Poly1305Key::new(&key).map_err(|_| io::Error::other("Bad admission key").into())
+}
+
+/// Sends `msg` sealed under a random nonce, which goes in front of it.
+async fn send<S>(signalling: &mut S, basekey: &[u8], msg: Admission) -> AdmissionResult<()>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    let mut nonce = [0; NONCE_LEN];
+    crypto_backend::fill_random(&mut nonce)
+        .map_err(|_| io::Error::other("Failed to generate nonce"))?;
+    let mut sealed = serde_json::to_vec(&msg)?;
+    key(basekey, msg.info())?
+        .seal_in_place(nonce, &mut sealed)
+        .map_err(|_| io::Error::other("Failed to seal"))?;
+    let msg = BASE64_STANDARD.encode([&nonce[..], &sealed].concat());
+    signalling.send(msg).await.map_err(Into::into)?;
+    Ok(())
+}
+
+async fn recv<S>(signalling: &mut S, basekey: &[u8]) -> AdmissionResult<Admission>
+where
+    S: Signalling,
+    S::Error: Into<SignalingError>,
+{
+    let rejection = Rejection::new(basekey);
+    loop {
+        let mut value = signalling.wait().await.map_err(Into::into)?;
+        let msg = signalling.then(&mut value).await.map_err(Into::into)?;
+        if let Some(msg) = msg {
+            rejection.check(&msg)?;
+            return open(basekey, &msg);
+        }
+    }
+}
+
+/// Opens `msg` under the key of either kind, checking it is of that kind.
+fn open(basekey: &[u8], msg: &str) -> AdmissionResult<Admission> {
+    let msg = BASE64_STANDARD
+        .decode(msg)
+        .map_err(|_| AdmissionError::Tampered)?;
+    if msg.len() < NONCE_LEN {
+        return Err(AdmissionError::Tampered);
+    }
+    let (nonce, sealed) = msg.split_at(NONCE_LEN);
+    let nonce = nonce.try_into().unwrap();
+    for info in [&b"hello"[..], b"decision"] {
+        let mut opened = sealed.to_vec();
+        if key(basekey, info)?
+            .open_in_place(nonce, &mut opened)
+            .is_err()
+        {
+            continue;
+        }
+        let admission: Admission = serde_json::from_slice(&opened)?;
+        if admission.info() != info {
+            return Err(AdmissionError::Tampered);
+        }
+        return Ok(admission);
+    }
+
+    Err(AdmissionError::Tampered)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AdmissionError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Timeout(#[from] TimeoutError),
+    #[error(transparent)]
+    SignalingError(SignalingError),
+    #[error("Admission message not understood, does the peer expect one? {0}")]
+    BadMessage(#[from] serde_json::Error),
+    #[error("Both peers presented a hello, only one of them should have a policy")]
+    UnexpectedHello,
+    #[error("Admission message tampered with, or sealed under another key")]
+    Tampered,
+    #[error("Connection rejected: {0}")]
+    Rejected(String),
+    #[error("Connection throttled, retry in {0:?}")]
+    Throttled(Duration),
+}
+impl From<SignalingError> for AdmissionError {
+    fn from(value: SignalingError) -> Self {
+        match value {
+            SignalingError::Io(e) => e.into(),
+            SignalingError::Timeout(e) => e.into(),
+            e => Self::SignalingError(e),
+        }
+    }
+}
+pub type AdmissionResult<T> = Result<T, AdmissionError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory_signalling::MemorySignalling, pipe_stream::WaitThen};
+
+    fn peer(public_key: Option<&[u8]>) -> PeerIdentity {
+        PeerIdentity {
+            channel: "channel".to_string(),
+            public_key: public_key.map(<[u8]>::to_vec),
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let policy = RateLimitPolicy::new(RateLimit {
+            burst: 2,
+            per_second: 4,
+        });
+        let hello = Hello::default();
+        for _ in 0..2 {
+            assert_eq!(
+                policy.authorize(&peer(None), &hello).await,
+                Decision::Accept
+            );
+        }
+        assert_eq!(
+            policy.authorize(&peer(None), &hello).await,
+            Decision::Throttle(Duration::from_millis(250))
+        );
+    }
+
+    #[tokio::test]
+    async fn allowlist() {
+        let policy = AllowlistPolicy {
+            keys: HashSet::from([vec![1; 32]]),
+        };
+        let hello = Hello::default();
+        assert_eq!(
+            policy.authorize(&peer(Some(&[1; 32])), &hello).await,
+            Decision::Accept
+        );
+        assert!(matches!(
+            policy.authorize(&peer(Some(&[2; 32])), &hello).await,
+            Decision::Reject(_)
+        ));
+        assert!(matches!(
+            policy.authorize(&peer(None), &hello).await,
+            Decision::Reject(_)
+        ));
+    }
+
+    /// Accepts the one invite it handed out, once.
+    struct Invite(Mutex<Option<String>>);
+    impl ConnectPolicy for Invite {
+        fn authorize<'a>(
+            &'a self,
+            _: &'a PeerIdentity,
+            hello: &'a Hello,
+        ) -> LocalBoxFuture<'a, Decision> {
+            let mut invite = self.0.lock().unwrap();
+            let decision = match hello.token.is_some() && *invite == hello.token {
+                true => {
+                    invite.take();
+                    Decision::Accept
+                }
+                false => Decision::Reject("Invite expired".to_string()),
+            };
+            ready(decision).boxed_local()
+        }
+    }
+
+    #[tokio::test]
+    async fn reason_reaches_the_peer() {
+        let policy = Invite(Mutex::new(Some("letmein".to_string())));
+        let hello = Hello {
+            token: Some("letmein".to_string()),
+        };
+
+        for accepted in [true, false] {
+            let (mut a, mut b) = MemorySignalling::pair();
+            let peer = peer(None);
+            let (admitted, presented) = tokio::join!(
+                admit(&mut a, b"basekey", &policy, &peer),
+                present(&mut b, b"basekey", &hello)
+            );
+            assert_eq!(admitted.is_ok(), accepted);
+            match presented {
+                Ok(()) => assert!(accepted),
+                Err(AdmissionError::Rejected(reason)) => assert_eq!(reason, "Invite expired"),
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    async fn raw(signalling: &mut MemorySignalling) -> String {
+        let mut value = signalling.wait().await.unwrap();
+        signalling.then(&mut value).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn sealed() {
+        let hello = Hello {
+            token: Some("letmein".to_string()),
+        };
+        let (mut a, mut b) = MemorySignalling::pair();
+        present(&mut a, b"basekey", &hello).now_or_never();
+        let wire = raw(&mut b).await;
+        // Hidden from the signalling server
+        assert!(!wire.contains("letmein"));
+        assert!(matches!(
+            open(b"basekey", &wire),
+            Ok(Admission::Hello(opened)) if opened == hello
+        ));
+        assert!(matches!(
+            open(b"other key", &wire),
+            Err(AdmissionError::Tampered)
+        ));
+
+        // Neither forged without the basekey nor reflected
+        let forged = serde_json::to_string(&Admission::Decision(Decision::Accept)).unwrap();
+        let (mut a, mut b) = MemorySignalling::pair();
+        b.send(forged).await.unwrap();
+        assert!(matches!(
+            present(&mut a, b"basekey", &hello).await,
+            Err(AdmissionError::Tampered)
+        ));
+        let reflected = raw(&mut b).await;
+        let (mut a, mut b) = MemorySignalling::pair();
+        b.send(reflected).await.unwrap();
+        assert!(matches!(
+            present(&mut a, b"basekey", &hello).await,
+            Err(AdmissionError::UnexpectedHello)
+        ));
+    }
+}
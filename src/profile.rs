@@ -0,0 +1,291 @@
+//! Named sets of connection settings kept in a file, so a peer is connected
+//! to by name instead of a long command line, see [`ConnectionProfile`] and
+//! [`Profiles`].
+//!
+//! Profiles hold secrets, the file is written readable by its owner only and
+//! loading one readable by others warns. Secrets are wiped from memory once
+//! dropped, see [`Secret`].
+
+use crate::{
+    channel::Psk,
+    connect::{ConnectError, ConnectOptions, ConnectResult, Encryption},
+    ice::IceServer,
+    logging,
+    ping::PingConfig,
+    trace,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::BTreeMap, fmt, fs, io, path::Path, time::Duration};
+use zeroize::Zeroizing;
+
+/// Channel password or private key, zeroed once dropped and never printed.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(Zeroizing<String>);
+impl Secret {
+    pub fn new(secret: impl Into<String>) -> Secret {
+        Secret(Zeroizing::new(secret.into()))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.expose())
+    }
+}
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+/// What is needed to reach a peer, turned into [`ConnectOptions`] with
+/// [`ConnectionProfile::options`]. Durations are in seconds, settings left
+/// out keep their default.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConnectionProfile {
+    /// Channel password, or the public key of the peer along with
+    /// `private_key`.
+    pub channel: Secret,
+    /// Seed of the Ed25519 key of this side in hex, connecting with the
+    /// peer key in `channel` instead of a password. Left to the application,
+    /// [`ConnectOptions`] has no key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<Secret>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signaling: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ice_servers: Vec<IceServer>,
+    pub encryption: Encryption,
+    /// See [`SctpConfig::close_timeout`](crate::sctp::SctpConfig::close_timeout).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub close_timeout: Option<u64>,
+    /// See [`ConnectOptions::signalling_ping`], 0 disables pings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signaling_ping: Option<u64>,
+    /// See [`ConnectOptions::signalling_send_timeout`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signaling_send_timeout: Option<u64>,
+}
+impl ConnectionProfile {
+    /// The settings of `options` kept in profiles, secrets included.
+    pub fn from_options(options: &ConnectOptions) -> ConnectResult<ConnectionProfile> {
+        let mut ice_servers = options.ice_servers.clone();
+        for ice in &options.ice {
+            ice_servers.push(ice.parse().map_err(ConnectError::BadIceUrl)?);
+        }
+
+        Ok(ConnectionProfile {
+            channel: Secret::new(
+                options
+                    .channel
+                    .psk()
+                    .ok_or(ConnectError::NoPsk("a profile"))?
+                    .expose(),
+            ),
+            private_key: None,
+            signaling: options.signaling.as_ref().map(ToString::to_string),
+            ice_servers,
+            encryption: options.encryption,
+            close_timeout: options.sctp.close_timeout.map(|d| d.as_secs()),
+            signaling_ping: Some(options.signalling_ping.interval.map_or(0, |d| d.as_secs())),
+            signaling_send_timeout: options.signalling_send_timeout.map(|d| d.as_secs()),
+        })
+    }
+
+    /// Fails on a signalling or ICE server URL that does not parse.
+    pub fn options(&self) -> ConnectResult<ConnectOptions> {
+        for server in &self.ice_servers {
+            server.parse_urls().map_err(ConnectError::BadIceUrl)?;
+        }
+        let mut options = ConnectOptions {
+            channel: Psk::new(self.channel.expose()).into(),
+            signaling: self
+                .signaling
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .map_err(ConnectError::BadSignalingUrl)?,
+            ice_servers: self.ice_servers.clone(),
+            encryption: self.encryption,
+            signalling_send_timeout: self.signaling_send_timeout.map(Duration::from_secs),
+            ..Default::default()
+        };
+        if let Some(secs) = self.close_timeout {
+            options.sctp.close_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.signaling_ping {
+            options.signalling_ping = match secs {
+                0 => PingConfig::DISABLED,
+                secs => PingConfig::every(Duration::from_secs(secs)),
+            };
+        }
+
+        Ok(options)
+    }
+}
+
+/// Profiles by name, stored as a JSON object.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Profiles(pub BTreeMap<String, ConnectionProfile>);
+impl Profiles {
+    /// No profiles if `path` does not exist. Warns if others may read it.
+    pub fn load(path: &Path) -> Result<Profiles, ProfileError> {
+        let json = match fs::read_to_string(path) {
+            Ok(json) => Zeroizing::new(json),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Profiles::default()),
+            Err(e) => return Err(e.into()),
+        };
+        if exposed(path)? {
+            trace::warn!(
+                target: logging::PROFILE,
+                "{} holds secrets but others may read it, restrict it with chmod 600",
+                path.display()
+            );
+        }
+
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Replaces `path`, readable by its owner only.
+    pub fn save(&self, path: &Path) -> Result<(), ProfileError> {
+        let json = Zeroizing::new(serde_json::to_string_pretty(self)?);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        // An existing file keeps its mode otherwise
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        io::Write::write_all(&mut file, json.as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&ConnectionProfile, ProfileError> {
+        self.0
+            .get(name)
+            .ok_or_else(|| ProfileError::Unknown(name.to_owned()))
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, profile: ConnectionProfile) {
+        self.0.insert(name.into(), profile);
+    }
+}
+
+/// Whether users other than the owner may read `path`.
+#[cfg(unix)]
+fn exposed(path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::metadata(path)?.permissions().mode() & 0o077 != 0)
+}
+
+#[cfg(not(unix))]
+fn exposed(_path: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProfileError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Bad profiles file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("No profile named {0:?}")]
+    Unknown(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_roundtrip() {
+        let options = ConnectOptions {
+            channel: "hunter2".into(),
+            signaling: Some("wss://signalling.example/".parse().unwrap()),
+            ice: vec!["turn:relay.example:3478&user&secret".to_string()],
+            signalling_ping: PingConfig::DISABLED,
+            ..Default::default()
+        };
+        let profile = ConnectionProfile::from_options(&options).unwrap();
+        assert_eq!(profile.ice_servers[0].username.as_deref(), Some("user"));
+        assert_eq!(profile.signaling_ping, Some(0));
+        assert!(!format!("{profile:?}").contains("hunter2"));
+
+        let options = profile.options().unwrap();
+        assert_eq!(options.channel.psk().unwrap().expose(), "hunter2");
+        assert_eq!(
+            options.signaling.unwrap().as_str(),
+            "wss://signalling.example/"
+        );
+        assert_eq!(options.signalling_ping, PingConfig::DISABLED);
+        assert_eq!(options.ice_servers, profile.ice_servers);
+    }
+
+    #[test]
+    fn bad_urls() {
+        let profile = ConnectionProfile {
+            signaling: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            profile.options(),
+            Err(ConnectError::BadSignalingUrl(_))
+        ));
+
+        let profile = ConnectionProfile {
+            ice_servers: vec![IceServer::new("http://relay.example")],
+            ..Default::default()
+        };
+        assert!(matches!(profile.options(), Err(ConnectError::BadIceUrl(_))));
+    }
+
+    #[test]
+    fn file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("icepipe").join("profiles.json");
+        assert_eq!(Profiles::load(&path).unwrap(), Profiles::default());
+
+        let json = r#"{
+            "office": {
+                "channel": "hunter2",
+                "ice-servers": [{"urls": "stun:stun.example:19302"}],
+                "encryption": "chacha20",
+                "close-timeout": 30
+            }
+        }"#;
+        let profiles: Profiles = serde_json::from_str(json).unwrap();
+        profiles.save(&path).unwrap();
+        #[cfg(unix)]
+        assert!(!exposed(&path).unwrap());
+
+        let loaded = Profiles::load(&path).unwrap();
+        assert_eq!(loaded, profiles);
+        let office = loaded.get("office").unwrap();
+        assert_eq!(office.close_timeout, Some(30));
+        let options = office.options().unwrap();
+        assert_eq!(options.sctp.close_timeout, Some(Duration::from_secs(30)));
+        assert!(matches!(loaded.get("home"), Err(ProfileError::Unknown(_))));
+
+        let typo = r#"{"office": {"chanel": "hunter2"}}"#;
+        assert!(serde_json::from_str::<Profiles>(typo).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! Bounded outbound queue in front of a stream, so a slow link does not stall
+//! whoever is also waiting on the receive side, see [`QueuedStream`].
+
+use crate::pipe_stream::{
+    CloseReason, ConnectionInfo, Control, PipeReadHalf, PipeStream, PipeWriteHalf, ShutdownRequest,
+    Split, StreamError, StreamResult, WaitThen,
+};
+use futures::{
+    future::{pending, LocalBoxFuture},
+    FutureExt,
+};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+use tokio::select;
+
+/// What [`QueuedStream::send`](PipeStream::send) does once the queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Waits for room, applying back pressure to the caller.
+    #[default]
+    Block,
+    /// Discards the message being sent.
+    DropNewest,
+    /// Discards queued messages, oldest first, until the new one fits.
+    DropOldest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Bytes queued on top of the message being sent. A larger message is
+    /// still accepted when the queue is empty.
+    pub max_bytes: usize,
+    pub policy: DropPolicy,
+}
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_bytes: 1024 * 1024,
+            policy: DropPolicy::Block,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub dropped_messages: u64,
+    pub dropped_bytes: u64,
+}
+
+/// Sends are queued and handed to the write half while the stream is waited
+/// on, so receiving goes on while the link drains. Messages are never split.
+pub struct QueuedStream<R, W>
+where
+    W: PipeWriteHalf,
+{
+    rx: R,
+    tx: SendQueue<W>,
+}
+impl<R, W> QueuedStream<R, W>
+where
+    R: PipeReadHalf,
+    R::Error: Into<StreamError>,
+    W: PipeWriteHalf + 'static,
+    W::Error: Into<StreamError>,
+{
+    pub fn new<S>(stream: S, config: QueueConfig) -> Self
+    where
+        S: Split<ReadHalf = R, WriteHalf = W>,
+        S::Error: Into<StreamError>,
+    {
+        let (rx, tx) = stream.split();
+        Self::from_halves(rx, tx, config)
+    }
+
+    pub fn from_halves(rx: R, tx: W, config: QueueConfig) -> Self {
+        QueuedStream {
+            rx,
+            tx: SendQueue {
+                tx: Some(tx),
+                in_flight: None,
+                queue: VecDeque::new(),
+                queued_bytes: 0,
+                config,
+                stats: QueueStats::default(),
+            },
+        }
+    }
+
+    /// Bytes waiting behind the message being sent.
+    pub fn queued_bytes(&self) -> usize {
+        self.tx.queued_bytes
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.tx.stats
+    }
+}
+impl<R, W> WaitThen for QueuedStream<R, W>
+where
+    R: PipeReadHalf,
+    R::Error: Into<StreamError>,
+    W: PipeWriteHalf + 'static,
+    W::Error: Into<StreamError>,
+{
+    type Value = R::Value;
+    type Output = Option<Vec<u8>>;
+    type Error = StreamError;
+
+    /// Cancel safe if the read half is, the send in flight is kept aside
+    /// and resumed by the next wait.
+    fn wait(&mut self) -> LocalBoxFuture<'_, StreamResult<Self::Value>> {
+        async move {
+            loop {
+                select! {
+                    value = self.rx.wait() => return value.map_err(Into::into),
+                    r = self.tx.progress() => r?,
+                }
+            }
+        }
+        .boxed_local()
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, StreamResult<Self::Output>> {
+        async move { self.rx.then(value).await.map_err(Into::into) }.boxed_local()
+    }
+}
+impl<R, W> Control for QueuedStream<R, W>
+where
+    R: PipeReadHalf,
+    R::Error: Into<StreamError>,
+    W: PipeWriteHalf + 'static,
+    W::Error: Into<StreamError>,
+{
+    /// Sends everything queued before closing.
+    fn close(&mut self) -> LocalBoxFuture<'_, StreamResult<()>> {
+        async move {
+            while self.tx.busy() {
+                self.tx.progress().await?;
+            }
+            let tx = self.tx.tx.as_mut().expect("write half is back once idle");
+            tx.close().await.map_err(Into::into)
+        }
+        .boxed_local()
+    }
+
+    fn rx_closed(&self) -> bool {
+        self.rx.rx_closed()
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.rx.close_reason()
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        self.rx.connection_info()
+    }
+
+    /// Sent behind everything queued.
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, StreamResult<()>> {
+        async move {
+            while self.tx.busy() {
+                self.tx.progress().await?;
+            }
+            let tx = self.tx.tx.as_mut().expect("write half is back once idle");
+            tx.request_shutdown(deadline).await.map_err(Into::into)
+        }
+        .boxed_local()
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        self.rx.shutdown_request()
+    }
+}
+impl<R, W> PipeStream for QueuedStream<R, W>
+where
+    R: PipeReadHalf,
+    R::Error: Into<StreamError>,
+    W: PipeWriteHalf + 'static,
+    W::Error: Into<StreamError>,
+{
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, StreamResult<()>> {
+        async move { self.tx.push(data).await }.boxed_local()
+    }
+}
+
+type InFlight<W> = LocalBoxFuture<'static, (W, Result<(), <W as PipeWriteHalf>::Error>)>;
+
+struct SendQueue<W: PipeWriteHalf> {
+    /// Taken by the message in flight.
+    tx: Option<W>,
+    in_flight: Option<InFlight<W>>,
+    queue: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+    config: QueueConfig,
+    stats: QueueStats,
+}
+impl<W> SendQueue<W>
+where
+    W: PipeWriteHalf + 'static,
+    W::Error: Into<StreamError>,
+{
+    async fn push(&mut self, data: &[u8]) -> StreamResult<()> {
+        match self.config.policy {
+            DropPolicy::Block => {
+                while !self.fits(data.len()) && self.busy() {
+                    self.progress().await?;
+                }
+            }
+            DropPolicy::DropNewest => {
+                if !self.fits(data.len()) && !self.queue.is_empty() {
+                    self.dropped(data.len());
+                    return Ok(());
+                }
+            }
+            DropPolicy::DropOldest => {
+                while !self.fits(data.len()) {
+                    let Some(oldest) = self.queue.pop_front() else {
+                        break;
+                    };
+                    self.queued_bytes -= oldest.len();
+                    self.dropped(oldest.len());
+                }
+            }
+        }
+
+        self.queued_bytes += data.len();
+        self.queue.push_back(data.to_owned());
+        self.start();
+
+        Ok(())
+    }
+
+    fn fits(&self, len: usize) -> bool {
+        self.queued_bytes + len <= self.config.max_bytes
+    }
+
+    fn busy(&self) -> bool {
+        self.in_flight.is_some() || !self.queue.is_empty()
+    }
+
+    fn dropped(&mut self, len: usize) {
+        self.stats.dropped_messages += 1;
+        self.stats.dropped_bytes += len as u64;
+    }
+
+    fn start(&mut self) {
+        if self.in_flight.is_some() {
+            return;
+        }
+        let Some(data) = self.queue.pop_front() else {
+            return;
+        };
+        self.queued_bytes -= data.len();
+
+        let mut tx = self.tx.take().expect("write half is back once idle");
+        self.in_flight = Some(
+            async move {
+                let r = tx.send(&data).await;
+                (tx, r)
+            }
+            .boxed_local(),
+        );
+    }
+
+    /// Completes once the message in flight is sent, never if there is none.
+    /// Cancelling it keeps the message in flight.
+    async fn progress(&mut self) -> StreamResult<()> {
+        self.start();
+        let (tx, r) = match &mut self.in_flight {
+            Some(in_flight) => in_flight.await,
+            None => pending().await,
+        };
+        self.in_flight = None;
+        self.tx = Some(tx);
+        self.start();
+
+        r.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::ready;
+    use std::time::{Duration, Instant};
+    use tokio::{sync::mpsc, time::sleep};
+
+    /// Link delivering a message every 20ms.
+    fn throttled() -> (Slow, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Slow(tx), rx)
+    }
+
+    struct Slow(mpsc::UnboundedSender<Vec<u8>>);
+    impl PipeWriteHalf for Slow {
+        type Error = StreamError;
+
+        fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, StreamResult<()>> {
+            async move {
+                sleep(Duration::from_millis(20)).await;
+                let _ = self.0.send(data.to_owned());
+                Ok(())
+            }
+            .boxed_local()
+        }
+
+        fn close(&mut self) -> LocalBoxFuture<'_, StreamResult<()>> {
+            ready(Ok(())).boxed_local()
+        }
+    }
+
+    /// Nothing is ever received.
+    struct Silent;
+    impl WaitThen for Silent {
+        type Value = ();
+        type Output = Option<Vec<u8>>;
+        type Error = StreamError;
+
+        fn wait(&mut self) -> LocalBoxFuture<'_, StreamResult<()>> {
+            pending().boxed_local()
+        }
+
+        fn then<'a>(&'a mut self, _: &'a mut ()) -> LocalBoxFuture<'a, StreamResult<Self::Output>> {
+            ready(Ok(None)).boxed_local()
+        }
+    }
+    impl PipeReadHalf for Silent {
+        fn rx_closed(&self) -> bool {
+            false
+        }
+    }
+
+    async fn saturate(policy: DropPolicy) -> (Vec<Vec<u8>>, QueueStats, Duration) {
+        let (tx, mut delivered) = throttled();
+        let config = QueueConfig {
+            max_bytes: 100,
+            policy,
+        };
+        let mut stream = QueuedStream::from_halves(Silent, tx, config);
+
+        let started = Instant::now();
+        for i in 0..10 {
+            stream.send(&[i; 50]).await.unwrap();
+        }
+        let elapsed = started.elapsed();
+        stream.close().await.unwrap();
+
+        let mut received = vec![];
+        while let Ok(data) = delivered.try_recv() {
+            received.push(data);
+        }
+        (received, stream.stats(), elapsed)
+    }
+
+    #[tokio::test]
+    async fn block() {
+        let (received, stats, elapsed) = saturate(DropPolicy::Block).await;
+
+        assert_eq!(received.len(), 10);
+        assert_eq!(stats, QueueStats::default());
+        // One in flight and two queued before the first send blocks
+        assert!(elapsed >= Duration::from_millis(7 * 20), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let (received, stats, _) = saturate(DropPolicy::DropNewest).await;
+
+        assert_eq!(received, [[0; 50], [1; 50], [2; 50]]);
+        assert_eq!(
+            stats,
+            QueueStats {
+                dropped_messages: 7,
+                dropped_bytes: 350,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let (received, stats, _) = saturate(DropPolicy::DropOldest).await;
+
+        assert_eq!(received, [[0; 50], [8; 50], [9; 50]]);
+        assert_eq!(stats.dropped_messages, 7);
+    }
+
+    #[tokio::test]
+    async fn receives_while_draining() {
+        let (tx, mut delivered) = throttled();
+        let mut stream = QueuedStream::from_halves(Silent, tx, QueueConfig::default());
+
+        for i in 0..5 {
+            stream.send(&[i; 10]).await.unwrap();
+        }
+        // Waiting drains the queue in the background of the pending receive
+        let _ = tokio::time::timeout(Duration::from_millis(200), stream.wait()).await;
+        assert_eq!(stream.queued_bytes(), 0);
+        assert_eq!(delivered.recv().await.unwrap(), [0; 10]);
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Instant;
+
+/// Token bucket parameters: up to `burst` messages at once, refilled at
+/// `per_second` messages per second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: u32,
+}
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            burst: 200,
+            per_second: 50,
+        }
+    }
+}
+
+pub struct RateLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Consumes one token, returns false if the rate limit was exceeded.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.per_second as f64)
+            .min(self.limit.burst as f64);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn burst_then_refill() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            burst: 3,
+            per_second: 2,
+        });
+        let start = limiter.last_refill;
+
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire_at(much_later));
+        }
+        assert!(!limiter.try_acquire_at(much_later));
+    }
+}
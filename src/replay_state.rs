@@ -0,0 +1,326 @@
+//! Sequence numbers of the ChaCha20 layer kept on disk, so a basekey used
+//! again after a restart, e.g. one handed to [`Chacha20Stream::new`] by the
+//! application, neither reuses nonces nor accepts messages of the earlier
+//! run. See [`ConnectOptions::state_dir`].
+//!
+//! Each session has a file per role, named after an identifier derived from
+//! the basekey. Sent sequence numbers are reserved [`SYNC_EVERY`] at a time
+//! and synced before use, so the sending side resumes past anything it may
+//! have sent. Received ones are synced every [`SYNC_EVERY`] messages and at
+//! close, a crash in between lets the peer replay what arrived since the
+//! last sync. Files are written on the blocking thread pool of the runtime. Files are authenticated with a key derived from the basekey,
+//! a corrupt or missing one is refused and a fresh key agreement is needed.
+//!
+//! [`Chacha20Stream::new`]: crate::crypto_stream::Chacha20Stream::new
+//! [`ConnectOptions::state_dir`]: crate::connect::ConnectOptions::state_dir
+
+use crate::{agreement::blocking, crypto_backend, logging, trace};
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Messages between syncs of each direction.
+pub const SYNC_EVERY: u64 = 256;
+
+const MAGIC: &[u8; 8] = b"icepipe1";
+const MAC_LEN: usize = 64;
+const FILE_LEN: usize = MAGIC.len() + 8 + 8 + MAC_LEN;
+
+/// Directory holding the state of the sessions.
+#[derive(Clone, Debug)]
+pub struct ReplayStore {
+    dir: PathBuf,
+}
+impl ReplayStore {
+    pub fn new(dir: impl Into<PathBuf>) -> ReplayStore {
+        ReplayStore { dir: dir.into() }
+    }
+
+    /// State of a session never started before, fails if `basekey` was.
+    pub fn fresh(&self, basekey: &[u8], dialer: bool) -> Result<ReplayState, ReplayStateError> {
+        let recorded = Recorded::new(&self.dir, basekey, dialer);
+        if recorded.path.try_exists()? {
+            return Err(ReplayStateError::Reused);
+        }
+        Ok(recorded.into())
+    }
+
+    /// State of a session started before, continuing from what was synced.
+    /// A missing state fails with [`ReplayStateError::Missing`], the session
+    /// cannot go on, only a new one under a new key with
+    /// [`ReplayStore::fresh`].
+    pub fn resume(&self, basekey: &[u8], dialer: bool) -> Result<ReplayState, ReplayStateError> {
+        let mut recorded = Recorded::new(&self.dir, basekey, dialer);
+        recorded.load()?;
+        Ok(recorded.into())
+    }
+}
+
+/// Sequence numbers of a session, shared by both halves of the stream.
+#[derive(Clone)]
+pub struct ReplayState(Arc<Shared>);
+
+struct Shared {
+    recorded: Mutex<Recorded>,
+    /// Held across a write, so that the file never goes back to older
+    /// numbers.
+    writing: tokio::sync::Mutex<()>,
+}
+impl From<Recorded> for ReplayState {
+    fn from(value: Recorded) -> Self {
+        ReplayState(Arc::new(Shared {
+            recorded: Mutex::new(value),
+            writing: tokio::sync::Mutex::new(()),
+        }))
+    }
+}
+impl ReplayState {
+    /// Sequence numbers to start sending and receiving at, and whether the
+    /// session was resumed.
+    pub(crate) fn start(&self) -> (u64, u64, bool) {
+        let recorded = self.0.recorded.lock().unwrap();
+        (recorded.sent, recorded.received, recorded.resumed)
+    }
+
+    /// Before sending `seq`, resolves once it is reserved on disk.
+    pub(crate) async fn reserve(&self, seq: u64) -> io::Result<()> {
+        self.write(|recorded| match seq >= recorded.sent {
+            true => Some(recorded.snapshot(seq + SYNC_EVERY)),
+            false => None,
+        })
+        .await
+    }
+
+    /// Whether sending `seq` was reserved by [`ReplayState::reserve`].
+    pub(crate) fn reserved(&self, seq: u64) -> bool {
+        seq < self.0.recorded.lock().unwrap().sent
+    }
+
+    /// After receiving up to `next`, exclusive, recorded on disk by the next
+    /// [`ReplayState::checkpoint`].
+    pub(crate) fn received(&self, next: u64) {
+        self.0.recorded.lock().unwrap().received = next;
+    }
+
+    /// Syncs once [`SYNC_EVERY`] messages were received since the last time.
+    pub(crate) async fn checkpoint(&self) -> io::Result<()> {
+        self.write(
+            |recorded| match recorded.received - recorded.synced >= SYNC_EVERY {
+                true => Some(recorded.snapshot(recorded.sent)),
+                false => None,
+            },
+        )
+        .await
+    }
+
+    pub(crate) async fn sync(&self) -> io::Result<()> {
+        self.write(|recorded| Some(recorded.snapshot(recorded.sent)))
+            .await
+    }
+
+    /// Writes the snapshot `prepare` takes, if any, and records it as synced
+    /// once written. A write dropped halfway is taken again by the next.
+    async fn write<F>(&self, prepare: F) -> io::Result<()>
+    where
+        F: FnOnce(&Recorded) -> Option<Snapshot>,
+    {
+        let _writing = self.0.writing.lock().await;
+        let Some(snapshot) = prepare(&self.0.recorded.lock().unwrap()) else {
+            return Ok(());
+        };
+        let (sent, received) = (snapshot.sent, snapshot.received);
+        blocking(move || snapshot.write()).await?;
+
+        let mut recorded = self.0.recorded.lock().unwrap();
+        recorded.sent = recorded.sent.max(sent);
+        recorded.synced = recorded.synced.max(received);
+        Ok(())
+    }
+}
+
+/// Contents of the file as of when it was taken.
+struct Snapshot {
+    path: PathBuf,
+    data: Vec<u8>,
+    sent: u64,
+    received: u64,
+}
+impl Snapshot {
+    /// Replaces the file as a whole, a crash leaves either version.
+    fn write(self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&self.data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        // The rename itself is only durable once the directory is synced
+        #[cfg(unix)]
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+struct Recorded {
+    path: PathBuf,
+    mac_key: [u8; 64],
+    dialer: bool,
+    /// Sent sequence numbers below are reserved.
+    sent: u64,
+    /// Received sequence numbers below are refused.
+    received: u64,
+    /// Received as of the last sync.
+    synced: u64,
+    resumed: bool,
+}
+impl Recorded {
+    fn new(dir: &Path, basekey: &[u8], dialer: bool) -> Recorded {
+        let mut id = [0; 16];
+        crypto_backend::hkdf_sha512(b"replay", basekey, b"session id", &mut id);
+        let mut name = id.iter().fold(String::new(), |mut name, byte| {
+            let _ = write!(name, "{byte:02x}");
+            name
+        });
+        name.push_str(match dialer {
+            true => "-dialer",
+            false => "-listener",
+        });
+        let mut mac_key = [0; 64];
+        crypto_backend::hkdf_sha512(b"replay", basekey, b"state", &mut mac_key);
+
+        Recorded {
+            path: dir.join(name),
+            mac_key,
+            dialer,
+            sent: 0,
+            received: 0,
+            synced: 0,
+            resumed: false,
+        }
+    }
+
+    /// With sent sequence numbers reserved below `sent`.
+    fn snapshot(&self, sent: u64) -> Snapshot {
+        Snapshot {
+            path: self.path.clone(),
+            data: self.encode(sent),
+            sent,
+            received: self.received,
+        }
+    }
+
+    fn encode(&self, sent: u64) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&sent.to_be_bytes());
+        data.extend_from_slice(&self.received.to_be_bytes());
+        let mac = crypto_backend::hmac_sha512_sign(&self.mac_key, &self.signed(&data));
+        data.extend_from_slice(&mac);
+        data
+    }
+
+    /// The role is signed too, so the files of both peers cannot be swapped.
+    fn signed(&self, data: &[u8]) -> Vec<u8> {
+        [data, &[self.dialer as u8]].concat()
+    }
+
+    fn load(&mut self) -> Result<(), ReplayStateError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(ReplayStateError::Missing),
+            Err(e) => return Err(e.into()),
+        };
+        if data.len() != FILE_LEN || !data.starts_with(MAGIC) {
+            return Err(ReplayStateError::Corrupt);
+        }
+        let (data, mac) = data.split_at(FILE_LEN - MAC_LEN);
+        crypto_backend::hmac_sha512_verify(&self.mac_key, &self.signed(data), mac)
+            .map_err(|_| ReplayStateError::Corrupt)?;
+
+        let number = |at: usize| u64::from_be_bytes(data[at..at + 8].try_into().unwrap());
+        self.sent = number(MAGIC.len());
+        self.received = number(MAGIC.len() + 8);
+        self.synced = self.received;
+        self.resumed = true;
+        trace::debug!(
+            target: logging::CRYPTO,
+            "Resuming at {} sent and {} received",
+            self.sent,
+            self.received
+        );
+        Ok(())
+    }
+}
+impl Drop for Recorded {
+    fn drop(&mut self) {
+        // Reserved numbers were synced already, only what was received since
+        // is left. Written in place, a drop cannot wait for the blocking pool
+        // and the state must be on disk before a restart reads it.
+        if self.received != self.synced {
+            if let Err(e) = self.snapshot(self.sent).write() {
+                trace::warn!(target: logging::CRYPTO, "Failed to sync replay state: {e}");
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReplayStateError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("No replay state recorded for the session, a fresh key agreement is needed")]
+    Missing,
+    #[error("Replay state of the session is corrupt, a fresh key agreement is needed")]
+    Corrupt,
+    #[error("Basekey already used by an earlier session, a fresh key agreement is needed")]
+    Reused,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_corrupt_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ReplayStore::new(dir.path());
+        let state = store.fresh(b"basekey", true).unwrap();
+        state.reserve(0).await.unwrap();
+        state.received(3);
+        drop(state);
+        assert_eq!(
+            store.resume(b"basekey", true).unwrap().start(),
+            (SYNC_EVERY, 3, true)
+        );
+        assert!(matches!(
+            store.resume(b"other basekey", true),
+            Err(ReplayStateError::Missing)
+        ));
+
+        // The files of both roles are told apart
+        let dialer = Recorded::new(dir.path(), b"basekey", true).path.clone();
+        let listener = Recorded::new(dir.path(), b"basekey", false).path.clone();
+        fs::copy(&dialer, &listener).unwrap();
+        assert!(matches!(
+            store.resume(b"basekey", false),
+            Err(ReplayStateError::Corrupt)
+        ));
+
+        let mut data = fs::read(&dialer).unwrap();
+        data[MAGIC.len()] ^= 1;
+        fs::write(&dialer, &data).unwrap();
+        assert!(matches!(
+            store.resume(b"basekey", true),
+            Err(ReplayStateError::Corrupt)
+        ));
+        fs::write(&dialer, MAGIC).unwrap();
+        assert!(matches!(
+            store.resume(b"basekey", true),
+            Err(ReplayStateError::Corrupt)
+        ));
+    }
+}
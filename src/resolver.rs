@@ -0,0 +1,105 @@
+//! Name resolution of the signalling server and of STUN and TURN servers,
+//! replaceable for resolvers the system does not know of, e.g. split DNS or
+//! DNS over HTTPS, see [`Resolver`].
+
+use futures::{future::BoxFuture, FutureExt};
+use std::{collections::HashMap, fmt, io, net::IpAddr, sync::Arc};
+
+/// Resolves host names to addresses, [`SystemResolver`] if none is given.
+/// Hosts that already are an IP address are not resolved.
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+}
+impl fmt::Debug for dyn Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+/// What the system is configured with, e.g. `/etc/resolv.conf`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        async move {
+            let addrs = tokio::net::lookup_host((host, 0)).await?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        }
+        .boxed()
+    }
+}
+
+/// Fixed addresses, hosts not added fail with
+/// [`io::ErrorKind::NotFound`].
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+}
+impl StaticResolver {
+    pub fn new() -> StaticResolver {
+        Default::default()
+    }
+
+    /// Adds `addr` to those `host` resolves to.
+    pub fn add(&mut self, host: impl Into<String>, addr: IpAddr) {
+        self.hosts.entry(host.into()).or_default().push(addr);
+    }
+}
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        let addrs =
+            self.hosts.get(host).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("Unknown host {host}"))
+            });
+        futures::future::ready(addrs).boxed()
+    }
+}
+
+/// Resolves `host` with `resolver`, [`SystemResolver`] if `None`, failing
+/// with [`io::ErrorKind::NotFound`] if it has no address.
+pub async fn resolve(resolver: Option<&Arc<dyn Resolver>>, host: &str) -> io::Result<Vec<IpAddr>> {
+    // Bracketed as in URLs for IPv6
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = literal.parse() {
+        return Ok(vec![ip]);
+    }
+
+    let addrs = match resolver {
+        Some(resolver) => resolver.resolve(host).await?,
+        None => SystemResolver.resolve(host).await?,
+    };
+    match addrs.is_empty() {
+        true => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No address for {host}"),
+        )),
+        false => Ok(addrs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn static_hosts() {
+        let mut hosts = StaticResolver::new();
+        hosts.add("relay.internal", Ipv4Addr::new(10, 0, 0, 1).into());
+        hosts.add("relay.internal", Ipv4Addr::new(10, 0, 0, 2).into());
+        let resolver: Arc<dyn Resolver> = Arc::new(hosts);
+
+        let addrs = resolve(Some(&resolver), "relay.internal").await.unwrap();
+        assert_eq!(addrs.len(), 2);
+        let e = resolve(Some(&resolver), "other.internal")
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        // Addresses are never looked up
+        let addrs = resolve(Some(&resolver), "[::1]").await.unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1])]);
+        let addrs = resolve(Some(&resolver), "127.0.0.1").await.unwrap();
+        assert_eq!(addrs, vec![IpAddr::from(Ipv4Addr::LOCALHOST)]);
+    }
+}
@@ -1,43 +1,272 @@
+#[cfg(feature = "full")]
+use crate::diagnostics::{ConnectTimings, DiagnosticsReport, SctpReport};
 use crate::{
-    error::TimeoutError,
-    pipe_stream::{Control, PipeStream, StreamError, WaitThen},
-    signalling::SignalingError,
+    deadline::Deadline,
+    delay_probe::{self, DelayProbe, OneWayDelay, MIN_PROBE_INTERVAL},
+    diagnostics::Diagnostics,
+    error::{ClosedDirty, TimeoutError},
+    events::{ConnectionEvent, Events},
+    ice::{self, CandidateCache, CandidatePairEntry},
+    logging,
+    metrics::{self, ActiveConnection},
+    mtu_probe::{MtuProbe, PathMtu, PROBE_TIMEOUT},
+    pipe_stream::{
+        CloseReason, ConnectionInfo, Control, PipeReadHalf, PipeStream, PipeWriteHalf,
+        ShutdownRequest, Split, StreamError, TransportKind, WaitThen,
+    },
+    signalling::{role_name, SignalingError},
+    strictness::{Strictness, Violation},
+    trace::{self, Instrument},
 };
 use bytes::Bytes;
 use futures::{
-    future::{ready, Either, LocalBoxFuture},
+    future::{pending, ready, Either, LocalBoxFuture},
     FutureExt,
 };
+use serde::Serialize;
 use std::{
+    collections::VecDeque,
+    future::Future,
     io,
     ops::Deref,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use tokio::{select, sync::watch, time::sleep};
-use webrtc_ice::state::ConnectionState;
+use tokio::{
+    select,
+    sync::watch,
+    time::{sleep, sleep_until},
+};
+use webrtc_ice::{agent::Agent, state::ConnectionState};
 use webrtc_sctp::{
     association::Association, chunk::chunk_payload_data::PayloadProtocolIdentifier, stream::Stream,
 };
 use webrtc_util::Conn;
 
+/// Largest message `send` accepts, received whole as well.
+pub const MAX_MESSAGE_SIZE: usize = 8 * 1024;
+/// Largest [`SctpConfig::receive_window`], and how far past what was sent a
+/// window advertised by the peer is taken at most.
+pub const MAX_RECEIVE_WINDOW: usize = 64 * 1024 * 1024;
+/// Largest UDP payload the association sends, fixed by webrtc-sctp. Messages
+/// are split into chunks that fit it whatever their size, so with 28 bytes
+/// of IPv4 and UDP headers, or 48 over IPv6, every packet crosses a 1280
+/// bytes MTU unfragmented. [`Sctp::probe_mtu`] warns about paths carrying
+/// less.
+pub const PACKET_SIZE: usize = 1228;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SctpConfig {
+    /// Extra wait on close after the send buffer drained, before resetting
+    /// the stream. The buffer only tracks data handed to the association, so
+    /// this gives the last packets time to reach the peer, and the peer time
+    /// to deliver them, before the reset tears the stream down on both ends.
+    /// Lowering it makes shutdown faster at the risk of truncating the tail
+    /// of the transfer on lossy links.
+    pub close_linger: Duration,
+    /// Bytes handed to the association but not acknowledged yet past which
+    /// `send` blocks. Lower values apply back pressure earlier, keeping the
+    /// queue and so the latency short, at the cost of throughput on links
+    /// with a large bandwidth-delay product.
+    pub max_buffered_amount: usize,
+    /// How often a `send` blocked on
+    /// [`max_buffered_amount`](SctpConfig::max_buffered_amount) checks whether
+    /// the buffer drained.
+    pub backpressure_poll: Duration,
+    /// Bytes buffered past which [`Control::writable`] waits, see
+    /// [`max_buffered_amount`](SctpConfig::max_buffered_amount).
+    /// [`forward`](crate::service::forward) only reads from the other stream
+    /// under it, bounding what a forwarded stream holds while the peer is
+    /// slow. Capped at `max_buffered_amount`.
+    pub writable_threshold: usize,
+    /// Bound on closing, past which the association and the ICE agent are
+    /// torn down without waiting for the peer and close fails with
+    /// [`ClosedDirty`]. Closing drains what is buffered first, which never
+    /// ends if the path died right before. Unbounded if `None`.
+    pub close_timeout: Option<Duration>,
+    /// Sends a timestamped probe this often, answered by the peer, to
+    /// estimate the delay in each direction, see
+    /// [`SctpStats::one_way_delay`]. Probes are only sent while the stream
+    /// is waited on, and they are not encrypted nor authenticated with
+    /// [`Encryption::Chacha20`](crate::connect::Encryption::Chacha20).
+    /// Disabled if `None`, at least [`MIN_PROBE_INTERVAL`] apart otherwise.
+    pub delay_probe_interval: Option<Duration>,
+    /// Holds sends until the peer application tells it is ready to
+    /// receive, with [`Sctp::ready`], so nothing sent early reaches a peer
+    /// that is not handling it yet. Held sends only block once
+    /// [`max_buffered_amount`](SctpConfig::max_buffered_amount) is held.
+    /// Peers without it tell they are ready as soon as they are connected,
+    /// versions predating it never do.
+    pub ready_barrier: bool,
+    /// Bytes this side accepts ahead of the application reading them,
+    /// advertised to the peer, whose `send` then waits for the application
+    /// to read instead of filling the association's receive buffer. The
+    /// window of the peer is enforced whenever it advertised one, whatever
+    /// this is set to. Between [`MAX_MESSAGE_SIZE`] and
+    /// [`MAX_RECEIVE_WINDOW`], unlimited if `None`.
+    pub receive_window: Option<usize>,
+    /// Tells the peer how many bytes the application read, for it to know
+    /// how far behind this side is, see [`SctpStats::remote_lag`]. Only
+    /// sent while the stream is waited on. Disabled if `None`.
+    pub delivery_reports: Option<DeliveryReports>,
+    /// How long ICE may stay disconnected, e.g. over a second of packet
+    /// loss, before the stream ends as if the transport went away. Sends are
+    /// buffered meanwhile, up to
+    /// [`max_buffered_amount`](SctpConfig::max_buffered_amount), and
+    /// [`ConnectionEvent::Degraded`] and [`ConnectionEvent::Recovered`] are
+    /// emitted. ICE failing or closing ends the stream at once, zero ends it
+    /// on disconnection too. Only noticed while the stream is waited on.
+    pub disconnect_grace: Duration,
+}
+impl Default for SctpConfig {
+    fn default() -> Self {
+        SctpConfig {
+            close_linger: Duration::from_millis(100),
+            max_buffered_amount: 4 * 1024 * 1024,
+            backpressure_poll: Duration::from_millis(100),
+            writable_threshold: 1024 * 1024,
+            close_timeout: None,
+            delay_probe_interval: None,
+            ready_barrier: false,
+            receive_window: None,
+            delivery_reports: Some(DeliveryReports::default()),
+            disconnect_grace: Duration::from_secs(10),
+        }
+    }
+}
+
+/// When the receiver tells the sender what it read, see
+/// [`SctpConfig::delivery_reports`]. A report is due once `every` bytes
+/// were read since the last one, or `interval` after the first byte left
+/// unreported, and reports are at least [`MIN_REPORT_INTERVAL`] apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeliveryReports {
+    pub every: usize,
+    pub interval: Duration,
+}
+impl Default for DeliveryReports {
+    fn default() -> Self {
+        DeliveryReports {
+            every: 256 * 1024,
+            interval: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Least time between two [`DeliveryReports`], however fast the
+/// application reads.
+pub const MIN_REPORT_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct SctpStats {
+    /// Probes included, unlike the [`metrics`](crate::metrics) byte counters.
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    /// Bytes handed to the association but not acknowledged yet.
+    pub buffered_amount: usize,
+    pub max_message_size: u32,
+    /// `None` until a probe is answered, see
+    /// [`SctpConfig::delay_probe_interval`].
+    pub one_way_delay: Option<OneWayDelay>,
+    /// `None` until probed, see [`Sctp::probe_mtu`].
+    pub path_mtu: Option<PathMtu>,
+    /// Bytes the peer still accepts before `send` waits, `None` until the
+    /// peer advertises a window, see [`SctpConfig::receive_window`].
+    pub send_window: Option<usize>,
+    /// Bytes the peer may still send before the application reads more,
+    /// `None` without [`SctpConfig::receive_window`].
+    pub receive_window: Option<usize>,
+    /// Bytes the transport delivered to the peer that its application did
+    /// not read yet, as of its last report. `None` until the peer reports,
+    /// which versions without [`SctpConfig::delivery_reports`] never do.
+    pub remote_lag: Option<usize>,
+}
+
+/// Pauses receiving of a stream, clones control the same stream. While
+/// paused `wait` stops reading, the association's receive buffer fills up
+/// and SCTP flow control makes the peer's `send` block. Sends waiting on
+/// the peer still read up to
+/// [`max_buffered_amount`](SctpConfig::max_buffered_amount) ahead, see
+/// [`PipeWriteHalf::send`].
+#[derive(Clone, Debug)]
+pub struct PauseHandle(Arc<watch::Sender<bool>>);
+impl PauseHandle {
+    fn new() -> PauseHandle {
+        PauseHandle(Arc::new(watch::channel(false).0))
+    }
+
+    pub fn pause(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.0.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.0.borrow()
+    }
+}
+
 pub struct Sctp {
-    _association: Association,
-    stream: Arc<Stream>,
-    buf: Vec<u8>,
-    connection: watch::Receiver<ConnectionState>,
-    rx_closed: bool,
+    rx: SctpReadHalf,
+    tx: SctpWriteHalf,
+    candidate_cache: Option<CandidateCache>,
+    config: SctpConfig,
+    diagnostics: Diagnostics,
+    events: Events,
 }
 impl Sctp {
+    /// The association takes ownership of `agent` and closes it along with
+    /// itself once both halves are dropped.
     pub async fn new(
+        net_conn: Arc<dyn Conn + Send + Sync>,
+        agent: Arc<Agent>,
+        dialer: bool,
+        connection: watch::Receiver<ConnectionState>,
+        sctp_config: SctpConfig,
+    ) -> SctpResult<Self> {
+        Sctp::assemble(
+            net_conn,
+            Carrier::Ice(agent),
+            dialer,
+            connection,
+            sctp_config,
+        )
+        .await
+    }
+
+    /// Runs straight over `net_conn`, which must already reach the peer,
+    /// e.g. a connected UDP socket. The connection state stays
+    /// [`ConnectionState::Connected`], only SCTP tells when the peer is gone.
+    pub async fn over(
         net_conn: Arc<dyn Conn + Send + Sync>,
         dialer: bool,
+        sctp_config: SctpConfig,
+    ) -> SctpResult<Self> {
+        let (state, connection) = watch::channel(ConnectionState::Connected);
+        Sctp::assemble(
+            net_conn,
+            Carrier::Direct { _state: state },
+            dialer,
+            connection,
+            sctp_config,
+        )
+        .await
+    }
+
+    async fn assemble(
+        net_conn: Arc<dyn Conn + Send + Sync>,
+        carrier: Carrier,
+        dialer: bool,
         connection: watch::Receiver<ConnectionState>,
+        sctp_config: SctpConfig,
     ) -> SctpResult<Self> {
+        let (net_conn, mtu) = MtuProbe::wrap(net_conn);
         let config = webrtc_sctp::association::Config {
             net_conn,
             max_receive_buffer_size: 4 * 1024 * 1024,
-            max_message_size: 8 * 1024,
+            max_message_size: MAX_MESSAGE_SIZE as u32,
             name: "IcePipe".to_string(),
         };
 
@@ -62,20 +291,887 @@ impl Sctp {
             &Bytes::from_static(b"\0"),
             PayloadProtocolIdentifier::StringEmpty,
         )?;
-        log::info!("Stream Connected");
+        trace::info!(target: logging::SCTP, "Stream Connected");
 
-        let buf = Vec::new();
+        let role = role_name(dialer);
+        let window = FlowWindow::new(sctp_config.receive_window);
+        if let Some(limit) = window.advertised {
+            stream_data.write_sctp(&window_frame(limit), CONTROL)?;
+            metrics::receive_window(role, window.receive_window().unwrap_or_default());
+        }
+        let association = Arc::new(SctpAssociation {
+            association: Some(association),
+            carrier,
+            close_reason: Mutex::new(None),
+            delay: Mutex::new(DelayProbe::new()),
+            mtu,
+            ready: Mutex::new(ReadyBarrier::default()),
+            peer_ready: watch::channel(false).0,
+            window: Mutex::new(window),
+            peer_window: watch::channel(None).0,
+            reader: tokio::sync::Mutex::new(()),
+            stash: Mutex::new(Stash::default()),
+            shutdown: Mutex::new(None),
+            _active: ActiveConnection::new(role, metrics::TRANSPORT_SCTP),
+        });
 
+        let pause = PauseHandle::new();
+        let info = Arc::new(ConnectionInfo {
+            dialer,
+            channel_id: String::new(),
+            transport: TransportKind::Sctp,
+            peer_public_key: None,
+            peer_payload: None,
+        });
         Ok(Sctp {
-            _association: association,
-            stream: stream_data,
-            buf,
-            connection,
-            rx_closed: false,
+            rx: SctpReadHalf {
+                association: association.clone(),
+                info: info.clone(),
+                paused: pause.0.subscribe(),
+                pause,
+                diagnostics: Diagnostics::default(),
+                events: Events::default(),
+                strictness: Strictness::default(),
+                stream: stream_data.clone(),
+                buf: Vec::new(),
+                connection,
+                stream_eof: false,
+                role,
+                deadline: Deadline::NEVER,
+                next_probe: sctp_config
+                    .delay_probe_interval
+                    .map(|interval| tokio::time::Instant::now() + interval.max(MIN_PROBE_INTERVAL)),
+                probe_interval: sctp_config.delay_probe_interval,
+                reports: sctp_config.delivery_reports,
+                last_report: None,
+                next_report: None,
+                grace: sctp_config.disconnect_grace,
+                degraded: None,
+            },
+            tx: SctpWriteHalf {
+                association,
+                info,
+                stream: stream_data,
+                buf: vec![0; MAX_MESSAGE_SIZE],
+                span: trace::Span::current(),
+                role,
+                config: sctp_config,
+                deadline: Deadline::NEVER,
+            },
+            candidate_cache: None,
+            config: sctp_config,
+            diagnostics: Diagnostics::default(),
+            events: Events::default(),
         })
     }
+}
+impl Sctp {
+    /// Every subsequent await of both halves fails with [`TimeoutError`] once
+    /// `deadline` passes.
+    pub fn set_deadline(&mut self, deadline: Deadline) {
+        self.rx.set_deadline(deadline);
+        self.tx.set_deadline(deadline);
+    }
+
+    pub fn set_candidate_cache(&mut self, candidate_cache: Option<CandidateCache>) {
+        self.candidate_cache = candidate_cache;
+    }
+
+    /// ICE candidates exchanged for this connection, see [`CandidateCache`].
+    pub fn candidate_cache(&self) -> Option<&CandidateCache> {
+        self.candidate_cache.as_ref()
+    }
+
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.rx.pause_handle()
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn watchdog(&self) -> Watchdog {
+        self.tx.watchdog()
+    }
+
+    /// Shared with both halves once split.
+    pub fn info(&self) -> &Arc<ConnectionInfo> {
+        &self.rx.info
+    }
+
+    /// Replaces what [`Sctp::new`] knows with what the caller assembling
+    /// the stack does.
+    pub fn set_info(&mut self, info: ConnectionInfo) {
+        let info = Arc::new(info);
+        self.rx.info = info.clone();
+        self.tx.info = info;
+    }
+
+    /// ICE connection state of the transport.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.rx.connection.clone()
+    }
+
+    /// See [`SctpReadHalf::degraded`].
+    pub fn degraded(&self) -> bool {
+        self.rx.degraded()
+    }
+
+    /// Resolves once ICE is connected, the SCTP handshake is done by the time
+    /// this exists. Fails with [`EstablishError::Connection`] if ICE fails or
+    /// closes first, or [`EstablishError::Timeout`] once the deadline passes.
+    pub fn wait_established(&self) -> LocalBoxFuture<'static, Result<(), EstablishError>> {
+        let mut connection = self.connection_state();
+        let deadline = self.rx.deadline;
+        async move {
+            deadline
+                .run(async {
+                    loop {
+                        match *connection.borrow_and_update() {
+                            ConnectionState::Connected => return Ok(()),
+                            ConnectionState::Completed => return Ok(()),
+                            state @ (ConnectionState::Failed | ConnectionState::Closed) => {
+                                return Err(EstablishError::Connection(state))
+                            }
+                            _ => (),
+                        }
+                        // The agent is gone along with the sender
+                        connection
+                            .changed()
+                            .await
+                            .map_err(|_| EstablishError::Connection(ConnectionState::Closed))?;
+                    }
+                })
+                .await
+        }
+        .boxed_local()
+    }
+
+    /// Applies [`SctpConfig::ready_barrier`] once done with what is
+    /// exchanged while connecting, [`connect`](crate::connect) does it.
+    /// Sends are held from then on until the peer is ready or, without the
+    /// barrier, the peer is told this side is ready right away.
+    pub fn start_ready_barrier(&self) -> SctpResult<()> {
+        match self.config.ready_barrier {
+            true => {
+                self.tx.association.ready.lock().unwrap().armed = true;
+                Ok(())
+            }
+            false => self.ready(),
+        }
+    }
+
+    /// Tells the peer this application is ready to receive, releasing what
+    /// it held with [`SctpConfig::ready_barrier`].
+    pub fn ready(&self) -> SctpResult<()> {
+        self.tx.ready()
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Diagnostics) {
+        self.rx.diagnostics = diagnostics.clone();
+        self.diagnostics = diagnostics;
+    }
+
+    pub fn set_events(&mut self, events: Events) {
+        self.rx.events = events.clone();
+        self.events = events;
+    }
+
+    /// Under [`Strictness::Strict`] messages with a payload protocol
+    /// identifier other than binary or string fail the read half, they are
+    /// ignored otherwise.
+    pub fn set_strictness(&mut self, strictness: Strictness) {
+        self.rx.strictness = strictness;
+    }
+
+    /// See [`SctpReadHalf::stream_eof`].
+    pub fn stream_eof(&self) -> bool {
+        self.rx.stream_eof()
+    }
+
+    /// See [`SctpReadHalf::association_closed`].
+    pub fn association_closed(&self) -> bool {
+        self.rx.association_closed()
+    }
+
+    /// Bus the layers of the connection emit to.
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// What was recorded while connecting, along with the current state of
+    /// the association. Candidate addresses are redacted unless `full`.
+    pub fn stats(&self) -> SctpStats {
+        self.tx.stats()
+    }
+
+    /// Largest datagram the path carries to the peer, also kept in
+    /// [`SctpStats::path_mtu`]. Probes are not encrypted, and take a few
+    /// seconds once one is lost. Relayed pairs are not probed, see
+    /// [`mtu_probe`](crate::mtu_probe).
+    pub async fn probe_mtu(&self) -> PathMtu {
+        let socket = match &self.tx.association.carrier {
+            Carrier::Ice(agent) => ice::selected_socket(agent),
+            Carrier::Direct { .. } => self.tx.association.mtu.local_addr(),
+        };
+        let mtu = self.tx.association.mtu.probe(socket, PROBE_TIMEOUT).await;
+        trace::debug!(target: logging::SCTP, "Path MTU probed: {mtu:?}");
+        if let (Some(false), Some(max_payload)) = (mtu.carries(PACKET_SIZE), mtu.max_payload) {
+            let event = ConnectionEvent::PathFragments { max_payload };
+            trace::warn!(target: logging::SCTP, "{event}");
+            self.diagnostics.warning(event.to_string());
+            self.events.emit(event);
+        }
+        mtu
+    }
+
+    /// Pairs checked by ICE, none when running over a direct transport.
+    pub async fn candidate_pairs(&self) -> Vec<CandidatePairEntry> {
+        match &self.tx.association.carrier {
+            Carrier::Ice(agent) => ice::candidate_pairs(agent).await,
+            Carrier::Direct { .. } => Vec::new(),
+        }
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn timings(&self) -> ConnectTimings {
+        self.diagnostics.timings()
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn diagnostics(&self, cipher: &'static str, full: bool) -> DiagnosticsReport {
+        let sctp = SctpReport {
+            close_linger_ms: self.config.close_linger.as_millis() as u64,
+            stats: self.stats(),
+        };
+        let mut report = self.diagnostics.report(cipher, sctp);
+        if !full {
+            report.session.redact();
+        }
+
+        report
+    }
+}
+impl PipeStream for Sctp {
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, SctpResult<()>> {
+        self.tx.send(data)
+    }
+}
+impl WaitThen for Sctp {
+    type Value = SctpValue;
+    type Output = Option<Vec<u8>>;
+    type Error = SctpError;
+
+    fn wait(&mut self) -> LocalBoxFuture<'_, SctpResult<Self::Value>> {
+        self.rx.wait()
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, SctpResult<Self::Output>> {
+        self.rx.then(value)
+    }
+}
+impl Control for Sctp {
+    fn close(&mut self) -> LocalBoxFuture<'_, SctpResult<()>> {
+        self.tx.close()
+    }
+
+    fn rx_closed(&self) -> bool {
+        PipeReadHalf::rx_closed(&self.rx)
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        PipeReadHalf::close_reason(&self.rx)
+    }
+
+    fn notify<'a>(&'a mut self, notice: &'a str) -> LocalBoxFuture<'a, SctpResult<()>> {
+        self.tx.notify(notice)
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        Some(self.info().clone())
+    }
+
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        self.tx.writable()
+    }
+
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, SctpResult<()>> {
+        self.tx.request_shutdown(deadline)
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        self.rx.shutdown_request()
+    }
+}
+impl Split for Sctp {
+    type ReadHalf = SctpReadHalf;
+    type WriteHalf = SctpWriteHalf;
+
+    fn split(self) -> (SctpReadHalf, SctpWriteHalf) {
+        (self.rx, self.tx)
+    }
+}
+
+/// What the association runs over, closed along with it.
+enum Carrier {
+    Ice(Arc<Agent>),
+    /// Holds the sender of a connection state that never changes.
+    Direct {
+        _state: watch::Sender<ConnectionState>,
+    },
+}
+
+struct SctpAssociation {
+    association: Option<Association>,
+    carrier: Carrier,
+    /// Shared by both halves, so the read half also tells when this side
+    /// closed first.
+    close_reason: Mutex<Option<CloseReason>>,
+    /// Requests are sent by the read half, which also records replies.
+    delay: Mutex<DelayProbe>,
+    mtu: MtuProbe,
+    ready: Mutex<ReadyBarrier>,
+    peer_ready: watch::Sender<bool>,
+    window: Mutex<FlowWindow>,
+    /// Bytes the peer accepts in total, as last advertised.
+    peer_window: watch::Sender<Option<u64>>,
+    /// Held by whichever half reads the stream.
+    reader: tokio::sync::Mutex<()>,
+    /// Read by the write half, for the read half, see
+    /// [`SctpAssociation::read_aside`].
+    stash: Mutex<Stash>,
+    /// The first shutdown requested, by either side.
+    shutdown: Mutex<Option<ShutdownRequest>>,
+    _active: ActiveConnection,
+}
+impl SctpAssociation {
+    /// Takes `data` to be sent once the peer is ready, if the barrier is up.
+    fn hold(&self, data: &[u8]) -> bool {
+        let mut ready = self.ready.lock().unwrap();
+        if !ready.armed || *self.peer_ready.borrow() {
+            return false;
+        }
+        ready.held_bytes += data.len();
+        ready.held.push_back(Bytes::copy_from_slice(data));
+        true
+    }
+
+    fn held_bytes(&self) -> usize {
+        self.ready.lock().unwrap().held_bytes
+    }
+
+    /// Sends what was held, in order, before any later send goes through.
+    fn peer_ready(&self, stream: &Stream, role: &'static str) -> SctpResult<()> {
+        let mut ready = self.ready.lock().unwrap();
+        self.peer_ready.send_replace(true);
+        trace::debug!(
+            target: logging::SCTP,
+            "Peer ready, releasing {} held bytes",
+            ready.held_bytes
+        );
+        while let Some(data) = ready.held.pop_front() {
+            ready.held_bytes -= data.len();
+            // Past the window if the peer advertised less than was held
+            self.window.lock().unwrap().sent += data.len() as u64;
+            metrics::bytes_sent(role, metrics::TRANSPORT_SCTP, data.len());
+            stream.write_sctp(&data, PayloadProtocolIdentifier::Binary)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes that may still be sent, `None` if the peer advertised no
+    /// window.
+    fn send_window(&self) -> Option<usize> {
+        let limit = (*self.peer_window.borrow())?;
+        let sent = self.window.lock().unwrap().sent;
+        Some(limit.saturating_sub(sent) as usize)
+    }
+
+    /// Waits for the peer to accept `len` more bytes and counts them as
+    /// sent, reading the stream meanwhile for the window to grow, see
+    /// [`SctpAssociation::read_aside`].
+    async fn reserve(&self, len: usize, mut reader: Reader<'_>) -> SctpResult<()> {
+        let mut peer_window = self.peer_window.subscribe();
+        loop {
+            if let Some(window) = self.send_window() {
+                metrics::send_window(reader.role, window);
+                if window < len {
+                    trace::debug!(
+                        target: logging::SCTP,
+                        "Send blocked, {window} bytes left in the peer window"
+                    );
+                    select! {
+                        // The association holds the sender
+                        _ = peer_window.changed() => {}
+                        r = self.read_aside(&mut reader) => r?,
+                    }
+                    continue;
+                }
+            }
+            self.window.lock().unwrap().sent += len as u64;
+            return Ok(());
+        }
+    }
+
+    /// Takes the window of the peer, at most [`MAX_RECEIVE_WINDOW`] past what
+    /// was sent, as window frames are not authenticated.
+    fn peer_window(&self, limit: u64) {
+        let sent = self.window.lock().unwrap().sent;
+        let limit = limit.min(sent.saturating_add(MAX_RECEIVE_WINDOW as u64));
+        self.peer_window.send_if_modified(|current| {
+            let grown = current.is_none_or(|current| limit > current);
+            if grown {
+                *current = Some(limit);
+            }
+            grown
+        });
+    }
+
+    /// Handled by whichever half read it, never handed out.
+    fn control_received(
+        &self,
+        frame: &[u8],
+        stream: &Stream,
+        role: &'static str,
+    ) -> SctpResult<()> {
+        if frame == READY {
+            return self.peer_ready(stream, role);
+        }
+        if frame.len() == WINDOW_LEN && frame.starts_with(WINDOW) {
+            let limit = u64::from_be_bytes(frame[WINDOW.len()..].try_into().unwrap());
+            self.peer_window(limit);
+            return Ok(());
+        }
+        if frame.len() == REPORT_LEN && frame.starts_with(REPORT) {
+            let consumed = u64::from_be_bytes(frame[REPORT.len()..].try_into().unwrap());
+            let mut window = self.window.lock().unwrap();
+            window.peer_consumed(consumed);
+            let lag = window.remote_lag(stream.buffered_amount());
+            metrics::remote_lag(role, lag.unwrap_or_default());
+            return Ok(());
+        }
+        let reply = self
+            .delay
+            .lock()
+            .unwrap()
+            .received(frame, delay_probe::now_us());
+        if let Some(reply) = reply {
+            stream.write_sctp(&reply.into(), CONTROL)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next message for the read half, stashed ones first.
+    async fn read(
+        &self,
+        stream: &Stream,
+        buf: &mut [u8],
+    ) -> SctpResult<(usize, PayloadProtocolIdentifier)> {
+        let _reader = self.reader.lock().await;
+        if let Some((data, protocol_id)) = self.stash.lock().unwrap().pop() {
+            buf[..data.len()].copy_from_slice(&data);
+            return Ok((data.len(), protocol_id));
+        }
+        Ok(stream.read_sctp(buf).await?)
+    }
+
+    /// Reads a message while a send waits on the peer, whose window or ready
+    /// would otherwise only be seen once the read half is waited on. Control
+    /// frames are handled, anything else is stashed for the read half.
+    /// Pending once `limit` bytes are stashed or the stream ended. Cancel
+    /// safe, like [`Stream::read_sctp`].
+    async fn read_aside(&self, reader: &mut Reader<'_>) -> SctpResult<()> {
+        if self.stash.lock().unwrap().full(reader.limit) {
+            return pending().await;
+        }
+        let _reader = self.reader.lock().await;
+        let (n, protocol_id) = reader.stream.read_sctp(reader.buf).await?;
+        let frame = &reader.buf[0..n];
+        if protocol_id == CONTROL && !is_shutdown(frame) {
+            return self.control_received(frame, reader.stream, reader.role);
+        }
+        self.stash.lock().unwrap().push(frame.to_vec(), protocol_id);
+        Ok(())
+    }
+
+    /// Handles what already arrived without waiting, see
+    /// [`SctpAssociation::read_aside`].
+    fn read_ready(&self, mut reader: Reader<'_>) -> SctpResult<()> {
+        while let Some(r) = self.read_aside(&mut reader).now_or_never() {
+            r?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the first reason.
+    fn closed(&self, reason: CloseReason) {
+        self.close_reason.lock().unwrap().get_or_insert(reason);
+    }
+
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason.lock().unwrap().clone()
+    }
+
+    /// Keeps the first request.
+    fn shutdown_requested(&self, request: ShutdownRequest) -> bool {
+        let mut shutdown = self.shutdown.lock().unwrap();
+        if shutdown.is_some() {
+            return false;
+        }
+        *shutdown = Some(request);
+        true
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        *self.shutdown.lock().unwrap()
+    }
+
+    fn failed(&self, e: &SctpError) {
+        self.closed(match e {
+            SctpError::Timeout(_) => CloseReason::Timeout,
+            e => CloseReason::transport_failed(e.to_string()),
+        });
+    }
+
+    /// Closes the association and the ICE agent in the background, instead
+    /// of once both halves are dropped.
+    fn abort(self: &Arc<Self>) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            if let Some(association) = &this.association {
+                let _ = association.close().await;
+            }
+            if let Carrier::Ice(agent) = &this.carrier {
+                let _ = agent.close().await;
+            }
+        });
+    }
+}
+
+/// Enforces [`SctpConfig::close_timeout`] on a close of a stream over the
+/// association.
+pub(crate) struct Watchdog {
+    timeout: Option<Duration>,
+    association: Arc<SctpAssociation>,
+}
+impl Watchdog {
+    pub(crate) async fn guard<F, E>(self, close: F) -> Result<(), E>
+    where
+        F: Future<Output = Result<(), E>>,
+        E: From<ClosedDirty>,
+    {
+        let Some(timeout) = self.timeout else {
+            return close.await;
+        };
+        match tokio::time::timeout(timeout, close).await {
+            Ok(r) => r,
+            Err(_) => {
+                trace::warn!(
+                    target: logging::SCTP,
+                    "Close stuck for {timeout:?}, dropping the association"
+                );
+                self.association.closed(CloseReason::LocalClose);
+                self.association.abort();
+                Err(ClosedDirty { after: timeout }.into())
+            }
+        }
+    }
+}
+impl Drop for SctpAssociation {
+    fn drop(&mut self) {
+        // Neither of them release their sockets and background tasks on drop
+        let association = self.association.take();
+        let agent = match &self.carrier {
+            Carrier::Ice(agent) => Some(agent.clone()),
+            Carrier::Direct { .. } => None,
+        };
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Some(association) = association {
+                    let _ = association.close().await;
+                }
+                if let Some(agent) = agent {
+                    let _ = agent.close().await;
+                }
+            });
+        }
+    }
+}
+
+/// Byte counts behind [`SctpConfig::receive_window`] and
+/// [`SctpConfig::delivery_reports`]. Windows are advertised and reads
+/// reported as totals, so neither depends on an earlier one.
+#[derive(Debug)]
+struct FlowWindow {
+    size: Option<u64>,
+    /// Read by the application.
+    consumed: u64,
+    advertised: Option<u64>,
+    reported: u64,
+    sent: u64,
+    /// Read by the peer application, as last reported.
+    peer_consumed: Option<u64>,
+}
+impl FlowWindow {
+    fn new(size: Option<usize>) -> FlowWindow {
+        let size = size.map(|size| size.clamp(MAX_MESSAGE_SIZE, MAX_RECEIVE_WINDOW) as u64);
+        FlowWindow {
+            size,
+            consumed: 0,
+            advertised: size,
+            reported: 0,
+            sent: 0,
+            peer_consumed: None,
+        }
+    }
+
+    /// Counts `n` bytes read, returning the window to advertise once half
+    /// of the last one was used.
+    fn consumed(&mut self, n: usize) -> Option<u64> {
+        self.consumed += n as u64;
+        let (size, advertised) = (self.size?, self.advertised?);
+        if advertised.saturating_sub(self.consumed) > size / 2 {
+            return None;
+        }
+        let limit = self.consumed + size;
+        self.advertised = Some(limit);
+        Some(limit)
+    }
+
+    fn receive_window(&self) -> Option<usize> {
+        Some(self.advertised?.saturating_sub(self.consumed) as usize)
+    }
+
+    /// Reports arrive in order, but a stale one never lowers the count.
+    fn peer_consumed(&mut self, total: u64) {
+        self.peer_consumed = Some(self.peer_consumed.map_or(total, |c| c.max(total)));
+    }
+
+    /// Sent past what the transport still buffers and the peer read.
+    fn remote_lag(&self, buffered: usize) -> Option<usize> {
+        let unread = self.sent.saturating_sub(self.peer_consumed?);
+        Some(unread.saturating_sub(buffered as u64) as usize)
+    }
+}
+
+/// What a send reads the stream with, see
+/// [`SctpAssociation::read_aside`].
+struct Reader<'a> {
+    stream: &'a Stream,
+    buf: &'a mut [u8],
+    role: &'static str,
+    /// Bytes stashed at most.
+    limit: usize,
+}
+impl Reader<'_> {
+    fn by_ref(&mut self) -> Reader<'_> {
+        Reader {
+            stream: self.stream,
+            buf: self.buf,
+            role: self.role,
+            limit: self.limit,
+        }
+    }
+}
+
+/// Messages read by the write half, handed to the read half first.
+#[derive(Default)]
+struct Stash {
+    messages: VecDeque<(Vec<u8>, PayloadProtocolIdentifier)>,
+    bytes: usize,
+    /// The peer reset the stream, nothing follows.
+    ended: bool,
+}
+impl Stash {
+    fn push(&mut self, data: Vec<u8>, protocol_id: PayloadProtocolIdentifier) {
+        self.ended |= data.is_empty();
+        self.bytes += data.len();
+        self.messages.push_back((data, protocol_id));
+    }
+
+    fn pop(&mut self) -> Option<(Vec<u8>, PayloadProtocolIdentifier)> {
+        let (data, protocol_id) = self.messages.pop_front()?;
+        self.bytes -= data.len();
+        Some((data, protocol_id))
+    }
+
+    fn full(&self, limit: usize) -> bool {
+        self.ended || self.bytes >= limit
+    }
+}
+
+/// Sends held back by [`SctpConfig::ready_barrier`].
+#[derive(Default)]
+struct ReadyBarrier {
+    armed: bool,
+    held: VecDeque<Bytes>,
+    held_bytes: usize,
+}
+
+pub type SctpValue = Either<ConnectionState, (usize, PayloadProtocolIdentifier)>;
+
+/// Control frames, either a [`delay_probe`], [`READY`], [`SHUTDOWN`],
+/// [`WINDOW`] or [`REPORT`].
+/// Ignored by versions without them, like every identifier but
+/// [`Binary`](PayloadProtocolIdentifier::Binary) and
+/// [`String`](PayloadProtocolIdentifier::String).
+const CONTROL: PayloadProtocolIdentifier = PayloadProtocolIdentifier::BinaryEmpty;
+/// Control frame telling the peer application is ready, see
+/// [`SctpConfig::ready_barrier`]. Too short to be taken for a probe.
+const READY: &[u8] = b"R";
+/// Control frame asking the peer to close, followed by the deadline in
+/// milliseconds, see [`ShutdownRequest`].
+const SHUTDOWN: &[u8] = b"S";
+const SHUTDOWN_LEN: usize = SHUTDOWN.len() + 8;
+
+fn is_shutdown(frame: &[u8]) -> bool {
+    frame.len() == SHUTDOWN_LEN && frame.starts_with(SHUTDOWN)
+}
+
+/// Control frame advertising the receive window, followed by the total
+/// bytes the peer may send, see [`SctpConfig::receive_window`].
+const WINDOW: &[u8] = b"W";
+const WINDOW_LEN: usize = WINDOW.len() + 8;
+
+fn window_frame(limit: u64) -> Bytes {
+    [WINDOW, &limit.to_be_bytes()].concat().into()
+}
+
+/// Control frame reporting the total bytes the application read, see
+/// [`SctpConfig::delivery_reports`].
+const REPORT: &[u8] = b"D";
+const REPORT_LEN: usize = REPORT.len() + 8;
+
+fn report_frame(consumed: u64) -> Bytes {
+    [REPORT, &consumed.to_be_bytes()].concat().into()
+}
+
+async fn sleep_until_some(at: Option<tokio::time::Instant>) {
+    match at {
+        Some(at) => sleep_until(at).await,
+        None => pending().await,
+    }
+}
+
+pub struct SctpReadHalf {
+    association: Arc<SctpAssociation>,
+    info: Arc<ConnectionInfo>,
+    pause: PauseHandle,
+    paused: watch::Receiver<bool>,
+    /// Records notices from the peer.
+    diagnostics: Diagnostics,
+    /// Tells the application about shutdown requests.
+    events: Events,
+    strictness: Strictness,
+    stream: Arc<Stream>,
+    buf: Vec<u8>,
+    connection: watch::Receiver<ConnectionState>,
+    /// The peer reset the stream, which ends it but not the association.
+    stream_eof: bool,
+    role: &'static str,
+    deadline: Deadline,
+    next_probe: Option<tokio::time::Instant>,
+    probe_interval: Option<Duration>,
+    reports: Option<DeliveryReports>,
+    last_report: Option<tokio::time::Instant>,
+    next_report: Option<tokio::time::Instant>,
+    grace: Duration,
+    /// Since when ICE is disconnected, see
+    /// [`SctpConfig::disconnect_grace`].
+    degraded: Option<Degraded>,
+}
+impl SctpReadHalf {
+    pub fn set_deadline(&mut self, deadline: Deadline) {
+        self.deadline = deadline;
+    }
+
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    fn send_probe(&mut self) -> SctpResult<()> {
+        let Some(interval) = self.probe_interval else {
+            return Ok(());
+        };
+        self.next_probe = Some(tokio::time::Instant::now() + interval.max(MIN_PROBE_INTERVAL));
+        let request = self
+            .association
+            .delay
+            .lock()
+            .unwrap()
+            .request(delay_probe::now_us());
+        self.stream.write_sctp(&request.into(), CONTROL)?;
+        Ok(())
+    }
+
+    /// Schedules the next report once the application read more, sending
+    /// it right away if due.
+    fn delivered(&mut self) -> SctpResult<()> {
+        let Some(reports) = self.reports else {
+            return Ok(());
+        };
+        let unreported = {
+            let window = self.association.window.lock().unwrap();
+            window.consumed - window.reported
+        };
+        let now = tokio::time::Instant::now();
+        let due = match unreported >= reports.every as u64 {
+            true => self
+                .last_report
+                .map_or(now, |at| (at + MIN_REPORT_INTERVAL).max(now)),
+            false => now + reports.interval.max(MIN_REPORT_INTERVAL),
+        };
+        let due = self.next_report.map_or(due, |next| next.min(due));
+        self.next_report = Some(due);
+        if due <= now {
+            self.send_report()?;
+        }
+        Ok(())
+    }
+
+    fn send_report(&mut self) -> SctpResult<()> {
+        self.next_report = None;
+        self.last_report = Some(tokio::time::Instant::now());
+        let consumed = {
+            let mut window = self.association.window.lock().unwrap();
+            window.reported = window.consumed;
+            window.consumed
+        };
+        self.stream.write_sctp(&report_frame(consumed), CONTROL)?;
+        Ok(())
+    }
 
-    fn connection_closed(&self) -> bool {
+    /// Ignored under [`TransportKind::Chacha20`], where the request comes
+    /// authenticated through the encrypted stream instead.
+    fn shutdown_received(&self, n: usize) {
+        if self.info.transport == TransportKind::Chacha20 {
+            trace::debug!(target: logging::SCTP, "Unauthenticated shutdown request dropped");
+            return;
+        }
+        let deadline = u64::from_be_bytes(self.buf[SHUTDOWN.len()..n].try_into().unwrap());
+        let deadline = Duration::from_millis(deadline);
+        let request = ShutdownRequest {
+            deadline,
+            at: Instant::now(),
+            remote: true,
+        };
+        if self.association.shutdown_requested(request) {
+            trace::info!(target: logging::SCTP, "Peer asked to close within {deadline:?}");
+            self.events
+                .emit(ConnectionEvent::ShutdownRequested { deadline });
+        }
+    }
+
+    /// Whether the peer reset the stream. Only ends this stream, the
+    /// association and the ICE connection under it may still be up.
+    pub fn stream_eof(&self) -> bool {
+        self.stream_eof
+    }
+
+    /// Whether the ICE connection carrying the association went away, the
+    /// end of every stream on it whatever they last read. Disconnected only
+    /// counts once [`SctpConfig::disconnect_grace`] is over.
+    pub fn association_closed(&self) -> bool {
         match self.connection.borrow().deref() {
             ConnectionState::Unspecified => false,
             ConnectionState::New => false,
@@ -83,43 +1179,129 @@ impl Sctp {
             ConnectionState::Connected => false,
             ConnectionState::Completed => true,
             ConnectionState::Failed => true,
-            ConnectionState::Disconnected => true,
+            ConnectionState::Disconnected => match self.degraded {
+                Some(degraded) => degraded.expired,
+                None => self.grace.is_zero(),
+            },
             ConnectionState::Closed => true,
         }
     }
-}
-impl PipeStream for Sctp {
-    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, SctpResult<()>> {
-        async move {
-            self.stream
-                .write_sctp(&data.to_owned().into(), PayloadProtocolIdentifier::Binary)?;
-            while self.stream.buffered_amount() > 4 * 1024 * 1024 {
-                sleep(Duration::from_millis(100)).await;
-            }
 
-            Ok(())
+    /// Whether ICE is disconnected but may still recover, see
+    /// [`SctpConfig::disconnect_grace`].
+    pub fn degraded(&self) -> bool {
+        self.degraded.is_some_and(|degraded| !degraded.expired)
+    }
+
+    /// Starts or ends the grace of a disconnection as ICE changes `state`.
+    fn connection_changed(&mut self, state: ConnectionState) {
+        match (state, self.degraded) {
+            (ConnectionState::Disconnected, None) if !self.grace.is_zero() => {
+                trace::warn!(
+                    target: logging::SCTP,
+                    "ICE disconnected, waiting {:?} for it to recover",
+                    self.grace
+                );
+                self.degraded = Some(Degraded {
+                    since: tokio::time::Instant::now(),
+                    expired: false,
+                });
+                metrics::ice_restart(self.role);
+                self.events
+                    .emit(ConnectionEvent::Degraded { grace: self.grace });
+            }
+            (ConnectionState::Connected, Some(degraded)) if !degraded.expired => {
+                let after = degraded.since.elapsed();
+                trace::info!(target: logging::SCTP, "ICE recovered after {after:?}");
+                self.degraded = None;
+                self.events.emit(ConnectionEvent::Recovered { after });
+            }
+            _ => (),
         }
-        .boxed_local()
+    }
+
+    /// When the grace of the current disconnection is over, if pending.
+    fn grace_over(&self) -> Option<tokio::time::Instant> {
+        self.degraded
+            .filter(|degraded| !degraded.expired)
+            .map(|degraded| degraded.since + self.grace)
     }
 }
-impl WaitThen for Sctp {
-    type Value = Either<ConnectionState, (usize, PayloadProtocolIdentifier)>;
+impl WaitThen for SctpReadHalf {
+    type Value = SctpValue;
     type Output = Option<Vec<u8>>;
     type Error = SctpError;
 
+    /// Cancel safe, a message only leaves the reassembly queue in the poll
+    /// that returns it and the next probe is due from `next_probe` until
+    /// sent. `buf` is only read by `then`, resizing it first loses nothing.
     fn wait(&mut self) -> LocalBoxFuture<'_, SctpResult<Self::Value>> {
-        self.buf.resize(8096, 0);
+        // A shorter buffer would fail the read and drop the message
+        self.buf.resize(MAX_MESSAGE_SIZE, 0);
 
+        let deadline = self.deadline;
         Box::pin(async move {
-            let r = select! {
-                r = self.connection.changed() => {
-                    r.unwrap();
-                    Either::Left(*self.connection.borrow())
-                },
-                r = self.stream.read_sctp(&mut self.buf[..]) => {
-                    let (n, protocol_id) = r?;
-                    Either::Right((n, protocol_id))
-                }
+            let r = loop {
+                let paused = *self.paused.borrow_and_update();
+                let grace_over = self.grace_over();
+                break select! {
+                    r = self.connection.changed() => {
+                        r.unwrap();
+                        Either::Left(*self.connection.borrow())
+                    },
+                    // The read half holds the sender, it is never dropped
+                    _ = self.paused.changed(), if paused => continue,
+                    _ = sleep_until_some(self.next_probe) => {
+                        if let Err(e) = self.send_probe() {
+                            self.association.failed(&e);
+                            return Err(e);
+                        }
+                        continue;
+                    }
+                    _ = sleep_until_some(grace_over) => {
+                        if let Some(degraded) = &mut self.degraded {
+                            degraded.expired = true;
+                        }
+                        Either::Left(*self.connection.borrow())
+                    }
+                    _ = sleep_until_some(self.next_report) => {
+                        if let Err(e) = self.send_report() {
+                            self.association.failed(&e);
+                            return Err(e);
+                        }
+                        continue;
+                    }
+                    r = self.association.read(&self.stream, &mut self.buf[..]), if !paused => {
+                        match r {
+                            // Handed out, for the reader to notice it
+                            Ok((n, CONTROL)) if is_shutdown(&self.buf[0..n]) => {
+                                Either::Right((n, CONTROL))
+                            }
+                            Ok((n, CONTROL)) => {
+                                let frame = &self.buf[0..n];
+                                let stream = &self.stream;
+                                let r = self.association.control_received(frame, stream, self.role);
+                                if let Err(e) = r {
+                                    self.association.failed(&e);
+                                    return Err(e);
+                                }
+                                continue;
+                            }
+                            Ok((n, protocol_id)) => {
+                                metrics::bytes_received(self.role, metrics::TRANSPORT_SCTP, n);
+                                Either::Right((n, protocol_id))
+                            }
+                            Err(e) => {
+                                self.association.failed(&e);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    e = deadline.expired() => {
+                        self.association.closed(CloseReason::Timeout);
+                        return Err(e.into());
+                    }
+                };
             };
             Ok(r)
         })
@@ -130,44 +1312,365 @@ impl WaitThen for Sctp {
         value: &'a mut Self::Value,
     ) -> LocalBoxFuture<'a, SctpResult<Self::Output>> {
         match value {
-            Either::Left(_x) => Box::pin(async move { Ok(None) }),
+            Either::Left(state) => {
+                self.connection_changed(*state);
+                if self.association_closed() {
+                    self.association.closed(ice_failed(*state));
+                }
+                Box::pin(async move { Ok(None) })
+            }
             Either::Right((n, protocol_id)) => {
                 if *n == 0 {
-                    self.rx_closed = true;
+                    self.stream_eof = true;
+                    self.association.closed(CloseReason::CleanFin);
+                    return ready(Ok(None)).boxed_local();
+                }
+                if *protocol_id == CONTROL {
+                    self.shutdown_received(*n);
                     return ready(Ok(None)).boxed_local();
                 }
 
+                if *protocol_id == PayloadProtocolIdentifier::String {
+                    let notice = String::from_utf8_lossy(&self.buf[0..*n]);
+                    trace::warn!(target: logging::SCTP, "Peer notice: {notice}");
+                    self.diagnostics.warning(format!("Peer notice: {notice}"));
+                }
                 if *protocol_id != PayloadProtocolIdentifier::Binary {
+                    let known = matches!(
+                        protocol_id,
+                        PayloadProtocolIdentifier::String | PayloadProtocolIdentifier::StringEmpty
+                    );
+                    if !known {
+                        let protocol_id = format!("{protocol_id:?}");
+                        if let Err(e) = self
+                            .strictness
+                            .check(|| Violation::UnexpectedPpid(protocol_id))
+                        {
+                            let e = SctpError::from(e);
+                            self.association.failed(&e);
+                            return ready(Err(e)).boxed_local();
+                        }
+                    }
                     return ready(Ok(None)).boxed_local();
                 }
 
+                trace::trace!(target: logging::SCTP, "RX {n} bytes");
                 let r = self.buf[0..*n].to_owned();
+                let mut window = self.association.window.lock().unwrap();
+                if let Some(limit) = window.consumed(*n) {
+                    if let Err(e) = self.stream.write_sctp(&window_frame(limit), CONTROL) {
+                        let e = SctpError::from(e);
+                        self.association.failed(&e);
+                        return ready(Err(e)).boxed_local();
+                    }
+                    metrics::receive_window(self.role, window.receive_window().unwrap_or_default());
+                }
+                drop(window);
+                if let Err(e) = self.delivered() {
+                    self.association.failed(&e);
+                    return ready(Err(e)).boxed_local();
+                }
                 Box::pin(ready(Ok(Some(r))))
             }
         }
     }
 }
-impl Control for Sctp {
-    fn close(&mut self) -> LocalBoxFuture<'_, SctpResult<()>> {
+impl PipeReadHalf for SctpReadHalf {
+    /// Each association carries this single stream, so its end ends
+    /// receiving as well as the association closing does.
+    fn rx_closed(&self) -> bool {
+        self.stream_eof || self.association_closed()
+    }
+
+    /// A zero length read is the peer resetting the stream, while a change
+    /// of the ICE connection state means the transport went away.
+    fn close_reason(&self) -> Option<CloseReason> {
+        self.association.close_reason().or_else(|| {
+            self.association_closed()
+                .then(|| ice_failed(*self.connection.borrow()))
+        })
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        Some(self.info.clone())
+    }
+
+    fn shutdown_request(&self) -> Option<ShutdownRequest> {
+        self.association.shutdown_request()
+    }
+}
+
+/// ICE disconnected, see [`SctpConfig::disconnect_grace`].
+#[derive(Clone, Copy, Debug)]
+struct Degraded {
+    since: tokio::time::Instant,
+    /// The grace is over, the stream ended.
+    expired: bool,
+}
+
+fn ice_failed(state: ConnectionState) -> CloseReason {
+    CloseReason::transport_failed(format!("ICE connection {state}"))
+}
+
+pub struct SctpWriteHalf {
+    association: Arc<SctpAssociation>,
+    info: Arc<ConnectionInfo>,
+    stream: Arc<Stream>,
+    /// Messages read while sends wait, see
+    /// [`SctpAssociation::read_aside`].
+    buf: Vec<u8>,
+    span: trace::Span,
+    role: &'static str,
+    config: SctpConfig,
+    deadline: Deadline,
+}
+impl SctpWriteHalf {
+    pub fn set_deadline(&mut self, deadline: Deadline) {
+        self.deadline = deadline;
+    }
+
+    pub(crate) fn watchdog(&self) -> Watchdog {
+        Watchdog {
+            timeout: self.config.close_timeout,
+            association: self.association.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> SctpStats {
+        let association = self.association.association.as_ref();
+        let buffered_amount = self.stream.buffered_amount();
+        let send_window = self.association.send_window();
+        let window = self.association.window.lock().unwrap();
+        SctpStats {
+            bytes_sent: association.map(Association::bytes_sent).unwrap_or_default(),
+            bytes_received: association
+                .map(Association::bytes_received)
+                .unwrap_or_default(),
+            buffered_amount,
+            max_message_size: association
+                .map(Association::max_message_size)
+                .unwrap_or_default(),
+            one_way_delay: self.association.delay.lock().unwrap().estimate(),
+            path_mtu: self.association.mtu.latest(),
+            send_window,
+            receive_window: window.receive_window(),
+            remote_lag: window.remote_lag(buffered_amount),
+        }
+    }
+
+    fn reader(&mut self) -> Reader<'_> {
+        Reader {
+            stream: &self.stream,
+            buf: &mut self.buf,
+            role: self.role,
+            limit: self.config.max_buffered_amount,
+        }
+    }
+
+    /// See [`Sctp::ready`].
+    pub fn ready(&self) -> SctpResult<()> {
+        self.stream
+            .write_sctp(&Bytes::from_static(READY), CONTROL)?;
+        Ok(())
+    }
+}
+impl PipeWriteHalf for SctpWriteHalf {
+    type Error = SctpError;
+
+    /// Reads the stream while waiting on the peer's ready or window, and
+    /// for what already arrived before each send, so both are seen even if
+    /// the read half is never waited on. Messages read are stashed for the
+    /// read half, up to
+    /// [`max_buffered_amount`](SctpConfig::max_buffered_amount).
+    fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, SctpResult<()>> {
+        let deadline = self.deadline;
+        let association = self.association.clone();
+        let send = async move {
+            let association = self.association.clone();
+            let max_buffered_amount = self.config.max_buffered_amount;
+            let mut reader = self.reader();
+            association.read_ready(reader.by_ref())?;
+            if association.hold(data) {
+                let mut peer_ready = association.peer_ready.subscribe();
+                while association.held_bytes() > max_buffered_amount
+                    && !*peer_ready.borrow_and_update()
+                {
+                    select! {
+                        // The association holds the sender
+                        _ = peer_ready.changed() => {}
+                        r = association.read_aside(&mut reader) => r?,
+                    }
+                }
+                return Ok(());
+            }
+            // Nothing is written until reserved, a send dropped while
+            // waiting is not sent
+            association.reserve(data.len(), reader).await?;
+            // Written before the next await, so sends keep their order even
+            // when dropped, see [`WaitThen`]
+            self.stream
+                .write_sctp(&data.to_owned().into(), PayloadProtocolIdentifier::Binary)?;
+            metrics::bytes_sent(self.role, metrics::TRANSPORT_SCTP, data.len());
+            trace::trace!(target: logging::SCTP, "TX {} bytes", data.len());
+            if self.stream.buffered_amount() > self.config.max_buffered_amount {
+                trace::debug!(
+                    target: logging::SCTP,
+                    "Send blocked, {} bytes buffered",
+                    self.stream.buffered_amount()
+                );
+            }
+            while self.stream.buffered_amount() > self.config.max_buffered_amount {
+                metrics::buffered_amount(
+                    self.role,
+                    metrics::TRANSPORT_SCTP,
+                    self.stream.buffered_amount(),
+                );
+                sleep(self.config.backpressure_poll).await;
+            }
+            metrics::buffered_amount(
+                self.role,
+                metrics::TRANSPORT_SCTP,
+                self.stream.buffered_amount(),
+            );
+
+            Ok(())
+        };
+
+        async move {
+            let r = deadline.run(send).await;
+            if let Err(e) = &r {
+                association.failed(e);
+            }
+            r
+        }
+        .boxed_local()
+    }
+
+    /// Sent with the string protocol identifier, which versions without
+    /// notices ignore. Notices are not encrypted nor authenticated with
+    /// [`Encryption::Chacha20`](crate::connect::Encryption::Chacha20).
+    fn notify<'a>(&'a mut self, notice: &'a str) -> LocalBoxFuture<'a, SctpResult<()>> {
+        let r = self
+            .stream
+            .write_sctp(
+                &notice.as_bytes().to_owned().into(),
+                PayloadProtocolIdentifier::String,
+            )
+            .map(|_| ())
+            .map_err(Into::into);
+        ready(r).boxed_local()
+    }
+
+    fn connection_info(&self) -> Option<Arc<ConnectionInfo>> {
+        Some(self.info.clone())
+    }
+
+    /// Sent as a control frame, which versions without them ignore. It is
+    /// not authenticated, so the peer drops it under
+    /// [`TransportKind::Chacha20`], which sends its own.
+    fn request_shutdown(&mut self, deadline: Duration) -> LocalBoxFuture<'_, SctpResult<()>> {
+        let request = ShutdownRequest {
+            deadline,
+            at: Instant::now(),
+            remote: false,
+        };
+        let millis = u64::try_from(deadline.as_millis()).unwrap_or(u64::MAX);
+        let frame = [SHUTDOWN, &millis.to_be_bytes()].concat();
+        let r = self
+            .stream
+            .write_sctp(&frame.into(), CONTROL)
+            .map(|_| {
+                self.association.shutdown_requested(request);
+            })
+            .map_err(Into::into);
+        ready(r).boxed_local()
+    }
+
+    /// Polled every [`SctpConfig::backpressure_poll`] until the buffer,
+    /// sends held for the peer to be ready included, drains under
+    /// [`SctpConfig::writable_threshold`] and, if the peer advertised one,
+    /// its window has room for a message, reading the stream meanwhile like
+    /// `send` does.
+    fn writable(&self) -> LocalBoxFuture<'static, ()> {
+        let association = self.association.clone();
+        let stream = self.stream.clone();
+        let threshold = self
+            .config
+            .writable_threshold
+            .min(self.config.max_buffered_amount);
+        let poll = self.config.backpressure_poll;
+        let (role, limit) = (self.role, self.config.max_buffered_amount);
         async move {
+            let window_full = || {
+                association
+                    .send_window()
+                    .is_some_and(|window| window < MAX_MESSAGE_SIZE)
+            };
+            let mut buf = vec![0; MAX_MESSAGE_SIZE];
+            let mut reader = Reader {
+                stream: &stream,
+                buf: &mut buf,
+                role,
+                limit,
+            };
+            while stream.buffered_amount() + association.held_bytes() > threshold || window_full() {
+                select! {
+                    _ = sleep(poll) => {}
+                    // Failures surface on the next send
+                    r = association.read_aside(&mut reader), if window_full() => {
+                        if r.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+        .boxed_local()
+    }
+
+    /// Flushes pending data and resets the stream, which also ends the read half.
+    /// Sends held for the peer to be ready are lost unless it gets ready
+    /// while the read half is waited on during the flush.
+    fn close(&mut self) -> LocalBoxFuture<'_, SctpResult<()>> {
+        let span = self.span.clone();
+        let deadline = self.deadline;
+        let watchdog = self.watchdog();
+        self.association
+            .closed(match self.association.shutdown_request() {
+                Some(request) if request.remote => CloseReason::RemoteRequested,
+                _ => CloseReason::LocalClose,
+            });
+        let close = async move {
             let max_wait = Instant::now() + Duration::from_secs(5);
-            while self.stream.buffered_amount() > 0 && Instant::now() < max_wait {
+            let pending = || self.stream.buffered_amount() + self.association.held_bytes();
+            while pending() > 0 && Instant::now() < max_wait {
                 sleep(Duration::from_millis(100)).await;
             }
-            sleep(Duration::from_millis(100)).await;
+            if !self.config.close_linger.is_zero() {
+                sleep(self.config.close_linger).await;
+            }
 
             self.stream.shutdown(std::net::Shutdown::Both).await?;
+            trace::info!(target: logging::SCTP, "Stream closed");
 
             Ok(())
-        }
-        .boxed_local()
-    }
+        };
 
-    fn rx_closed(&self) -> bool {
-        self.rx_closed || self.connection_closed()
+        async move { watchdog.guard(deadline.run(close)).await }
+            .instrument(span)
+            .boxed_local()
     }
 }
 
+/// Why [`Sctp::wait_established`] gave up.
+#[derive(thiserror::Error, Debug)]
+pub enum EstablishError {
+    #[error(transparent)]
+    Timeout(#[from] TimeoutError),
+    #[error("ICE connection {0} before it was established")]
+    Connection(ConnectionState),
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum SctpError {
     #[error(transparent)]
@@ -182,6 +1685,10 @@ pub enum SctpError {
     AssociationClosedWithoutStream,
     #[error(transparent)]
     WebrtcSctpError(#[from] webrtc_sctp::Error),
+    #[error(transparent)]
+    ClosedDirty(#[from] ClosedDirty),
+    #[error(transparent)]
+    Violation(#[from] Violation),
 }
 impl From<SignalingError> for SctpError {
     fn from(value: SignalingError) -> Self {
@@ -198,7 +1705,8 @@ impl From<StreamError> for SctpError {
             StreamError::Io(e) => e.into(),
             StreamError::Timeout(e) => e.into(),
             StreamError::SignalingError(e) => e.into(),
-            e @ StreamError::Other(_) => Self::StreamError(e),
+            StreamError::ClosedDirty(e) => e.into(),
+            e @ (StreamError::Other(_) | StreamError::TrafficLimit(_)) => Self::StreamError(e),
         }
     }
 }
@@ -211,7 +1719,385 @@ impl From<SctpError> for StreamError {
             SctpError::StreamError(e) => e,
             e @ SctpError::AssociationClosedWithoutStream => StreamError::Other(Box::new(e)),
             e @ SctpError::WebrtcSctpError(_) => StreamError::Other(Box::new(e)),
+            SctpError::ClosedDirty(e) => e.into(),
+            e @ SctpError::Violation(_) => StreamError::Other(Box::new(e)),
         }
     }
 }
 pub type SctpResult<T> = Result<T, SctpError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::UdpSocket;
+
+    async fn pair() -> (Sctp, Sctp) {
+        pair_with(Default::default()).await
+    }
+
+    async fn pair_with(config: SctpConfig) -> (Sctp, Sctp) {
+        pair_of(config, config).await
+    }
+
+    async fn pair_of(a_config: SctpConfig, b_config: SctpConfig) -> (Sctp, Sctp) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        let (a, b) = tokio::join!(
+            Sctp::over(Arc::new(a), true, a_config),
+            Sctp::over(Arc::new(b), false, b_config)
+        );
+        (a.unwrap(), b.unwrap())
+    }
+
+    async fn recv(stream: &mut Sctp) -> SctpResult<Vec<u8>> {
+        loop {
+            let mut value = stream.wait().await?;
+            if let Some(data) = stream.then(&mut value).await? {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Keeps the largest datagram sent.
+    struct Recorded {
+        socket: UdpSocket,
+        largest: Arc<AtomicUsize>,
+    }
+    #[async_trait::async_trait]
+    impl Conn for Recorded {
+        async fn connect(&self, addr: std::net::SocketAddr) -> webrtc_util::Result<()> {
+            Ok(self.socket.connect(addr).await?)
+        }
+
+        async fn recv(&self, buf: &mut [u8]) -> webrtc_util::Result<usize> {
+            Ok(self.socket.recv(buf).await?)
+        }
+
+        async fn recv_from(
+            &self,
+            buf: &mut [u8],
+        ) -> webrtc_util::Result<(usize, std::net::SocketAddr)> {
+            Ok(self.socket.recv_from(buf).await?)
+        }
+
+        async fn send(&self, buf: &[u8]) -> webrtc_util::Result<usize> {
+            self.largest.fetch_max(buf.len(), Ordering::Relaxed);
+            Ok(self.socket.send(buf).await?)
+        }
+
+        async fn send_to(&self, buf: &[u8], _: std::net::SocketAddr) -> webrtc_util::Result<usize> {
+            self.send(buf).await
+        }
+
+        fn local_addr(&self) -> webrtc_util::Result<std::net::SocketAddr> {
+            Ok(self.socket.local_addr()?)
+        }
+
+        fn remote_addr(&self) -> Option<std::net::SocketAddr> {
+            self.socket.peer_addr().ok()
+        }
+
+        async fn close(&self) -> webrtc_util::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn packets_fit_packet_size() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        let largest = Arc::new(AtomicUsize::new(0));
+        let a = Recorded {
+            socket: a,
+            largest: largest.clone(),
+        };
+        let (a, b) = tokio::join!(
+            Sctp::over(Arc::new(a), true, Default::default()),
+            Sctp::over(Arc::new(b), false, Default::default())
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+        let message = vec![7; MAX_MESSAGE_SIZE];
+        a.send(&message).await.unwrap();
+        assert_eq!(recv(&mut b).await.unwrap(), message);
+        assert_eq!(largest.load(Ordering::Relaxed), PACKET_SIZE);
+    }
+
+    #[tokio::test]
+    async fn establish_failed() {
+        let (mut a, _b) = pair().await;
+        let (state, connection) = watch::channel(ConnectionState::Checking);
+        a.rx.connection = connection;
+        let established = a.wait_established();
+        state.send(ConnectionState::Disconnected).unwrap();
+        state.send(ConnectionState::Failed).unwrap();
+
+        assert!(matches!(
+            established.await,
+            Err(EstablishError::Connection(ConnectionState::Failed))
+        ));
+
+        // The agent went away without a word
+        let (state, connection) = watch::channel(ConnectionState::Checking);
+        a.rx.connection = connection;
+        let established = a.wait_established();
+        drop(state);
+        assert!(matches!(
+            established.await,
+            Err(EstablishError::Connection(ConnectionState::Closed))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stream_eof() {
+        let (mut a, mut b) = pair().await;
+        a.close().await.unwrap();
+        while !b.rx_closed() {
+            let mut value = b.wait().await.unwrap();
+            b.then(&mut value).await.unwrap();
+        }
+
+        assert!(b.stream_eof());
+        assert!(!b.association_closed());
+        assert!(matches!(b.close_reason(), Some(CloseReason::CleanFin)));
+    }
+
+    #[tokio::test]
+    async fn unexpected_ppid() {
+        for strictness in [Strictness::Lenient, Strictness::Strict] {
+            let (a, mut b) = pair().await;
+            b.set_strictness(strictness);
+            for (data, protocol_id) in [
+                (b"dcep".as_slice(), PayloadProtocolIdentifier::Dcep),
+                (b"data".as_slice(), PayloadProtocolIdentifier::Binary),
+            ] {
+                a.tx.stream
+                    .write_sctp(&data.to_owned().into(), protocol_id)
+                    .unwrap();
+            }
+
+            let r = recv(&mut b).await;
+            match strictness {
+                Strictness::Lenient => assert_eq!(r.unwrap(), b"data"),
+                Strictness::Strict => assert!(matches!(
+                    r,
+                    Err(SctpError::Violation(Violation::UnexpectedPpid(ppid))) if ppid == "Dcep"
+                )),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_reader() {
+        const WINDOW: usize = 4 * MAX_MESSAGE_SIZE;
+        const MESSAGES: usize = 32;
+        // Enforced without a window of its own
+        let (mut a, mut b) = pair_of(
+            Default::default(),
+            SctpConfig {
+                receive_window: Some(WINDOW),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(a.stats().receive_window, None);
+        assert_eq!(b.stats().receive_window, Some(WINDOW));
+        // Enforced once the window of the peer is read
+        b.send(b"go").await.unwrap();
+        assert_eq!(recv(&mut a).await.unwrap(), b"go");
+        assert_eq!(a.stats().send_window, Some(WINDOW));
+
+        // Only sends, the window advertisements are read by the sends
+        let received = std::cell::Cell::new(0);
+        let blocked = std::cell::Cell::new(false);
+        let sender = async {
+            for i in 0..MESSAGES {
+                let message = vec![i as u8; MAX_MESSAGE_SIZE];
+                a.send(&message).await.unwrap();
+                let sent = (i + 1) * MAX_MESSAGE_SIZE;
+                assert!(sent <= received.get() + WINDOW, "{sent} bytes sent");
+                if a.stats().send_window == Some(0) {
+                    blocked.set(true);
+                }
+            }
+        };
+        let reader = async {
+            for i in 0..MESSAGES {
+                sleep(Duration::from_millis(5)).await;
+                let data = recv(&mut b).await.unwrap();
+                assert_eq!(data, vec![i as u8; MAX_MESSAGE_SIZE]);
+                received.set(received.get() + data.len());
+                if i == MESSAGES / 2 {
+                    b.send(b"halfway").await.unwrap();
+                }
+            }
+        };
+        tokio::join!(sender, reader);
+
+        assert!(blocked.get());
+        assert!(b.stats().receive_window.unwrap() >= WINDOW / 2);
+        // Stashed for the read half if read by a send
+        assert_eq!(recv(&mut a).await.unwrap(), b"halfway");
+    }
+
+    #[tokio::test]
+    async fn forged_window() {
+        let (mut a, mut b) = pair().await;
+        b.tx.stream
+            .write_sctp(&window_frame(u64::MAX), CONTROL)
+            .unwrap();
+        b.send(b"data").await.unwrap();
+        assert_eq!(recv(&mut a).await.unwrap(), b"data");
+        assert_eq!(a.stats().send_window, Some(MAX_RECEIVE_WINDOW));
+    }
+
+    #[tokio::test]
+    async fn remote_lag() {
+        const MESSAGES: usize = 128;
+        let (a, mut b) = pair_with(SctpConfig {
+            delivery_reports: Some(DeliveryReports {
+                every: 4 * MAX_MESSAGE_SIZE,
+                interval: Duration::from_millis(50),
+            }),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(a.stats().remote_lag, None);
+        let (mut a_rx, mut a_tx) = a.split();
+
+        let done = std::cell::Cell::new(false);
+        let sender = async {
+            // Reads the reports
+            let control = async {
+                loop {
+                    let mut value = a_rx.wait().await.unwrap();
+                    a_rx.then(&mut value).await.unwrap();
+                }
+            };
+            let sends = async {
+                let mut highest = 0;
+                for i in 0..MESSAGES {
+                    a_tx.send(&vec![i as u8; MAX_MESSAGE_SIZE]).await.unwrap();
+                    highest = highest.max(a_tx.stats().remote_lag.unwrap_or_default());
+                }
+                while a_tx.stats().remote_lag != Some(0) {
+                    sleep(Duration::from_millis(10)).await;
+                    highest = highest.max(a_tx.stats().remote_lag.unwrap_or_default());
+                }
+                done.set(true);
+                highest
+            };
+            futures::pin_mut!(control, sends);
+            match futures::future::select(control, sends).await {
+                futures::future::Either::Right((highest, _)) => highest,
+                futures::future::Either::Left(_) => unreachable!(),
+            }
+        };
+        let reader = async {
+            for i in 0..MESSAGES {
+                sleep(Duration::from_millis(5)).await;
+                let data = recv(&mut b).await.unwrap();
+                assert_eq!(data, vec![i as u8; MAX_MESSAGE_SIZE]);
+            }
+            // Sends the last report
+            while !done.get() {
+                let _ = tokio::time::timeout(Duration::from_millis(10), b.wait()).await;
+            }
+        };
+        let (highest, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(sender, reader)
+        })
+        .await
+        .unwrap();
+
+        assert!(
+            highest > 16 * MAX_MESSAGE_SIZE,
+            "{highest} bytes behind at most"
+        );
+    }
+
+    /// `a` over a connection state driven by the test.
+    async fn pair_with_state(config: SctpConfig) -> (Sctp, Sctp, watch::Sender<ConnectionState>) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        let (state, connection) = watch::channel(ConnectionState::Connected);
+        let carrier = Carrier::Direct {
+            _state: watch::channel(ConnectionState::Connected).0,
+        };
+        let (a, b) = tokio::join!(
+            Sctp::assemble(Arc::new(a), carrier, true, connection, config),
+            Sctp::over(Arc::new(b), false, config)
+        );
+        (a.unwrap(), b.unwrap(), state)
+    }
+
+    /// Waits on `stream` until it ends, or for `wait` at most.
+    async fn wait_closed(stream: &mut Sctp, wait: Duration) -> bool {
+        let waits = async {
+            while !stream.rx_closed() {
+                let mut value = stream.wait().await.unwrap();
+                stream.then(&mut value).await.unwrap();
+            }
+        };
+        tokio::time::timeout(wait, waits).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn disconnect_grace() {
+        let config = SctpConfig {
+            disconnect_grace: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let (mut a, mut b, state) = pair_with_state(config).await;
+        let events = Events::new();
+        let mut rx = events.subscribe();
+        a.set_events(events);
+
+        // A blip within the grace, sends are buffered meanwhile
+        state.send_replace(ConnectionState::Disconnected);
+        assert!(!wait_closed(&mut a, Duration::from_millis(100)).await);
+        assert!(a.degraded());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ConnectionEvent::Degraded { grace }) if grace == config.disconnect_grace
+        ));
+        a.send(b"held").await.unwrap();
+        state.send_replace(ConnectionState::Connected);
+        assert!(!wait_closed(&mut a, Duration::from_millis(100)).await);
+        assert!(!a.degraded());
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ConnectionEvent::Recovered { .. })
+        ));
+        assert_eq!(recv(&mut b).await.unwrap(), b"held");
+        b.send(b"after").await.unwrap();
+        assert_eq!(recv(&mut a).await.unwrap(), b"after");
+
+        // Beyond the grace
+        state.send_replace(ConnectionState::Disconnected);
+        let started = Instant::now();
+        assert!(wait_closed(&mut a, Duration::from_secs(5)).await);
+        assert!(started.elapsed() >= config.disconnect_grace);
+        assert!(matches!(
+            a.close_reason(),
+            Some(CloseReason::TransportFailed(_))
+        ));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ConnectionEvent::Degraded { .. })
+        ));
+        assert!(rx.try_recv().is_err());
+
+        // Failing is not waited for
+        let (mut a, _b, state) = pair_with_state(config).await;
+        state.send_replace(ConnectionState::Failed);
+        assert!(wait_closed(&mut a, Duration::from_millis(100)).await);
+    }
+}
@@ -0,0 +1,278 @@
+//! Minimal SDP offer/answer, enough to exchange ICE credentials and
+//! candidates with regular WebRTC endpoints, see
+//! [`SignallingFormat::Sdp`](crate::signalling::SignallingFormat::Sdp).
+//!
+//! Only the application (data channel) media section is described. Unless a
+//! fingerprint is provided, e.g. by the DTLS layer, a
+//! placeholder is announced and endpoints that insist on DTLS will not get
+//! past ICE.
+
+use serde::{Deserialize, Serialize};
+
+const PLACEHOLDER_FINGERPRINT: &str = "sha-256 \
+    00:00:00:00:00:00:00:00:00:00:00:00:00:00:00:00:\
+    00:00:00:00:00:00:00:00:00:00:00:00:00:00:00:00";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SdpType {
+    Offer,
+    Answer,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionDescription {
+    pub ufrag: String,
+    pub pwd: String,
+    /// `<hash function> <fingerprint>` of the DTLS certificate.
+    pub fingerprint: Option<String>,
+    /// Candidates as marshalled by `webrtc_ice`, without the `candidate:`
+    /// prefix.
+    pub candidates: Vec<String>,
+}
+impl SessionDescription {
+    pub fn to_sdp(&self, sdp_type: SdpType) -> String {
+        let setup = match sdp_type {
+            SdpType::Offer => "actpass",
+            SdpType::Answer => "active",
+        };
+        let fingerprint = self
+            .fingerprint
+            .as_deref()
+            .unwrap_or(PLACEHOLDER_FINGERPRINT);
+
+        let mut sdp = String::new();
+        let mut line = |l: &str| {
+            sdp.push_str(l);
+            sdp.push_str("\r\n");
+        };
+        line("v=0");
+        line("o=- 0 2 IN IP4 127.0.0.1");
+        line("s=-");
+        line("t=0 0");
+        line("a=group:BUNDLE 0");
+        line("m=application 9 UDP/DTLS/SCTP webrtc-datachannel");
+        line("c=IN IP4 0.0.0.0");
+        line(&format!("a=ice-ufrag:{}", self.ufrag));
+        line(&format!("a=ice-pwd:{}", self.pwd));
+        line(&format!("a=fingerprint:{fingerprint}"));
+        line(&format!("a=setup:{setup}"));
+        line("a=mid:0");
+        line("a=sctp-port:5000");
+        for candidate in &self.candidates {
+            line(&format!("a=candidate:{candidate}"));
+        }
+        line("a=end-of-candidates");
+
+        sdp
+    }
+
+    /// Extracts credentials and candidates, attributes may be at session or
+    /// media level. Only the first media section is considered.
+    pub fn parse(sdp: &str) -> Result<SessionDescription, SdpError> {
+        let mut description = SessionDescription::default();
+        let mut ufrag = None;
+        let mut pwd = None;
+        let mut medias = 0;
+
+        for line in sdp.lines().map(str::trim) {
+            if line.starts_with("m=") {
+                medias += 1;
+            }
+            if medias > 1 {
+                break;
+            }
+
+            let Some(attribute) = line.strip_prefix("a=") else {
+                continue;
+            };
+            let (name, value) = attribute.split_once(':').unwrap_or((attribute, ""));
+            match name {
+                "ice-ufrag" => {
+                    ufrag.get_or_insert_with(|| value.to_owned());
+                }
+                "ice-pwd" => {
+                    pwd.get_or_insert_with(|| value.to_owned());
+                }
+                "fingerprint" => {
+                    description
+                        .fingerprint
+                        .get_or_insert_with(|| value.to_owned());
+                }
+                "candidate" => description.candidates.push(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        description.ufrag = ufrag.ok_or(SdpError::Missing("ice-ufrag"))?;
+        description.pwd = pwd.ok_or(SdpError::Missing("ice-pwd"))?;
+
+        Ok(description)
+    }
+}
+
+/// Candidate as exchanged by WebRTC JavaScript APIs, see
+/// [`CandidateEncoding::Json`](crate::signalling::CandidateEncoding::Json).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidateInit {
+    /// `candidate:` attribute, empty for the end of candidates.
+    pub candidate: String,
+    #[serde(default)]
+    pub sdp_mid: Option<String>,
+    #[serde(default)]
+    pub sdp_m_line_index: Option<u16>,
+    #[serde(default)]
+    pub username_fragment: Option<String>,
+}
+impl IceCandidateInit {
+    /// `candidate` as marshalled by `webrtc_ice`, in the only media section
+    /// of [`SessionDescription::to_sdp`].
+    pub fn new(candidate: &str) -> IceCandidateInit {
+        IceCandidateInit {
+            candidate: format!("candidate:{candidate}"),
+            sdp_mid: Some("0".to_string()),
+            sdp_m_line_index: Some(0),
+            username_fragment: None,
+        }
+    }
+
+    /// End of candidates, as sent by WebRTC.
+    pub fn end() -> IceCandidateInit {
+        IceCandidateInit {
+            candidate: String::new(),
+            ..IceCandidateInit::new("")
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Serializing a candidate cannot fail")
+    }
+
+    /// Candidate carried by `msg` if it is one, as marshalled by
+    /// `webrtc_ice`. Empty for the end of candidates.
+    pub fn candidate_from_json(msg: &str) -> Option<String> {
+        let init: IceCandidateInit = serde_json::from_str(msg).ok()?;
+        match init.candidate.strip_prefix("candidate:") {
+            Some(candidate) => Some(candidate.to_owned()),
+            None => Some(init.candidate),
+        }
+    }
+}
+
+/// Tells offers from answers by their `a=setup` attribute, offers leave the
+/// DTLS role open with `actpass`.
+pub fn sdp_type(sdp: &str) -> Option<SdpType> {
+    let setup = sdp
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("a=setup:"))?;
+
+    match setup {
+        "actpass" => Some(SdpType::Offer),
+        "active" | "passive" => Some(SdpType::Answer),
+        _ => None,
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SdpError {
+    #[error("SDP is missing the {0} attribute")]
+    Missing(&'static str),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let description = SessionDescription {
+            ufrag: "ufrag".to_string(),
+            pwd: "password".to_string(),
+            fingerprint: None,
+            candidates: vec!["1 1 udp 2130706431 192.0.2.1 9 typ host".to_string()],
+        };
+
+        let offer = description.to_sdp(SdpType::Offer);
+        assert_eq!(sdp_type(&offer), Some(SdpType::Offer));
+        assert_eq!(
+            sdp_type(&description.to_sdp(SdpType::Answer)),
+            Some(SdpType::Answer)
+        );
+
+        let parsed = SessionDescription::parse(&offer).unwrap();
+        assert_eq!(
+            parsed,
+            SessionDescription {
+                fingerprint: Some(PLACEHOLDER_FINGERPRINT.to_string()),
+                ..description
+            }
+        );
+    }
+
+    #[test]
+    fn parse_browser_answer() {
+        let sdp = "v=0\r\n\
+            o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+            s=-\r\n\
+            t=0 0\r\n\
+            a=group:BUNDLE 0\r\n\
+            a=ice-options:trickle\r\n\
+            m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+            c=IN IP4 0.0.0.0\r\n\
+            a=candidate:842163049 1 udp 1677729535 198.51.100.7 50000 typ srflx raddr 0.0.0.0 rport 0\r\n\
+            a=ice-ufrag:EsAw\r\n\
+            a=ice-pwd:bP+XJMM09aR8AiX1jdukzR6Y\r\n\
+            a=fingerprint:sha-256 0F:74:31:25:CB:A2:13:EC:28:6F:6D:2C:61:FF:5D:C2\r\n\
+            a=setup:active\r\n\
+            a=mid:0\r\n\
+            a=sctp-port:5000\r\n\
+            m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+            a=candidate:1 1 udp 1 203.0.113.1 1 typ host\r\n";
+
+        let parsed = SessionDescription::parse(sdp).unwrap();
+        assert_eq!(parsed.ufrag, "EsAw");
+        assert_eq!(parsed.pwd, "bP+XJMM09aR8AiX1jdukzR6Y");
+        assert_eq!(
+            parsed.fingerprint.as_deref(),
+            Some("sha-256 0F:74:31:25:CB:A2:13:EC:28:6F:6D:2C:61:FF:5D:C2")
+        );
+        assert_eq!(
+            parsed.candidates,
+            ["842163049 1 udp 1677729535 198.51.100.7 50000 typ srflx raddr 0.0.0.0 rport 0"]
+        );
+    }
+
+    #[test]
+    fn candidate_json() {
+        let candidate = "1 1 udp 2130706431 192.0.2.1 9 typ host";
+        let json = IceCandidateInit::new(candidate).to_json();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"candidate":"candidate:{candidate}","sdpMid":"0","sdpMLineIndex":0,"usernameFragment":null}}"#
+            )
+        );
+        assert_eq!(
+            IceCandidateInit::candidate_from_json(&json).as_deref(),
+            Some(candidate)
+        );
+
+        // As passed around by browsers, end of candidates included
+        let browser = r#"{"candidate":"candidate:842163049 1 udp 1677729535 198.51.100.7 50000 typ srflx raddr 0.0.0.0 rport 0 generation 0 ufrag EsAw","sdpMid":"0","sdpMLineIndex":0,"usernameFragment":"EsAw"}"#;
+        assert_eq!(
+            IceCandidateInit::candidate_from_json(browser).as_deref(),
+            Some("842163049 1 udp 1677729535 198.51.100.7 50000 typ srflx raddr 0.0.0.0 rport 0 generation 0 ufrag EsAw")
+        );
+        assert_eq!(
+            IceCandidateInit::candidate_from_json(r#"{"candidate":""}"#).as_deref(),
+            Some("")
+        );
+        assert_eq!(IceCandidateInit::candidate_from_json(candidate), None);
+    }
+
+    #[test]
+    fn missing_credentials() {
+        let err = SessionDescription::parse("v=0\r\na=ice-ufrag:x\r\n").unwrap_err();
+        assert!(matches!(err, SdpError::Missing("ice-pwd")));
+    }
+}
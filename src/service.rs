@@ -0,0 +1,706 @@
+//! Several named services behind a single peer. The requesting side sends a
+//! hello naming the service right after connecting and the serving side,
+//! once it validates the name against its [`ServiceRegistry`], forwards the
+//! connection to the service [`Endpoint`].
+
+use crate::{
+    async_pipe_stream::AsyncPipeStream,
+    error::TimeoutError,
+    logging,
+    pipe_stream::{CloseReason, PipeStream, PipeWriteHalf, Split, StreamError, WaitThen},
+    queued_stream::{QueueConfig, QueueStats, QueuedStream},
+    signalling::SignalingError,
+    trace,
+};
+use futures::future::pending;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    path::PathBuf,
+    pin::pin,
+    process::Stdio,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::TcpStream,
+    process::Command,
+    select,
+    time::{sleep_until, timeout_at},
+};
+
+const HELLO: &str = "icepipe-service";
+const ACCEPTED: &str = "accepted";
+const REJECTED: &str = "rejected";
+
+/// Where a service forwards its connections to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// `host:port` to connect to.
+    Tcp(String),
+    /// Unix domain socket, `unix:<path>`.
+    Unix(PathBuf),
+    /// Shell command wired to stdin and stdout, `exec:<command>`.
+    Exec(String),
+}
+impl Endpoint {
+    pub async fn open(&self) -> io::Result<AsyncPipeStream> {
+        match self {
+            Endpoint::Tcp(addr) => {
+                let (read, write) = TcpStream::connect(addr).await?.into_split();
+                Ok(AsyncPipeStream::new(read, write))
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let (read, write) = tokio::net::UnixStream::connect(path).await?.into_split();
+                Ok(AsyncPipeStream::new(read, write))
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => Err(io::ErrorKind::Unsupported.into()),
+            Endpoint::Exec(command) => {
+                let mut child = shell(command)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let stdin = child.stdin.take().expect("stdin is piped");
+                let stdout = child.stdout.take().expect("stdout is piped");
+                Ok(AsyncPipeStream::new(stdout, stdin))
+            }
+        }
+    }
+
+    /// Like [`Endpoint::open`], failing with [`ServiceError::Unreachable`]
+    /// if it takes longer than `timeout` too, e.g. a firewalled host
+    /// dropping the connection attempt.
+    pub async fn open_within(&self, timeout: Duration) -> ServiceResult<AsyncPipeStream> {
+        within(timeout, self.open()).await
+    }
+}
+
+/// Connects to `addr`, failing with [`ServiceError::Unreachable`] if it
+/// takes longer than `timeout`.
+pub async fn connect_tcp(addr: &str, timeout: Duration) -> ServiceResult<TcpStream> {
+    within(timeout, TcpStream::connect(addr)).await
+}
+
+async fn within<T>(
+    timeout: Duration,
+    open: impl Future<Output = io::Result<T>>,
+) -> ServiceResult<T> {
+    match tokio::time::timeout(timeout, open).await {
+        Ok(r) => r.map_err(ServiceError::Unreachable),
+        Err(_) => Err(ServiceError::Unreachable(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no answer within {timeout:?}"),
+        ))),
+    }
+}
+
+/// Tells the peer why, as a notice, and closes, so it sees more than the
+/// connection ending. Returns `e`.
+pub async fn refuse<S>(peer: &mut S, e: ServiceError) -> ServiceError
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    trace::warn!(target: logging::SERVICE, "{e}");
+    let _ = peer.notify(&e.to_string()).await;
+    let _ = peer.close().await;
+
+    e
+}
+impl FromStr for Endpoint {
+    type Err = ServiceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(path.into()));
+        }
+        if let Some(command) = s.strip_prefix("exec:") {
+            return Ok(Endpoint::Exec(command.to_owned()));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Endpoint::Tcp(s.to_owned()))
+            }
+            _ => Err(ServiceError::BadEndpoint(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Service {
+    pub endpoint: Endpoint,
+    /// Token the requesting side must present in its hello.
+    pub token: Option<String>,
+}
+impl From<Endpoint> for Service {
+    fn from(endpoint: Endpoint) -> Self {
+        Service {
+            endpoint,
+            token: None,
+        }
+    }
+}
+
+/// Services a peer accepts requests for, anything else is rejected.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceRegistry {
+    pub services: HashMap<String, Service>,
+    /// Bounds opening the endpoint of an accepted request, see
+    /// [`Endpoint::open_within`].
+    pub open_timeout: Option<Duration>,
+}
+impl ServiceRegistry {
+    pub fn new() -> ServiceRegistry {
+        Default::default()
+    }
+
+    pub fn add<S: Into<Service>>(&mut self, name: impl Into<String>, service: S) {
+        self.services.insert(name.into(), service.into());
+    }
+
+    /// Reads the hello of the requesting side and answers it, returning the
+    /// requested service if it was accepted.
+    pub async fn accept<S>(&self, peer: &mut S) -> ServiceResult<&Service>
+    where
+        S: PipeStream,
+        S::Error: Into<StreamError>,
+    {
+        let hello = recv(peer).await?;
+        let (name, token) = match hello.split('\0').collect::<Vec<_>>()[..] {
+            [HELLO, name] => (name, None),
+            [HELLO, name, token] => (name, Some(token)),
+            _ => return Err(ServiceError::BadMessage(hello)),
+        };
+
+        let service = match self.services.get(name) {
+            None => Err(Rejection::UnknownService),
+            Some(Service {
+                token: Some(expected),
+                ..
+            }) if !token.is_some_and(|token| token_matches(expected, token)) => {
+                Err(Rejection::Unauthorized)
+            }
+            Some(service) => Ok(service),
+        };
+
+        match service {
+            Ok(service) => {
+                trace::info!(target: logging::SERVICE, "Serving {name}");
+                send(peer, ACCEPTED).await?;
+                Ok(service)
+            }
+            Err(rejection) => {
+                trace::warn!(target: logging::SERVICE, "Rejected service {name:?}: {rejection}");
+                send(peer, &format!("{REJECTED}\0{}", rejection.code())).await?;
+                Err(ServiceError::Rejected(rejection))
+            }
+        }
+    }
+
+    /// Accepts the request and forwards the connection to the service
+    /// endpoint until either side closes. The peer is told when the endpoint
+    /// cannot be opened, see [`refuse`].
+    pub async fn serve<S>(&self, peer: &mut S) -> ServiceResult<()>
+    where
+        S: PipeStream,
+        S::Error: Into<StreamError>,
+    {
+        let service = self.accept(peer).await?;
+        let local = match self.open_timeout {
+            Some(timeout) => service.endpoint.open_within(timeout).await,
+            None => service
+                .endpoint
+                .open()
+                .await
+                .map_err(ServiceError::Unreachable),
+        };
+        let mut local = match local {
+            Ok(local) => local,
+            Err(e) => return Err(refuse(peer, e).await),
+        };
+        forward(peer, &mut local).await?;
+
+        Ok(())
+    }
+}
+
+/// Asks the peer for the service `name`, the connection is wired to it once
+/// this returns successfully.
+pub async fn request<S>(peer: &mut S, name: &str, token: Option<&str>) -> ServiceResult<()>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let hello = match token {
+        Some(token) => format!("{HELLO}\0{name}\0{token}"),
+        None => format!("{HELLO}\0{name}"),
+    };
+    send(peer, &hello).await?;
+
+    let answer = recv(peer).await?;
+    match answer.split_once('\0') {
+        None if answer == ACCEPTED => Ok(()),
+        Some((REJECTED, code)) => Err(ServiceError::Rejected(
+            Rejection::from_code(code).ok_or_else(|| ServiceError::BadMessage(answer.clone()))?,
+        )),
+        _ => Err(ServiceError::BadMessage(answer)),
+    }
+}
+
+/// How [`forward`] ended.
+#[derive(Clone, Debug, Default)]
+pub struct ForwardSummary {
+    /// Why each stream ended, taken before forwarding closes them, so the
+    /// side that stopped forwarding is the one with a reason.
+    pub a: Option<CloseReason>,
+    pub b: Option<CloseReason>,
+}
+
+/// Copies data between both streams until either side closes, then closes
+/// both, except a stream that failed, closing it would only wait for a peer
+/// that is gone.
+///
+/// A stream is only read from while the other one is
+/// [`writable`](crate::pipe_stream::Control::writable), so data for a slow peer waits at its
+/// source, e.g. behind TCP flow control, instead of in between, and the
+/// other direction keeps flowing meanwhile.
+///
+/// Once the peer of either stream asks to shut down, the other stream is no
+/// longer read from and both are closed, what was forwarded flushed first,
+/// failing with [`TimeoutError`] past the deadline. The stream asked through
+/// ends with [`CloseReason::RemoteRequested`]. After a shutdown this side
+/// requested through a stream, the other one is no longer read from either,
+/// while what the peer still sends is forwarded until it closes. Both are
+/// closed once the deadline passes, if the peer did not close by then.
+pub async fn forward<A, B>(a: &mut A, b: &mut B) -> Result<ForwardSummary, StreamError>
+where
+    A: PipeStream,
+    A::Error: Into<StreamError>,
+    B: PipeStream,
+    B::Error: Into<StreamError>,
+{
+    forward_until(a, b, pending()).await
+}
+
+/// Like [`forward`] but also stops once `stop` resolves, e.g. on a signal,
+/// closing both streams as if either side had closed. Data is never cut in
+/// between: what was read from one stream before `stop` resolved is sent to
+/// the other one and flushed by the close.
+pub async fn forward_until<A, B, F>(
+    a: &mut A,
+    b: &mut B,
+    stop: F,
+) -> Result<ForwardSummary, StreamError>
+where
+    A: PipeStream,
+    A::Error: Into<StreamError>,
+    B: PipeStream,
+    B::Error: Into<StreamError>,
+    F: Future<Output = ()>,
+{
+    let mut stop = pin!(stop);
+    while !a.rx_closed() && !b.rx_closed() {
+        if let Some(expires) = remote_shutdown(a).or(remote_shutdown(b)) {
+            trace::info!(target: logging::SERVICE, "Closing as the peer asked");
+            let summary = ForwardSummary {
+                a: remote_shutdown(a).map(|_| CloseReason::RemoteRequested),
+                b: remote_shutdown(b).map(|_| CloseReason::RemoteRequested),
+            };
+            let close = async {
+                a.close().await.map_err(Into::into)?;
+                b.close().await.map_err(Into::into)
+            };
+            timeout_at(expires.into(), close)
+                .await
+                .map_err(|_| TimeoutError)??;
+
+            return Ok(summary);
+        }
+
+        let requested = [a.shutdown_request(), b.shutdown_request()]
+            .into_iter()
+            .flatten()
+            .map(|request| request.expires())
+            .min();
+        // Half closed, only what the peer still sends is forwarded
+        let (read_a, read_b) = (local_shutdown(b).is_none(), local_shutdown(a).is_none());
+        let (a_writable, b_writable) = (a.writable(), b.writable());
+        select! {
+            _ = sleep_until_some(requested) => {
+                trace::warn!(target: logging::SERVICE, "Peer ignored the shutdown request");
+                break;
+            }
+            _ = stop.as_mut() => {
+                trace::info!(target: logging::SERVICE, "Stopped forwarding");
+                break;
+            }
+            value = async { b_writable.await; a.wait().await }, if read_a => {
+                let recv = a.then(&mut value.map_err(Into::into)?).await.map_err(Into::into)?;
+                if let Some(data) = recv {
+                    b.send(&data).await.map_err(Into::into)?;
+                }
+            }
+            value = async { a_writable.await; b.wait().await }, if read_b => {
+                let recv = b.then(&mut value.map_err(Into::into)?).await.map_err(Into::into)?;
+                if let Some(data) = recv {
+                    a.send(&data).await.map_err(Into::into)?;
+                }
+            },
+        }
+    }
+    let summary = ForwardSummary {
+        a: a.close_reason(),
+        b: b.close_reason(),
+    };
+    if !failed(&summary.a) {
+        a.close().await.map_err(Into::into)?;
+    }
+    if !failed(&summary.b) {
+        b.close().await.map_err(Into::into)?;
+    }
+
+    Ok(summary)
+}
+
+/// Like [`forward`] but data for `peer` goes through a [`QueuedStream`], so a
+/// peer link slower than `local` does not hold up receiving from the peer.
+/// Returns what the queue dropped.
+pub async fn forward_queued<P, L>(
+    peer: P,
+    local: &mut L,
+    config: QueueConfig,
+) -> Result<QueueStats, StreamError>
+where
+    P: Split,
+    P::Error: Into<StreamError>,
+    <P::ReadHalf as WaitThen>::Error: Into<StreamError>,
+    P::WriteHalf: 'static,
+    <P::WriteHalf as PipeWriteHalf>::Error: Into<StreamError>,
+    L: PipeStream,
+    L::Error: Into<StreamError>,
+{
+    let mut peer = QueuedStream::new(peer, config);
+    forward(&mut peer, local).await?;
+
+    Ok(peer.stats())
+}
+
+/// When a shutdown the peer of `stream` requested expires.
+fn remote_shutdown<S>(stream: &S) -> Option<Instant>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    stream
+        .shutdown_request()
+        .filter(|request| request.remote)
+        .map(|request| request.expires())
+}
+
+/// When a shutdown this side requested through `stream` expires.
+fn local_shutdown<S>(stream: &S) -> Option<Instant>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    stream
+        .shutdown_request()
+        .filter(|request| !request.remote)
+        .map(|request| request.expires())
+}
+
+async fn sleep_until_some(at: Option<Instant>) {
+    match at {
+        Some(at) => sleep_until(at.into()).await,
+        None => pending().await,
+    }
+}
+
+fn failed(reason: &Option<CloseReason>) -> bool {
+    reason.as_ref().is_some_and(|reason| !reason.is_clean())
+}
+
+async fn send<S>(peer: &mut S, message: &str) -> ServiceResult<()>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    peer.send(message.as_bytes()).await.map_err(Into::into)?;
+    Ok(())
+}
+
+async fn recv<S>(peer: &mut S) -> ServiceResult<String>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    loop {
+        if peer.rx_closed() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let mut value = peer.wait().await.map_err(Into::into)?;
+        let data: Option<Vec<u8>> = peer.then(&mut value).await.map_err(Into::into)?;
+        if let Some(data) = data {
+            return String::from_utf8(data).map_err(|e| {
+                ServiceError::BadMessage(String::from_utf8_lossy(e.as_bytes()).into())
+            });
+        }
+    }
+}
+
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    #[error("Unknown service")]
+    UnknownService,
+    #[error("Missing or wrong service token")]
+    Unauthorized,
+}
+impl Rejection {
+    fn code(self) -> &'static str {
+        match self {
+            Rejection::UnknownService => "unknown-service",
+            Rejection::Unauthorized => "unauthorized",
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Rejection> {
+        [Rejection::UnknownService, Rejection::Unauthorized]
+            .into_iter()
+            .find(|rejection| rejection.code() == code)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ServiceError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Timeout(#[from] TimeoutError),
+    #[error(transparent)]
+    SignalingError(SignalingError),
+    #[error(transparent)]
+    StreamError(StreamError),
+    #[error("Service rejected: {0}")]
+    Rejected(Rejection),
+    #[error("Unexpected service negotiation message {0:?}")]
+    BadMessage(String),
+    #[error("Bad service endpoint {0:?}, expected host:port, unix:<path> or exec:<command>")]
+    BadEndpoint(String),
+    #[error("forward target unreachable: {0}")]
+    Unreachable(io::Error),
+}
+impl From<SignalingError> for ServiceError {
+    fn from(value: SignalingError) -> Self {
+        match value {
+            SignalingError::Io(e) => e.into(),
+            SignalingError::Timeout(e) => e.into(),
+            e @ SignalingError::ProtocolError(_) => Self::SignalingError(e),
+        }
+    }
+}
+impl From<StreamError> for ServiceError {
+    fn from(value: StreamError) -> Self {
+        match value {
+            StreamError::Io(e) => e.into(),
+            StreamError::Timeout(e) => e.into(),
+            StreamError::SignalingError(e) => e.into(),
+            e @ (StreamError::Other(_)
+            | StreamError::TrafficLimit(_)
+            | StreamError::ClosedDirty(_)) => Self::StreamError(e),
+        }
+    }
+}
+pub type ServiceResult<T> = Result<T, ServiceError>;
+
+impl From<ServiceError> for StreamError {
+    fn from(value: ServiceError) -> Self {
+        match value {
+            ServiceError::Io(e) => e.into(),
+            ServiceError::Timeout(e) => e.into(),
+            ServiceError::SignalingError(e) => e.into(),
+            ServiceError::StreamError(e) => e,
+            e @ (ServiceError::Rejected(_)
+            | ServiceError::BadMessage(_)
+            | ServiceError::BadEndpoint(_)
+            | ServiceError::Unreachable(_)) => Self::Other(Box::new(e)),
+        }
+    }
+}
+
+/// Compares in time independent of where the token differs, so guessing it
+/// byte by byte from the time to reject is not possible. The length still
+/// shows.
+fn token_matches(expected: &str, token: &str) -> bool {
+    let (expected, token) = (expected.as_bytes(), token.as_bytes());
+    let diff = expected
+        .iter()
+        .zip(token)
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    expected.len() == token.len() && std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipe_stream::Control;
+    use futures::{
+        future::{ready, LocalBoxFuture},
+        FutureExt,
+    };
+    use std::time::Duration;
+    use tokio::{
+        sync::{mpsc, watch},
+        time::sleep,
+    };
+
+    #[test]
+    fn parse_endpoint() {
+        assert_eq!(
+            "127.0.0.1:22".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp("127.0.0.1:22".to_string())
+        );
+        assert_eq!(
+            "unix:/run/app.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Unix("/run/app.sock".into())
+        );
+        assert_eq!(
+            "exec:cat -".parse::<Endpoint>().unwrap(),
+            Endpoint::Exec("cat -".to_string())
+        );
+        assert!("localhost".parse::<Endpoint>().is_err());
+    }
+
+    /// Stream fed and drained through channels, writable when told so.
+    struct Mock {
+        rx: mpsc::UnboundedReceiver<Vec<u8>>,
+        tx: mpsc::UnboundedSender<Vec<u8>>,
+        writable: watch::Receiver<bool>,
+    }
+    impl WaitThen for Mock {
+        type Value = Option<Vec<u8>>;
+        type Output = Option<Vec<u8>>;
+        type Error = StreamError;
+
+        fn wait(&mut self) -> LocalBoxFuture<'_, Result<Self::Value, StreamError>> {
+            async move { Ok(self.rx.recv().await) }.boxed_local()
+        }
+
+        fn then<'a>(
+            &'a mut self,
+            value: &'a mut Self::Value,
+        ) -> LocalBoxFuture<'a, Result<Self::Output, StreamError>> {
+            ready(Ok(value.take())).boxed_local()
+        }
+    }
+    impl Control for Mock {
+        fn close(&mut self) -> LocalBoxFuture<'_, Result<(), StreamError>> {
+            ready(Ok(())).boxed_local()
+        }
+
+        fn rx_closed(&self) -> bool {
+            false
+        }
+
+        fn writable(&self) -> LocalBoxFuture<'static, ()> {
+            let mut writable = self.writable.clone();
+            async move {
+                let _ = writable.wait_for(|writable| *writable).await;
+            }
+            .boxed_local()
+        }
+    }
+    impl PipeStream for Mock {
+        fn send<'a>(&'a mut self, data: &'a [u8]) -> LocalBoxFuture<'a, Result<(), StreamError>> {
+            let _ = self.tx.send(data.to_owned());
+            ready(Ok(())).boxed_local()
+        }
+    }
+
+    /// The other ends of a [`Mock`].
+    struct Ends {
+        input: mpsc::UnboundedSender<Vec<u8>>,
+        output: mpsc::UnboundedReceiver<Vec<u8>>,
+        writable: watch::Sender<bool>,
+    }
+
+    fn mock(writable: bool) -> (Mock, Ends) {
+        let (in_tx, in_rx) = mpsc::unbounded_channel();
+        let (out_tx, out_rx) = mpsc::unbounded_channel();
+        let (writable_tx, writable_rx) = watch::channel(writable);
+        let mock = Mock {
+            rx: in_rx,
+            tx: out_tx,
+            writable: writable_rx,
+        };
+
+        let ends = Ends {
+            input: in_tx,
+            output: out_rx,
+            writable: writable_tx,
+        };
+
+        (mock, ends)
+    }
+
+    async fn forward_for(a: &mut Mock, b: &mut Mock, duration: Duration) {
+        select! {
+            r = forward(a, b) => panic!("forward ended: {r:?}"),
+            _ = sleep(duration) => {},
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_waits_for_writable() {
+        let (mut local, mut local_ends) = mock(true);
+        let (mut peer, mut peer_ends) = mock(false);
+        for i in 0..3 {
+            local_ends.input.send(vec![i]).unwrap();
+        }
+        peer_ends.input.send(b"reply".to_vec()).unwrap();
+
+        let wait = Duration::from_millis(100);
+        forward_for(&mut local, &mut peer, wait).await;
+        assert_eq!(local_ends.output.try_recv().unwrap(), b"reply");
+        assert!(peer_ends.output.try_recv().is_err());
+
+        peer_ends.writable.send_replace(true);
+        forward_for(&mut local, &mut peer, wait).await;
+        for i in 0..3 {
+            assert_eq!(peer_ends.output.try_recv().unwrap(), [i]);
+        }
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(token_matches("letmein", "letmein"));
+        assert!(token_matches("", ""));
+        assert!(!token_matches("letmein", "letmeout"));
+        assert!(!token_matches("letmein", "letme"));
+        assert!(!token_matches("letmein", ""));
+        assert!(!token_matches("letmein", "Letmein"));
+    }
+
+    #[test]
+    fn rejection_codes() {
+        for rejection in [Rejection::UnknownService, Rejection::Unauthorized] {
+            assert_eq!(Rejection::from_code(rejection.code()), Some(rejection));
+        }
+    }
+}
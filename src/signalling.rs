@@ -1,4 +1,12 @@
+#[cfg(feature = "crypto")]
+use crate::crypto_backend;
+#[cfg(feature = "ice-transport")]
+use crate::sdp::IceCandidateInit;
 use crate::{error::TimeoutError, pipe_stream::WaitThen};
+#[cfg(any(feature = "crypto", feature = "ice-transport"))]
+use crate::{logging, trace};
+#[cfg(feature = "crypto")]
+use base64::{prelude::BASE64_STANDARD, Engine};
 use futures::future::LocalBoxFuture;
 use std::io;
 
@@ -9,6 +17,56 @@ where
     fn send(&mut self, candidates: String) -> LocalBoxFuture<'_, Result<(), Self::Error>>;
 }
 
+/// How ICE parameters are exchanged over the signalling channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignallingFormat {
+    /// Candidates are trickled as they are gathered, icepipe peers only.
+    #[default]
+    Native,
+    /// A single SDP offer from the dialer answered by the listener once
+    /// gathering completes, understood by regular WebRTC endpoints. See
+    /// [`crate::sdp`].
+    Sdp,
+}
+impl SignallingFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            SignallingFormat::Native => "native",
+            SignallingFormat::Sdp => "sdp",
+        }
+    }
+}
+
+/// How trickled candidates are written with [`SignallingFormat::Native`].
+/// Both are understood when received.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CandidateEncoding {
+    /// The candidate line alone.
+    #[default]
+    Raw,
+    /// `RTCIceCandidateInit` JSON, as JavaScript signalling code passes them
+    /// around, see [`IceCandidateInit`]. The handshake and the closing
+    /// message stay raw.
+    Json,
+}
+impl CandidateEncoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            CandidateEncoding::Raw => "raw",
+            CandidateEncoding::Json => "json",
+        }
+    }
+
+    #[cfg(feature = "ice-transport")]
+    /// `candidate` as marshalled by `webrtc_ice`.
+    pub(crate) fn encode(self, candidate: String) -> String {
+        match self {
+            CandidateEncoding::Raw => candidate,
+            CandidateEncoding::Json => IceCandidateInit::new(&candidate).to_json(),
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum SignalingError {
     #[error(transparent)]
@@ -27,3 +85,102 @@ impl From<SignalingError> for std::io::Error {
         }
     }
 }
+
+/// How the roles assigned by the server are named in logs, errors,
+/// diagnostics and metric labels.
+#[cfg(any(feature = "crypto", feature = "ice-transport"))]
+pub(crate) fn role_name(dialer: bool) -> &'static str {
+    match dialer {
+        true => "dialer",
+        false => "listener",
+    }
+}
+
+#[cfg(any(feature = "crypto", feature = "ice-transport"))]
+/// Sent by a peer failing to authenticate the other in the key agreement
+/// before it gives up, read by the exchanges following the agreement on the
+/// other side, see [`Rejection`].
+const REJECTED: &str = "icepipe-rejected";
+
+/// The peer failed to authenticate this one in the key agreement while this
+/// one authenticated the peer, e.g. the key of this one is not among those
+/// the peer accepts. Peers predating the notice, or its authentication,
+/// leave without one.
+#[derive(thiserror::Error, Debug)]
+#[error("The peer rejected our identity, it does not accept our key or PSK")]
+pub struct PeerRejected;
+
+#[cfg(any(feature = "crypto", feature = "ice-transport"))]
+/// Tells the notice of a peer rejecting this one, see [`PeerRejected`].
+/// The notice is authenticated with a key derived from the agreement, which
+/// only the peer this one authenticated shares, so no one else on the path,
+/// e.g. the signalling server, can have a peer give up blaming its own key.
+/// Without the key, as by default, notices are ignored.
+#[derive(Clone, Default)]
+pub struct Rejection {
+    #[cfg(feature = "crypto")]
+    key: Option<[u8; 64]>,
+}
+#[cfg(any(feature = "crypto", feature = "ice-transport"))]
+impl Rejection {
+    #[cfg(feature = "crypto")]
+    pub(crate) fn new(basekey: &[u8]) -> Rejection {
+        Rejection {
+            key: Some(notice_key(basekey)),
+        }
+    }
+
+    /// What the peer rejecting the other sends, `basekey` as agreed with the
+    /// public key the rejected one sent.
+    #[cfg(feature = "crypto")]
+    pub(crate) fn notice(basekey: &[u8]) -> String {
+        let tag = crypto_backend::hmac_sha512_sign(&notice_key(basekey), REJECTED.as_bytes());
+        format!("{REJECTED}\0{}", BASE64_STANDARD.encode(tag))
+    }
+
+    /// Fails with [`PeerRejected`] on the notice of a peer that rejected
+    /// this one. One that fails authentication is handed on like any other
+    /// message.
+    pub(crate) fn check(&self, msg: &str) -> Result<(), SignalingError> {
+        let Some(tag) = msg
+            .strip_prefix(REJECTED)
+            .and_then(|tag| tag.strip_prefix('\0'))
+        else {
+            return Ok(());
+        };
+        if self.authentic(tag) {
+            return Err(SignalingError::ProtocolError(Box::new(PeerRejected)));
+        }
+
+        trace::warn!(target: logging::SIGNALLING, "Rejection notice failed authentication");
+        Ok(())
+    }
+
+    #[cfg(feature = "crypto")]
+    fn authentic(&self, tag: &str) -> bool {
+        let Some(key) = &self.key else {
+            return false;
+        };
+        BASE64_STANDARD.decode(tag).is_ok_and(|tag| {
+            crypto_backend::hmac_sha512_verify(key, REJECTED.as_bytes(), &tag).is_ok()
+        })
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn authentic(&self, _tag: &str) -> bool {
+        false
+    }
+}
+#[cfg(any(feature = "crypto", feature = "ice-transport"))]
+impl std::fmt::Debug for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Rejection")
+    }
+}
+
+#[cfg(feature = "crypto")]
+fn notice_key(basekey: &[u8]) -> [u8; 64] {
+    let mut key = [0; 64];
+    crypto_backend::hkdf_sha512(b"rejected", basekey, b"notice", &mut key);
+    key
+}
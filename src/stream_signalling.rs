@@ -0,0 +1,77 @@
+//! Signalling over a [`PipeStream`] the peers already share, see
+//! [`secure_existing`](crate::connect::secure_existing).
+
+use crate::{
+    pipe_stream::{PipeStream, StreamError, WaitThen},
+    signalling::{SignalingError, Signalling},
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use std::io;
+
+/// Each signalling message is a message of `stream`, which must keep
+/// message boundaries, e.g. an
+/// [`AsyncPipeStream`](crate::async_pipe_stream::AsyncPipeStream) with
+/// [`Framing::LengthPrefixed`](crate::async_pipe_stream::Framing::LengthPrefixed).
+pub struct StreamSignalling<S> {
+    stream: S,
+}
+impl<S> StreamSignalling<S>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    pub fn new(stream: S) -> StreamSignalling<S> {
+        StreamSignalling { stream }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+impl<S> Signalling for StreamSignalling<S>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    fn send(&mut self, msg: String) -> LocalBoxFuture<'_, Result<(), SignalingError>> {
+        async move {
+            let r = self.stream.send(msg.as_bytes()).await;
+            r.map_err(|e| e.into().into())
+        }
+        .boxed_local()
+    }
+}
+impl<S> WaitThen for StreamSignalling<S>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    type Value = S::Value;
+    type Output = Option<String>;
+    type Error = SignalingError;
+
+    /// Fails once the stream ended, there is nothing left to agree on.
+    fn wait(&mut self) -> LocalBoxFuture<'_, Result<Self::Value, SignalingError>> {
+        async move {
+            if self.stream.rx_closed() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let r = self.stream.wait().await;
+            r.map_err(|e| e.into().into())
+        }
+        .boxed_local()
+    }
+
+    fn then<'a>(
+        &'a mut self,
+        value: &'a mut Self::Value,
+    ) -> LocalBoxFuture<'a, Result<Self::Output, SignalingError>> {
+        async move {
+            let msg = self.stream.then(value).await.map_err(|e| e.into())?;
+            msg.map(String::from_utf8)
+                .transpose()
+                .map_err(|e| SignalingError::ProtocolError(Box::new(e)))
+        }
+        .boxed_local()
+    }
+}
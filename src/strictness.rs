@@ -0,0 +1,87 @@
+//! How much protocol weirdness from the peer or the signalling server is
+//! tolerated, see [`ConnectOptions::strictness`](crate::ConnectOptions::strictness).
+//!
+//! Each layer consults it where it would otherwise log and carry on. Under
+//! [`Strictness::Strict`] the attempt fails with the [`Violation`] instead,
+//! logged under the [`AUDIT`](crate::logging::AUDIT) target.
+
+use crate::signalling::SignalingError;
+#[cfg(any(
+    feature = "crypto",
+    feature = "ice-transport",
+    feature = "ws-signalling"
+))]
+use crate::{logging, trace};
+
+/// Remote candidates accepted under [`Strictness::Strict`], duplicates
+/// included.
+pub const MAX_CANDIDATES: usize = 32;
+/// Longest signalling message accepted under [`Strictness::Strict`], a
+/// description with [`MAX_CANDIDATES`] candidates fits.
+pub const MAX_SIGNALLING_MESSAGE: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strictness {
+    /// Logs and ignores what can be ignored, as peers of older versions or
+    /// flaky networks may send it.
+    #[default]
+    Lenient,
+    /// Fails on any [`Violation`], for listeners exposed to anyone.
+    Strict,
+}
+impl Strictness {
+    #[cfg(any(
+        feature = "crypto",
+        feature = "ice-transport",
+        feature = "ws-signalling"
+    ))]
+    /// `Err` under [`Strictness::Strict`], tolerated otherwise.
+    pub(crate) fn check(self, violation: impl FnOnce() -> Violation) -> Result<(), Violation> {
+        match self {
+            Strictness::Lenient => Ok(()),
+            Strictness::Strict => {
+                let violation = violation();
+                trace::warn!(target: logging::AUDIT, "Rejected: {violation}");
+                Err(violation)
+            }
+        }
+    }
+}
+
+/// Rule broken by the peer or the signalling server.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    #[error("Signalling message of {0} bytes, at most {MAX_SIGNALLING_MESSAGE} are accepted")]
+    OversizedSignalling(usize),
+    #[error("Peer predates role negotiation")]
+    LegacyHandshake,
+    #[error("Peer exceeded the signalling rate limit")]
+    CandidateRateLimit,
+    #[error("Peer sent more than {MAX_CANDIDATES} candidates")]
+    TooManyCandidates,
+    #[error("Peer sent the unparsable candidate {0:?}")]
+    UnparsableCandidate(String),
+    /// Identifiers other than those of WebRTC all read as `Unknown`.
+    #[error("Peer sent a message with the unexpected payload protocol identifier {0}")]
+    UnexpectedPpid(String),
+    #[error("Expected message {expected} from the peer, received {received}")]
+    NonceDesync { expected: u64, received: u64 },
+}
+impl From<Violation> for SignalingError {
+    fn from(value: Violation) -> Self {
+        SignalingError::ProtocolError(Box::new(value))
+    }
+}
+impl Violation {
+    #[cfg(feature = "ice-transport")]
+    /// The violation carried by `error`, which is given back otherwise.
+    pub(crate) fn downcast(error: SignalingError) -> Result<Violation, SignalingError> {
+        match error {
+            SignalingError::ProtocolError(e) => match e.downcast() {
+                Ok(violation) => Ok(*violation),
+                Err(e) => Err(SignalingError::ProtocolError(e)),
+            },
+            e => Err(e),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Peers for checking a setup without a second person at the other end: an
+//! [`echo_peer`] sends back every message and a [`discard_peer`] only counts
+//! them, while [`echo_check`] dials an echo peer and verifies what comes
+//! back.
+
+use crate::{
+    agreement::Authentication,
+    connect::ConnectOptions,
+    logging,
+    pipe_stream::{PipeStream, StreamError, StreamResult},
+    sctp::MAX_MESSAGE_SIZE,
+    trace,
+};
+use std::time::{Duration, Instant};
+
+/// What an [`echo_peer`] or a [`discard_peer`] received.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    pub messages: u64,
+    pub bytes: u64,
+    /// From connected until the peer closed.
+    pub elapsed: Duration,
+}
+
+/// Connects and sends every message back until the peer disconnects.
+pub async fn echo_peer<A: Authentication>(
+    options: ConnectOptions,
+    auth: A,
+) -> StreamResult<PeerStats> {
+    let mut peer = options.connect(auth).await?;
+    echo(&mut peer).await
+}
+
+/// Connects and counts the messages received until the peer disconnects.
+pub async fn discard_peer<A: Authentication>(
+    options: ConnectOptions,
+    auth: A,
+) -> StreamResult<PeerStats> {
+    let mut peer = options.connect(auth).await?;
+    discard(&mut peer).await
+}
+
+/// [`echo_peer`] over an established connection, closed once the peer
+/// closes.
+pub async fn echo<S>(peer: &mut S) -> StreamResult<PeerStats>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    serve(peer, true).await
+}
+
+/// [`discard_peer`] over an established connection, closed once the peer
+/// closes.
+pub async fn discard<S>(peer: &mut S) -> StreamResult<PeerStats>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    serve(peer, false).await
+}
+
+async fn serve<S>(peer: &mut S, echo: bool) -> StreamResult<PeerStats>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let started = Instant::now();
+    let mut stats = PeerStats::default();
+    while !peer.rx_closed() {
+        let mut value = peer.wait().await.map_err(Into::into)?;
+        let Some(data) = peer.then(&mut value).await.map_err(Into::into)? else {
+            continue;
+        };
+        stats.messages += 1;
+        stats.bytes += data.len() as u64;
+        if echo {
+            peer.send(&data).await.map_err(Into::into)?;
+        }
+    }
+    peer.close().await.map_err(Into::into)?;
+    stats.elapsed = started.elapsed();
+    trace::info!(target: logging::TESTING,
+        "Peer closed after {} messages, {} bytes",
+        stats.messages,
+        stats.bytes
+    );
+
+    Ok(stats)
+}
+
+/// Bytes sent ahead of those echoed by [`echo_check`], below what the
+/// association buffers so neither side waits on the other to read.
+const ECHO_WINDOW: u64 = 256 * 1024;
+
+/// How much [`echo_check`] sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EchoCheck {
+    /// Sent one at a time, each waiting for its echo, to time round trips.
+    pub probes: u32,
+    /// Sent as fast as the echo comes back, to time throughput.
+    pub bytes: u64,
+    /// At most [`MAX_MESSAGE_SIZE`], less what the encryption adds to each
+    /// message.
+    pub message_size: usize,
+}
+impl Default for EchoCheck {
+    fn default() -> Self {
+        EchoCheck {
+            probes: 5,
+            bytes: 16 << 20,
+            message_size: 4096,
+        }
+    }
+}
+
+/// How the echo of an [`echo_check`] came back, all of it verified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EchoReport {
+    /// Shortest round trip of the probes, `None` without probes.
+    pub rtt: Option<Duration>,
+    /// Echoed after the probes.
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+impl EchoReport {
+    /// Bytes per second each way.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Sends patterned data to an [`echo_peer`] and verifies the echo byte for
+/// byte, failing with [`EchoError`] on the first difference. The connection
+/// is left open.
+pub async fn echo_check<S>(peer: &mut S, check: EchoCheck) -> StreamResult<EchoReport>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    let message_size = check.message_size.clamp(1, MAX_MESSAGE_SIZE) as u64;
+    let mut offset = 0;
+    let mut rtt = None::<Duration>;
+    for _ in 0..check.probes {
+        let started = Instant::now();
+        peer.send(&pattern(offset, 64)).await.map_err(Into::into)?;
+        offset = echoed(peer, offset, offset + 64).await?;
+        let elapsed = started.elapsed();
+        rtt = Some(rtt.map_or(elapsed, |rtt| rtt.min(elapsed)));
+    }
+
+    let started = Instant::now();
+    let end = offset + check.bytes;
+    let (mut sent, mut received) = (offset, offset);
+    while received < end {
+        if sent < end && sent - received < ECHO_WINDOW {
+            let len = message_size.min(end - sent);
+            peer.send(&pattern(sent, len)).await.map_err(Into::into)?;
+            sent += len;
+            continue;
+        }
+        received = echoed(peer, received, sent).await?;
+    }
+
+    Ok(EchoReport {
+        rtt,
+        bytes: check.bytes,
+        elapsed: started.elapsed(),
+    })
+}
+
+/// Byte `offset` of what [`echo_check`] sends, a period of 251 so it never
+/// lines up with message boundaries.
+fn pattern(offset: u64, len: u64) -> Vec<u8> {
+    (offset..offset + len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Receives one message echoing the data at `offset`, returning the offset
+/// past it.
+async fn echoed<S>(peer: &mut S, offset: u64, sent: u64) -> StreamResult<u64>
+where
+    S: PipeStream,
+    S::Error: Into<StreamError>,
+{
+    loop {
+        if peer.rx_closed() {
+            return Err(echo_error(EchoError::Closed {
+                echoed: offset,
+                sent,
+            }));
+        }
+        let mut value = peer.wait().await.map_err(Into::into)?;
+        let Some(data) = peer.then(&mut value).await.map_err(Into::into)? else {
+            continue;
+        };
+        if offset + data.len() as u64 > sent {
+            return Err(echo_error(EchoError::Unexpected { offset }));
+        }
+        let expected = pattern(offset, data.len() as u64);
+        if let Some(at) = data.iter().zip(&expected).position(|(a, b)| a != b) {
+            return Err(echo_error(EchoError::Mismatch {
+                offset: offset + at as u64,
+            }));
+        }
+
+        return Ok(offset + data.len() as u64);
+    }
+}
+
+fn echo_error(e: EchoError) -> StreamError {
+    StreamError::Other(Box::new(e))
+}
+
+/// Why an [`echo_check`] failed, wrapped in [`StreamError::Other`].
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum EchoError {
+    #[error("Echo differs from what was sent at byte {offset}")]
+    Mismatch { offset: u64 },
+    #[error("Echo at byte {offset} holds more than was sent")]
+    Unexpected { offset: u64 },
+    #[error("Peer closed after echoing {echoed} of {sent} bytes")]
+    Closed { echoed: u64, sent: u64 },
+}
@@ -0,0 +1,95 @@
+//! Diagnostics go through `tracing` when the `tracing` feature is enabled and
+//! through `log` otherwise, and to the [`crate::logging`] hook either way.
+//! Every message names one of the targets of [`crate::logging`]. Spans are
+//! no-ops without the feature.
+
+#[cfg(all(feature = "tracing", feature = "ice-transport"))]
+pub(crate) use tracing::{Instrument, Span};
+
+macro_rules! event {
+    ($macro:ident, $level:ident, target: $target:expr, $($args:tt)+) => {{
+        $crate::logging::dispatch(
+            $crate::logging::Level::$level,
+            $target,
+            format_args!($($args)+),
+        );
+        #[cfg(feature = "tracing")]
+        tracing::$macro!(target: $target, $($args)+);
+        #[cfg(not(feature = "tracing"))]
+        log::$macro!(target: $target, $($args)+);
+    }};
+}
+pub(crate) use event;
+
+// Named apart from the `warn` lint attribute, which a plain import would be
+// ambiguous with
+macro_rules! warning {
+    ($($args:tt)+) => {
+        $crate::trace::event!(warn, Warn, $($args)+)
+    };
+}
+pub(crate) use warning as warn;
+
+macro_rules! info {
+    ($($args:tt)+) => {
+        $crate::trace::event!(info, Info, $($args)+)
+    };
+}
+pub(crate) use info;
+
+macro_rules! debug {
+    ($($args:tt)+) => {
+        $crate::trace::event!(debug, Debug, $($args)+)
+    };
+}
+pub(crate) use debug;
+
+#[cfg(feature = "ice-transport")]
+macro_rules! trace {
+    ($($args:tt)+) => {
+        $crate::trace::event!(trace, Trace, $($args)+)
+    };
+}
+#[cfg(feature = "ice-transport")]
+pub(crate) use trace;
+
+#[cfg(all(not(feature = "tracing"), feature = "ice-transport"))]
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Span;
+#[cfg(all(not(feature = "tracing"), feature = "ice-transport"))]
+impl Span {
+    pub(crate) fn current() -> Span {
+        Span
+    }
+
+    #[cfg(feature = "full")]
+    pub(crate) fn record<V>(&self, _field: &str, _value: V) -> &Self {
+        self
+    }
+
+    pub(crate) fn in_scope<F: FnOnce() -> T, T>(&self, f: F) -> T {
+        f()
+    }
+}
+
+#[cfg(all(not(feature = "tracing"), feature = "ice-transport"))]
+pub(crate) trait Instrument: Sized {
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+#[cfg(all(not(feature = "tracing"), feature = "ice-transport"))]
+impl<T> Instrument for T {}
+
+#[cfg(feature = "ice-transport")]
+macro_rules! info_span {
+    ($($args:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!($($args)*);
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::trace::Span;
+        span
+    }};
+}
+#[cfg(feature = "ice-transport")]
+pub(crate) use info_span;
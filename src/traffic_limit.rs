@@ -0,0 +1,199 @@
+//! Cap on how much a single session transfers, see
+//! [`ConnectOptions::traffic_limit`](crate::connect::ConnectOptions::traffic_limit).
+
+#[cfg(feature = "crypto")]
+use crate::{error::TrafficLimitExceeded, logging, trace};
+#[cfg(feature = "crypto")]
+use std::sync::{Arc, Mutex};
+
+/// Bytes of plaintext a session may transfer. Crossing `soft` logs a warning
+/// and notifies the peer, crossing `hard` closes the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrafficLimit {
+    pub soft: u64,
+    pub hard: u64,
+    pub counted: Counted,
+}
+impl TrafficLimit {
+    /// Counting both directions.
+    pub fn new(soft: u64, hard: u64) -> TrafficLimit {
+        TrafficLimit {
+            soft,
+            hard,
+            counted: Counted::Both,
+        }
+    }
+}
+
+/// Directions counted against a [`TrafficLimit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Counted {
+    /// Both directions combined.
+    #[default]
+    Both,
+    Sent,
+    Received,
+    /// Each direction on its own, the first to cross a limit crosses it for
+    /// the session.
+    Each,
+}
+
+#[cfg(feature = "crypto")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Sent,
+    Received,
+}
+
+/// Counts the traffic of both halves of a stream.
+#[cfg(feature = "crypto")]
+#[derive(Clone, Debug)]
+pub(crate) struct TrafficMeter(Arc<Mutex<Meter>>);
+
+#[cfg(feature = "crypto")]
+#[derive(Debug)]
+struct Meter {
+    limit: TrafficLimit,
+    sent: u64,
+    received: u64,
+    /// Soft limit notice not handed to the peer yet.
+    notice: Option<String>,
+    warned: bool,
+    exceeded: bool,
+}
+
+#[cfg(feature = "crypto")]
+impl TrafficMeter {
+    pub(crate) fn new(limit: TrafficLimit) -> TrafficMeter {
+        TrafficMeter(Arc::new(Mutex::new(Meter {
+            limit,
+            sent: 0,
+            received: 0,
+            notice: None,
+            warned: false,
+            exceeded: false,
+        })))
+    }
+
+    /// Counts `len` bytes, failing once they would take the session past the
+    /// hard limit. Bytes refused this way are not counted.
+    pub(crate) fn count(
+        &self,
+        direction: Direction,
+        len: usize,
+    ) -> Result<(), TrafficLimitExceeded> {
+        let mut meter = self.0.lock().unwrap();
+        let limit = meter.limit;
+        let exceeded = TrafficLimitExceeded { hard: limit.hard };
+        if meter.exceeded {
+            return Err(exceeded);
+        }
+
+        let counted = matches!(
+            (limit.counted, direction),
+            (Counted::Both | Counted::Each, _)
+                | (Counted::Sent, Direction::Sent)
+                | (Counted::Received, Direction::Received)
+        );
+        if !counted {
+            return Ok(());
+        }
+
+        let bytes = match (limit.counted, direction) {
+            (Counted::Each, Direction::Sent) => meter.sent,
+            (Counted::Each, Direction::Received) => meter.received,
+            _ => meter.sent + meter.received,
+        };
+        let bytes = bytes.saturating_add(len as u64);
+        if bytes > limit.hard {
+            meter.exceeded = true;
+            trace::warn!(target: logging::TRAFFIC_LIMIT,
+                "Session crossed its hard traffic limit of {} bytes",
+                limit.hard
+            );
+            return Err(exceeded);
+        }
+        match direction {
+            Direction::Sent => meter.sent += len as u64,
+            Direction::Received => meter.received += len as u64,
+        }
+
+        if bytes > limit.soft && !meter.warned {
+            meter.warned = true;
+            trace::warn!(target: logging::TRAFFIC_LIMIT,
+                "Session crossed its soft traffic limit, {bytes} of {} bytes",
+                limit.hard
+            );
+            meter.notice = Some(format!(
+                "Peer crossed its soft traffic limit, {bytes} of {} bytes",
+                limit.hard
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Notice for the peer, once after the soft limit was crossed.
+    pub(crate) fn take_notice(&self) -> Option<String> {
+        self.0.lock().unwrap().notice.take()
+    }
+
+    pub(crate) fn exceeded(&self) -> bool {
+        self.0.lock().unwrap().exceeded
+    }
+
+    /// Both directions, only those counted.
+    pub(crate) fn bytes(&self) -> u64 {
+        let meter = self.0.lock().unwrap();
+        meter.sent + meter.received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        let meter = TrafficMeter::new(TrafficLimit::new(10, 20));
+
+        meter.count(Direction::Sent, 10).unwrap();
+        assert_eq!(meter.take_notice(), None);
+        meter.count(Direction::Received, 5).unwrap();
+        assert!(meter.take_notice().is_some());
+        assert_eq!(meter.take_notice(), None);
+
+        assert!(meter.count(Direction::Sent, 6).is_err());
+        assert_eq!(meter.bytes(), 15);
+        // Stays closed
+        assert!(meter.count(Direction::Sent, 1).is_err());
+        assert!(meter.exceeded());
+    }
+
+    #[test]
+    fn one_direction() {
+        let meter = TrafficMeter::new(TrafficLimit {
+            counted: Counted::Sent,
+            ..TrafficLimit::new(10, 20)
+        });
+
+        meter.count(Direction::Received, 100).unwrap();
+        meter.count(Direction::Sent, 20).unwrap();
+        assert_eq!(meter.bytes(), 20);
+        assert!(meter.count(Direction::Sent, 1).is_err());
+    }
+
+    #[test]
+    fn each_direction() {
+        let meter = TrafficMeter::new(TrafficLimit {
+            counted: Counted::Each,
+            ..TrafficLimit::new(10, 20)
+        });
+
+        meter.count(Direction::Sent, 15).unwrap();
+        meter.count(Direction::Received, 20).unwrap();
+        assert_eq!(meter.bytes(), 35);
+        assert!(meter.count(Direction::Sent, 6).is_err());
+        assert!(meter.count(Direction::Received, 1).is_err());
+    }
+}
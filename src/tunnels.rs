@@ -0,0 +1,268 @@
+//! Several independent tunnels from one process, e.g. a daemon keeping a
+//! tunnel up per peer. Each [`Tunnels::add`]ed tunnel connects on its own
+//! channel, runs its session over the connection and reconnects once the
+//! session ends or the connection fails, after the backoff of the
+//! [`ReconnectPolicy`], without affecting the others.
+//! [`Tunnels::status`] gives a combined view.
+//!
+//! Like [`manager`](crate::manager), tunnels are driven by tasks spawned
+//! with [`tokio::task::spawn_local`] and must be added from within a
+//! [`tokio::task::LocalSet`].
+
+use crate::{
+    connect::{ConnectOptions, Connection},
+    logging,
+    manager::{
+        Link, LinkEvent, LinkHealth, LinkSession, LinkState, PeerId, PeerSpec, ReconnectPolicy,
+        SessionEnd,
+    },
+    pipe_stream::StreamResult,
+    trace,
+};
+use futures::future::LocalBoxFuture;
+use std::{collections::BTreeMap, fmt, future::Future, rc::Rc};
+use tokio::{
+    select,
+    sync::{watch, Notify},
+};
+
+/// Run over each connection of a tunnel, which reconnects once it returns,
+/// after the backoff of a failed attempt. The session owns the connection
+/// and should close it.
+pub type Session = Rc<dyn Fn(Connection) -> LocalBoxFuture<'static, StreamResult<()>>>;
+
+/// Handle to a tunnel, cheap to clone. Dropping it leaves the tunnel
+/// running, see [`TunnelHandle::close`].
+#[derive(Clone)]
+pub struct TunnelHandle {
+    name: String,
+    peer: PeerId,
+    state: watch::Receiver<LinkState>,
+    health: watch::Receiver<LinkHealth>,
+    close: Rc<Notify>,
+}
+impl TunnelHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn peer(&self) -> &PeerId {
+        &self.peer
+    }
+
+    pub fn state(&self) -> watch::Receiver<LinkState> {
+        self.state.clone()
+    }
+
+    pub fn health(&self) -> watch::Receiver<LinkHealth> {
+        self.health.clone()
+    }
+
+    /// Closes the connection, if any, and stops reconnecting.
+    pub fn close(&self) {
+        self.close.notify_one();
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(*self.state.borrow(), LinkState::Failed | LinkState::Closed)
+    }
+
+    fn status(&self) -> TunnelStatus {
+        TunnelStatus {
+            name: self.name.clone(),
+            peer: self.peer.clone(),
+            state: *self.state.borrow(),
+            health: self.health.borrow().clone(),
+        }
+    }
+}
+
+/// A tunnel as of [`Tunnels::status`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TunnelStatus {
+    pub name: String,
+    pub peer: PeerId,
+    pub state: LinkState,
+    pub health: LinkHealth,
+}
+
+/// Every tunnel, ordered by name. Displayed one tunnel per line.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TunnelsStatus(pub Vec<TunnelStatus>);
+impl TunnelsStatus {
+    /// Tunnels currently connected.
+    pub fn connected(&self) -> usize {
+        self.0
+            .iter()
+            .filter(|tunnel| tunnel.state == LinkState::Connected)
+            .count()
+    }
+}
+impl fmt::Display for TunnelsStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for tunnel in &self.0 {
+            write!(
+                f,
+                "{}: {:?}, {} connects",
+                tunnel.name, tunnel.state, tunnel.health.connects
+            )?;
+            if let Some(since) = tunnel.health.connected_since {
+                write!(f, ", up {:.0?}", since.elapsed())?;
+            }
+            if tunnel.health.failures > 0 {
+                write!(f, ", {} failures", tunnel.health.failures)?;
+            }
+            if let Some(error) = &tunnel.health.last_error {
+                write!(f, ", last error: {error}")?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Tunnels {
+    template: ConnectOptions,
+    policy: ReconnectPolicy,
+    tunnels: BTreeMap<String, TunnelHandle>,
+    changed: watch::Sender<()>,
+}
+impl Tunnels {
+    /// Every tunnel connects with a copy of `template`, only the channel and
+    /// the candidate cache are replaced, see
+    /// [`ConnectionManager::new`](crate::manager::ConnectionManager::new).
+    pub fn new(template: ConnectOptions, policy: ReconnectPolicy) -> Tunnels {
+        Tunnels {
+            template,
+            policy,
+            tunnels: Default::default(),
+            changed: watch::channel(()).0,
+        }
+    }
+
+    /// Starts connecting right away. Fails if a tunnel of that name is still
+    /// running, one that was closed or gave up is replaced.
+    pub fn add<F, Fut>(
+        &mut self,
+        name: impl Into<String>,
+        spec: PeerSpec,
+        session: F,
+    ) -> Result<TunnelHandle, TunnelError>
+    where
+        F: Fn(Connection) -> Fut + 'static,
+        Fut: Future<Output = StreamResult<()>> + 'static,
+    {
+        let name = name.into();
+        if self
+            .tunnels
+            .get(&name)
+            .is_some_and(|tunnel| !tunnel.is_finished())
+        {
+            return Err(TunnelError::Exists(name));
+        }
+
+        let (state_tx, state) = watch::channel(LinkState::Idle);
+        let (health_tx, health) = watch::channel(LinkHealth::default());
+        let close = Rc::new(Notify::new());
+        let supervisor = Link {
+            name: name.clone(),
+            spec: spec.clone(),
+            template: self.template.clone(),
+            policy: self.policy,
+            state: state_tx,
+            health: health_tx,
+            close: close.clone(),
+            changed: Some(self.changed.clone()),
+            log,
+        };
+        let session: Session = Rc::new(move |connection| Box::pin(session(connection)));
+        tokio::task::spawn_local(supervisor.run(session));
+
+        let tunnel = TunnelHandle {
+            name: name.clone(),
+            peer: spec.id(),
+            state,
+            health,
+            close,
+        };
+        self.tunnels.insert(name, tunnel.clone());
+        self.changed.send_replace(());
+
+        Ok(tunnel)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TunnelHandle> {
+        self.tunnels.get(name)
+    }
+
+    /// Closes the tunnel and forgets it.
+    pub fn remove(&mut self, name: &str) -> Option<TunnelHandle> {
+        let tunnel = self.tunnels.remove(name)?;
+        tunnel.close();
+        self.changed.send_replace(());
+        Some(tunnel)
+    }
+
+    pub fn tunnels(&self) -> impl Iterator<Item = &TunnelHandle> {
+        self.tunnels.values()
+    }
+
+    pub fn status(&self) -> TunnelsStatus {
+        TunnelsStatus(self.tunnels().map(TunnelHandle::status).collect())
+    }
+
+    /// Changes whenever a tunnel is added, removed or changes state.
+    pub fn watch(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    /// Closes every tunnel and waits for them to finish.
+    pub async fn close(&mut self) {
+        for tunnel in self.tunnels.values() {
+            tunnel.close();
+        }
+        for tunnel in self.tunnels.values() {
+            // The supervisor only drops the sender once finished
+            let _ = tunnel
+                .state()
+                .wait_for(|state| matches!(state, LinkState::Failed | LinkState::Closed))
+                .await;
+        }
+    }
+}
+
+impl LinkSession for Session {
+    fn run<'a>(
+        &'a mut self,
+        connection: Connection,
+        close: &'a Notify,
+    ) -> LocalBoxFuture<'a, SessionEnd> {
+        Box::pin(async move {
+            // Dropping the session drops the connection
+            select! {
+                r = self(connection) => SessionEnd::Lost(match r {
+                    Ok(()) => "Session ended".to_string(),
+                    Err(e) => e.to_string(),
+                }),
+                _ = close.notified() => SessionEnd::Closed,
+            }
+        })
+    }
+}
+
+fn log(name: &str, event: LinkEvent<'_>) {
+    match event {
+        LinkEvent::Connected => trace::info!(target: logging::TUNNELS, "Tunnel {} connected", name),
+        LinkEvent::Lost(error) => {
+            trace::warn!(target: logging::TUNNELS, "Tunnel {} lost: {}", name, error)
+        }
+        LinkEvent::Closed => trace::info!(target: logging::TUNNELS, "Tunnel {} closed", name),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TunnelError {
+    #[error("A tunnel named {0} is already running")]
+    Exists(String),
+}
@@ -1,32 +1,134 @@
 use crate::{
     error::TimeoutError,
-    ping::{MustPing, Ping},
+    logging,
+    ping::{MustPing, Ping, PingConfig},
     pipe_stream::WaitThen,
+    resolver::{resolve, Resolver},
     signalling::{SignalingError, Signalling},
+    strictness::{Strictness, Violation, MAX_SIGNALLING_MESSAGE},
+    trace,
 };
-use futures::{future::LocalBoxFuture, FutureExt, SinkExt, StreamExt};
-use std::io;
-use tokio::{net::TcpStream, select};
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use futures::{
+    future::{poll_fn, LocalBoxFuture},
+    FutureExt, SinkExt, StreamExt,
+};
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, select, time::timeout};
+use tokio_tungstenite::{client_async_tls, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 use url::Url;
 
+/// Default [`WebsocketConfig::send_timeout`].
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default [`WebsocketConfig::send_queue`].
+pub const SEND_QUEUE: usize = 16;
+
+/// Idle time before TCP probes the signalling server, which notices a dead
+/// one when pings are rare or disabled, see [`PingConfig`].
+pub const KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// How [`Websocket::connect`] talks to the signalling server.
+#[derive(Clone, Debug)]
+pub struct WebsocketConfig {
+    /// Under [`Strictness::Strict`] messages longer than
+    /// [`MAX_SIGNALLING_MESSAGE`] fail.
+    pub strictness: Strictness,
+    pub ping: PingConfig,
+    /// How long the server may take to accept what is queued before sending
+    /// fails with [`WebsocketError::SendTimeout`], `None` waits indefinitely.
+    /// An overloaded server is reported instead of stalling ICE, whose
+    /// gathered candidates would meanwhile overflow
+    /// [`IceConfig::candidate_queue`](crate::ice::IceConfig::candidate_queue).
+    pub send_timeout: Option<Duration>,
+    /// Frames left queued by dropped sends before a send waits for the
+    /// queue to drain.
+    pub send_queue: usize,
+    /// Resolves the server host, which TLS and the `Host` header still
+    /// name, [`SystemResolver`](crate::resolver::SystemResolver) if `None`.
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// Retries a `wss` URL over plain `ws` when the TLS handshake is
+    /// refused, by a certificate failing verification or a fatal alert, e.g.
+    /// on networks intercepting it. A connection reset or cut short is not
+    /// retried, anyone on the path could cause it. Not
+    /// safe in general: anyone on the path then sees the channel and the
+    /// messages, and may drop, delay or replace them. Only the key agreement
+    /// and what is encrypted end to end stay protected, which is why
+    /// [`ConnectOptions`](crate::ConnectOptions) refuses it short of key
+    /// authentication.
+    pub tls_fallback: bool,
+}
+impl Default for WebsocketConfig {
+    fn default() -> Self {
+        WebsocketConfig {
+            strictness: Default::default(),
+            ping: Default::default(),
+            send_timeout: Some(SEND_TIMEOUT),
+            send_queue: SEND_QUEUE,
+            resolver: None,
+            tls_fallback: false,
+        }
+    }
+}
+
 pub struct Websocket {
     ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    /// Every frame sent goes through here, pings and pongs sent by
+    /// [`WaitThen::then`] included, see [`Websocket::send_queued`].
+    outgoing: VecDeque<Message>,
+    send_timeout: Option<Duration>,
+    send_queue: usize,
     ping: Ping,
+    strictness: Strictness,
+    /// How long opening the websocket took.
+    pub opened_in: Duration,
+    /// How long the server took to assign the role once open.
+    pub role_in: Duration,
+    /// Whether TLS failed and the websocket is plain, see
+    /// [`WebsocketConfig::tls_fallback`].
+    pub fell_back: bool,
 }
 unsafe impl Send for Websocket {}
 impl Websocket {
     pub async fn new(url: Url) -> WebsocketResult<(Self, bool)> {
-        let (mut ws, _) = connect_async(url).await?;
+        Websocket::connect(url, WebsocketConfig::default()).await
+    }
+
+    /// The server must assign `DIALER` or `LISTENER` first, anything else
+    /// fails, see [`parse_role`].
+    pub async fn connect(url: Url, config: WebsocketConfig) -> WebsocketResult<(Self, bool)> {
+        let host = url.host_str().unwrap_or_default().to_owned();
+        let started = Instant::now();
+        let (mut ws, fell_back) = match open(&url, &config).await {
+            Err(e) if config.tls_fallback && url.scheme() == "wss" && tls_failure(&e) => {
+                let mut plain = url;
+                plain.set_scheme("ws").expect("wss and ws are both special");
+                trace::warn!(
+                    target: logging::SIGNALLING,
+                    "TLS to {host} failed, falling back to unencrypted {plain}: {e}"
+                );
+                (open(&plain, &config).await?, true)
+            }
+            r => (r?, false),
+        };
+        let opened_in = started.elapsed();
+        trace::debug!(target: logging::SIGNALLING, "Connected to {host}");
         let peer_type = ws
             .next()
             .await
             .ok_or(TungsteniteError::ConnectionClosed)??;
+        let role_in = started.elapsed() - opened_in;
 
         let dialer = match peer_type {
             Message::Text(msg) => {
-                log::info!("User type {:?}", msg);
-                msg == "DIALER"
+                trace::info!(target: logging::SIGNALLING, "User type {:?}", msg);
+                parse_role(&msg)?
             }
             x => {
                 return Err(ProtocolError::Unexpected(
@@ -39,16 +141,118 @@ impl Websocket {
         Ok((
             Websocket {
                 ws,
-                ping: Default::default(),
+                outgoing: VecDeque::new(),
+                send_timeout: config.send_timeout,
+                send_queue: config.send_queue.max(1),
+                ping: Ping::with_config(config.ping),
+                strictness: config.strictness,
+                opened_in,
+                role_in,
+                fell_back,
             },
             dialer,
         ))
     }
+
+    /// Sends `msg` after whatever is still queued, see
+    /// [`Websocket::send_queued`]. Waits for the queue to drain first once
+    /// it holds [`WebsocketConfig::send_queue`] frames.
+    async fn send_in_order(&mut self, msg: Message) -> WebsocketResult<()> {
+        if self.outgoing.len() >= self.send_queue {
+            self.send_queued().await?;
+        }
+        self.outgoing.push_back(msg);
+        self.send_queued().await
+    }
+
+    /// Hands the queued frames to the websocket in order and flushes them,
+    /// within [`WebsocketConfig::send_timeout`]. A frame leaves the queue
+    /// once the websocket buffered it, so a send dropped halfway is
+    /// completed by the next one instead of being lost or overtaken.
+    async fn send_queued(&mut self) -> WebsocketResult<()> {
+        let send = async {
+            while !self.outgoing.is_empty() {
+                poll_fn(|cx| self.ws.poll_ready_unpin(cx)).await?;
+                let msg = self.outgoing.pop_front().unwrap();
+                self.ws.start_send_unpin(msg)?;
+            }
+            self.ws.flush().await
+        };
+        match self.send_timeout {
+            Some(limit) => timeout(limit, send).await.map_err(|_| {
+                trace::warn!(
+                    target: logging::SIGNALLING,
+                    "Signalling server accepted nothing for {limit:?}"
+                );
+                WebsocketError::SendTimeout(limit)
+            })??,
+            None => send.await?,
+        }
+
+        Ok(())
+    }
 }
+async fn open(
+    url: &Url,
+    config: &WebsocketConfig,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, TungsteniteError> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or_default();
+    let addrs = resolve(config.resolver.as_ref(), host).await?;
+    let addrs = addrs.into_iter().map(|ip| SocketAddr::new(ip, port));
+    let tcp = TcpStream::connect(&addrs.collect::<Vec<_>>()[..]).await?;
+    SockRef::from(&tcp).set_tcp_keepalive(&TcpKeepalive::new().with_time(KEEPALIVE))?;
+    let (ws, _) = client_async_tls(url.clone(), tcp).await?;
+
+    Ok(ws)
+}
+
+/// Whether the server, or whoever intercepts it, refused the TLS handshake:
+/// a certificate failing verification or a fatal alert. tokio-tungstenite
+/// hands these over as the rustls error inside an I/O one. Connections
+/// merely reset or cut short are not, any attacker on the path can cause
+/// those to force the fallback.
+fn tls_failure(e: &TungsteniteError) -> bool {
+    let TungsteniteError::Io(e) = e else {
+        return false;
+    };
+    matches!(
+        e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()),
+        Some(
+            rustls::Error::AlertReceived(_)
+                | rustls::Error::InvalidCertificateEncoding
+                | rustls::Error::InvalidCertificateSignatureType
+                | rustls::Error::InvalidCertificateSignature
+                | rustls::Error::InvalidCertificateData(_)
+                | rustls::Error::NoCertificatesPresented
+        )
+    )
+}
+
+/// Whether the role message makes this side the dialer. Servers answering
+/// with an error instead, e.g. `ERROR channel full`, fail with
+/// [`ProtocolError::ServerError`].
+pub fn parse_role(msg: &str) -> Result<bool, ProtocolError> {
+    match msg {
+        "DIALER" => Ok(true),
+        "LISTENER" => Ok(false),
+        msg => match ERROR_PREFIXES
+            .iter()
+            .find_map(|prefix| msg.strip_prefix(prefix))
+        {
+            Some(reason) => Err(ProtocolError::ServerError(reason.trim().to_owned())),
+            None => Err(ProtocolError::UnexpectedRoleMessage(msg.to_owned())),
+        },
+    }
+}
+
+/// How servers known to icepipe start an error sent in place of the role.
+const ERROR_PREFIXES: [&str; 3] = ["ERROR ", "ERROR:", "error:"];
+
 impl Signalling for Websocket {
-    fn send(&mut self, msg: String) -> LocalBoxFuture<WebsocketResult<()>> {
+    fn send(&mut self, msg: String) -> LocalBoxFuture<'_, WebsocketResult<()>> {
         Box::pin(async move {
-            self.ws.send(Message::Text(msg)).await?;
+            self.send_in_order(Message::Text(msg)).await?;
 
             Ok(())
         })
@@ -59,8 +263,14 @@ impl WaitThen for Websocket {
     type Output = Option<String>;
     type Error = WebsocketError;
 
+    /// Cancel safe, partial frames are buffered by the websocket and a ping
+    /// stays due until [`WaitThen::then`] sends it.
     fn wait(&mut self) -> LocalBoxFuture<'_, WebsocketResult<Self::Value>> {
         async move {
+            // Frames left by a dropped send go out before anything else
+            if !self.outgoing.is_empty() {
+                self.send_queued().await?;
+            }
             select! {
                 msg = self.ws.next() => {
                     let msg = msg.ok_or(TungsteniteError::ConnectionClosed)??;
@@ -83,10 +293,16 @@ impl WaitThen for Websocket {
                 WebsocketValue::Incoming(msg) => {
                     let msg = std::mem::replace(msg, Message::Text(Default::default()));
                     let candidate = match msg {
-                        Message::Text(candidate) => candidate,
+                        Message::Text(candidate) => {
+                            if candidate.len() > MAX_SIGNALLING_MESSAGE {
+                                self.strictness
+                                    .check(|| Violation::OversizedSignalling(candidate.len()))?;
+                            }
+                            candidate
+                        }
                         Message::Ping(a) => {
                             self.ping.received_pong();
-                            self.ws.send(Message::Pong(a)).await?;
+                            self.send_in_order(Message::Pong(a)).await?;
                             return Ok(None);
                         }
                         Message::Pong(_) => {
@@ -104,7 +320,7 @@ impl WaitThen for Websocket {
                 }
                 WebsocketValue::MustPing(_) => {
                     self.ping.sent_ping();
-                    self.ws.send(Message::Ping(vec![])).await?;
+                    self.send_in_order(Message::Ping(vec![])).await?;
                     Ok(None)
                 }
             }
@@ -131,6 +347,10 @@ pub enum WebsocketError {
     WebsocketError(#[from] TungsteniteError),
     #[error("Ping timeout")]
     Timeout(#[from] TimeoutError),
+    #[error(transparent)]
+    Violation(#[from] Violation),
+    #[error("Signalling server accepted nothing for {0:?}, overloaded?")]
+    SendTimeout(Duration),
 }
 impl From<WebsocketError> for SignalingError {
     fn from(value: WebsocketError) -> Self {
@@ -138,6 +358,8 @@ impl From<WebsocketError> for SignalingError {
             WebsocketError::ProtocolError(e) => e.into(),
             WebsocketError::WebsocketError(e) => e.into(),
             WebsocketError::Timeout(e) => e.into(),
+            WebsocketError::Violation(e) => e.into(),
+            e @ WebsocketError::SendTimeout(_) => io::Error::new(io::ErrorKind::TimedOut, e).into(),
         }
     }
 }
@@ -175,6 +397,10 @@ impl From<TungsteniteError> for SignalingError {
 pub enum ProtocolError {
     #[error("Expected {0:?} but got {0:?}")]
     Unexpected(Message, Expected),
+    #[error("Signalling server sent {0:?} instead of DIALER or LISTENER")]
+    UnexpectedRoleMessage(String),
+    #[error("Signalling server refused the connection: {0}")]
+    ServerError(String),
 }
 impl From<ProtocolError> for SignalingError {
     fn from(value: ProtocolError) -> Self {
@@ -195,3 +421,268 @@ impl std::fmt::Display for Expected {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::StaticResolver;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use tokio::{io::AsyncWriteExt, net::TcpListener, time::timeout};
+    use tokio_tungstenite::{
+        accept_async, accept_hdr_async, tungstenite::handshake::server::Request,
+    };
+
+    /// Serves a single client, sending it `messages`.
+    async fn server(messages: Vec<String>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/channel", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(tcp).await.unwrap();
+            for msg in messages {
+                ws.send(Message::Text(msg)).await.unwrap();
+            }
+            while let Some(Ok(_)) = ws.next().await {}
+        });
+        url.parse().unwrap()
+    }
+
+    /// Assigns a role and then never reads.
+    async fn stalled_server() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/channel", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(tcp).await.unwrap();
+            ws.send(Message::Text("DIALER".to_string())).await.unwrap();
+            std::future::pending::<()>().await;
+            drop(ws);
+        });
+        url.parse().unwrap()
+    }
+
+    async fn recv(ws: &mut Websocket) -> WebsocketResult<String> {
+        loop {
+            let mut value = ws.wait().await?;
+            if let Some(msg) = ws.then(&mut value).await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    #[test]
+    fn roles() {
+        assert!(parse_role("DIALER").unwrap());
+        assert!(!parse_role("LISTENER").unwrap());
+        for msg in ["", "dialer", "DIALER ", "OBSERVER", "channel full"] {
+            assert!(matches!(
+                parse_role(msg),
+                Err(ProtocolError::UnexpectedRoleMessage(role)) if role == msg
+            ));
+        }
+        for msg in [
+            "ERROR channel full",
+            "ERROR: channel full",
+            "error: channel full",
+        ] {
+            assert!(matches!(
+                parse_role(msg),
+                Err(ProtocolError::ServerError(reason)) if reason == "channel full"
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_role() {
+        for strictness in [Strictness::Lenient, Strictness::Strict] {
+            let url = server(vec!["OBSERVER".to_string()]).await;
+            let config = WebsocketConfig {
+                strictness,
+                ..Default::default()
+            };
+            let r = Websocket::connect(url, config).await;
+            assert!(matches!(
+                r,
+                Err(WebsocketError::ProtocolError(ProtocolError::UnexpectedRoleMessage(role)))
+                    if role == "OBSERVER"
+            ));
+        }
+
+        let url = server(vec!["ERROR channel full".to_string()]).await;
+        let r = Websocket::new(url).await;
+        assert!(matches!(
+            r,
+            Err(WebsocketError::ProtocolError(ProtocolError::ServerError(reason)))
+                if reason == "channel full"
+        ));
+
+        let url = server(vec!["LISTENER".to_string()]).await;
+        let (_, dialer) = Websocket::new(url).await.unwrap();
+        assert!(!dialer);
+    }
+
+    #[tokio::test]
+    async fn ping_interval() {
+        let wait = Duration::from_millis(300);
+        let config = |ping| WebsocketConfig {
+            ping,
+            ..Default::default()
+        };
+
+        let url = server(vec!["DIALER".to_string()]).await;
+        let every = PingConfig::every(Duration::from_millis(50));
+        let (mut ws, _) = Websocket::connect(url, config(every)).await.unwrap();
+        let value = timeout(wait, ws.wait()).await.unwrap().unwrap();
+        assert!(matches!(value, WebsocketValue::MustPing(_)));
+
+        let url = server(vec!["DIALER".to_string()]).await;
+        let (mut ws, _) = Websocket::connect(url, config(PingConfig::DISABLED))
+            .await
+            .unwrap();
+        assert!(timeout(wait, ws.wait()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_message() {
+        let oversized = "a".repeat(MAX_SIGNALLING_MESSAGE + 1);
+        let messages = vec!["DIALER".to_string(), oversized.clone()];
+
+        let url = server(messages.clone()).await;
+        let (mut ws, _) = Websocket::new(url).await.unwrap();
+        assert_eq!(recv(&mut ws).await.unwrap(), oversized);
+
+        let url = server(messages).await;
+        let config = WebsocketConfig {
+            strictness: Strictness::Strict,
+            ..Default::default()
+        };
+        let (mut ws, _) = Websocket::connect(url, config).await.unwrap();
+        assert!(matches!(
+            recv(&mut ws).await,
+            Err(WebsocketError::Violation(Violation::OversizedSignalling(n))) if n == oversized.len()
+        ));
+    }
+
+    #[tokio::test]
+    async fn slow_server() {
+        let config = WebsocketConfig {
+            send_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (mut ws, _) = Websocket::connect(stalled_server().await, config)
+            .await
+            .unwrap();
+
+        // Sends complete until the socket buffers fill up
+        let candidate = "a".repeat(64 << 10);
+        let mut r = Ok(());
+        for _ in 0..1024 {
+            r = ws.send(candidate.clone()).await;
+            if r.is_err() {
+                break;
+            }
+        }
+        let e = r.unwrap_err();
+        assert!(matches!(e, WebsocketError::SendTimeout(_)), "{e}");
+        let e = SignalingError::from(e);
+        assert!(matches!(e, SignalingError::Io(ref e) if e.kind() == io::ErrorKind::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn resolved_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host = tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let mut host = None;
+            #[allow(clippy::result_large_err)]
+            let mut ws = accept_hdr_async(tcp, |request: &Request, response| {
+                host = request.headers().get("host").cloned();
+                Ok(response)
+            })
+            .await
+            .unwrap();
+            ws.send(Message::Text("DIALER".to_string())).await.unwrap();
+            host
+        });
+
+        let mut resolver = StaticResolver::new();
+        resolver.add("signalling.internal", Ipv4Addr::LOCALHOST.into());
+        let config = WebsocketConfig {
+            resolver: Some(Arc::new(resolver)),
+            ..Default::default()
+        };
+        let url = format!("ws://signalling.internal:{port}/channel");
+        let (_, dialer) = Websocket::connect(url.parse().unwrap(), config)
+            .await
+            .unwrap();
+        assert!(dialer);
+        // Connected by address, still named by host
+        let host = host.await.unwrap().unwrap();
+        assert_eq!(host, format!("signalling.internal:{port}").as_str());
+    }
+
+    #[tokio::test]
+    async fn tls_fallback() {
+        // Speaks plain ws only. Refuses the TLS handshake of each wss client
+        // with a fatal alert if `refuse`, cuts the connection otherwise.
+        async fn server(refuse: bool) -> u16 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                loop {
+                    let (mut tcp, _) = listener.accept().await.unwrap();
+                    let mut first = [0; 1];
+                    tcp.peek(&mut first).await.unwrap();
+                    if first[0] == 0x16 {
+                        if refuse {
+                            // Alert, TLS 1.2, 2 bytes, fatal handshake_failure
+                            let alert = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+                            tcp.write_all(&alert).await.unwrap();
+                        }
+                        continue;
+                    }
+                    let Ok(mut ws) = accept_async(tcp).await else {
+                        continue;
+                    };
+                    ws.send(Message::Text("LISTENER".to_string()))
+                        .await
+                        .unwrap();
+                    while let Some(Ok(_)) = ws.next().await {}
+                }
+            });
+            port
+        }
+        let mut resolver = StaticResolver::new();
+        resolver.add("signalling.internal", Ipv4Addr::LOCALHOST.into());
+        let config = WebsocketConfig {
+            resolver: Some(Arc::new(resolver)),
+            ..Default::default()
+        };
+        let url = |port: u16| -> Url {
+            format!("wss://signalling.internal:{port}/channel")
+                .parse()
+                .unwrap()
+        };
+
+        let refusing = url(server(true).await);
+        let r = Websocket::connect(refusing.clone(), config.clone()).await;
+        assert!(r.is_err_and(|e| matches!(&e, WebsocketError::WebsocketError(e) if tls_failure(e))));
+
+        let config = WebsocketConfig {
+            tls_fallback: true,
+            ..config
+        };
+        let (ws, dialer) = Websocket::connect(refusing, config.clone()).await.unwrap();
+        assert!(!dialer);
+        assert!(ws.fell_back);
+
+        // Not falling back on what anyone could cause
+        let cutting = url(server(false).await);
+        let r = Websocket::connect(cutting, config).await;
+        assert!(
+            r.is_err_and(|e| matches!(&e, WebsocketError::WebsocketError(e) if !tls_failure(e)))
+        );
+    }
+}
@@ -0,0 +1,76 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::signalling_server;
+use futures::FutureExt;
+use icepipe::{
+    async_pipe_stream::{AsyncPipeStream, Framing},
+    pipe_stream::WaitThen,
+    signalling::Signalling,
+    ws::Websocket,
+};
+use std::{fmt::Debug, time::Duration};
+use tokio::{io::AsyncWriteExt, time::timeout};
+
+/// Receives `count` messages, dropping most waits before they resolve.
+async fn receive_cancelling<S, T>(stream: &mut S, count: usize) -> Vec<T>
+where
+    S: WaitThen<Output = Option<T>>,
+    S::Error: Debug,
+{
+    let mut received = vec![];
+    for polls in 0.. {
+        if received.len() == count {
+            break;
+        }
+        let value = match polls % 3 {
+            0 => stream.wait().now_or_never(),
+            1 => timeout(Duration::from_micros(100), stream.wait())
+                .await
+                .ok(),
+            _ => Some(stream.wait().await),
+        };
+        if let Some(value) = value {
+            if let Some(data) = stream.then(&mut value.unwrap()).await.unwrap() {
+                received.push(data);
+            }
+        }
+    }
+    received
+}
+
+#[tokio::test]
+async fn websocket() {
+    let url = signalling_server().await.join("cancel_safety").unwrap();
+    let (a, b) = tokio::join!(Websocket::new(url.clone()), Websocket::new(url));
+    let (mut a, mut b) = (a.unwrap().0, b.unwrap().0);
+    let sent: Vec<String> = (0..200).map(|i| format!("message {i}")).collect();
+
+    let send = async {
+        for msg in &sent {
+            a.send(msg.clone()).await.unwrap();
+        }
+    };
+    let ((), received) = tokio::join!(send, receive_cancelling(&mut b, sent.len()));
+    assert_eq!(received, sent);
+}
+
+#[tokio::test]
+async fn async_pipe_stream_lines() {
+    let (mut tx, rx) = tokio::io::duplex(16);
+    let mut stream =
+        AsyncPipeStream::new(rx, tokio::io::sink()).with_framing(Framing::Lines { max_len: 64 });
+    let sent: Vec<Vec<u8>> = (0..200)
+        .map(|i| format!("line {i}\n").into_bytes())
+        .collect();
+
+    // Lines span several reads through the small pipe
+    let send = async {
+        for line in &sent {
+            tx.write_all(line).await.unwrap();
+        }
+    };
+    let ((), received) = tokio::join!(send, receive_cancelling(&mut stream, sent.len()));
+    assert_eq!(received, sent);
+}
@@ -0,0 +1,110 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::signalling_server;
+use icepipe::{
+    channel_hopping::ChannelHopping,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    ConnectOptions,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+
+const WINDOW: Duration = Duration::from_secs(600);
+
+fn window_start() -> SystemTime {
+    UNIX_EPOCH + WINDOW * 10
+}
+
+fn previous_window_end() -> SystemTime {
+    window_start() - Duration::from_secs(1)
+}
+
+fn two_windows_later() -> SystemTime {
+    window_start() + WINDOW * 2
+}
+
+fn options(signaling: &url::Url, clock: Option<fn() -> SystemTime>) -> ConnectOptions {
+    ConnectOptions {
+        channel: "hopping".into(),
+        signaling: Some(signaling.clone()),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        channel_hopping: clock.map(|clock| ChannelHopping {
+            clock,
+            ..ChannelHopping::new(WINDOW)
+        }),
+        ..Default::default()
+    }
+}
+
+async fn connects(a: ConnectOptions, b: ConnectOptions) {
+    // Boxed, so that the connects do not run out of the test thread stack
+    let (a, b) = timeout(
+        Duration::from_secs(30),
+        Box::pin(async { tokio::join!(a.connect_psk(), b.connect_psk()) }),
+    )
+    .await
+    .unwrap();
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    a.send(b"hop").await.unwrap();
+    let received = loop {
+        let mut value = b.wait().await.unwrap();
+        if let Some(data) = b.then(&mut value).await.unwrap() {
+            break data;
+        }
+    };
+    assert_eq!(received, b"hop");
+
+    let (a, b) = tokio::join!(a.close(), b.close());
+    a.unwrap();
+    b.unwrap();
+}
+
+async fn never_meet(a: ConnectOptions, b: ConnectOptions) {
+    let met = timeout(
+        Duration::from_secs(5),
+        Box::pin(async { tokio::join!(a.connect_psk(), b.connect_psk()) }),
+    )
+    .await;
+
+    assert!(met.is_err());
+}
+
+#[tokio::test]
+async fn same_window() {
+    let signaling = signalling_server().await;
+    connects(
+        options(&signaling, Some(window_start)),
+        options(&signaling, Some(window_start)),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn tolerates_previous_window() {
+    let signaling = signalling_server().await;
+    connects(
+        options(&signaling, Some(previous_window_end)),
+        options(&signaling, Some(window_start)),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn rejects_mismatched_schemes() {
+    let signaling = signalling_server().await;
+    never_meet(
+        options(&signaling, Some(previous_window_end)),
+        options(&signaling, Some(two_windows_later)),
+    )
+    .await;
+
+    let signaling = signalling_server().await;
+    never_meet(
+        options(&signaling, Some(window_start)),
+        options(&signaling, None),
+    )
+    .await;
+}
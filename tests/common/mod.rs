@@ -0,0 +1,176 @@
+use futures::{SinkExt, StreamExt};
+use icepipe::ice::IceServer;
+use std::time::{Duration, SystemTime};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        Message,
+    },
+    WebSocketStream,
+};
+use turn::{
+    auth::{generate_auth_key, AuthHandler},
+    relay::relay_static::RelayAddressGeneratorStatic,
+    server::{
+        config::{ConnConfig, ServerConfig},
+        Server,
+    },
+};
+use webrtc_util::vnet::net::Net;
+
+type Ws = WebSocketStream<TcpStream>;
+
+/// Pairs the first two clients of each path and relays text messages
+/// between them, the way the public signalling server does.
+#[allow(dead_code)]
+pub async fn signalling_server() -> url::Url {
+    server(None, false).await
+}
+
+/// Like [`signalling_server`] but the first message containing `pattern` is
+/// not relayed, both clients are disconnected instead.
+#[allow(dead_code)]
+pub async fn flaky_signalling_server(pattern: &'static str) -> url::Url {
+    server(Some(pattern), false).await
+}
+
+/// Like [`signalling_server`] but paths are entry points followed by the
+/// channel, clients joining the same channel through any of them are
+/// paired.
+#[allow(dead_code)]
+pub async fn federated_signalling_server() -> url::Url {
+    server(None, true).await
+}
+
+#[allow(clippy::result_large_err)] // The handshake callback signature is given by tungstenite
+async fn server(drop_on: Option<&'static str>, federated: bool) -> url::Url {
+    let dropped = Arc::new(AtomicBool::new(false));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/", listener.local_addr().unwrap());
+    let waiting = Arc::new(Mutex::new(HashMap::<String, Ws>::new()));
+
+    tokio::spawn(async move {
+        loop {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let waiting = waiting.clone();
+            let dropped = dropped.clone();
+            tokio::spawn(async move {
+                let mut path = String::new();
+                let ws = accept_hdr_async(tcp, |request: &Request, response: Response| {
+                    path = request.uri().path().to_owned();
+                    let mut response = response;
+                    let date = httpdate::fmt_http_date(SystemTime::now());
+                    response.headers_mut().insert("date", date.parse().unwrap());
+                    Ok(response)
+                })
+                .await
+                .unwrap();
+                if federated {
+                    let channel = path.trim_start_matches('/').split_once('/');
+                    path = channel.map(|(_, c)| c.to_owned()).unwrap_or_default();
+                }
+
+                let peer = waiting.lock().unwrap().remove(&path);
+                match peer {
+                    Some(peer) => relay(peer, ws, drop_on, &dropped).await,
+                    None => {
+                        waiting.lock().unwrap().insert(path, ws);
+                    }
+                }
+            });
+        }
+    });
+
+    url.parse().unwrap()
+}
+
+async fn relay(mut dialer: Ws, mut listener: Ws, drop_on: Option<&str>, dropped: &AtomicBool) {
+    let drop = |msg: &Message| match (drop_on, msg) {
+        (Some(pattern), Message::Text(text)) if text.contains(pattern) => {
+            !dropped.swap(true, Ordering::SeqCst)
+        }
+        _ => false,
+    };
+
+    if dialer.send(Message::Text("DIALER".into())).await.is_err()
+        || listener
+            .send(Message::Text("LISTENER".into()))
+            .await
+            .is_err()
+    {
+        return;
+    }
+
+    let (mut dialer_tx, mut dialer_rx) = dialer.split();
+    let (mut listener_tx, mut listener_rx) = listener.split();
+    loop {
+        let r = tokio::select! {
+            msg = dialer_rx.next() => match msg {
+                Some(Ok(msg)) if drop(&msg) => break,
+                Some(Ok(msg @ Message::Text(_))) => listener_tx.send(msg).await,
+                Some(Ok(_)) => Ok(()),
+                _ => break,
+            },
+            msg = listener_rx.next() => match msg {
+                Some(Ok(msg)) if drop(&msg) => break,
+                Some(Ok(msg @ Message::Text(_))) => dialer_tx.send(msg).await,
+                Some(Ok(_)) => Ok(()),
+                _ => break,
+            },
+        };
+        if r.is_err() {
+            break;
+        }
+    }
+
+    let _ = dialer_tx.close().await;
+    let _ = listener_tx.close().await;
+}
+
+const REALM: &str = "icepipe";
+
+struct Credentials;
+impl AuthHandler for Credentials {
+    fn auth_handle(&self, username: &str, _: &str, _: SocketAddr) -> Result<Vec<u8>, turn::Error> {
+        match username {
+            "user" => Ok(generate_auth_key(username, REALM, "secret")),
+            _ => Err(turn::Error::ErrNoSuchUser),
+        }
+    }
+}
+
+/// TURN server on loopback, relaying from loopback as well. Accepts `user`
+/// with the credential `secret`.
+#[allow(dead_code)]
+pub async fn turn_server() -> (Server, IceServer) {
+    let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = conn.local_addr().unwrap();
+    let server = Server::new(ServerConfig {
+        conn_configs: vec![ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(RelayAddressGeneratorStatic {
+                relay_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                address: "127.0.0.1".to_string(),
+                net: Arc::new(Net::new(None)),
+            }),
+        }],
+        realm: REALM.to_string(),
+        auth_handler: Arc::new(Credentials),
+        channel_bind_timeout: Duration::ZERO,
+    })
+    .await
+    .unwrap();
+
+    let ice_server = IceServer::new(format!("turn:{addr}")).with_credentials("user", "secret");
+    (server, ice_server)
+}
@@ -0,0 +1,75 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::signalling_server;
+use icepipe::{
+    connect_limiter::ConnectLimiter,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    ConnectOptions,
+};
+use std::{cell::Cell, time::Duration};
+
+const PEERS: usize = 20;
+const SLOTS: usize = 4;
+
+/// A burst of peers connecting to one listener, which only handshakes with
+/// a few of them at a time while its runtime keeps running other tasks. The
+/// order peers are served in is checked on a paused clock by the unit tests
+/// of the limiter.
+#[tokio::test]
+async fn burst_of_peers() {
+    let signaling = signalling_server().await;
+    let limiter = ConnectLimiter::new(SLOTS);
+    let options = |channel: usize, limiter: Option<&ConnectLimiter>| ConnectOptions {
+        channel: format!("limited-{channel}").into(),
+        signaling: Some(signaling.clone()),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        connect_limiter: limiter.cloned(),
+        ..Default::default()
+    };
+
+    let (done, most_active, most_queued, ticks_while_queued) =
+        (Cell::new(false), Cell::new(0), Cell::new(0), Cell::new(0));
+    let ticker = async {
+        while !done.get() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            most_active.set(most_active.get().max(limiter.active()));
+            most_queued.set(most_queued.get().max(limiter.queued()));
+            if limiter.queued() > 0 {
+                ticks_while_queued.set(ticks_while_queued.get() + 1);
+            }
+        }
+    };
+    let connects = async {
+        let listener = (0..PEERS).map(|i| options(i, Some(&limiter)).connect_psk());
+        let peers = (0..PEERS).map(|i| options(i, None).connect_psk());
+        let connections = tokio::join!(
+            futures::future::join_all(listener),
+            futures::future::join_all(peers),
+        );
+        done.set(true);
+        connections
+    };
+    let ((listener, peers), ()) = tokio::join!(connects, ticker);
+
+    for (a, b) in listener.into_iter().zip(peers) {
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        a.send(b"limited").await.unwrap();
+        let received = loop {
+            let mut value = b.wait().await.unwrap();
+            if let Some(data) = b.then(&mut value).await.unwrap() {
+                break data;
+            }
+        };
+        assert_eq!(received, b"limited");
+        let (a, b) = tokio::join!(a.close(), b.close());
+        a.unwrap();
+        b.unwrap();
+    }
+    assert_eq!(most_active.get(), SLOTS);
+    assert!(most_queued.get() > 0);
+    assert_eq!((limiter.active(), limiter.queued()), (0, 0));
+    // Other tasks kept running while peers waited for a slot
+    assert!(ticks_while_queued.get() > 0);
+}
@@ -0,0 +1,146 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::signalling_server;
+use icepipe::{
+    diagnostics::Diagnostics,
+    ice::{IceServer, PairSelection, SignallingPolicy},
+    pipe_stream::Control,
+    ConnectOptions,
+};
+use std::{sync::Arc, time::Duration};
+use stun::{
+    message::{Message, Setter, BINDING_SUCCESS},
+    xoraddr::XorMappedAddress,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::watch,
+    task::LocalSet,
+    time::{sleep, timeout, Instant},
+};
+
+/// STUN server holding its answers until released, so server reflexive
+/// candidates are only gathered once connected over host ones.
+async fn held_stun_server() -> (String, watch::Sender<bool>) {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = socket.local_addr().unwrap();
+    let (release, released) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut buf = [0; 1500];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            let mut request = Message::new();
+            if request.unmarshal_binary(&buf[..n]).is_err() {
+                continue;
+            }
+            let mut response = Message::new();
+            let setters: [Box<dyn Setter>; 3] = [
+                Box::new(BINDING_SUCCESS),
+                Box::new(request.transaction_id),
+                Box::new(XorMappedAddress {
+                    ip: from.ip(),
+                    port: from.port(),
+                }),
+            ];
+            response.build(&setters).unwrap();
+            let (socket, mut released) = (socket.clone(), released.clone());
+            tokio::spawn(async move {
+                let _ = released.wait_for(|released| *released).await;
+                let _ = socket.send_to(&response.raw, from).await;
+            });
+        }
+    });
+
+    (format!("stun:{addr}"), release)
+}
+
+fn options(
+    signaling: &url::Url,
+    stun: &str,
+    signalling_policy: SignallingPolicy,
+) -> ConnectOptions {
+    ConnectOptions {
+        channel: "continual".into(),
+        signaling: Some(signaling.clone()),
+        ice_servers: vec![IceServer::new(stun)],
+        pair_selection: PairSelection::FirstResponding,
+        signalling_policy,
+        diagnostics: Diagnostics::new(),
+        ..Default::default()
+    }
+}
+
+/// Whether either peer learns the server reflexive candidate of the other
+/// within `wait` of gathering it, which is only once connected.
+async fn late_candidates_exchanged(signalling_policy: SignallingPolicy, wait: Duration) -> bool {
+    let signaling = signalling_server().await;
+    let (stun, release) = held_stun_server().await;
+    let (a, b) = (
+        options(&signaling, &stun, signalling_policy),
+        options(&signaling, &stun, signalling_policy),
+    );
+    let diagnostics = [a.diagnostics.clone(), b.diagnostics.clone()];
+
+    let (a, b) = timeout(Duration::from_secs(30), async {
+        tokio::join!(a.connect_psk(), b.connect_psk())
+    })
+    .await
+    .unwrap();
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    let srflx = |remote| {
+        diagnostics.iter().any(|diagnostics| {
+            let session = diagnostics.session().unwrap();
+            let candidates = match remote {
+                true => session.remote_candidates,
+                false => session.local_candidates,
+            };
+            candidates.iter().any(|c| c.kind == "srflx")
+        })
+    };
+    assert!(!srflx(false), "Gathered before connecting");
+    release.send(true).unwrap();
+    timeout(Duration::from_secs(10), async {
+        while !srflx(false) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Never gathered");
+
+    let until = Instant::now() + wait;
+    while !srflx(true) && Instant::now() < until {
+        sleep(Duration::from_millis(10)).await;
+    }
+    let learned = srflx(true);
+
+    let (a, b) = tokio::join!(a.close(), b.close());
+    a.unwrap();
+    b.unwrap();
+    learned
+}
+
+#[tokio::test]
+async fn exchanges_late_candidates() {
+    let exchanged =
+        late_candidates_exchanged(SignallingPolicy::KeepExchanging, Duration::from_secs(10));
+    assert!(LocalSet::new().run_until(Box::pin(exchanged)).await);
+}
+
+// Long enough for a candidate to go through the signalling server, which is
+// local, had it been sent
+const NOT_EXCHANGED: Duration = Duration::from_millis(500);
+
+#[tokio::test]
+async fn stops_once_connected_by_default() {
+    // Nothing is spawned, no LocalSet needed
+    assert!(!late_candidates_exchanged(SignallingPolicy::default(), NOT_EXCHANGED).await);
+}
+
+#[tokio::test]
+async fn parked_drops_late_candidates() {
+    let exchanged = late_candidates_exchanged(SignallingPolicy::Park, NOT_EXCHANGED);
+    assert!(!LocalSet::new().run_until(Box::pin(exchanged)).await);
+}
@@ -0,0 +1,88 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::{signalling_server, turn_server};
+use icepipe::{
+    doctor::{self, Status},
+    ice::IceServer,
+    ConnectOptions,
+};
+
+#[tokio::test]
+async fn all_pass() {
+    let (_server, ice_server) = turn_server().await;
+    let stun = ice_server.urls[0].replace("turn:", "stun:");
+    let options = ConnectOptions {
+        signaling: Some(signalling_server().await),
+        ice_servers: vec![ice_server],
+        ice: vec![stun],
+        ..Default::default()
+    };
+
+    let report = doctor::run(&options).await;
+    assert_eq!(report.status(), Status::Pass, "{report}");
+    let names = report
+        .checks
+        .iter()
+        .map(|check| check.name.split(' ').next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "dns",
+            "tcp",
+            "signalling",
+            "clock",
+            "turn",
+            "turn",
+            "stun",
+            "udp"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn rejected_credentials() {
+    let (_server, ice_server) = turn_server().await;
+    let ice_server = IceServer::new(ice_server.urls[0].clone()).with_credentials("user", "wrong");
+    let options = ConnectOptions {
+        signaling: Some(signalling_server().await),
+        ice_servers: vec![ice_server],
+        ..Default::default()
+    };
+
+    let report = doctor::run(&options).await;
+    assert_eq!(report.status(), Status::Fail);
+    let failed = report
+        .checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .collect::<Vec<_>>();
+    assert_eq!(failed.len(), 1, "{report}");
+    assert!(failed[0].name.ends_with("allocation"));
+    assert!(failed[0].fix.as_ref().unwrap().contains("credential"));
+}
+
+#[tokio::test]
+async fn unreachable_signalling() {
+    // Bound and dropped, nothing listens on it
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    let options = ConnectOptions {
+        signaling: Some(format!("ws://{addr}/").parse().unwrap()),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        ..Default::default()
+    };
+
+    let report = doctor::run(&options).await;
+    let tcp = report.checks.iter().find(|check| check.name == "tcp");
+    assert_eq!(tcp.unwrap().status, Status::Fail, "{report}");
+    assert!(report.checks.iter().all(|check| check.name != "signalling"));
+
+    let mut json = Vec::new();
+    report.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["checks"][1]["status"], "fail");
+}
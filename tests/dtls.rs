@@ -0,0 +1,72 @@
+use icepipe::{
+    connect::{ConnectError, Connection, Encryption},
+    dtls::DtlsError,
+    memory_signalling::MemorySignalling,
+    pipe_stream::{Control, PipeStream, TransportKind, WaitThen},
+    ConnectOptions,
+};
+use std::time::Duration;
+use tokio::time::timeout;
+
+fn options(encryption: Encryption) -> ConnectOptions {
+    ConnectOptions {
+        channel: "dtls".into(),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        encryption,
+        ..Default::default()
+    }
+}
+
+async fn recv(connection: &mut Connection) -> Vec<u8> {
+    loop {
+        let mut value = connection.wait().await.unwrap();
+        if let Some(data) = connection.then(&mut value).await.unwrap() {
+            return data;
+        }
+    }
+}
+
+#[tokio::test]
+async fn exchange_over_dtls() {
+    let (a, b) = MemorySignalling::pair();
+    let (a, b) = timeout(Duration::from_secs(30), async {
+        tokio::join!(
+            options(Encryption::Dtls).connect_psk_with_signalling(a, true),
+            options(Encryption::Dtls).connect_psk_with_signalling(b, false),
+        )
+    })
+    .await
+    .unwrap();
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+    assert!(matches!(a, Connection::Dtls(_)));
+    assert_eq!(a.transport_kind(), TransportKind::Dtls);
+    assert_eq!(b.transport_kind(), TransportKind::Dtls);
+
+    a.send(b"from dialer").await.unwrap();
+    assert_eq!(recv(&mut b).await, b"from dialer");
+    b.send(b"from listener").await.unwrap();
+    assert_eq!(recv(&mut a).await, b"from listener");
+
+    let (a, b) = tokio::join!(a.close(), b.close());
+    a.unwrap();
+    b.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_mismatched_encryption() {
+    let (a, b) = MemorySignalling::pair();
+    let (a, b) = timeout(Duration::from_secs(30), async {
+        tokio::join!(
+            options(Encryption::Dtls).connect_psk_with_signalling(a, true),
+            options(Encryption::Chacha20).connect_psk_with_signalling(b, false),
+        )
+    })
+    .await
+    .unwrap();
+
+    assert!(matches!(
+        a,
+        Err(ConnectError::DtlsError(DtlsError::NotNegotiated))
+    ));
+    assert!(b.is_err());
+}
@@ -0,0 +1,32 @@
+//! Checks that every combination of the layer features builds on its own,
+//! with default features disabled and without warnings.
+
+use std::process::Command;
+
+const LAYERS: [&str; 3] = ["crypto", "ice-transport", "ws-signalling"];
+
+#[test]
+fn every_combination_builds() {
+    let target_dir = concat!(env!("CARGO_TARGET_TMPDIR"), "/feature-matrix");
+    for mask in 0..1 << LAYERS.len() {
+        let mut features: Vec<_> = (0..LAYERS.len())
+            .filter(|i| mask & (1 << i) != 0)
+            .map(|i| LAYERS[i])
+            .collect();
+        // The crypto layers refuse to build without a backend
+        if features.contains(&"crypto") {
+            features.push("ring");
+        }
+        let features = features.join(",");
+
+        let status = Command::new(env!("CARGO"))
+            .args(["check", "--lib", "--no-default-features"])
+            .args(["--features", &features])
+            .env("CARGO_TARGET_DIR", target_dir)
+            .env("RUSTFLAGS", "-D warnings")
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .unwrap();
+        assert!(status.success(), "features {features:?} do not build");
+    }
+}
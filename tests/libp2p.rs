@@ -0,0 +1,72 @@
+mod common;
+
+use common::signalling_server;
+use futures::StreamExt;
+use icepipe::{libp2p::IcepipeTransport, ConnectOptions};
+use libp2p::{
+    core::upgrade,
+    multiaddr::Multiaddr,
+    noise, ping,
+    swarm::{Swarm, SwarmEvent},
+    yamux, SwarmBuilder, Transport,
+};
+use std::time::Duration;
+use tokio::{select, time::timeout};
+
+fn node(signaling: &url::Url) -> (Swarm<ping::Behaviour>, Multiaddr) {
+    let mut transport = IcepipeTransport::new(ConnectOptions {
+        channel: "libp2p-ping".into(),
+        signaling: Some(signaling.clone()),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        ..Default::default()
+    });
+    transport.plaintext = true;
+    let addr = transport.multiaddr();
+
+    let swarm = SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_other_transport(|key| {
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(
+                transport
+                    .upgrade(upgrade::Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()),
+            )
+        })
+        .unwrap()
+        .with_behaviour(|_| {
+            ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_millis(100)))
+        })
+        .unwrap()
+        .with_swarm_config(|config| config.with_idle_connection_timeout(Duration::MAX))
+        .build();
+    (swarm, addr)
+}
+
+fn pinged(event: SwarmEvent<ping::Event>) -> bool {
+    matches!(
+        event,
+        SwarmEvent::Behaviour(ping::Event { result: Ok(_), .. })
+    )
+}
+
+#[tokio::test]
+async fn ping_between_two_nodes() {
+    let signaling = signalling_server().await;
+    let (mut listener, addr) = node(&signaling);
+    let (mut dialer, _) = node(&signaling);
+    listener.listen_on(addr.clone()).unwrap();
+    dialer.dial(addr).unwrap();
+
+    let (mut listener_pinged, mut dialer_pinged) = (false, false);
+    timeout(Duration::from_secs(30), async {
+        while !(listener_pinged && dialer_pinged) {
+            select! {
+                event = listener.select_next_some() => listener_pinged |= pinged(event),
+                event = dialer.select_next_some() => dialer_pinged |= pinged(event),
+            }
+        }
+    })
+    .await
+    .expect("Nodes did not ping each other");
+}
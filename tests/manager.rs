@@ -0,0 +1,157 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::signalling_server;
+use icepipe::{
+    crypto_backend::Ed25519KeyPair,
+    manager::{ConnectionManager, LinkHandle, LinkState, PeerId, PeerSpec, ReconnectPolicy},
+    ConnectOptions,
+};
+use std::time::Duration;
+use tokio::{task::LocalSet, time::timeout};
+
+fn manager(signaling: &url::Url) -> ConnectionManager {
+    ConnectionManager::with_policy(
+        ConnectOptions {
+            signaling: Some(signaling.clone()),
+            ice: vec!["stun:127.0.0.1:3478".to_string()],
+            ..Default::default()
+        },
+        ReconnectPolicy {
+            max_attempts: None,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        },
+    )
+}
+
+fn psk(channel: &str) -> PeerSpec {
+    PeerSpec::Psk {
+        psk: channel.into(),
+    }
+}
+
+async fn wait_state(link: &LinkHandle, state: LinkState) {
+    timeout(
+        Duration::from_secs(30),
+        link.state().wait_for(|current| *current == state),
+    )
+    .await
+    .unwrap_or_else(|_| panic!("{} never reached {state:?}", link.peer()))
+    .unwrap();
+}
+
+async fn recv(manager: &mut ConnectionManager) -> (PeerId, Vec<u8>) {
+    timeout(Duration::from_secs(30), manager.recv())
+        .await
+        .expect("Nothing received")
+}
+
+#[tokio::test]
+async fn routes_and_broadcasts_to_several_peers() {
+    LocalSet::new()
+        .run_until(async {
+            let signaling = signalling_server().await;
+            let mut hub = manager(&signaling);
+            let mut b = manager(&signaling);
+            let mut c = manager(&signaling);
+
+            let hub_links = [hub.ensure(psk("hub-b")), hub.ensure(psk("hub-c"))];
+            let spoke_links = [b.ensure(psk("hub-b")), c.ensure(psk("hub-c"))];
+            assert_eq!(*hub_links[0].state().borrow(), LinkState::Idle);
+            assert_eq!(hub.broadcast(b"nobody").await, Vec::<PeerId>::new());
+
+            for link in hub_links.iter().chain(&spoke_links) {
+                link.start();
+            }
+            for link in hub_links.iter().chain(&spoke_links) {
+                wait_state(link, LinkState::Connected).await;
+            }
+            assert_eq!(hub.active().count(), 2);
+
+            let mut sent = hub.broadcast(b"hello").await;
+            sent.sort();
+            let mut expected = [psk("hub-b").id(), psk("hub-c").id()];
+            expected.sort();
+            assert_eq!(sent, expected);
+            assert_eq!(recv(&mut b).await, (psk("hub-b").id(), b"hello".to_vec()));
+            assert_eq!(recv(&mut c).await, (psk("hub-c").id(), b"hello".to_vec()));
+
+            c.send(&psk("hub-c").id(), b"from c".to_vec())
+                .await
+                .unwrap();
+            assert_eq!(
+                recv(&mut hub).await,
+                (psk("hub-c").id(), b"from c".to_vec())
+            );
+
+            hub.close();
+            b.close();
+            c.close();
+            for link in hub_links.iter().chain(&spoke_links) {
+                wait_state(link, LinkState::Closed).await;
+            }
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn reconnects_key_peer_after_it_comes_back() {
+    LocalSet::new()
+        .run_until(async {
+            let signaling = signalling_server().await;
+            let a_seed = [1; 32];
+            let b_seed = [2; 32];
+            let a_public = Ed25519KeyPair::from_seed(&a_seed)
+                .unwrap()
+                .public_key()
+                .to_vec();
+            let b_public = Ed25519KeyPair::from_seed(&b_seed)
+                .unwrap()
+                .public_key()
+                .to_vec();
+            let a_spec = PeerSpec::Key {
+                seed: a_seed,
+                peer: b_public,
+            };
+            let b_spec = PeerSpec::Key {
+                seed: b_seed,
+                peer: a_public,
+            };
+
+            let mut a = manager(&signaling);
+            let mut b = manager(&signaling);
+            let a_link = a.ensure(a_spec);
+            let b_link = b.ensure(b_spec.clone());
+            a_link.start();
+            b_link.start();
+            wait_state(&a_link, LinkState::Connected).await;
+            wait_state(&b_link, LinkState::Connected).await;
+
+            b_link.close();
+            wait_state(&b_link, LinkState::Closed).await;
+            a_link
+                .state()
+                .wait_for(|state| *state != LinkState::Connected)
+                .await
+                .unwrap();
+
+            let b_link = b.ensure(b_spec);
+            b_link.send(b"back".to_vec()).await.unwrap();
+            let (peer, data) = recv(&mut a).await;
+            assert_eq!(&peer, a_link.peer());
+            assert_eq!(data, b"back");
+
+            let health = a_link.health().borrow().clone();
+            assert_eq!(health.connects, 2);
+            assert_eq!(health.failures, 0);
+            assert!(health.last_error.is_some());
+
+            a.close();
+            b.close();
+            wait_state(&a_link, LinkState::Closed).await;
+            wait_state(&b_link, LinkState::Closed).await;
+        })
+        .await;
+}
@@ -0,0 +1,103 @@
+use icepipe::{
+    ice::IceServer,
+    memory_signalling::MemorySignalling,
+    metrics::*,
+    ping::{Ping, PingConfig},
+    pipe_stream::{Control, PipeStream, WaitThen},
+    ConnectOptions,
+};
+use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+use std::time::Duration;
+
+fn options() -> ConnectOptions {
+    ConnectOptions {
+        channel: "metrics".into(),
+        ice_servers: vec![IceServer::new("stun:127.0.0.1:3478")],
+        ..Default::default()
+    }
+}
+
+/// Value of the metric `name` with exactly `labels`.
+fn metric(snapshotter: &Snapshotter, name: &str, labels: &[(&str, &str)]) -> Option<DebugValue> {
+    snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .find(|(key, ..)| {
+            let key = key.key();
+            let key_labels: Vec<_> = key.labels().map(|l| (l.key(), l.value())).collect();
+            key.name() == name && key_labels == labels
+        })
+        .map(|(.., value)| value)
+}
+
+fn counter(snapshotter: &Snapshotter, name: &str, labels: &[(&str, &str)]) -> u64 {
+    match metric(snapshotter, name, labels) {
+        Some(DebugValue::Counter(n)) => n,
+        other => panic!("{name} {labels:?}: {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn loopback_transfer() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let _recorder = metrics::set_default_local_recorder(&recorder);
+
+    let (a, b) = MemorySignalling::pair();
+    let (dialer, listener) = tokio::join!(
+        options().connect_psk_with_signalling(a, true),
+        options().connect_psk_with_signalling(b, false),
+    );
+    let (mut dialer, mut listener) = (dialer.unwrap(), listener.unwrap());
+
+    let active = &[("role", "dialer"), ("transport", "sctp")];
+    let gauge = |labels| match metric(&snapshotter, CONNECTIONS_ACTIVE, labels) {
+        Some(DebugValue::Gauge(n)) => n.0,
+        other => panic!("{other:?}"),
+    };
+    assert_eq!(gauge(active), 1.0);
+
+    dialer.send(&[7; 1024]).await.unwrap();
+    loop {
+        let mut value = listener.wait().await.unwrap();
+        if listener.then(&mut value).await.unwrap().is_some() {
+            break;
+        }
+    }
+
+    let (a, b) = tokio::join!(dialer.close(), listener.close());
+    a.unwrap();
+    b.unwrap();
+    drop((dialer, listener));
+
+    assert_eq!(counter(&snapshotter, CONNECT_ATTEMPTS, &[]), 2);
+    for role in ["dialer", "listener"] {
+        assert_eq!(
+            counter(&snapshotter, CONNECT_SUCCESSES, &[("role", role)]),
+            1
+        );
+    }
+    let sent = counter(&snapshotter, BYTES_SENT, active);
+    assert!(sent >= 1024, "{sent}");
+    assert_eq!(gauge(active), 0.0);
+    assert!(metric(&snapshotter, CONNECT_FAILURES, &[("phase", "ice")]).is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn signalling_pings() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let _recorder = metrics::set_default_local_recorder(&recorder);
+
+    let mut ping = Ping::with_config(PingConfig::every(Duration::from_secs(1)));
+    ping.wait().await.unwrap();
+    ping.sent_ping();
+    assert_eq!(counter(&snapshotter, SIGNALLING_PINGS, &[]), 1);
+
+    // Never answered
+    while ping.wait().await.is_ok() {
+        ping.sent_ping();
+    }
+    assert_eq!(counter(&snapshotter, SIGNALLING_PING_TIMEOUTS, &[]), 1);
+}
@@ -0,0 +1,75 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::turn_server;
+use icepipe::{
+    connect::ConnectError,
+    diagnostics::Diagnostics,
+    ice::{GatherPolicy, IceServer},
+    memory_signalling::MemorySignalling,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    ConnectOptions,
+};
+
+fn options(ice_server: &IceServer, diagnostics: &Diagnostics) -> ConnectOptions {
+    ConnectOptions {
+        channel: "relay-only".into(),
+        ice_servers: vec![ice_server.clone()],
+        gather_policy: GatherPolicy::RelayOnlyNoBind,
+        diagnostics: diagnostics.clone(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn connects_through_relay() {
+    let (server, ice_server) = turn_server().await;
+    let (diag_a, diag_b) = (Diagnostics::new(), Diagnostics::new());
+    let (a, b) = MemorySignalling::pair();
+    let (a, b) = tokio::join!(
+        options(&ice_server, &diag_a).connect_psk_with_signalling(a, true),
+        options(&ice_server, &diag_b).connect_psk_with_signalling(b, false),
+    );
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    a.send(b"relayed").await.unwrap();
+    let received = loop {
+        let mut value = b.wait().await.unwrap();
+        if let Some(data) = b.then(&mut value).await.unwrap() {
+            break data;
+        }
+    };
+    assert_eq!(received, b"relayed");
+
+    for diagnostics in [diag_a, diag_b] {
+        let session = diagnostics.session().unwrap();
+        // What was gathered is what was sent to the peer
+        assert!(!session.local_candidates.is_empty());
+        for candidate in session
+            .local_candidates
+            .iter()
+            .chain(&session.remote_candidates)
+        {
+            assert_eq!(candidate.kind, "relay", "{}", candidate.candidate);
+        }
+        assert!(session.selected_pair.unwrap().local.contains(" typ relay"));
+    }
+
+    let (a, b) = tokio::join!(a.close(), b.close());
+    a.unwrap();
+    b.unwrap();
+    server.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn needs_turn_server() {
+    let stun = IceServer::new("stun:127.0.0.1:3478");
+    let (a, _b) = MemorySignalling::pair();
+    let error = options(&stun, &Diagnostics::default())
+        .connect_psk_with_signalling(a, true)
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(error, ConnectError::NoRelay), "{error}");
+}
@@ -0,0 +1,59 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::{signalling_server, turn_server};
+use icepipe::{
+    diagnostics::Diagnostics,
+    ice::{GatherPolicy, IceServer},
+    pipe_stream::{Control, PipeStream, WaitThen},
+    resolver::StaticResolver,
+    ConnectOptions,
+};
+use std::{net::Ipv4Addr, sync::Arc};
+
+/// Neither host resolves but through the static resolver.
+#[tokio::test]
+async fn static_hosts() {
+    let (server, ice_server) = turn_server().await;
+    let turn_port = ice_server.urls[0].rsplit(':').next().unwrap();
+    let ice_server = IceServer::new(format!("turn:relay.internal:{turn_port}"))
+        .with_credentials("user", "secret");
+    let mut signaling = signalling_server().await;
+    signaling.set_host(Some("signalling.internal")).unwrap();
+
+    let mut resolver = StaticResolver::new();
+    resolver.add("relay.internal", Ipv4Addr::LOCALHOST.into());
+    resolver.add("signalling.internal", Ipv4Addr::LOCALHOST.into());
+    let options = |diagnostics: &Diagnostics| ConnectOptions {
+        channel: "resolver".into(),
+        signaling: Some(signaling.clone()),
+        ice_servers: vec![ice_server.clone()],
+        gather_policy: GatherPolicy::RelayOnlyNoBind,
+        resolver: Some(Arc::new(resolver.clone())),
+        diagnostics: diagnostics.clone(),
+        ..Default::default()
+    };
+    let diagnostics = Diagnostics::new();
+    let (a, b) = tokio::join!(
+        options(&diagnostics).connect_psk(),
+        options(&Diagnostics::default()).connect_psk(),
+    );
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    a.send(b"resolved").await.unwrap();
+    let received = loop {
+        let mut value = b.wait().await.unwrap();
+        if let Some(data) = b.then(&mut value).await.unwrap() {
+            break data;
+        }
+    };
+    assert_eq!(received, b"resolved");
+    let session = diagnostics.session().unwrap();
+    assert!(session.selected_pair.unwrap().local.contains(" typ relay"));
+
+    let (a, b) = tokio::join!(a.close(), b.close());
+    a.unwrap();
+    b.unwrap();
+    server.close().await.unwrap();
+}
@@ -0,0 +1,58 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::federated_signalling_server;
+use icepipe::{
+    connect::RoleSignaling,
+    diagnostics::Diagnostics,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    ConnectOptions,
+};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+fn options(signaling: &url::Url, as_dialer: bool) -> ConnectOptions {
+    ConnectOptions {
+        channel: "federated".into(),
+        role_signaling: Some(RoleSignaling {
+            dialer: signaling.join("dialers/").unwrap(),
+            listener: signaling.join("listeners/").unwrap(),
+            as_dialer,
+        }),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        diagnostics: Diagnostics::new(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn meets_through_different_entry_points() {
+    let signaling = federated_signalling_server().await;
+
+    // The listener joins first, so the server assigns it the dialer role
+    let (listener, dialer) = timeout(Duration::from_secs(30), async {
+        tokio::join!(options(&signaling, false).connect_psk(), async {
+            sleep(Duration::from_millis(200)).await;
+            options(&signaling, true).connect_psk().await
+        })
+    })
+    .await
+    .unwrap();
+    let (mut listener, mut dialer) = (listener.unwrap(), dialer.unwrap());
+
+    dialer.send(b"bridged").await.unwrap();
+    let received = loop {
+        let mut value = listener.wait().await.unwrap();
+        if let Some(data) = listener.then(&mut value).await.unwrap() {
+            break data;
+        }
+    };
+    assert_eq!(received, b"bridged");
+    assert_eq!(dialer.diagnostics().session.role, Some("dialer"));
+    assert_eq!(listener.diagnostics().session.role, Some("listener"));
+
+    let (a, b) = tokio::join!(dialer.close(), listener.close());
+    a.unwrap();
+    b.unwrap();
+}
@@ -0,0 +1,97 @@
+//! Connects an `IceAgent` in SDP mode to a plain `webrtc_ice` agent, the Rust
+//! port of Pion's ICE, which knows nothing about icepipe's native signalling.
+
+use icepipe::{
+    ice::{IceAgent, IceConfig},
+    memory_signalling::MemorySignalling,
+    pipe_stream::WaitThen,
+    sdp::{SdpType, SessionDescription},
+    signalling::{Signalling, SignallingFormat},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::mpsc, time::timeout};
+use webrtc_ice::{
+    agent::{agent_config::AgentConfig, Agent},
+    candidate::{candidate_base::unmarshal_candidate, Candidate},
+    network_type::NetworkType,
+};
+use webrtc_util::Conn;
+
+async fn recv(signalling: &mut MemorySignalling) -> String {
+    let mut value = signalling.wait().await.unwrap();
+    signalling.then(&mut value).await.unwrap().unwrap()
+}
+
+/// Answers the offer received on `signalling` and accepts the connection.
+async fn foreign_peer(mut signalling: MemorySignalling) -> Vec<u8> {
+    let agent = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let (candidates_tx, mut candidates_rx) = mpsc::unbounded_channel();
+    agent.on_candidate(Box::new(move |c| {
+        let _ = candidates_tx.send(c.map(|c| c.marshal()));
+        Box::pin(async {})
+    }));
+    agent.gather_candidates().unwrap();
+    let mut candidates = Vec::new();
+    while let Some(Some(candidate)) = candidates_rx.recv().await {
+        candidates.push(candidate);
+    }
+
+    let offer = SessionDescription::parse(&recv(&mut signalling).await).unwrap();
+    let (ufrag, pwd) = agent.get_local_user_credentials().await;
+    let answer = SessionDescription {
+        ufrag,
+        pwd,
+        fingerprint: None,
+        candidates,
+    };
+    signalling
+        .send(answer.to_sdp(SdpType::Answer))
+        .await
+        .unwrap();
+    for candidate in &offer.candidates {
+        let candidate: Arc<dyn Candidate + Send + Sync> =
+            Arc::new(unmarshal_candidate(candidate).unwrap());
+        agent.add_remote_candidate(&candidate).unwrap();
+    }
+
+    let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+    let conn = agent
+        .accept(cancel_rx, offer.ufrag, offer.pwd)
+        .await
+        .unwrap();
+    let mut buf = vec![0; 64];
+    let n = conn.recv(&mut buf).await.unwrap();
+    buf.truncate(n);
+    agent.close().await.unwrap();
+
+    buf
+}
+
+#[tokio::test]
+async fn ice_with_plain_webrtc_ice_agent() {
+    let (ours, theirs) = MemorySignalling::pair();
+
+    let config = IceConfig {
+        format: SignallingFormat::Sdp,
+        ..Default::default()
+    };
+    let ours = async move {
+        let mut agent = IceAgent::new(ours, true, config).await.unwrap();
+        let conn = agent.connect().await.unwrap();
+        conn.send(b"hello pion").await.unwrap();
+        agent
+    };
+
+    let (agent, received) = timeout(Duration::from_secs(30), async {
+        tokio::join!(ours, foreign_peer(theirs))
+    })
+    .await
+    .unwrap();
+    assert_eq!(received, b"hello pion");
+    agent.agent().close().await.unwrap();
+}
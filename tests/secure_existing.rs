@@ -0,0 +1,92 @@
+#![cfg(feature = "full")]
+
+use icepipe::{
+    agreement::PskAuthentication,
+    async_pipe_stream::{AsyncPipeStream, Framing},
+    crypto_stream::Chacha20Stream,
+    pipe_stream::{CloseReason, Control, PipeStream, WaitThen},
+    secure_existing,
+};
+use tokio::{
+    io::duplex,
+    net::{TcpListener, TcpStream},
+};
+
+const FRAMING: Framing = Framing::LengthPrefixed { max_len: 64 * 1024 };
+
+fn psk(key: &str) -> PskAuthentication {
+    PskAuthentication::new(key.to_string())
+}
+
+fn loopback() -> (AsyncPipeStream, AsyncPipeStream) {
+    let (a_tx, b_rx) = duplex(64 * 1024);
+    let (b_tx, a_rx) = duplex(64 * 1024);
+    (
+        AsyncPipeStream::new(a_rx, a_tx).with_framing(FRAMING),
+        AsyncPipeStream::new(b_rx, b_tx).with_framing(FRAMING),
+    )
+}
+
+async fn tcp() -> (AsyncPipeStream, AsyncPipeStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (accepted, connected) = tokio::join!(
+        listener.accept(),
+        TcpStream::connect(listener.local_addr().unwrap())
+    );
+    let (a_rx, a_tx) = accepted.unwrap().0.into_split();
+    let (b_rx, b_tx) = connected.unwrap().into_split();
+    (
+        AsyncPipeStream::new(a_rx, a_tx).with_framing(FRAMING),
+        AsyncPipeStream::new(b_rx, b_tx).with_framing(FRAMING),
+    )
+}
+
+async fn recv(stream: &mut Chacha20Stream<AsyncPipeStream>) -> Option<Vec<u8>> {
+    while !stream.rx_closed() {
+        let mut value = stream.wait().await.ok()?;
+        if let Some(data) = stream.then(&mut value).await.ok()? {
+            return Some(data);
+        }
+    }
+    None
+}
+
+async fn exchange(a: AsyncPipeStream, b: AsyncPipeStream) {
+    let (a, b) = tokio::join!(
+        secure_existing(a, psk("shared"), true),
+        secure_existing(b, psk("shared"), false),
+    );
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    a.send(b"over an existing channel").await.unwrap();
+    assert_eq!(recv(&mut b).await.unwrap(), b"over an existing channel");
+    b.send(&[7; 40_000]).await.unwrap();
+    assert_eq!(recv(&mut a).await.unwrap(), [7; 40_000]);
+
+    a.close().await.unwrap();
+    assert_eq!(recv(&mut b).await, None);
+    assert!(matches!(b.close_reason(), Some(CloseReason::CleanFin)));
+}
+
+#[tokio::test]
+async fn over_loopback() {
+    let (a, b) = loopback();
+    exchange(a, b).await;
+}
+
+#[tokio::test]
+async fn over_tcp() {
+    let (a, b) = tcp().await;
+    exchange(a, b).await;
+}
+
+#[tokio::test]
+async fn mismatched_key() {
+    let (a, b) = loopback();
+    let (a, b) = tokio::join!(
+        secure_existing(a, psk("shared"), true),
+        secure_existing(b, psk("guessed"), false),
+    );
+    assert!(a.is_err());
+    assert!(b.is_err());
+}
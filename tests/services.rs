@@ -0,0 +1,225 @@
+#![cfg(feature = "full")]
+
+use icepipe::{
+    async_pipe_stream::AsyncPipeStream,
+    connect::{Connection, LatencyProfile},
+    diagnostics::Diagnostics,
+    memory_signalling::MemorySignalling,
+    pipe_stream::{Control, PipeStream, WaitThen},
+    service::{self, Rejection, ServiceError, ServiceRegistry},
+    ConnectOptions,
+};
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf},
+    net::TcpListener,
+    select,
+    time::{sleep, timeout},
+};
+
+/// TCP server that greets every client with `greeting`.
+async fn greeter(greeting: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut tcp, _) = listener.accept().await.unwrap();
+            tcp.write_all(greeting.as_bytes()).await.unwrap();
+        }
+    });
+
+    addr
+}
+
+/// Endless source counting what was read from it.
+struct Source(Arc<AtomicUsize>);
+impl AsyncRead for Source {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = buf.remaining();
+        buf.put_slice(&vec![0; n]);
+        self.0.fetch_add(n, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn connection_pair(channel: &str) -> (Connection, Connection) {
+    connection_pair_with(channel, ConnectOptions::default()).await
+}
+
+async fn connection_pair_with(channel: &str, options: ConnectOptions) -> (Connection, Connection) {
+    let options = ConnectOptions {
+        channel: channel.into(),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        ..options
+    };
+    // Each side records on its own
+    let options = |diagnostics| ConnectOptions {
+        diagnostics,
+        ..options.clone()
+    };
+    let (a, b) = MemorySignalling::pair();
+    let (a, b) = tokio::join!(
+        options(Diagnostics::new()).connect_psk_with_signalling(a, true),
+        options(Diagnostics::new()).connect_psk_with_signalling(b, false),
+    );
+
+    (a.unwrap(), b.unwrap())
+}
+
+async fn recv(connection: &mut Connection) -> Vec<u8> {
+    loop {
+        let mut value = connection.wait().await.unwrap();
+        if let Some(data) = connection.then(&mut value).await.unwrap() {
+            return data;
+        }
+    }
+}
+
+#[tokio::test]
+async fn serves_requested_service() {
+    let mut registry = ServiceRegistry::new();
+    registry.add(
+        "ssh",
+        greeter("SSH-2.0")
+            .await
+            .parse::<service::Endpoint>()
+            .unwrap(),
+    );
+    registry.add(
+        "web",
+        greeter("HTTP/1.1")
+            .await
+            .parse::<service::Endpoint>()
+            .unwrap(),
+    );
+
+    for (name, greeting) in [("ssh", b"SSH-2.0".as_slice()), ("web", b"HTTP/1.1")] {
+        let (mut client, mut server) = connection_pair(name).await;
+        let client = async {
+            service::request(&mut client, name, None).await.unwrap();
+            let data = recv(&mut client).await;
+            client.close().await.unwrap();
+            data
+        };
+
+        let (data, served) = timeout(Duration::from_secs(30), async {
+            tokio::join!(client, registry.serve(&mut server))
+        })
+        .await
+        .unwrap();
+        assert_eq!(data, greeting);
+        served.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn rejects_unknown_service() {
+    let mut registry = ServiceRegistry::new();
+    registry.add(
+        "ssh",
+        greeter("SSH-2.0")
+            .await
+            .parse::<service::Endpoint>()
+            .unwrap(),
+    );
+
+    let (mut client, mut server) = connection_pair("unknown").await;
+    let (requested, accepted) = timeout(Duration::from_secs(30), async {
+        tokio::join!(
+            service::request(&mut client, "ftp", None),
+            registry.accept(&mut server),
+        )
+    })
+    .await
+    .unwrap();
+
+    assert!(matches!(
+        requested,
+        Err(ServiceError::Rejected(Rejection::UnknownService))
+    ));
+    assert!(matches!(
+        accepted,
+        Err(ServiceError::Rejected(Rejection::UnknownService))
+    ));
+}
+
+#[tokio::test]
+async fn tells_peer_endpoint_is_unreachable() {
+    let mut registry = ServiceRegistry::new();
+    // TEST-NET-1, nothing answers there
+    registry.add("ssh", "192.0.2.1:22".parse::<service::Endpoint>().unwrap());
+    registry.open_timeout = Some(Duration::from_millis(200));
+
+    let (mut client, mut server) = connection_pair("unreachable").await;
+    let client = async {
+        service::request(&mut client, "ssh", None).await.unwrap();
+        while !client.rx_closed() {
+            let mut value = client.wait().await.unwrap();
+            client.then(&mut value).await.unwrap();
+        }
+        client.diagnostics().session.warnings
+    };
+
+    let (warnings, served) = timeout(Duration::from_secs(30), async {
+        tokio::join!(client, registry.serve(&mut server))
+    })
+    .await
+    .unwrap();
+    assert!(matches!(served, Err(ServiceError::Unreachable(_))));
+    assert!(
+        warnings
+            .iter()
+            .any(|w| w.message.contains("forward target unreachable: ")),
+        "{warnings:?}"
+    );
+}
+
+#[tokio::test]
+async fn forward_holds_back_for_slow_peer() {
+    let options = ConnectOptions::default().with_latency_profile(LatencyProfile::Interactive);
+    let (mut near, mut far) = connection_pair_with("slow-peer", options).await;
+    let read = Arc::new(AtomicUsize::new(0));
+    let (output, mut delivered) = duplex(1024);
+    let mut local = AsyncPipeStream::new(Source(read.clone()), output);
+    far.pause();
+
+    let check = async {
+        sleep(Duration::from_secs(1)).await;
+        let read = read.load(Ordering::Relaxed);
+        let received = far.stats().bytes_received;
+
+        // The other direction is not held up meanwhile
+        far.send(b"reply").await.unwrap();
+        let mut reply = [0; 5];
+        timeout(Duration::from_secs(5), delivered.read_exact(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+
+        (read, received, reply)
+    };
+    let (read, received, reply) = select! {
+        r = service::forward(&mut near, &mut local) => panic!("forward ended: {r:?}"),
+        checked = check => checked,
+    };
+
+    // Past the writable threshold of 16 KiB, only the chunk being sent
+    assert!(
+        read <= received + 64 * 1024,
+        "read {read}, received {received}"
+    );
+    assert_eq!(&reply, b"reply");
+}
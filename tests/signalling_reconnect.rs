@@ -0,0 +1,88 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::flaky_signalling_server;
+use icepipe::{
+    events::{ConnectionEvent, Events},
+    pipe_stream::{Control, PipeStream, WaitThen},
+    ConnectOptions,
+};
+use std::time::Duration;
+use tokio::time::timeout;
+
+fn options(signaling: &url::Url, reconnects: u32) -> ConnectOptions {
+    ConnectOptions {
+        channel: "flaky".into(),
+        signaling: Some(signaling.clone()),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        signaling_reconnects: reconnects,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn resumes_candidate_exchange() {
+    // Candidates are the only messages with " udp ", the role announcement
+    // is dressed as a TCP one
+    let signaling = flaky_signalling_server(" udp ").await;
+    let events = Events::new();
+    let mut rx = events.subscribe();
+
+    let (a, b) = timeout(Duration::from_secs(30), async {
+        tokio::join!(
+            ConnectOptions {
+                events,
+                ..options(&signaling, 3)
+            }
+            .connect_psk(),
+            options(&signaling, 3).connect_psk(),
+        )
+    })
+    .await
+    .unwrap();
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    a.send(b"resumed").await.unwrap();
+    let received = loop {
+        let mut value = b.wait().await.unwrap();
+        if let Some(data) = b.then(&mut value).await.unwrap() {
+            break data;
+        }
+    };
+    assert_eq!(received, b"resumed");
+
+    // The pair may be notified after connecting
+    let (mut reconnecting, mut selected) = (false, false);
+    while !(reconnecting && selected) {
+        match timeout(Duration::from_secs(5), rx.recv()).await.unwrap() {
+            Ok(ConnectionEvent::SignallingReconnecting { remaining, .. }) => {
+                assert!(remaining < 3);
+                reconnecting = true;
+            }
+            Ok(ConnectionEvent::PairSelected { .. }) => selected = true,
+            Ok(_) => {}
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    let (a, b) = tokio::join!(a.close(), b.close());
+    a.unwrap();
+    b.unwrap();
+}
+
+#[tokio::test]
+async fn fails_without_reconnects() {
+    let signaling = flaky_signalling_server(" udp ").await;
+
+    let (a, b) = timeout(Duration::from_secs(30), async {
+        tokio::join!(
+            options(&signaling, 0).connect_psk(),
+            options(&signaling, 0).connect_psk(),
+        )
+    })
+    .await
+    .unwrap();
+
+    assert!(a.is_err() && b.is_err());
+}
@@ -0,0 +1,71 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::signalling_server;
+use icepipe::{
+    agreement::PskAuthentication,
+    pipe_stream::{Control, PipeStream},
+    testing::{self, EchoCheck},
+    ConnectOptions,
+};
+
+fn options(signaling: &url::Url, channel: &str) -> ConnectOptions {
+    ConnectOptions {
+        channel: channel.into(),
+        signaling: Some(signaling.clone()),
+        ice: vec!["stun:127.0.0.1:3478".to_string()],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn echo_peer() {
+    let signaling = signalling_server().await;
+    let check = EchoCheck {
+        bytes: 1 << 20,
+        message_size: 3000,
+        ..Default::default()
+    };
+    let auth = || PskAuthentication::new("echo".to_owned());
+    let dialer = async {
+        let mut peer = options(&signaling, "echo").connect(auth()).await.unwrap();
+        let report = testing::echo_check(&mut peer, check).await.unwrap();
+        peer.close().await.unwrap();
+        report
+    };
+    let (stats, report) = tokio::join!(
+        testing::echo_peer(options(&signaling, "echo"), auth()),
+        dialer
+    );
+
+    let stats = stats.unwrap();
+    assert_eq!(stats.bytes, (1 << 20) + 5 * 64);
+    assert_eq!(stats.messages, 5 + (1 << 20) / 3000 + 1);
+    assert_eq!(report.bytes, 1 << 20);
+    assert!(report.rtt.is_some());
+    assert!(report.throughput() > 0.0);
+}
+
+#[tokio::test]
+async fn discard_peer() {
+    let signaling = signalling_server().await;
+    let auth = || PskAuthentication::new("discard".to_owned());
+    let dialer = async {
+        let mut peer = options(&signaling, "discard")
+            .connect(auth())
+            .await
+            .unwrap();
+        for message in [&b"counted"[..], b"and", b"dropped"] {
+            peer.send(message).await.unwrap();
+        }
+        peer.close().await.unwrap();
+    };
+    let (stats, ()) = tokio::join!(
+        testing::discard_peer(options(&signaling, "discard"), auth()),
+        dialer
+    );
+
+    let stats = stats.unwrap();
+    assert_eq!((stats.messages, stats.bytes), (3, 17));
+}
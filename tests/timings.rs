@@ -0,0 +1,48 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::{signalling_server, turn_server};
+use icepipe::{pipe_stream::Control, ConnectOptions};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+#[tokio::test]
+async fn every_step_timed() {
+    let (_server, ice_server) = turn_server().await;
+    let options = ConnectOptions {
+        channel: "timings".into(),
+        signaling: Some(signalling_server().await),
+        ice: vec![ice_server.urls[0].replace("turn:", "stun:")],
+        ..Default::default()
+    };
+    let (a, b) = tokio::join!(options.clone().connect_psk(), options.connect_psk());
+    let (mut a, mut b) = (a.unwrap(), b.unwrap());
+
+    // Gathering may complete after connecting
+    timeout(Duration::from_secs(10), async {
+        while a.timings().gathering.is_none() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let timings = a.timings();
+    assert_eq!(timings.steps().count(), 7, "{timings}");
+    let total = timings.total.unwrap();
+    // The steps before ICE run one after the other
+    let sequential = [
+        timings.websocket,
+        timings.role,
+        timings.agreement,
+        timings.ice,
+        timings.sctp,
+    ];
+    let sequential = sequential.into_iter().map(Option::unwrap).sum::<Duration>();
+    assert!(sequential <= total, "{timings}");
+    assert_eq!(a.diagnostics().session.timings, timings);
+
+    a.close().await.unwrap();
+    b.close().await.unwrap();
+}
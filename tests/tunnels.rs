@@ -0,0 +1,134 @@
+#![cfg(feature = "full")]
+
+mod common;
+
+use common::signalling_server;
+use icepipe::{
+    connect::Connection,
+    manager::{LinkState, PeerSpec, ReconnectPolicy},
+    pipe_stream::{Control, PipeStream, StreamResult, WaitThen},
+    testing,
+    tunnels::{TunnelError, TunnelHandle, Tunnels},
+    ConnectOptions,
+};
+use std::{cell::Cell, rc::Rc, time::Duration};
+use tokio::{sync::mpsc, task::LocalSet, time::timeout};
+
+fn tunnels(signaling: &url::Url) -> Tunnels {
+    Tunnels::new(
+        ConnectOptions {
+            signaling: Some(signaling.clone()),
+            ice: vec!["stun:127.0.0.1:3478".to_string()],
+            ..Default::default()
+        },
+        ReconnectPolicy {
+            max_attempts: None,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+        },
+    )
+}
+
+fn psk(channel: &str) -> PeerSpec {
+    PeerSpec::Psk {
+        psk: channel.into(),
+    }
+}
+
+async fn wait_state(tunnel: &TunnelHandle, state: LinkState) {
+    timeout(
+        Duration::from_secs(30),
+        tunnel.state().wait_for(|current| *current == state),
+    )
+    .await
+    .unwrap_or_else(|_| panic!("{} never reached {state:?}", tunnel.name()))
+    .unwrap();
+}
+
+async fn echo(mut connection: Connection) -> StreamResult<()> {
+    testing::echo(&mut connection).await.map(|_| ())
+}
+
+/// Sends a message and hands over its echo, then leaves unless it should
+/// `stay` until the tunnel is closed.
+async fn ping(
+    mut connection: Connection,
+    echoed: mpsc::UnboundedSender<Vec<u8>>,
+    stay: bool,
+) -> StreamResult<()> {
+    connection.send(b"ping").await?;
+    while !connection.rx_closed() {
+        let mut value = connection.wait().await?;
+        if let Some(data) = connection.then(&mut value).await? {
+            let _ = echoed.send(data);
+            break;
+        }
+    }
+    if stay {
+        std::future::pending::<()>().await;
+    }
+    connection.close().await
+}
+
+#[tokio::test]
+async fn independent_tunnels() {
+    LocalSet::new()
+        .run_until(async {
+            let signaling = signalling_server().await;
+            let mut server = tunnels(&signaling);
+            let mut client = tunnels(&signaling);
+
+            let served = server.add("served", psk("tunnel-served"), echo).unwrap();
+            let idle = server.add("idle", psk("tunnel-idle"), echo).unwrap();
+            assert!(matches!(
+                server.add("idle", psk("tunnel-other"), echo),
+                Err(TunnelError::Exists(_))
+            ));
+
+            let (echoed_tx, mut echoed) = mpsc::unbounded_channel();
+            let sessions = Rc::new(Cell::new(0));
+            let pinging = client
+                .add("pinging", psk("tunnel-served"), move |connection| {
+                    sessions.set(sessions.get() + 1);
+                    ping(connection, echoed_tx.clone(), sessions.get() > 1)
+                })
+                .unwrap();
+
+            // The first session ends with the client leaving, both reconnect
+            for _ in 0..2 {
+                let data = timeout(Duration::from_secs(30), echoed.recv())
+                    .await
+                    .expect("Nothing echoed")
+                    .unwrap();
+                assert_eq!(data, b"ping");
+            }
+            assert!(pinging.health().borrow().connects >= 2);
+            // Reconnected after the backoff, as after a failure
+            assert_eq!(
+                pinging.health().borrow().last_error.as_deref(),
+                Some("Session ended")
+            );
+            wait_state(&served, LinkState::Connected).await;
+            assert!(served.health().borrow().connects >= 2);
+
+            // Never had a peer, unaffected by the other one
+            assert_eq!(*idle.state().borrow(), LinkState::Connecting);
+            let status = server.status();
+            assert_eq!(status.0.len(), 2);
+            assert_eq!(status.0[0].name, "idle");
+            assert_eq!(status.0[0].health.connects, 0);
+            assert_eq!(status.0[1].peer, psk("tunnel-served").id());
+            assert!(status.to_string().contains("served: Connected"));
+
+            let removed = server.remove("idle").unwrap();
+            wait_state(&removed, LinkState::Closed).await;
+            assert_eq!(server.status().0.len(), 1);
+            assert_eq!(*served.state().borrow(), LinkState::Connected);
+
+            client.close().await;
+            server.close().await;
+            assert_eq!(*pinging.state().borrow(), LinkState::Closed);
+            assert_eq!(*served.state().borrow(), LinkState::Closed);
+        })
+        .await;
+}
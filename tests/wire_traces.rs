@@ -0,0 +1,261 @@
+#![cfg(feature = "full")]
+
+//! Replays signalling sessions recorded from peers, so a change to what is
+//! sent or accepted on the wire breaks these instead of interop with other
+//! versions. The traces are in `tests/traces`, see `handshake.trace` for
+//! their format.
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::{SinkExt, StreamExt};
+use icepipe::{
+    agreement::{Agreed, Agreement, PskAuthentication},
+    diagnostics::{Diagnostics, Session},
+    ice::{AgentConfigHook, IceAgent, IceConfig},
+    pipe_stream::{Control, WaitThen},
+    ws::Websocket,
+};
+use std::time::Duration;
+use tokio::{net::TcpListener, task::JoinHandle, time::timeout};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+use webrtc_ice::{
+    candidate::{candidate_base::unmarshal_candidate, Candidate, CandidateType},
+    tcp_type::TcpType,
+};
+
+const PSK: &str = "wire-trace";
+
+enum Step {
+    /// Sent by the server or the peer.
+    Recv(String),
+    /// Expected from this side.
+    Send(String),
+}
+
+fn parse(trace: &str) -> Vec<Step> {
+    trace
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let line = line.replace("\\0", "\0");
+            match line.split_once(' ') {
+                Some(("<-", msg)) => Step::Recv(msg.to_owned()),
+                Some(("->", pattern)) => Step::Send(pattern.to_owned()),
+                _ => panic!("Bad trace line {line:?}"),
+            }
+        })
+        .collect()
+}
+
+/// Whether `msg` is `pattern`, placeholders standing for random values.
+fn matches(pattern: &str, msg: &str) -> bool {
+    let Some((literal, rest)) = pattern.split_once('{') else {
+        return pattern == msg;
+    };
+    let Some(msg) = msg.strip_prefix(literal) else {
+        return false;
+    };
+    let (placeholder, pattern) = rest.split_once('}').expect("Unclosed placeholder");
+    // Random values run up to the literal following them
+    let next = pattern.split('{').next().unwrap_or_default();
+    let at = match next {
+        "" => msg.len(),
+        next => match msg.find(next) {
+            Some(at) => at,
+            None => return false,
+        },
+    };
+    let (value, msg) = msg.split_at(at);
+    let valid = match placeholder {
+        "key" => BASE64_STANDARD
+            .decode(value)
+            .is_ok_and(|key| key.len() == 32),
+        "signature" => BASE64_STANDARD
+            .decode(value)
+            .is_ok_and(|sig| sig.len() == 64),
+        "nonce" => value.len() == 32 && value.bytes().all(|c| c.is_ascii_hexdigit()),
+        placeholder => panic!("Unknown placeholder {{{placeholder}}}"),
+    };
+
+    valid && matches(pattern, msg)
+}
+
+/// A signalling server playing `trace` to its single client, failing on
+/// the first message that differs. Returns what the client sent.
+async fn replay(trace: &str) -> (url::Url, JoinHandle<Vec<String>>) {
+    let steps = parse(trace);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/channel", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let (tcp, _) = listener.accept().await.unwrap();
+        let mut ws = accept_async(tcp).await.unwrap();
+        let mut sent = vec![];
+        for step in steps {
+            match step {
+                Step::Recv(msg) => ws.send(Message::Text(msg)).await.unwrap(),
+                Step::Send(pattern) => {
+                    let msg = loop {
+                        match ws.next().await.expect("Client left").unwrap() {
+                            Message::Text(msg) => break msg,
+                            Message::Ping(_) | Message::Pong(_) => continue,
+                            msg => panic!("Expected {pattern:?}, got {msg:?}"),
+                        }
+                    };
+                    assert!(matches(&pattern, &msg), "Expected {pattern:?}, got {msg:?}");
+                    sent.push(msg);
+                }
+            }
+        }
+        sent
+    });
+
+    (url.parse().unwrap(), server)
+}
+
+/// What this side settled on replaying the trace.
+struct Replayed {
+    dialer: bool,
+    basekey: Vec<u8>,
+    peer_payload: Option<Vec<u8>>,
+    session: Session,
+    /// Every message this side sent.
+    sent: Vec<String>,
+}
+
+/// Goes through the whole handshake against `trace`, without gathering so
+/// this side sends no candidates.
+async fn handshake(trace: &str) -> Replayed {
+    let (url, server) = replay(trace).await;
+    let replayed = async {
+        let (ws, dialer) = Websocket::new(url).await.unwrap();
+        let Agreed {
+            basekey,
+            signalling,
+            peer_payload,
+            ..
+        } = Agreement::new(ws, PskAuthentication::new(PSK.to_owned()))
+            .agree(None)
+            .await
+            .unwrap();
+
+        let diagnostics = Diagnostics::new();
+        let config = IceConfig {
+            diagnostics: diagnostics.clone(),
+            agent_config: Some(AgentConfigHook::new(|cfg| {
+                cfg.candidate_types = vec![CandidateType::Relay];
+            })),
+            ..Default::default()
+        };
+        let mut agent = IceAgent::new(signalling, dialer, config).await.unwrap();
+        while !agent.rx_closed() {
+            let mut value = agent.wait().await.unwrap();
+            agent.then(&mut value).await.unwrap();
+        }
+        agent.close().await.unwrap();
+
+        (
+            agent.dialer(),
+            basekey,
+            peer_payload,
+            diagnostics.session().unwrap(),
+        )
+    };
+    let (dialer, basekey, peer_payload, session) =
+        timeout(Duration::from_secs(10), replayed).await.unwrap();
+    let sent = timeout(Duration::from_secs(10), server)
+        .await
+        .unwrap()
+        .unwrap();
+
+    Replayed {
+        dialer,
+        basekey,
+        peer_payload,
+        session,
+        sent,
+    }
+}
+
+fn remote_candidates(session: &Session) -> Vec<&str> {
+    session
+        .remote_candidates
+        .iter()
+        .map(|entry| entry.candidate.as_str())
+        .collect()
+}
+
+#[tokio::test]
+async fn handshake_trace() {
+    let replayed = handshake(include_str!("traces/handshake.trace")).await;
+    assert!(!replayed.dialer);
+    assert_eq!(replayed.basekey.len(), 32);
+    assert_eq!(replayed.peer_payload.as_deref(), Some(b"hello".as_slice()));
+    assert_eq!(replayed.session.peer_role_negotiation, Some(true));
+    assert_eq!(
+        remote_candidates(&replayed.session),
+        [
+            "2130706431 1 udp 2130706431 192.0.2.1 50000 typ host",
+            "842163049 1 udp 1677729535 198.51.100.7 50000 typ srflx raddr 192.0.2.1 rport 50000",
+            "1052353102 1 udp 2130706175 192.0.2.2 50001 typ host",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn legacy_peer_trace() {
+    let replayed = handshake(include_str!("traces/legacy_peer.trace")).await;
+    assert!(replayed.dialer);
+    assert_eq!(replayed.peer_payload, None);
+    assert_eq!(replayed.session.peer_role_negotiation, Some(false));
+    assert_eq!(
+        remote_candidates(&replayed.session),
+        ["2130706431 1 udp 2130706431 192.0.2.1 50000 typ host"]
+    );
+}
+
+#[tokio::test]
+async fn baseline_peer_trace() {
+    let replayed = handshake(include_str!("traces/baseline_peer.trace")).await;
+    assert!(replayed.dialer);
+    assert_eq!(replayed.session.peer_role_negotiation, Some(false));
+    assert_eq!(remote_candidates(&replayed.session).len(), 2);
+
+    // Past the agreement and the start, such a peer fails on anything but
+    // candidates, and only probes those that are not active TCP ones
+    let sent = &replayed.sent[2..];
+    assert_eq!(sent[0], "Icepipe");
+    for msg in &sent[1..sent.len() - 1] {
+        let candidate = unmarshal_candidate(msg).unwrap();
+        assert_eq!(candidate.tcp_type(), TcpType::Active, "{msg}");
+    }
+    assert_eq!(sent.last().map(String::as_str), Some("Close"));
+}
+
+#[tokio::test]
+async fn role_conflict_trace() {
+    let replayed = handshake(include_str!("traces/role_conflict.trace")).await;
+    assert!(!replayed.dialer);
+    assert_eq!(replayed.session.peer_role_negotiation, Some(true));
+    assert!(remote_candidates(&replayed.session).is_empty());
+    assert!(replayed
+        .session
+        .warnings
+        .iter()
+        .any(|warning| warning.message.contains("continuing as listener")));
+}
+
+#[test]
+fn placeholders() {
+    let key = BASE64_STANDARD.encode([1; 32]);
+    assert!(matches("{key}", &key));
+    assert!(!matches("{signature}", &key));
+    assert!(matches(
+        "Icepipe/dialer/{nonce}",
+        "Icepipe/dialer/0123456789abcdef0123456789abcdef"
+    ));
+    assert!(!matches("Icepipe/dialer/{nonce}", "Icepipe/dialer/0123"));
+    assert!(matches(
+        "Icepipe/dialer/{nonce};end-of-candidates 1 tcp 0 0.0.0.0 9 typ host tcptype active",
+        "Icepipe/dialer/0123456789abcdef0123456789abcdef;end-of-candidates 1 tcp 0 0.0.0.0 9 typ host tcptype active"
+    ));
+    assert!(!matches("Icepipe/listener/{nonce}", "Icepipe/dialer/0123"));
+}